@@ -1,8 +1,11 @@
 extern crate rustc_version;
 
 use rustc_version::{version, Version};
+use std::env;
 use std::io::{self, Write};
-use std::process::exit;
+use std::path::PathBuf;
+use std::process::{exit, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// The minimum required version of rustc needed to build the Pennsieve agent.
 const REQUIRED_MIN_VERSION: &str = "1.44.0";
@@ -23,4 +26,91 @@ fn main() {
         );
         exit(1);
     }
+
+    // Build metadata printed by `version --format`:
+    println!("cargo:rustc-env=PENNSIEVE_BUILD_COMMIT={}", git_commit());
+    rerun_if_commit_changes();
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!(
+        "cargo:rustc-env=PENNSIEVE_BUILD_TIMESTAMP={}",
+        build_timestamp()
+    );
+}
+
+// The short SHA of the commit being built, or "unknown" outside of a git
+// checkout.
+fn git_commit() -> String {
+    git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string())
+}
+
+// Runs `git` with `args`, returning its trimmed output if it succeeded.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|out| out.trim().to_string())
+        .filter(|out| !out.is_empty())
+}
+
+// Reruns this script when a commit is made or checked out, even if it
+// touches no file in the package, so the embedded commit doesn't go stale.
+// That's when HEAD moves to another ref, or the ref it's on moves.
+fn rerun_if_commit_changes() {
+    let git_dir = match git(&["rev-parse", "--git-dir"]) {
+        Some(git_dir) => PathBuf::from(git_dir),
+        None => return,
+    };
+    println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+    if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        let ref_file = git_dir.join(&head_ref);
+        // A ref that was packed only lives in `packed-refs`:
+        let ref_file = if ref_file.exists() {
+            ref_file
+        } else {
+            git_dir.join("packed-refs")
+        };
+        println!("cargo:rerun-if-changed={}", ref_file.display());
+    }
+}
+
+// The time of the build as an RFC 3339 UTC timestamp. SOURCE_DATE_EPOCH is
+// honored, so that builds can be reproduced.
+fn build_timestamp() -> String {
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0)
+        });
+
+    // Days since the epoch to a civil date, after Howard Hinnant's
+    // `civil_from_days`:
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
 }
@@ -0,0 +1,13 @@
+CREATE TABLE page_record_down (
+    id VARCHAR(255) PRIMARY KEY,
+    nan_filled BOOLEAN,
+    complete BOOLEAN,
+    size INTEGER,
+    last_used VARCHAR(255) NOT NULL
+);
+INSERT INTO page_record_down
+SELECT id, nan_filled, complete, size, last_used
+FROM page_record;
+DROP TABLE page_record;
+ALTER TABLE page_record_down RENAME TO page_record;
+CREATE INDEX page_record_i1 ON page_record (nan_filled, last_used);
@@ -0,0 +1 @@
+ALTER TABLE page_record ADD COLUMN checksum INTEGER;
@@ -0,0 +1 @@
+ALTER TABLE upload_record ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
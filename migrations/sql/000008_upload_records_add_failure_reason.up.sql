@@ -0,0 +1 @@
+ALTER TABLE upload_record ADD COLUMN failure_reason TEXT;
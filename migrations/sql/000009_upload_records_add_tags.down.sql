@@ -0,0 +1,25 @@
+CREATE TABLE upload_record_down (
+    id INTEGER PRIMARY KEY,
+    file_path TEXT NOT NULL,
+    dataset_id VARCHAR(255) NOT NULL,
+    package_id VARCHAR(255),
+    import_id VARCHAR(255) NOT NULL,
+    progress INTEGER,
+    status VARCHAR(255) NOT NULL,
+    created_at VARCHAR(255) NOT NULL,
+    updated_at VARCHAR(255) NOT NULL,
+    append INTEGER NOT NULL DEFAULT 0,
+    upload_service INTEGER NOT NULL DEFAULT 0,
+    organization_id VARCHAR(255) NOT NULL DEFAULT '',
+    chunk_size BIGINT,
+    multipart_upload_id VARCHAR(100),
+    priority INTEGER NOT NULL DEFAULT 0,
+    failure_reason TEXT
+);
+INSERT INTO upload_record_down
+SELECT id, file_path, dataset_id, package_id, import_id, progress, status, created_at, updated_at, append, upload_service, organization_id, chunk_size, multipart_upload_id, priority, failure_reason
+FROM upload_record;
+DROP TABLE upload_record;
+ALTER TABLE upload_record_down RENAME TO upload_record;
+CREATE INDEX upload_record_i1 ON upload_record (import_id, file_path);
+CREATE INDEX upload_record_i2 ON upload_record (status, created_at);
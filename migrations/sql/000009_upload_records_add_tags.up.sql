@@ -0,0 +1 @@
+ALTER TABLE upload_record ADD COLUMN tags TEXT;
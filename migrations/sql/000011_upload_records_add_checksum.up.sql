@@ -0,0 +1 @@
+ALTER TABLE upload_record ADD COLUMN checksum VARCHAR(255);
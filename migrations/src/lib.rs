@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::str;
 
 use rust_embed::RustEmbed;
@@ -7,15 +8,60 @@ use rust_embed::RustEmbed;
 #[folder = "migrations/sql/"]
 struct SqlFiles;
 
+/// A single migration, made of the SQL that applies it and, if it can be
+/// reversed, the SQL that rolls it back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Migration {
+    /// The name of the migration, without the `.up.sql`/`.sql` extension,
+    /// e.g. `000001_upload_records_add_append`.
+    pub name: String,
+    pub up: Cow<'static, str>,
+    pub down: Option<Cow<'static, str>>,
+}
+
+impl Migration {
+    /// Pairs up (<filename>, <content>) tuples into migrations, sorted by
+    /// name. `NNNNNN_name.sql` and `NNNNNN_name.up.sql` files apply a
+    /// migration, and an optional `NNNNNN_name.down.sql` file rolls it back.
+    pub fn from_files<I>(files: I) -> Vec<Migration>
+    where
+        I: IntoIterator<Item = (String, Cow<'static, str>)>,
+    {
+        let mut ups = BTreeMap::new();
+        let mut downs = BTreeMap::new();
+
+        for (filename, contents) in files {
+            if filename.ends_with(".down.sql") {
+                let name = filename.trim_end_matches(".down.sql").to_string();
+                downs.insert(name, contents);
+            } else if filename.ends_with(".up.sql") {
+                let name = filename.trim_end_matches(".up.sql").to_string();
+                ups.insert(name, contents);
+            } else if filename.ends_with(".sql") {
+                let name = filename.trim_end_matches(".sql").to_string();
+                ups.insert(name, contents);
+            }
+        }
+
+        if let Some(name) = downs.keys().find(|name| !ups.contains_key(*name)) {
+            panic!("MIGRATION FILE ~ DOWN WITHOUT UP: {}", name);
+        }
+
+        ups.into_iter()
+            .map(|(name, up)| {
+                let down = downs.remove(&name);
+                Migration { name, up, down }
+            })
+            .collect()
+    }
+}
+
 pub struct Migrations;
 
 impl Migrations {
-    /// Get a vector of all (<filename>, <content>) tuples migrations to run,
-    /// in the order they are meant to be applied.
-    pub fn get_all() -> impl Iterator<Item = (String, Cow<'static, str>)> {
-        let mut file_names: Vec<String> = SqlFiles::iter().map(|s| s.into_owned()).collect();
-        file_names.sort();
-        file_names.into_iter().map(|filename| {
+    /// Get all migrations, in the order they are meant to be applied.
+    pub fn get_all() -> Vec<Migration> {
+        Migration::from_files(SqlFiles::iter().map(|filename| {
             let contents: Cow<[u8]> = SqlFiles::get(filename.as_ref())
                 .expect(&format!("MIGRATION FILE ~ MISSING: {}", filename));
             let decode_failure = format!("MIGRATION FILE ~ BAD UTF-8 CHARACTERS: {}", filename);
@@ -23,7 +69,56 @@ impl Migrations {
                 Cow::Borrowed(bytes) => str::from_utf8(bytes).expect(&decode_failure).into(),
                 Cow::Owned(bytes) => String::from_utf8(bytes).expect(&decode_failure).into(),
             };
-            (filename, text)
-        })
+            (filename.into_owned(), text)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, sql: &'static str) -> (String, Cow<'static, str>) {
+        (name.to_string(), sql.into())
+    }
+
+    #[test]
+    fn up_and_down_files_are_paired() {
+        let migrations = Migration::from_files(vec![
+            file("000002_add_index.down.sql", "DROP INDEX i;"),
+            file("000001_add_column.sql", "ALTER TABLE t ADD c INTEGER;"),
+            file("000002_add_index.up.sql", "CREATE INDEX i ON t (c);"),
+        ]);
+
+        assert_eq!(
+            migrations,
+            vec![
+                Migration {
+                    name: "000001_add_column".to_string(),
+                    up: "ALTER TABLE t ADD c INTEGER;".into(),
+                    down: None,
+                },
+                Migration {
+                    name: "000002_add_index".to_string(),
+                    up: "CREATE INDEX i ON t (c);".into(),
+                    down: Some("DROP INDEX i;".into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "DOWN WITHOUT UP")]
+    fn down_files_require_an_up_file() {
+        Migration::from_files(vec![file("000001_add_index.down.sql", "DROP INDEX i;")]);
+    }
+
+    #[test]
+    fn embedded_migrations_are_ordered() {
+        let names: Vec<String> = Migrations::get_all().into_iter().map(|m| m.name).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+        assert_eq!(names[0], "000001_upload_records_add_append");
     }
 }
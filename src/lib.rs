@@ -16,6 +16,7 @@ pub use crate::ps::agent::{server, Agent};
 pub use crate::ps::proto;
 pub use crate::ps::util;
 pub use crate::ps::{
-    cache_dir, config_file, database_file, home_dir, messages, Error, ErrorKind, Future, HostName,
-    OutputFormat, Result, Server, Service, ServiceId, WithProps, Worker,
+    cache_dir, config_file, database_file, home_dir, messages, set_config_file, set_database_file,
+    Error, ErrorKind, ExitCode, Future, HostName, OutputFormat, Result, Server, Service, ServiceId,
+    WithProps, Worker,
 };
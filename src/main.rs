@@ -9,17 +9,20 @@
 // devs of rustc are aware.
 #![allow(where_clauses_object_safety)]
 
-use std::cmp;
+use std::collections::BTreeMap;
 use std::env::{self, current_exe, var};
+use std::io;
 use std::mem;
 use std::path::PathBuf;
 use std::process::exit;
-use std::sync::atomic;
+use std::sync::{atomic, Mutex};
+use std::thread;
+use std::time;
 
 use actix::prelude::*;
 use chrono::Duration;
 use clap::value_t;
-use futures::{future, Future, IntoFuture};
+use futures::{future, Future, IntoFuture, Stream};
 // Set up human-panic for release build
 #[cfg(not(debug_assertions))]
 use human_panic::setup_panic;
@@ -32,7 +35,6 @@ use log4rs::append::rolling_file::{self, RollingFileAppender};
 #[cfg(not(debug_assertions))]
 use log4rs::config::Logger;
 use log4rs::config::{Appender, Config as LogConfig, Root};
-use log4rs::encode::pattern::PatternEncoder;
 use timer::Timer;
 
 use pennsieve::cache::{self, CachePageCollector};
@@ -40,11 +42,14 @@ use pennsieve::cli::upload::{StartMode, StopMode};
 use pennsieve::cli::{self, Cli};
 use pennsieve::config::constants as c;
 use pennsieve::config::{self, Config, Service};
-use pennsieve::database::{Database, Source, UserSettings};
+use pennsieve::database::{Database, Source, UploadStatus, UploadThresholds, UserSettings};
 use pennsieve::upload::{self, Uploader};
 use pennsieve::util::futures::*;
-use pennsieve::{self as ps, api, messages, server, Error, ErrorKind};
+use pennsieve::util::logging::{LevelToggle, LogFormat};
+use pennsieve::util::temporal::{parse_date_bound, Bound};
+use pennsieve::{self as ps, api, messages, server, Error, ErrorKind, ExitCode};
 use pennsieve_macros::{strings, try_future};
+use pennsieve_rust::Environment as ApiEnvironment;
 
 ///////////////////////////////////////////////////////////////////////////////
 //
@@ -57,11 +62,28 @@ use pennsieve_macros::{strings, try_future};
 //   Specify the debug-build console logging level (case-insensitive).
 //   If omitted, "warn" will be used.
 //
+// - PENNSIEVE_LOG_FORMAT=(pattern|json)?
+//
+//   Write log records as JSON objects, one per line, with "timestamp",
+//   "level", "target" and "message" keys, instead of the human-readable
+//   pattern. If omitted, "pattern" will be used.
+//
+// - PENNSIEVE_DISABLE_VERSION_CHECK=true|1|yes
+//
+//   If given, the agent never checks for new versions in the background,
+//   regardless of the `check_updates` config key.
+//
+// - PENNSIEVE_HOME=<path>?
+//
+//   Relocate the directory holding the config file, the database, the
+//   cache and the logs. If omitted, "~/.pennsieve" will be used.
+//
 // - DISABLE_MIGRATIONS=true|1|yes
 //
 //   If given, no attempt to run database migration will occur. This is
 //   useful if a user's agent.db SQLite database is in a bad state and we
-//   need to manually repair it.
+//   need to manually repair it. Once repaired, `config schema-version
+//   --migrate` runs the pending migrations regardless.
 //
 ///////////////////////////////////////////////////////////////////////////////
 
@@ -117,134 +139,107 @@ macro_rules! with_cli {
     };
 }
 
-// Defines the common arguments for an upload command.
-// This applies to "append" and "upload".
-//
-// Note: the functionality to build a subcommand with `clap` must be
-// implemented with a macro due to the lifetime specifier requirements of
-// `clap::App<'_, '_>`. The `clap` library expects the user to construct
-// a chain on subcommands and associated args in a single pass, without
-// subdividing the task between builder functions. Most functions on `clap::Arg`
-// that accept a string, take a `&str`, which is the cause of the complication.
-macro_rules! build_upload_args {
-    ($operation:expr, $about:expr, $fallback_dataset:ident) => {
-        clap::SubCommand::with_name($operation)
-            .about($about)
-            .arg(
-                clap::Arg::with_name("paths")
-                    .value_name("paths")
-                    .takes_value(true)
-                    .multiple(true)
-                    .min_values(1)
-                    .required(true)
-                    .validator(file_exists)
-                    .help(concat!(
-                        "Paths of the files to ",
-                        $operation,
-                        ".\n",
-                        "If a single path is provided, it can be a directory from which to ",
-                        $operation,
-                        " files"
-                    )),
-            )
-            .arg(
-                clap::Arg::with_name("dataset")
-                    .long("dataset")
-                    .value_name("dataset")
-                    .takes_value(true)
-                    .default_value($fallback_dataset)
-                    .validator(id_nonempty)
-                    .help(concat!(
-                        "The ID or name of the dataset to ",
-                        $operation,
-                        " to.\n",
-                        "Example: --dataset=N:dataset:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
-                        "         --dataset=\"My dataset\""
-                    )),
-            )
-            .arg(
-                clap::Arg::with_name("force")
-                    .short("f")
-                    .long("force")
-                    .help("Bypass the file selection confirmation prompt"),
-            )
-            .arg(
-                clap::Arg::with_name("legacy").long("legacy").help(
-                    "Use the legacy S3 uploader instead of the Upload Service for this upload",
-                ),
-            )
-            .arg(
-                clap::Arg::with_name("recursive")
-                    .short("r")
-                    .long("recursive")
-                    .help(concat!(
-                        "Recursively upload all folders in a directory.\n",
-                        "This argument will be ignored if multiple files are specified ",
-                        "instead of a single directory"
-                    )),
-            )
-            .arg(
-                clap::Arg::with_name("parallelism")
-                    .long("parallelism")
-                    .value_name("parallelism")
-                    .takes_value(true)
-                    .hidden(true)
-                    .help("Parallelism level; default is the number of CPUs"),
-            )
-    };
+lazy_static! {
+    /// Set if the agent is running in server mode.
+    static ref SERVER_MODE: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+    /// Set once a SIGINT starts shutting the agent down.
+    static ref SHUTTING_DOWN: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+    /// Reconfigures logging at runtime, once it is set up.
+    static ref LOG_LEVEL_TOGGLE: Mutex<Option<(log4rs::Handle, LevelToggle)>> = Mutex::new(None);
 }
 
-macro_rules! append_command {
-    ($fallback_dataset:ident) => {
-        build_upload_args!(
-            "append",
-            "Append data to a timeseries package",
-            $fallback_dataset
-        )
-        .arg(
-            clap::Arg::with_name("package")
-                .long("package")
-                .value_name("package")
-                .takes_value(true)
-                .validator(id_nonempty)
-                .help(concat!(
-                    "The ID or name of the timeseries package to append to\n",
-                    "Example: --package=N:package:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
-                    "         --package=\"My Timeseries Data\""
-                )),
-        )
-    };
+/// Gets the parallelism level from the `--parallelism` and
+/// `--allow-oversubscribe` arguments, exiting if it is invalid.
+fn parallelism_level(args: &clap::ArgMatches<'_>) -> usize {
+    cli::parallelism_level(
+        args.value_of("parallelism"),
+        num_cpus::get(),
+        args.is_present("allow_oversubscribe"),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    })
 }
 
-macro_rules! upload_command {
-    ($fallback_dataset:ident) => {
-        build_upload_args!("upload", "Upload files to the Pennsieve platform", $fallback_dataset)
-            .arg(
-                clap::Arg::with_name("folder")
-                    .long("folder")
-                    .value_name("folder")
-                    .takes_value(true)
-                    .validator(id_nonempty)
-                    .help(concat!(
-                        "The ID or name of the folder to upload to. If it doesn't exist, it will be created\n",
-                        "Example: --folder=N:collection:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
-                        "         --folder=\"My Samples\""
-                        )
-                    ),
-            )
-    };
+/// Gets how many files, and chunks of each, to upload at once from the
+/// `--parallelism`, `--file-parallelism`, `--chunk-parallelism` and
+/// `--allow-oversubscribe` arguments, exiting if any is invalid.
+fn upload_parallelism(args: &clap::ArgMatches<'_>) -> upload::Parallelism {
+    cli::upload_parallelism(
+        args.value_of("parallelism"),
+        args.value_of("file_parallelism"),
+        args.value_of("chunk_parallelism"),
+        num_cpus::get(),
+        args.is_present("allow_oversubscribe"),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    })
 }
 
-lazy_static! {
-    /// Set if the agent is running in server mode.
-    static ref SERVER_MODE: atomic::AtomicBool = atomic::AtomicBool::new(false);
+/// Gets the tags given with `--tag`, exiting if any is malformed.
+fn upload_tags(args: &clap::ArgMatches<'_>) -> BTreeMap<String, String> {
+    upload::parse_tags(args.values_of("tag").into_iter().flatten()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    })
+}
+
+/// Reports an error from `upload-verify` and exits.
+fn verify_failed(e: ps::Error) -> ps::Error {
+    match e.kind() {
+        ErrorKind::CliError {
+            kind: cli::ErrorKind::UploadDoesNotMatch { path: local_path },
+        } => {
+            eprintln!(
+                "Local file does not match file on the Pennsieve platform: {:?}",
+                local_path
+            );
+            exit(ExitCode::UploadError.into())
+        }
+        _ => exit(e.render()),
+    }
 }
 
-fn parallelism_level(raw_value: Option<&str>) -> usize {
-    let max_parallelism: usize = num_cpus::get();
-    match raw_value {
-        Some(p) => cmp::min(p.parse::<usize>().unwrap(), max_parallelism),
-        None => max_parallelism,
+/// Gets the `--idle-timeout` argument, if given.
+fn idle_timeout(args: &clap::ArgMatches<'_>) -> Option<time::Duration> {
+    value_t!(args, "idle_timeout", u64)
+        .ok()
+        .map(time::Duration::from_secs)
+}
+
+/// Tests if the command given by `matches` prints a table whose columns
+/// can be chosen with `--columns`.
+fn supports_columns(matches: &clap::ArgMatches<'_>) -> bool {
+    match matches.subcommand() {
+        ("datasets", _) | ("members", _) => true,
+        // `ls` only prints a table when it falls back to listing datasets:
+        ("ls", Some(args)) => {
+            args.value_of("dataset").is_none() && args.value_of("collection").is_none()
+        }
+        // `upload-status` prints a table unless it's asked to act on
+        // uploads, or to show them some other way:
+        ("upload-status", Some(args)) => ![
+            "cancel",
+            "retry",
+            "retry_all",
+            "cancel_all",
+            "cancel_dataset",
+            "cancel_import",
+            "cancel_pending",
+            "listen",
+            "resume",
+            "export",
+            "detail",
+            "summary",
+        ]
+        .iter()
+        .any(|name| args.is_present(name)),
+        _ => false,
     }
 }
 
@@ -255,9 +250,22 @@ struct Context {
     config: Option<Config>, // Empty until `get_config()` is called
     api: Option<api::Api>,  // Empty until `get_api()` is called
     output: ps::OutputFormat,
+    columns: cli::Columns,
+    timeout: Option<time::Duration>,
+    dataset_lookup: api::DatasetLookup,
+    name_cache: bool,
+    organization: Option<String>,
+    environment: Option<ApiEnvironment>,
+    verbosity: cli::Verbosity,
+    no_progress: bool,
+    no_color: bool,
+    upload_log_dir: Option<PathBuf>,
+    manifest_output: Option<PathBuf>,
 }
 
 impl Context {
+    /// Creates a new context, opening the database returned by
+    /// `ps::database_file()`.
     fn new() -> ps::Result<Self> {
         let db = Database::new(&Source::File(ps::database_file()?.to_path_buf()))?;
         Ok(Self {
@@ -266,6 +274,17 @@ impl Context {
             config: None,
             api: None,
             output: Default::default(),
+            columns: Default::default(),
+            timeout: None,
+            dataset_lookup: Default::default(),
+            name_cache: true,
+            organization: None,
+            environment: None,
+            verbosity: Default::default(),
+            no_progress: false,
+            no_color: false,
+            upload_log_dir: None,
+            manifest_output: None,
         })
     }
 
@@ -275,8 +294,12 @@ impl Context {
             // If it exists, return a clone:
             Some(ref api) => Ok(api.clone()),
             None => {
-                // Otherwise, create it based on the current profile:
-                let config = self.get_config()?;
+                // Otherwise, create it based on the current profile,
+                // pointed at the environment chosen with `--env`:
+                let mut config = self.get_config()?;
+                if let Some(environment) = self.environment {
+                    config = config.with_environment(environment);
+                }
 
                 let user_profile = self.get_current_profile()?;
                 let user_profile = config
@@ -288,7 +311,11 @@ impl Context {
 
                 // if successful, memoize the result and return that in
                 // subsequent calls:
-                let api = api::Api::new(&self.db, &config, user_profile.environment);
+                let api = api::Api::new(&self.db, &config, &user_profile)
+                    .with_timeout(self.timeout)
+                    .with_dataset_lookup(self.dataset_lookup)
+                    .with_name_cache(self.name_cache)
+                    .with_organization(self.organization.clone());
                 mem::replace(&mut self.api, Some(api.clone()));
                 Ok(api)
             }
@@ -296,7 +323,7 @@ impl Context {
     }
 
     /// Lazily reads the `config.ini` file in the Pennsieve home directory,
-    /// returning a typed representation.
+    /// or the file given with `--config`, returning a typed representation.
     fn get_config(&mut self) -> ps::Result<Config> {
         match self.config {
             // If it exists, return a clone:
@@ -321,9 +348,74 @@ impl Context {
         self.output = new_format;
     }
 
+    /// Sets the table columns chosen with `--columns`.
+    fn set_columns(&mut self, columns: cli::Columns) {
+        self.columns = columns;
+    }
+
+    /// Sets the timeout applied to requests to the Pennsieve platform.
+    fn set_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how dataset arguments are resolved, as chosen with
+    /// `--dataset-by-id` or `--dataset-by-name-only`.
+    fn set_dataset_lookup(&mut self, dataset_lookup: api::DatasetLookup) {
+        self.dataset_lookup = dataset_lookup;
+    }
+
+    /// Turns off the cache of resolved dataset names, as asked for with
+    /// `--no-cache`.
+    fn set_name_cache(&mut self, name_cache: bool) {
+        self.name_cache = name_cache;
+    }
+
+    /// Sets the environment chosen with `--env`, which overrides the
+    /// environment of the profile for this run only.
+    fn set_environment(&mut self, environment: Option<ApiEnvironment>) {
+        self.environment = environment;
+    }
+
+    /// Sets the organization chosen with `--organization`.
+    fn set_organization(&mut self, organization: Option<String>) {
+        self.organization = organization;
+    }
+
+    /// Sets the verbosity chosen with the `--quiet` and `-v` flags.
+    fn set_verbosity(&mut self, verbosity: cli::Verbosity) {
+        self.verbosity = verbosity;
+    }
+
+    /// Turns off the animated upload progress bars, as asked for with
+    /// `--no-progress`.
+    fn set_no_progress(&mut self, no_progress: bool) {
+        self.no_progress = no_progress;
+    }
+
+    /// Turns off color in rich output, as asked for with `--no-color`.
+    fn set_no_color(&mut self, no_color: bool) {
+        self.no_color = no_color;
+    }
+
+    /// Sets the directory each upload writes its own log file to, as
+    /// given with `--upload-log`.
+    fn set_upload_log_dir(&mut self, upload_log_dir: Option<PathBuf>) {
+        self.upload_log_dir = upload_log_dir;
+    }
+
+    /// Sets where a manifest of the uploaded files is written once
+    /// uploading is complete, as given with `--manifest-output`.
+    fn set_manifest_output(&mut self, manifest_output: Option<PathBuf>) {
+        self.manifest_output = manifest_output;
+    }
+
     /// Adds the supplied service to the Pennsieve agent to run when it is
     /// started in server mode.
-    fn add_service(&mut self, service: &Service, parallelism: usize) -> ps::Result<()> {
+    fn add_service(
+        &mut self,
+        service: &Service,
+        parallelism: upload::Parallelism,
+    ) -> ps::Result<()> {
         let config = self.get_config()?;
         let api = self.get_api()?;
 
@@ -332,6 +424,7 @@ impl Context {
             // SERVICE: Reverse proxy
             // ----------------------------------------------------------------
             Service::Proxy(config::ProxyService {
+                local_bind,
                 local_port,
                 ref remote_host,
                 remote_port,
@@ -341,7 +434,12 @@ impl Context {
                     remote_port,
                 };
                 self.agent
-                    .define_server(local_port, props, ps::server::ReverseProxyServer)
+                    .define_server_with_bind(
+                        local_bind,
+                        local_port,
+                        props,
+                        ps::server::ReverseProxyServer,
+                    )
                     .map(|_| ())
             }
 
@@ -349,6 +447,7 @@ impl Context {
             // SERVICE: Streaming timeseries data
             // ----------------------------------------------------------------
             Service::TimeSeries(config::TimeSeriesService {
+                local_bind,
                 local_port,
                 ref remote_host,
                 remote_port,
@@ -372,9 +471,16 @@ impl Context {
                         port: remote_port,
                         config: cache_config,
                         db: self.db.clone(),
+                        max_frame_bytes: Some(config.timeseries_max_frame_bytes)
+                            .filter(|max_bytes| *max_bytes > 0),
                     };
                     self.agent
-                        .define_server(local_port, props, ps::server::TimeSeriesServer)
+                        .define_server_with_bind(
+                            local_bind,
+                            local_port,
+                            props,
+                            ps::server::TimeSeriesServer,
+                        )
                         .map(|_| ())
                 }
             }
@@ -386,7 +492,11 @@ impl Context {
                 let props = upload::Props {
                     api,
                     db: self.db.clone(),
-                    parallelism,
+                    file_parallelism: parallelism.files,
+                    chunk_parallelism: parallelism.chunks,
+                    progress_sink: None,
+                    upload_log_dir: self.upload_log_dir.clone(),
+                    thresholds: UploadThresholds::from(&config),
                 };
                 self.agent.define_worker(props, Uploader).map(|_| ())
             }
@@ -406,7 +516,16 @@ impl Context {
     ///
     /// Runs the agent in server mode, passing the Agent instance to a callback
     /// before its `start()` method is invoked.
-    fn custom_server_mode<F>(mut self, before_start: F, parallelism: usize) -> ps::Result<()>
+    ///
+    /// If `check_status_port` is set, the agent refuses to start when its
+    /// status port is already taken, as it is when another agent is running.
+    fn custom_server_mode<F>(
+        mut self,
+        before_start: F,
+        check_status_port: bool,
+        parallelism: upload::Parallelism,
+        idle_timeout: Option<time::Duration>,
+    ) -> ps::Result<()>
     where
         F: Fn(&mut ps::Agent) -> ps::Result<()>,
     {
@@ -426,14 +545,33 @@ impl Context {
             self.add_service(&service, parallelism)?;
         }
 
+        // Report on the agent's database at the status server's `/health`:
+        self.agent.set_database(self.db.clone());
+
         // Apply any mutations to the agent instance before its started:
         before_start(&mut self.agent)?;
 
+        if check_status_port {
+            self.agent.check_status_port()?;
+        }
+
+        // The verbosity flags win over the agent's own choice of output:
+        if self.verbosity.quiet {
+            self.agent.quiet();
+        } else if self.verbosity.verbose > 0 {
+            self.agent.loud();
+        }
+
         let mut handle = self.agent.setup()?;
 
         Self::set_server_mode(true);
 
         install_sigint_handler(System::current());
+        install_sighup_handler();
+
+        if let Some(timeout) = idle_timeout {
+            server::idle::install_idle_shutdown(System::current(), self.db.clone(), timeout);
+        }
 
         handle.run().expect("start in server mode");
 
@@ -442,28 +580,69 @@ impl Context {
         Ok(())
     }
 
-    /// Starts the agent in server mode.
-    fn start_server_mode(mut self, parallelism: usize) -> ps::Result<()> {
+    /// Starts the agent in server mode. If an idle timeout is given, the
+    /// agent shuts down once it has been idle for that long. If a PID file
+    /// is given, it is written before the agent starts and removed once it
+    /// shuts down.
+    fn start_server_mode(
+        mut self,
+        parallelism: upload::Parallelism,
+        idle_timeout: Option<time::Duration>,
+        pid_file: Option<PathBuf>,
+    ) -> ps::Result<()> {
         let config = self.get_config()?;
 
-        self.custom_server_mode(
+        // Held until the agent shuts down, or is forced to exit:
+        if let Some(path) = pid_file {
+            server::PidFile::create(path)?.hold();
+        }
+
+        let result = self.custom_server_mode(
             |ref mut agent| {
-                // Set the status server port:
+                // Set the status server address and port:
+                agent.set_status_bind(config.status_server_bind);
                 agent.set_status_port(config.status_server_port);
 
                 Ok(())
             },
+            true,
             parallelism,
-        )
+            idle_timeout,
+        );
+        server::PidFile::release();
+        result
     }
 
-    /// Sets up logging.
-    fn setup_logging() -> ps::Result<()> {
-        // Get log level from the environment, falling back to the provided default
+    /// Sets up logging. In server mode, SIGHUP switches it to the debug
+    /// level and back; see `toggle_debug_logging`.
+    fn setup_logging(verbosity: cli::Verbosity) -> ps::Result<()> {
+        // PENNSIEVE_LOG_FORMAT=json writes one JSON object per log record:
+        let log_format = LogFormat::from_env();
+
+        let toggle = LevelToggle::new(move |level| Self::log_config(verbosity, log_format, level));
+        let handle = log4rs::init_config(toggle.config())?;
+        *LOG_LEVEL_TOGGLE.lock().unwrap() = Some((handle, toggle));
+        Ok(())
+    }
+
+    /// Builds the logging configuration, with every logger logging at
+    /// `level` if it is given.
+    fn log_config(
+        verbosity: cli::Verbosity,
+        log_format: LogFormat,
+        level: Option<LevelFilter>,
+    ) -> LogConfig {
+        // Get log level from the verbosity flags, then the environment, falling
+        // back to the provided default.
         // PENNSIEVE_LOG_LEVEL is preferred for compatibility with the Python client,
         // but LOGLEVEL is also supported.
-        fn get_log_level(default_level: LevelFilter) -> LevelFilter {
-            if let Ok(loglevel) = env::var("PENNSIEVE_LOG_LEVEL").or_else(|_| env::var("LOGLEVEL"))
+        let get_log_level = |default_level: LevelFilter| -> LevelFilter {
+            if let Some(level) = level {
+                level
+            } else if let Some(level) = verbosity.level_filter() {
+                level
+            } else if let Ok(loglevel) =
+                env::var("PENNSIEVE_LOG_LEVEL").or_else(|_| env::var("LOGLEVEL"))
             {
                 match loglevel.to_lowercase().as_str() {
                     "debug" => LevelFilter::Debug,
@@ -478,15 +657,13 @@ impl Context {
             } else {
                 default_level
             }
-        }
+        };
 
         // === DEBUG BUILD ====================================================
         #[cfg(debug_assertions)]
         let config: LogConfig = {
             let stdout = ConsoleAppender::builder()
-                .encoder(Box::new(PatternEncoder::new(
-                    config::constants::AGENT_LOG_FORMAT,
-                )))
+                .encoder(log_format.encoder())
                 .build();
 
             LogConfig::builder()
@@ -522,15 +699,11 @@ impl Context {
             );
 
             let file = RollingFileAppender::builder()
-                .encoder(Box::new(PatternEncoder::new(
-                    config::constants::AGENT_LOG_FORMAT,
-                )))
+                .encoder(log_format.encoder())
                 .build(&log_path, Box::new(policy))
                 .expect("ps:main:context:logging:init ~ couldn't build the file logger");
             let stdout = ConsoleAppender::builder()
-                .encoder(Box::new(PatternEncoder::new(
-                    config::constants::AGENT_LOG_FORMAT,
-                )))
+                .encoder(log_format.encoder())
                 .build();
 
             LogConfig::builder()
@@ -555,7 +728,7 @@ impl Context {
                 .expect("ps:main:context:logging:init ~ couldn't build the console logger")
         };
 
-        log4rs::init_config(config).map(|_| ()).map_err(Into::into)
+        config
     }
 
     // NOTE:
@@ -568,20 +741,23 @@ impl Context {
     fn cli(&mut self) -> ps::Result<Cli> {
         let api = self.get_api()?;
         let config = self.get_config()?;
-        Ok(Cli::new(&self.db, &api, self.output, &config.api_settings))
+        Ok(Cli::new(&self.db, &api, self.output, &config.api_settings)
+            .with_columns(self.columns.clone()))
     }
 
     /// Toggles file uploading watch mode.
     ///
     /// In this mode, the file upload progress indicator will be rendered on
     /// the CLI.  If the Pennsieve agent is not running in server mode, it is
-    /// started before upload watching occurs.
+    /// started before upload watching occurs. An agent started here shuts
+    /// down once idle for `idle_timeout`, unless it never stops.
     fn uploading(
         self,
         _cli: Cli,
         start_mode: StartMode,
         stop_mode: StopMode,
-        parallelism: usize,
+        parallelism: upload::Parallelism,
+        idle_timeout: Option<time::Duration>,
     ) -> ps::Future<()> {
         let active_uploads = try_future!(self.db.get_active_uploads());
 
@@ -601,7 +777,13 @@ impl Context {
             watcher.watch().into_trait()
         } else {
             let db = self.db.clone();
-            let output = self.output;
+            let manifest_output = self.manifest_output.clone();
+            let output = cli::upload::progress_output(self.output, self.no_progress, || {
+                atty::is(atty::Stream::Stdout)
+            });
+            let color = cli::upload::color_enabled(self.no_color, env::var_os("NO_COLOR"), || {
+                atty::is(atty::Stream::Stdout)
+            });
 
             // The agent is not running. Start the server alongside the an
             // upload watcher worker.
@@ -611,10 +793,12 @@ impl Context {
                     let props = cli::upload::Props {
                         db,
                         output,
+                        color,
                         interval_ms: config::constants::UPLOAD_PROGRESS_REFRESH_INTERVAL_MS,
                         parallelism,
                         start_mode,
                         stop_mode,
+                        manifest_output: manifest_output.clone(),
                     };
 
                     // If a port is given, use that to set the status port:
@@ -630,7 +814,11 @@ impl Context {
                     // Set up the upload worker to run:
                     agent.define_worker(props, watcher).map(|_| ())
                 },
+                // A second instance alongside a running agent only checks
+                // a status port it was explicitly given:
+                start_mode.status_port().is_some(),
                 parallelism,
+                idle_timeout.filter(|_| !stop_mode.never()),
             )
             .into_future()
             .into_trait()
@@ -647,13 +835,10 @@ impl Context {
             .ok_or_else(|| api::Error::invalid_user_profile(profile).into())
             .into_future()
             .and_then(move |new_profile| {
-                let api = api::Api::new(&self.db, &config, new_profile.environment);
+                let api = api::Api::new(&self.db, &config, &new_profile);
                 api.login_with_profile(new_profile.profile).map(|_| Self {
-                    agent: self.agent,
-                    db: self.db,
-                    config: self.config,
                     api: Some(api),
-                    output: self.output,
+                    ..self
                 })
             })
             .into_trait()
@@ -697,97 +882,135 @@ impl Context {
     }
 }
 
+/// Switches logging to the debug level, or back to the levels it was set
+/// up with.
+fn toggle_debug_logging() {
+    if let Some((ref handle, ref mut toggle)) = *LOG_LEVEL_TOGGLE.lock().unwrap() {
+        handle.set_config(toggle.toggle());
+        if toggle.is_debug() {
+            info!("logging at the debug level");
+        } else {
+            info!("logging at the configured levels");
+        }
+    }
+}
+
+/// Sets up SIGHUP handling for the Pennsieve agent when running in server
+/// mode: each SIGHUP switches logging to the debug level and back, so a
+/// running agent can be made verbose without restarting it.
+#[cfg(unix)]
+fn install_sighup_handler() {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    Arbiter::spawn(
+        Signal::new(SIGHUP)
+            .flatten_stream()
+            .for_each(|_| {
+                info!("received SIGHUP");
+                toggle_debug_logging();
+                Ok(())
+            })
+            .map_err(|e| error!("couldn't handle SIGHUP: {}", e)),
+    );
+}
+
+#[cfg(not(unix))]
+fn install_sighup_handler() {}
+
 /// Sets up cross-platform SIGINT (ctrl+c) handling for the Pennsieve agent
 /// when running in server mode.
 ///
-/// This function returns a future that will not resolve until a SIGINT
-/// signal is received.
+/// The uploads in flight are drained before the actix system is shut down,
+/// and a watchdog timer kills the process if the drain and the shutdown
+/// together take too long. A second SIGINT exits right away.
 fn install_sigint_handler(system: System) {
     ctrlc::set_handler(move || {
+        if SHUTTING_DOWN.swap(true, atomic::Ordering::SeqCst) {
+            info!("received a second SIGINT, exiting");
+            server::PidFile::release();
+            exit(0);
+        }
         info!("received SIGINT");
         #[cfg(not(debug_assertions))]
         println!("Shutting down");
 
-        // Shutdown the actix system:
-        system
-            .registry()
-            .get::<server::StatusServer>()
-            .do_send(messages::SystemShutdown);
-
-        // Kick off a watchdog timer to kill the process if shutdown takes
-        // too long.
+        // Kick off a watchdog timer to kill the process if draining the
+        // uploads and shutting down take too long:
+        let drain_timeout = c::UPLOAD_MAX_DRAIN_TIMEOUT_SECS;
         let timer = Timer::new();
         timer
             .schedule_with_delay(
-                Duration::seconds(config::constants::AGENT_MAX_SHUTDOWN_TIMEOUT_SECS as i64),
+                Duration::seconds((drain_timeout + c::AGENT_MAX_SHUTDOWN_TIMEOUT_SECS) as i64),
                 move || {
                     info!("shutdown timeout exceeded");
+                    server::PidFile::release();
                     exit(0);
                 },
             )
             .ignore();
         mem::forget(timer);
-    })
-    .expect("couldn't install SIGINT handler");
-}
 
-/// Function to validate whether a given profile_name exists.
-fn profile_exists<S: Into<String>>(profile_name: S) -> Result<(), String> {
-    let profile_name: String = profile_name.into();
-    Config::from_config_file_and_environment()
-        .or_else(|e| Err(format!("error building the configuration: {}", e)))
-        .and_then(|config| {
-            if config.api_settings.contains_profile(profile_name.clone()) {
-                Ok(())
-            } else {
-                Err(format!(
-                    "Invalid profile: {}. These are valid profiles: {}",
-                    profile_name,
-                    config.api_settings.profile_names().join(", ")
-                ))
+        // Stop starting new uploads, and let the uploads in flight finish
+        // and update their records before shutting down. The drain waits
+        // on its own thread, so that this handler can catch another SIGINT:
+        Uploader::begin_drain();
+        let system = system.clone();
+        thread::spawn(move || {
+            if !Uploader::wait_until_idle(time::Duration::from_secs(drain_timeout)) {
+                warn!(
+                    "uploads still in flight after {}s; shutting down anyway",
+                    drain_timeout
+                );
             }
-        })
-}
-
-/// Function to validate if an identifier is non-empty.
-fn id_nonempty<S: Into<String>>(id: S) -> Result<(), String> {
-    let id = id.into();
-    if id.trim().is_empty() {
-        Err("an ID is required".into())
-    } else {
-        Ok(())
-    }
-}
 
-/// Function to validate if a file exists.
-fn file_exists<S: Into<String>>(filepath: S) -> Result<(), String> {
-    let filepath = filepath.into();
-    if !PathBuf::from(filepath.clone()).as_path().exists() {
-        Err(format!("file not found: {:?}", filepath))
-    } else {
-        Ok(())
-    }
+            // Shutdown the actix system:
+            system
+                .registry()
+                .get::<server::StatusServer>()
+                .do_send(messages::SystemShutdown);
+        });
+    })
+    .expect("couldn't install SIGINT handler");
 }
 
-/// Function to validate if a given argument is numeric.
-fn is_numeric<S: Into<String>>(argument: S) -> Result<(), String> {
-    let argument = argument.into();
-    if argument.parse::<usize>().is_ok() {
-        Ok(())
-    } else {
-        Err(format!("received non-numeric value: {}", argument))
+/// Finds the value of a global path option, given as `--<name> <path>` or
+/// `--<name>=<path>`, in the raw command line arguments.
+///
+/// These options must be applied before the database is opened, which
+/// happens before the full argument parser can be built.
+fn path_option(args: &[String], name: &str) -> Option<PathBuf> {
+    let flag = format!("--{}", name);
+    let prefix = format!("{}=", flag);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if *arg == flag {
+            return args.next().map(PathBuf::from);
+        } else if arg.starts_with(&prefix) {
+            return Some(PathBuf::from(&arg[prefix.len()..]));
+        }
     }
+    None
 }
 
 #[allow(clippy::cyclomatic_complexity)]
 fn main() {
     // First, initialize all logging:
-    Context::setup_logging().expect("couldn't initialize the logger");
+    let raw_args: Vec<String> = env::args().collect();
+    let verbosity = cli::Verbosity::from_args(&raw_args);
+    Context::setup_logging(verbosity).expect("couldn't initialize the logger");
 
     // Set up human-panic for release build
     #[cfg(not(debug_assertions))]
     setup_panic!();
 
+    // Point the agent at an alternate config.ini and agent.db, if given:
+    if let Some(path) = path_option(&raw_args, "config") {
+        ps::set_config_file(path);
+    }
+    if let Some(path) = path_option(&raw_args, "db") {
+        ps::set_database_file(path);
+    }
+
     let mut context = Context::new().unwrap_or_else(|e| {
         eprintln!("Error creating command line context:");
         print!("    ");
@@ -803,318 +1026,7 @@ fn main() {
         .as_ref()
         .map_or("", String::as_str);
 
-    let mut app = clap::App::new(env!("CARGO_PKG_NAME"))
-                .version(env!("CARGO_PKG_VERSION"))
-                .author(env!("CARGO_PKG_AUTHORS"))
-                .about("The official Pennsieve client")
-                .setting(clap::AppSettings::UnifiedHelpMessage)
-        .arg(clap::Arg::with_name("output")
-             .short("O")
-             .long("output")
-             .takes_value(true)
-             .global(true)
-             .possible_value("simple")
-             .possible_value("rich")
-             .default_value("rich")
-             //.possible_value("json")
-             .help("Sets the output format"))
-        .subcommand(append_command!(fallback_dataset))
-        .subcommand(clap::SubCommand::with_name("config")
-                    .about("Configure the Pennsieve Agent")
-                    .long_about("Configure the Pennsieve Agent")
-                    .subcommand(clap::SubCommand::with_name("show")
-                                .about("Show a configuration value")
-                                .arg(clap::Arg::with_name("key")
-                                     .value_name("key")
-                                     .takes_value(true)
-                                     .required(false)))
-                    .subcommand(clap::SubCommand::with_name("wizard")
-                                .about("Create a new config file using the configuration wizard."))
-                    .subcommand(clap::SubCommand::with_name("example")
-                                .about("Print a template configuration file to standard output"))
-                    .subcommand(clap::SubCommand::with_name("schema-version")
-                                .about("Get/set the agent.db SQLite database schema version (user_version)")
-                                .arg(clap::Arg::with_name("version")
-                                    .value_name("version")
-                                    .required(false)
-                                    .hidden(cfg!(not(debug_assertions)))
-                                    .takes_value(true))))
-        .subcommand(clap::SubCommand::with_name("create-collection")
-                    .about("Create a new collection")
-                    .long_about("Create a new collection.")
-                    .arg(clap::Arg::with_name("name")
-                         .value_name("name")
-                         .takes_value(true)
-                         .required(true)
-                         .index(1)
-                         .help("A collection name"))
-                    .arg(clap::Arg::with_name("dataset")
-                         .long("dataset")
-                         .value_name("dataset")
-                         .takes_value(true)
-                         .default_value(fallback_dataset)
-                         .validator(id_nonempty)
-                         .help(concat!(
-                                 "A dataset ID or name.\n",
-                                 "Example: --dataset=N:dataset:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
-                                 "         --dataset=\"My Samples\""
-                            ))))
-        .subcommand(clap::SubCommand::with_name("clear")
-                    .about("Clear the current working dataset")
-                    .long_about("Clear the current working dataset.")
-                    .display_order(2))
-        .subcommand(clap::SubCommand::with_name("collaborators")
-                    .about("List the collaborators of a dataset")
-                    .long_about("List the collaborators of a dataset.")
-                    .arg(clap::Arg::with_name("dataset")
-                         .long("dataset")
-                         .value_name("dataset")
-                         .takes_value(true)
-                         .global(true)
-                         .default_value(fallback_dataset)
-                         .validator(id_nonempty)
-                         .help(concat!(
-                                 "A dataset ID or name.\n",
-                                 "Example: --dataset=N:dataset:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
-                                 "         --dataset=\"My Samples\""
-                            )))
-                    .subcommand(clap::SubCommand::with_name("users")
-                                .about("List all user collaborators.")
-                                .long_about("List all users and their permission level on the given dataset."))
-                    .subcommand(clap::SubCommand::with_name("teams")
-                                .about("List all team collaborators.")
-                                .long_about("List all teams and their permission level on the given dataset."))
-                    .subcommand(clap::SubCommand::with_name("organization")
-                                .about("Show the organization role.")
-                                .long_about("Show the role of the user's preferred organization on the given dataset.")))
-        .subcommand(clap::SubCommand::with_name("datasets")
-                    .about("List your datasets")
-                    .long_about("List your datasets.")
-                    .alias("ds"))
-        .subcommand(clap::SubCommand::with_name("create-dataset")
-                    .about("Create a new dataset")
-                    .long_about("Create a new dataset.")
-                    .display_order(3)
-                    .arg(clap::Arg::with_name("name")
-                         .value_name("name")
-                         .takes_value(true)
-                         .required(true)
-                         .index(1)
-                         .help("A dataset name"))
-                    .arg(clap::Arg::with_name("description")
-                         .long("description")
-                         .required(false)
-                         .index(2)
-                         .help("An optional description")))
-        .subcommand(clap::SubCommand::with_name("ls")
-                    .about("Provides navigation around datasets and collections")
-                    .long_about("Provides navigation around datasets and collections.")
-                    .arg(clap::Arg::with_name("dataset")
-                         .long("dataset")
-                         .value_name("dataset")
-                         .takes_value(true)
-                         .default_value(fallback_dataset)
-                         .validator(id_nonempty)
-                         .help(concat!(
-                                 "A dataset ID or name.\n",
-                                 "Example: --dataset=N:dataset:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
-                                 "         --dataset=\"My Samples\""
-                            )))
-                    .arg(clap::Arg::with_name("collection")
-                         .long("collection")
-                         .value_name("collection")
-                         .takes_value(true)
-                         .help("A package ID.\nExample: --collection=N:collection:1234abcd-1234-abcd-efef-a0b1c2d3e4f5")))
-
-        .subcommand(clap::SubCommand::with_name("move")
-                    .alias("mv")
-                    .about("Move packages and collections")
-                    .long_about("Move packages and collections")
-                    .arg(clap::Arg::with_name("source")
-                         .long("source")
-                         .value_name("source")
-                         .required(true)
-                         .index(1)
-                         .help("The package or collection to move")
-                    )
-                    .arg(clap::Arg::with_name("destination")
-                         .long("destination")
-                         .value_name("destination")
-                         .required(false)
-                         .index(2)
-                         .help("The destination collection. If not provided, the source will be moved to the root of the dataset")))
-
-        .subcommand(clap::SubCommand::with_name("members")
-                    .about("List the members that are part of the organization you belong to")
-                    .long_about("List the members that are part of the organization you belong to."))
-        .subcommand(clap::SubCommand::with_name("organizations")
-                    .about("List the organizations you belong to")
-                    .long_about("List the organizations you belong to.")
-                    .alias("orgs"))
-        .subcommand(clap::SubCommand::with_name("rename")
-                    .about("Rename a package or dataset")
-                    .long_about("Rename a package or dataset.")
-                    .arg(clap::Arg::with_name("package_or_dataset_id")
-                         .value_name("id")
-                         .takes_value(true)
-                         .required(true)
-                         .index(1)
-                         .help("A package ID, or dataset ID, or dataset name"))
-                    .arg(clap::Arg::with_name("name")
-                         .value_name("name")
-                         .takes_value(true)
-                         .required(true)
-                         .index(2)
-                         .help("A new name")))
-        .subcommand(clap::SubCommand::with_name("server")
-                    .about("Start the Pennsieve agent in server mode")
-                    .long_about("Start the Pennsieve agent in server mode.")
-                    .arg(
-                         clap::Arg::with_name("parallelism")
-                         .long("parallelism")
-                         .value_name("parallelism")
-                         .takes_value(true)
-                         .hidden(true)
-                         .help("Parallelism level; default is the number of CPUs")))
-        .subcommand(clap::SubCommand::with_name("teams")
-                    .about("List the teams that are part of the organization you belong to")
-                    .long_about("List the teams that are part of the organization you belong to."))
-        .subcommand(upload_command!(fallback_dataset))
-        .subcommand(clap::SubCommand::with_name("profile")
-                    .about("Manage profiles")
-                    .long_about("Manage profiles.")
-                    .subcommand(clap::SubCommand::with_name("show")
-                                .about("Show current active profile"))
-                    .subcommand(clap::SubCommand::with_name("switch")
-                                .about("Switch to a new profile")
-                                .arg(clap::Arg::with_name("profile")
-                                     .value_name("profile")
-                                     .required(true)
-                                     .takes_value(true)
-                                     .validator(profile_exists)
-                                     .index(1)
-                                     .help("The target profile")))
-                    .subcommand(clap::SubCommand::with_name("create")
-                                .about("Create a new profile"))
-                    .subcommand(clap::SubCommand::with_name("delete")
-                                .about("Delete a profile")
-                                .arg(clap::Arg::with_name("profile")
-                                     .value_name("profile")
-                                     .required(true)
-                                     .takes_value(true)
-                                     .validator(profile_exists)
-                                     .index(1)
-                                     .help("The profile to be deleted")))
-                    .subcommand(clap::SubCommand::with_name("set-default")
-                                .about("Set a profile as the default")
-                                .arg(clap::Arg::with_name("profile")
-                                     .value_name("profile")
-                                     .required(true)
-                                     .takes_value(true)
-                                     .validator(profile_exists)
-                                     .index(1)
-                                     .help("The profile to use as new default")))
-                    .subcommand(clap::SubCommand::with_name("list")
-                                .about("Display a list of available profiles")))
-        .subcommand(clap::SubCommand::with_name("upload-status")
-                    .about("Check the upload status of files")
-                    .long_about("Check the upload status of files; resume and cancel uploads.")
-                    .arg(clap::Arg::with_name("cancel")
-                            .long("cancel")
-                            .value_name("ID")
-                            .multiple(true)
-                            .takes_value(true)
-                            .help("Cancel an upload by its ID"))
-                    .arg(clap::Arg::with_name("cancel_pending")
-                            .long("cancel-pending")
-                            .value_name("cancel-pending")
-                            .takes_value(false)
-                            .help("Cancel all pending uploads"))
-                    .arg(clap::Arg::with_name("cancel_all")
-                            .long("cancel-all")
-                            .value_name("cancel-all")
-                            .takes_value(false)
-                            .help("Cancel all uploads, regardless of status"))
-                    .arg(clap::Arg::with_name("retry")
-                            .long("retry")
-                            .value_name("ID")
-                            .multiple(true)
-                            .takes_value(true)
-                            .help("Retry an upload by ID"))
-                    .arg(clap::Arg::with_name("resume")
-                         .long("resume")
-                         .help("Resume queued uploads"))
-                    .arg(clap::Arg::with_name("failed")
-                         .long("failed")
-                         .help("View failed uploads"))
-                    .arg(clap::Arg::with_name("completed")
-                         .long("completed")
-                         .value_name("completed")
-                         .validator(is_numeric)
-                         .takes_value(true)
-                         .help("View last N completed uploads"))
-                    .arg(clap::Arg::with_name("listen")
-                         .long("listen")
-                         .takes_value(false)
-                         .help(concat!("Listens for incoming uploads and does not terminate upon upload completion.\n",
-                                       "This mode is useful for scripting the upload behavior of the Pennsieve command line tool \n",
-                                       "by sending files to be uploaded over a websocket.")))
-                    .arg(clap::Arg::with_name("port")
-                         .long("port")
-                         .takes_value(true)
-                         .requires("listen")
-                         .help("The port to listen on"))
-                    .arg(clap::Arg::with_name("parallelism")
-                         .long("parallelism")
-                         .value_name("parallelism")
-                         .takes_value(true)
-                         .hidden(true)
-                         .help("Parallelism level; default is the number of CPUs")))
-        .subcommand(clap::SubCommand::with_name("upload-verify")
-                    .about("Verify the integrity of files on the platform")
-                    .long_about(concat!("Verify that local files match uploaded files in the platform.\n",
-                                        "If a local filepath is not specified, the local file that was ",
-                                        "originally uploaded will be used to verify."))
-                    .arg(clap::Arg::with_name("id")
-                            .short("i")
-                            .long("upload-id")
-                            .value_name("ID")
-                            .takes_value(true)
-                            .validator(is_numeric)
-                            .required(true)
-                            .help("The ID of the uploaded file, as it appears in `upload-status --completed N`"))
-                    .arg(clap::Arg::with_name("path")
-                            .short("f")
-                            .long("path")
-                            .value_name("PATH")
-                            .takes_value(true)
-                            .validator(file_exists)
-                            .help("An optional local file to check against the uploaded file.")))
-        .subcommand(clap::SubCommand::with_name("use")
-                    .about("Set your current working dataset")
-                    .long_about("Set your current working dataset.")
-                    .display_order(1)
-                    .arg(clap::Arg::with_name("dataset")
-                         .value_name("dataset")
-                         .takes_value(true)
-                         .index(1)
-                         .help("A dataset's ID or name. If omitted, the current dataset will be printed.")))
-        .subcommand(clap::SubCommand::with_name("version")
-            .about("Print the current version number")
-            .long_about("Print the current version number."))
-        .subcommand(clap::SubCommand::with_name("where")
-                    .about("Show the path to a package or dataset")
-                    .long_about("Show the path to a package or dataset.")
-                    .arg(clap::Arg::with_name("package_or_dataset_id")
-                         .value_name("id")
-                         .takes_value(true)
-                         .default_value(fallback_dataset)
-                         .validator(id_nonempty)
-                         .index(1)
-                         .help("A package or collection ID")))
-        .subcommand(clap::SubCommand::with_name("whoami")
-                    .about("Displays information about the logged in user")
-                    .long_about("Displays information about the logged in user."));
+    let mut app = cli::build_app(fallback_dataset);
 
     // Get the raw argument count:
     let raw_arg_count = env::args().count();
@@ -1141,6 +1053,42 @@ fn main() {
 
     context.set_output(output);
 
+    // Which table columns should be shown?
+    let columns = args
+        .value_of("columns")
+        .map(cli::Columns::parse)
+        .unwrap_or_default();
+
+    context.set_columns(columns);
+
+    // Bound the time requests to the platform may take, if asked:
+    let timeout = args
+        .value_of("timeout")
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .map(time::Duration::from_secs);
+
+    context.set_timeout(timeout);
+
+    // Resolve datasets strictly by ID or by name, if asked:
+    let dataset_lookup = if args.is_present("dataset_by_id") {
+        api::DatasetLookup::Id
+    } else if args.is_present("dataset_by_name_only") {
+        api::DatasetLookup::Name
+    } else {
+        api::DatasetLookup::IdOrName
+    };
+
+    context.set_dataset_lookup(dataset_lookup);
+    context.set_name_cache(!args.is_present("no_cache"));
+    context.set_organization(args.value_of("organization").map(String::from));
+    context.set_environment(
+        args.value_of("env")
+            .and_then(|environment| environment.parse::<ApiEnvironment>().ok()),
+    );
+    context.set_verbosity(verbosity);
+    context.set_no_color(args.is_present("no_color"));
+
     let matches = match app.get_matches_from_safe_borrow(&mut env::args()) {
         Ok(matches) => matches,
         Err(e) => {
@@ -1149,7 +1097,42 @@ fn main() {
         }
     };
 
+    // `--columns` only applies to the commands that print tables:
+    if matches.is_present("columns") && !supports_columns(&matches) {
+        eprintln!(
+            "{}",
+            cli::Error::columns_not_supported(matches.subcommand_name().unwrap_or_default())
+        );
+        exit(1);
+    }
+
+    // `--no-progress` is only defined on the upload commands:
+    context.set_no_progress(
+        matches
+            .subcommand()
+            .1
+            .map_or(false, |args| args.is_present("no_progress")),
+    );
+    // ...as is `--upload-log`:
+    context.set_upload_log_dir(
+        matches
+            .subcommand()
+            .1
+            .and_then(|args| args.value_of("upload_log"))
+            .map(PathBuf::from),
+    );
+    // `--manifest-output` is only defined on `upload`:
+    context.set_manifest_output(
+        matches
+            .subcommand()
+            .1
+            .and_then(|args| args.value_of("manifest_output"))
+            .map(PathBuf::from),
+    );
+
     let db = context.db.clone();
+    let update_check =
+        ps::version::UpdateCheck::from_config(&context.get_config().unwrap_or_default());
 
     let system = System::new("ps:main");
 
@@ -1163,28 +1146,160 @@ fn main() {
             let package = args.value_of("package");
             let recursive = args.is_present("recursive");
             let force = args.is_present("force");
-            let parallelism = parallelism_level(args.value_of("parallelism"));
-
-            cli.queue_uploads(files, dataset, package, true, force, recursive)
-                .and_then(move |_| {
-                    context.uploading(
-                        cli,
-                        StartMode::NoEmptyQueue,
-                        StopMode::OnFinish,
-                        parallelism,
+            let force_reappend = args.is_present("force_reappend");
+            let follow_symlinks = args.is_present("follow_symlinks");
+            let skip_empty = args.is_present("skip_empty");
+            let priority = value_t!(args, "priority", i32).unwrap_or(0);
+            let tags = upload_tags(args);
+            let chunk_size = value_t!(args, "chunk_size", u64).ok();
+            let parallelism = upload_parallelism(args);
+
+            if args.is_present("dry_run") {
+                run_then_exit!(cli.dry_run_uploads(
+                    files,
+                    dataset,
+                    package,
+                    recursive,
+                    follow_symlinks,
+                    skip_empty
+                ))
+            } else {
+                to_future_trait(
+                    cli.queue_uploads(
+                        files,
+                        dataset,
+                        package,
+                        true,
+                        force,
+                        force_reappend,
+                        recursive,
+                        follow_symlinks,
+                        skip_empty,
+                        false,
+                        priority,
+                        tags,
+                        chunk_size,
+                        false,
                     )
-                })
+                    .and_then(move |_| {
+                        context.uploading(
+                            cli,
+                            StartMode::NoEmptyQueue,
+                            StopMode::OnFinish,
+                            parallelism,
+                            None,
+                        )
+                    }),
+                )
+            }
         }),
         ("clear", _) => with_cli!(context, cli, {
             run_then_exit!(cli.clear_settings_dataset())
         }),
         ("create-collection", Some(args)) => with_cli!(context, cli, {
-            run_then_exit!(cli.create_collection(
-                args.value_of("name").unwrap(),
-                args.value_of("dataset").unwrap(),
-            ))
+            if args.is_present("path") {
+                run_then_exit!(cli.create_collection_path(
+                    args.value_of("name").unwrap(),
+                    args.value_of("dataset").unwrap(),
+                    args.is_present("id_only"),
+                ))
+            } else {
+                run_then_exit!(cli.create_collection(
+                    args.value_of("name").unwrap(),
+                    args.value_of("dataset").unwrap(),
+                    args.is_present("id_only"),
+                ))
+            }
         }),
+        ("cache", Some(cache_matches)) => match cache_matches.subcommand() {
+            ("prefetch", Some(args)) => {
+                let rate = value_t!(args, "rate", f64).unwrap_or_else(|e| e.exit());
+                let start = value_t!(args, "start", u64).unwrap_or_else(|e| e.exit());
+                let end = value_t!(args, "end", u64).unwrap_or_else(|e| e.exit());
+                if start >= end {
+                    eprintln!("--start must be before --end");
+                    exit(1)
+                }
+                let channels = args
+                    .values_of("channels")
+                    .unwrap()
+                    .map(|id| cache::Channel::new(id, rate))
+                    .collect();
+                let request = cache::Request::new(
+                    args.value_of("package").unwrap(),
+                    channels,
+                    start,
+                    end,
+                    0,
+                    true,
+                );
+                match context.get_config() {
+                    Ok(config) => with_cli!(context, cli, {
+                        run_then_exit!(cli.prefetch_cache(&config, request))
+                    }),
+                    Err(e) => run_then_exit!(future::err::<(), _>(e).into_trait()),
+                }
+            }
+            ("export", Some(args)) => {
+                let rate = value_t!(args, "rate", f64).unwrap_or_else(|e| e.exit());
+                let start = value_t!(args, "start", u64).unwrap_or_else(|e| e.exit());
+                let end = value_t!(args, "end", u64).unwrap_or_else(|e| e.exit());
+                let format =
+                    value_t!(args, "format", cache::ExportFormat).unwrap_or_else(|e| e.exit());
+                if start >= end {
+                    eprintln!("--start must be before --end");
+                    exit(1)
+                }
+                let channels = args
+                    .values_of("channels")
+                    .unwrap()
+                    .map(|id| cache::Channel::new(id, rate))
+                    .collect();
+                let request = cache::Request::new(
+                    args.value_of("package").unwrap(),
+                    channels,
+                    start,
+                    end,
+                    cache::EXPORT_CHUNK_SIZE,
+                    true,
+                );
+                let dir = PathBuf::from(args.value_of("output_dir").unwrap());
+                match context.get_config() {
+                    Ok(config) => run_then_exit!(Cli::export_cache(
+                        context.db,
+                        &config.cache,
+                        request,
+                        format,
+                        dir
+                    )),
+                    Err(e) => run_then_exit!(future::err::<(), _>(e).into_trait()),
+                }
+            }
+            ("verify", Some(args)) => match context.get_config() {
+                Ok(config) => {
+                    let repair = args.is_present("repair");
+                    run_then_exit!(Cli::verify_cache(context.db, &config.cache, repair).map(
+                        |report| if !report.is_consistent() && !repair {
+                            exit(1)
+                        }
+                    ))
+                }
+                Err(e) => run_then_exit!(future::err::<(), _>(e).into_trait()),
+            },
+            _ => unreachable!(),
+        },
         ("config", Some(config_matches)) => match config_matches.subcommand() {
+            ("show", Some(args)) if args.is_present("all") => {
+                let show_secrets = args.is_present("show_secrets");
+                match context.get_config().and_then(|config| {
+                    context
+                        .get_current_profile()
+                        .map(|profile| config.effective_values(&profile, show_secrets))
+                }) {
+                    Ok(values) => run_then_exit!(Cli::print_effective_config(values)),
+                    Err(e) => run_then_exit!(future::err::<(), _>(e).into_trait()),
+                }
+            }
             ("show", Some(args)) => with_cli!(context, cli, {
                 match args.value_of("key") {
                     Some(conf_key) => run_then_exit!(cli.print_settings_value(conf_key)),
@@ -1193,6 +1308,9 @@ fn main() {
             }),
             ("example", _) => run_then_exit!(Cli::print_config_example()),
             ("wizard", _) => run_then_exit!(Cli::start_config_wizard(context.db)),
+            ("schema-version", Some(args)) if args.is_present("migrate") => {
+                with_cli!(context, cli, { run_then_exit!(cli.migrate_schema()) })
+            }
             ("schema-version", Some(args)) => match args.value_of("version") {
                 Some(schema_version) => with_cli!(context, cli, {
                     match schema_version.parse::<usize>() {
@@ -1217,38 +1335,139 @@ fn main() {
                 run_then_exit!(cli
                     .print_dataset_user_collaborators(collab_matches.value_of("dataset").unwrap()))
             }),
+            ("effective", Some(effective_matches)) => with_cli!(context, cli, {
+                run_then_exit!(cli.print_effective_dataset_permission(
+                    collab_matches.value_of("dataset").unwrap(),
+                    effective_matches.value_of("user").unwrap()
+                ))
+            }),
             _ => with_cli!(context, cli, {
                 run_then_exit!(cli
                     .print_all_dataset_collaborators(collab_matches.value_of("dataset").unwrap()))
             }),
         },
-        ("datasets", _) => with_cli!(context, cli, { run_then_exit!(cli.print_datasets()) }),
+        ("completions", Some(args)) => {
+            let shell = value_t!(args, "shell", clap::Shell).unwrap_or_else(|e| e.exit());
+            cli::build_app(fallback_dataset).gen_completions_to(
+                env!("CARGO_PKG_NAME"),
+                shell,
+                &mut io::stdout(),
+            );
+            run_then_exit!({})
+        }
+        ("datasets", Some(args)) => with_cli!(context, cli, {
+            let query = cli::ListQuery {
+                search: args.value_of("search").map(String::from),
+                offset: args
+                    .value_of("offset")
+                    .map_or(0, |offset| offset.parse::<usize>().unwrap()),
+                limit: args
+                    .value_of("limit")
+                    .map(|limit| limit.parse::<usize>().unwrap()),
+            };
+            let sort = cli::ListSort {
+                key: value_t!(args, "sort", cli::SortKey).unwrap_or_default(),
+                reverse: args.is_present("reverse"),
+            };
+            run_then_exit!(cli.print_datasets(query, sort, args.is_present("id_only")))
+        }),
         ("create-dataset", Some(args)) => with_cli!(context, cli, {
-            run_then_exit!(
-                cli.create_dataset(args.value_of("name").unwrap(), args.value_of("description"))
-            )
+            run_then_exit!(cli.create_dataset(
+                args.value_of("name").unwrap(),
+                args.value_of("description"),
+                args.value_of("description_file"),
+                args.value_of("from_template"),
+                args.is_present("id_only")
+            ))
+        }),
+        ("update-dataset", Some(args)) => with_cli!(context, cli, {
+            run_then_exit!(cli.update_dataset(
+                args.value_of("dataset").unwrap(),
+                args.value_of("name").map(String::from),
+                args.value_of("description").map(String::from),
+                args.value_of("description_file"),
+                args.values_of("tags")
+                    .map(|tags| tags.map(String::from).collect())
+            ))
+        }),
+        ("delete-dataset", Some(args)) => with_cli!(context, cli, {
+            run_then_exit!(cli.delete_dataset(
+                args.value_of("dataset").unwrap(),
+                args.is_present("force"),
+                args.is_present("recursive")
+            ))
+        }),
+        ("delete-package", Some(args)) => with_cli!(context, cli, {
+            run_then_exit!(cli.delete_package(
+                args.value_of("package").unwrap(),
+                args.is_present("force"),
+                args.is_present("recursive")
+            ))
         }),
         ("ls", Some(ls_matches)) => {
             let dataset = ls_matches.value_of("dataset");
             let collection_id = ls_matches.value_of("collection");
+            let recursive = ls_matches.is_present("recursive");
+            let id_only = ls_matches.is_present("id_only");
+            // The types were checked when the arguments were parsed:
+            let types = cli::PackageTypeFilter::new(
+                ls_matches
+                    .values_of("type")
+                    .map(|types| types.collect::<Vec<_>>())
+                    .unwrap_or_default(),
+            )
+            .unwrap();
+            let max_depth = ls_matches
+                .value_of("max_depth")
+                .map(|depth| depth.parse::<usize>().unwrap());
             with_cli!(context, cli, {
                 match (dataset, collection_id) {
-                    (_, Some(collection_id)) => run_then_exit!(cli.print_collection(collection_id)),
-                    (Some(dataset), _) => run_then_exit!(cli.print_dataset(dataset)),
-                    _ => run_then_exit!(cli.print_datasets()),
+                    (_, Some(collection_id)) if recursive => {
+                        run_then_exit!(cli.print_collection_tree(collection_id, max_depth))
+                    }
+                    (_, Some(collection_id)) => {
+                        run_then_exit!(cli.print_collection(collection_id, types, id_only))
+                    }
+                    (Some(dataset), _) if recursive => {
+                        run_then_exit!(cli.print_dataset_tree(dataset, max_depth))
+                    }
+                    (Some(dataset), _) => {
+                        run_then_exit!(cli.print_dataset(dataset, types, id_only))
+                    }
+                    _ => run_then_exit!(cli.print_datasets(
+                        cli::ListQuery::default(),
+                        cli::ListSort::default(),
+                        id_only
+                    )),
                 }
             })
         }
-        ("members", _) => with_cli!(context, cli, { run_then_exit!(cli.print_members()) }),
+        ("members", Some(args)) => {
+            let filter = cli::MemberFilter {
+                search: args.value_of("search").map(String::from),
+                role: args.value_of("role").map(String::from),
+            };
+            with_cli!(context, cli, { run_then_exit!(cli.print_members(filter)) })
+        }
         ("move", Some(mv_matches)) => {
             let source = mv_matches.value_of("source").unwrap();
             let destination = mv_matches.value_of("destination");
+            let dry_run = mv_matches.is_present("dry_run");
             with_cli!(context, cli, {
-                run_then_exit!(cli.move_package(source, destination))
+                run_then_exit!(cli.move_package(source, destination, dry_run))
             })
         }
-        ("organizations", _) => {
-            with_cli!(context, cli, { run_then_exit!(cli.print_organizations()) })
+        ("organizations", Some(args)) => {
+            let current = args.is_present("current");
+            with_cli!(context, cli, {
+                run_then_exit!(cli.print_organizations(current))
+            })
+        }
+        ("use-org", Some(args)) => {
+            let organization = args.value_of("org").unwrap().to_string();
+            with_cli!(context, cli, {
+                run_then_exit!(cli.use_organization(organization))
+            })
         }
         ("profile", Some(profile_matches)) => match profile_matches.subcommand() {
             ("switch", Some(args)) => {
@@ -1305,6 +1524,59 @@ Unset these variables in order to use profiles from config.ini:
                     .map_err(Into::into)
                     .into_future())
             }
+            ("rename", Some(args)) => {
+                let old_name = args.value_of("profile").unwrap().to_string();
+                let new_name = args.value_of("new_name").unwrap().to_string();
+                let db = context.db.clone();
+                run_then_exit!(Config::from_config_file_and_environment()
+                    .and_then(|mut config| config::api::rename_profile(
+                        &mut config.api_settings,
+                        old_name.clone(),
+                        new_name.clone()
+                    )
+                    .and_then(|_| config.write_to_config_file()))
+                    .map_err(ps::Error::from)
+                    .and_then(|_| db
+                        .rename_profile(&old_name, &new_name)
+                        .map_err(ps::Error::from))
+                    .map(|_| println!("Renamed profile '{}' to '{}'", old_name, new_name))
+                    .into_future())
+            }
+            ("export", Some(args)) => {
+                let profile = args.value_of("profile").unwrap().to_string();
+                let output = args
+                    .value_of("output")
+                    .map(String::from)
+                    .unwrap_or_else(|| format!("{}.json", profile));
+                let include_secrets = args.is_present("include_secrets");
+                let force = args.is_present("force");
+                run_then_exit!(Config::from_config_file_and_environment()
+                    .and_then(|config| config::api::export_profile(
+                        &config.api_settings,
+                        profile.clone(),
+                        include_secrets
+                    ))
+                    .and_then(|export| export.write_to(&output, force))
+                    .map(|_| println!("Exported profile '{}' to {}", profile, output))
+                    .map_err(Into::into)
+                    .into_future())
+            }
+            ("import", Some(args)) => {
+                let path = args.value_of("file").unwrap();
+                let force = args.is_present("force");
+                run_then_exit!(Config::from_config_file_and_environment()
+                    .and_then(|mut config| config::api::ProfileExport::from_path(path)
+                        .and_then(|export| config::api::import_profile(
+                            &mut config.api_settings,
+                            export,
+                            force
+                        ))
+                        .and_then(|profile| config
+                            .write_to_config_file()
+                            .map(|_| println!("Imported profile '{}'", profile.profile))))
+                    .map_err(Into::into)
+                    .into_future())
+            }
             ("list", _) => run_then_exit!(Config::from_config_file_and_environment()
                 .map(|config| println!(
                     "Profiles: \n  {}",
@@ -1321,14 +1593,34 @@ Unset these variables in order to use profiles from config.ini:
         ("rename", Some(args)) => with_cli!(context, cli, {
             run_then_exit!(cli.rename(
                 args.value_of("package_or_dataset_id").unwrap(),
-                args.value_of("name").unwrap()
+                args.value_of("name").unwrap(),
+                args.is_present("force"),
+                args.is_present("dry_run")
             ))
         }),
-        ("server", Some(args)) => {
-            let parallelism = parallelism_level(args.value_of("parallelism"));
+        ("server", Some(args)) => match args.subcommand() {
+            ("status", Some(args)) => {
+                let path = PathBuf::from(args.value_of("pid_file").unwrap());
+                run_then_exit!(server::PidStatus::read(path)
+                    .map(|status| {
+                        println!("{}", status);
+                        if !status.is_running() {
+                            exit(1)
+                        }
+                    })
+                    .map_err(ps::Error::from)
+                    .into_future())
+            }
+            _ => {
+                let parallelism = upload_parallelism(args);
+                let idle_timeout = idle_timeout(args);
+                let pid_file = args.value_of("pid_file").map(PathBuf::from);
 
-            run!(context.start_server_mode(parallelism).into_future())
-        }
+                run!(context
+                    .start_server_mode(parallelism, idle_timeout, pid_file)
+                    .into_future())
+            }
+        },
         ("teams", _) => with_cli!(context, cli, { run_then_exit!(cli.print_teams()) }),
         ("upload", Some(args)) => with_cli!(context, cli, {
             let files = args
@@ -1338,26 +1630,93 @@ Unset these variables in order to use profiles from config.ini:
             let dataset = args.value_of("dataset");
             let package = args.value_of("folder"); // folder == package
             let recursive = args.is_present("recursive");
+            let follow_symlinks = args.is_present("follow_symlinks");
+            let skip_empty = args.is_present("skip_empty");
+            let skip_existing = args.is_present("skip_existing");
             let force = args.is_present("force");
-            let parallelism = parallelism_level(args.value_of("parallelism"));
+            let priority = value_t!(args, "priority", i32).unwrap_or(0);
+            let tags = upload_tags(args);
+            let chunk_size = value_t!(args, "chunk_size", u64).ok();
+            let parallelism = upload_parallelism(args);
+            // files are only hashed as they're queued if there's a manifest to write:
+            let checksums = args.is_present("manifest_output");
 
             // validate the upload args
             if recursive && files.len() > 1 {
                 eprintln!("Recursive uploads can only contain one path argument");
                 exit(1)
             }
-            cli.queue_uploads(files, dataset, package, false, force, recursive)
-                .and_then(move |_| {
+            let dataset_from_path = args.is_present("dataset_from_path");
+            if dataset_from_path && files.len() > 1 {
+                eprintln!("--dataset-from-path uploads can only contain one path argument");
+                exit(1)
+            }
+            if args.is_present("dry_run") {
+                run_then_exit!(cli.dry_run_uploads(
+                    files,
+                    dataset,
+                    package,
+                    recursive,
+                    follow_symlinks,
+                    skip_empty
+                ))
+            } else {
+                let queued = match (args.value_of("manifest"), args.value_of("name")) {
+                    _ if dataset_from_path => cli.queue_dataset_from_path_uploads(
+                        files[0],
+                        force,
+                        follow_symlinks,
+                        skip_empty,
+                        skip_existing,
+                        priority,
+                        tags,
+                        chunk_size,
+                        checksums,
+                    ),
+                    (Some(manifest), _) => cli.queue_manifest_uploads(
+                        manifest,
+                        dataset,
+                        force,
+                        skip_existing,
+                        priority,
+                        tags,
+                        chunk_size,
+                        checksums,
+                    ),
+                    (None, Some(name)) if args.is_present("stdin") => cli.queue_stdin_upload(
+                        name, dataset, package, priority, tags, chunk_size, checksums,
+                    ),
+                    _ => cli.queue_uploads(
+                        files,
+                        dataset,
+                        package,
+                        false,
+                        force,
+                        false,
+                        recursive,
+                        follow_symlinks,
+                        skip_empty,
+                        skip_existing,
+                        priority,
+                        tags,
+                        chunk_size,
+                        checksums,
+                    ),
+                };
+                to_future_trait(queued.and_then(move |_| {
                     context.uploading(
                         cli,
                         StartMode::NoEmptyQueue,
                         StopMode::OnFinish,
                         parallelism,
+                        None,
                     )
-                })
+                }))
+            }
         }),
         ("upload-status", Some(args)) => with_cli!(context, cli, {
-            let parallelism = parallelism_level(args.value_of("parallelism"));
+            let parallelism = upload_parallelism(args);
+            let idle_timeout = idle_timeout(args);
 
             if let Some(cancel_ids) = args.values_of("cancel") {
                 run_then_exit!(cli.cancel_uploads(strings!(cancel_ids)))
@@ -1367,11 +1726,25 @@ Unset these variables in order to use profiles from config.ini:
                         cli,
                         StartMode::NoEmptyQueue,
                         StopMode::OnFinish,
-                        parallelism
+                        parallelism,
+                        idle_timeout
                     )
                 ))
+            } else if args.is_present("retry_all") {
+                run_then_exit!(cli.requeue_all_failed_uploads().and_then(move |_| context
+                    .uploading(
+                        cli,
+                        StartMode::NoEmptyQueue,
+                        StopMode::OnFinish,
+                        parallelism,
+                        idle_timeout
+                    )))
             } else if args.is_present("cancel_all") {
                 run_then_exit!(cli.cancel_all_uploads())
+            } else if let Some(dataset) = args.value_of("cancel_dataset") {
+                run_then_exit!(cli.cancel_dataset_uploads(dataset))
+            } else if let Some(import_id) = args.value_of("cancel_import") {
+                run_then_exit!(cli.cancel_import_uploads(import_id))
             } else if args.is_present("cancel_pending") {
                 run_then_exit!(cli.cancel_pending_uploads())
             } else if args.is_present("listen") {
@@ -1380,54 +1753,99 @@ Unset these variables in order to use profiles from config.ini:
                     cli,
                     StartMode::AllowEmptyQueue(port),
                     StopMode::Never,
-                    parallelism
+                    parallelism,
+                    None
                 ))
             } else if args.is_present("resume") {
                 run!(context.uploading(
                     cli,
                     StartMode::NoEmptyQueue,
                     StopMode::OnFinish,
-                    parallelism
+                    parallelism,
+                    idle_timeout
                 ))
-            } else if let Some(num) = args.value_of("completed") {
-                run_then_exit!(cli.most_recently_completed_uploads(num.parse::<usize>().unwrap()))
+            } else if args.is_present("completed") {
+                let num = value_t!(args, "completed", usize).ok();
+                let since = args
+                    .value_of("since")
+                    .and_then(|since| parse_date_bound(since, Bound::Start));
+                let until = args
+                    .value_of("until")
+                    .and_then(|until| parse_date_bound(until, Bound::End));
+                run_then_exit!(cli.completed_uploads(num, since, until))
+            } else if args.is_present("export") {
+                let format =
+                    value_t!(args, "export", cli::ExportFormat).unwrap_or_else(|e| e.exit());
+                let status = args
+                    .value_of("status")
+                    .map(|status| status.parse::<UploadStatus>().unwrap());
+                run_then_exit!(cli.export_uploads(format, status))
+            } else if let Some(id) = args.value_of("detail") {
+                run_then_exit!(cli.upload_detail(id.parse::<usize>().unwrap()))
+            } else if args.is_present("summary") {
+                run_then_exit!(cli.upload_summary())
             } else if args.is_present("failed") {
                 run_then_exit!(cli.failed_uploads())
+            } else if args.is_present("watch") {
+                let interval = value_t!(args, "watch", f64)
+                    .map(time::Duration::from_secs_f64)
+                    .unwrap_or_else(|_| {
+                        time::Duration::from_millis(c::UPLOAD_PROGRESS_REFRESH_INTERVAL_MS)
+                    });
+                run_then_exit!(cli.watch_active_uploads(interval))
             } else {
                 run_then_exit!(cli.active_uploads())
             }
         }),
         ("upload-verify", Some(args)) => with_cli!(context, cli, {
-            let upload_id = args.value_of("id").unwrap().parse::<usize>().unwrap();
-            let file_path = args.value_of("path").map(PathBuf::from);
-
-            run_then_exit!(cli
-                .verify_upload(upload_id, file_path)
-                .map(move |_| println!("Verified upload {}.", upload_id))
-                .map_err(|e| match e.kind() {
-                    ErrorKind::CliError {
-                        kind: cli::ErrorKind::UploadDoesNotMatch { path: local_path },
-                    } => {
-                        eprintln!(
-                            "Local file does not match file on the Pennsieve platform: {:?}",
-                            local_path
-                        );
-                        exit(1)
-                    }
-                    _ => exit(e.render()),
-                }))
+            if args.is_present("all") || args.is_present("dataset") {
+                let dataset_id = args.value_of("dataset").map(String::from);
+                let parallelism = parallelism_level(args);
+
+                run_then_exit!(cli
+                    .verify_uploads(dataset_id, parallelism)
+                    .map(|summary| if !summary.is_ok() {
+                        exit(ExitCode::UploadError.into())
+                    })
+                    .map_err(verify_failed))
+            } else {
+                let upload_id = args.value_of("id").unwrap().parse::<usize>().unwrap();
+                let file_path = args.value_of("path").map(PathBuf::from);
+                let search_dir = args.value_of("search_dir").map(PathBuf::from);
+
+                run_then_exit!(cli
+                    .verify_upload(upload_id, file_path, search_dir)
+                    .map(move |_| println!("Verified upload {}.", upload_id))
+                    .map_err(verify_failed))
+            }
         }),
         ("use", Some(args)) => with_cli!(context, cli, {
             match args.value_of("dataset") {
                 Some(id) => run_then_exit!(cli.set_settings_dataset(id)),
+                None if args.is_present("clear") => run_then_exit!(cli.clear_settings_dataset()),
                 None => run_then_exit!(cli.print_settings_dataset()),
             }
         }),
-        ("version", _) => run_then_exit!({ println!("{}", env!("CARGO_PKG_VERSION")) }),
+        ("version", Some(args)) if args.is_present("check") => {
+            run_then_exit!(ps::version::check_version_now(context.db).map(|check| {
+                println!("{}", check);
+            }))
+        }
+        ("version", Some(args)) => {
+            let format =
+                value_t!(args, "format", ps::version::VersionFormat).unwrap_or_else(|e| e.exit());
+            run_then_exit!({ println!("{}", ps::version::BuildInfo::current().render(format)) })
+        }
         ("where", Some(args)) => with_cli!(context, cli, {
-            run_then_exit!(cli.where_(args.value_of("package_or_dataset_id").unwrap()))
+            run_then_exit!(cli.where_(
+                args.value_of("package_or_dataset_id").unwrap(),
+                args.is_present("id_only"),
+                args.is_present("copy")
+            ))
+        }),
+        ("whoami", Some(args)) => with_cli!(context, cli, {
+            run_then_exit!(cli.print_whoami(args.is_present("refresh")))
         }),
-        ("whoami", Some(_)) => with_cli!(context, cli, { run_then_exit!(cli.print_whoami()) }),
         _ => {
             // Calling this will result in a panic. See clap issue
             // https://github.com/clap-rs/clap/issues/1356
@@ -1443,7 +1861,7 @@ Unset these variables in order to use profiles from config.ini:
 
     // Check for new agent version before anything else
     // Ignore any errors and log a warning
-    let fut = ps::version::check_for_new_version(db)
+    let fut = ps::version::check_for_new_version(db, update_check)
         .then(|result| {
             if let Err(e) = result {
                 info!("{}", e.kind());
@@ -41,6 +41,40 @@ impl Error {
         }
         .into()
     }
+
+    pub fn timeout(seconds: u64) -> Error {
+        ErrorKind::Timeout { seconds }.into()
+    }
+
+    pub fn not_an_organization_member<S: Into<String>>(organization: S) -> Error {
+        ErrorKind::NotAnOrganizationMember {
+            organization: organization.into(),
+        }
+        .into()
+    }
+
+    pub fn unauthorized<S: Into<String>>(profile: S) -> Error {
+        ErrorKind::Unauthorized {
+            profile: profile.into(),
+        }
+        .into()
+    }
+
+    pub fn invalid_dataset_id<S: Into<String>>(id: S) -> Error {
+        ErrorKind::InvalidDatasetId { id: id.into() }.into()
+    }
+
+    pub fn dataset_name_not_found<S: Into<String>>(name: S) -> Error {
+        ErrorKind::DatasetNameNotFound { name: name.into() }.into()
+    }
+
+    pub fn ambiguous_dataset_name<S: Into<String>>(name: S, ids: Vec<String>) -> Error {
+        ErrorKind::AmbiguousDatasetName {
+            name: name.into(),
+            ids: ids.join(", "),
+        }
+        .into()
+    }
 }
 
 impl Fail for Error {
@@ -111,6 +145,30 @@ pub enum ErrorKind {
 
     #[fail(display = "Pennsieve error: {:?}", error)]
     Pennsieve { error: String },
+
+    #[fail(display = "Request timed out after {} seconds", seconds)]
+    Timeout { seconds: u64 },
+
+    #[fail(display = "Not a dataset ID: {}", id)]
+    InvalidDatasetId { id: String },
+
+    #[fail(display = "No dataset named \"{}\"", name)]
+    DatasetNameNotFound { name: String },
+
+    #[fail(
+        display = "More than one dataset is named \"{}\"; use one of the IDs instead: {}",
+        name, ids
+    )]
+    AmbiguousDatasetName { name: String, ids: String },
+
+    #[fail(display = "You are not a member of the organization: {}", organization)]
+    NotAnOrganizationMember { organization: String },
+
+    #[fail(
+        display = "The Pennsieve platform rejected the credentials of profile \"{}\"; they may have been rotated or revoked. Run `pennsieve profile switch` or `pennsieve config wizard` to log in again",
+        profile
+    )]
+    Unauthorized { profile: String },
 }
 
 impl From<ErrorKind> for Error {
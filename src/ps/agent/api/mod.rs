@@ -1,24 +1,30 @@
 //! Agent api composes the `Pennsieve-rust` crate and the local
 //! `Database` instance.
 
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{iter, result};
 
 use futures::*;
 use futures::{Future as _Future, IntoFuture};
+use log::*;
+use pennsieve_macros::try_future;
+use tokio::timer::{Delay, Timeout};
 
 use pennsieve_rust::api::response;
-use pennsieve_rust::{model, Config, Environment as ApiEnvironment, Pennsieve};
+use pennsieve_rust::{model, Config, Pennsieve};
 
 use crate::ps::agent;
 pub use crate::ps::agent::api::error::{Error, ErrorKind, Result};
 use crate::ps::agent::config::api::ProfileConfig;
-use crate::ps::agent::config::constants::ENVIRONMENT_OVERRIDE_PROFILE;
+use crate::ps::agent::config::constants::{API_RETRY_BACKOFF_MS, ENVIRONMENT_OVERRIDE_PROFILE};
 use crate::ps::agent::config::Config as AgentConfig;
-use crate::ps::agent::database::{Database, UploadRecord, UploadRecords, UserRecord};
+use crate::ps::agent::database::{Database, UploadRecord, UploadRecords, UploadStatus, UserRecord};
 use crate::ps::agent::messages::Response;
-use crate::ps::agent::{server, upload, Future};
+use crate::ps::agent::{cli, server, upload, Future};
 use crate::ps::util::futures::{to_future_trait, PSFuture};
 use crate::ps::util::{actor as a, strings as s};
 
@@ -60,11 +66,187 @@ impl Validator for SimplePackageValidator {
     }
 }
 
+/// Receives the files left out of an upload while its files are queued.
+pub trait SkipReporter: Send + Sync + 'static {
+    fn skipped(&self, skipped: upload::Skipped);
+}
+
+/// Logs the files left out of uploads enqueued by an external process, as
+/// there is no one to tell.
+struct LogSkipped;
+
+impl SkipReporter for LogSkipped {
+    fn skipped(&self, skipped: upload::Skipped) {
+        info!("skipped queueing a file: {:?}", skipped);
+    }
+}
+
+/// How a dataset argument is interpreted when it is resolved to a dataset.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DatasetLookup {
+    /// Dataset node IDs are looked up by ID, anything else by name.
+    IdOrName,
+    /// The argument must be a dataset node ID.
+    Id,
+    /// The argument is a dataset name, even if it looks like a node ID.
+    Name,
+}
+
+impl Default for DatasetLookup {
+    fn default() -> Self {
+        DatasetLookup::IdOrName
+    }
+}
+
+impl DatasetLookup {
+    /// Returns true if `id_or_name` is resolved by name, or fails if it
+    /// must be a dataset ID but does not look like one.
+    pub fn by_name(self, id_or_name: &str) -> Result<bool> {
+        let is_id = s::looks_like_dataset_node_id(id_or_name);
+        match self {
+            DatasetLookup::IdOrName => Ok(!is_id),
+            DatasetLookup::Id if is_id => Ok(false),
+            DatasetLookup::Id => Err(Error::invalid_dataset_id(id_or_name)),
+            DatasetLookup::Name => Ok(true),
+        }
+    }
+}
+
+/// Finds the ID of the one dataset named `name` among `datasets`, given as
+/// (ID, name) pairs. If several datasets share the name, the error lists
+/// their IDs rather than picking one of them.
+pub fn dataset_id_by_name<I>(name: &str, datasets: I) -> Result<String>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut ids: Vec<String> = datasets
+        .into_iter()
+        .filter(|(_, dataset_name)| dataset_name == name)
+        .map(|(id, _)| id)
+        .collect();
+    match ids.len() {
+        0 => Err(Error::dataset_name_not_found(name)),
+        1 => Ok(ids.remove(0)),
+        _ => {
+            ids.sort();
+            Err(Error::ambiguous_dataset_name(name, ids))
+        }
+    }
+}
+
+/// Resolves `name` in the organization to a node ID with `lookup`, unless
+/// `db` remembers it being resolved within the last `ttl`. Without a `ttl`,
+/// `lookup` is always used and nothing is remembered.
+fn resolve_name<F>(
+    db: Database,
+    organization_id: String,
+    name: String,
+    ttl: Option<Duration>,
+    lookup: F,
+) -> Future<String>
+where
+    F: FnOnce() -> Future<String>,
+{
+    let ttl = match ttl {
+        Some(ttl) => ttl,
+        None => return lookup(),
+    };
+
+    let now = time::now().to_timespec();
+    let since = time::Timespec::new(
+        now.sec
+            .saturating_sub(ttl.as_secs().min(i64::max_value() as u64) as i64),
+        now.nsec,
+    );
+    match db.get_resolved_name(&organization_id, &name, since) {
+        Ok(Some(node_id)) => {
+            debug!("resolved {:?} to {} from the cache", name, node_id);
+            return future::ok(node_id).into_trait();
+        }
+        Ok(None) => {}
+        Err(e) => warn!("couldn't read the name cache: {}", e),
+    }
+
+    lookup()
+        .map(move |node_id| {
+            if let Err(e) = db.upsert_resolved_name(&organization_id, &name, &node_id) {
+                warn!("couldn't update the name cache: {}", e);
+            }
+            node_id
+        })
+        .into_trait()
+}
+
+/// An organization the user can scope their operations to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OrganizationScope {
+    pub id: String,
+    pub name: String,
+    pub encryption_key: String,
+}
+
+impl OrganizationScope {
+    pub fn new<I, N, E>(id: I, name: N, encryption_key: E) -> Self
+    where
+        I: Into<String>,
+        N: Into<String>,
+        E: Into<String>,
+    {
+        Self {
+            id: id.into(),
+            name: name.into(),
+            encryption_key: encryption_key.into(),
+        }
+    }
+
+    /// Rescopes `user` to this organization.
+    pub fn scope(&self, mut user: UserRecord) -> UserRecord {
+        user.organization_id = self.id.clone();
+        user.organization_name = self.name.clone();
+        user.encryption_key = self.encryption_key.clone();
+        user
+    }
+}
+
+/// The organizations the user is a member of, as scopes.
+fn organization_scopes(organizations: response::Organizations) -> Vec<OrganizationScope> {
+    organizations
+        .into_iter()
+        .map(|org| {
+            let o = org.organization();
+            OrganizationScope::new(o.id(), o.name().clone(), o.encryption_key_id())
+        })
+        .collect()
+}
+
+/// Finds the organization with the ID or name `id_or_name` among the
+/// organizations the user is a member of.
+pub fn find_organization<I>(id_or_name: &str, organizations: I) -> Result<OrganizationScope>
+where
+    I: IntoIterator<Item = OrganizationScope>,
+{
+    let organizations: Vec<OrganizationScope> = organizations.into_iter().collect();
+    organizations
+        .iter()
+        .find(|o| o.id == id_or_name)
+        .or_else(|| organizations.iter().find(|o| o.name == id_or_name))
+        .cloned()
+        .ok_or_else(|| Error::not_an_organization_member(id_or_name))
+}
+
 #[derive(Clone)]
 pub struct Api {
     ps: Pennsieve,
     db: Database,
     config: AgentConfig,
+    timeout: Option<Duration>,
+    dataset_lookup: DatasetLookup,
+    // The organization requested with `with_organization`, and the scope it
+    // resolved to once the user's membership was checked:
+    organization: Option<String>,
+    organization_scope: Arc<Mutex<Option<OrganizationScope>>>,
+    rate_limiter: RateLimiter,
+    name_cache: bool,
 }
 
 /// The result of a renaming operation
@@ -74,17 +256,487 @@ pub struct Renamed {
     pub new_name: String,
 }
 
+/// A dataset or package resolved for renaming, along with the names of the
+/// items it must not collide with: the other datasets for a dataset, or
+/// the other packages in the same collection for a package.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RenameTarget {
+    pub id: String,
+    pub name: String,
+    pub is_dataset: bool,
+    pub sibling_names: Vec<String>,
+}
+
+/// Returns true if an error is likely to be transient, such as a network
+/// failure, a timeout or a 5xx response from the Pennsieve platform.
+fn is_retriable(error: &agent::Error) -> bool {
+    fn is_retriable_pennsieve(kind: &pennsieve_rust::ErrorKind) -> bool {
+        match kind {
+            pennsieve_rust::ErrorKind::ApiError { status_code, .. } => {
+                status_code.is_server_error()
+                    || *status_code == hyper::StatusCode::TOO_MANY_REQUESTS
+            }
+            pennsieve_rust::ErrorKind::HyperError { .. } => true,
+            _ => false,
+        }
+    }
+
+    match error.kind() {
+        agent::ErrorKind::Pennsieve { kind } => is_retriable_pennsieve(kind),
+        agent::ErrorKind::ApiError {
+            kind: ErrorKind::Pennsieve { kind },
+        } => is_retriable_pennsieve(kind),
+        agent::ErrorKind::ApiError {
+            kind: ErrorKind::Timeout { .. },
+        } => true,
+        agent::ErrorKind::TimeoutError | agent::ErrorKind::HyperError { .. } => true,
+        _ => false,
+    }
+}
+
+/// Returns true if an error is a 404 response from the Pennsieve platform,
+/// e.g. because the requested dataset or package no longer exists.
+pub fn is_not_found(error: &agent::Error) -> bool {
+    fn is_not_found_pennsieve(kind: &pennsieve_rust::ErrorKind) -> bool {
+        match kind {
+            pennsieve_rust::ErrorKind::ApiError { status_code, .. } => {
+                *status_code == hyper::StatusCode::NOT_FOUND
+            }
+            _ => false,
+        }
+    }
+
+    match error.kind() {
+        agent::ErrorKind::Pennsieve { kind } => is_not_found_pennsieve(kind),
+        agent::ErrorKind::ApiError {
+            kind: ErrorKind::Pennsieve { kind },
+        } => is_not_found_pennsieve(kind),
+        agent::ErrorKind::ApiError {
+            kind: ErrorKind::DatasetNameNotFound { .. },
+        } => true,
+        _ => false,
+    }
+}
+
+/// Returns true if an error reports a dataset name shared by several
+/// datasets.
+fn is_ambiguous_dataset_name(error: &agent::Error) -> bool {
+    match error.kind() {
+        agent::ErrorKind::ApiError {
+            kind: ErrorKind::AmbiguousDatasetName { .. },
+        } => true,
+        _ => false,
+    }
+}
+
+/// How many requests for collections or checksums are made at once while
+/// listing the files at an upload target.
+const EXISTING_FILES_CONCURRENCY: usize = 4;
+
+/// The folders, as paths relative to an upload target, that the files of
+/// an upload at `target_paths` are placed in: "a" and "a/b" for "a/b/c".
+fn target_folders<I>(target_paths: I) -> HashSet<String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut folders = HashSet::new();
+    for path in target_paths {
+        let mut end = 0;
+        while let Some(offset) = path[end..].find('/') {
+            end += offset;
+            folders.insert(path[..end].to_string());
+            end += 1;
+        }
+    }
+    folders
+}
+
+/// Lists the path of every package among `children` and the collections
+/// nested beneath them that are among `folders`, a level at a time.
+/// Collections themselves are not listed, and other collections are not
+/// fetched.
+fn package_paths(
+    api: Api,
+    children: Vec<response::Package>,
+    folders: HashSet<String>,
+) -> Future<Vec<String>> {
+    let level: Vec<(String, response::Package)> = children
+        .into_iter()
+        .map(|child| (String::new(), child))
+        .collect();
+    let f = future::loop_fn((level, vec![]), move |(level, mut paths)| {
+        let mut collections = vec![];
+        for (prefix, child) in level {
+            let package = child.take();
+            let path = format!("{}{}", prefix, package.name());
+            let is_collection = package
+                .package_type()
+                .map(|package_type| package_type.to_string().eq_ignore_ascii_case("collection"))
+                .unwrap_or(false);
+            if !is_collection {
+                paths.push(path);
+            } else if folders.contains(&path) {
+                collections.push((path, package.id().clone()));
+            }
+        }
+        if collections.is_empty() {
+            return future::ok::<_, agent::Error>(future::Loop::Break(paths)).into_trait();
+        }
+
+        let api = api.clone();
+        stream::iter_ok::<_, agent::Error>(collections)
+            .map(move |(path, id)| {
+                api.get_collection(id).map(move |collection| {
+                    let prefix = format!("{}/", path);
+                    collection
+                        .children()
+                        .cloned()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|child| (prefix.clone(), child))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .buffer_unordered(EXISTING_FILES_CONCURRENCY)
+            .concat2()
+            .map(move |level| future::Loop::Continue((level, paths)))
+            .into_trait()
+    });
+    to_future_trait(f)
+}
+
+/// Sets `tags` on each of the `targets` with `set_properties`, one package
+/// at a time.
+fn tag_packages<F>(
+    targets: Vec<model::PackageId>,
+    tags: BTreeMap<String, String>,
+    set_properties: F,
+) -> Future<()>
+where
+    F: Fn(model::PackageId, BTreeMap<String, String>) -> Future<()> + Send + 'static,
+{
+    let f = stream::iter_ok::<_, agent::Error>(targets)
+        .for_each(move |id| set_properties(id, tags.clone()));
+    to_future_trait(f)
+}
+
+/// Paces requests to the Pennsieve platform to at most a given number per
+/// second, as set by `api_rate_limit_per_sec` in the agent config. Clones
+/// share the same pace, so every request made through an `Api` and its
+/// clones counts towards it.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    // The earliest time the next request may be made:
+    next: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    /// Allows `per_sec` requests a second. Zero requests a second means
+    /// requests are not paced at all.
+    pub fn new(per_sec: u32) -> Self {
+        Self {
+            interval: if per_sec == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(1) / per_sec)
+            },
+            next: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// A rate limiter that never makes requests wait.
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    /// Resolves once the next request may be made, claiming its slot.
+    pub fn acquire(&self) -> Future<()> {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return future::ok(()).into_trait(),
+        };
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = if *next > now { *next } else { now };
+            *next = slot + interval;
+            slot
+        };
+        if slot > now {
+            Delay::new(slot).map_err(Into::into).into_trait()
+        } else {
+            future::ok(()).into_trait()
+        }
+    }
+}
+
+/// Runs the request produced by `request`, retrying it up to `retries`
+/// times if it fails with a retriable error. Each attempt is paced by
+/// `limiter`, and fails with a `Timeout` error, which is retried too, if it
+/// takes longer than `timeout`. The delay between attempts starts at
+/// `backoff` and doubles after each failure. Rate limited (429) responses
+/// are backed off from the same way: the Pennsieve client doesn't expose
+/// response headers, so a `Retry-After` header can't be honored.
+pub fn retry_request<T, F>(
+    limiter: RateLimiter,
+    retries: u32,
+    backoff: Duration,
+    timeout: Option<Duration>,
+    request: F,
+) -> Future<T>
+where
+    T: 'static + Send,
+    F: 'static + Send + Fn() -> Future<T>,
+{
+    retry_attempt(limiter, 0, retries, backoff, timeout, request)
+}
+
+fn retry_attempt<T, F>(
+    limiter: RateLimiter,
+    attempt: u32,
+    retries: u32,
+    backoff: Duration,
+    timeout: Option<Duration>,
+    request: F,
+) -> Future<T>
+where
+    T: 'static + Send,
+    F: 'static + Send + Fn() -> Future<T>,
+{
+    limiter
+        .acquire()
+        .and_then(move |_| {
+            timeout_request(timeout, request()).or_else(move |e| {
+                if attempt < retries && is_retriable(&e) {
+                    // cap the exponent so the delay can't overflow:
+                    let delay = backoff * 2u32.pow(attempt.min(6));
+                    warn!(
+                        "request failed, retrying in {:?} ({}/{}): {}",
+                        delay,
+                        attempt + 1,
+                        retries,
+                        e
+                    );
+                    Delay::new(Instant::now() + delay)
+                        .map_err(Into::into)
+                        .and_then(move |_| {
+                            retry_attempt(limiter, attempt + 1, retries, backoff, timeout, request)
+                        })
+                        .into_trait()
+                } else {
+                    future::err(e).into_trait()
+                }
+            })
+        })
+        .into_trait()
+}
+
+/// Returns true if an error means the Pennsieve platform rejected the
+/// credentials a request was made with. A 403 response only means that
+/// when `logging_in`: for any other request it is a lack of permission on
+/// the item requested.
+pub fn is_unauthorized(error: &agent::Error, logging_in: bool) -> bool {
+    let is_unauthorized_pennsieve = |kind: &pennsieve_rust::ErrorKind| match kind {
+        pennsieve_rust::ErrorKind::ApiError { status_code, .. } => {
+            *status_code == hyper::StatusCode::UNAUTHORIZED
+                || (logging_in && *status_code == hyper::StatusCode::FORBIDDEN)
+        }
+        _ => false,
+    };
+
+    match error.kind() {
+        agent::ErrorKind::Pennsieve { kind } => is_unauthorized_pennsieve(kind),
+        agent::ErrorKind::ApiError {
+            kind: ErrorKind::Pennsieve { kind },
+        } => is_unauthorized_pennsieve(kind),
+        _ => false,
+    }
+}
+
+/// Fails `request` with an `Unauthorized` error if the Pennsieve platform
+/// rejected the credentials of `profile`, or of the logged in user's
+/// profile if none is given. The stale session is deleted from `db`, so
+/// the next command logs in again.
+fn check_credentials<T, F>(
+    db: Database,
+    profile: Option<String>,
+    logging_in: bool,
+    request: F,
+) -> Future<T>
+where
+    T: 'static + Send,
+    F: 'static + Send + _Future<Item = T, Error = agent::Error>,
+{
+    request
+        .map_err(move |e| {
+            if is_unauthorized(&e, logging_in) {
+                clear_session(&db, profile, &e)
+            } else {
+                e
+            }
+        })
+        .into_trait()
+}
+
+/// Retries a request rejected with a 401 once `login` logs the user in
+/// again. The stale session is only cleared, failing with an
+/// `Unauthorized` error, if the Pennsieve platform rejects the login or
+/// the retried request; other failures, like the platform being
+/// unreachable, keep the session.
+fn relogin_and_retry<T, F, R>(
+    db: Database,
+    profile: Option<String>,
+    login: Future<UserRecord>,
+    retry: F,
+) -> Future<T>
+where
+    T: 'static + Send,
+    F: 'static + Send + FnOnce(UserRecord) -> R,
+    R: 'static + Send + _Future<Item = T, Error = agent::Error>,
+{
+    let retried = check_credentials(db.clone(), profile.clone(), true, login).and_then(retry);
+    check_credentials(db, profile, false, retried)
+}
+
+/// Deletes the stale session from `db`, so the next command logs in again,
+/// returning the `Unauthorized` error the credentials of `profile`, or of
+/// the logged in user's profile if none is given, were rejected with.
+fn clear_session(db: &Database, profile: Option<String>, error: &agent::Error) -> agent::Error {
+    let profile = profile
+        .or_else(|| {
+            db.get_user()
+                .ok()
+                .and_then(|user| user)
+                .map(|user| user.profile)
+        })
+        .unwrap_or_else(|| ENVIRONMENT_OVERRIDE_PROFILE.to_string());
+    warn!(
+        "credentials of profile {} were rejected: {}",
+        profile, error
+    );
+    if let Err(e) = db.delete_user() {
+        warn!("couldn't clear the stale session: {}", e);
+    }
+    Error::unauthorized(profile).into()
+}
+
+/// Fails `request` with a `Timeout` error if it does not complete within
+/// `timeout`. Without a timeout, `request` is returned unchanged.
+pub fn timeout_request<T, F>(timeout: Option<Duration>, request: F) -> Future<T>
+where
+    T: 'static + Send,
+    F: 'static + Send + _Future<Item = T, Error = agent::Error>,
+{
+    match timeout {
+        Some(timeout) => to_future_trait(Timeout::new(request, timeout).map_err(move |e| {
+            if e.is_elapsed() {
+                Error::timeout(timeout.as_secs()).into()
+            } else if e.is_timer() {
+                e.into_timer().unwrap().into()
+            } else {
+                e.into_inner().unwrap()
+            }
+        })),
+        None => to_future_trait(request),
+    }
+}
+
+/// Extends futures returned by the `Api` with `timeout_request`.
+trait Timed<T> {
+    fn timed(self, api: &Api) -> Future<T>;
+}
+
+impl<T, F> Timed<T> for F
+where
+    T: 'static + Send,
+    F: 'static + Send + _Future<Item = T, Error = agent::Error>,
+{
+    fn timed(self, api: &Api) -> Future<T> {
+        timeout_request(api.timeout, self)
+    }
+}
+
 impl Api {
-    /// Creates a new `Api` instance.
-    pub fn new(db: &Database, config: &AgentConfig, environment: ApiEnvironment) -> Self {
-        let ps = Pennsieve::new(Config::new(environment));
+    /// Creates a new `Api` instance for the given profile.
+    ///
+    /// Requests to the Pennsieve platform are routed through the proxy
+    /// configured for the profile, if any. The reverse proxy and timeseries
+    /// servers manage their own connections and are not affected.
+    pub fn new(db: &Database, config: &AgentConfig, profile: &ProfileConfig) -> Self {
+        let mut client_config = Config::new(profile.environment);
+        match profile.proxy_for(client_config.api_service()) {
+            Ok(Some(proxy)) => {
+                debug!("routing api requests through proxy {}", proxy);
+                client_config = client_config.with_proxy(proxy);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("ignoring proxy configuration: {}", e),
+        }
+        let ps = Pennsieve::new(client_config);
         Self {
             ps: ps.clone(),
             db: db.clone(),
             config: config.clone(),
+            timeout: None,
+            dataset_lookup: DatasetLookup::default(),
+            organization: None,
+            organization_scope: Arc::new(Mutex::new(None)),
+            rate_limiter: RateLimiter::new(config.api_rate_limit_per_sec),
+            name_cache: true,
+        }
+    }
+
+    /// Bounds how long each operation against the Pennsieve platform may
+    /// take. Operations that run longer fail with a `Timeout` error; for
+    /// read-only requests, which are retried, each attempt is bounded on its
+    /// own. With no timeout, requests wait for as long as the client allows.
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Sets how dataset arguments are resolved: by ID or name depending on
+    /// their form (the default), strictly by ID, or strictly by name.
+    pub fn with_dataset_lookup(self, dataset_lookup: DatasetLookup) -> Self {
+        Self {
+            dataset_lookup,
+            ..self
+        }
+    }
+
+    /// Sets whether dataset names resolved to IDs are remembered for
+    /// `api_name_cache_ttl_secs`, and resolved from what was remembered.
+    /// Turning it off always looks names up on the Pennsieve platform.
+    pub fn with_name_cache(self, name_cache: bool) -> Self {
+        Self { name_cache, ..self }
+    }
+
+    /// How long resolved names are remembered for, if at all.
+    fn name_cache_ttl(&self) -> Option<Duration> {
+        if self.name_cache && self.config.api_name_cache_ttl_secs > 0 {
+            Some(Duration::from_secs(self.config.api_name_cache_ttl_secs))
+        } else {
+            None
+        }
+    }
+
+    /// Scopes every operation to the organization with the given ID or
+    /// name instead of the user's preferred organization, without changing
+    /// the preference. The user must be a member of the organization.
+    pub fn with_organization<S: Into<String>>(self, organization: Option<S>) -> Self {
+        Self {
+            organization: organization.map(Into::into),
+            organization_scope: Arc::new(Mutex::new(None)),
+            ..self
         }
     }
 
+    /// Returns the organization operations are scoped to, if set with
+    /// `with_organization` and resolved.
+    pub fn organization_scope(&self) -> Option<OrganizationScope> {
+        self.organization_scope.lock().unwrap().clone()
+    }
+
     /// Returns an instance of the Pennsieve platform client.
     pub fn client(&self) -> &Pennsieve {
         &self.ps
@@ -105,7 +757,49 @@ impl Api {
     ///   an error.
     ///
     pub fn get_user_and_refresh(&self) -> Future<UserRecord> {
+        self.get_user_and_login(false)
+    }
+
+    /// Like `get_user_and_refresh`, but always logs in again, replacing the
+    /// cached session token of the active user even if it is still valid.
+    pub fn get_user_and_force_refresh(&self) -> Future<UserRecord> {
+        self.get_user_and_login(true)
+    }
+
+    /// Runs `request` as the logged in user, logging in first if needed.
+    /// The Pennsieve platform may expire a session before the agent expects
+    /// it to, so if `request` is rejected with a 401, the user logs in again
+    /// and `request` is retried once; see `relogin_and_retry`.
+    fn with_session<T, F, R>(&self, request: F) -> Future<T>
+    where
+        T: 'static + Send,
+        F: 'static + Send + Clone + FnOnce(UserRecord) -> R,
+        R: 'static + Send + _Future<Item = T, Error = agent::Error>,
+    {
+        let this = self.clone();
+        let retry = request.clone();
+        self.get_user_and_refresh()
+            .and_then(request)
+            .or_else(move |e| {
+                if !is_unauthorized(&e, false) {
+                    return future::err(e).into_trait();
+                }
+                warn!("the session was rejected, logging in again: {}", e);
+                let db = this.db.clone();
+                let profile = db
+                    .get_user()
+                    .ok()
+                    .and_then(|user| user)
+                    .map(|user| user.profile);
+                relogin_and_retry(db, profile, this.get_user_and_force_refresh(), retry)
+            })
+            .into_trait()
+    }
+
+    fn get_user_and_login(&self, force: bool) -> Future<UserRecord> {
         let ps = self.ps.clone();
+        let ps_scoped = self.ps.clone();
+        let this = self.clone();
         self.db
             .get_user()
             .map(|user| {
@@ -114,10 +808,10 @@ impl Api {
                 } else {
                     match user {
                         Some(u) => {
-                            if u.is_token_valid() {
-                                future::ok(u).into_trait()
-                            } else {
+                            if u.requires_login(force) || self.environment_changed(&u) {
                                 self.login_with_profile(u.profile)
+                            } else {
+                                future::ok(u).into_trait()
                             }
                         }
                         None => self.login_default(),
@@ -128,7 +822,10 @@ impl Api {
             .flatten()
             .and_then(move |user| {
                 ps.set_session_token(Some(model::SessionToken::new(user.session_token.clone())));
-                ps.set_current_organization(Some(&model::OrganizationId::new(
+                this.scope_user(user)
+            })
+            .and_then(move |user| {
+                ps_scoped.set_current_organization(Some(&model::OrganizationId::new(
                     user.organization_id.clone(),
                 )));
                 future::ok(user)
@@ -136,6 +833,67 @@ impl Api {
             .into_trait()
     }
 
+    /// Tests if the session of `user` was started in another environment
+    /// than the one their profile now points to, such as when it is
+    /// overridden with `--env`. Sessions don't carry over between
+    /// environments.
+    fn environment_changed(&self, user: &UserRecord) -> bool {
+        self.config
+            .api_settings
+            .get_profile(user.profile.clone())
+            .map_or(false, |profile| profile.environment != user.environment)
+    }
+
+    /// Rescopes `user` to the organization set with `with_organization`,
+    /// checking that they are a member of it the first time.
+    fn scope_user(&self, user: UserRecord) -> Future<UserRecord> {
+        let requested = match self.organization {
+            Some(ref organization) => organization.clone(),
+            None => return future::ok(user).into_trait(),
+        };
+        if let Some(scope) = self.organization_scope() {
+            return future::ok(scope.scope(user)).into_trait();
+        }
+        if user.organization_id == requested || user.organization_name == requested {
+            return future::ok(user).into_trait();
+        }
+
+        let organization_scope = Arc::clone(&self.organization_scope);
+        self.ps
+            .get_organizations()
+            .map_err(Into::into)
+            .and_then(move |organizations| -> agent::Result<UserRecord> {
+                let scope = find_organization(&requested, organization_scopes(organizations))?;
+                debug!("scoping operations to organization {}", scope.id);
+                *organization_scope.lock().unwrap() = Some(scope.clone());
+                Ok(scope.scope(user))
+            })
+            .into_trait()
+    }
+
+    /// Switches the user's preferred organization to the one with the given
+    /// ID or name, which they must be a member of. The choice is kept when
+    /// the user logs in again.
+    pub fn switch_organization<S: Into<String>>(&self, id_or_name: S) -> Future<UserRecord> {
+        let id_or_name = id_or_name.into();
+        let ps = self.ps.clone();
+        let db = self.db.clone();
+        self.with_session(move |user| {
+            ps.get_organizations().map_err(Into::into).and_then(
+                move |organizations| -> agent::Result<UserRecord> {
+                    let scope = find_organization(&id_or_name, organization_scopes(organizations))?;
+                    let mut user = scope.scope(user);
+                    db.upsert_user(&mut user)?;
+                    ps.set_current_organization(Some(&model::OrganizationId::new(
+                        user.organization_id.clone(),
+                    )));
+                    Ok(user)
+                },
+            )
+        })
+        .timed(self)
+    }
+
     /// Log into the Pennsieve platform using the default profile in config.ini.
     /// If successful, the Future will resolve with the corresponding user record.
     pub fn login_default(&self) -> Future<UserRecord> {
@@ -156,20 +914,45 @@ impl Api {
 
     pub fn login(&self, profile: ProfileConfig) -> Future<UserRecord> {
         let db = self.db.clone();
+        let profile_name = profile.profile.clone();
         let api_key = profile.token.clone();
         let api_secret = profile.secret.clone();
         let ps = self.ps.clone();
         ps.set_environment(profile.environment);
 
-        ps.login(api_key, api_secret)
+        // keep the organization chosen with `use-org` for this profile:
+        let preferred_organization = db
+            .get_user()
+            .ok()
+            .and_then(|user| user)
+            .filter(|user| user.profile == profile.profile)
+            .map(|user| model::OrganizationId::new(user.organization_id));
+
+        // a rejected key and secret are reported as such:
+        let session = check_credentials(
+            db.clone(),
+            Some(profile_name),
+            true,
+            ps.login(api_key, api_secret).map_err(Into::into),
+        );
+
+        session
             .and_then(move |session| {
-                ps.get_organization_by_id(model::OrganizationId::new(
-                    session.organization().clone(),
-                ))
+                let session_organization =
+                    model::OrganizationId::new(session.organization().clone());
+                let fallback = ps.clone();
+                ps.get_organization_by_id(
+                    preferred_organization.unwrap_or_else(|| session_organization.clone()),
+                )
+                .or_else(move |_| fallback.get_organization_by_id(session_organization))
                 .map(|org| (ps, session, org))
+                .map_err(Into::into)
+            })
+            .and_then(|(ps, session, org)| {
+                ps.get_user()
+                    .map(|user| (session, user, org))
+                    .map_err(Into::into)
             })
-            .and_then(|(ps, session, org)| ps.get_user().map(|user| (session, user, org)))
-            .map_err(Into::<agent::Error>::into)
             .and_then(
                 move |(session, user, org)| -> future::FutureResult<_, agent::Error> {
                     let o = org.organization();
@@ -189,7 +972,7 @@ impl Api {
                         .into()
                 },
             )
-            .into_trait()
+            .timed(self)
     }
 
     // Resolution rules for dataset/package identifer combinations
@@ -372,25 +1155,57 @@ impl Api {
             package_id_or_name, // package_id_or_name
             append,             // append
             true,               // force
+            false,              // force_reappend
             recursive,          // recursive
+            false,              // follow_symlinks
+            false,              // skip_empty
+            false,              // skip_existing
+            0,                  // priority
+            BTreeMap::new(),    // tags
+            None,               // chunk_size
+            false,              // checksums
             SimpleDatasetValidator,
             SimplePackageValidator,
+            LogSkipped,
         )
     }
 
     /// Queues matching files for upload to the Pennsieve platform given a
-    /// path and inclusion/exclusion pattern globs.
+    /// path and inclusion/exclusion pattern globs. Files queued with a
+    /// higher `priority` are uploaded before other queued files, and
+    /// `tags` are set as properties on the uploaded packages once the
+    /// upload completes.
+    ///
+    /// Files the upload service sends in parts are split into chunks of
+    /// `chunk_size` bytes, or of a size picked from the size of each file
+    /// if it is not given. With `checksums`, those files are hashed as they
+    /// are queued, for the manifest of the upload.
+    ///
+    /// Unless `force` is set, files that are already queued or being
+    /// uploaded to the dataset are not queued again. With `skip_existing`,
+    /// files that already exist at the target are not queued; see
+    /// `existing_files`. Every file that is left out is passed to
+    /// `report_skipped`.
     #[allow(clippy::too_many_arguments)]
-    pub fn queue_uploads<F, D, P, VD, VF>(
+    pub fn queue_uploads<F, D, P, VD, VF, R>(
         &self,
         files: Vec<F>,
         dataset_id_or_name: Option<D>,
         package_id_or_name: Option<P>,
         append: bool,
         force: bool,
+        force_reappend: bool,
         recursive: bool,
+        follow_symlinks: bool,
+        skip_empty: bool,
+        skip_existing: bool,
+        priority: i32,
+        tags: BTreeMap<String, String>,
+        chunk_size: Option<u64>,
+        checksums: bool,
         validate_dataset: VD,
         validate_folder: VF,
+        report_skipped: R,
     ) -> Future<UploadRecords>
     where
         F: Into<String>,
@@ -398,6 +1213,7 @@ impl Api {
         P: Into<String>,
         VD: Validator,
         VF: Validator,
+        R: SkipReporter,
     {
         let files: Vec<String> = files.into_iter().map(|f| f.into()).collect();
         let dataset_id_or_name: Option<String> = dataset_id_or_name.map(Into::into);
@@ -421,7 +1237,17 @@ impl Api {
         let package_id_or_name: Option<String> = package_id_or_name.map(Into::into);
         let ps = self.ps.clone();
         let db = self.db.clone();
+        let append_db = self.db.clone();
+        let queued_db = self.db.clone();
         let this = self.clone();
+        let existing_this = self.clone();
+        let report_skipped = Arc::new(report_skipped);
+        let (empty_reporter, appended_reporter, queued_reporter, existing_reporter) = (
+            report_skipped.clone(),
+            report_skipped.clone(),
+            report_skipped.clone(),
+            report_skipped.clone(),
+        );
 
         let preview_dataset_id_or_name = dataset_id_or_name.clone();
         let preview_package_id_or_name = package_id_or_name.clone();
@@ -475,10 +1301,93 @@ impl Api {
             })
             // Step 4. Generate a normalized and canonicalized list of files:
             .and_then(move |(ps, dataset, package_id, organization_id)| {
-                upload::generate_file_preview(files, recursive)
+                upload::generate_file_preview(files, recursive, follow_symlinks)
+                    .map(|preview| {
+                        for link in preview.skipped_links() {
+                            report_skipped.skipped(upload::Skipped::SymbolicLink(link.clone()));
+                        }
+                        preview
+                    })
+                    .and_then(|preview| {
+                        if skip_empty {
+                            preview.without_empty_files().map(|(preview, skipped)| {
+                                for file_path in skipped {
+                                    empty_reporter.skipped(upload::Skipped::Empty(file_path));
+                                }
+                                preview
+                            })
+                        } else {
+                            Ok(preview)
+                        }
+                    })
                     .map(|preview| (ps, dataset, package_id, organization_id, preview))
                     .map_err(Into::into)
             })
+            // Step 4A. If append = true, skip files that were already fully
+            // appended to the package, unless a re-append is forced:
+            .and_then(move |(ps, dataset, package_id, organization_id, preview)| {
+                match package_id.clone() {
+                    Some(pkg_id) if append && !force_reappend => {
+                        let pkg_id: String = pkg_id.into();
+                        upload::skip_appended_files(&append_db, preview, &pkg_id)
+                            .map(|(preview, skipped)| {
+                                for file_path in skipped {
+                                    appended_reporter.skipped(upload::Skipped::AlreadyAppended {
+                                        file_path,
+                                        package_id: pkg_id.clone(),
+                                    });
+                                }
+                                (ps, dataset, package_id, organization_id, preview)
+                            })
+                            .map_err(Into::into)
+                    }
+                    _ => Ok((ps, dataset, package_id, organization_id, preview)),
+                }
+            })
+            // Step 4B. Unless forced, skip files that are already queued or
+            // being uploaded to the dataset:
+            .and_then(move |(ps, dataset, package_id, organization_id, preview)| {
+                if force {
+                    return Ok((ps, dataset, package_id, organization_id, preview));
+                }
+                let dataset_id: String = dataset.id().clone().into();
+                upload::skip_queued_files(&queued_db, preview, &dataset_id)
+                    .map(|(preview, skipped)| {
+                        for file_path in skipped {
+                            queued_reporter.skipped(upload::Skipped::AlreadyQueued {
+                                file_path,
+                                dataset_id: dataset_id.clone(),
+                            });
+                        }
+                        (ps, dataset, package_id, organization_id, preview)
+                    })
+                    .map_err(Into::into)
+            })
+            // Step 4C. If skip_existing = true, skip files that already exist
+            // at the upload target:
+            .and_then(move |(ps, dataset, package_id, organization_id, preview)| {
+                if !skip_existing {
+                    return future::ok::<_, agent::Error>((ps, dataset, package_id, organization_id, preview))
+                        .into_trait();
+                }
+                existing_this
+                    .existing_files(dataset.id().clone(), package_id.clone(), &preview)
+                    .and_then(move |existing| {
+                        upload::skip_existing_files(preview, &existing, |file_path, chunk_size| {
+                            fs::File::open(file_path)
+                                .ok()
+                                .and_then(|file| cli::compute_hash(file, chunk_size).ok())
+                        })
+                        .map(|(preview, skipped)| {
+                            if !skipped.is_empty() {
+                                existing_reporter.skipped(upload::Skipped::AlreadyExist(skipped));
+                            }
+                            (ps, dataset, package_id, organization_id, preview)
+                        })
+                        .map_err(Into::into)
+                    })
+                    .into_trait()
+            })
             // Step 5. Register the preview with the Pennsieve platform:
             .and_then(
                 move |(ps, dataset, package_id, organization_id, agent_preview)| {
@@ -544,6 +1453,21 @@ impl Api {
                                                 })
                                         })
                                         .and_then(|file_path| {
+                                            let chunk_size = match s3_file.chunked_upload() {
+                                                Some(_) => {
+                                                    let file_size = fs::metadata(file_path)?.len();
+                                                    Some(upload::choose_chunk_size(file_size, chunk_size)?)
+                                                }
+                                                None => None,
+                                            };
+                                            // Hash the file as it is now, for the manifest of the upload:
+                                            let checksum = chunk_size.filter(|_| checksums).and_then(|chunk_size| {
+                                                fs::File::open(file_path)
+                                                    .map_err(Into::into)
+                                                    .and_then(|file| cli::compute_hash(file, chunk_size))
+                                                    .map_err(|e| warn!("Couldn't checksum {:?}: {}", file_path, e))
+                                                    .ok()
+                                            });
                                             // Send a status update:
                                             a::send_unconditionally::<server::StatusServer, _>(
                                                 Response::file_queued_for_upload(file_path.clone(), import_id.clone()),
@@ -555,11 +1479,16 @@ impl Api {
                                                 organization_id.clone(),
                                                 import_id,
                                                 append,
-                                                s3_file
-                                                    .chunked_upload()
-                                                    .map(|properties| properties.chunk_size),
+                                                chunk_size,
                                                 s3_file.multipart_upload_id().map(Into::into),
-                                            ).map_err(Into::into)
+                                            )
+                                            .map(|record| {
+                                                record
+                                                    .with_priority(priority)
+                                                    .with_tags(tags.clone())
+                                                    .with_checksum(checksum)
+                                            })
+                                            .map_err(Into::into)
                                         })
                                 })
                                 .collect::<Vec<_>>()
@@ -591,69 +1520,241 @@ impl Api {
             .into_trait()
     }
 
-    pub fn get_upload_file_hash(&self, upload_id: usize) -> Future<response::FileHash> {
-        let ps = self.ps.clone();
-        let db = self.db.clone();
-        let db_clone = db.clone();
-        self.get_user_and_refresh()
-            .and_then(move |_| {
-                db_clone
-                    .get_upload_by_upload_id(upload_id)
-                    .map_err(Into::<agent::Error>::into)
-                    .into_future()
-            })
-            .and_then(move |upload| {
-                if upload.is_package_completed() {
-                    let file_path = PathBuf::from(upload.file_path);
-
-                    // Since this path is stored in the DB, we know it is
-                    // a path to a file (so file_name() will work) and we
-                    // know that it contains valid unicode (so to_str()
-                    // will also work)
-                    let file_name = file_path
-                        .file_name()
-                        .and_then(|name| name.to_str())
-                        .unwrap();
-
-                    ps.get_upload_hash(&upload.import_id.into(), file_name)
-                        .map_err(Into::<agent::Error>::into)
-                        .into_trait()
-                } else {
-                    future::failed(
-                        Error::invalid_upload(format!(
-                            "upload {} ({}) is not complete",
-                            upload_id, upload.status
-                        ))
-                        .into(),
-                    )
-                    .into_trait()
-                }
-            })
-            .into_trait()
+    /// Lists the packages at the top of an upload target: the dataset, or
+    /// the collection `package_id` within it.
+    fn target_children(
+        &self,
+        dataset_id: model::DatasetNodeId,
+        package_id: Option<model::PackageId>,
+    ) -> Future<Vec<response::Package>> {
+        match package_id {
+            Some(package_id) => self
+                .get_collection(package_id)
+                .map(|collection| collection.children().cloned().unwrap_or_default())
+                .into_trait(),
+            None => {
+                let ps = self.ps.clone();
+                self.with_session(move |_| {
+                    ps.get_dataset(dataset_id)
+                        .map(|dataset| dataset.children().cloned().unwrap_or_default())
+                        .map_err(Into::into)
+                })
+                .timed(self)
+            }
+        }
     }
 
-    /// Get all organizations the current user is member of.
-    pub fn get_organizations(&self) -> Future<response::Organizations> {
-        let ps = self.ps.clone();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.get_organizations().map_err(Into::into))
+    /// Sets `tags`, as given with `upload --tag`, as properties on the
+    /// packages and collections at the top of an upload target that are
+    /// called one of `names`: those created by an upload.
+    pub fn tag_uploaded_packages(
+        &self,
+        dataset_id: model::DatasetNodeId,
+        package_id: Option<model::PackageId>,
+        names: HashSet<String>,
+        tags: BTreeMap<String, String>,
+    ) -> Future<()> {
+        let api = self.clone();
+        self.target_children(dataset_id, package_id)
+            .and_then(move |children| {
+                let targets = children
+                    .into_iter()
+                    .map(|child| child.take())
+                    .filter(|package| names.contains(package.name()))
+                    .map(|package| package.id().clone())
+                    .collect();
+                tag_packages(targets, tags, move |id, tags| {
+                    api.set_package_properties(id, tags)
+                })
+            })
             .into_trait()
     }
 
-    /// Get the members that belong to the users organization.
-    pub fn get_members(&self) -> Future<Vec<model::User>> {
-        let ps = self.ps.clone();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.get_members().map_err(Into::into))
-            .into_trait()
+    /// Lists the files already at an upload target: the dataset, or the
+    /// collection `package_id` within it. Files are keyed by their path
+    /// relative to the target.
+    ///
+    /// When a file of `preview` was uploaded to the same target by this
+    /// agent before, the checksum the platform computed for it is looked up
+    /// too, so a file that has since changed is not mistaken for the one
+    /// that was uploaded.
+    fn existing_files(
+        &self,
+        dataset_id: model::DatasetNodeId,
+        package_id: Option<model::PackageId>,
+        preview: &upload::PreviewFiles,
+    ) -> Future<upload::ExistingFiles> {
+        let api = self.clone();
+        let hash_api = self.clone();
+
+        // The most recent completed upload of each file in the preview,
+        // keyed by its path relative to the target:
+        let mut previous: HashMap<String, UploadRecord> = HashMap::new();
+        let base = preview.path().map(PathBuf::from);
+        let file_paths: HashSet<String> = preview
+            .file_paths()
+            .iter()
+            .map(|(_, file_path)| file_path.display().to_string())
+            .collect();
+        let target_dataset: String = dataset_id.clone().into();
+        let target_package: Option<String> = package_id.clone().map(Into::into);
+        try_future!(self
+            .db
+            .get_all_uploads(Some(UploadStatus::Completed), |upload| {
+                if upload.dataset_id == target_dataset
+                    && upload.package_id == target_package
+                    && upload.chunk_size.is_some()
+                    && file_paths.contains(&upload.file_path)
+                {
+                    let path = upload::target_path(
+                        base.as_ref().map(AsRef::as_ref),
+                        Path::new(&upload.file_path),
+                    );
+                    previous.insert(path, upload);
+                }
+                Ok::<_, agent::Error>(())
+            }));
+
+        let folders = target_folders(preview.file_paths().iter().map(|(_, file_path)| {
+            upload::target_path(base.as_ref().map(AsRef::as_ref), file_path)
+        }));
+
+        self.target_children(dataset_id, package_id)
+            .and_then(move |children| package_paths(api, children, folders))
+            .and_then(move |paths| {
+                stream::iter_ok::<_, agent::Error>(paths)
+                    .map(move |path| {
+                        let checksum: Future<Option<(String, u64)>> = match previous.remove(&path) {
+                            Some(UploadRecord {
+                                id: Some(id),
+                                chunk_size: Some(chunk_size),
+                                ..
+                            }) => hash_api
+                                .get_upload_file_hash(id as usize)
+                                .map(move |hash| Some((hash.hash, chunk_size)))
+                                // without the checksum, the file is matched by path alone:
+                                .or_else(|_| Ok::<_, agent::Error>(None))
+                                .into_trait(),
+                            _ => future::ok(None).into_trait(),
+                        };
+                        checksum.map(|checksum| (path, upload::ExistingFile { checksum }))
+                    })
+                    .buffer_unordered(EXISTING_FILES_CONCURRENCY)
+                    .collect()
+            })
+            .map(
+                |files: Vec<(String, upload::ExistingFile)>| -> upload::ExistingFiles {
+                    files.into_iter().collect()
+                },
+            )
+            .into_trait()
+    }
+
+    pub fn get_upload_file_hash(&self, upload_id: usize) -> Future<response::FileHash> {
+        let ps = self.ps.clone();
+        let db = self.db.clone();
+        let db_clone = db.clone();
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            db_clone
+                .get_upload_by_upload_id(upload_id)
+                .map_err(Into::<agent::Error>::into)
+                .into_future()
+                .and_then(move |upload| {
+                    if upload.is_package_completed() {
+                        let file_path = PathBuf::from(upload.file_path);
+
+                        // Since this path is stored in the DB, we know it is
+                        // a path to a file (so file_name() will work) and we
+                        // know that it contains valid unicode (so to_str()
+                        // will also work)
+                        let file_name = file_path
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .unwrap()
+                            .to_string();
+                        let import_id = upload.import_id;
+
+                        retry_request(
+                            limiter,
+                            retries,
+                            Duration::from_millis(API_RETRY_BACKOFF_MS),
+                            timeout,
+                            move || {
+                                ps.get_upload_hash(&import_id.clone().into(), &file_name)
+                                    .map_err(Into::into)
+                                    .into_trait()
+                            },
+                        )
+                    } else {
+                        future::failed(
+                            Error::invalid_upload(format!(
+                                "upload {} ({}) is not complete",
+                                upload_id, upload.status
+                            ))
+                            .into(),
+                        )
+                        .into_trait()
+                    }
+                })
+        })
+    }
+
+    /// Get all organizations the current user is member of.
+    pub fn get_organizations(&self) -> Future<response::Organizations> {
+        let ps = self.ps.clone();
+        self.with_session(move |_| ps.get_organizations().map_err(Into::into))
+            .timed(self)
+    }
+
+    /// Get the organizations the user is a member of, along with the user,
+    /// whose record names the organization their operations are scoped to.
+    pub fn get_organization_scopes(&self) -> Future<(UserRecord, Vec<OrganizationScope>)> {
+        let ps = self.ps.clone();
+        self.with_session(move |user| {
+            ps.get_organizations()
+                .map_err(Into::into)
+                .map(|organizations| (user, organization_scopes(organizations)))
+        })
+        .timed(self)
+    }
+
+    /// Get the members that belong to the users organization.
+    pub fn get_members(&self) -> Future<Vec<model::User>> {
+        let ps = self.ps.clone();
+        self.with_session(move |_| ps.get_members().map_err(Into::into))
+            .timed(self)
     }
 
     /// Get the teams that belong to the users organization.
     pub fn get_teams(&self) -> Future<Vec<response::Team>> {
         let ps = self.ps.clone();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.get_teams().map_err(Into::into))
-            .into_trait()
+        self.with_session(move |_| ps.get_teams().map_err(Into::into))
+            .timed(self)
+    }
+
+    /// Get the members of a team in the users organization.
+    pub fn get_team_members<T: Into<String>>(&self, team_id: T) -> Future<Vec<model::User>> {
+        let ps = self.ps.clone();
+        let team_id = team_id.into();
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(
+                limiter,
+                retries,
+                Duration::from_millis(API_RETRY_BACKOFF_MS),
+                timeout,
+                move || {
+                    ps.get_team_members(team_id.clone())
+                        .map_err(Into::into)
+                        .into_trait()
+                },
+            )
+        })
     }
 
     /// Create a new package.
@@ -667,18 +1768,35 @@ impl Api {
         D: Into<DatasetNodeId>,
         N: Into<String>,
         P: Into<String>,
+    {
+        self.create_package_in(name, type_, dataset, None as Option<String>)
+    }
+
+    /// Create a new package inside of the collection `parent`, or at the
+    /// root of the dataset if there is no parent.
+    pub fn create_package_in<D, N, P, Q>(
+        &self,
+        name: N,
+        type_: P,
+        dataset: D,
+        parent: Option<Q>,
+    ) -> Future<response::Package>
+    where
+        D: Into<DatasetNodeId>,
+        N: Into<String>,
+        P: Into<String>,
+        Q: Into<String>,
     {
         let ps = self.ps.clone();
         let name = name.into();
         let type_ = type_.into();
         let dataset_id = dataset.into();
-        self.get_user_and_refresh()
-            .and_then(move |_| {
-                // TODO: allow creating nested collections
-                ps.create_package(name.clone(), type_, dataset_id, None as Option<String>)
-                    .map_err(Into::into)
-            })
-            .into_trait()
+        let parent = parent.map(Into::into);
+        self.with_session(move |_| {
+            ps.create_package(name.clone(), type_, dataset_id, parent)
+                .map_err(Into::into)
+        })
+        .timed(self)
     }
 
     /// Get the source files of a package.
@@ -688,9 +1806,8 @@ impl Api {
     {
         let ps = self.ps.clone();
         let id = id.into();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.get_package_sources(id.clone()).map_err(Into::into))
-            .into_trait()
+        self.with_session(move |_| ps.get_package_sources(id.clone()).map_err(Into::into))
+            .timed(self)
     }
 
     /// Updates an existing package.
@@ -702,9 +1819,40 @@ impl Api {
         let ps = self.ps.clone();
         let id = id.into();
         let name = new_name.into();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.update_package(id.clone(), name).map_err(Into::into))
-            .into_trait()
+        self.with_session(move |_| ps.update_package(id.clone(), name).map_err(Into::into))
+            .timed(self)
+    }
+
+    /// Sets properties on a package, replacing any existing properties
+    /// with the same keys.
+    pub fn set_package_properties<P>(
+        &self,
+        id: P,
+        properties: BTreeMap<String, String>,
+    ) -> Future<()>
+    where
+        P: Into<PackageId>,
+    {
+        let ps = self.ps.clone();
+        let id = id.into();
+        let properties: Vec<(String, String)> = properties.into_iter().collect();
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(
+                limiter,
+                retries,
+                Duration::from_millis(API_RETRY_BACKOFF_MS),
+                timeout,
+                move || {
+                    ps.update_package_properties(id.clone(), properties.clone())
+                        .map(|_| ())
+                        .map_err(Into::into)
+                        .into_trait()
+                },
+            )
+        })
     }
 
     /// Get a specific collection.
@@ -714,9 +1862,22 @@ impl Api {
     {
         let ps = self.ps.clone();
         let id = id.into();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.get_package_by_id(id.clone()).map_err(Into::into))
-            .into_trait()
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(
+                limiter,
+                retries,
+                Duration::from_millis(API_RETRY_BACKOFF_MS),
+                timeout,
+                move || {
+                    ps.get_package_by_id(id.clone())
+                        .map_err(Into::into)
+                        .into_trait()
+                },
+            )
+        })
     }
 
     /// Move packages to a new destination
@@ -733,9 +1894,8 @@ impl Api {
         let targets = targets.into_iter().map(Into::into).collect::<Vec<_>>();
         let destination = destination.map(Into::into);
         let ps = self.ps.clone();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.mv(targets, destination).map_err(Into::into))
-            .into_trait()
+        self.with_session(move |_| ps.mv(targets, destination).map_err(Into::into))
+            .timed(self)
     }
 
     /// Create a new collection.
@@ -747,6 +1907,22 @@ impl Api {
         self.create_package(name, "Collection", dataset)
     }
 
+    /// Create a new collection inside of the collection `parent`, or at the
+    /// root of the dataset if there is no parent.
+    pub fn create_collection_in<P, Q, R>(
+        &self,
+        name: P,
+        dataset: Q,
+        parent: Option<R>,
+    ) -> Future<response::Package>
+    where
+        P: Into<String>,
+        Q: Into<DatasetNodeId>,
+        R: Into<String>,
+    {
+        self.create_package_in(name, "Collection", dataset, parent)
+    }
+
     /// Create a new dataset.
     pub fn create_dataset<P, Q>(&self, name: P, description: Option<Q>) -> Future<response::Dataset>
     where
@@ -756,32 +1932,136 @@ impl Api {
         let ps = self.ps.clone();
         let name = name.into();
         let description = description.map(Into::into);
-        self.get_user_and_refresh()
-            .and_then(move |_| {
-                ps.create_dataset(name.clone(), description)
-                    .map_err(Into::into)
-            })
-            .into_trait()
+        self.with_session(move |_| {
+            ps.create_dataset(name.clone(), description)
+                .map_err(Into::into)
+        })
+        .timed(self)
     }
 
     /// Get all datasets.
     pub fn get_datasets(&self) -> Future<Vec<response::Dataset>> {
         let ps = self.ps.clone();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.get_datasets().map_err(Into::into))
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(
+                limiter,
+                retries,
+                Duration::from_millis(API_RETRY_BACKOFF_MS),
+                timeout,
+                move || ps.get_datasets().map_err(Into::into).into_trait(),
+            )
+        })
+    }
+
+    /// Finds the ID of the one dataset named `name` among the user's
+    /// datasets.
+    fn find_dataset_id(&self, name: String) -> Future<String> {
+        self.get_datasets()
+            .and_then(move |datasets| {
+                let datasets = datasets.into_iter().map(|d| {
+                    let d = d.take();
+                    (Into::<String>::into(d.id().clone()), d.name().clone())
+                });
+                dataset_id_by_name(&name, datasets).map_err(Into::into)
+            })
             .into_trait()
     }
 
-    /// Get a specific dataset, either by id or by name.
+    /// Get a specific dataset, either by id or by name, as set with
+    /// `with_dataset_lookup`. A name must match exactly one dataset.
     pub fn get_dataset<P>(&self, id_or_name: P) -> Future<response::Dataset>
     where
         P: Into<String>,
     {
         let ps = self.ps.clone();
         let id_or_name = id_or_name.into();
-        self.get_user_and_refresh()
-            .and_then(move |_| ps.get_dataset(id_or_name.clone()).map_err(Into::into))
-            .into_trait()
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+
+        let db = self.db.clone();
+        let name = id_or_name.clone();
+        let by_name = try_future!(self.dataset_lookup.by_name(&id_or_name));
+        let this = self.clone();
+        let ttl = self.name_cache_ttl();
+
+        self.with_session(move |user| {
+            // Fetches a dataset by its ID, which is never resolved as a name:
+            let fetch = move |id: String| -> Future<response::Dataset> {
+                let ps = ps.clone();
+                retry_request(
+                    limiter.clone(),
+                    retries,
+                    Duration::from_millis(API_RETRY_BACKOFF_MS),
+                    timeout,
+                    move || {
+                        let id = id.clone();
+                        ps.get_dataset(id.clone())
+                            .map_err(Into::<agent::Error>::into)
+                            .and_then(move |dataset| {
+                                if Into::<String>::into(dataset.id().clone()) == id {
+                                    Ok(dataset)
+                                } else {
+                                    Err(Error::invalid_dataset_id(id).into())
+                                }
+                            })
+                            .into_trait()
+                    },
+                )
+            };
+
+            if !by_name {
+                return fetch(id_or_name);
+            }
+
+            // A dataset resolved by name carries the organization it was
+            // resolved in, so a stale resolution can be forgotten:
+            let organization_id = user.organization_id;
+            let lookup = {
+                let this = this.clone();
+                let name = name.clone();
+                move || this.find_dataset_id(name)
+            };
+            let forget = {
+                let db = db.clone();
+                let organization_id = organization_id.clone();
+                let name = name.clone();
+                move || {
+                    if let Err(e) = db.delete_resolved_name(&organization_id, &name) {
+                        warn!("couldn't update the name cache: {}", e);
+                    }
+                }
+            };
+            let refetch = fetch.clone();
+            let forget_renamed = forget.clone();
+
+            resolve_name(db.clone(), organization_id.clone(), id_or_name, ttl, lookup)
+                .and_then(fetch)
+                .or_else(move |e| {
+                    forget();
+                    Err(e)
+                })
+                .and_then(move |dataset| -> Future<response::Dataset> {
+                    if dataset.name() == &name {
+                        return future::ok(dataset).into_trait();
+                    }
+                    // The dataset remembered by this name was renamed
+                    // since, so the name is looked up again:
+                    debug!("{:?} was renamed; resolving it again", name);
+                    forget_renamed();
+                    let lookup = {
+                        let name = name.clone();
+                        move || this.find_dataset_id(name)
+                    };
+                    resolve_name(db, organization_id, name, ttl, lookup)
+                        .and_then(refetch)
+                        .into_trait()
+                })
+                .into_trait()
+        })
     }
 
     /// Attempts to get a dataset by its name or ID.
@@ -806,6 +2086,10 @@ impl Api {
             .then(move |result| {
                 match result {
                     Ok(dataset) => Ok(dataset).into_future().into_trait(),
+                    // a name shared by several datasets is never created again:
+                    Err(ref e) if is_ambiguous_dataset_name(e) => {
+                        future::err::<_, agent::Error>(e.clone()).into_trait()
+                    }
                     // if not, try to create it:
                     Err(_) => match validate.validate(&id_or_name) {
                         Ok(allowed) => {
@@ -823,7 +2107,7 @@ impl Api {
                     },
                 }
             })
-            .into_trait()
+            .timed(self)
     }
 
     /// Get the user collaborators of the dataset.
@@ -833,12 +2117,22 @@ impl Api {
     {
         let ps = self.ps.clone();
         let id = id.into();
-        self.get_user_and_refresh()
-            .and_then(move |_| {
-                ps.get_dataset_user_collaborators(id.clone())
-                    .map_err(Into::into)
-            })
-            .into_trait()
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(
+                limiter,
+                retries,
+                Duration::from_millis(API_RETRY_BACKOFF_MS),
+                timeout,
+                move || {
+                    ps.get_dataset_user_collaborators(id.clone())
+                        .map_err(Into::into)
+                        .into_trait()
+                },
+            )
+        })
     }
 
     /// Get the team collaborators of the dataset.
@@ -848,12 +2142,22 @@ impl Api {
     {
         let ps = self.ps.clone();
         let id = id.into();
-        self.get_user_and_refresh()
-            .and_then(move |_| {
-                ps.get_dataset_team_collaborators(id.clone())
-                    .map_err(Into::into)
-            })
-            .into_trait()
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(
+                limiter,
+                retries,
+                Duration::from_millis(API_RETRY_BACKOFF_MS),
+                timeout,
+                move || {
+                    ps.get_dataset_team_collaborators(id.clone())
+                        .map_err(Into::into)
+                        .into_trait()
+                },
+            )
+        })
     }
 
     /// Get the team collaborators of the dataset.
@@ -863,12 +2167,22 @@ impl Api {
     {
         let ps = self.ps.clone();
         let id = id.into();
-        self.get_user_and_refresh()
-            .and_then(move |_| {
-                ps.get_dataset_organization_role(id.clone())
-                    .map_err(Into::into)
-            })
-            .into_trait()
+        let retries = self.config.api_retries;
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(
+                limiter,
+                retries,
+                Duration::from_millis(API_RETRY_BACKOFF_MS),
+                timeout,
+                move || {
+                    ps.get_dataset_organization_role(id.clone())
+                        .map_err(Into::into)
+                        .into_trait()
+                },
+            )
+        })
     }
 
     /// Update an existing dataset.
@@ -887,23 +2201,68 @@ impl Api {
         let id = id.into();
         let name = new_name.into();
         let description = new_description.map(Into::into);
-        to_future_trait(self.get_user_and_refresh().and_then(move |_| {
+        self.with_session(move |_| {
             ps.update_dataset(id.clone(), name, description)
                 .map_err(Into::into)
-        }))
+        })
+        .timed(self)
+    }
+
+    /// Set the tags and license of an existing dataset.
+    pub fn update_dataset_metadata<P>(
+        &self,
+        id: P,
+        tags: Vec<String>,
+        license: Option<String>,
+    ) -> Future<response::Dataset>
+    where
+        P: Into<DatasetNodeId>,
+    {
+        let ps = self.ps.clone();
+        let id = id.into();
+        self.with_session(move |_| {
+            ps.update_dataset_metadata(id.clone(), tags, license)
+                .map_err(Into::into)
+        })
+        .timed(self)
     }
 
-    /// Delete an existing dataset.
+    /// Delete an existing dataset. Deletes are paced like other requests,
+    /// but not retried.
     pub fn delete_dataset<P>(&self, id: P) -> Future<()>
     where
         P: Into<DatasetNodeId>,
     {
         let ps = self.ps.clone();
         let id = id.into();
-        to_future_trait(
-            self.get_user_and_refresh()
-                .and_then(move |_| ps.delete_dataset(id.clone()).map_err(Into::into)),
-        )
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(limiter, 0, Duration::from_millis(0), timeout, move || {
+                ps.delete_dataset(id.clone())
+                    .map_err(Into::into)
+                    .into_trait()
+            })
+        })
+    }
+
+    /// Delete an existing package. Deleting a collection deletes everything
+    /// in it. Deletes are paced like other requests, but not retried.
+    pub fn delete_package<P>(&self, id: P) -> Future<()>
+    where
+        P: Into<PackageId>,
+    {
+        let ps = self.ps.clone();
+        let id = id.into();
+        let limiter = self.rate_limiter.clone();
+        let timeout = self.timeout;
+        self.with_session(move |_| {
+            retry_request(limiter, 0, Duration::from_millis(0), timeout, move || {
+                ps.delete_package(id.clone())
+                    .map_err(Into::into)
+                    .into_trait()
+            })
+        })
     }
 
     /// Given a string, attempts to rename the specified object. The object will
@@ -912,54 +2271,761 @@ impl Api {
     where
         P: Into<String>,
         Q: Into<String>,
+    {
+        let this = self.clone();
+        let new_name = new_name.into();
+        self.get_rename_target(id_or_name)
+            .and_then(move |target| this.rename_target(&target, new_name))
+            .into_trait()
+    }
+
+    /// Resolves a dataset ID, dataset name, or package ID to the object it
+    /// names, and looks up the names of its siblings.
+    pub fn get_rename_target<P>(&self, id_or_name: P) -> Future<RenameTarget>
+    where
+        P: Into<String>,
     {
         let ps = self.ps.clone();
         let id_or_name = id_or_name.into();
-        let new_name = new_name.into();
+        self.with_session(move |_| {
+            let ps_inner = ps.clone();
+            let id_inner = id_or_name.clone();
+
+            // Find the requested dataset or package
+            ps.get_dataset(id_or_name.clone())
+                .map_err(Into::<agent::Error>::into)
+                .and_then(move |dataset| {
+                    let dataset = dataset.take();
+                    ps.get_datasets()
+                        .map(move |datasets| RenameTarget {
+                            id: Into::<String>::into(dataset.id().clone()),
+                            name: dataset.name().clone(),
+                            is_dataset: true,
+                            sibling_names: datasets
+                                .into_iter()
+                                .map(|d| d.take())
+                                .filter(|d| d.id() != dataset.id())
+                                .map(|d| d.name().clone())
+                                .collect(),
+                        })
+                        .map_err(Into::into)
+                })
+                .or_else(move |_| {
+                    ps_inner
+                        .get_package_by_id(PackageId::new(id_inner.clone()))
+                        .map_err(Into::<agent::Error>::into)
+                        .and_then(move |package| {
+                            let package = package.take();
+                            // siblings are the children of the parent
+                            // collection, or of the dataset at the root:
+                            let siblings = match package.parent_id() {
+                                Some(parent_id) => to_future_trait(
+                                    ps_inner
+                                        .get_package_by_id(parent_id.clone())
+                                        .map(|parent| parent.children().cloned())
+                                        .map_err(Into::into),
+                                ),
+                                None => to_future_trait(
+                                    ps_inner
+                                        .get_dataset(Into::<String>::into(
+                                            package.dataset_id().clone(),
+                                        ))
+                                        .map(|dataset| dataset.children().cloned())
+                                        .map_err(Into::into),
+                                ),
+                            };
+                            siblings.map(move |siblings| RenameTarget {
+                                id: Into::<String>::into(package.id().clone()),
+                                name: package.name().clone(),
+                                is_dataset: false,
+                                sibling_names: siblings
+                                    .unwrap_or_else(Vec::new)
+                                    .into_iter()
+                                    .map(|p| p.take())
+                                    .filter(|p| p.id() != package.id())
+                                    .map(|p| p.name().clone())
+                                    .collect(),
+                            })
+                        })
+                })
+        })
+        .timed(self)
+    }
+
+    /// Renames a dataset or package resolved with `get_rename_target`.
+    pub fn rename_target(&self, target: &RenameTarget, new_name: String) -> Future<Renamed> {
+        let ps = self.ps.clone();
         let renamed = Renamed {
-            id: id_or_name.clone(),
+            id: target.id.clone(),
             new_name: new_name.clone(),
         };
-        self.get_user_and_refresh()
-            .and_then(move |_| {
-                let ps_inner = ps.clone();
-                let id_inner = id_or_name.clone();
-                let new_name_inner = new_name.clone();
-
-                // Find the requested dataset or package
-                ps.get_dataset(id_or_name.clone())
-                    .map(move |dataset| (ps, Some(dataset), None))
-                    .map_err(Into::<agent::Error>::into)
-                    .or_else(move |_| {
-                        ps_inner
-                            .get_package_by_id(PackageId::new(id_inner.clone()))
-                            .map(move |package| (ps_inner, None, Some(package)))
-                            .map_err(Into::into)
-                    })
-                    // then rename it
-                    .and_then(move |(ps, maybe_dataset, maybe_package)| {
-                        if let Some(dataset) = maybe_dataset {
-                            to_future_trait(
-                                ps.update_dataset(
-                                    dataset.id().clone(),
-                                    new_name_inner.clone(),
-                                    None as Option<String>,
-                                )
-                                .map(|_| ())
-                                .map_err(Into::into),
-                            )
-                        } else {
-                            // maybe_package.is_some() == true
-                            let package = maybe_package.unwrap();
-                            to_future_trait(
-                                ps.update_package(package.id().clone(), new_name_inner.clone())
-                                    .map(|_| ())
-                                    .map_err(Into::into),
-                            )
-                        }
-                    })
-            })
-            .and_then(|_| Ok(renamed))
-            .into_trait()
+        let target = target.clone();
+        self.with_session(move |_| {
+            if target.is_dataset {
+                to_future_trait(
+                    ps.update_dataset(
+                        DatasetNodeId::new(target.id),
+                        new_name,
+                        None as Option<String>,
+                    )
+                    .map(|_| ())
+                    .map_err(Into::into),
+                )
+            } else {
+                to_future_trait(
+                    ps.update_package(PackageId::new(target.id), new_name)
+                        .map(|_| ())
+                        .map_err(Into::into),
+                )
+            }
+        })
+        .and_then(|_| Ok(renamed))
+        .timed(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use tokio::runtime::current_thread::Runtime;
+
+    fn server_error() -> agent::Error {
+        pennsieve_rust::ErrorKind::ApiError {
+            status_code: hyper::StatusCode::SERVICE_UNAVAILABLE,
+            message: "service unavailable".to_string(),
+        }
+        .into()
+    }
+
+    /// Returns a request that fails with `error` the first `failures` times
+    /// it is made, and then succeeds, along with a count of the attempts.
+    fn mock_request<E>(
+        failures: usize,
+        error: E,
+    ) -> (
+        impl Fn() -> Future<&'static str> + Send + 'static,
+        Arc<AtomicUsize>,
+    )
+    where
+        E: 'static + Send + Fn() -> agent::Error,
+    {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&attempts);
+        let request = move || {
+            if counter.fetch_add(1, Ordering::SeqCst) < failures {
+                future::err(error()).into_trait()
+            } else {
+                future::ok("datasets").into_trait()
+            }
+        };
+        (request, attempts)
+    }
+
+    #[test]
+    fn retries_transient_errors_until_success() {
+        let (request, attempts) = mock_request(2, server_error);
+        let result = Runtime::new().unwrap().block_on(retry_request(
+            RateLimiter::unlimited(),
+            3,
+            Duration::from_millis(1),
+            None,
+            request,
+        ));
+
+        assert_eq!(result.unwrap(), "datasets");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn each_attempt_is_timed_out_on_its_own() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&attempts);
+        // The first attempt never completes:
+        let request = move || -> Future<&'static str> {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                future::empty().into_trait()
+            } else {
+                future::ok("datasets").into_trait()
+            }
+        };
+        let result = Runtime::new().unwrap().block_on(retry_request(
+            RateLimiter::unlimited(),
+            2,
+            Duration::from_millis(1),
+            Some(Duration::from_millis(50)),
+            request,
+        ));
+
+        assert_eq!(result.unwrap(), "datasets");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn gives_up_after_the_retry_limit() {
+        let (request, attempts) = mock_request(5, server_error);
+        let result = Runtime::new().unwrap().block_on(retry_request(
+            RateLimiter::unlimited(),
+            2,
+            Duration::from_millis(1),
+            None,
+            request,
+        ));
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn rate_limited_requests_are_backed_off_and_retried() {
+        let (request, attempts) = mock_request(2, || {
+            pennsieve_rust::ErrorKind::ApiError {
+                status_code: hyper::StatusCode::TOO_MANY_REQUESTS,
+                message: "too many requests".to_string(),
+            }
+            .into()
+        });
+        let started = Instant::now();
+        let result = Runtime::new().unwrap().block_on(retry_request(
+            RateLimiter::unlimited(),
+            3,
+            Duration::from_millis(50),
+            None,
+            request,
+        ));
+
+        assert_eq!(result.unwrap(), "datasets");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        // 50ms, then 100ms:
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn requests_are_paced_by_the_rate_limiter() {
+        let limiter = RateLimiter::new(20);
+        let (request, attempts) = mock_request(0, server_error);
+        let request = Arc::new(request);
+        let started = Instant::now();
+        for _ in 0..5 {
+            let request = Arc::clone(&request);
+            Runtime::new()
+                .unwrap()
+                .block_on(retry_request(
+                    limiter.clone(),
+                    0,
+                    Duration::from_millis(1),
+                    None,
+                    move || request(),
+                ))
+                .unwrap();
+        }
+
+        // The first request goes at once, the rest 50ms apart:
+        assert_eq!(attempts.load(Ordering::SeqCst), 5);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn slow_requests_time_out() {
+        let slow_request = Delay::new(Instant::now() + Duration::from_secs(5))
+            .map(|_| "datasets")
+            .map_err(Into::into);
+        let started = Instant::now();
+        let result = Runtime::new().unwrap().block_on(timeout_request(
+            Some(Duration::from_millis(50)),
+            slow_request,
+        ));
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        match result.unwrap_err().kind() {
+            agent::ErrorKind::ApiError {
+                kind: ErrorKind::Timeout { .. },
+            } => (),
+            kind => panic!("expected a timeout, got: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn uploaded_packages_are_tagged_with_every_tag() {
+        let mut tags = BTreeMap::new();
+        tags.insert("subject".to_string(), "mouse-12".to_string());
+        tags.insert("session".to_string(), "3".to_string());
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let set_properties = {
+            let received = received.clone();
+            move |id: model::PackageId, tags: BTreeMap<String, String>| -> Future<()> {
+                received.lock().unwrap().push((id, tags));
+                future::ok(()).into_trait()
+            }
+        };
+        let targets = vec![
+            model::PackageId::new("N:package:1"),
+            model::PackageId::new("N:collection:2"),
+        ];
+
+        Runtime::new()
+            .unwrap()
+            .block_on(tag_packages(targets.clone(), tags.clone(), set_properties))
+            .unwrap();
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(
+            received,
+            targets
+                .into_iter()
+                .map(|id| (id, tags.clone()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn fast_requests_do_not_time_out() {
+        let result = Runtime::new().unwrap().block_on(timeout_request(
+            Some(Duration::from_secs(5)),
+            future::ok::<_, agent::Error>("datasets"),
+        ));
+        assert_eq!(result.unwrap(), "datasets");
+
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(timeout_request(None, future::err::<(), _>(server_error())));
+        assert_eq!(result.unwrap_err().kind(), server_error().kind());
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (request, attempts) = mock_request(1, || {
+            pennsieve_rust::ErrorKind::ApiError {
+                status_code: hyper::StatusCode::NOT_FOUND,
+                message: "not found".to_string(),
+            }
+            .into()
+        });
+        let result = Runtime::new().unwrap().block_on(retry_request(
+            RateLimiter::unlimited(),
+            3,
+            Duration::from_millis(1),
+            None,
+            request,
+        ));
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn detects_not_found_errors() {
+        let not_found: agent::Error = pennsieve_rust::ErrorKind::ApiError {
+            status_code: hyper::StatusCode::NOT_FOUND,
+            message: "not found".to_string(),
+        }
+        .into();
+        assert!(is_not_found(&not_found));
+
+        let forbidden: agent::Error = pennsieve_rust::ErrorKind::ApiError {
+            status_code: hyper::StatusCode::FORBIDDEN,
+            message: "forbidden".to_string(),
+        }
+        .into();
+        assert!(!is_not_found(&forbidden));
+        assert!(!is_not_found(&agent::ErrorKind::TimeoutError.into()));
+    }
+
+    fn user() -> UserRecord {
+        UserRecord::new(
+            "N:user:1".to_string(),
+            "Joe Schmoe".to_string(),
+            "stale-token".to_string(),
+            "default".to_string(),
+            pennsieve_rust::Environment::NonProduction,
+            "N:organization:1".to_string(),
+            "Pennsieve".to_string(),
+            "encryption_key".to_string(),
+        )
+    }
+
+    fn api_error(status_code: hyper::StatusCode) -> agent::Error {
+        pennsieve_rust::ErrorKind::ApiError {
+            status_code,
+            message: status_code.to_string(),
+        }
+        .into()
+    }
+
+    /// Makes a request as the logged in user of `db` that fails with
+    /// `status_code`, returning its result and how often it was made.
+    fn rejected_request(
+        db: &Database,
+        status_code: hyper::StatusCode,
+    ) -> (agent::Result<()>, usize) {
+        let api = Api::new(
+            db,
+            &AgentConfig::default(),
+            &ProfileConfig::new("default", "key", "secret"),
+        );
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&attempts);
+        let result = Runtime::new().unwrap().block_on(api.with_session(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            future::err(api_error(status_code))
+        }));
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn failing_to_log_in_again_keeps_the_session() {
+        let db = crate::ps::util::database::temp().unwrap();
+        db.upsert_user(&mut user()).unwrap();
+
+        // A 401 makes the user log in again, which fails here as the
+        // profile isn't configured, so the request isn't made again:
+        let (result, attempts) = rejected_request(&db, hyper::StatusCode::UNAUTHORIZED);
+        assert_eq!(attempts, 1);
+        match result.unwrap_err().kind() {
+            agent::ErrorKind::ApiError {
+                kind: ErrorKind::NoUserProfileError,
+            } => {}
+            kind => panic!("unexpected error: {}", kind),
+        }
+        assert_eq!(db.get_user().unwrap().unwrap().session_token, "stale-token");
+
+        // ...as does a login that doesn't get through:
+        let result: agent::Result<()> = Runtime::new().unwrap().block_on(relogin_and_retry(
+            db.clone(),
+            None,
+            future::err(agent::ErrorKind::TimeoutError.into()).into_trait(),
+            |_| future::ok(()),
+        ));
+        assert!(!is_unauthorized(&result.unwrap_err(), true));
+        assert_eq!(db.get_user().unwrap().unwrap().session_token, "stale-token");
+    }
+
+    #[test]
+    fn rejected_credentials_clear_the_stale_session() {
+        let relogin = |login: Future<UserRecord>| {
+            let db = crate::ps::util::database::temp().unwrap();
+            db.upsert_user(&mut user()).unwrap();
+            let result: agent::Result<()> = Runtime::new().unwrap().block_on(relogin_and_retry(
+                db.clone(),
+                None,
+                login,
+                |_| future::err(api_error(hyper::StatusCode::UNAUTHORIZED)),
+            ));
+            match result.unwrap_err().kind() {
+                agent::ErrorKind::ApiError {
+                    kind: ErrorKind::Unauthorized { profile },
+                } => assert_eq!(profile, "default"),
+                kind => panic!("unexpected error: {}", kind),
+            }
+            assert_eq!(db.get_user().unwrap(), None);
+        };
+
+        // The login is rejected...
+        relogin(future::err(api_error(hyper::StatusCode::UNAUTHORIZED)).into_trait());
+        // ...or the request is rejected even with a new session:
+        relogin(future::ok(user()).into_trait());
+    }
+
+    #[test]
+    fn a_forced_refresh_logs_in_even_with_a_valid_session() {
+        let db = crate::ps::util::database::temp().unwrap();
+        let mut cached = user();
+        db.upsert_user(&mut cached).unwrap();
+        assert!(cached.is_token_valid());
+
+        // The profile isn't configured, so logging in again fails before
+        // any request is made:
+        let config = AgentConfig::default();
+        let api = Api::new(
+            &db,
+            &config,
+            &ProfileConfig::new("default", "key", "secret"),
+        );
+        let mut runtime = Runtime::new().unwrap();
+
+        let refreshed = runtime.block_on(api.get_user_and_refresh()).unwrap();
+        assert_eq!(refreshed.session_token, "stale-token");
+
+        match runtime.block_on(api.get_user_and_force_refresh()) {
+            Err(e) => match e.kind() {
+                agent::ErrorKind::ApiError {
+                    kind: ErrorKind::NoUserProfileError,
+                } => {}
+                kind => panic!("unexpected error: {}", kind),
+            },
+            Ok(user) => panic!("the cached session was kept: {:?}", user),
+        }
+    }
+
+    #[test]
+    fn forbidden_requests_keep_the_session() {
+        let db = crate::ps::util::database::temp().unwrap();
+        db.upsert_user(&mut user()).unwrap();
+
+        let (result, attempts) = rejected_request(&db, hyper::StatusCode::FORBIDDEN);
+        assert_eq!(attempts, 1);
+        assert!(!is_unauthorized(&result.unwrap_err(), false));
+        assert_eq!(db.get_user().unwrap().unwrap().session_token, "stale-token");
+
+        // ...but a 403 when logging in means the key was rejected:
+        let (request, _) = mock_request(1, || api_error(hyper::StatusCode::FORBIDDEN));
+        let result = Runtime::new().unwrap().block_on(check_credentials(
+            db.clone(),
+            Some("lab".to_string()),
+            true,
+            request(),
+        ));
+        match result.unwrap_err().kind() {
+            agent::ErrorKind::ApiError {
+                kind: ErrorKind::Unauthorized { profile },
+            } => assert_eq!(profile, "lab"),
+            kind => panic!("unexpected error: {}", kind),
+        }
+        assert_eq!(db.get_user().unwrap(), None);
+    }
+
+    fn datasets() -> Vec<(String, String)> {
+        vec![
+            ("N:dataset:1", "recordings"),
+            ("N:dataset:2", "N:dataset:1"),
+            ("N:dataset:3", "recordings"),
+            ("N:dataset:4", "annotations"),
+        ]
+        .into_iter()
+        .map(|(id, name)| (id.to_string(), name.to_string()))
+        .collect()
+    }
+
+    /// Returns a lookup resolving to `node_id`, counting the lookups made
+    /// in `lookups`.
+    fn counted_lookup(
+        lookups: &Arc<AtomicUsize>,
+        node_id: &'static str,
+    ) -> impl FnOnce() -> Future<String> {
+        let lookups = Arc::clone(lookups);
+        move || {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            future::ok(node_id.to_string()).into_trait()
+        }
+    }
+
+    #[test]
+    fn resolved_names_are_cached_until_they_expire() {
+        let db = crate::ps::util::database::temp().unwrap();
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolve = |ttl, node_id| {
+            Runtime::new().unwrap().block_on(resolve_name(
+                db.clone(),
+                "N:organization:1".to_string(),
+                "recordings".to_string(),
+                ttl,
+                counted_lookup(&lookups, node_id),
+            ))
+        };
+        let ttl = Some(Duration::from_secs(60));
+
+        assert_eq!(resolve(ttl, "N:dataset:1").unwrap(), "N:dataset:1");
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        // A second resolution within the TTL hits the cache:
+        assert_eq!(resolve(ttl, "N:dataset:2").unwrap(), "N:dataset:1");
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        // ...unless the cache is turned off:
+        assert_eq!(resolve(None, "N:dataset:2").unwrap(), "N:dataset:2");
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        // Once the resolution has expired, the name is looked up again:
+        thread::sleep(Duration::from_millis(10));
+        let ttl = Some(Duration::from_secs(0));
+        assert_eq!(resolve(ttl, "N:dataset:3").unwrap(), "N:dataset:3");
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            resolve(Some(Duration::from_secs(60)), "N:dataset:4").unwrap(),
+            "N:dataset:3"
+        );
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn ambiguous_dataset_names_list_the_candidate_ids() {
+        assert_eq!(
+            dataset_id_by_name("annotations", datasets()).unwrap(),
+            "N:dataset:4"
+        );
+        assert_eq!(
+            dataset_id_by_name("recordings", datasets())
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::AmbiguousDatasetName {
+                name: "recordings".to_string(),
+                ids: "N:dataset:1, N:dataset:3".to_string(),
+            }
+        );
+        assert_eq!(
+            dataset_id_by_name("images", datasets()).unwrap_err().kind(),
+            &ErrorKind::DatasetNameNotFound {
+                name: "images".to_string()
+            }
+        );
+
+        // an ambiguous name is never treated as missing, so it isn't
+        // created again by `get_or_create_dataset`:
+        let error: agent::Error = dataset_id_by_name("recordings", datasets())
+            .unwrap_err()
+            .into();
+        assert!(is_ambiguous_dataset_name(&error));
+        assert!(!is_not_found(&error));
+    }
+
+    #[test]
+    fn forced_ids_are_never_resolved_as_names() {
+        assert_eq!(DatasetLookup::Id.by_name("N:dataset:1").unwrap(), false);
+        assert_eq!(
+            DatasetLookup::Id.by_name("recordings").unwrap_err().kind(),
+            &ErrorKind::InvalidDatasetId {
+                id: "recordings".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn datasets_are_resolved_by_id_or_name() {
+        assert_eq!(DatasetLookup::default(), DatasetLookup::IdOrName);
+        assert_eq!(
+            DatasetLookup::IdOrName.by_name("N:dataset:1").unwrap(),
+            false
+        );
+        assert_eq!(DatasetLookup::IdOrName.by_name("recordings").unwrap(), true);
+
+        // a name that looks like an ID can still be used by name:
+        assert_eq!(DatasetLookup::Name.by_name("N:dataset:1").unwrap(), true);
+        assert_eq!(
+            dataset_id_by_name("N:dataset:1", datasets()).unwrap(),
+            "N:dataset:2"
+        );
+    }
+
+    fn organizations() -> Vec<OrganizationScope> {
+        vec![
+            OrganizationScope::new("N:organization:1", "Pennsieve", "key-1"),
+            OrganizationScope::new("N:organization:2", "Neuro Lab", "key-2"),
+        ]
+    }
+
+    #[test]
+    fn switching_to_another_organization_requires_membership() {
+        assert_eq!(
+            find_organization("N:organization:3", organizations())
+                .unwrap_err()
+                .kind(),
+            &ErrorKind::NotAnOrganizationMember {
+                organization: "N:organization:3".to_string()
+            }
+        );
+        assert!(find_organization("Other Lab", organizations()).is_err());
+        assert!(find_organization("Neuro Lab", vec![]).is_err());
+    }
+
+    #[test]
+    fn switching_organizations_rescopes_the_user() {
+        let user = UserRecord::new(
+            "N:user:1",
+            "Joe Schmoe",
+            "token",
+            "default",
+            pennsieve_rust::Environment::NonProduction,
+            "N:organization:1",
+            "Pennsieve",
+            "key-1",
+        );
+
+        let scope = find_organization("Neuro Lab", organizations()).unwrap();
+        assert_eq!(
+            scope,
+            find_organization("N:organization:2", organizations()).unwrap()
+        );
+
+        let scoped = scope.scope(user.clone());
+        assert_eq!(scoped.organization_id, "N:organization:2");
+        assert_eq!(scoped.organization_name, "Neuro Lab");
+        assert_eq!(scoped.encryption_key, "key-2");
+        assert_eq!(scoped.session_token, user.session_token);
+        assert_eq!(scoped.profile, user.profile);
+
+        // a client scoped to an organization the user is already in needs
+        // no membership check:
+        let db = crate::ps::util::database::temp().unwrap();
+        let api = Api::new(
+            &db,
+            &AgentConfig::default(),
+            &ProfileConfig::new("default", "key", "secret"),
+        )
+        .with_organization(Some("Neuro Lab"));
+        assert_eq!(
+            api.organization.as_ref().map(String::as_str),
+            Some("Neuro Lab")
+        );
+        assert_eq!(api.organization_scope(), None);
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(api.scope_user(scoped.clone()));
+        assert_eq!(result.unwrap(), scoped);
+
+        let api = api.with_organization(None as Option<String>);
+        let result = Runtime::new()
+            .unwrap()
+            .block_on(api.scope_user(user.clone()));
+        assert_eq!(result.unwrap(), user);
+    }
+
+    #[test]
+    fn sessions_from_another_environment_require_a_login() {
+        let db = crate::ps::util::database::temp().unwrap();
+        let mut config: AgentConfig = AgentConfig::default();
+        let profile = ProfileConfig::new("default", "key", "secret");
+        config.api_settings.add_profile(profile.clone());
+        let user = user();
+        assert_eq!(user.environment, pennsieve_rust::Environment::NonProduction);
+
+        let api = Api::new(&db, &config, &profile);
+        assert!(api.environment_changed(&user));
+
+        // Overriding the environment for a run only changes the copy of
+        // the configuration used for it:
+        let overridden = config
+            .clone()
+            .with_environment(pennsieve_rust::Environment::NonProduction);
+        let api = Api::new(
+            &db,
+            &overridden,
+            &profile.with_environment(pennsieve_rust::Environment::NonProduction),
+        );
+        assert!(!api.environment_changed(&user));
+        assert_eq!(
+            config
+                .api_settings
+                .get_profile("default")
+                .unwrap()
+                .environment,
+            pennsieve_rust::Environment::Production
+        );
+    }
+
+    #[test]
+    fn only_the_folders_files_are_placed_in_are_walked() {
+        let folders = target_folders(vec![
+            "a.txt".to_string(),
+            "data/b.txt".to_string(),
+            "data/nested/deeper/c.txt".to_string(),
+        ]);
+        let mut folders: Vec<String> = folders.into_iter().collect();
+        folders.sort();
+        assert_eq!(folders, vec!["data", "data/nested", "data/nested/deeper"]);
     }
 }
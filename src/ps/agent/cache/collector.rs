@@ -10,7 +10,7 @@ use futures::Future as _Future;
 use log::*;
 use tokio::timer::Interval;
 
-use crate::ps::agent::cache::{self, Error, Page, Result};
+use crate::ps::agent::cache::{self, Error, EvictionPolicy, Page, Result};
 use crate::ps::agent::config::CacheConfig as Config;
 use crate::ps::agent::database::{Database, PageRecord};
 use crate::ps::agent::messages::Response;
@@ -73,13 +73,50 @@ impl CachePageCollector {
             // This case is fixed once that page is cached again.
             db.delete_page(&record)?;
 
-            let (package, channel, _, index) = cache::from_page_key(&record.id);
-            let page = Page::new(config, &package, &channel, 0, 0, index);
+            // Pages cached with an earlier page size are stored apart from
+            // the current ones:
+            let (package, channel, page_size, index) = cache::from_page_key(&record.id);
+            let mut config = config.clone();
+            config.set_page_size(page_size);
+            let page = Page::new(&config, &package, &channel, 0, 0, index);
 
             page.delete()
         })
     }
 
+    /// Removes cache pages that were cached with a page size other than the
+    /// configured one. These can no longer be read, but still count towards
+    /// the size of the cache. Returns the number of pages removed.
+    pub fn evict_mismatched_pages(&self) -> Result<usize> {
+        let id = self.id();
+
+        let pages = self.borrow_props(|props: Option<&Props>| {
+            let props: &Props = props.unwrap_or_else(|| panic!("{:?}: missing props", id));
+            props
+                .db
+                .get_pages_with_other_page_size(props.config.page_size())
+                .map(|pages| (pages.collect::<Vec<_>>(), props.config.page_size()))
+        });
+        let (pages, page_size) = pages?;
+
+        if !pages.is_empty() {
+            warn!(
+                "Found {} cache page(s) with a page size other than the configured size of {}; removing them",
+                pages.len(),
+                page_size
+            );
+        }
+
+        for page in &pages {
+            if let Err(e) = self.remove_page(page) {
+                // the file may already be gone:
+                warn!("Couldn't remove cache page {}: {}", page.id, e);
+            }
+        }
+
+        Ok(pages.len())
+    }
+
     /// Removes cache pages according to the soft aged records
     /// implementation.
     pub fn soft_recycle(&self) -> Result<i64> {
@@ -97,8 +134,18 @@ impl CachePageCollector {
                 config.soft_cache_size()
             );
 
-            let recycled =
-                cache::soft_cleanup(self, db, config.soft_cache_size(), &mut current_size)?;
+            let recycled = match config.eviction_policy() {
+                EvictionPolicy::TimeAged => cache::soft_cleanup(
+                    self,
+                    db,
+                    config.soft_cache_size(),
+                    config.into(),
+                    &mut current_size,
+                )?,
+                EvictionPolicy::Lru => {
+                    cache::lru_cleanup(self, db, config.soft_cache_size(), &mut current_size)?
+                }
+            };
 
             if recycled > 0 {
                 info!("Soft recycling recaptured {} page(s)", recycled);
@@ -125,8 +172,18 @@ impl CachePageCollector {
                 config.soft_cache_size()
             );
 
-            let recycled =
-                cache::hard_cleanup(self, db, config.hard_cache_size(), &mut current_size)?;
+            let recycled = match config.eviction_policy() {
+                EvictionPolicy::TimeAged => cache::hard_cleanup(
+                    self,
+                    db,
+                    config.hard_cache_size(),
+                    config.into(),
+                    &mut current_size,
+                )?,
+                EvictionPolicy::Lru => {
+                    cache::lru_cleanup(self, db, config.hard_cache_size(), &mut current_size)?
+                }
+            };
 
             if current_size as u64 > config.hard_cache_size() {
                 let msg = format!(
@@ -152,6 +209,10 @@ impl Handler<messages::WorkerStartup> for CachePageCollector {
 
     fn handle(&mut self, _msg: messages::WorkerStartup, _ctx: &mut Self::Context) -> Self::Result {
         let id = self.id();
+        self.heartbeat();
+        if let Err(e) = self.evict_mismatched_pages() {
+            error!("Couldn't remove mismatched cache pages {:?}", e);
+        }
         Arbiter::spawn(ServiceFuture::wrap(self.run()).map_err(move |e| {
             e.render_with_context(id);
             a::send_unconditionally::<server::StatusServer, _>(Response::error(e));
@@ -166,6 +227,14 @@ impl Worker for CachePageCollector {
 }
 
 impl CachePageCollector {
+    /// Records that the collector is alive.
+    fn heartbeat(&self) {
+        server::heartbeat::beat(
+            self.id(),
+            time::Duration::from_secs(config::constants::CACHE_COLLECTOR_STALL_SECS),
+        );
+    }
+
     fn run(&self) -> Future<()> {
         // This is needed due to the 'static constraint placed on the returned Future.
         // Cloning `Collector` is cheap because copies are just refcounted.
@@ -187,6 +256,7 @@ impl CachePageCollector {
         let f = timer
             .map_err(Into::<agent::Error>::into)
             .fold(0, move |step, _| -> agent::Future<i32> {
+                this.heartbeat();
                 if step < 5 {
                     this.soft_recycle().map(|_| step + 1).or_else(|e| {
                         warn!("Soft recycle failure {:?}", e);
@@ -304,6 +374,100 @@ mod test {
         assert_eq!(CachePageCollector.soft_recycle().unwrap(), 200);
     }
 
+    #[test]
+    fn soft_recycle_lru_evicts_least_recently_used_first() {
+        let mut config = Config::new(
+            &*TEMP_DIR, // base_path
+            150,        // page_size
+            100,        // soft_cache_size
+            0,          // hard_cache_size
+        );
+        config.set_eviction_policy(EvictionPolicy::Lru);
+        assert!(cache::create_page_template(&config).is_ok());
+
+        let page_creator = PageCreator::new();
+        let db = util::database::temp().unwrap();
+
+        // All pages were used recently, so none would be evicted by the
+        // time aged policy:
+        let records = vec![
+            ("p1.c_collector_lru_1.150.1", 60, Duration::minutes(30)),
+            ("p1.c_collector_lru_1.150.2", 60, Duration::minutes(20)),
+            ("p1.c_collector_lru_1.150.3", 60, Duration::minutes(10)),
+        ];
+        for (id, size, age) in records {
+            let (package, channel, _, index) = cache::from_page_key(id);
+            let page = Page::new(&config, &package, &channel, 0, 0, index);
+            page_creator
+                .copy_page_template(&page.path, &config)
+                .unwrap();
+            db.upsert_page(&PageRecord {
+                id: String::from(id),
+                nan_filled: false,
+                complete: true,
+                size,
+                last_used: now_utc().to_timespec() - age,
+            })
+            .unwrap();
+        }
+
+        CachePageCollector::with_props(Props {
+            config,
+            db: db.clone(),
+        });
+
+        assert_eq!(CachePageCollector.soft_recycle().unwrap(), 60);
+        assert!(db.get_page("p1.c_collector_lru_1.150.1").is_err());
+        assert!(db.get_page("p1.c_collector_lru_1.150.2").is_err());
+        assert!(db.get_page("p1.c_collector_lru_1.150.3").is_ok());
+    }
+
+    #[test]
+    fn hard_recycle_lru_evicts_down_to_target() {
+        let mut config = Config::new(
+            &*TEMP_DIR, // base_path
+            150,        // page_size
+            0,          // soft_cache_size
+            100,        // hard_cache_size
+        );
+        config.set_eviction_policy(EvictionPolicy::Lru);
+        assert!(cache::create_page_template(&config).is_ok());
+
+        let page_creator = PageCreator::new();
+        let db = util::database::temp().unwrap();
+
+        let records = vec![
+            ("p1.c_collector_lru_2.150.1", 50, Duration::hours(1)),
+            ("p1.c_collector_lru_2.150.2", 50, Duration::hours(3)),
+            ("p1.c_collector_lru_2.150.3", 50, Duration::hours(2)),
+        ];
+        for (id, size, age) in records {
+            let (package, channel, _, index) = cache::from_page_key(id);
+            let page = Page::new(&config, &package, &channel, 0, 0, index);
+            page_creator
+                .copy_page_template(&page.path, &config)
+                .unwrap();
+            db.upsert_page(&PageRecord {
+                id: String::from(id),
+                nan_filled: false,
+                complete: true,
+                size,
+                last_used: now_utc().to_timespec() - age,
+            })
+            .unwrap();
+        }
+
+        CachePageCollector::with_props(Props {
+            config,
+            db: db.clone(),
+        });
+
+        assert_eq!(CachePageCollector.hard_recycle().unwrap(), 100);
+        assert!(db.get_page("p1.c_collector_lru_2.150.1").is_ok());
+        assert!(db.get_page("p1.c_collector_lru_2.150.2").is_err());
+        assert!(db.get_page("p1.c_collector_lru_2.150.3").is_ok());
+    }
+
     #[test]
     fn hard_recycle_with_deletes() {
         let config = Config::new(
@@ -413,4 +577,52 @@ mod test {
 
         assert!(CachePageCollector.hard_recycle().is_err());
     }
+
+    #[test]
+    fn pages_with_another_page_size_are_evicted() {
+        let mut config = Config::new(
+            &*TEMP_DIR, // base_path
+            150,        // page_size
+            0,          // soft_cache_size
+            0,          // hard_cache_size
+        );
+        let page_creator = PageCreator::new();
+        let db = util::database::temp().unwrap();
+
+        // Pages cached before and after the page size was changed to 150:
+        let records = vec![
+            ("p1.c_collector_size_1.100.1", 100),
+            ("p1.c_collector_size_1.100.2", 100),
+            ("p1.c_collector_size_1.150.1", 150),
+        ];
+        let mut paths = Vec::new();
+        for (id, page_size) in records {
+            config.set_page_size(page_size);
+            assert!(cache::create_page_template(&config).is_ok());
+            let (package, channel, _, index) = cache::from_page_key(id);
+            let page = Page::new(&config, &package, &channel, 0, 0, index);
+            page_creator
+                .copy_page_template(&page.path, &config)
+                .unwrap();
+            paths.push(page.path);
+            db.upsert_page(&PageRecord::new(id, false, true, i64::from(page_size)))
+                .unwrap();
+        }
+        assert_eq!(db.get_total_size().unwrap(), 350);
+
+        CachePageCollector::with_props(Props {
+            config,
+            db: db.clone(),
+        });
+
+        assert_eq!(CachePageCollector.evict_mismatched_pages().unwrap(), 2);
+        assert_eq!(db.get_total_size().unwrap(), 150);
+        assert!(db.get_page("p1.c_collector_size_1.100.1").is_err());
+        assert!(db.get_page("p1.c_collector_size_1.150.1").is_ok());
+        assert!(!paths[0].exists());
+        assert!(!paths[1].exists());
+        assert!(paths[2].exists());
+
+        assert_eq!(CachePageCollector.evict_mismatched_pages().unwrap(), 0);
+    }
 }
@@ -34,12 +34,27 @@ impl Error {
         .into()
     }
 
+    pub fn corrupt_page<S: Into<String>>(page: S) -> Error {
+        ErrorKind::CorruptPage { page: page.into() }.into()
+    }
+
     pub fn no_space<S: Into<String>>(message: S) -> Error {
         ErrorKind::NoSpace {
             message: message.into(),
         }
         .into()
     }
+
+    pub fn not_cached(pages: usize) -> Error {
+        ErrorKind::NotCached { pages }.into()
+    }
+
+    pub fn invalid_export_format<S: Into<String>>(format: S) -> Error {
+        ErrorKind::InvalidExportFormat {
+            format: format.into(),
+        }
+        .into()
+    }
 }
 
 impl Fail for Error {
@@ -75,12 +90,24 @@ pub enum ErrorKind {
     #[fail(display = "invalid channel: {}", channel)]
     InvalidChannel { channel: String },
 
+    #[fail(display = "corrupt page: {}", page)]
+    CorruptPage { page: String },
+
     #[fail(display = "collector cancelled")]
     CollectorCancelled,
 
     #[fail(display = "no available space: {}", message)]
     NoSpace { message: String },
 
+    #[fail(
+        display = "{} pages of the window are not cached; cache them with `cache prefetch` first",
+        pages
+    )]
+    NotCached { pages: usize },
+
+    #[fail(display = "invalid export format: {} (expected csv or npy)", format)]
+    InvalidExportFormat { format: String },
+
     #[fail(display = "io error: {}", error)]
     IoError { error: String },
 
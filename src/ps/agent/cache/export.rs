@@ -0,0 +1,342 @@
+//! Writing cached timeseries data out to files, as done by `cache export`.
+
+use std::collections::BTreeMap;
+use std::f64;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use log::*;
+
+use crate::ps::agent::database;
+use crate::ps::proto::timeseries::AgentTimeSeriesResponse;
+
+use super::{Config, Error, Request, Result};
+
+/// The length of the chunks read from the cache while exporting, in
+/// microseconds.
+pub const EXPORT_CHUNK_SIZE: u32 = 1_000_000;
+
+/// The formats cached timeseries data can be exported in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A `timestamp,value` line per sample.
+    Csv,
+    /// A NumPy array of `(timestamp, value)` rows of 64-bit floats.
+    Npy,
+}
+
+impl ExportFormat {
+    /// The file extension of exported files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Npy => "npy",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "npy" => Ok(ExportFormat::Npy),
+            _ => Err(Error::invalid_export_format(s)),
+        }
+    }
+}
+
+/// A channel's samples, as (timestamp, value) pairs.
+pub type Series = Vec<(u64, f64)>;
+
+/// Reads the window of `request` out of the cache, returning the series of
+/// every channel by channel id. Every series has a sample for each period
+/// of the window; samples that are not available are NaN.
+///
+/// Every page of the window must be cached, with `cache prefetch` or by
+/// the timeseries server.
+pub fn read_series(
+    request: &Request,
+    config: &Config,
+    db: &database::Database,
+) -> Result<BTreeMap<String, Series>> {
+    let response = request.get_response(config);
+
+    let mut missing = 0;
+    for key in response.pages.keys() {
+        if let Err(e) = db.get_page(key) {
+            match e.kind() {
+                database::ErrorKind::QueryReturnedNoRows => missing += 1,
+                _ => return Err(e.into()),
+            }
+        }
+    }
+    if missing > 0 {
+        return Err(Error::not_cached(missing));
+    }
+
+    let mut series: BTreeMap<String, Series> = request
+        .channels()
+        .iter()
+        .map(|channel| (channel.id().clone(), Vec::new()))
+        .collect();
+
+    for bytes in response.owned_chunk_response_iter(db.clone()) {
+        let mut response: AgentTimeSeriesResponse = protobuf::parse_from_bytes(&bytes?)?;
+        for channel in response.take_chunk().take_channels().into_vec() {
+            let samples = series
+                .get_mut(&channel.id)
+                .ok_or_else(|| Error::invalid_channel(channel.id.clone()))?;
+            samples.extend(channel.data.iter().map(|datum| (datum.time, datum.value)));
+        }
+    }
+
+    for channel in request.channels() {
+        if let Some(samples) = series.get_mut(channel.id()) {
+            *samples = fill_gaps(samples, request.start(), request.end(), channel.period());
+        }
+    }
+
+    Ok(series)
+}
+
+/// Adds a NaN sample for every period between `start` and `end`, inclusive,
+/// that `samples` has no sample for. Samples after `end` are dropped.
+///
+/// Sample times are whole microseconds, so at rates whose period isn't a
+/// whole number of them, such as 256 Hz, consecutive samples are a period
+/// apart give or take one. A sample is only missing once the next one is
+/// more than one and a half periods away.
+fn fill_gaps(samples: &[(u64, f64)], start: u64, end: u64, period: f64) -> Series {
+    let period = period.max(1.0);
+    let mut filled = Vec::new();
+    // The time of the last sample, a period before `start` to begin with:
+    let mut last = start as f64 - period;
+
+    for &(time, value) in samples.iter().filter(|(time, _)| *time <= end) {
+        while time as f64 - last > 1.5 * period {
+            last += period;
+            filled.push((last.round() as u64, f64::NAN));
+        }
+        filled.push((time, value));
+        last = time as f64;
+    }
+    while last + period <= end as f64 {
+        last += period;
+        filled.push((last.round() as u64, f64::NAN));
+    }
+
+    filled
+}
+
+/// Writes the series as CSV, with a header line. Gaps are written as `NaN`.
+fn write_csv<W: Write>(mut writer: W, samples: &[(u64, f64)]) -> io::Result<()> {
+    writeln!(writer, "timestamp,value")?;
+    for (time, value) in samples {
+        writeln!(writer, "{},{}", time, value)?;
+    }
+    writer.flush()
+}
+
+/// Writes the series as a version 1.0 `.npy` file holding an `(n, 2)`
+/// array of little endian 64-bit floats.
+fn write_npy<W: Write>(mut writer: W, samples: &[(u64, f64)]) -> io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, 2), }}",
+        samples.len()
+    );
+    // The magic string, version and header length take 10 bytes. The
+    // header is padded with spaces and ends with a newline, so the data
+    // starts on a 64 byte boundary:
+    let len = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - len % 64) % 64));
+    header.push('\n');
+
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_u16::<LittleEndian>(header.len() as u16)?;
+    writer.write_all(header.as_bytes())?;
+    for &(time, value) in samples {
+        writer.write_f64::<LittleEndian>(time as f64)?;
+        writer.write_f64::<LittleEndian>(value)?;
+    }
+    writer.flush()
+}
+
+/// Exports the window of `request` from the cache to `dir`, writing a file
+/// per channel named after the channel id. Returns the paths written.
+pub fn export<P: AsRef<Path>>(
+    request: &Request,
+    config: &Config,
+    db: &database::Database,
+    format: ExportFormat,
+    dir: P,
+) -> Result<Vec<PathBuf>> {
+    let series = read_series(request, config, db)?;
+
+    fs::create_dir_all(dir.as_ref())?;
+    let mut paths = Vec::with_capacity(series.len());
+    for (channel_id, samples) in series {
+        let path = dir
+            .as_ref()
+            .join(format!("{}.{}", channel_id, format.extension()));
+        info!(
+            "exporting {} samples of {} to {:?}",
+            samples.len(),
+            channel_id,
+            path
+        );
+
+        let writer = BufWriter::new(File::create(&path)?);
+        match format {
+            ExportFormat::Csv => write_csv(writer, &samples)?,
+            ExportFormat::Npy => write_npy(writer, &samples)?,
+        }
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use byteorder::{ByteOrder, LittleEndian};
+    use tempfile::tempdir;
+
+    use crate::ps::agent::cache::{create_page_template, Channel, ErrorKind, PageCreator};
+    use crate::ps::proto::timeseries::Segment;
+    use crate::ps::util;
+
+    /// Caches the window from 10 to 19 of one channel, sampled at 1MHz over
+    /// pages of 5 samples. The first page is a gap; the second holds the
+    /// values 5 to 9.
+    fn cache_window(config: &Config, db: &database::Database) -> Request {
+        assert!(create_page_template(config).is_ok());
+        let request = Request::new("p1", vec![Channel::new("c1", 1e6)], 10, 19, 10, true);
+
+        let mut gap = Segment::new();
+        gap.set_startTs(10);
+        gap.set_source(String::from("c1"));
+        gap.set_samplePeriod(1f64);
+
+        let mut data = Segment::new();
+        data.set_startTs(15);
+        data.set_source(String::from("c1"));
+        data.set_samplePeriod(1f64);
+        data.set_data(vec![5.0, 6.0, 7.0, 8.0, 9.0]);
+
+        let page_creator = PageCreator::new();
+        let mut response = request.get_response(config);
+        assert_eq!(response.uncached_page_requests(db).unwrap().count(), 2);
+        response.cache_response(&page_creator, &gap).unwrap();
+        response.cache_response(&page_creator, &data).unwrap();
+        response.record_page_requests(db).unwrap();
+
+        request
+    }
+
+    #[test]
+    fn exporting_a_cached_window_as_csv() {
+        let cache_dir = tempdir().unwrap();
+        let config = Config::new(cache_dir.path(), 5, 0, 0);
+        let db = util::database::temp().unwrap();
+        let request = cache_window(&config, &db);
+
+        let out = tempdir().unwrap();
+        let paths = export(&request, &config, &db, ExportFormat::Csv, out.path()).unwrap();
+        assert_eq!(paths, vec![out.path().join("c1.csv")]);
+
+        let csv = fs::read_to_string(&paths[0]).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("timestamp,value"));
+        let rows: Vec<&str> = lines.collect();
+        assert_eq!(
+            rows,
+            vec![
+                "10,NaN", "11,NaN", "12,NaN", "13,NaN", "14,NaN", "15,5", "16,6", "17,7", "18,8",
+                "19,9"
+            ]
+        );
+    }
+
+    #[test]
+    fn exporting_a_cached_window_as_npy() {
+        let cache_dir = tempdir().unwrap();
+        let config = Config::new(cache_dir.path(), 5, 0, 0);
+        let db = util::database::temp().unwrap();
+        let request = cache_window(&config, &db);
+
+        let out = tempdir().unwrap();
+        let paths = export(&request, &config, &db, ExportFormat::Npy, out.path()).unwrap();
+        let npy = fs::read(&paths[0]).unwrap();
+
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        let header_len = LittleEndian::read_u16(&npy[8..10]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = String::from_utf8(npy[10..10 + header_len].to_vec()).unwrap();
+        assert!(header.contains("'shape': (10, 2)"));
+        assert!(header.ends_with('\n'));
+
+        let data = &npy[10 + header_len..];
+        assert_eq!(data.len(), 10 * 2 * 8);
+        let mut values = vec![0f64; 20];
+        LittleEndian::read_f64_into(data, &mut values);
+        assert_eq!(values[0], 10.0);
+        assert!(values[1].is_nan());
+        assert_eq!(&values[10..12], &[15.0, 5.0]);
+        assert_eq!(&values[18..20], &[19.0, 9.0]);
+    }
+
+    #[test]
+    fn exporting_an_uncached_window_fails() {
+        let cache_dir = tempdir().unwrap();
+        let config = Config::new(cache_dir.path(), 5, 0, 0);
+        let db = util::database::temp().unwrap();
+        assert!(create_page_template(&config).is_ok());
+        let request = Request::new("p1", vec![Channel::new("c1", 1e6)], 10, 19, 10, true);
+
+        let out = tempdir().unwrap();
+        let e = export(&request, &config, &db, ExportFormat::Csv, out.path()).unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::NotCached { pages: 2 });
+        assert!(!out.path().join("c1.csv").exists());
+    }
+
+    #[test]
+    fn samples_a_fraction_of_a_microsecond_apart_leave_no_gaps() {
+        // 256 Hz: a period of 3906.25us, so samples are 3906 or 3907us
+        // apart. The eleventh sample is missing:
+        let period = 1e6 / 256.0;
+        let samples: Vec<(u64, f64)> = (0..20)
+            .filter(|i| *i != 10)
+            .map(|i| ((f64::from(i) * period) as u64, f64::from(i)))
+            .collect();
+        let end = (19.0 * period) as u64;
+
+        let filled = fill_gaps(&samples, 0, end, period);
+        assert_eq!(filled.len(), 20);
+        let gaps: Vec<u64> = filled
+            .iter()
+            .filter(|(_, value)| value.is_nan())
+            .map(|(time, _)| *time)
+            .collect();
+        assert_eq!(gaps, vec![39_062]);
+        assert_eq!(filled[11], (42_968, 11.0));
+
+        // A window running past the last sample is filled to its end:
+        let filled = fill_gaps(&samples, 0, end + 2 * 3907, period);
+        assert_eq!(filled.len(), 22);
+        assert!(filled[21].1.is_nan());
+    }
+
+    #[test]
+    fn export_formats_are_parsed() {
+        assert_eq!("CSV".parse::<ExportFormat>().unwrap(), ExportFormat::Csv);
+        assert_eq!("npy".parse::<ExportFormat>().unwrap(), ExportFormat::Npy);
+        assert!("xlsx".parse::<ExportFormat>().is_err());
+    }
+}
@@ -10,24 +10,48 @@ use std::vec::IntoIter;
 use std::{cmp, fs, io};
 
 use byteorder::{ByteOrder, NativeEndian};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{stream, Future, Stream};
+use futures_cpupool::{CpuFuture, CpuPool};
+use lazy_static::lazy_static;
 use log::*;
 use protobuf::repeated::RepeatedField;
 use protobuf::Message;
 
+use crate::ps::agent::config::constants as c;
 use crate::ps::agent::database;
 use crate::ps::proto;
 use crate::ps::proto::timeseries::{AgentTimeSeriesResponse, ChunkResponse, Segment};
 
 mod collector;
 mod error;
+mod export;
+mod prefetch;
+mod verify;
 
 pub use self::collector::{CachePageCollector, Props};
 pub use self::error::{Error, ErrorKind, Result};
-pub use crate::ps::agent::config::CacheConfig as Config;
+pub use self::export::{export, read_series, ExportFormat, Series, EXPORT_CHUNK_SIZE};
+pub use self::prefetch::{prefetch, Prefetched};
+pub use self::verify::{verify, CacheReport};
+pub use crate::ps::agent::config::{CacheConfig as Config, EvictionPolicy};
 
 /// Number of bits in a byte.
 const BYTE_WIDTH: usize = 8;
 
+/// The leading bytes of a gzip stream, as written by `GzEncoder`. These are
+/// used to tell compressed pages apart from uncompressed pages written
+/// before compression was enabled.
+const GZIP_MAGIC: [u8; 4] = [0x1f, 0x8b, 0x08, 0x00];
+
+lazy_static! {
+    // The checksums of the pages verified since the agent started, by page
+    // key, so a page is only hashed the first time it is read:
+    static ref VERIFIED_PAGES: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
 /// Converts hz to microseconds.
 fn hz_to_us(hz: f64) -> f64 {
     1e6 / hz
@@ -102,8 +126,49 @@ fn get_offset(start: u64, page_start: u64, period: f64) -> usize {
     offset.floor() as usize
 }
 
+/// Writes the given data points to `writer`, gzip compressing them if
+/// `compress` is set.
+fn write_page_data<W, I>(writer: W, data: I, compress: bool) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = f64>,
+{
+    fn write_values<W: Write, I: IntoIterator<Item = f64>>(
+        writer: &mut W,
+        data: I,
+    ) -> io::Result<()> {
+        let mut buf: [u8; BYTE_WIDTH] = [0; BYTE_WIDTH];
+        for d in data {
+            NativeEndian::write_f64(&mut buf, d);
+            writer.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    if compress {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        write_values(&mut encoder, data)?;
+        encoder.finish()?.flush()
+    } else {
+        let mut writer = writer;
+        write_values(&mut writer, data)?;
+        writer.flush()
+    }
+}
+
+/// Reads `count` data points from `reader`.
+fn read_page_data<R: Read>(mut reader: R, count: usize) -> io::Result<Vec<f64>> {
+    let mut data = Vec::with_capacity(count);
+    let mut buf: [u8; BYTE_WIDTH] = [0; BYTE_WIDTH];
+    for _ in 0..count {
+        reader.read_exact(&mut buf)?;
+        data.push(NativeEndian::read_f64(&buf));
+    }
+    Ok(data)
+}
+
 /// Creates a template file for the given page size. The file will be
-/// NaN filled.
+/// NaN filled, and compressed if the cache is configured to compress pages.
 pub fn create_page_template(config: &Config) -> io::Result<()> {
     let path = config.get_template_path();
 
@@ -115,16 +180,10 @@ pub fn create_page_template(config: &Config) -> io::Result<()> {
             .and_then(fs::create_dir_all)?;
 
         let file = fs::File::create(&path)?;
-        let mut writer = io::BufWriter::new(&file);
+        let writer = io::BufWriter::new(&file);
+        let data = (0..config.page_size()).map(|_| f64::NAN);
 
-        let mut buf: [u8; BYTE_WIDTH] = [0; BYTE_WIDTH];
-        NativeEndian::write_f64(&mut buf, f64::NAN);
-
-        for _ in 0..config.page_size() {
-            writer.write_all(&buf)?;
-        }
-
-        writer.flush()?;
+        write_page_data(writer, data, config.compress())?;
     }
 
     Ok(())
@@ -276,12 +335,13 @@ pub fn soft_cleanup(
     collector: &CachePageCollector,
     db: &database::Database,
     soft_cache_size: u64,
+    thresholds: database::PageAgeThresholds,
     current_size: &mut i64,
 ) -> Result<i64> {
     let mut recycled = 0;
 
     if *current_size as u64 > soft_cache_size {
-        for page in db.get_soft_aged_pages()? {
+        for page in db.get_soft_aged_pages(thresholds)? {
             if *current_size as u64 > soft_cache_size {
                 recycled += 1;
                 *current_size -= page.size;
@@ -301,12 +361,13 @@ pub fn hard_cleanup(
     collector: &CachePageCollector,
     db: &database::Database,
     hard_cache_size: u64,
+    thresholds: database::PageAgeThresholds,
     current_size: &mut i64,
 ) -> Result<i64> {
     let mut recycled = 0;
 
     if *current_size as u64 > hard_cache_size {
-        for page in db.get_hard_aged_pages()? {
+        for page in db.get_hard_aged_pages(thresholds)? {
             if *current_size as u64 > hard_cache_size {
                 recycled += 1;
                 *current_size -= page.size;
@@ -320,6 +381,66 @@ pub fn hard_cleanup(
     Ok(recycled)
 }
 
+/// Encapsulates the critical section, for least recently used cleanups,
+/// that cannot be interleaved with other cache logic. Unlike the aged
+/// cleanups, pages are evicted in LRU order until `cache_size` is met,
+/// regardless of when they were last used.
+pub fn lru_cleanup(
+    collector: &CachePageCollector,
+    db: &database::Database,
+    cache_size: u64,
+    current_size: &mut i64,
+) -> Result<i64> {
+    let mut recycled = 0;
+
+    while *current_size as u64 > cache_size {
+        let mut pages = db
+            .get_pages_by_lru(c::CACHE_COLLECTOR_LRU_BATCH_SIZE)?
+            .peekable();
+        if pages.peek().is_none() {
+            break;
+        }
+        for page in pages {
+            if *current_size as u64 > cache_size {
+                recycled += 1;
+                *current_size -= page.size;
+                collector.remove_page(&page)?;
+            } else {
+                break;
+            }
+        }
+    }
+
+    Ok(recycled)
+}
+
+/// Tests if the page file on disk matches what was recorded when it was
+/// cached. A page whose size has changed is never intact; otherwise its
+/// checksum is compared, but only the first time the page is read since the
+/// agent started. NaN filled pages have no file to check, and pages cached
+/// before checksums were recorded can't be checked, so both are trusted.
+fn page_is_intact(page: &Page, key: &str, db: &database::Database) -> Result<bool> {
+    let expected = match db.get_page_checksum(key)? {
+        None | Some(database::NAN_PAGE_CHECKSUM) => return Ok(true),
+        Some(expected) => expected,
+    };
+    if page.disk_size().ok() != Some(db.get_page(key)?.size as u64) {
+        return Ok(false);
+    }
+    if VERIFIED_PAGES.lock().unwrap().get(key) == Some(&expected) {
+        return Ok(true);
+    }
+
+    let intact = page.checksum().ok().map(i64::from) == Some(expected);
+    if intact {
+        VERIFIED_PAGES
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), expected);
+    }
+    Ok(intact)
+}
+
 /// Encapsulates the critical section, for fetching requests, that cannot
 /// be interleaved with other cache logic.
 fn get_uncached_pages(
@@ -351,7 +472,21 @@ fn get_uncached_pages(
             let page_end =
                 page_start as f64 + channel.period() * f64::from(response.config.page_size());
 
-            if !response.use_cache || !db.is_page_cached(&key)? {
+            let mut cached = response.use_cache && db.is_page_cached(&key)?;
+            if cached {
+                if let Some(page) = response.pages.get(&key) {
+                    if !page_is_intact(page, &key, db)? {
+                        // Treat the corrupt page as a cache miss. It is
+                        // deleted so it is rebuilt from the page template,
+                        // rather than updated in place:
+                        warn!("Cache page {} is corrupt, fetching it again", key);
+                        let _ = page.delete();
+                        cached = false;
+                    }
+                }
+            }
+
+            if !cached {
                 response.page_requests.push(key);
                 requests.push(PageRequest {
                     channel_id: channel.id().clone(),
@@ -520,7 +655,56 @@ impl Page {
         }
     }
 
+    /// Tests if the page on disk is gzip compressed. Uncompressed pages are
+    /// always exactly `size` data points long, so only pages of any other
+    /// length are checked for the gzip header.
+    fn is_compressed(&self) -> Result<bool> {
+        let mut file = fs::File::open(&self.path)?;
+        if file.metadata()?.len() == u64::from(self.size) * BYTE_WIDTH as u64 {
+            return Ok(false);
+        }
+
+        let mut magic = [0; 4];
+        Ok(file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC)
+    }
+
+    /// Reads every data point in the page, decompressing it if needed.
+    fn read_all(&self) -> Result<Vec<f64>> {
+        let file = fs::File::open(&self.path)?;
+        let reader = io::BufReader::new(&file);
+        let size = self.size as usize;
+
+        if self.is_compressed()? {
+            read_page_data(GzDecoder::new(reader), size).map_err(Into::into)
+        } else {
+            read_page_data(reader, size).map_err(Into::into)
+        }
+    }
+
+    /// Returns the size of the page on disk, in bytes.
+    fn disk_size(&self) -> Result<u64> {
+        Ok(fs::metadata(&self.path)?.len())
+    }
+
+    /// Returns the CRC32 checksum of the page on disk.
+    fn checksum(&self) -> Result<u32> {
+        Ok(crc32fast::hash(&fs::read(&self.path)?))
+    }
+
+    /// Returns the size of the page on disk, in bytes, along with its CRC32
+    /// checksum, reading the page only once.
+    fn disk_size_and_checksum(&self) -> Result<(u64, u32)> {
+        let contents = fs::read(&self.path)?;
+        Ok((contents.len() as u64, crc32fast::hash(&contents)))
+    }
+
     /// Writes the data to the cached page with the requested offset.
+    ///
+    /// Compressed pages cannot be updated in place, so they are read in
+    /// full, updated, and rewritten. This also applies to uncompressed pages
+    /// when compression is enabled, converting them as they are written.
+    /// The page is rewritten to a temporary file that then replaces it, so
+    /// a failed write never leaves a truncated page behind.
     fn write(
         &self,
         page_creator: &PageCreator,
@@ -532,15 +716,32 @@ impl Page {
             page_creator.copy_page_template(&self.path, config)?;
         }
 
-        let file = fs::OpenOptions::new().write(true).open(&self.path)?;
-        let mut writer = io::BufWriter::new(&file);
-
         if offset + data.len() > self.size as usize {
             return Err(
                 io::Error::new(io::ErrorKind::Other, "would write outside of file range").into(),
             );
         }
 
+        if config.compress() || self.is_compressed()? {
+            let mut page = self.read_all()?;
+            page[offset..offset + data.len()].copy_from_slice(data);
+
+            let dir = self.path.parent().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "page has no parent directory")
+            })?;
+            let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+            write_page_data(
+                io::BufWriter::new(temp.as_file_mut()),
+                page,
+                config.compress(),
+            )?;
+            temp.persist(&self.path).map_err(|e| e.error)?;
+            return Ok(());
+        }
+
+        let file = fs::OpenOptions::new().write(true).open(&self.path)?;
+        let mut writer = io::BufWriter::new(&file);
+
         if offset > 0 {
             writer.seek(io::SeekFrom::Start(offset as u64 * BYTE_WIDTH as u64))?;
         }
@@ -558,15 +759,21 @@ impl Page {
     /// the amount of data points read. The position of the start of the reaad
     /// is determined by the offset.
     fn read(&self, offset: usize, data: &mut [f64]) -> Result<()> {
-        let file = fs::File::open(&self.path)?;
-        let mut reader = io::BufReader::new(&file);
-
         if offset + data.len() > self.size as usize {
             return Err(
                 io::Error::new(io::ErrorKind::Other, "would write outside of file range").into(),
             );
         }
 
+        if self.is_compressed()? {
+            let page = self.read_all()?;
+            data.copy_from_slice(&page[offset..offset + data.len()]);
+            return Ok(());
+        }
+
+        let file = fs::File::open(&self.path)?;
+        let mut reader = io::BufReader::new(&file);
+
         if offset > 0 {
             reader.seek(io::SeekFrom::Start(offset as u64 * BYTE_WIDTH as u64))?;
         }
@@ -586,6 +793,57 @@ impl Page {
     }
 }
 
+/// The pages filled in while writing segments: the NaN filled pages, and
+/// the highest page of each channel that received data. Every segment
+/// written produces its own, and they are merged as the writes finish.
+#[derive(Debug, Default)]
+pub struct CacheProgress {
+    nan_pages: HashSet<String>,
+    max_completed: HashMap<String, u64>,
+}
+
+impl CacheProgress {
+    /// Adds the pages filled in `other` to these.
+    pub fn merge(mut self, other: CacheProgress) -> Self {
+        self.nan_pages.extend(other.nan_pages);
+        for (channel_id, page_id) in other.max_completed {
+            let max_completed = self.max_completed.entry(channel_id).or_insert(0);
+            *max_completed = cmp::max(*max_completed, page_id);
+        }
+        self
+    }
+}
+
+/// Writes segments to the pages of a response on a pool of threads, so
+/// segments can be written as they arrive. Segments of the same channel are
+/// written one at a time, so no page is ever written by two threads at once.
+#[derive(Clone)]
+pub struct SegmentWriter {
+    response: Arc<Response>,
+    page_creator: PageCreator,
+    channels: Arc<HashMap<String, Mutex<()>>>,
+    pool: CpuPool,
+}
+
+impl SegmentWriter {
+    /// Writes the segment on the writer's thread pool, resolving to the
+    /// pages it filled.
+    pub fn write(&self, segment: Segment) -> CpuFuture<CacheProgress, Error> {
+        let writer = self.clone();
+        self.pool.spawn_fn(move || {
+            let _writing = writer
+                .channels
+                .get(&normalize_path(&segment.source))
+                .map(|channel| channel.lock().unwrap());
+            let mut progress = CacheProgress::default();
+            writer
+                .response
+                .write_segment(&writer.page_creator, &segment, &mut progress)?;
+            Ok(progress)
+        })
+    }
+}
+
 /// Creates a new timeseries response. This response is capable of caching segments
 /// returned from Pennsieve and holds the contents from the original request in
 /// order to be able to return chunks through an iterator.
@@ -669,13 +927,11 @@ impl Response {
             if self.nan_pages.contains(&key) {
                 db.write_nan_filled(&key, completed)?;
             } else {
-                let page = database::PageRecord::new(
-                    key,
-                    false,
-                    completed,
-                    i64::from(self.config.page_size()),
-                );
-                db.upsert_page(&page)?;
+                // compressed pages vary in size, so every page records the
+                // bytes it takes up on disk:
+                let (size, checksum) = self.get_page(&key)?.disk_size_and_checksum()?;
+                let page = database::PageRecord::new(key, false, completed, size as i64);
+                db.upsert_page_with_checksum(&page, Some(checksum))?;
             }
         }
 
@@ -685,6 +941,58 @@ impl Response {
     /// Caches the provided segment of data to the cache. If the segment is empty,
     /// a NaN page is written.
     pub fn cache_response(&mut self, page_creator: &PageCreator, segment: &Segment) -> Result<()> {
+        let mut progress = CacheProgress::default();
+        self.write_segment(page_creator, segment, &mut progress)?;
+        self.record_progress(progress)
+    }
+
+    /// Caches the provided segments, writing up to `parallelism` segments
+    /// at once.
+    pub fn cache_segments(
+        &mut self,
+        page_creator: &PageCreator,
+        segments: Vec<Segment>,
+        parallelism: usize,
+    ) -> Result<()> {
+        let parallelism = cmp::max(1, parallelism);
+        let writer = self.segment_writer(page_creator, parallelism);
+        let progress = stream::iter_ok::<_, Error>(segments)
+            .map(move |segment| writer.write(segment))
+            .buffer_unordered(parallelism)
+            .fold(CacheProgress::default(), |progress, written| {
+                Ok::<_, Error>(progress.merge(written))
+            })
+            .wait()?;
+
+        self.record_progress(progress)
+    }
+
+    /// Creates a writer that writes segments to the pages of this response
+    /// on `parallelism` threads. The pages it fills are added back with
+    /// `record_progress` once every segment is written.
+    pub fn segment_writer(&self, page_creator: &PageCreator, parallelism: usize) -> SegmentWriter {
+        let channels = self
+            .channels
+            .iter()
+            .map(|c| (normalize_path(c.id()), Mutex::new(())))
+            .collect();
+
+        SegmentWriter {
+            response: Arc::new(self.clone()),
+            page_creator: page_creator.clone(),
+            channels: Arc::new(channels),
+            pool: CpuPool::new(parallelism),
+        }
+    }
+
+    /// Writes the segment to the pages it covers, noting the pages it
+    /// filled in `progress`.
+    fn write_segment(
+        &self,
+        page_creator: &PageCreator,
+        segment: &Segment,
+        progress: &mut CacheProgress,
+    ) -> Result<()> {
         if segment.data.is_empty() {
             for c in &self.channels {
                 let channel_id = c.id().clone();
@@ -696,7 +1004,7 @@ impl Response {
                         self.config.page_size(),
                         index,
                     );
-                    self.nan_pages.insert(key);
+                    progress.nan_pages.insert(key);
                 }
             }
 
@@ -714,48 +1022,58 @@ impl Response {
             let segment_source_id = normalize_path(&segment.source);
 
             while data_pos < segment.data.len() {
-                let page_id;
-
-                {
-                    let key = page_key(
-                        &self.package_id,
-                        &segment_source_id,
-                        self.config.page_size(),
-                        index,
-                    );
-                    let page = self.get_page(&key)?;
-                    page_id = page.id;
-                    let offset = page.get_offset(segment.startTs, segment.samplePeriod)?;
-                    let len = cmp::min(segment.data.len() - data_pos, page.size as usize - offset);
-
-                    page.write(
-                        &page_creator,
-                        &self.config,
-                        offset,
-                        &segment.data[data_pos..(data_pos + len)],
-                    )?;
-
-                    data_pos += len;
-                    index += 1;
-                }
+                let key = page_key(
+                    &self.package_id,
+                    &segment_source_id,
+                    self.config.page_size(),
+                    index,
+                );
+                let page = self.get_page(&key)?;
+                let offset = page.get_offset(segment.startTs, segment.samplePeriod)?;
+                let len = cmp::min(segment.data.len() - data_pos, page.size as usize - offset);
+
+                page.write(
+                    &page_creator,
+                    &self.config,
+                    offset,
+                    &segment.data[data_pos..(data_pos + len)],
+                )?;
+
+                data_pos += len;
+                index += 1;
 
-                // when we are in this arm, the segment has datapoints in it. Fetch the
-                // current max completed page for this channel and increment it if the current
-                // page is greater than the value that already exists.
-                {
-                    let max_completed: Result<&mut u64> = self
-                        .max_completed
-                        .get_mut(&segment_source_id)
-                        .ok_or_else(|| Error::invalid_channel(segment_source_id.clone()));
-                    let max_completed: &mut u64 = max_completed?;
-                    *max_completed = cmp::max(*max_completed, page_id);
-                }
+                // when we are in this arm, the segment has datapoints in it. Track the
+                // max completed page for this channel:
+                let max_completed = progress
+                    .max_completed
+                    .entry(segment_source_id.clone())
+                    .or_insert(0);
+                *max_completed = cmp::max(*max_completed, page.id);
             }
 
             Ok(())
         }
     }
 
+    /// Adds the pages filled while writing segments to the lookup maps used
+    /// by `record_page_requests`.
+    pub fn record_progress(&mut self, progress: CacheProgress) -> Result<()> {
+        self.nan_pages.extend(progress.nan_pages);
+
+        for (channel_id, page_id) in progress.max_completed {
+            // Fetch the current max completed page for this channel and increment
+            // it if the page is greater than the value that already exists.
+            let max_completed: Result<&mut u64> = self
+                .max_completed
+                .get_mut(&channel_id)
+                .ok_or_else(|| Error::invalid_channel(channel_id.clone()));
+            let max_completed: &mut u64 = max_completed?;
+            *max_completed = cmp::max(*max_completed, page_id);
+        }
+
+        Ok(())
+    }
+
     /// Returns an iterator that represents each chunk defined in the original
     /// request.
     pub fn owned_chunk_response_iter(self, db: database::Database) -> ChunkResponseIterator {
@@ -769,6 +1087,7 @@ impl Response {
             response: self,
             db,
             pos,
+            intact_pages: HashSet::new(),
         }
     }
 }
@@ -779,6 +1098,8 @@ pub struct ChunkResponseIterator {
     response: Response,
     db: database::Database,
     pos: HashMap<String, u64>,
+    /// Pages whose checksums have already been verified.
+    intact_pages: HashSet<String>,
 }
 
 impl ChunkResponseIterator {
@@ -825,6 +1146,12 @@ impl ChunkResponseIterator {
                         *d = f64::NAN;
                     }
                 } else {
+                    if !self.intact_pages.contains(&key) {
+                        if !page_is_intact(page, &key, &self.db)? {
+                            return Err(Error::corrupt_page(key));
+                        }
+                        self.intact_pages.insert(key.clone());
+                    }
                     page.read(
                         offset,
                         &mut data_slice
@@ -891,7 +1218,7 @@ mod test {
     use protobuf;
     use tempfile::tempdir;
 
-    use std::path;
+    use std::{path, time};
 
     use pennsieve_macros::path;
 
@@ -2572,4 +2899,410 @@ mod test {
 
         assert!(iter.next().is_none());
     }
+
+    fn helper_create_compressed_config(page_size: u32) -> Config {
+        let mut config = helper_create_config(page_size);
+        config.set_compress(true);
+        config
+    }
+
+    #[test]
+    fn test_create_compressed_page_template() {
+        let config = helper_create_compressed_config(300);
+        assert!(create_page_template(&config).is_ok());
+
+        let path = config.get_template_path();
+        assert_ne!(path, helper_create_config(300).get_template_path());
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(metadata.len() < 300 * BYTE_WIDTH as u64);
+    }
+
+    #[test]
+    fn page_read_write_compressed() {
+        let config = helper_create_compressed_config(100);
+        assert!(create_page_template(&config).is_ok());
+
+        let page = Page::new(&config, "compressed_p1", "c1", 0, 99, 1);
+        let page_creator = PageCreator::new();
+
+        let output: Vec<f64> = (0..100).map(|i| f64::from(i % 10)).collect();
+        page.write(&page_creator, &config, 0, &output).unwrap();
+        assert!(page.is_compressed().unwrap());
+        assert!(page.disk_size().unwrap() < 100 * BYTE_WIDTH as u64);
+
+        let mut input = [0f64; 100];
+        page.read(0, &mut input).unwrap();
+        assert_eq!(input.to_vec(), output);
+
+        page.write(&page_creator, &config, 10, &[0.5, 0.25])
+            .unwrap();
+        let mut input = [0f64; 4];
+        page.read(9, &mut input).unwrap();
+        assert_eq!(input, [9.0, 0.5, 0.25, 2.0]);
+    }
+
+    #[test]
+    fn page_uncompressed_is_readable_and_converted_when_compressed() {
+        let config = helper_create_config(100);
+        assert!(create_page_template(&config).is_ok());
+
+        let page = Page::new(&config, "compressed_p2", "c1", 0, 99, 1);
+        let page_creator = PageCreator::new();
+
+        let output: Vec<f64> = (0..100).map(f64::from).collect();
+        page.write(&page_creator, &config, 0, &output).unwrap();
+        assert!(!page.is_compressed().unwrap());
+
+        // once compression is enabled, existing pages can still be read:
+        let compressed_config = helper_create_compressed_config(100);
+        let mut input = [0f64; 100];
+        page.read(0, &mut input).unwrap();
+        assert_eq!(input.to_vec(), output);
+
+        // and are compressed the next time they are written to:
+        page.write(&page_creator, &compressed_config, 0, &[100.0])
+            .unwrap();
+        assert!(page.is_compressed().unwrap());
+
+        let mut input = [0f64; 2];
+        page.read(0, &mut input).unwrap();
+        assert_eq!(input, [100.0, 1.0]);
+    }
+
+    #[test]
+    fn record_page_requests_records_compressed_size() {
+        let config = helper_create_compressed_config(1000);
+        let page_creator = PageCreator::new();
+        let db = util::database::temp().unwrap();
+        assert!(create_page_template(&config).is_ok());
+
+        let request = Request::new(
+            "compressed_p3", // package_id
+            vec![Channel::new("cache_c1", 1e6)],
+            1000,  // start
+            1999,  // end
+            0,     // chunk_size
+            false, // use_cache
+        );
+        let mut segment = Segment::new();
+        segment.set_startTs(1000);
+        segment.set_source(String::from("cache_c1"));
+        segment.set_samplePeriod(1f64);
+        segment.set_data((0..1000).map(|i| f64::from(i % 10)).collect());
+
+        let mut response = request.get_response(&config);
+        response.uncached_page_requests(&db).unwrap();
+        response.cache_response(&page_creator, &segment).unwrap();
+        response.record_page_requests(&db).unwrap();
+
+        let page = Page::new(&config, "compressed_p3", "cache_c1", 1000, 1999, 1);
+        let mut input = [0f64; 1000];
+        page.read(0, &mut input).unwrap();
+        assert_eq!(input.to_vec(), segment.get_data().to_vec());
+
+        let key = page_key("compressed_p3", "cache_c1", 1000, 1);
+        let record = db.get_page(&key).unwrap();
+        assert_eq!(record.size as u64, page.disk_size().unwrap());
+        assert!(record.size < 1000 * BYTE_WIDTH as i64);
+    }
+
+    #[test]
+    fn record_page_requests_records_uncompressed_size_in_bytes() {
+        let config = helper_create_config(10);
+        let db = util::database::temp().unwrap();
+        let (request, page) = helper_cache_page(&config, &db, "size_c1");
+
+        let key = page_key(request.package_id(), "size_c1", 10, 1);
+        let record = db.get_page(&key).unwrap();
+        assert_eq!(record.size, 10 * BYTE_WIDTH as i64);
+        assert_eq!(record.size as u64, page.disk_size().unwrap());
+    }
+
+    /// Caches one page of data for `channel_id` through a response, the same
+    /// way the timeseries server does. Returns the request and cached page.
+    fn helper_cache_page(
+        config: &Config,
+        db: &database::Database,
+        channel_id: &str,
+    ) -> (Request, Page) {
+        let page_creator = PageCreator::new();
+        assert!(create_page_template(&config).is_ok());
+
+        let request = Request::new(
+            "p1", // package_id
+            vec![Channel::new(
+                // channels
+                channel_id, 1e6,
+            )],
+            10,   // start
+            19,   // end
+            10,   // chunk_size
+            true, // use_cache
+        );
+        let mut segment = Segment::new();
+        segment.set_startTs(10);
+        segment.set_source(String::from(channel_id));
+        segment.set_samplePeriod(1f64);
+        segment.set_data(vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+
+        let mut response = request.get_response(&config);
+        assert_eq!(response.uncached_page_requests(&db).unwrap().count(), 1);
+        response.cache_response(&page_creator, &segment).unwrap();
+        response.record_page_requests(&db).unwrap();
+
+        let key = page_key(request.package_id(), channel_id, config.page_size(), 1);
+        let page = response.get_page(&key).unwrap().clone();
+        (request, page)
+    }
+
+    #[test]
+    fn corrupt_pages_are_fetched_again() {
+        let config = helper_create_config(10);
+        let db = util::database::temp().unwrap();
+        let (request, page) = helper_cache_page(&config, &db, "checksum_c1");
+
+        let mut response = request.get_response(&config);
+        assert_eq!(response.uncached_page_requests(&db).unwrap().count(), 0);
+
+        // A page truncated by a crash mid-write:
+        let contents = fs::read(&page.path).unwrap();
+        fs::write(&page.path, &contents[..contents.len() / 2]).unwrap();
+
+        let mut response = request.get_response(&config);
+        let pages: Vec<PageRequest> = response.uncached_page_requests(&db).unwrap().collect();
+        assert_eq!(pages, vec![PageRequest::new("checksum_c1", 10, 20)]);
+        assert!(!page.path.exists());
+    }
+
+    #[test]
+    fn corrupt_pages_are_not_read() {
+        let config = helper_create_config(10);
+        let db = util::database::temp().unwrap();
+        let (request, page) = helper_cache_page(&config, &db, "checksum_c2");
+
+        // Overwrite the page with the same number of different values:
+        let mut contents = Vec::new();
+        write_page_data(&mut contents, (0..10).map(|_| 42.0), false).unwrap();
+        fs::write(&page.path, contents).unwrap();
+
+        let response = request.get_response(&config);
+        let mut iter = response.owned_chunk_response_iter(db);
+        match iter.next() {
+            Some(Err(e)) => assert_eq!(
+                e.kind(),
+                &ErrorKind::CorruptPage {
+                    page: page_key(request.package_id(), "checksum_c2", 10, 1)
+                }
+            ),
+            Some(Ok(bytes)) => panic!("read corrupt data: {:?}", helper_convert_chunk(&bytes)),
+            None => panic!("expected an error"),
+        }
+    }
+
+    /// A request for `pages` pages of each of `channels` channels, along
+    /// with segments that fill every page in two halves. The last page of
+    /// every other channel is left empty, so it is NaN filled.
+    fn helper_wide_request(channels: usize, pages: u64, page_size: u32) -> (Request, Vec<Segment>) {
+        let page_size = u64::from(page_size);
+        let half = page_size / 2;
+        let channels: Vec<Channel> = (0..channels)
+            .map(|c| Channel::new(format!("wide_c{}", c), 1e6))
+            .collect();
+        let request = Request::new(
+            "wide_p1",
+            channels.clone(),
+            page_size,
+            (pages + 1) * page_size - 1,
+            0,
+            true,
+        );
+
+        let mut segments = Vec::new();
+        for (c, channel) in channels.iter().enumerate() {
+            for page in 1..=pages {
+                for part in 0..2 {
+                    let start = page * page_size + part * half;
+                    let mut segment = Segment::new();
+                    segment.set_startTs(start);
+                    segment.set_source(channel.id().clone());
+                    segment.set_samplePeriod(1f64);
+                    if c % 2 == 0 || page < pages {
+                        segment.set_data(
+                            (start..start + half)
+                                .map(|t| (c as u64 * 1_000_000 + t) as f64)
+                                .collect(),
+                        );
+                    }
+                    segments.push(segment);
+                }
+            }
+        }
+
+        (request, segments)
+    }
+
+    /// Caches the segments of a request in a new cache with `cache`,
+    /// returning the cache directory, the response, the database and how
+    /// long caching took.
+    fn helper_cache_wide_request<F>(
+        request: &Request,
+        page_size: u32,
+        cache: F,
+    ) -> (
+        tempfile::TempDir,
+        Response,
+        database::Database,
+        time::Duration,
+    )
+    where
+        F: FnOnce(&mut Response, &PageCreator),
+    {
+        let dir = tempdir().unwrap();
+        let config = Config::new(dir.path(), page_size, 0, 0);
+        let db = util::database::temp().unwrap();
+        create_page_template(&config).unwrap();
+
+        let mut response = request.get_response(&config);
+        response.uncached_page_requests(&db).unwrap();
+        let started_at = time::Instant::now();
+        cache(&mut response, &PageCreator::new());
+        let elapsed = started_at.elapsed();
+        response.record_page_requests(&db).unwrap();
+
+        (dir, response, db, elapsed)
+    }
+
+    fn assert_same_cache(
+        (a, a_db): (&Response, &database::Database),
+        (b, b_db): (&Response, &database::Database),
+    ) {
+        assert_eq!(a.nan_pages, b.nan_pages);
+        assert_eq!(a.max_completed, b.max_completed);
+        assert_eq!(a.page_requests, b.page_requests);
+        assert_eq!(
+            a.pages.keys().collect::<Vec<_>>(),
+            b.pages.keys().collect::<Vec<_>>()
+        );
+
+        for (key, a_page) in &a.pages {
+            let b_page = &b.pages[key];
+            assert_eq!(a_page.path.exists(), b_page.path.exists(), "{}", key);
+            if a_page.path.exists() {
+                assert!(
+                    vec_compare(&a_page.read_all().unwrap(), &b_page.read_all().unwrap()),
+                    "{}",
+                    key
+                );
+            }
+
+            let (a_record, b_record) = (a_db.get_page(key).unwrap(), b_db.get_page(key).unwrap());
+            assert_eq!(a_record.nan_filled, b_record.nan_filled, "{}", key);
+            assert_eq!(a_record.complete, b_record.complete, "{}", key);
+            assert_eq!(a_record.size, b_record.size, "{}", key);
+        }
+    }
+
+    #[test]
+    fn parallel_caching_matches_sequential_caching() {
+        let (request, segments) = helper_wide_request(6, 3, 10);
+
+        let (_a_dir, sequential, sequential_db, _) =
+            helper_cache_wide_request(&request, 10, |response, page_creator| {
+                for segment in &segments {
+                    response.cache_response(page_creator, segment).unwrap();
+                }
+            });
+        let (_b_dir, parallel, parallel_db, _) =
+            helper_cache_wide_request(&request, 10, |response, page_creator| {
+                response
+                    .cache_segments(page_creator, segments.clone(), 4)
+                    .unwrap();
+            });
+
+        assert_eq!(sequential.nan_pages.len(), 3);
+        assert_eq!(sequential.max_completed.len(), 6);
+        assert_same_cache((&sequential, &sequential_db), (&parallel, &parallel_db));
+    }
+
+    #[test]
+    fn parallel_caching_of_unrequested_channels_fails() {
+        let config = helper_create_config(10);
+        let db = util::database::temp().unwrap();
+        assert!(create_page_template(&config).is_ok());
+        let (request, _) = helper_wide_request(2, 1, 10);
+        let (_, segments) = helper_wide_request(3, 1, 10);
+
+        let mut response = request.get_response(&config);
+        response.uncached_page_requests(&db).unwrap();
+        assert!(response
+            .cache_segments(&PageCreator::new(), segments, 4)
+            .is_err());
+    }
+
+    #[test]
+    fn benchmark_parallel_caching() {
+        let page_size = 10_000;
+        let (request, segments) = helper_wide_request(32, 4, page_size);
+
+        let (_a_dir, sequential, sequential_db, sequential_elapsed) =
+            helper_cache_wide_request(&request, page_size, |response, page_creator| {
+                response
+                    .cache_segments(page_creator, segments.clone(), 1)
+                    .unwrap();
+            });
+        let (_b_dir, parallel, parallel_db, parallel_elapsed) =
+            helper_cache_wide_request(&request, page_size, |response, page_creator| {
+                response
+                    .cache_segments(page_creator, segments.clone(), 8)
+                    .unwrap();
+            });
+
+        assert_same_cache((&sequential, &sequential_db), (&parallel, &parallel_db));
+        // writing with 8 threads should never be slower than writing with
+        // one, give or take the noise of a busy machine:
+        assert!(
+            parallel_elapsed < sequential_elapsed * 2,
+            "cached {} segments: sequentially in {:?}, with 8 threads in {:?}",
+            segments.len(),
+            sequential_elapsed,
+            parallel_elapsed
+        );
+    }
+
+    #[test]
+    fn segments_are_written_as_they_arrive() {
+        let (request, segments) = helper_wide_request(4, 2, 10);
+
+        let (_dir, response, db, _) =
+            helper_cache_wide_request(&request, 10, |response, page_creator| {
+                let writer = response.segment_writer(page_creator, 4);
+                let (first, rest) = segments.split_at(1);
+
+                // the first segment is on disk before the rest are written:
+                let progress = writer.write(first[0].clone()).wait().unwrap();
+                let key = page_key("wide_p1", "wide_c0", 10, 1);
+                assert!(response.pages[&key].read_all().unwrap()[..5]
+                    .iter()
+                    .all(|d| !d.is_nan()));
+
+                let progress = rest
+                    .iter()
+                    .map(|segment| writer.write(segment.clone()))
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .fold(progress, |progress, written| {
+                        progress.merge(written.wait().unwrap())
+                    });
+                response.record_progress(progress).unwrap();
+            });
+
+        let (_sequential_dir, sequential, sequential_db, _) =
+            helper_cache_wide_request(&request, 10, |response, page_creator| {
+                for segment in &segments {
+                    response.cache_response(page_creator, segment).unwrap();
+                }
+            });
+        assert_same_cache((&sequential, &sequential_db), (&response, &db));
+    }
 }
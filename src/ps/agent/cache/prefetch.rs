@@ -0,0 +1,143 @@
+//! Warming the cache ahead of time, as done by `cache prefetch`.
+
+use futures::Future as _Future;
+use log::*;
+
+use pennsieve_macros::try_future;
+
+use crate::ps::agent::{self, database, Future};
+use crate::ps::proto::timeseries::Segment;
+use crate::ps::util::futures::*;
+
+use super::{create_page_template, Config, PageCreator, PageRequest, Request};
+
+/// The outcome of a prefetch: how many pages had to be fetched, and how many
+/// were already cached.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Prefetched {
+    pub fetched: usize,
+    pub cached: usize,
+}
+
+/// Caches every page in the window of `request`. The pages that are not
+/// already cached are requested with `fetch`, and the segments it returns
+/// are cached the same way the timeseries server caches them.
+pub fn prefetch<F>(
+    request: &Request,
+    config: &Config,
+    db: &database::Database,
+    fetch: F,
+) -> Future<Prefetched>
+where
+    F: FnOnce(Vec<PageRequest>) -> Future<Vec<Segment>>,
+{
+    try_future!(create_page_template(config));
+
+    let mut response = request.get_response(config);
+    let requests: Vec<PageRequest> = try_future!(response.uncached_page_requests(db)).collect();
+    let prefetched = Prefetched {
+        fetched: requests.len(),
+        cached: response.pages.len() - requests.len(),
+    };
+    info!(
+        "prefetch: {} pages to fetch, {} already cached",
+        prefetched.fetched, prefetched.cached
+    );
+
+    let db = db.clone();
+    let parallelism = config.parallelism();
+    fetch(requests)
+        .and_then(move |segments| -> agent::Result<Prefetched> {
+            response.cache_segments(&PageCreator::new(), segments, parallelism)?;
+            response.record_page_requests(&db)?;
+            Ok(prefetched)
+        })
+        .into_trait()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use futures::future;
+    use tempfile::tempdir;
+
+    use crate::ps::agent::cache::{page_key, Channel};
+    use crate::ps::util;
+
+    /// Answers every page request with a segment filling the whole page.
+    fn segments_for(pages: &[PageRequest]) -> Vec<Segment> {
+        pages
+            .iter()
+            .map(|page| {
+                let mut segment = Segment::new();
+                segment.set_startTs(page.start());
+                segment.set_source(page.channel_id().clone());
+                segment.set_samplePeriod(1f64);
+                segment.set_data((page.start()..page.end()).map(|t| t as f64).collect());
+                segment
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prefetching_a_window_caches_every_page() {
+        let dir = tempdir().unwrap();
+        let config = Config::new(dir.path(), 10, 0, 0);
+        let db = util::database::temp().unwrap();
+        let request = Request::new(
+            "p1",
+            vec![Channel::new("c1", 1e6), Channel::new("c2", 1e6)],
+            10,
+            39,
+            0,
+            true,
+        );
+        let requested = Arc::new(Mutex::new(Vec::new()));
+
+        let fetch = {
+            let requested = Arc::clone(&requested);
+            move |pages: Vec<PageRequest>| {
+                requested.lock().unwrap().extend(pages.clone());
+                future::ok::<_, agent::Error>(segments_for(&pages)).into_trait()
+            }
+        };
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let prefetched = rt
+            .block_on(prefetch(&request, &config, &db, fetch))
+            .unwrap();
+
+        assert_eq!(
+            prefetched,
+            Prefetched {
+                fetched: 6,
+                cached: 0
+            }
+        );
+        assert_eq!(requested.lock().unwrap().len(), 6);
+        for channel in &["c1", "c2"] {
+            for page_id in 1..4 {
+                let page = db.get_page(&page_key("p1", channel, 10, page_id)).unwrap();
+                assert!(!page.nan_filled);
+                // the last page of the window may still be filled in later:
+                assert_eq!(page.complete, page_id < 3);
+            }
+        }
+
+        // Only the incomplete pages are fetched again:
+        let prefetched = rt
+            .block_on(prefetch(&request, &config, &db, |pages| {
+                future::ok::<_, agent::Error>(segments_for(&pages)).into_trait()
+            }))
+            .unwrap();
+        assert_eq!(
+            prefetched,
+            Prefetched {
+                fetched: 2,
+                cached: 4
+            }
+        );
+    }
+}
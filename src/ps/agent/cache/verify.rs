@@ -0,0 +1,248 @@
+//! Reconciling the page records in the database with the page files on
+//! disk, as done by `cache verify`.
+
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use walkdir::WalkDir;
+
+use crate::ps::agent::database;
+
+use super::{Config, Page, Result};
+
+/// The drift found between the page records and the page files of the
+/// cache, and what was done about it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheReport {
+    pub records: usize,
+    pub files: usize,
+    // Keys of page records whose file is missing:
+    pub missing_files: Vec<String>,
+    // Page files that no record points at:
+    pub orphaned_files: Vec<PathBuf>,
+    // The size of the cache after a repair, in bytes:
+    pub repaired_size: Option<i64>,
+}
+
+impl CacheReport {
+    /// Tests if the records and files of the cache agree.
+    pub fn is_consistent(&self) -> bool {
+        self.missing_files.is_empty() && self.orphaned_files.is_empty()
+    }
+}
+
+impl Display for CacheReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checked {} page records and {} page files",
+            self.records, self.files
+        )?;
+        for key in &self.missing_files {
+            write!(f, "\n  record without a file: {}", key)?;
+        }
+        for path in &self.orphaned_files {
+            write!(f, "\n  file without a record: {:?}", path)?;
+        }
+        if let Some(size) = self.repaired_size {
+            write!(
+                f,
+                "\nRemoved {} dangling records and {} orphaned files; the cache now holds {} bytes",
+                self.missing_files.len(),
+                self.orphaned_files.len(),
+                size
+            )?;
+        } else if self.is_consistent() {
+            write!(f, "\nThe cache is consistent")?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets the path of the file backing the page with the given key, if the
+/// key is well formed.
+fn page_path(config: &Config, key: &str) -> Option<PathBuf> {
+    let parts: Vec<&str> = key.split('.').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    let page_size = parts[2].parse::<u32>().ok()?;
+    let index = parts[3].parse::<u64>().ok()?;
+
+    // Pages cached with an earlier page size are stored apart from the
+    // current ones:
+    let mut config = config.clone();
+    config.set_page_size(page_size);
+    Some(Page::new(&config, parts[0], parts[1], 0, 0, index).path)
+}
+
+/// Lists every page file under the cache base path. Page templates are not
+/// pages, and are left out.
+fn page_files(config: &Config) -> Result<Vec<PathBuf>> {
+    let base_path = config.base_path();
+    if !base_path.exists() {
+        return Ok(vec![]);
+    }
+    let templates = base_path.join("templates");
+
+    let mut files = vec![];
+    for entry in WalkDir::new(base_path) {
+        let entry = entry.map_err(io::Error::from)?;
+        let path = entry.path();
+        if entry.file_type().is_file()
+            && !path.starts_with(&templates)
+            && path.extension().map_or(false, |ext| ext == "bin")
+        {
+            files.push(path.to_path_buf());
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// Cross-checks the page records in `db` against the page files under the
+/// cache base path. NaN filled pages have no file, so they are never
+/// reported missing.
+///
+/// With `repair`, dangling records are deleted, orphaned files are removed
+/// and the resulting size of the cache is recorded in the report.
+pub fn verify(config: &Config, db: &database::Database, repair: bool) -> Result<CacheReport> {
+    let records: Vec<database::PageRecord> = db.get_pages()?.collect();
+    let files = page_files(config)?;
+
+    let mut expected: HashSet<PathBuf> = HashSet::new();
+    let mut dangling = vec![];
+    for record in &records {
+        match page_path(config, &record.id) {
+            Some(path) => {
+                if !record.nan_filled && !path.exists() {
+                    dangling.push(record);
+                }
+                expected.insert(path);
+            }
+            None if !record.nan_filled => dangling.push(record),
+            None => (),
+        }
+    }
+
+    let orphaned_files: Vec<PathBuf> = files
+        .iter()
+        .filter(|path| !expected.contains(*path))
+        .cloned()
+        .collect();
+
+    let repaired_size = if repair {
+        for record in &dangling {
+            info!("cache verify: removing dangling page record {}", record.id);
+            db.delete_page(record)?;
+        }
+        for path in &orphaned_files {
+            info!("cache verify: removing orphaned page file {:?}", path);
+            remove_file(path)?;
+        }
+        Some(db.get_total_size()?)
+    } else {
+        None
+    };
+
+    Ok(CacheReport {
+        records: records.len(),
+        files: files.len(),
+        missing_files: dangling.iter().map(|record| record.id.clone()).collect(),
+        orphaned_files,
+        repaired_size,
+    })
+}
+
+// Removes a file that may already have been removed by the collector.
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result.map_err(Into::into),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    use crate::ps::agent::cache::{create_page_template, page_key};
+    use crate::ps::util;
+
+    /// Writes a page file and its record, as caching a page does.
+    fn cache_page(config: &Config, db: &database::Database, key: &str) -> PathBuf {
+        let path = page_path(config, key).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, vec![0; 80]).unwrap();
+        db.upsert_page(&database::PageRecord::new(key, false, true, 80))
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn drift_is_detected() {
+        let dir = tempdir().unwrap();
+        let config = Config::new(dir.path(), 10, 0, 0);
+        let db = util::database::temp().unwrap();
+        create_page_template(&config).unwrap();
+
+        cache_page(&config, &db, &page_key("p1", "c1", 10, 0));
+        let missing = cache_page(&config, &db, &page_key("p1", "c1", 10, 1));
+        fs::remove_file(&missing).unwrap();
+        let orphan = cache_page(&config, &db, &page_key("p1", "c2", 10, 0));
+        db.delete_page(&database::PageRecord::new(
+            page_key("p1", "c2", 10, 0),
+            false,
+            true,
+            80,
+        ))
+        .unwrap();
+        // NaN filled pages have no file:
+        db.write_nan_filled(&page_key("p1", "c1", 10, 2), true)
+            .unwrap();
+
+        let report = verify(&config, &db, false).unwrap();
+        assert_eq!(report.records, 3);
+        assert_eq!(report.files, 2);
+        assert_eq!(report.missing_files, vec![page_key("p1", "c1", 10, 1)]);
+        assert_eq!(report.orphaned_files, vec![orphan.clone()]);
+        assert_eq!(report.repaired_size, None);
+        assert!(!report.is_consistent());
+
+        // Nothing was changed:
+        assert!(orphan.exists());
+        assert_eq!(db.get_page_count().unwrap(), 3);
+    }
+
+    #[test]
+    fn drift_is_repaired() {
+        let dir = tempdir().unwrap();
+        let config = Config::new(dir.path(), 10, 0, 0);
+        let db = util::database::temp().unwrap();
+
+        let kept = cache_page(&config, &db, &page_key("p1", "c1", 10, 0));
+        let missing = cache_page(&config, &db, &page_key("p1", "c1", 10, 1));
+        fs::remove_file(&missing).unwrap();
+        let orphan = page_path(&config, &page_key("p2", "c1", 10, 0)).unwrap();
+        fs::create_dir_all(orphan.parent().unwrap()).unwrap();
+        fs::write(&orphan, vec![0; 80]).unwrap();
+        assert_eq!(db.get_total_size().unwrap(), 160);
+
+        let report = verify(&config, &db, true).unwrap();
+        assert_eq!(report.missing_files.len(), 1);
+        assert_eq!(report.orphaned_files, vec![orphan.clone()]);
+        assert_eq!(report.repaired_size, Some(80));
+
+        assert!(kept.exists());
+        assert!(!orphan.exists());
+        assert_eq!(db.get_page_count().unwrap(), 1);
+        assert!(verify(&config, &db, false).unwrap().is_consistent());
+    }
+}
@@ -0,0 +1,1637 @@
+//! The command line interface definition for the Pennsieve agent.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use pennsieve_rust::Environment as ApiEnvironment;
+
+use crate::ps::agent::cli::PackageTypeFilter;
+use crate::ps::agent::config::constants::{
+    UPLOAD_MAX_CHUNK_SIZE, UPLOAD_MAX_WATCH_INTERVAL_SECS, UPLOAD_MIN_CHUNK_SIZE,
+};
+use crate::ps::agent::config::Config;
+use crate::ps::agent::upload;
+use crate::ps::util::temporal::{parse_date_bound, Bound};
+
+// Defines the common arguments for an upload command.
+// This applies to "append" and "upload".
+//
+// Note: the functionality to build a subcommand with `clap` must be
+// implemented with a macro due to the lifetime specifier requirements of
+// `clap::App<'_, '_>`. The `clap` library expects the user to construct
+// a chain on subcommands and associated args in a single pass, without
+// subdividing the task between builder functions. Most functions on `clap::Arg`
+// that accept a string, take a `&str`, which is the cause of the complication.
+macro_rules! build_upload_args {
+    ($operation:expr, $about:expr, $fallback_dataset:ident) => {
+        clap::SubCommand::with_name($operation)
+            .about($about)
+            .arg(
+                clap::Arg::with_name("paths")
+                    .value_name("paths")
+                    .takes_value(true)
+                    .multiple(true)
+                    .min_values(1)
+                    // only `upload` defines `--manifest` and `--stdin`:
+                    .required_unless_one(&["manifest", "stdin"])
+                    .validator(file_exists)
+                    .help(concat!(
+                        "Paths of the files to ",
+                        $operation,
+                        ".\n",
+                        "If a single path is provided, it can be a directory from which to ",
+                        $operation,
+                        " files"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("dataset")
+                    .long("dataset")
+                    .value_name("dataset")
+                    .takes_value(true)
+                    .default_value($fallback_dataset)
+                    .validator(id_nonempty)
+                    .help(concat!(
+                        "The ID or name of the dataset to ",
+                        $operation,
+                        " to.\n",
+                        "Example: --dataset=N:dataset:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
+                        "         --dataset=\"My dataset\""
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("force")
+                    .short("f")
+                    .long("force")
+                    .help(concat!(
+                        "Bypass the file selection confirmation prompt, and queue files even if ",
+                        "they are already queued for upload to the dataset"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("legacy").long("legacy").help(
+                    "Use the legacy S3 uploader instead of the Upload Service for this upload",
+                ),
+            )
+            .arg(
+                clap::Arg::with_name("recursive")
+                    .short("r")
+                    .long("recursive")
+                    .help(concat!(
+                        "Recursively upload all folders in a directory.\n",
+                        "This argument will be ignored if multiple files are specified ",
+                        "instead of a single directory"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("follow_symlinks")
+                    .long("follow-symlinks")
+                    .help(concat!(
+                        "Upload what symbolic links in the directory point to; by default they are skipped.\n",
+                        "Links that lead back to a directory that was already visited are always skipped"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("skip_empty")
+                    .long("skip-empty")
+                    .help("Skip empty files with a warning instead of uploading them"),
+            )
+            .arg(
+                clap::Arg::with_name("priority")
+                    .long("priority")
+                    .value_name("n")
+                    .takes_value(true)
+                    .allow_hyphen_values(true)
+                    .validator(is_integer)
+                    .help(concat!(
+                        "The priority of the files in the upload queue; default is 0.\n",
+                        "Queued files with a higher priority are uploaded first"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("tag")
+                    .long("tag")
+                    .value_name("key=value")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(is_tag)
+                    .help(concat!(
+                        "Tags the uploaded packages with a property once the upload completes.\n",
+                        "Can be given more than once"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("chunk_size")
+                    .long("chunk-size")
+                    .value_name("bytes")
+                    .takes_value(true)
+                    .validator(is_chunk_size)
+                    .help(concat!(
+                        "The size of the parts large files are uploaded in, from 5 MiB to 5 GiB.\n",
+                        "By default it is picked from the size of each file"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("parallelism")
+                    .long("parallelism")
+                    .value_name("parallelism")
+                    .takes_value(true)
+                    .hidden(true)
+                    .help("Parallelism level; default is the number of CPUs"),
+            )
+            .arg(
+                clap::Arg::with_name("file_parallelism")
+                    .long("file-parallelism")
+                    .value_name("n")
+                    .takes_value(true)
+                    .validator(is_numeric)
+                    .help(concat!(
+                        "How many files to upload at once; default is 1.\n",
+                        "Many small files upload faster with more files at once"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("chunk_parallelism")
+                    .long("chunk-parallelism")
+                    .value_name("n")
+                    .takes_value(true)
+                    .validator(is_numeric)
+                    .help(concat!(
+                        "How many chunks of each file to upload at once; default is the number of CPUs.\n",
+                        "A few large files upload faster with more chunks at once"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("allow_oversubscribe")
+                    .long("allow-oversubscribe")
+                    .hidden(true)
+                    .help("Allow a parallelism level above the number of CPUs"),
+            )
+            .arg(
+                clap::Arg::with_name("no_progress")
+                    .long("no-progress")
+                    .help("Print periodic status lines instead of progress bars"),
+            )
+            .arg(
+                clap::Arg::with_name("upload_log")
+                    .long("upload-log")
+                    .value_name("dir")
+                    .takes_value(true)
+                    .help("Write a log file for each upload, named by its import ID, to this directory"),
+            )
+            .arg(
+                clap::Arg::with_name("dry_run")
+                    .long("dry-run")
+                    .conflicts_with("manifest")
+                    .help(concat!(
+                        "Print how many files would be uploaded, their total size and the\n",
+                        "dataset and folder they would go to, without queueing anything"
+                    )),
+            )
+    };
+}
+
+macro_rules! append_command {
+    ($fallback_dataset:ident) => {
+        build_upload_args!(
+            "append",
+            "Append data to a timeseries package",
+            $fallback_dataset
+        )
+        .arg(
+            clap::Arg::with_name("package")
+                .long("package")
+                .value_name("package")
+                .takes_value(true)
+                .validator(id_nonempty)
+                .help(concat!(
+                    "The ID or name of the timeseries package to append to\n",
+                    "Example: --package=N:package:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
+                    "         --package=\"My Timeseries Data\""
+                )),
+        )
+        .arg(
+            clap::Arg::with_name("force_reappend")
+                .long("force-reappend")
+                .help("Append files even if they were already appended to the package"),
+        )
+    };
+}
+
+macro_rules! upload_command {
+    ($fallback_dataset:ident) => {
+        build_upload_args!("upload", "Upload files to the Pennsieve platform", $fallback_dataset)
+            .long_about(concat!(
+                "Upload files to the Pennsieve platform.\n\n",
+                "A directory is uploaded the way rsync copies one: with a trailing slash, ",
+                "like data/, the contents of the directory are uploaded to the target. ",
+                "Without the slash, a folder named after the directory is created in the ",
+                "target to hold its contents."
+            ))
+            .arg(
+                clap::Arg::with_name("folder")
+                    .long("folder")
+                    .value_name("folder")
+                    .takes_value(true)
+                    .validator(id_nonempty)
+                    .help(concat!(
+                        "The ID or name of the folder to upload to. If it doesn't exist, it will be created\n",
+                        "Example: --folder=N:collection:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
+                        "         --folder=\"My Samples\""
+                        )
+                    ),
+            )
+            .arg(
+                clap::Arg::with_name("manifest")
+                    .long("manifest")
+                    .value_name("path")
+                    .takes_value(true)
+                    .conflicts_with_all(&["paths", "folder", "recursive"])
+                    .validator(file_exists)
+                    .help(concat!(
+                        "A file listing the paths to upload, one per line. A path can be followed\n",
+                        "by a comma and the ID or name of the folder to upload it to.\n",
+                        "Blank lines and lines beginning with '#' are ignored"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("stdin")
+                    .long("stdin")
+                    .requires("name")
+                    .conflicts_with_all(&["paths", "manifest", "recursive", "dry_run"])
+                    .help(concat!(
+                        "Upload the data read from stdin as a single file, named by --name.\n",
+                        "Example: pg_dump mydb | pennsieve upload --stdin --name=mydb.sql --dataset=\"My dataset\""
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("name")
+                    .long("name")
+                    .value_name("filename")
+                    .takes_value(true)
+                    .requires("stdin")
+                    .validator(id_nonempty)
+                    .help("The file name to give the data read with --stdin"),
+            )
+            .arg(
+                clap::Arg::with_name("dataset_from_path")
+                    .long("dataset-from-path")
+                    .requires("paths")
+                    .conflicts_with_all(&["manifest", "stdin", "folder", "dry_run"])
+                    .help(concat!(
+                        "Upload each directory in the given path to the dataset it is named after,\n",
+                        "creating datasets that don't exist yet. --dataset is ignored, and the\n",
+                        "contents of each directory are uploaded recursively"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("manifest_output")
+                    .long("manifest-output")
+                    .value_name("path")
+                    .takes_value(true)
+                    .conflicts_with("dry_run")
+                    .help(concat!(
+                        "Once the upload finishes, write the local path, checksum when queued, import ID,\n",
+                        "destination package or collection ID and dataset ID of each uploaded file to\n",
+                        "this file: as CSV if it ends in .csv, and as JSON otherwise"
+                    )),
+            )
+            .arg(
+                clap::Arg::with_name("skip_existing")
+                    .long("skip-existing")
+                    .help(concat!(
+                        "Skip files that already exist at the same path in the dataset or folder.\n",
+                        "Files this agent uploaded before are only skipped if they are unchanged"
+                    )),
+            )
+    };
+}
+
+/// Function to validate whether a given profile_name exists.
+fn profile_exists<S: Into<String>>(profile_name: S) -> Result<(), String> {
+    let profile_name: String = profile_name.into();
+    Config::from_config_file_and_environment()
+        .or_else(|e| Err(format!("error building the configuration: {}", e)))
+        .and_then(|config| {
+            if config.api_settings.contains_profile(profile_name.clone()) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Invalid profile: {}. These are valid profiles: {}",
+                    profile_name,
+                    config.api_settings.profile_names().join(", ")
+                ))
+            }
+        })
+}
+
+/// Function to validate if an identifier is non-empty.
+fn id_nonempty<S: Into<String>>(id: S) -> Result<(), String> {
+    let id = id.into();
+    if id.trim().is_empty() {
+        Err("an ID is required".into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Function to validate if a file exists.
+fn file_exists<S: Into<String>>(filepath: S) -> Result<(), String> {
+    let filepath = filepath.into();
+    if !PathBuf::from(filepath.clone()).as_path().exists() {
+        Err(format!("file not found: {:?}", filepath))
+    } else {
+        Ok(())
+    }
+}
+
+/// Function to validate if a given argument is numeric.
+fn is_numeric<S: Into<String>>(argument: S) -> Result<(), String> {
+    let argument = argument.into();
+    if argument.parse::<usize>().is_ok() {
+        Ok(())
+    } else {
+        Err(format!("received non-numeric value: {}", argument))
+    }
+}
+
+fn is_date<S: Into<String>>(argument: S) -> Result<(), String> {
+    let argument = argument.into();
+    if parse_date_bound(&argument, Bound::Start).is_some() {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected an ISO 8601 date or date and time, like 2019-03-01 or 2019-03-01T12:30:00, received: {}",
+            argument
+        ))
+    }
+}
+
+fn is_package_type<S: Into<String>>(argument: S) -> Result<(), String> {
+    PackageTypeFilter::new(&[argument.into()])
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn is_integer<S: Into<String>>(argument: S) -> Result<(), String> {
+    let argument = argument.into();
+    if argument.parse::<i32>().is_ok() {
+        Ok(())
+    } else {
+        Err(format!("received non-integer value: {}", argument))
+    }
+}
+
+fn is_chunk_size<S: Into<String>>(argument: S) -> Result<(), String> {
+    let argument = argument.into();
+    match argument.parse::<u64>() {
+        Ok(size) if size >= UPLOAD_MIN_CHUNK_SIZE && size <= UPLOAD_MAX_CHUNK_SIZE => Ok(()),
+        _ => Err(format!(
+            "chunk size must be between {} and {} bytes: {}",
+            UPLOAD_MIN_CHUNK_SIZE, UPLOAD_MAX_CHUNK_SIZE, argument
+        )),
+    }
+}
+
+fn is_tag<S: Into<String>>(argument: S) -> Result<(), String> {
+    upload::parse_tag(&argument.into())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn is_positive_float<S: Into<String>>(argument: S) -> Result<(), String> {
+    let argument = argument.into();
+    match argument.parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(()),
+        _ => Err(format!("received non-positive value: {}", argument)),
+    }
+}
+
+fn is_watch_interval<S: Into<String>>(argument: S) -> Result<(), String> {
+    let argument = argument.into();
+    match argument.parse::<f64>() {
+        Ok(value) if value > 0.0 && value <= UPLOAD_MAX_WATCH_INTERVAL_SECS as f64 => Ok(()),
+        _ => Err(format!(
+            "interval must be more than 0 and at most {} seconds: {}",
+            UPLOAD_MAX_WATCH_INTERVAL_SECS, argument
+        )),
+    }
+}
+
+fn is_environment<S: Into<String>>(argument: S) -> Result<(), String> {
+    let argument = argument.into();
+    match ApiEnvironment::from_str(&argument) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("received unknown environment: {}", argument)),
+    }
+}
+
+/// Builds the `clap::App` defining every subcommand and argument accepted by
+/// the Pennsieve agent.
+///
+/// `fallback_dataset` is used as the default value of `--dataset` arguments,
+/// typically the dataset selected with `use`.
+pub fn build_app<'a>(fallback_dataset: &'a str) -> clap::App<'a, 'a> {
+    clap::App::new(env!("CARGO_PKG_NAME"))
+                .version(env!("CARGO_PKG_VERSION"))
+                .author(env!("CARGO_PKG_AUTHORS"))
+                .about("The official Pennsieve client")
+                .setting(clap::AppSettings::UnifiedHelpMessage)
+        .arg(clap::Arg::with_name("output")
+             .short("O")
+             .long("output")
+             .takes_value(true)
+             .global(true)
+             .possible_value("simple")
+             .possible_value("rich")
+             .possible_value("json")
+             .possible_value("table")
+             .default_value("rich")
+             .help(concat!(
+                 "Sets the output format; \"table\" is the same as \"rich\". ",
+                 "JSON is printed by datasets, ls, where, members, organizations, ",
+                 "collaborators effective, create-dataset, create-collection, update-dataset ",
+                 "and upload-status --summary, --detail and --completed; ",
+                 "other commands print text")))
+        .arg(clap::Arg::with_name("columns")
+             .long("columns")
+             .value_name("columns")
+             .takes_value(true)
+             .global(true)
+             .help(concat!(
+                     "The comma separated columns to show, in order, in the tables of ",
+                     "datasets, ls, members and upload-status; other commands reject it. ",
+                     "`id` always chooses the ID column.\n",
+                     "Example: --columns=id,status"
+                )))
+        .arg(clap::Arg::with_name("no_color")
+             .long("no-color")
+             .global(true)
+             .help("Draw rich output without color; also turned off by NO_COLOR or when not writing to a terminal"))
+        .arg(clap::Arg::with_name("config")
+             .long("config")
+             .value_name("path")
+             .takes_value(true)
+             .global(true)
+             .help("Read configuration from this file instead of the default config.ini"))
+        .arg(clap::Arg::with_name("db")
+             .long("db")
+             .value_name("path")
+             .takes_value(true)
+             .global(true)
+             .help("Use this SQLite database file instead of the default agent.db"))
+        .arg(clap::Arg::with_name("timeout")
+             .long("timeout")
+             .value_name("seconds")
+             .takes_value(true)
+             .global(true)
+             .validator(is_numeric)
+             .help("Fail requests to the Pennsieve platform that take longer than this (0 waits indefinitely)"))
+        .arg(clap::Arg::with_name("organization")
+             .long("organization")
+             .value_name("id|name")
+             .takes_value(true)
+             .global(true)
+             .help("Run the command in this organization instead of your preferred one (see `use-org`)"))
+        .arg(clap::Arg::with_name("env")
+             .long("env")
+             .value_name("environment")
+             .takes_value(true)
+             .global(true)
+             .validator(is_environment)
+             .help(concat!(
+                     "Run the command against this environment instead of the profile's, without changing ",
+                     "the profile.\n",
+                     "Example: --env=development"
+                )))
+        .arg(clap::Arg::with_name("dataset_by_id")
+             .long("dataset-by-id")
+             .global(true)
+             .conflicts_with("dataset_by_name_only")
+             .help("Only accept dataset IDs (N:dataset:...) where a dataset is given, never names"))
+        .arg(clap::Arg::with_name("dataset_by_name_only")
+             .long("dataset-by-name-only")
+             .global(true)
+             .help("Always treat a given dataset as a name, even if it looks like a dataset ID"))
+        .arg(clap::Arg::with_name("no_cache")
+             .long("no-cache")
+             .global(true)
+             .help("Always look dataset names up on the Pennsieve platform, instead of reusing IDs they were recently resolved to"))
+        .arg(clap::Arg::with_name("quiet")
+             .short("q")
+             .long("quiet")
+             .global(true)
+             .help("Only log errors; overrides PENNSIEVE_LOG_LEVEL and -v"))
+        .arg(clap::Arg::with_name("verbose")
+             .short("v")
+             .long("verbose")
+             .multiple(true)
+             .global(true)
+             .help("Log more: -v logs at the info level, -vv at the debug level; overrides PENNSIEVE_LOG_LEVEL"))
+        .subcommand(append_command!(fallback_dataset))
+        .subcommand(clap::SubCommand::with_name("cache")
+                    .about("Manage the local timeseries cache")
+                    .long_about("Manage the local timeseries cache.")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(clap::SubCommand::with_name("prefetch")
+                                .about("Cache a window of timeseries data ahead of time")
+                                .arg(clap::Arg::with_name("package")
+                                     .long("package")
+                                     .value_name("ID")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(id_nonempty)
+                                     .help("The timeseries package to cache"))
+                                .arg(clap::Arg::with_name("channels")
+                                     .long("channels")
+                                     .value_name("IDS")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .multiple(true)
+                                     .use_delimiter(true)
+                                     .validator(id_nonempty)
+                                     .help("The channels to cache, separated by commas"))
+                                .arg(clap::Arg::with_name("rate")
+                                     .long("rate")
+                                     .value_name("HZ")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(is_positive_float)
+                                     .help("The sampling rate of the channels, in Hz"))
+                                .arg(clap::Arg::with_name("start")
+                                     .long("start")
+                                     .value_name("TIMESTAMP")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(is_numeric)
+                                     .help("The start of the window, in microseconds"))
+                                .arg(clap::Arg::with_name("end")
+                                     .long("end")
+                                     .value_name("TIMESTAMP")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(is_numeric)
+                                     .help("The end of the window, in microseconds")))
+                    .subcommand(clap::SubCommand::with_name("export")
+                                .about("Write a window of cached timeseries data to a file per channel")
+                                .long_about(concat!(
+                                    "Write a window of cached timeseries data to a file per channel, as\n",
+                                    "(timestamp, value) samples. Samples missing from the data are written as NaN.\n",
+                                    "Every page of the window must already be cached; see `cache prefetch`."))
+                                .arg(clap::Arg::with_name("package")
+                                     .long("package")
+                                     .value_name("ID")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(id_nonempty)
+                                     .help("The timeseries package to export"))
+                                .arg(clap::Arg::with_name("channels")
+                                     .long("channels")
+                                     .value_name("IDS")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .multiple(true)
+                                     .use_delimiter(true)
+                                     .validator(id_nonempty)
+                                     .help("The channels to export, separated by commas"))
+                                .arg(clap::Arg::with_name("rate")
+                                     .long("rate")
+                                     .value_name("HZ")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(is_positive_float)
+                                     .help("The sampling rate of the channels, in Hz"))
+                                .arg(clap::Arg::with_name("start")
+                                     .long("start")
+                                     .value_name("TIMESTAMP")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(is_numeric)
+                                     .help("The start of the window, in microseconds"))
+                                .arg(clap::Arg::with_name("end")
+                                     .long("end")
+                                     .value_name("TIMESTAMP")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(is_numeric)
+                                     .help("The end of the window, in microseconds"))
+                                .arg(clap::Arg::with_name("format")
+                                     .long("format")
+                                     .value_name("FORMAT")
+                                     .takes_value(true)
+                                     .default_value("csv")
+                                     .possible_values(&["csv", "npy"])
+                                     .help("Write CSV files, or NumPy .npy files of (timestamp, value) rows"))
+                                .arg(clap::Arg::with_name("output_dir")
+                                     .long("output-dir")
+                                     .value_name("DIR")
+                                     .takes_value(true)
+                                     .default_value(".")
+                                     .help("The directory to write the files to")))
+                    .subcommand(clap::SubCommand::with_name("verify")
+                                .about("Check the cache's page records against the page files on disk")
+                                .long_about(concat!(
+                                    "Check the cache's page records against the page files on disk, reporting\n",
+                                    "records whose file is missing and files that have no record.\n",
+                                    "Exits with a non-zero status if they disagree, unless --repair is given."))
+                                .arg(clap::Arg::with_name("repair")
+                                     .long("repair")
+                                     .help("Remove the dangling records and orphaned files that were found"))))
+        .subcommand(clap::SubCommand::with_name("config")
+                    .about("Configure the Pennsieve Agent")
+                    .long_about("Configure the Pennsieve Agent")
+                    .subcommand(clap::SubCommand::with_name("show")
+                                .about("Show a configuration value")
+                                .arg(clap::Arg::with_name("key")
+                                     .value_name("key")
+                                     .takes_value(true)
+                                     .required(false))
+                                .arg(clap::Arg::with_name("all")
+                                     .long("all")
+                                     .conflicts_with("key")
+                                     .help("Show the full effective configuration, including defaults and environment overrides"))
+                                .arg(clap::Arg::with_name("show_secrets")
+                                     .long("show-secrets")
+                                     .requires("all")
+                                     .help("Show the API token and secret instead of redacting them")))
+                    .subcommand(clap::SubCommand::with_name("wizard")
+                                .about("Create a new config file using the configuration wizard."))
+                    .subcommand(clap::SubCommand::with_name("example")
+                                .about("Print a template configuration file to standard output"))
+                    .subcommand(clap::SubCommand::with_name("schema-version")
+                                .about("Get/set the agent.db SQLite database schema version (user_version)")
+                                .arg(clap::Arg::with_name("version")
+                                    .value_name("version")
+                                    .required(false)
+                                    .hidden(cfg!(not(debug_assertions)))
+                                    .takes_value(true))
+                                .arg(clap::Arg::with_name("migrate")
+                                    .long("migrate")
+                                    .conflicts_with("version")
+                                    .help("Run pending migrations, even if DISABLE_MIGRATIONS is set, and print the resulting version"))))
+        .subcommand(clap::SubCommand::with_name("create-collection")
+                    .about("Create a new collection")
+                    .long_about("Create a new collection.")
+                    .arg(clap::Arg::with_name("name")
+                         .value_name("name")
+                         .takes_value(true)
+                         .required(true)
+                         .index(1)
+                         .help("A collection name, or a path of nested collections with --path"))
+                    .arg(clap::Arg::with_name("path")
+                         .long("path")
+                         .help("Treat the name as a slash-separated path like A/B/C, creating each collection along it that doesn't exist yet"))
+                    .arg(clap::Arg::with_name("dataset")
+                         .long("dataset")
+                         .value_name("dataset")
+                         .takes_value(true)
+                         .default_value(fallback_dataset)
+                         .validator(id_nonempty)
+                         .help(concat!(
+                                 "A dataset ID or name.\n",
+                                 "Example: --dataset=N:dataset:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
+                                 "         --dataset=\"My Samples\""
+                            )))
+                    .arg(clap::Arg::with_name("id_only")
+                         .long("id-only")
+                         .help("Print only the ID of the new collection")))
+        .subcommand(clap::SubCommand::with_name("clear")
+                    .about("Clear the current working dataset")
+                    .long_about("Clear the current working dataset.")
+                    .display_order(2))
+        .subcommand(clap::SubCommand::with_name("collaborators")
+                    .about("List the collaborators of a dataset")
+                    .long_about("List the collaborators of a dataset.")
+                    .arg(clap::Arg::with_name("dataset")
+                         .long("dataset")
+                         .value_name("dataset")
+                         .takes_value(true)
+                         .global(true)
+                         .default_value(fallback_dataset)
+                         .validator(id_nonempty)
+                         .help(concat!(
+                                 "A dataset ID or name.\n",
+                                 "Example: --dataset=N:dataset:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
+                                 "         --dataset=\"My Samples\""
+                            )))
+                    .subcommand(clap::SubCommand::with_name("users")
+                                .about("List all user collaborators.")
+                                .long_about("List all users and their permission level on the given dataset."))
+                    .subcommand(clap::SubCommand::with_name("teams")
+                                .about("List all team collaborators.")
+                                .long_about("List all teams and their permission level on the given dataset."))
+                    .subcommand(clap::SubCommand::with_name("organization")
+                                .about("Show the organization role.")
+                                .long_about("Show the role of the user's preferred organization on the given dataset."))
+                    .subcommand(clap::SubCommand::with_name("effective")
+                                .about("Show the effective role of a user.")
+                                .long_about(concat!(
+                                    "Show the role a user effectively has on the given dataset: the highest of the roles ",
+                                    "granted to them directly, to the teams they are a member of, and to their organization.\n",
+                                    "From least to most permissive, roles are: none, blind_reviewer, viewer, editor, ",
+                                    "manager and owner."
+                                ))
+                                .arg(clap::Arg::with_name("user")
+                                     .long("user")
+                                     .value_name("id")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .validator(id_nonempty)
+                                     .help("The ID of the user, as listed by `members`"))))
+        .subcommand(clap::SubCommand::with_name("completions")
+                    .about("Generate a shell completion script")
+                    .long_about("Generate a shell completion script and print it to standard output.")
+                    .arg(clap::Arg::with_name("shell")
+                         .value_name("shell")
+                         .takes_value(true)
+                         .required(true)
+                         .index(1)
+                         .possible_values(&["bash", "zsh", "fish", "powershell"])
+                         .help("The shell to generate completions for")))
+        .subcommand(clap::SubCommand::with_name("datasets")
+                    .about("List your datasets")
+                    .long_about("List your datasets.")
+                    .alias("ds")
+                    .arg(clap::Arg::with_name("search")
+                         .long("search")
+                         .value_name("query")
+                         .takes_value(true)
+                         .help("Only list datasets whose name or ID contains this text, ignoring case"))
+                    .arg(clap::Arg::with_name("limit")
+                         .long("limit")
+                         .value_name("N")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .help("List at most N datasets"))
+                    .arg(clap::Arg::with_name("offset")
+                         .long("offset")
+                         .value_name("N")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .help("Skip the first N datasets"))
+                    .arg(clap::Arg::with_name("sort")
+                         .long("sort")
+                         .value_name("key")
+                         .takes_value(true)
+                         .possible_values(&["name", "created", "updated"])
+                         .help("Sort datasets by name, creation time or last update; default is name"))
+                    .arg(clap::Arg::with_name("reverse")
+                         .long("reverse")
+                         .help("List datasets in reverse order. Datasets without the sort key are still listed last"))
+                    .arg(clap::Arg::with_name("id_only")
+                         .long("id-only")
+                         .help("Print only the ID of each dataset, one per line")))
+        .subcommand(clap::SubCommand::with_name("create-dataset")
+                    .about("Create a new dataset")
+                    .long_about("Create a new dataset.")
+                    .display_order(3)
+                    .arg(clap::Arg::with_name("name")
+                         .value_name("name")
+                         .takes_value(true)
+                         .required(true)
+                         .index(1)
+                         .help("A dataset name"))
+                    .arg(clap::Arg::with_name("description")
+                         .long("description")
+                         .required(false)
+                         .index(2)
+                         .help("An optional description"))
+                    .arg(clap::Arg::with_name("description_file")
+                         .long("description-file")
+                         .value_name("path")
+                         .takes_value(true)
+                         .validator(file_exists)
+                         .conflicts_with("description")
+                         .help("A UTF-8 text file to read a long or multi-line description from"))
+                    .arg(clap::Arg::with_name("from_template")
+                         .long("from-template")
+                         .value_name("path")
+                         .takes_value(true)
+                         .validator(file_exists)
+                         .help("A JSON file with the description, tags and license to create the dataset with"))
+                    .arg(clap::Arg::with_name("id_only")
+                         .long("id-only")
+                         .help("Print only the ID of the new dataset")))
+        .subcommand(clap::SubCommand::with_name("update-dataset")
+                    .about("Update the name, description or tags of a dataset")
+                    .long_about("Update the name, description or tags of a dataset. Only the given fields are changed.")
+                    .arg(clap::Arg::with_name("dataset")
+                         .value_name("dataset")
+                         .takes_value(true)
+                         .required(true)
+                         .index(1)
+                         .validator(id_nonempty)
+                         .help("A dataset ID or name"))
+                    .arg(clap::Arg::with_name("name")
+                         .long("name")
+                         .value_name("name")
+                         .takes_value(true)
+                         .validator(id_nonempty)
+                         .help("A new name"))
+                    .arg(clap::Arg::with_name("description")
+                         .long("description")
+                         .value_name("description")
+                         .takes_value(true)
+                         .help("A new description"))
+                    .arg(clap::Arg::with_name("description_file")
+                         .long("description-file")
+                         .value_name("path")
+                         .takes_value(true)
+                         .validator(file_exists)
+                         .conflicts_with("description")
+                         .help("A UTF-8 text file to read a long or multi-line description from"))
+                    .arg(clap::Arg::with_name("tags")
+                         .long("tags")
+                         .value_name("tags")
+                         .takes_value(true)
+                         .use_delimiter(true)
+                         .help(concat!(
+                             "A comma separated list of tags, replacing the current ones.\n",
+                             "Example: --tags=eeg,sleep"
+                         ))))
+        .subcommand(clap::SubCommand::with_name("delete-dataset")
+                    .about("Delete a dataset")
+                    .long_about("Delete a dataset, after asking for confirmation. A dataset that isn't empty is only deleted with --recursive.")
+                    .arg(clap::Arg::with_name("dataset")
+                         .value_name("dataset")
+                         .takes_value(true)
+                         .required(true)
+                         .index(1)
+                         .validator(id_nonempty)
+                         .help("A dataset ID or name"))
+                    .arg(clap::Arg::with_name("force")
+                         .short("f")
+                         .long("force")
+                         .help("Bypass the confirmation prompt"))
+                    .arg(clap::Arg::with_name("recursive")
+                         .short("r")
+                         .long("recursive")
+                         .help("Delete the dataset along with everything in it")))
+        .subcommand(clap::SubCommand::with_name("delete-package")
+                    .about("Delete a package or collection")
+                    .long_about("Delete a package or collection, after asking for confirmation. A collection that isn't empty is only deleted with --recursive.")
+                    .arg(clap::Arg::with_name("package")
+                         .value_name("package")
+                         .takes_value(true)
+                         .required(true)
+                         .index(1)
+                         .validator(id_nonempty)
+                         .help("A package or collection ID.\nExample: N:package:1234abcd-1234-abcd-efef-a0b1c2d3e4f5"))
+                    .arg(clap::Arg::with_name("force")
+                         .short("f")
+                         .long("force")
+                         .help("Bypass the confirmation prompt"))
+                    .arg(clap::Arg::with_name("recursive")
+                         .short("r")
+                         .long("recursive")
+                         .help("Delete the collection along with everything in it")))
+        .subcommand(clap::SubCommand::with_name("ls")
+                    .about("Provides navigation around datasets and collections")
+                    .long_about("Provides navigation around datasets and collections.")
+                    .arg(clap::Arg::with_name("dataset")
+                         .long("dataset")
+                         .value_name("dataset")
+                         .takes_value(true)
+                         .default_value(fallback_dataset)
+                         .validator(id_nonempty)
+                         .help(concat!(
+                                 "A dataset ID or name.\n",
+                                 "Example: --dataset=N:dataset:1234abcd-1234-abcd-efef-a0b1c2d3e4f5 or\n",
+                                 "         --dataset=\"My Samples\""
+                            )))
+                    .arg(clap::Arg::with_name("collection")
+                         .long("collection")
+                         .value_name("collection")
+                         .takes_value(true)
+                         .help("A package ID.\nExample: --collection=N:collection:1234abcd-1234-abcd-efef-a0b1c2d3e4f5"))
+                    .arg(clap::Arg::with_name("recursive")
+                         .short("r")
+                         .long("recursive")
+                         .help("Print the full tree of collections and packages"))
+                    .arg(clap::Arg::with_name("max_depth")
+                         .long("max-depth")
+                         .value_name("depth")
+                         .takes_value(true)
+                         .requires("recursive")
+                         .validator(is_numeric)
+                         .help("The maximum depth of the tree printed by --recursive"))
+                    .arg(clap::Arg::with_name("type")
+                         .long("type")
+                         .value_name("type")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .conflicts_with("recursive")
+                         .validator(is_package_type)
+                         .help(concat!(
+                                 "List only packages of the given type; may be repeated.\n",
+                                 "Example: --type=TimeSeries --type=Collection"
+                            )))
+                    .arg(clap::Arg::with_name("id_only")
+                         .long("id-only")
+                         .conflicts_with("recursive")
+                         .help("Print only the ID of each dataset or package listed, one per line")))
+
+        .subcommand(clap::SubCommand::with_name("move")
+                    .alias("mv")
+                    .about("Move packages and collections")
+                    .long_about("Move packages and collections")
+                    .arg(clap::Arg::with_name("source")
+                         .long("source")
+                         .value_name("source")
+                         .required(true)
+                         .index(1)
+                         .help("The package or collection to move")
+                    )
+                    .arg(clap::Arg::with_name("destination")
+                         .long("destination")
+                         .value_name("destination")
+                         .required(false)
+                         .index(2)
+                         .help("The destination collection. If not provided, the source will be moved to the root of the dataset"))
+                    .arg(clap::Arg::with_name("dry_run")
+                         .long("dry-run")
+                         .help("Print what would be moved, and where, without moving anything")))
+
+        .subcommand(clap::SubCommand::with_name("members")
+                    .about("List the members that are part of the organization you belong to")
+                    .long_about("List the members that are part of the organization you belong to.")
+                    .arg(clap::Arg::with_name("search")
+                         .long("search")
+                         .value_name("query")
+                         .takes_value(true)
+                         .help("Only list members whose name or email contains this text, ignoring case"))
+                    .arg(clap::Arg::with_name("role")
+                         .long("role")
+                         .value_name("role")
+                         .takes_value(true)
+                         .help("Only list members with this role, e.g. \"owner\" or \"editor\"")))
+        .subcommand(clap::SubCommand::with_name("organizations")
+                    .about("List the organizations you belong to")
+                    .long_about("List the organizations you belong to. The active organization is marked with a *.")
+                    .alias("orgs")
+                    .arg(clap::Arg::with_name("current")
+                         .long("current")
+                         .help("Only print the ID and name of the active organization")))
+        .subcommand(clap::SubCommand::with_name("use-org")
+                    .about("Switch to another organization you belong to")
+                    .long_about("Switch to another organization you belong to. The organization is used by every following command until you switch again.")
+                    .arg(clap::Arg::with_name("org")
+                         .value_name("id|name")
+                         .takes_value(true)
+                         .required(true)
+                         .help("The ID or name of the organization, as listed by `organizations`")))
+        .subcommand(clap::SubCommand::with_name("rename")
+                    .about("Rename a package or dataset")
+                    .long_about("Rename a package or dataset.")
+                    .arg(clap::Arg::with_name("package_or_dataset_id")
+                         .value_name("id")
+                         .takes_value(true)
+                         .required(true)
+                         .index(1)
+                         .help("A package ID, or dataset ID, or dataset name"))
+                    .arg(clap::Arg::with_name("name")
+                         .value_name("name")
+                         .takes_value(true)
+                         .required(true)
+                         .index(2)
+                         .help("A new name"))
+                    .arg(clap::Arg::with_name("force")
+                         .long("force")
+                         .short("f")
+                         .help("Rename even if another item in the same place already has the new name"))
+                    .arg(clap::Arg::with_name("dry_run")
+                         .long("dry-run")
+                         .help("Print the old and new names without renaming anything")))
+        .subcommand(clap::SubCommand::with_name("server")
+                    .about("Start the Pennsieve agent in server mode")
+                    .long_about("Start the Pennsieve agent in server mode.")
+                    .arg(
+                         clap::Arg::with_name("parallelism")
+                         .long("parallelism")
+                         .value_name("parallelism")
+                         .takes_value(true)
+                         .hidden(true)
+                         .help("Parallelism level; default is the number of CPUs"))
+                    .arg(
+                         clap::Arg::with_name("file_parallelism")
+                         .long("file-parallelism")
+                         .value_name("n")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .help("How many files to upload at once; default is 1"))
+                    .arg(
+                         clap::Arg::with_name("chunk_parallelism")
+                         .long("chunk-parallelism")
+                         .value_name("n")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .help("How many chunks of each file to upload at once; default is the number of CPUs"))
+                    .arg(
+                         clap::Arg::with_name("allow_oversubscribe")
+                         .long("allow-oversubscribe")
+                         .hidden(true)
+                         .help("Allow a parallelism level above the number of CPUs"))
+                    .arg(
+                         clap::Arg::with_name("idle_timeout")
+                         .long("idle-timeout")
+                         .value_name("seconds")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .help("Shut down once no uploads are queued and no status requests have arrived for this many seconds"))
+                    .arg(
+                         clap::Arg::with_name("pid_file")
+                         .long("pid-file")
+                         .value_name("path")
+                         .takes_value(true)
+                         .help("Write the agent's PID to this file while it runs; refuses to start if it names a running agent"))
+                    .subcommand(clap::SubCommand::with_name("status")
+                                .about("Check if the agent named by a PID file is running")
+                                .long_about("Check if the agent named by the PID file given with --pid-file is running.\nExits with a non-zero status if it is not.")
+                                .arg(
+                                     clap::Arg::with_name("pid_file")
+                                     .long("pid-file")
+                                     .value_name("path")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .help("The PID file written by `server --pid-file`"))))
+        .subcommand(clap::SubCommand::with_name("teams")
+                    .about("List the teams that are part of the organization you belong to")
+                    .long_about("List the teams that are part of the organization you belong to."))
+        .subcommand(upload_command!(fallback_dataset))
+        .subcommand(clap::SubCommand::with_name("profile")
+                    .about("Manage profiles")
+                    .long_about("Manage profiles.")
+                    .subcommand(clap::SubCommand::with_name("show")
+                                .about("Show current active profile"))
+                    .subcommand(clap::SubCommand::with_name("switch")
+                                .about("Switch to a new profile")
+                                .arg(clap::Arg::with_name("profile")
+                                     .value_name("profile")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .validator(profile_exists)
+                                     .index(1)
+                                     .help("The target profile")))
+                    .subcommand(clap::SubCommand::with_name("create")
+                                .about("Create a new profile"))
+                    .subcommand(clap::SubCommand::with_name("delete")
+                                .about("Delete a profile")
+                                .arg(clap::Arg::with_name("profile")
+                                     .value_name("profile")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .validator(profile_exists)
+                                     .index(1)
+                                     .help("The profile to be deleted")))
+                    .subcommand(clap::SubCommand::with_name("set-default")
+                                .about("Set a profile as the default")
+                                .arg(clap::Arg::with_name("profile")
+                                     .value_name("profile")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .validator(profile_exists)
+                                     .index(1)
+                                     .help("The profile to use as new default")))
+                    .subcommand(clap::SubCommand::with_name("rename")
+                                .about("Rename a profile")
+                                .long_about("Rename a profile. It remains the default or current profile if it was before, and keeps its settings.")
+                                .arg(clap::Arg::with_name("profile")
+                                     .value_name("profile")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .validator(profile_exists)
+                                     .index(1)
+                                     .help("The profile to be renamed"))
+                                .arg(clap::Arg::with_name("new_name")
+                                     .value_name("new-name")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .index(2)
+                                     .help("The new name of the profile")))
+                    .subcommand(clap::SubCommand::with_name("list")
+                                .about("Display a list of available profiles"))
+                    .subcommand(clap::SubCommand::with_name("export")
+                                .about("Export a profile to a JSON file")
+                                .long_about("Export a profile to a JSON file, which can be imported on another machine with `profile import`. The API token and secret are only exported with --include-secrets.")
+                                .arg(clap::Arg::with_name("profile")
+                                     .value_name("profile")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .validator(profile_exists)
+                                     .index(1)
+                                     .help("The profile to export"))
+                                .arg(clap::Arg::with_name("output")
+                                     .short("o")
+                                     .long("output")
+                                     .value_name("path")
+                                     .takes_value(true)
+                                     .help("The file to write to; defaults to <profile>.json"))
+                                .arg(clap::Arg::with_name("include_secrets")
+                                     .long("include-secrets")
+                                     .help("Include the API token and secret in the export"))
+                                .arg(clap::Arg::with_name("force")
+                                     .short("f")
+                                     .long("force")
+                                     .help("Overwrite an existing file at the output path")))
+                    .subcommand(clap::SubCommand::with_name("import")
+                                .about("Import a profile from a JSON file")
+                                .long_about("Import a profile written by `profile export`. If the API token and secret were not exported, you will be asked for them.")
+                                .arg(clap::Arg::with_name("file")
+                                     .value_name("file")
+                                     .required(true)
+                                     .takes_value(true)
+                                     .validator(file_exists)
+                                     .index(1)
+                                     .help("The exported profile"))
+                                .arg(clap::Arg::with_name("force")
+                                     .short("f")
+                                     .long("force")
+                                     .help("Overwrite an existing profile with the same name"))))
+        .subcommand(clap::SubCommand::with_name("upload-status")
+                    .about("Check the upload status of files")
+                    .long_about("Check the upload status of files; resume and cancel uploads.")
+                    .arg(clap::Arg::with_name("cancel")
+                            .long("cancel")
+                            .value_name("ID")
+                            .multiple(true)
+                            .takes_value(true)
+                            .help("Cancel an upload by its ID"))
+                    .arg(clap::Arg::with_name("cancel_pending")
+                            .long("cancel-pending")
+                            .value_name("cancel-pending")
+                            .takes_value(false)
+                            .help("Cancel all pending uploads"))
+                    .arg(clap::Arg::with_name("cancel_all")
+                            .long("cancel-all")
+                            .value_name("cancel-all")
+                            .takes_value(false)
+                            .help("Cancel all uploads, regardless of status"))
+                    .arg(clap::Arg::with_name("cancel_dataset")
+                            .long("cancel-dataset")
+                            .value_name("dataset")
+                            .takes_value(true)
+                            .validator(id_nonempty)
+                            .help(concat!(
+                                    "Cancel the queued and in-progress uploads to a dataset, given by ID or name.\n",
+                                    "Example: --cancel-dataset=\"My Samples\""
+                               )))
+                    .arg(clap::Arg::with_name("cancel_import")
+                            .long("cancel-import")
+                            .value_name("import_id")
+                            .takes_value(true)
+                            .validator(id_nonempty)
+                            .help("Cancel the queued and in-progress uploads of every file queued under an import ID"))
+                    .arg(clap::Arg::with_name("retry")
+                            .long("retry")
+                            .value_name("ID")
+                            .multiple(true)
+                            .takes_value(true)
+                            .help("Retry an upload by ID"))
+                    .arg(clap::Arg::with_name("retry_all")
+                            .long("retry-all")
+                            .conflicts_with("retry")
+                            .help("Retry every failed upload that was interrupted midway"))
+                    .arg(clap::Arg::with_name("resume")
+                         .long("resume")
+                         .help("Resume queued uploads"))
+                    .arg(clap::Arg::with_name("failed")
+                         .long("failed")
+                         .help("View failed uploads"))
+                    .arg(clap::Arg::with_name("watch")
+                         .long("watch")
+                         .value_name("seconds")
+                         .takes_value(true)
+                         .min_values(0)
+                         .max_values(1)
+                         .validator(is_watch_interval)
+                         .help("Redraw the active uploads every few seconds, half a second by default, until none are left"))
+                    .arg(clap::Arg::with_name("summary")
+                         .long("summary")
+                         .help("View the number of uploads with each status, the bytes left to upload and the age of the oldest queued upload"))
+                    .arg(clap::Arg::with_name("detail")
+                         .long("detail")
+                         .value_name("ID")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .help("View every stored detail of an upload by its ID, including the last error of the whole upload (errors of chunks that succeeded when retried are not recorded)"))
+                    .arg(clap::Arg::with_name("export")
+                         .long("export")
+                         .value_name("FORMAT")
+                         .takes_value(true)
+                         .possible_values(&["csv", "json"])
+                         .help("Write every upload record to stdout as CSV or JSON"))
+                    .arg(clap::Arg::with_name("status")
+                         .long("status")
+                         .value_name("STATUS")
+                         .takes_value(true)
+                         .requires("export")
+                         .possible_values(&["queued", "in_progress", "completed", "failed"])
+                         .help("Only export uploads with this status"))
+                    .arg(clap::Arg::with_name("completed")
+                         .long("completed")
+                         .value_name("N")
+                         .validator(is_numeric)
+                         .takes_value(true)
+                         .min_values(0)
+                         .max_values(1)
+                         .help("View the last N completed uploads, or every completed upload if N is not given"))
+                    .arg(clap::Arg::with_name("since")
+                         .long("since")
+                         .value_name("DATE")
+                         .takes_value(true)
+                         .requires("completed")
+                         .validator(is_date)
+                         .help("Only view uploads completed on or after this ISO 8601 date or date and time"))
+                    .arg(clap::Arg::with_name("until")
+                         .long("until")
+                         .value_name("DATE")
+                         .takes_value(true)
+                         .requires("completed")
+                         .validator(is_date)
+                         .help("Only view uploads completed on or before this ISO 8601 date or date and time"))
+                    .arg(clap::Arg::with_name("listen")
+                         .long("listen")
+                         .takes_value(false)
+                         .help(concat!("Listens for incoming uploads and does not terminate upon upload completion.\n",
+                                       "This mode is useful for scripting the upload behavior of the Pennsieve command line tool \n",
+                                       "by sending files to be uploaded over a websocket.")))
+                    .arg(clap::Arg::with_name("port")
+                         .long("port")
+                         .takes_value(true)
+                         .requires("listen")
+                         .help("The port to listen on"))
+                    .arg(clap::Arg::with_name("no_progress")
+                         .long("no-progress")
+                         .help("Print periodic status lines instead of progress bars"))
+                    .arg(clap::Arg::with_name("upload_log")
+                         .long("upload-log")
+                         .value_name("dir")
+                         .takes_value(true)
+                         .help("Write a log file for each upload, named by its import ID, to this directory"))
+                    .arg(clap::Arg::with_name("idle_timeout")
+                         .long("idle-timeout")
+                         .value_name("seconds")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .conflicts_with("listen")
+                         .help("If the agent is started, shut it down once no uploads are queued and no status requests have arrived for this many seconds"))
+                    .arg(clap::Arg::with_name("parallelism")
+                         .long("parallelism")
+                         .value_name("parallelism")
+                         .takes_value(true)
+                         .hidden(true)
+                         .help("Parallelism level; default is the number of CPUs"))
+                    .arg(clap::Arg::with_name("file_parallelism")
+                         .long("file-parallelism")
+                         .value_name("n")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .help("If the agent is started, how many files to upload at once; default is 1"))
+                    .arg(clap::Arg::with_name("chunk_parallelism")
+                         .long("chunk-parallelism")
+                         .value_name("n")
+                         .takes_value(true)
+                         .validator(is_numeric)
+                         .help("If the agent is started, how many chunks of each file to upload at once; default is the number of CPUs"))
+                    .arg(clap::Arg::with_name("allow_oversubscribe")
+                         .long("allow-oversubscribe")
+                         .hidden(true)
+                         .help("Allow a parallelism level above the number of CPUs")))
+        .subcommand(clap::SubCommand::with_name("upload-verify")
+                    .about("Verify the integrity of files on the platform")
+                    .long_about(concat!("Verify that local files match uploaded files in the platform.\n",
+                                        "If a local filepath is not specified, the local file that was ",
+                                        "originally uploaded will be used to verify."))
+                    .arg(clap::Arg::with_name("id")
+                            .short("i")
+                            .long("upload-id")
+                            .value_name("ID")
+                            .takes_value(true)
+                            .validator(is_numeric)
+                            .required_unless_one(&["all", "dataset"])
+                            .help("The ID of the uploaded file, as it appears in `upload-status --completed N`"))
+                    .arg(clap::Arg::with_name("path")
+                            .short("f")
+                            .long("path")
+                            .value_name("PATH")
+                            .takes_value(true)
+                            .validator(file_exists)
+                            .requires("id")
+                            .help("An optional local file to check against the uploaded file."))
+                    .arg(clap::Arg::with_name("search_dir")
+                            .long("search-dir")
+                            .value_name("PATH")
+                            .takes_value(true)
+                            .validator(file_exists)
+                            .requires("id")
+                            .conflicts_with("path")
+                            .help(concat!("A directory to look for the file in if it was moved since it was uploaded.\n",
+                                          "A file with the same name and contents is used to verify the upload.")))
+                    .arg(clap::Arg::with_name("all")
+                            .long("all")
+                            .conflicts_with_all(&["id", "dataset"])
+                            .help("Verify every completed upload"))
+                    .arg(clap::Arg::with_name("dataset")
+                            .long("dataset")
+                            .value_name("ID")
+                            .takes_value(true)
+                            .conflicts_with("id")
+                            .help("Verify every completed upload to the dataset with this ID"))
+                    .arg(clap::Arg::with_name("parallelism")
+                            .long("parallelism")
+                            .value_name("n")
+                            .takes_value(true)
+                            .help("How many uploads to verify at once with --all or --dataset; default is the number of CPUs")))
+        .subcommand(clap::SubCommand::with_name("use")
+                    .about("Set your current working dataset")
+                    .long_about("Set your current working dataset.")
+                    .display_order(1)
+                    .arg(clap::Arg::with_name("dataset")
+                         .value_name("dataset")
+                         .takes_value(true)
+                         .index(1)
+                         .help("A dataset's ID or name. If omitted, the current dataset will be printed."))
+                    .arg(clap::Arg::with_name("clear")
+                         .long("clear")
+                         .conflicts_with("dataset")
+                         .help("Clear the current working dataset, the same as `clear`")))
+        .subcommand(clap::SubCommand::with_name("version")
+            .about("Print the current version number")
+            .long_about("Print the current version number.")
+            .arg(clap::Arg::with_name("check")
+                 .long("check")
+                 .help("Check now whether a newer version is available"))
+            .arg(clap::Arg::with_name("format")
+                 .long("format")
+                 .takes_value(true)
+                 .possible_values(&["plain", "json", "long"])
+                 .default_value("plain")
+                 .help("Print just the version, a JSON object with the version, commit and build time, or all of these for humans")))
+        .subcommand(clap::SubCommand::with_name("where")
+                    .about("Show the path to a package or dataset")
+                    .long_about("Show the path to a package or dataset.")
+                    .arg(clap::Arg::with_name("package_or_dataset_id")
+                         .value_name("id")
+                         .takes_value(true)
+                         .default_value(fallback_dataset)
+                         .validator(id_nonempty)
+                         .index(1)
+                         .help("A package or collection ID"))
+                    .arg(clap::Arg::with_name("id_only")
+                         .long("id-only")
+                         .help("Print only the ID of the package or dataset"))
+                    .arg(clap::Arg::with_name("copy")
+                         .long("copy")
+                         .help(concat!(
+                             "Print only the ID of the package or dataset, and copy it to the clipboard\n",
+                             "if the agent was built with the `clipboard` feature"
+                         ))))
+        .subcommand(clap::SubCommand::with_name("whoami")
+                    .about("Displays information about the logged in user")
+                    .long_about("Displays information about the logged in user.")
+                    .arg(clap::Arg::with_name("refresh")
+                         .long("refresh")
+                         .help("Log in again to renew the session token, even if the current one is still valid")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bash_completions_contain_subcommands() {
+        let mut buf: Vec<u8> = Vec::new();
+        build_app("").gen_completions_to(env!("CARGO_PKG_NAME"), clap::Shell::Bash, &mut buf);
+        let script = String::from_utf8(buf).unwrap();
+
+        assert!(script.contains("upload"));
+        assert!(script.contains("upload-status"));
+        assert!(script.contains("completions"));
+    }
+
+    #[test]
+    fn dates_must_be_iso_8601() {
+        assert!(is_date("2019-03-01").is_ok());
+        assert!(is_date("2019-03-01T12:30:00Z").is_ok());
+        assert!(is_date("March 1st").is_err());
+    }
+
+    #[test]
+    fn package_types_are_validated() {
+        assert!(is_package_type("timeseries").is_ok());
+        let e = is_package_type("spreadsheet").unwrap_err();
+        assert!(e.contains("Tabular"));
+    }
+
+    #[test]
+    fn stdin_uploads_need_a_name_instead_of_paths() {
+        let upload = |args: &[&str]| {
+            build_app("").get_matches_from_safe(
+                ["pennsieve", "upload", "--dataset=N:dataset:1"]
+                    .iter()
+                    .chain(args.iter())
+                    .cloned(),
+            )
+        };
+
+        let matches = upload(&["--stdin", "--name=data.csv"]).unwrap();
+        let args = matches.subcommand_matches("upload").unwrap();
+        assert!(args.is_present("stdin"));
+        assert_eq!(args.value_of("name"), Some("data.csv"));
+
+        assert!(upload(&["--stdin"]).is_err());
+        assert!(upload(&["--name=data.csv"]).is_err());
+        assert!(upload(&["--stdin", "--name=data.csv", "Cargo.toml"]).is_err());
+    }
+
+    #[test]
+    fn a_manifest_can_be_written_of_what_was_uploaded() {
+        let upload = |args: &[&str]| {
+            build_app("").get_matches_from_safe(
+                ["pennsieve", "upload", "--dataset=N:dataset:1", "Cargo.toml"]
+                    .iter()
+                    .chain(args.iter())
+                    .cloned(),
+            )
+        };
+
+        let matches = upload(&["--manifest-output=uploaded.csv"]).unwrap();
+        let args = matches.subcommand_matches("upload").unwrap();
+        assert_eq!(args.value_of("manifest_output"), Some("uploaded.csv"));
+
+        // nothing is uploaded in a dry run:
+        assert!(upload(&["--manifest-output=uploaded.csv", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn environments_are_validated() {
+        assert!(is_environment("production").is_ok());
+        assert!(is_environment("development").is_ok());
+        assert!(is_environment("staging-ish").is_err());
+    }
+
+    #[test]
+    fn upload_status_can_be_watched_with_an_optional_interval() {
+        let status = |args: &[&str]| {
+            build_app("").get_matches_from_safe(
+                ["pennsieve", "upload-status"]
+                    .iter()
+                    .chain(args.iter())
+                    .cloned(),
+            )
+        };
+
+        let matches = status(&["--watch"]).unwrap();
+        let args = matches.subcommand_matches("upload-status").unwrap();
+        assert!(args.is_present("watch"));
+        assert_eq!(args.value_of("watch"), None);
+
+        let matches = status(&["--watch", "2.5"]).unwrap();
+        let args = matches.subcommand_matches("upload-status").unwrap();
+        assert_eq!(args.value_of("watch"), Some("2.5"));
+
+        assert!(status(&["--watch", "0"]).is_err());
+        assert!(status(&["--watch", "often"]).is_err());
+    }
+
+    #[test]
+    fn datasets_from_path_take_a_single_root() {
+        let upload = |args: &[&str]| {
+            build_app("").get_matches_from_safe(
+                ["pennsieve", "upload", "--dataset-from-path"]
+                    .iter()
+                    .chain(args.iter())
+                    .cloned(),
+            )
+        };
+
+        let matches = upload(&["src"]).unwrap();
+        let args = matches.subcommand_matches("upload").unwrap();
+        assert!(args.is_present("dataset_from_path"));
+        assert_eq!(
+            args.values_of("paths").unwrap().collect::<Vec<_>>(),
+            vec!["src"]
+        );
+
+        assert!(upload(&[]).is_err());
+        assert!(upload(&["src", "--folder=Samples"]).is_err());
+        assert!(upload(&["src", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn tags_can_be_given_more_than_once() {
+        let app = |command: &str, tags: &[&str]| {
+            let mut args = vec!["pennsieve", command, "--dataset=N:dataset:1"];
+            for tag in tags {
+                args.push("--tag");
+                args.push(tag);
+            }
+            args.push("Cargo.toml");
+            build_app("").get_matches_from_safe(args)
+        };
+
+        for command in &["upload", "append"] {
+            let matches = app(command, &["subject=mouse-12", "session=3"]).unwrap();
+            let args = matches.subcommand_matches(command).unwrap();
+            assert_eq!(
+                args.values_of("tag").unwrap().collect::<Vec<_>>(),
+                vec!["subject=mouse-12", "session=3"]
+            );
+            assert_eq!(
+                args.values_of("paths").unwrap().collect::<Vec<_>>(),
+                vec!["Cargo.toml"]
+            );
+
+            assert!(app(command, &["subject"]).is_err());
+            assert!(app(command, &["=mouse-12"]).is_err());
+        }
+    }
+
+    #[test]
+    fn dataset_descriptions_are_given_inline_or_from_a_file() {
+        let create = |args: &[&str]| {
+            build_app("").get_matches_from_safe(
+                ["pennsieve", "create-dataset", "Sleep study"]
+                    .iter()
+                    .chain(args.iter())
+                    .cloned(),
+            )
+        };
+
+        let matches = create(&["--description-file", "Cargo.toml"]).unwrap();
+        let args = matches.subcommand_matches("create-dataset").unwrap();
+        assert_eq!(args.value_of("description_file"), Some("Cargo.toml"));
+        assert_eq!(args.value_of("description"), None);
+
+        assert!(create(&["EEG recordings", "--description-file", "Cargo.toml"]).is_err());
+        assert!(create(&["--description-file", "no-such-description.txt"]).is_err());
+    }
+
+    #[test]
+    fn datasets_are_updated_by_field() {
+        let update = |args: &[&str]| {
+            build_app("").get_matches_from_safe(
+                ["pennsieve", "update-dataset", "Sleep study"]
+                    .iter()
+                    .chain(args.iter())
+                    .cloned(),
+            )
+        };
+
+        let matches = update(&["--description", "EEG recordings", "--tags=eeg,sleep"]).unwrap();
+        let args = matches.subcommand_matches("update-dataset").unwrap();
+        assert_eq!(args.value_of("dataset"), Some("Sleep study"));
+        assert_eq!(args.value_of("name"), None);
+        assert_eq!(args.value_of("description"), Some("EEG recordings"));
+        assert_eq!(
+            args.values_of("tags").unwrap().collect::<Vec<_>>(),
+            vec!["eeg", "sleep"]
+        );
+
+        assert!(update(&["--description-file", "Cargo.toml"]).is_ok());
+        assert!(update(&["--description=EEG", "--description-file", "Cargo.toml"]).is_err());
+        assert!(build_app("")
+            .get_matches_from_safe(vec!["pennsieve", "update-dataset"])
+            .is_err());
+    }
+
+    #[test]
+    fn deletes_can_be_forced_and_recursive() {
+        for (command, target) in &[
+            ("delete-dataset", "Sleep study"),
+            ("delete-package", "N:collection:1"),
+        ] {
+            let matches = build_app("")
+                .get_matches_from_safe(vec!["pennsieve", command, target, "-f", "--recursive"])
+                .unwrap();
+            let args = matches.subcommand_matches(command).unwrap();
+            assert!(args.is_present("force"));
+            assert!(args.is_present("recursive"));
+
+            assert!(build_app("")
+                .get_matches_from_safe(vec!["pennsieve", command])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn chunk_sizes_must_be_valid_parts() {
+        assert!(is_chunk_size("5242880").is_ok());
+        assert!(is_chunk_size("104857600").is_ok());
+        assert!(is_chunk_size("1024").is_err());
+        assert!(is_chunk_size("6000000000").is_err());
+        assert!(is_chunk_size("big").is_err());
+    }
+
+    #[test]
+    fn watch_intervals_must_fit_a_duration() {
+        assert!(is_watch_interval("0.5").is_ok());
+        assert!(is_watch_interval("86400").is_ok());
+        assert!(is_watch_interval("0").is_err());
+        assert!(is_watch_interval("1e30").is_err());
+        assert!(is_watch_interval("inf").is_err());
+        assert!(is_watch_interval("NaN").is_err());
+    }
+}
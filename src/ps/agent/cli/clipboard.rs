@@ -0,0 +1,54 @@
+//! Copying text to the system clipboard, as done by `where --copy`. This is
+//! only built with the `clipboard` feature.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+// The clipboard tools to try, in order, along with their arguments:
+#[cfg(target_os = "macos")]
+const CLIPBOARD_TOOLS: &[(&str, &[&str])] = &[("pbcopy", &[])];
+
+#[cfg(windows)]
+const CLIPBOARD_TOOLS: &[(&str, &[&str])] = &[("clip", &[])];
+
+#[cfg(all(unix, not(target_os = "macos")))]
+const CLIPBOARD_TOOLS: &[(&str, &[&str])] = &[
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+];
+
+/// Copies `text` to the system clipboard with the first clipboard tool
+/// that succeeds.
+pub fn copy(text: &str) -> io::Result<()> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no clipboard tool was found");
+    for (program, args) in CLIPBOARD_TOOLS {
+        match pipe_to(program, args, text) {
+            Ok(()) => return Ok(()),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Runs `program`, writing `text` to its standard input.
+fn pipe_to(program: &str, args: &[&str], text: &str) -> io::Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} exited with {}", program, status),
+        ))
+    }
+}
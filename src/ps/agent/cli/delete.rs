@@ -0,0 +1,224 @@
+//! Checks made before deleting a dataset or package with `delete-dataset`
+//! and `delete-package`.
+
+use std::io;
+
+use futures::Future as _Future;
+use pennsieve_macros::try_future;
+
+use crate::ps::agent::cli::output::CliPackageTree;
+use crate::ps::agent::cli::Error;
+use crate::ps::agent::{self, Future};
+use crate::ps::util::futures::*;
+
+/// The kind of node `target` is, as it is named in prompts and errors.
+fn kind(target: &CliPackageTree) -> &'static str {
+    match target.package_type.as_str() {
+        "Dataset" => "dataset",
+        "Collection" => "collection",
+        _ => "package",
+    }
+}
+
+/// Describes what deleting `target` removes, as asked before deleting it.
+pub fn describe(target: &CliPackageTree) -> String {
+    let contents = match target.children.len() {
+        0 => String::new(),
+        1 => " and the 1 item in it".to_string(),
+        n => format!(" and the {} items in it", n),
+    };
+    format!(
+        "Delete {kind} \"{name}\" ({id}){contents}?",
+        kind = kind(target),
+        name = target.name,
+        id = target.id,
+        contents = contents
+    )
+}
+
+/// Checks that `target` may be deleted: a dataset or collection that
+/// isn't empty only with `recursive`, and, unless `force` is given, only
+/// once `confirm` agrees to it.
+fn check<C>(target: &CliPackageTree, force: bool, recursive: bool, confirm: C) -> agent::Result<()>
+where
+    C: FnOnce(String) -> io::Result<bool>,
+{
+    if !target.children.is_empty() && !recursive {
+        return Err(
+            Error::not_empty(kind(target), target.name.clone(), target.children.len()).into(),
+        );
+    }
+    if force || confirm(describe(target)).map_err(Error::from)? {
+        Ok(())
+    } else {
+        Err(agent::ErrorKind::UserCancelledError.into())
+    }
+}
+
+/// Deletes `target` with `delete`, given its ID. The direct children of
+/// `target` are expected to be populated already.
+///
+/// A dataset or collection that isn't empty is only deleted when
+/// `recursive` is given. Unless `force` is given, `confirm` is asked
+/// first; if it declines, nothing is deleted and the deletion fails as
+/// cancelled. Returns the deleted target.
+pub fn delete<C, D>(
+    target: CliPackageTree,
+    force: bool,
+    recursive: bool,
+    confirm: C,
+    delete: D,
+) -> Future<CliPackageTree>
+where
+    C: FnOnce(String) -> io::Result<bool>,
+    D: FnOnce(String) -> Future<()>,
+{
+    try_future!(check(&target, force, recursive, confirm));
+    delete(target.id.clone()).map(|_| target).into_trait()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use futures::future;
+
+    use crate::ps::agent::cli::ErrorKind;
+
+    fn collection() -> CliPackageTree {
+        CliPackageTree::new("Subjects", "N:collection:1", "Collection").with_children(vec![
+            CliPackageTree::new("recording.edf", "N:package:2", "TimeSeries"),
+            CliPackageTree::new("scan.nii", "N:package:3", "MRI"),
+        ])
+    }
+
+    fn package() -> CliPackageTree {
+        CliPackageTree::new("readme.txt", "N:package:1", "Text")
+    }
+
+    /// Returns a `delete` function that records the ID of every node it
+    /// deletes, standing in for the Pennsieve API.
+    fn mock_delete(deleted: Arc<Mutex<Vec<String>>>) -> impl FnOnce(String) -> Future<()> {
+        move |id: String| {
+            deleted.lock().unwrap().push(id);
+            future::ok(()).into_trait()
+        }
+    }
+
+    /// Returns a `confirm` function that gives `answer` and records the
+    /// prompt it was asked.
+    fn mock_confirm(
+        answer: bool,
+        prompts: Arc<Mutex<Vec<String>>>,
+    ) -> impl FnOnce(String) -> io::Result<bool> {
+        move |prompt: String| {
+            prompts.lock().unwrap().push(prompt);
+            Ok(answer)
+        }
+    }
+
+    #[test]
+    fn deletion_is_confirmed_first() {
+        let deleted = Arc::new(Mutex::new(vec![]));
+        let prompts = Arc::new(Mutex::new(vec![]));
+
+        let target = delete(
+            package(),
+            false,
+            false,
+            mock_confirm(true, Arc::clone(&prompts)),
+            mock_delete(Arc::clone(&deleted)),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(target.id, "N:package:1");
+        assert_eq!(*deleted.lock().unwrap(), vec!["N:package:1"]);
+        assert_eq!(
+            *prompts.lock().unwrap(),
+            vec!["Delete package \"readme.txt\" (N:package:1)?"]
+        );
+    }
+
+    #[test]
+    fn declining_the_confirmation_deletes_nothing() {
+        let deleted = Arc::new(Mutex::new(vec![]));
+        let prompts = Arc::new(Mutex::new(vec![]));
+
+        let e = delete(
+            package(),
+            false,
+            false,
+            mock_confirm(false, Arc::clone(&prompts)),
+            mock_delete(Arc::clone(&deleted)),
+        )
+        .wait()
+        .unwrap_err();
+        assert_eq!(e.kind(), &agent::ErrorKind::UserCancelledError);
+        assert!(deleted.lock().unwrap().is_empty());
+        assert_eq!(prompts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn force_skips_the_confirmation() {
+        let deleted = Arc::new(Mutex::new(vec![]));
+        let prompts = Arc::new(Mutex::new(vec![]));
+
+        delete(
+            package(),
+            true,
+            false,
+            mock_confirm(false, Arc::clone(&prompts)),
+            mock_delete(Arc::clone(&deleted)),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(*deleted.lock().unwrap(), vec!["N:package:1"]);
+        assert!(prompts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn non_empty_collections_need_recursive() {
+        let deleted = Arc::new(Mutex::new(vec![]));
+        let prompts = Arc::new(Mutex::new(vec![]));
+
+        // not even with --force:
+        let e = delete(
+            collection(),
+            true,
+            false,
+            mock_confirm(true, Arc::clone(&prompts)),
+            mock_delete(Arc::clone(&deleted)),
+        )
+        .wait()
+        .unwrap_err();
+        assert_eq!(
+            e.kind(),
+            &agent::ErrorKind::CliError {
+                kind: ErrorKind::NotEmpty {
+                    kind: "collection".to_string(),
+                    name: "Subjects".to_string(),
+                    items: 2
+                }
+            }
+        );
+        assert!(deleted.lock().unwrap().is_empty());
+        assert!(prompts.lock().unwrap().is_empty());
+
+        delete(
+            collection(),
+            false,
+            true,
+            mock_confirm(true, Arc::clone(&prompts)),
+            mock_delete(Arc::clone(&deleted)),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(*deleted.lock().unwrap(), vec!["N:collection:1"]);
+        assert_eq!(
+            *prompts.lock().unwrap(),
+            vec!["Delete collection \"Subjects\" (N:collection:1) and the 2 items in it?"]
+        );
+    }
+}
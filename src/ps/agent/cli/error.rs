@@ -5,7 +5,7 @@ use std::{fmt, io, path::PathBuf, result};
 
 use tokio::timer;
 
-use crate::ps::agent::{self, config, database};
+use crate::ps::agent::{self, cli, config, database};
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -43,6 +43,106 @@ impl Error {
         }
         .into()
     }
+
+    pub fn invalid_template<S: Into<String>>(message: S) -> Error {
+        ErrorKind::InvalidTemplate {
+            message: message.into(),
+        }
+        .into()
+    }
+
+    pub fn invalid_description_file<S: Into<String>>(message: S) -> Error {
+        ErrorKind::InvalidDescriptionFile {
+            message: message.into(),
+        }
+        .into()
+    }
+
+    pub fn invalid_parallelism<S: Into<String>>(value: S) -> Error {
+        ErrorKind::InvalidParallelism {
+            value: value.into(),
+        }
+        .into()
+    }
+
+    pub fn invalid_export_format<S: Into<String>>(format: S) -> Error {
+        ErrorKind::InvalidExportFormat {
+            format: format.into(),
+        }
+        .into()
+    }
+
+    pub fn invalid_sort_key<S: Into<String>>(key: S) -> Error {
+        ErrorKind::InvalidSortKey { key: key.into() }.into()
+    }
+
+    pub fn invalid_package_type<S: Into<String>>(package_type: S) -> Error {
+        ErrorKind::InvalidPackageType {
+            package_type: package_type.into(),
+            expected: cli::PACKAGE_TYPES.join(", "),
+        }
+        .into()
+    }
+
+    pub fn invalid_column<S, T>(column: S, expected: T) -> Error
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        ErrorKind::InvalidColumn {
+            column: column.into(),
+            expected: expected.into(),
+        }
+        .into()
+    }
+
+    pub fn columns_not_supported<S: Into<String>>(command: S) -> Error {
+        ErrorKind::ColumnsNotSupported {
+            command: command.into(),
+        }
+        .into()
+    }
+
+    pub fn name_collision<S: Into<String>>(name: S) -> Error {
+        ErrorKind::NameCollision { name: name.into() }.into()
+    }
+
+    pub fn invalid_collection_path<S: Into<String>>(path: S) -> Error {
+        ErrorKind::InvalidCollectionPath { path: path.into() }.into()
+    }
+
+    pub fn not_a_collection<S, T>(name: S, id: T) -> Error
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        ErrorKind::NotACollection {
+            name: name.into(),
+            id: id.into(),
+        }
+        .into()
+    }
+
+    pub fn not_empty<S: Into<String>>(kind: &str, name: S, items: usize) -> Error {
+        ErrorKind::NotEmpty {
+            kind: kind.to_string(),
+            name: name.into(),
+            items,
+        }
+        .into()
+    }
+
+    pub fn move_into_descendant<S, T>(source: S, destination: T) -> Error
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        ErrorKind::MoveIntoDescendant {
+            source: source.into(),
+            destination: destination.into(),
+        }
+        .into()
+    }
 }
 
 impl Fail for Error {
@@ -95,6 +195,84 @@ pub enum ErrorKind {
 
     #[fail(display = "Move error: {}", message)]
     MoveError { message: String },
+
+    #[fail(
+        display = "Cannot move {} into {}, which is nested inside of it",
+        source, destination
+    )]
+    MoveIntoDescendant { source: String, destination: String },
+
+    #[fail(
+        display = "The {} {} holds {} items. Use --recursive to delete it along with them",
+        kind, name, items
+    )]
+    NotEmpty {
+        kind: String,
+        name: String,
+        items: usize,
+    },
+
+    #[fail(display = "Not a collection path: \"{}\"", path)]
+    InvalidCollectionPath { path: String },
+
+    #[fail(
+        display = "\"{}\" ({}) already exists and is not a collection",
+        name, id
+    )]
+    NotACollection { name: String, id: String },
+
+    #[fail(
+        display = "An item named \"{}\" already exists in the same place; use --force to rename anyway",
+        name
+    )]
+    NameCollision { name: String },
+
+    #[fail(display = "Invalid dataset template: {}", message)]
+    InvalidTemplate { message: String },
+
+    #[fail(display = "Invalid description file: {}", message)]
+    InvalidDescriptionFile { message: String },
+
+    #[fail(
+        display = "Nothing to update: give --name, --description, --description-file or --tags"
+    )]
+    NothingToUpdate,
+
+    #[fail(
+        display = "Invalid parallelism level: {}; it must be a whole number of at least 1",
+        value
+    )]
+    InvalidParallelism { value: String },
+
+    #[fail(
+        display = "Invalid export format: {}; expected \"csv\" or \"json\"",
+        format
+    )]
+    InvalidExportFormat { format: String },
+
+    #[fail(
+        display = "Invalid sort key: {}; expected \"name\", \"created\" or \"updated\"",
+        key
+    )]
+    InvalidSortKey { key: String },
+
+    #[fail(
+        display = "Invalid package type: {}; expected one of {}",
+        package_type, expected
+    )]
+    InvalidPackageType {
+        package_type: String,
+        expected: String,
+    },
+
+    #[fail(display = "Unknown column: {}; expected one of {}", column, expected)]
+    InvalidColumn { column: String, expected: String },
+
+    #[fail(
+        display = "--columns is not supported by {}; only by datasets, ls, members and the upload-status tables",
+        command
+    )]
+    ColumnsNotSupported { command: String },
 }
 
 impl From<ErrorKind> for Error {
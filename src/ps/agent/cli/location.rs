@@ -0,0 +1,210 @@
+//! Resolving the path from the root of a dataset down to a package, as
+//! printed by `where` and checked by `move`.
+
+use std::fmt::{self, Display};
+
+use futures::future;
+use futures::Future as _Future;
+use serde_derive::Serialize;
+
+use crate::ps::agent::cli::{Error, Result};
+use crate::ps::agent::Future;
+use crate::ps::util::futures::*;
+
+/// A dataset or package, along with the ID of the collection or dataset it
+/// is found in. Datasets have no parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathNode {
+    pub id: String,
+    pub name: String,
+    pub parent: Option<String>,
+}
+
+impl PathNode {
+    pub fn new<I, N>(id: I, name: N, parent: Option<String>) -> Self
+    where
+        I: Into<String>,
+        N: Into<String>,
+    {
+        Self {
+            id: id.into(),
+            name: name.into(),
+            parent,
+        }
+    }
+}
+
+/// One step of a `CliPath`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PathEntry {
+    pub id: String,
+    pub name: String,
+}
+
+/// The chain of ancestors from the root of a dataset down to a package,
+/// ending with the package itself. The path to a dataset is the dataset
+/// alone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CliPath(pub Vec<PathEntry>);
+
+impl CliPath {
+    /// The ID of the dataset or package the path leads to.
+    pub fn id(&self) -> &str {
+        self.0.last().map_or("", |entry| entry.id.as_str())
+    }
+
+    /// Tests if the package with the given ID is on the path.
+    pub fn contains(&self, id: &str) -> bool {
+        self.0.iter().any(|entry| entry.id == id)
+    }
+}
+
+impl Display for CliPath {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.0.iter().map(|entry| entry.name.as_str()).collect();
+        write!(fmt, "{}", names.join("/"))
+    }
+}
+
+/// Walks up from `target` to the root of its dataset, using `lookup` to
+/// resolve the parent of each node by its ID.
+///
+/// A parent that already appears further down the path is not looked up
+/// again, so a cyclic hierarchy cannot cause an endless walk.
+pub fn locate<F>(target: PathNode, lookup: F) -> Future<CliPath>
+where
+    F: 'static + Send + Clone + Fn(String) -> Future<PathNode>,
+{
+    walk(target, vec![], lookup)
+}
+
+/// Checks that `source` may be moved into the collection at the end of
+/// `destination`, which must not be `source` itself or any collection
+/// nested beneath it: `source` must not be on the path to it. Moving to
+/// the dataset root is always allowed.
+pub fn check_move(source: &str, destination: Option<&CliPath>) -> Result<()> {
+    match destination {
+        Some(destination) if destination.contains(source) => {
+            Err(Error::move_into_descendant(source, destination.id()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn walk<F>(node: PathNode, mut descendants: Vec<PathEntry>, lookup: F) -> Future<CliPath>
+where
+    F: 'static + Send + Clone + Fn(String) -> Future<PathNode>,
+{
+    descendants.push(PathEntry {
+        id: node.id,
+        name: node.name,
+    });
+
+    match node.parent {
+        Some(parent) if !descendants.iter().any(|entry| entry.id == parent) => lookup(parent)
+            .and_then(move |parent| walk(parent, descendants, lookup))
+            .into_trait(),
+        _ => {
+            descendants.reverse();
+            future::ok(CliPath(descendants)).into_trait()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use crate::ps::agent::cli::ErrorKind;
+
+    /// Returns a `lookup` function that serves nodes from a fixed map,
+    /// standing in for the Pennsieve API.
+    fn mock_api() -> impl Fn(String) -> Future<PathNode> + Clone + Send + 'static {
+        let nodes: HashMap<String, PathNode> = vec![
+            PathNode::new("N:dataset:1", "Samples", None),
+            PathNode::new(
+                "N:collection:1",
+                "Subjects",
+                Some("N:dataset:1".to_string()),
+            ),
+            PathNode::new(
+                "N:collection:2",
+                "subject-1",
+                Some("N:collection:1".to_string()),
+            ),
+        ]
+        .into_iter()
+        .map(|node| (node.id.clone(), node))
+        .collect();
+        let nodes = Arc::new(nodes);
+        move |id: String| future::ok(nodes[&id].clone()).into_trait()
+    }
+
+    #[test]
+    fn locates_a_nested_package() {
+        let package = PathNode::new(
+            "N:package:1",
+            "recording.edf",
+            Some("N:collection:2".to_string()),
+        );
+        let path = locate(package, mock_api()).wait().unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&path).unwrap(),
+            json!([
+                {"id": "N:dataset:1", "name": "Samples"},
+                {"id": "N:collection:1", "name": "Subjects"},
+                {"id": "N:collection:2", "name": "subject-1"},
+                {"id": "N:package:1", "name": "recording.edf"}
+            ])
+        );
+        assert_eq!(path.to_string(), "Samples/Subjects/subject-1/recording.edf");
+        assert_eq!(path.id(), "N:package:1");
+    }
+
+    #[test]
+    fn locates_a_dataset_root() {
+        let dataset = PathNode::new("N:dataset:1", "Samples", None);
+        let path = locate(dataset, mock_api()).wait().unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&path).unwrap(),
+            json!([{"id": "N:dataset:1", "name": "Samples"}])
+        );
+        assert_eq!(path.to_string(), "Samples");
+        assert_eq!(path.id(), "N:dataset:1");
+    }
+
+    #[test]
+    fn moving_into_a_descendant_is_refused() {
+        let lookup = mock_api();
+        for destination in &["N:collection:1", "N:collection:2"] {
+            let node = lookup(destination.to_string()).wait().unwrap();
+            let path = locate(node, lookup.clone()).wait().unwrap();
+            match check_move("N:collection:1", Some(&path)) {
+                Err(e) => assert_eq!(
+                    e.kind(),
+                    &ErrorKind::MoveIntoDescendant {
+                        source: "N:collection:1".to_string(),
+                        destination: destination.to_string(),
+                    }
+                ),
+                Ok(()) => panic!("moving into {} should be refused", destination),
+            }
+        }
+    }
+
+    #[test]
+    fn moving_elsewhere_is_allowed() {
+        let lookup = mock_api();
+        let subjects = lookup("N:collection:1".to_string()).wait().unwrap();
+        let path = locate(subjects, lookup).wait().unwrap();
+        assert!(check_move("N:collection:2", Some(&path)).is_ok());
+        assert!(check_move("N:collection:2", None).is_ok());
+    }
+}
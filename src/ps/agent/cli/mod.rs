@@ -1,12 +1,16 @@
+use std::borrow::Borrow;
 use std::cmp::max;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::result;
 
 use futures::Future as _Future;
 use futures::*;
-use sha2::{Digest, Sha256};
+
+use pennsieve_macros::try_future;
+use pennsieve_rust::model;
 
 use crate::ps;
 pub use crate::ps::agent::api::{
@@ -15,19 +19,51 @@ pub use crate::ps::agent::api::{
 pub use crate::ps::agent::cli::error::{Error, ErrorKind, Result};
 use crate::ps::agent::config::api::Settings as ApiSettings;
 use crate::ps::agent::config::{self, Config};
-use crate::ps::agent::database::{Database, Error as DBError, UserRecord, UserSettings};
-use crate::ps::agent::{self, Future, OutputFormat};
+use crate::ps::agent::database::{Database, UploadStatus, UserRecord, UserSettings};
+use crate::ps::agent::{self, cache, server, Future, OutputFormat};
 use crate::ps::util::futures::*;
+use crate::ps::util::strings as s;
 
+pub mod app;
+#[cfg(feature = "clipboard")]
+mod clipboard;
+mod delete;
 pub mod error;
 pub mod input;
+mod location;
 mod output;
+mod parallelism;
+mod permissions;
+mod query;
+mod rename;
+mod skipped;
+mod template;
+mod throughput;
+mod tree;
 mod types;
+mod update;
 pub mod upload;
+mod upload_history;
+mod upload_manifest;
 mod validate;
-
-pub use self::types::{cli_table as table, CliTable};
+mod verbosity;
+mod verify;
+mod watch;
+mod working_dataset;
+
+pub use self::app::build_app;
+pub use self::parallelism::{parallelism_level, upload_parallelism};
+pub use self::query::{
+    ListQuery, ListSort, MemberFilter, PackageTypeFilter, SortKey, PACKAGE_TYPES,
+};
+pub use self::types::{
+    cli_table as table, cli_table_with_columns as table_with_columns, CliTable, Columns,
+};
 pub use self::upload::{StartMode, StopMode, UploadWatcher};
+pub use self::upload_history::ExportFormat;
+pub use self::verbosity::Verbosity;
+pub use self::verify::{compute_hash, VerifySummary};
+pub use self::working_dataset::WorkingDataset;
 
 /// A `Cli` is a wrapper around an `Api` and `Database` that
 /// often calls api methods and maps the resulting `future`
@@ -36,6 +72,7 @@ pub struct Cli {
     api: Api,
     db: Database,
     output: OutputFormat,
+    columns: Columns,
     settings: ApiSettings,
 }
 
@@ -46,10 +83,18 @@ impl Cli {
             api: api.clone(),
             db: db.clone(),
             output,
+            columns: Columns::default(),
             settings: settings.clone(),
         }
     }
 
+    /// Shows only the given columns in the tables printed for datasets,
+    /// members and uploads.
+    pub fn with_columns(mut self, columns: Columns) -> Self {
+        self.columns = columns;
+        self
+    }
+
     /// Returns the current output format.
     pub fn output(&self) -> &OutputFormat {
         &self.output
@@ -73,6 +118,53 @@ impl Cli {
         .into_trait()
     }
 
+    /// Cross-checks the page records of the timeseries cache against the
+    /// page files on disk and prints what drifted apart. With `repair`, the
+    /// drift is corrected.
+    pub fn verify_cache(
+        db: Database,
+        config: &cache::Config,
+        repair: bool,
+    ) -> Future<cache::CacheReport> {
+        let config = config.clone();
+        future::lazy(move || {
+            let report = cache::verify(&config, &db, repair)?;
+            println!("{}", report);
+            Ok(report)
+        })
+        .into_trait()
+    }
+
+    /// Exports the cached window of `request` to a file per channel in
+    /// `dir`, printing the path of each file written.
+    pub fn export_cache(
+        db: Database,
+        config: &cache::Config,
+        request: cache::Request,
+        format: cache::ExportFormat,
+        dir: PathBuf,
+    ) -> Future<()> {
+        let config = config.clone();
+        future::lazy(move || {
+            for path in cache::export(&request, &config, &db, format, dir)? {
+                println!("{}", path.display());
+            }
+            Ok(())
+        })
+        .into_trait()
+    }
+
+    /// Prints every resolved configuration value, along with whether it was
+    /// read from the environment or the config file.
+    pub fn print_effective_config(values: Vec<config::EffectiveValue>) -> Future<()> {
+        future::lazy(move || {
+            let values = Into::<output::CliEffectiveConfig>::into(values);
+            println!("{}", values);
+            Ok(())
+        })
+        .into_trait()
+    }
+
     /// Prints the user's `config.ini` to stdout.
     ///
     /// If the config.ini cannot be found, the config wizard will be
@@ -107,7 +199,7 @@ impl Cli {
             .into_future()
             .and_then(move |config| {
                 let profile = config.api_settings.default_profile();
-                let api = api::Api::new(&db, &config, profile.environment);
+                let api = api::Api::new(&db, &config, &profile);
                 api.login(profile).map(|_| ()).into_trait()
             })
             .into_trait()
@@ -170,6 +262,20 @@ impl Cli {
             .into_trait()
     }
 
+    /// Runs every pending `agent.db` migration, regardless of
+    /// `DISABLE_MIGRATIONS`, printing the resulting schema version.
+    pub fn migrate_schema(&self) -> Future<()> {
+        self.db
+            .migrate()
+            .map_err(Into::into)
+            .into_future()
+            .and_then(|version| {
+                println!("{}", version);
+                Ok(())
+            })
+            .into_trait()
+    }
+
     /// Sets the `agent.db` schema version to the version provided,
     /// printing the set version on success.
     pub fn set_schema_version(&self, new_version: usize) -> Future<()> {
@@ -185,19 +291,65 @@ impl Cli {
             .into_trait()
     }
 
-    /// Print account details of the currently logged in user.
-    pub fn print_whoami(&self) -> Future<()> {
+    /// Caches every page of the requested window ahead of time, fetching
+    /// uncached pages from the streaming timeseries server configured in
+    /// `config`, and prints how many pages were fetched.
+    pub fn prefetch_cache(&self, config: &Config, request: cache::Request) -> Future<()> {
+        let cache_config = config.cache.clone();
+        let service = config
+            .get_services()
+            .iter()
+            .filter_map(|service| match service {
+                config::Service::TimeSeries(ts) => Some(ts.clone()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or_default();
+        let hostname = try_future!(service.remote_host.parse::<agent::HostName>());
+        let db = self.db.clone();
+
         self.api
             .get_user_and_refresh()
-            .and_then(|response| {
-                println!("{}", response);
-                Ok(())
+            .and_then(move |user| {
+                let package_id = request.package_id().clone();
+                cache::prefetch(&request, &cache_config, &db, move |pages| {
+                    server::ts::fetch_segments(
+                        hostname,
+                        service.remote_port,
+                        user.session_token,
+                        package_id,
+                        pages,
+                    )
+                })
+            })
+            .map(|prefetched| {
+                println!(
+                    "Fetched {} pages; {} pages were already cached",
+                    prefetched.fetched, prefetched.cached
+                );
             })
             .into_trait()
     }
 
+    /// Print account details of the currently logged in user.
+    ///
+    /// If `refresh` is true, the session token is renewed first.
+    pub fn print_whoami(&self, refresh: bool) -> Future<()> {
+        let user = if refresh {
+            self.api.get_user_and_force_refresh()
+        } else {
+            self.api.get_user_and_refresh()
+        };
+        user.and_then(|response| {
+            println!("{}", response);
+            Ok(())
+        })
+        .into_trait()
+    }
+
     /// Queues files for upload to the Pennsieve platform, printing status
-    /// upon success.
+    /// upon success. With `checksums`, the files are hashed as they are
+    /// queued, for `upload --manifest-output`.
     #[allow(clippy::too_many_arguments)]
     pub fn queue_uploads<F, D, P>(
         &self,
@@ -206,7 +358,15 @@ impl Cli {
         package_id_or_name: Option<P>,
         append: bool,
         force: bool,
+        force_reappend: bool,
         recursive: bool,
+        follow_symlinks: bool,
+        skip_empty: bool,
+        skip_existing: bool,
+        priority: i32,
+        tags: BTreeMap<String, String>,
+        chunk_size: Option<u64>,
+        checksums: bool,
     ) -> Future<()>
     where
         F: Into<String>,
@@ -220,9 +380,18 @@ impl Cli {
                 package_id_or_name,
                 append,
                 force,
+                force_reappend,
                 recursive,
+                follow_symlinks,
+                skip_empty,
+                skip_existing,
+                priority,
+                tags,
+                chunk_size,
+                checksums,
                 validate::Dataset::new(force),
                 validate::Folder::new(force),
+                skipped::PrintSkipped,
             )
             .and_then(|queued| {
                 let n = queued.len();
@@ -236,26 +405,277 @@ impl Cli {
             .into_trait()
     }
 
+    /// Reads data from stdin until it is closed and queues it for upload
+    /// as a single file called `name`, printing status upon success.
+    ///
+    /// stdin is consumed by the data, so there is no confirmation prompt.
+    #[allow(clippy::too_many_arguments)]
+    pub fn queue_stdin_upload<N, D, P>(
+        &self,
+        name: N,
+        dataset_id_or_name: Option<D>,
+        package_id_or_name: Option<P>,
+        priority: i32,
+        tags: BTreeMap<String, String>,
+        chunk_size: Option<u64>,
+        checksums: bool,
+    ) -> Future<()>
+    where
+        N: AsRef<str>,
+        D: Into<String>,
+        P: Into<String>,
+    {
+        let spool_dir = try_future!(agent::upload::stdin::spool_dir());
+        let stdin = io::stdin();
+        let path = try_future!(agent::upload::stdin::spool(
+            stdin.lock(),
+            &spool_dir,
+            name.as_ref()
+        ));
+        let path = path.to_string_lossy().to_string();
+        let spooled = path.clone();
+
+        self.queue_uploads(
+            vec![path],
+            dataset_id_or_name,
+            package_id_or_name,
+            false,
+            true,
+            false,
+            false,
+            false,
+            false,
+            false,
+            priority,
+            tags,
+            chunk_size,
+            checksums,
+        )
+        .map_err(move |e| {
+            agent::upload::stdin::remove_spooled(spooled);
+            e
+        })
+        .into_trait()
+    }
+
+    /// Resolves the files an upload would queue and prints how many there
+    /// are, their total size and where they would go, without queueing
+    /// anything or creating the dataset or folder.
+    pub fn dry_run_uploads<F, D, P>(
+        &self,
+        files: Vec<F>,
+        dataset_id_or_name: Option<D>,
+        package_id_or_name: Option<P>,
+        recursive: bool,
+        follow_symlinks: bool,
+        skip_empty: bool,
+    ) -> Future<()>
+    where
+        F: Into<String>,
+        D: Into<String>,
+        P: Into<String>,
+    {
+        let files: Vec<String> = files.into_iter().map(Into::into).collect();
+        let summary = try_future!(agent::upload::summarize_upload(
+            files,
+            recursive,
+            follow_symlinks,
+            skip_empty
+        ));
+        let folder: Option<String> = package_id_or_name.map(Into::into);
+        let dataset: String = match dataset_id_or_name.map(Into::into) {
+            Some(ref dataset) if !dataset.trim().is_empty() => dataset.clone(),
+            _ => return future::err(api::ErrorKind::MissingDatasetPackage.into()).into_trait(),
+        };
+
+        self.api
+            .get_dataset(dataset.clone())
+            .then(move |result| -> agent::Result<()> {
+                let dataset = match result {
+                    Ok(response) => {
+                        let found = response.take();
+                        format!("{} ({})", found.name(), found.id())
+                    }
+                    Err(ref e) if api::is_not_found(e) => {
+                        format!("{} (does not exist yet and would be created)", dataset)
+                    }
+                    Err(e) => return Err(e),
+                };
+                println!("Would upload {}", summary);
+                println!("  to dataset: {}", dataset);
+                if let Some(folder) = folder {
+                    println!("  in folder: {}", folder);
+                }
+                Ok(())
+            })
+            .into_trait()
+    }
+
+    /// Queues every file listed in an upload manifest, printing how many
+    /// files were queued and how many were skipped because they could not
+    /// be found.
+    #[allow(clippy::too_many_arguments)]
+    pub fn queue_manifest_uploads<M, D>(
+        &self,
+        manifest: M,
+        dataset_id_or_name: Option<D>,
+        force: bool,
+        skip_existing: bool,
+        priority: i32,
+        tags: BTreeMap<String, String>,
+        chunk_size: Option<u64>,
+        checksums: bool,
+    ) -> Future<()>
+    where
+        M: AsRef<Path>,
+        D: Into<String>,
+    {
+        let manifest = try_future!(agent::upload::Manifest::from_path(manifest));
+        for missing in manifest.missing() {
+            eprintln!("Skipping missing file: {:?}", missing);
+        }
+        let skipped = manifest.missing().len();
+        let groups = try_future!(manifest.group_by_folder());
+
+        let api = self.api.clone();
+        let dataset_id_or_name: Option<String> = dataset_id_or_name.map(Into::into);
+        stream::iter_ok::<_, agent::Error>(groups)
+            .fold(0, move |queued, (folder, files)| {
+                api.queue_uploads(
+                    files,
+                    dataset_id_or_name.clone(),
+                    folder,
+                    false,
+                    force,
+                    false,
+                    false,
+                    false,
+                    false,
+                    skip_existing,
+                    priority,
+                    tags.clone(),
+                    chunk_size,
+                    checksums,
+                    validate::Dataset::new(force),
+                    validate::Folder::new(force),
+                    skipped::PrintSkipped,
+                )
+                .map(move |uploads| queued + uploads.len())
+            })
+            .and_then(move |queued| {
+                println!(
+                    "\nQueued {queued} {thing}, skipped {skipped} missing\n",
+                    queued = queued,
+                    thing = if queued == 1 { "file" } else { "files" },
+                    skipped = skipped
+                );
+                Ok(())
+            })
+            .into_trait()
+    }
+
+    /// Queues the contents of each directory beneath `root` for upload to
+    /// the dataset it is named after, creating datasets that don't exist
+    /// yet. The contents of each directory are walked recursively, and
+    /// files directly beneath `root` are skipped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn queue_dataset_from_path_uploads<R>(
+        &self,
+        root: R,
+        force: bool,
+        follow_symlinks: bool,
+        skip_empty: bool,
+        skip_existing: bool,
+        priority: i32,
+        tags: BTreeMap<String, String>,
+        chunk_size: Option<u64>,
+        checksums: bool,
+    ) -> Future<()>
+    where
+        R: AsRef<Path>,
+    {
+        let (datasets, skipped) = try_future!(agent::upload::datasets_from_path(root));
+        for path in &skipped {
+            eprintln!("Skipping {:?}: it is not in a dataset directory", path);
+        }
+
+        let api = self.api.clone();
+        stream::iter_ok::<_, agent::Error>(datasets)
+            .fold(0, move |queued, (dataset, path)| {
+                // the trailing slash uploads the contents of the directory
+                // rather than a folder named after the dataset:
+                api.queue_uploads(
+                    vec![path.join("").to_string_lossy().to_string()],
+                    Some(dataset),
+                    None as Option<String>,
+                    false,
+                    force,
+                    false,
+                    true,
+                    follow_symlinks,
+                    skip_empty,
+                    skip_existing,
+                    priority,
+                    tags.clone(),
+                    chunk_size,
+                    checksums,
+                    validate::Dataset::new(force),
+                    validate::Folder::new(force),
+                    skipped::PrintSkipped,
+                )
+                .map(move |uploads| queued + uploads.len())
+            })
+            .and_then(|queued| {
+                println!(
+                    "\nQueued {queued} {thing}\n",
+                    queued = queued,
+                    thing = if queued == 1 { "file" } else { "files" }
+                );
+                Ok(())
+            })
+            .into_trait()
+    }
+
     /// Requeues the specified file uploads.
     pub fn requeue_failed_uploads(&self, upload_ids: Vec<String>) -> Future<()> {
         let db = self.db.clone();
         future::lazy(move || {
-            upload_ids
-                .into_iter()
-                .map(|id| {
-                    db.resume_failed_upload(&id).map(|success| {
-                        if !success {
-                            eprintln!(
-                                "Could not retry upload with id {}. \
-                                 Only failed uploads that were interrupted midway can be retried.",
-                                id
-                            )
-                        }
-                    })
-                })
-                .collect::<result::Result<Vec<_>, _>>()
-                .map_err(Into::into)
-                .map(|_| ())
+            let (_, skipped) = upload::requeue_failed(&db, upload_ids)?;
+            for id in skipped {
+                eprintln!(
+                    "Could not retry upload with id {}. \
+                     Only failed uploads that were interrupted midway can be retried.",
+                    id
+                )
+            }
+            Ok(())
+        })
+        .into_trait()
+    }
+
+    /// Requeues every failed upload that can be retried, reporting the
+    /// failed uploads that were skipped.
+    pub fn requeue_all_failed_uploads(&self) -> Future<()> {
+        let db = self.db.clone();
+        future::lazy(move || {
+            let (requeued, skipped) = upload::requeue_all_failed(&db)?;
+            if requeued.is_empty() && skipped.is_empty() {
+                println!("No failed uploads");
+                return Ok(());
+            }
+            println!(
+                "Retrying {n} failed {thing}",
+                n = requeued.len(),
+                thing = if requeued.len() == 1 { "upload" } else { "uploads" }
+            );
+            if !skipped.is_empty() {
+                eprintln!(
+                    "Skipped {n}, as only failed uploads that were interrupted midway can be retried: {ids}",
+                    n = skipped.len(),
+                    ids = skipped.join(", ")
+                );
+            }
+            Ok(())
         })
         .into_trait()
     }
@@ -264,10 +684,13 @@ impl Cli {
     pub fn cancel_uploads(&self, upload_ids: Vec<String>) -> Future<()> {
         let db = self.db.clone();
         future::lazy(move || {
-            let ids = upload_ids
-                .into_iter()
-                .map(|id| db.cancel_upload(&id).map(|success| (id, success)))
-                .collect::<result::Result<Vec<_>, _>>()?;
+            let ids = cancel_spooled(&db, || {
+                upload_ids
+                    .into_iter()
+                    .map(|id| db.cancel_upload(&id).map(|success| (id, success)))
+                    .collect::<result::Result<Vec<_>, _>>()
+                    .map_err(Into::into)
+            })?;
             ids.into_iter().for_each(|(id, success)| {
                 if success {
                     println!("Cancelled upload {}", id);
@@ -281,8 +704,7 @@ impl Cli {
     /// Cancels the specified file uploads.
     pub fn cancel_pending_uploads(&self) -> Future<()> {
         let db = self.db.clone();
-        db.cancel_queued_uploads()
-            .map_err(Into::into)
+        cancel_spooled(&db, || db.cancel_queued_uploads().map_err(Into::into))
             .and_then(|count| {
                 println!(
                     "Cancelled {count} {action}",
@@ -298,8 +720,7 @@ impl Cli {
     /// Cancels all file uploads, regardless of status.
     pub fn cancel_all_uploads(&self) -> Future<()> {
         let db = self.db.clone();
-        db.cancel_all_uploads()
-            .map_err(Into::into)
+        cancel_spooled(&db, || db.cancel_all_uploads().map_err(Into::into))
             .and_then(|count| {
                 println!(
                     "Cancelled {count} {action}",
@@ -312,98 +733,175 @@ impl Cli {
             .into_trait()
     }
 
+    /// Cancels the queued and in-progress uploads to a dataset, given by
+    /// its ID or name.
+    pub fn cancel_dataset_uploads<P>(&self, id_or_name: P) -> Future<()>
+    where
+        P: Into<String>,
+    {
+        let db = self.db.clone();
+        self.api
+            .get_dataset(id_or_name)
+            .and_then(move |dataset| -> agent::Result<()> {
+                let dataset = dataset.take();
+                let count = cancel_spooled(&db, || {
+                    db.cancel_uploads_for_dataset(dataset.id().as_ref())
+                        .map_err(Into::into)
+                })?;
+                println!(
+                    "Cancelled {count} {action} to \"{name}\"",
+                    count = count,
+                    action = if count == 1 { "upload" } else { "uploads" },
+                    name = dataset.name()
+                );
+                Ok(())
+            })
+            .into_trait()
+    }
+
+    /// Cancels the queued and in-progress uploads of every file queued
+    /// under an import ID.
+    pub fn cancel_import_uploads<S>(&self, import_id: S) -> Future<()>
+    where
+        S: Into<String>,
+    {
+        let import_id = import_id.into();
+        let db = self.db.clone();
+        cancel_spooled(&db, || {
+            db.cancel_uploads_by_import_id(&import_id)
+                .map_err(Into::into)
+        })
+        .and_then(|count| {
+            println!(
+                "Cancelled {count} {action} of import {import_id}",
+                count = count,
+                action = if count == 1 { "upload" } else { "uploads" },
+                import_id = import_id
+            );
+            Ok(())
+        })
+        .into_future()
+        .into_trait()
+    }
+
     /// Prints the details of active uploads (queued and in-progress).
     pub fn active_uploads(&self) -> Future<()> {
         let db = self.db.clone();
+        let columns = self.columns.clone();
         future::lazy(move || {
             let uploads = db.get_active_uploads()?;
             if uploads.is_package_completed() {
                 println!("No uploads");
             } else {
-                println!("{}\n", Into::<output::CliUploadRecords>::into(uploads));
+                println!(
+                    "{}\n",
+                    Into::<output::CliUploadRecords>::into(uploads).table(&columns)?
+                );
             }
             Ok(())
         })
         .into_trait()
     }
 
-    /// Prints the details of the NUM most recent uploads.
-    pub fn most_recently_completed_uploads(&self, num: usize) -> Future<()> {
+    /// Redraws the details of active uploads every `interval`, clearing the
+    /// screen in between, until none are left.
+    pub fn watch_active_uploads(&self, interval: std::time::Duration) -> Future<()> {
+        watch::watch_active_uploads(self.db.clone(), self.columns.clone(), interval)
+    }
+
+    /// Prints the details of the most recently completed uploads, at most
+    /// `num` of them, optionally only those last updated between `since`
+    /// and `until`.
+    pub fn completed_uploads(
+        &self,
+        num: Option<usize>,
+        since: Option<time::Timespec>,
+        until: Option<time::Timespec>,
+    ) -> Future<()> {
         let db = self.db.clone();
+        let output = self.output;
+        let columns = self.columns.clone();
         future::lazy(move || {
-            let uploads = db.get_completed_uploads(num)?;
-            if uploads.is_empty() {
-                println!("No completed uploads");
+            println!(
+                "{}",
+                upload_history::completed_uploads(&db, num, since, until, output, &columns)?
+            );
+            Ok(())
+        })
+        .into_trait()
+    }
+
+    /// Prints every stored field of a single upload, including the
+    /// multipart upload ID and chunk size used by the Upload Service.
+    pub fn upload_detail(&self, upload_id: usize) -> Future<()> {
+        let db = self.db.clone();
+        let output = self.output;
+        future::lazy(move || {
+            println!("{}", upload_history::upload_detail(&db, upload_id, output)?);
+            Ok(())
+        })
+        .into_trait()
+    }
+
+    /// Writes every upload record, or only those with the given status, to
+    /// stdout as CSV or JSON.
+    pub fn export_uploads(&self, format: ExportFormat, status: Option<UploadStatus>) -> Future<()> {
+        let db = self.db.clone();
+        future::lazy(move || {
+            let stdout = io::stdout();
+            upload_history::export_uploads(&db, status, format, stdout.lock())?;
+            Ok(())
+        })
+        .into_trait()
+    }
+
+    /// Prints how many uploads have each status, how many bytes are left
+    /// to upload and how long the oldest queued upload has been waiting.
+    pub fn upload_summary(&self) -> Future<()> {
+        let db = self.db.clone();
+        let output = self.output;
+        future::lazy(move || {
+            let summary = upload_history::UploadSummary::from_db(&db, time::now().to_timespec())?;
+            if output.is_json() {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
             } else {
-                println!("{}\n", Into::<output::CliUploadRecords>::into(uploads));
+                println!("{}", summary);
             }
             Ok(())
         })
         .into_trait()
     }
 
-    /// Prints the details of failed uploads
+    /// Prints the details of failed uploads, along with why they failed
     pub fn failed_uploads(&self) -> Future<()> {
         let db = self.db.clone();
+        let columns = self.columns.clone();
         future::lazy(move || {
             let uploads = db.get_failed_uploads()?;
             if uploads.is_empty() {
                 println!("No uploads");
             } else {
-                println!("{}\n", Into::<output::CliUploadRecords>::into(uploads));
+                println!(
+                    "{}\n",
+                    Into::<output::CliFailedUploads>::into(uploads).table(&columns)?
+                );
             }
             Ok(())
         })
         .into_trait()
     }
 
-    fn compute_multichunk_hash(mut file: File, chunk_size: u64) -> Result<String> {
-        let mut chunk_hashes: Vec<String> = vec![];
-        let mut total_bytes_read: u64 = 0;
-        let mut buffer = vec![0; chunk_size as usize];
-
-        // Multi-chunk case:
-
-        loop {
-            let mut hasher = Sha256::new();
-
-            file.seek(SeekFrom::Start(total_bytes_read))?;
-            let bytes_read = file.read(&mut buffer)?;
-            total_bytes_read += bytes_read as u64;
-
-            if bytes_read > 0 {
-                hasher.update(&buffer[..bytes_read]);
-                chunk_hashes.push(format!("{:x}", hasher.finalize()));
-            } else {
-                break;
-            }
-        }
-
-        Ok(format!(
-            "{:x}",
-            chunk_hashes
-                .into_iter()
-                .fold(Sha256::new(), |mut acc, hash| {
-                    acc.update(hash);
-                    acc
-                })
-                .finalize()
-        ))
-    }
-
-    fn compute_simple_hash(mut file: File, file_size: u64) -> Result<String> {
-        let mut buffer = vec![0; file_size as usize];
-        let mut hasher = Sha256::new();
-
-        file.seek(SeekFrom::Start(0))?;
-        let bytes_read: usize = file.read(&mut buffer)?;
-
-        hasher.update(&buffer[..bytes_read]);
-        Ok(format!("{:x}", hasher.finalize()))
-    }
-
     /// Verify the specified file upload.
-    pub fn verify_upload(&self, upload_id: usize, file_path: Option<PathBuf>) -> Future<()> {
+    ///
+    /// If the original file is gone and `search_dir` is given, a file
+    /// with the same name and contents is looked for beneath it, and the
+    /// file that matched is reported.
+    pub fn verify_upload(
+        &self,
+        upload_id: usize,
+        file_path: Option<PathBuf>,
+        search_dir: Option<PathBuf>,
+    ) -> Future<()> {
         let db = self.db.clone();
         self.api
             .get_upload_file_hash(upload_id)
@@ -411,65 +909,127 @@ impl Cli {
                 // if get_upload_file_hash succeeded, then this upload
                 // must exist in the database
                 let upload = db.get_upload_by_upload_id(upload_id).unwrap();
+                let verified = verify::verify_file(
+                    &upload,
+                    &hash.hash,
+                    file_path,
+                    search_dir.as_ref().map(PathBuf::as_path),
+                )?;
+                if search_dir.is_some() && verified != PathBuf::from(&upload.file_path) {
+                    println!(
+                        "{:?} was moved: matched {:?} instead",
+                        upload.file_path, verified
+                    );
+                }
+                Ok(())
+            })
+            .into_trait()
+    }
 
-                let verify_against = if let Some(file_path) = file_path {
-                    file_path
-                } else {
-                    PathBuf::from(upload.file_path.clone())
-                };
+    /// Verifies every completed upload, or only the uploads to the given
+    /// dataset, checking up to `parallelism` uploads at once. Prints a
+    /// summary listing the files that failed verification.
+    pub fn verify_uploads(
+        &self,
+        dataset_id: Option<String>,
+        parallelism: usize,
+    ) -> Future<verify::VerifySummary> {
+        let mut uploads = vec![];
+        try_future!(self
+            .db
+            .get_all_uploads(Some(UploadStatus::Completed), |upload| {
+                if dataset_id.iter().all(|id| *id == upload.dataset_id) {
+                    uploads.push(upload);
+                }
+                Ok::<_, agent::Error>(())
+            }));
 
-                File::open(verify_against.clone())
-                    .map_err(Into::into)
-                    .and_then(|file| {
-                        upload
-                            .chunk_size
-                            .ok_or_else(|| {
-                                Into::<agent::Error>::into(DBError::upload_without_chunk_size(
-                                    upload_id,
-                                ))
-                            })
-                            .map(|chunk_size| (file, chunk_size))
-                    })
-                    .and_then(|(file, chunk_size)| {
-                        let file_size: u64 = file.metadata()?.len();
-                        let computed_hash: String = if file_size > chunk_size {
-                            Cli::compute_multichunk_hash(file, chunk_size)?
-                        } else {
-                            Cli::compute_simple_hash(file, chunk_size)?
-                        };
+        let api = self.api.clone();
+        verify::verify_uploads(uploads, parallelism, move |upload_id| {
+            api.get_upload_file_hash(upload_id)
+                .map(|hash| hash.hash)
+                .into_trait()
+        })
+        .map(|summary| {
+            println!("{}", summary);
+            summary
+        })
+        .into_trait()
+    }
 
-                        if computed_hash != hash.hash {
-                            Err(Error::upload_does_not_match(verify_against).into())
-                        } else {
-                            Ok(())
-                        }
-                    })
-                    .into_future()
-                    .into_trait()
+    /// Prints all organizations the current user is a member of, marking
+    /// the active one. With `current`, only the active organization is
+    /// printed.
+    pub fn print_organizations(&self, current: bool) -> Future<()> {
+        let output = self.output;
+        self.api
+            .get_organization_scopes()
+            .and_then(move |(user, scopes)| -> agent::Result<()> {
+                let organizations = output::CliOrganizations::new(scopes, &user.organization_id);
+                if current {
+                    let organization = organizations
+                        .current()
+                        .cloned()
+                        .unwrap_or_else(|| output::CliOrganization::from(&user));
+                    if output.is_json() {
+                        println!("{}", organization.to_json()?);
+                    } else {
+                        println!("{}", organization);
+                    }
+                } else if output.is_json() {
+                    println!("{}", organizations.to_json()?);
+                } else {
+                    println!("{}", organizations);
+                }
+                Ok(())
             })
             .into_trait()
     }
 
-    /// Prints all organizations the current user is a member of.
-    pub fn print_organizations(&self) -> Future<()> {
+    /// Switches the user's preferred organization.
+    pub fn use_organization<S: Into<String>>(&self, id_or_name: S) -> Future<()> {
         self.api
-            .get_organizations()
-            .and_then(|response| {
-                println!("{}", Into::<output::CliOrganizations>::into(response));
+            .switch_organization(id_or_name)
+            .and_then(|user| {
+                println!(
+                    "Switched to organization {} ({})",
+                    user.organization_name, user.organization_id
+                );
                 Ok(())
             })
             .into_trait()
     }
 
-    /// Print all members that are part of the current organization.
-    pub fn print_members(&self) -> Future<()> {
+    /// Print all members that are part of the current organization,
+    /// narrowed down by `filter`.
+    ///
+    /// The Pennsieve API returns every member in a single response, so the
+    /// filter is applied client-side.
+    pub fn print_members(&self, filter: MemberFilter) -> Future<()> {
+        let output = self.output;
+        let columns = self.columns.clone();
         self.api
             .get_members()
-            .and_then(|members| {
-                println!(
-                    "{}",
-                    Into::<output::CliUsers>::into(members).table_without_roles()
-                );
+            .map(move |members| -> output::CliUsers {
+                filter
+                    .apply(
+                        members,
+                        |member| {
+                            vec![
+                                format!("{} {}", member.first_name(), member.last_name()),
+                                member.email().to_string(),
+                            ]
+                        },
+                        |member| member.role().cloned(),
+                    )
+                    .into()
+            })
+            .and_then(move |members| -> agent::Result<()> {
+                if output.is_json() {
+                    println!("{}", members.to_json()?);
+                } else {
+                    println!("{}", members.table_without_roles(&columns)?);
+                }
                 Ok(())
             })
             .into_trait()
@@ -488,72 +1048,252 @@ impl Cli {
     }
 
     /// Prints all datasets the current user has access to.
-    pub fn print_datasets(&self) -> Future<()> {
+    ///
+    /// The datasets are sorted by name, then narrowed down by `query`.
+    pub fn print_datasets(&self, query: ListQuery, sort: ListSort, id_only: bool) -> Future<()> {
+        let output = self.output;
+        let columns = self.columns.clone();
         self.api
             .get_datasets()
-            .map(|response| -> Vec<output::CliDataset> {
-                response
+            .map(move |response| -> output::CliDatasets {
+                let datasets = response
                     .into_iter()
                     .map(Into::<output::CliDataset>::into)
-                    .collect()
+                    .collect::<Vec<_>>();
+                // sort before paginating, so pages are stable:
+                let datasets = sort.apply(datasets, |dataset, key| match key {
+                    SortKey::Name => Some(dataset.name().to_string()),
+                    SortKey::Created => dataset.created_at(),
+                    SortKey::Updated => dataset.updated_at(),
+                });
+                query
+                    .apply(datasets, |dataset: &output::CliDataset| {
+                        vec![dataset.id().to_string(), dataset.name().to_string()]
+                    })
+                    .into()
             })
-            .and_then(|response| {
-                println!("{}", Into::<output::CliDatasets>::into(response));
+            .and_then(move |datasets| -> agent::Result<()> {
+                if id_only {
+                    print_ids(datasets.ids());
+                } else if output.is_json() {
+                    println!("{}", datasets.to_json()?);
+                } else {
+                    println!("{}", datasets.table(&columns)?);
+                }
                 Ok(())
             })
             .into_trait()
     }
 
     /// Create a new dataset.
-    pub fn create_dataset<P, Q>(&self, name: P, description: Option<Q>) -> Future<()>
+    ///
+    /// The description is given inline, or read from the file at
+    /// `description_path`. If the path to a template is given, its
+    /// description is used unless one is given explicitly, and its tags and
+    /// license are applied once the dataset has been created.
+    ///
+    /// With `id_only`, only the ID of the new dataset is printed.
+    pub fn create_dataset<P, Q>(
+        &self,
+        name: P,
+        description: Option<Q>,
+        description_path: Option<&str>,
+        template_path: Option<&str>,
+        id_only: bool,
+    ) -> Future<()>
     where
         P: Into<String>,
         Q: Into<String>,
     {
         let name = name.into();
-        let description = description.map(Into::into);
+        let description = match description_path {
+            Some(path) => Some(try_future!(template::read_description(path))),
+            None => description.map(Into::into),
+        };
+        let template = match template_path {
+            Some(path) => try_future!(template::DatasetTemplate::from_path(path)),
+            None => Default::default(),
+        };
+        let template = template.with_overrides(name.clone(), description);
+        let api = self.api.clone();
+        let output = self.output;
         self.api
-            .create_dataset(name.clone(), description)
-            .and_then(move |dataset| {
-                println!(
-                    "Created dataset {name} ({id})",
-                    name = name,
-                    id = dataset.take().id()
-                );
-                Ok(())
+            .create_dataset(name.clone(), template.description.clone())
+            .and_then(move |dataset| -> Future<()> {
+                let id = dataset.take().id().clone();
+                let created = output::CliCreated::new("dataset", id.to_string(), name);
+                println!("{}", try_future!(created.render(output, id_only)));
+                if !template.has_metadata() {
+                    return future::ok(()).into_trait();
+                }
+                let quiet = id_only || output.is_json();
+                api.update_dataset_metadata(id, template.tags, template.license)
+                    .map(move |_| {
+                        if !quiet {
+                            println!("Applied the template's tags and license")
+                        }
+                    })
+                    .into_trait()
             })
             .into_trait()
     }
 
-    /// Delete a dataset by its ID.
-    pub fn delete_dataset<P>(&self, id: P) -> Future<()>
+    /// Updates the name, description or tags of the dataset with the given
+    /// ID or name. Only the fields that are given are changed, and at least
+    /// one must be.
+    ///
+    /// The description is given inline, or read from the file at
+    /// `description_path`.
+    pub fn update_dataset<P>(
+        &self,
+        id_or_name: P,
+        name: Option<String>,
+        description: Option<String>,
+        description_path: Option<&str>,
+        tags: Option<Vec<String>>,
+    ) -> Future<()>
     where
         P: Into<String>,
     {
-        let id = id.into();
+        let description = match description_path {
+            Some(path) => Some(try_future!(template::read_description(path))),
+            None => description,
+        };
+        let update = try_future!(update::DatasetUpdate::new(name, description, tags));
+        let api = self.api.clone();
+        let output = self.output;
         self.api
-            .delete_dataset(id.clone())
-            .and_then(move |_| {
-                println!("Deleted {id}", id = id);
-                Ok(())
+            .get_dataset(id_or_name)
+            .and_then(move |response| -> Future<()> {
+                let dataset = response.take();
+                let id = dataset.id().clone();
+                let name = update
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| dataset.name().to_string());
+
+                // The name is always sent along with the description, so
+                // the current one is kept unless a new one is given:
+                let details = if update.changes_details() {
+                    api.update_dataset(id.clone(), name.clone(), update.description.clone())
+                        .map(|_| ())
+                        .into_trait()
+                } else {
+                    future::ok(()).into_trait()
+                };
+                let tags = match update.tags.clone() {
+                    Some(tags) => api
+                        .update_dataset_metadata(id.clone(), tags, None)
+                        .map(|_| ())
+                        .into_trait(),
+                    None => future::ok(()).into_trait(),
+                };
+
+                details
+                    .and_then(move |_| tags)
+                    .and_then(move |_| -> agent::Result<()> {
+                        let updated =
+                            output::CliUpdated::new("dataset", id.to_string(), name, update);
+                        println!("{}", updated.render(output)?);
+                        Ok(())
+                    })
+                    .into_trait()
             })
             .into_trait()
     }
 
-    /// Prints a specific dataset.
-    pub fn print_dataset<P>(&self, id_or_name: P) -> Future<()>
+    /// Deletes the dataset with the given ID or name, after asking for
+    /// confirmation unless `force` is given. A dataset that isn't empty is
+    /// only deleted with `recursive`.
+    pub fn delete_dataset<P>(&self, id_or_name: P, force: bool, recursive: bool) -> Future<()>
     where
         P: Into<String>,
     {
+        let api = self.api.clone();
         self.api
             .get_dataset(id_or_name)
-            .and_then(|response| {
-                println!("{}", Into::<output::CliDataset>::into(response));
+            .and_then(move |response| {
+                delete::delete(
+                    response.into(),
+                    force,
+                    recursive,
+                    input::confirm::<String>,
+                    move |id| api.delete_dataset(id),
+                )
+            })
+            .map(|deleted| println!("Deleted {} ({})", deleted.name, deleted.id))
+            .into_trait()
+    }
+
+    /// Deletes the package or collection with the given ID, after asking
+    /// for confirmation unless `force` is given. A collection that isn't
+    /// empty is only deleted with `recursive`.
+    pub fn delete_package<P>(&self, id: P, force: bool, recursive: bool) -> Future<()>
+    where
+        P: Into<model::PackageId>,
+    {
+        let api = self.api.clone();
+        self.api
+            .get_collection(id)
+            .and_then(move |response| {
+                delete::delete(
+                    response.into(),
+                    force,
+                    recursive,
+                    input::confirm::<String>,
+                    move |id| api.delete_package(id),
+                )
+            })
+            .map(|deleted| println!("Deleted {} ({})", deleted.name, deleted.id))
+            .into_trait()
+    }
+
+    /// Prints a specific dataset, listing only the packages at its top
+    /// level that pass `types`.
+    pub fn print_dataset<P>(
+        &self,
+        id_or_name: P,
+        types: PackageTypeFilter,
+        id_only: bool,
+    ) -> Future<()>
+    where
+        P: Into<String>,
+    {
+        let output = self.output;
+        self.api
+            .get_dataset(id_or_name)
+            .and_then(move |response| -> agent::Result<()> {
+                if output.is_json() && !id_only {
+                    let listing =
+                        Into::<output::CliPackageTree>::into(response).filter_types(&types);
+                    println!("{}", serde_json::to_string_pretty(&listing)?);
+                    return Ok(());
+                }
+                let dataset = Into::<output::CliDataset>::into(response).filter_types(&types);
+                if id_only {
+                    print_ids(dataset.child_ids());
+                } else {
+                    println!("{}", dataset);
+                }
                 Ok(())
             })
             .into_trait()
     }
 
+    /// Prints a dataset along with every collection and package nested
+    /// beneath it, down to an optional maximum depth.
+    pub fn print_dataset_tree<P>(&self, id_or_name: P, max_depth: Option<usize>) -> Future<()>
+    where
+        P: Into<String>,
+    {
+        let api = self.api.clone();
+        let output = self.output;
+        self.api
+            .get_dataset(id_or_name)
+            .and_then(move |response| Self::print_tree(api, response.into(), max_depth, output))
+            .into_trait()
+    }
+
     /// Print the user collaborators for a dataset.
     pub fn print_dataset_user_collaborators<P: Into<String>>(&self, id_or_name: P) -> Future<()> {
         let api = self.api.clone();
@@ -627,14 +1367,106 @@ impl Cli {
             .into_trait()
     }
 
+    /// Print the role a user effectively has on a dataset: the highest of
+    /// the roles granted to them directly, to the teams they are a member
+    /// of, and to their organization.
+    pub fn print_effective_dataset_permission<P, U>(&self, id_or_name: P, user_id: U) -> Future<()>
+    where
+        P: Into<String>,
+        U: Into<String>,
+    {
+        use self::permissions::{EffectivePermission, Grant, Source};
+
+        let api = self.api.clone();
+        let output = self.output;
+        let user_id = user_id.into();
+        let is_user = {
+            let user_id = user_id.clone();
+            move |user: &model::User| Borrow::<str>::borrow(user.id()) == user_id.as_str()
+        };
+
+        self.api
+            .get_dataset(id_or_name)
+            .map(|ds| ds.take().id().clone())
+            .and_then(move |dataset_id| {
+                api.get_dataset_user_collaborators(dataset_id.clone())
+                    .map(|users| (api, dataset_id, users))
+            })
+            .and_then(move |(api, dataset_id, users)| {
+                let mut grants: Vec<Grant> = users
+                    .iter()
+                    .filter(|user| is_user(*user))
+                    .map(|user| {
+                        let name = format!("{} {}", user.first_name(), user.last_name());
+                        Grant::new(Source::User, name, user.role().map(String::as_str))
+                    })
+                    .collect();
+                api.get_dataset_team_collaborators(dataset_id.clone())
+                    .and_then({
+                        let api = api.clone();
+                        move |teams| {
+                            future::join_all(teams.into_iter().map(move |team| {
+                                let team_id: String = team.id().clone().into();
+                                api.get_team_members(team_id).map(|members| (team, members))
+                            }))
+                        }
+                    })
+                    .map(move |teams| {
+                        grants.extend(
+                            teams
+                                .into_iter()
+                                .filter(|(_, members)| members.iter().any(&is_user))
+                                .map(|(team, _)| {
+                                    Grant::new(
+                                        Source::Team,
+                                        team.name().clone(),
+                                        team.role().map(String::as_str),
+                                    )
+                                }),
+                        );
+                        (api, dataset_id, grants, is_user)
+                    })
+            })
+            .and_then(|(api, dataset_id, grants, is_user)| {
+                let members = api.get_members();
+                api.get_dataset_organization_role(dataset_id)
+                    .join(members)
+                    .map(move |(organization, members)| {
+                        let mut grants = grants;
+                        // The organization's role only applies to its members:
+                        if members.iter().any(&is_user) {
+                            grants.push(Grant::new(
+                                Source::Organization,
+                                organization.name().clone(),
+                                organization.role().map(String::as_str),
+                            ));
+                        }
+                        grants
+                    })
+            })
+            .and_then(move |grants| -> agent::Result<()> {
+                let permission = EffectivePermission::new(user_id, grants);
+                if output.is_json() {
+                    println!("{}", serde_json::to_string_pretty(&permission)?);
+                } else {
+                    println!("{}", permission);
+                }
+                Ok(())
+            })
+            .into_trait()
+    }
+
     /// Creates a new, empty collection.
-    pub fn create_collection<P, Q>(&self, name: P, destination: Q) -> Future<()>
+    ///
+    /// With `id_only`, only the ID of the new collection is printed.
+    pub fn create_collection<P, Q>(&self, name: P, destination: Q, id_only: bool) -> Future<()>
     where
         P: Into<String>,
         Q: Into<String>,
     {
         let name = name.into();
         let api = self.api.clone();
+        let output = self.output;
         let dataset_id_or_name = destination.into();
         self.api
             .get_dataset(dataset_id_or_name.clone())
@@ -642,27 +1474,132 @@ impl Cli {
                 api.create_collection(name.clone(), dataset.take().id().clone())
                     .into_trait()
             })
-            .and_then(move |package| {
+            .and_then(move |package| -> agent::Result<()> {
                 let package = package.take();
-                println!(
-                    "Created collection {name} ({id})",
-                    name = package.name(),
-                    id = package.id()
+                let created = output::CliCreated::new(
+                    "collection",
+                    package.id().to_string(),
+                    package.name().to_string(),
                 );
+                println!("{}", created.render(output, id_only)?);
                 Ok(())
             })
             .into_trait()
     }
 
-    /// Prints the collection associated with the provided collection ID.
-    pub fn print_collection<P>(&self, id: P) -> Future<()>
+    /// Creates the nested collections named by the slash-separated `path`
+    /// beneath the root of a dataset, skipping those that already exist,
+    /// and prints the ID of the innermost one.
+    ///
+    /// With `id_only`, only that ID is printed.
+    pub fn create_collection_path<P, Q>(&self, path: P, destination: Q, id_only: bool) -> Future<()>
+    where
+        P: Into<String>,
+        Q: Into<String>,
+    {
+        let path = path.into();
+        let components = try_future!(tree::collection_path(&path));
+        let api = self.api.clone();
+        let output = self.output;
+        let fetch = Self::fetch_children(self.api.clone());
+        self.api
+            .get_dataset(destination.into())
+            .and_then(move |dataset| {
+                let dataset_id = dataset.id().clone();
+                let create = move |name: String, parent: Option<String>| {
+                    api.create_collection_in(name, dataset_id.clone(), parent)
+                        .map(|package| -> output::CliPackageTree { package.into() })
+                        .into_trait()
+                };
+                tree::create_path(dataset.into(), components, fetch, create)
+            })
+            .and_then(move |(leaf, created)| -> agent::Result<()> {
+                if id_only || output.is_json() {
+                    let leaf = output::CliCreated::new("collection", leaf.id, path);
+                    println!("{}", leaf.render(output, id_only)?);
+                } else if created == 0 {
+                    println!("Collection {} already exists ({})", path, leaf.id);
+                } else {
+                    println!(
+                        "Created collection {} ({}), {} new collection(s)",
+                        path, leaf.id, created
+                    );
+                }
+                Ok(())
+            })
+            .into_trait()
+    }
+
+    /// Prints the collection associated with the provided collection ID,
+    /// listing only the packages in it that pass `types`.
+    pub fn print_collection<P>(&self, id: P, types: PackageTypeFilter, id_only: bool) -> Future<()>
     where
         P: Into<PackageId>,
     {
+        let output = self.output;
         self.api
             .get_collection(id)
-            .and_then(|response| {
-                println!("{}", Into::<output::CliCollection>::into(response));
+            .and_then(move |response| -> agent::Result<()> {
+                if output.is_json() && !id_only {
+                    let listing =
+                        Into::<output::CliPackageTree>::into(response).filter_types(&types);
+                    println!("{}", serde_json::to_string_pretty(&listing)?);
+                    return Ok(());
+                }
+                let collection = Into::<output::CliCollection>::into(response).filter_types(&types);
+                if id_only {
+                    print_ids(collection.child_ids());
+                } else {
+                    println!("{}", collection);
+                }
+                Ok(())
+            })
+            .into_trait()
+    }
+
+    /// Prints a collection along with every collection and package nested
+    /// beneath it, down to an optional maximum depth.
+    pub fn print_collection_tree<P>(&self, id: P, max_depth: Option<usize>) -> Future<()>
+    where
+        P: Into<PackageId>,
+    {
+        let api = self.api.clone();
+        let output = self.output;
+        self.api
+            .get_collection(id)
+            .and_then(move |response| Self::print_tree(api, response.into(), max_depth, output))
+            .into_trait()
+    }
+
+    /// Returns a function that looks up the children of a collection, for
+    /// use with `tree::expand`.
+    fn fetch_children(
+        api: Api,
+    ) -> impl Fn(String) -> Future<Vec<output::CliPackageTree>> + Clone + Send + 'static {
+        move |id: String| {
+            api.get_collection(id)
+                .map(|response| -> Vec<output::CliPackageTree> {
+                    Into::<output::CliPackageTree>::into(response).children
+                })
+                .into_trait()
+        }
+    }
+
+    /// Walks the collections beneath `root` and prints the resulting tree,
+    /// either indented or as nested JSON.
+    fn print_tree(
+        api: Api,
+        root: output::CliPackageTree,
+        max_depth: Option<usize>,
+        output: OutputFormat,
+    ) -> Future<()> {
+        tree::expand(root, max_depth, Self::fetch_children(api))
+            .and_then(move |tree| -> agent::Result<()> {
+                if output.is_json() {
+                    println!("{}", serde_json::to_string_pretty(&tree)?);
+                } else {
+                    print!("{}", tree);
+                }
                 Ok(())
             })
             .into_trait()
@@ -686,48 +1623,173 @@ impl Cli {
     }
 
     /// Given an object ID, try to resolve it as a dataset or failing that,
-    /// a collection.
-    pub fn where_<P>(&self, id: P) -> Future<()>
+    /// a package, and print the path to it from the root of its dataset.
+    /// With `id_only`, only the ID of the dataset or package is printed;
+    /// `copy` also copies it to the clipboard.
+    pub fn where_<P>(&self, id: P, id_only: bool, copy: bool) -> Future<()>
     where
         P: Into<String>,
     {
         let id = id.into();
-        let print_dataset = self.print_dataset(id.clone());
-        let print_collection = self.print_collection(id);
-        print_dataset
-            .or_else(move |_| print_collection)
+        let output = self.output;
+        let lookup = Self::lookup_path_node(self.api.clone());
+        let package = lookup(id.clone());
+        self.api
+            .get_dataset(id)
+            .map(|response| {
+                let dataset = response.take();
+                location::PathNode::new(dataset.id().clone(), dataset.name().clone(), None)
+            })
+            .or_else(move |_| package)
+            .and_then(move |target| location::locate(target, lookup))
+            .and_then(move |path| -> agent::Result<()> {
+                if id_only || copy {
+                    print_ids(vec![path.id()]);
+                    if copy {
+                        copy_to_clipboard(path.id());
+                    }
+                } else if output.is_json() {
+                    println!("{}", serde_json::to_string_pretty(&path)?);
+                } else {
+                    println!("{}", path);
+                }
+                Ok(())
+            })
             .into_trait()
     }
 
+    /// Returns a function that looks up a dataset or package by its ID,
+    /// for use with `location::locate`.
+    fn lookup_path_node(
+        api: Api,
+    ) -> impl Fn(String) -> Future<location::PathNode> + Clone + Send + 'static {
+        move |id: String| {
+            if s::looks_like_dataset_node_id(&id) {
+                api.get_dataset(id)
+                    .map(|response| {
+                        let dataset = response.take();
+                        location::PathNode::new(dataset.id().clone(), dataset.name().clone(), None)
+                    })
+                    .into_trait()
+            } else {
+                api.get_collection(id)
+                    .map(|response| {
+                        let package = response.take();
+                        let parent: String = match package.parent_id() {
+                            Some(parent_id) => parent_id.clone().into(),
+                            None => package.dataset_id().clone().into(),
+                        };
+                        location::PathNode::new(
+                            package.id().clone(),
+                            package.name().clone(),
+                            Some(parent),
+                        )
+                    })
+                    .into_trait()
+            }
+        }
+    }
+
     /// Rename a dataset or package.
-    pub fn rename<P, Q>(&self, id: P, new_name: Q) -> Future<()>
+    ///
+    /// Renaming to the exact name of a sibling (another dataset, or another
+    /// package in the same collection) is refused unless `force` is given.
+    /// With `dry_run`, the rename is printed but not applied.
+    pub fn rename<P, Q>(&self, id: P, new_name: Q, force: bool, dry_run: bool) -> Future<()>
     where
         P: Into<String>,
         Q: Into<String>,
     {
+        let api = self.api.clone();
+        let new_name = new_name.into();
         self.api
-            .rename(id, new_name)
-            .and_then(move |renamed: Renamed| {
-                println!(
-                    "Renamed \"{id}\" to \"{new_name}\"",
-                    id = renamed.id,
-                    new_name = renamed.new_name
-                );
-                Ok(())
+            .get_rename_target(id)
+            .and_then(move |target| {
+                try_future!(rename::check_collision(&target, &new_name, force));
+                if dry_run {
+                    println!("{}", rename::describe(&target, &new_name));
+                    return future::ok(()).into_trait();
+                }
+                api.rename_target(&target, new_name)
+                    .map(|renamed: Renamed| {
+                        println!(
+                            "Renamed \"{id}\" to \"{new_name}\"",
+                            id = renamed.id,
+                            new_name = renamed.new_name
+                        );
+                    })
+                    .into_trait()
             })
             .into_trait()
     }
 
     /// Move packages around.
     /// If destination is None, move the package to the dataset root
-    pub fn move_package<P, Q>(&self, source: P, destination: Option<Q>) -> Future<()>
+    ///
+    /// Collections are never moved into one of their own descendants. With
+    /// `dry_run`, the planned move is printed but not performed.
+    pub fn move_package<P, Q>(&self, source: P, destination: Option<Q>, dry_run: bool) -> Future<()>
     where
         P: Into<PackageId>,
         Q: Into<PackageId>,
     {
-        let destination = destination.map(Into::into);
+        let source: PackageId = source.into();
+        let destination: Option<PackageId> = destination.map(Into::into);
+        let api = self.api.clone();
+        let fetch = Self::fetch_children(self.api.clone());
+
+        // Resolve the path to the destination, which is enough to tell if
+        // it lies beneath the source without walking the source's subtree:
+        let resolved_destination = match destination {
+            Some(destination) => {
+                let lookup = Self::lookup_path_node(self.api.clone());
+                lookup(destination.into())
+                    .and_then(move |node| location::locate(node, lookup))
+                    .map(Some)
+                    .into_trait()
+            }
+            None => future::ok(None).into_trait(),
+        };
+
         self.api
-            .move_packages(vec![source], destination.clone())
+            .get_collection(source)
+            .map(Into::<output::CliPackageTree>::into)
+            .join(resolved_destination)
+            .and_then(move |(source, destination)| {
+                location::check_move(&source.id, destination.as_ref())?;
+                Ok((source, destination))
+            })
+            .and_then(move |(source, destination)| {
+                if dry_run {
+                    // Only a dry run needs to count what would move along:
+                    let destination = destination
+                        .as_ref()
+                        .and_then(|path| path.0.last())
+                        .map(|d| format!("{} ({})", d.name, d.id))
+                        .unwrap_or_else(|| "dataset root".to_string());
+                    return tree::expand(source, None, fetch)
+                        .map(move |source| {
+                            println!(
+                                "Would move {} ({}) and {} nested item(s) to {}",
+                                source.name,
+                                source.id,
+                                source.descendant_count(),
+                                destination
+                            );
+                        })
+                        .into_trait();
+                }
+                Self::perform_move(
+                    &api,
+                    PackageId::new(source.id),
+                    destination.map(|path| PackageId::new(path.id().to_string())),
+                )
+            })
+            .into_trait()
+    }
+
+    fn perform_move(api: &Api, source: PackageId, destination: Option<PackageId>) -> Future<()> {
+        api.move_packages(vec![source], destination.clone())
             .and_then(move |response| {
                 response
                     .success()
@@ -767,14 +1829,44 @@ impl Cli {
             .into_trait()
     }
 
-    /// Prints the persistent dataset based on the user's current profile.
+    /// Prints the persistent dataset based on the user's current profile,
+    /// along with its name. If the dataset no longer exists, the user is
+    /// offered to clear it.
     pub fn print_settings_dataset(&self) -> Future<()> {
+        let api = self.api.clone();
+        let db = self.db.clone();
         self.get_user_and_settings()
-            .and_then(|(_, settings)| {
-                if let Some(id) = settings.use_dataset_id {
-                    println!("Using dataset \"{}\"", id);
-                } else {
-                    println!("No dataset");
+            .and_then(move |(user, settings)| {
+                match settings.use_dataset_id.clone() {
+                    Some(id) => api
+                        .get_dataset(id.clone())
+                        .then(move |result| {
+                            Ok::<_, agent::Error>(WorkingDataset::resolve(
+                                id,
+                                result.map(|dataset| dataset.name().to_string()),
+                            ))
+                        })
+                        .into_trait(),
+                    None => future::ok(WorkingDataset::Unset).into_trait(),
+                }
+                .map(|dataset| (user, settings, dataset))
+            })
+            .and_then(move |(user, settings, dataset)| -> agent::Result<()> {
+                println!("{}", dataset.describe());
+                if let WorkingDataset::Missing { id } = dataset {
+                    warn!("ps:cli:use ~ stale dataset {}", id);
+                    let clear = input::confirm("Clear it?").unwrap_or_else(|e| {
+                        error!("ps:cli:use:confirm ~ {}", e);
+                        false
+                    });
+                    if clear {
+                        db.upsert_user_settings(
+                            user.id.as_ref(),
+                            user.profile.as_ref(),
+                            &settings.with_dataset(None),
+                        )?;
+                        println!("Cleared dataset for \"{profile}\".", profile = user.profile);
+                    }
                 }
                 Ok(())
             })
@@ -847,7 +1939,7 @@ impl Cli {
             .into_future()
             .map_err(Into::into)
             .and_then(move |(config, profile)| {
-                let api = api::Api::new(&db, &config, profile.environment);
+                let api = api::Api::new(&db, &config, &profile);
                 api.login(profile).map(|_| ()).into_trait()
             })
             .map_err(Into::into)
@@ -867,3 +1959,44 @@ impl Cli {
         self.update_settings_dataset(None as Option<String>)
     }
 }
+
+// Runs `cancel`, then removes the files spooled from stdin for the uploads
+// it cancelled, which nothing will upload any longer.
+fn cancel_spooled<T, F>(db: &Database, cancel: F) -> agent::Result<T>
+where
+    F: FnOnce() -> agent::Result<T>,
+{
+    let spool_dir = agent::upload::stdin::spool_dir()?;
+    let spooled = agent::upload::stdin::spooled_in(&db.get_active_uploads()?, &spool_dir);
+    let cancelled = cancel()?;
+    agent::upload::stdin::remove_unneeded(db, spooled)?;
+    Ok(cancelled)
+}
+
+// Prints node IDs as with `--id-only`. Nothing is printed for an empty
+// listing, so piping it on yields no input at all.
+fn print_ids<I, S>(ids: I)
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let ids = output::id_lines(ids);
+    if !ids.is_empty() {
+        println!("{}", ids);
+    }
+}
+
+// Copies a node ID to the system clipboard, warning if it can't be.
+#[cfg(feature = "clipboard")]
+fn copy_to_clipboard(id: &str) {
+    match clipboard::copy(id) {
+        Ok(()) => eprintln!("Copied {} to the clipboard", id),
+        Err(e) => eprintln!("Couldn't copy {} to the clipboard: {}", id, e),
+    }
+}
+
+// Without the `clipboard` feature, `--copy` can only print the ID.
+#[cfg(not(feature = "clipboard"))]
+fn copy_to_clipboard(_id: &str) {
+    eprintln!("This agent was built without clipboard support; the ID was only printed");
+}
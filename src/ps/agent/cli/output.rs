@@ -8,12 +8,16 @@ use std::convert::From;
 use std::fmt::{self, Display};
 
 use prettytable::{self as pt, cell, row};
+use serde_derive::Serialize;
 
 use pennsieve_rust::api::response;
 use pennsieve_rust::model;
 
+use crate::ps::agent::api::OrganizationScope;
 use crate::ps::agent::cli;
+use crate::ps::agent::config::EffectiveValue;
 use crate::ps::agent::database::{UploadRecords, UserRecord};
+use crate::ps::agent::OutputFormat;
 use crate::ps::util::temporal::timespec_to_rfc3339;
 
 // ~~~ ApiSettings ~~~
@@ -55,6 +59,26 @@ impl Display for CliSettings {
     }
 }
 
+// ~~~ Effective configuration ~~~
+pub struct CliEffectiveConfig(Vec<EffectiveValue>);
+
+impl From<Vec<EffectiveValue>> for CliEffectiveConfig {
+    fn from(values: Vec<EffectiveValue>) -> Self {
+        CliEffectiveConfig(values)
+    }
+}
+
+impl Display for CliEffectiveConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        cli::table(Some(vec!["KEY", "VALUE", "SOURCE"]), move |t| {
+            self.0.iter().for_each(|value| {
+                t.add_row(row![value.key, value.value, value.source]);
+            });
+        })
+        .fmt(fmt)
+    }
+}
+
 // ~~~ Collaborators ~~~
 pub struct CliCollaborators {
     pub organizations: CliOrganizationRoles,
@@ -114,18 +138,84 @@ impl Display for CliCollaborators {
 
 // ~~~ Organizations ~~~
 
+/// An organization the user belongs to, and whether it is the one their
+/// operations are scoped to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CliOrganization {
+    pub id: String,
+    pub name: String,
+    pub active: bool,
+}
+
+impl From<&UserRecord> for CliOrganization {
+    fn from(user: &UserRecord) -> Self {
+        CliOrganization {
+            id: user.organization_id.clone(),
+            name: user.organization_name.clone(),
+            active: true,
+        }
+    }
+}
+
+impl CliOrganization {
+    /// Renders the organization as a JSON object with `id` and `name` keys.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct Summary<'a> {
+            id: &'a str,
+            name: &'a str,
+        }
+
+        serde_json::to_string_pretty(&Summary {
+            id: &self.id,
+            name: &self.name,
+        })
+    }
+}
+
+impl Display for CliOrganization {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{} ({})", self.name, self.id)
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct CliOrganizations(response::Organizations);
+pub struct CliOrganizations(Vec<CliOrganization>);
 
 impl CliOrganizations {
+    /// Lists `organizations`, flagging the one with the ID `active_id`.
+    pub fn new(organizations: Vec<OrganizationScope>, active_id: &str) -> Self {
+        CliOrganizations(
+            organizations
+                .into_iter()
+                .map(|o| CliOrganization {
+                    active: o.id == active_id,
+                    id: o.id,
+                    name: o.name,
+                })
+                .collect(),
+        )
+    }
+
     #[allow(dead_code)]
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    /// Gets the active organization, if it is listed.
+    pub fn current(&self) -> Option<&CliOrganization> {
+        self.0.iter().find(|o| o.active)
+    }
+
+    /// Renders the organizations as a JSON array of objects with `id`,
+    /// `name` and `active` keys.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&self.0)
+    }
 }
 
 impl IntoIterator for CliOrganizations {
-    type Item = response::Organization;
+    type Item = CliOrganization;
     type IntoIter = ::std::vec::IntoIter<Self::Item>;
 
     fn into_iter(self) -> Self::IntoIter {
@@ -133,27 +223,13 @@ impl IntoIterator for CliOrganizations {
     }
 }
 
-impl From<response::Organizations> for CliOrganizations {
-    fn from(organizations: response::Organizations) -> Self {
-        CliOrganizations(organizations)
-    }
-}
-
-impl From<Vec<model::Organization>> for CliOrganizations {
-    fn from(organizations: Vec<model::Organization>) -> Self {
-        Into::<response::Organizations>::into(organizations).into()
-    }
-}
-
 impl Display for CliOrganizations {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.len() > 0 {
-            cli::table(Some(vec!["ORGANIZATION"]), move |t| {
-                self.clone()
-                    .into_iter()
-                    .for_each(|o: response::Organization| {
-                        t.add_row(row![Into::<String>::into(o.organization().name().clone())]);
-                    });
+            cli::table(Some(vec!["", "ORGANIZATION", "ID"]), move |t| {
+                self.0.iter().for_each(|o| {
+                    t.add_row(row![if o.active { "*" } else { "" }, o.name, o.id]);
+                });
             })
             .fmt(fmt)
         } else {
@@ -222,6 +298,121 @@ impl Display for CliOrganizationRoles {
     }
 }
 
+// ~~~ IDs ~~~
+
+/// Renders node IDs one per line with nothing around them, as printed by
+/// `--id-only`, so they can be piped into other commands.
+pub fn id_lines<I, S>(ids: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    ids.into_iter()
+        .map(|id| id.as_ref().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// ~~~ Created ~~~
+
+/// A dataset or collection that was just created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CliCreated {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+}
+
+impl CliCreated {
+    pub fn new<I, N>(kind: &'static str, id: I, name: N) -> Self
+    where
+        I: Into<String>,
+        N: Into<String>,
+    {
+        Self {
+            id: id.into(),
+            name: name.into(),
+            kind,
+        }
+    }
+
+    /// Renders the created node as printed by the `create-*` commands:
+    /// with `id_only`, just its ID; otherwise a confirmation, or a JSON
+    /// object with `id`, `name` and `type` keys.
+    pub fn render(&self, output: OutputFormat, id_only: bool) -> serde_json::Result<String> {
+        if id_only {
+            Ok(self.id.clone())
+        } else if output.is_json() {
+            serde_json::to_string_pretty(self)
+        } else {
+            Ok(self.to_string())
+        }
+    }
+}
+
+impl Display for CliCreated {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "Created {} {} ({})", self.kind, self.name, self.id)
+    }
+}
+
+// ~~~ Updated ~~~
+
+/// A dataset that was just updated, along with the fields that changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CliUpdated {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub updated: cli::update::DatasetUpdate,
+}
+
+impl CliUpdated {
+    pub fn new<I, N>(
+        kind: &'static str,
+        id: I,
+        name: N,
+        updated: cli::update::DatasetUpdate,
+    ) -> Self
+    where
+        I: Into<String>,
+        N: Into<String>,
+    {
+        Self {
+            id: id.into(),
+            name: name.into(),
+            kind,
+            updated,
+        }
+    }
+
+    /// Renders the updated node as printed by the `update-*` commands: a
+    /// confirmation, or a JSON object with `id`, `name`, `type` and
+    /// `updated` keys, the last holding the fields that changed.
+    pub fn render(&self, output: OutputFormat) -> serde_json::Result<String> {
+        if output.is_json() {
+            serde_json::to_string_pretty(self)
+        } else {
+            Ok(self.to_string())
+        }
+    }
+}
+
+impl Display for CliUpdated {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fmt,
+            "Updated the {} of {} {} ({})",
+            self.updated.fields().join(", "),
+            self.kind,
+            self.name,
+            self.id
+        )
+    }
+}
+
 // ~~~ Packages ~~~
 
 #[derive(Debug, Clone)]
@@ -232,6 +423,16 @@ impl CliPackages {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    /// The IDs of the packages, in listing order.
+    pub fn ids(&self) -> Vec<String> {
+        self.0.iter().map(CliPackage::id).collect()
+    }
+
+    /// Keeps only the packages that pass `filter`.
+    pub fn filter_types(self, filter: &cli::PackageTypeFilter) -> Self {
+        CliPackages(filter.apply(self.0, CliPackage::package_type))
+    }
 }
 
 impl From<Vec<CliPackage>> for CliPackages {
@@ -280,6 +481,17 @@ impl CliPackage {
     pub fn len(&self) -> usize {
         self.children.len()
     }
+
+    pub fn id(&self) -> String {
+        self.content.id().clone().into()
+    }
+
+    /// The type of the package, if it has one.
+    pub fn package_type(&self) -> Option<String> {
+        self.content
+            .package_type()
+            .map(|package_type| package_type.to_string())
+    }
 }
 
 impl IntoIterator for CliPackage {
@@ -337,6 +549,17 @@ impl CliCollection {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    /// The IDs of the packages in the collection, in listing order.
+    pub fn child_ids(&self) -> Vec<String> {
+        self.0.children.iter().map(CliPackage::id).collect()
+    }
+
+    /// Keeps only the packages in the collection that pass `filter`.
+    pub fn filter_types(mut self, filter: &cli::PackageTypeFilter) -> Self {
+        self.0.children = filter.apply(self.0.children, CliPackage::package_type);
+        self
+    }
 }
 
 impl From<CliPackage> for CliCollection {
@@ -391,6 +614,59 @@ impl CliDataset {
     pub fn len(&self) -> usize {
         self.children.len()
     }
+
+    pub fn id(&self) -> &str {
+        self.content.id().as_ref()
+    }
+
+    pub fn name(&self) -> &str {
+        self.content.name()
+    }
+
+    /// The IDs of the packages at the top level of the dataset, in listing
+    /// order.
+    pub fn child_ids(&self) -> Vec<String> {
+        self.children.ids()
+    }
+
+    /// Keeps only the packages at the top level of the dataset that pass
+    /// `filter`.
+    pub fn filter_types(mut self, filter: &cli::PackageTypeFilter) -> Self {
+        self.children = self.children.filter_types(filter);
+        self
+    }
+
+    /// When the dataset was created, as a UTC timestamp that sorts in
+    /// chronological order.
+    pub fn created_at(&self) -> Option<String> {
+        self.content
+            .created_at()
+            .as_ref()
+            .map(ToString::to_string)
+            .map(|timestamp| sortable_timestamp(&timestamp))
+    }
+
+    /// When the dataset was last updated, as a UTC timestamp that sorts in
+    /// chronological order.
+    pub fn updated_at(&self) -> Option<String> {
+        self.content
+            .updated_at()
+            .as_ref()
+            .map(ToString::to_string)
+            .map(|timestamp| sortable_timestamp(&timestamp))
+    }
+}
+
+/// Normalizes an RFC 3339 timestamp to UTC with a fixed number of
+/// fractional digits, so that timestamps compare in chronological order.
+/// Timestamps that cannot be parsed are left as they are.
+fn sortable_timestamp(timestamp: &str) -> String {
+    chrono::DateTime::parse_from_rfc3339(timestamp)
+        .map(|t| {
+            t.with_timezone(&chrono::Utc)
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true)
+        })
+        .unwrap_or_else(|_| timestamp.to_string())
 }
 
 impl From<response::Dataset> for CliDataset {
@@ -439,6 +715,56 @@ impl CliDatasets {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    /// The IDs of the datasets, in listing order.
+    pub fn ids(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|dataset| dataset.id().to_string())
+            .collect()
+    }
+
+    /// Renders the datasets as a JSON array of objects with `id`, `name`
+    /// and `status` keys, in listing order.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct Summary {
+            id: String,
+            name: String,
+            status: String,
+        }
+
+        let summaries = self
+            .clone()
+            .into_iter()
+            .map(|dataset| Summary {
+                id: dataset.id().to_string(),
+                name: dataset.name().to_string(),
+                status: dataset.content.status().to_owned(),
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string_pretty(&summaries)
+    }
+}
+
+impl CliDatasets {
+    const TITLES: &'static [&'static str] = &["DATASET", "NAME", "STATUS"];
+
+    /// Renders the datasets as a table showing only the chosen `columns`.
+    pub fn table(&self, columns: &cli::Columns) -> cli::Result<cli::CliTable> {
+        let rows = self
+            .0
+            .iter()
+            .map(|r| {
+                vec![
+                    r.id().to_string(),
+                    r.name().to_string(),
+                    r.content.status().to_owned(),
+                ]
+            })
+            .collect();
+        cli::table_with_columns(Self::TITLES, rows, columns)
+    }
 }
 
 impl IntoIterator for CliDatasets {
@@ -446,15 +772,7 @@ impl IntoIterator for CliDatasets {
     type IntoIter = ::std::vec::IntoIter<Self::Item>;
 
     fn into_iter(self) -> Self::IntoIter {
-        // Sort before returning
-        let mut ds = self.0;
-        ds.sort_by(|a, b| {
-            a.content
-                .name()
-                .to_lowercase()
-                .cmp(&b.content.name().to_lowercase())
-        });
-        ds.into_iter()
+        self.0.into_iter()
     }
 }
 
@@ -466,16 +784,127 @@ impl From<Vec<CliDataset>> for CliDatasets {
 
 impl Display for CliDatasets {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        cli::table(Some(vec!["DATASET", "NAME", "STATUS"]), |t| {
-            for r in self.clone() {
-                t.add_row(row![
-                    pt::Cell::new(r.content.id().as_ref()),
-                    cell!(r.content.name()),
-                    cell!(r.content.status().to_owned())
-                ]);
-            }
-        })
-        .fmt(fmt)
+        self.table(&cli::Columns::default())
+            .map_err(|_| fmt::Error)?
+            .fmt(fmt)
+    }
+}
+
+// ~~~ Package tree ~~~
+
+/// A dataset or package, along with every package nested beneath it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CliPackageTree {
+    pub name: String,
+    pub id: String,
+    pub package_type: String,
+    pub children: Vec<CliPackageTree>,
+}
+
+impl CliPackageTree {
+    pub fn new<N, I, T>(name: N, id: I, package_type: T) -> Self
+    where
+        N: Into<String>,
+        I: Into<String>,
+        T: Into<String>,
+    {
+        Self {
+            name: name.into(),
+            id: id.into(),
+            package_type: package_type.into(),
+            children: vec![],
+        }
+    }
+
+    pub fn with_children(mut self, children: Vec<CliPackageTree>) -> Self {
+        self.children = children;
+        self
+    }
+
+    /// Keeps only the children of this node that pass `filter`. Packages
+    /// nested deeper are left as they are.
+    pub fn filter_types(mut self, filter: &cli::PackageTypeFilter) -> Self {
+        self.children = filter.apply(self.children, |child| Some(child.package_type.clone()));
+        self
+    }
+
+    /// Tests if this node can contain other packages.
+    pub fn is_collection(&self) -> bool {
+        self.package_type.eq_ignore_ascii_case("collection")
+    }
+
+    /// The number of packages nested beneath this node.
+    pub fn descendant_count(&self) -> usize {
+        self.children
+            .iter()
+            .map(|child| 1 + child.descendant_count())
+            .sum()
+    }
+
+    fn fmt_indented(&self, fmt: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        writeln!(
+            fmt,
+            "{indent}{name} ({package_type}) {id}",
+            indent = "  ".repeat(depth),
+            name = self.name,
+            package_type = self.package_type,
+            id = self.id
+        )?;
+        for child in &self.children {
+            child.fmt_indented(fmt, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<model::Package> for CliPackageTree {
+    fn from(package: model::Package) -> Self {
+        CliPackageTree::new(
+            package.name().clone(),
+            Into::<String>::into(package.id().clone()),
+            package
+                .package_type()
+                .map(|package_type| package_type.to_string())
+                .unwrap_or_else(|| "Unknown".to_string()),
+        )
+    }
+}
+
+impl From<response::Package> for CliPackageTree {
+    fn from(package: response::Package) -> Self {
+        let children = package
+            .children()
+            .unwrap_or(&vec![])
+            .iter()
+            .cloned()
+            .map(|child| Into::<CliPackageTree>::into(child.take()))
+            .collect::<Vec<_>>();
+        Into::<CliPackageTree>::into(package.take()).with_children(children)
+    }
+}
+
+impl From<response::Dataset> for CliPackageTree {
+    fn from(dataset: response::Dataset) -> Self {
+        let children = dataset
+            .children()
+            .unwrap_or(&vec![])
+            .iter()
+            .cloned()
+            .map(|child| Into::<CliPackageTree>::into(child.take()))
+            .collect::<Vec<_>>();
+        let dataset = dataset.take();
+        CliPackageTree::new(
+            dataset.name().clone(),
+            Into::<String>::into(dataset.id().clone()),
+            "Dataset",
+        )
+        .with_children(children)
+    }
+}
+
+impl Display for CliPackageTree {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(fmt, 0)
     }
 }
 
@@ -574,15 +1003,50 @@ impl CliUsers {
         )
     }
 
-    pub fn table_without_roles(&self) -> cli::CliTable {
-        self.table(vec!["LAST NAME", "FIRST NAME", "EMAIL", "ID"], |r| {
-            row![
-                cell!(r.last_name()),
-                cell!(r.first_name()),
-                cell!(r.email()),
-                pt::Cell::new(r.id().borrow()),
-            ]
-        })
+    /// Renders the users as a JSON array of objects with `id`, `first_name`,
+    /// `last_name`, `email` and `role` keys, sorted by last name.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct Summary {
+            id: String,
+            first_name: String,
+            last_name: String,
+            email: String,
+            role: Option<String>,
+        }
+
+        let mut users = self.0.clone();
+        users.sort_by(|a, b| a.last_name().cmp(&b.last_name()));
+        let summaries = users
+            .iter()
+            .map(|user| Summary {
+                id: Borrow::<str>::borrow(user.id()).to_string(),
+                first_name: user.first_name().to_string(),
+                last_name: user.last_name().to_string(),
+                email: user.email().to_string(),
+                role: user.role().cloned(),
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string_pretty(&summaries)
+    }
+
+    /// Renders the users, sorted by last name, as a table showing only the
+    /// chosen `columns`.
+    pub fn table_without_roles(&self, columns: &cli::Columns) -> cli::Result<cli::CliTable> {
+        let mut users = self.0.clone();
+        users.sort_by(|a, b| a.last_name().cmp(&b.last_name()));
+        let rows = users
+            .iter()
+            .map(|r| {
+                vec![
+                    r.last_name().to_string(),
+                    r.first_name().to_string(),
+                    r.email().to_string(),
+                    Borrow::<str>::borrow(r.id()).to_string(),
+                ]
+            })
+            .collect();
+        cli::table_with_columns(&["LAST NAME", "FIRST NAME", "EMAIL", "ID"], rows, columns)
     }
 }
 
@@ -726,43 +1190,280 @@ impl From<UploadRecords> for CliUploadRecords {
     }
 }
 
+impl CliUploadRecords {
+    const TITLES: &'static [&'static str] = &[
+        "ID",
+        "FILE",
+        "CREATED AT",
+        "DATASET",
+        "PACKAGE",
+        "STATUS",
+        "APPEND",
+        "PRIORITY",
+        "% DONE",
+    ];
+
+    /// Renders the records as a table showing only the chosen `columns`.
+    pub fn table(&self, columns: &cli::Columns) -> cli::Result<cli::CliTable> {
+        let rows = self
+            .0
+            .records
+            .iter()
+            .map(|r| {
+                vec![
+                    r.id.map(|id| id.to_string())
+                        .unwrap_or_else(|| "N/A".to_string()),
+                    r.file_path.clone(),
+                    timespec_to_rfc3339(r.created_at),
+                    r.dataset_id.clone(),
+                    r.package_id.clone().unwrap_or_else(|| "N/A".to_string()),
+                    r.status.into(),
+                    r.append.to_string(),
+                    r.priority.to_string(),
+                    r.progress.to_string(),
+                ]
+            })
+            .collect();
+        cli::table_with_columns(Self::TITLES, rows, columns)
+    }
+}
+
 impl Display for CliUploadRecords {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        cli::table(
-            Some(vec![
-                "ID",
-                "FILE",
-                "CREATED AT",
-                "DATASET",
-                "PACKAGE",
-                "STATUS",
-                "APPEND",
-                "% DONE",
-            ]),
-            |t| {
-                for r in &self.0.records {
-                    t.add_row(row![
-                        pt::Cell::new(
-                            r.id.map(|id| id.to_string())
-                                .unwrap_or_else(|| "N/A".to_string())
-                                .as_ref(),
-                        ),
-                        pt::Cell::new(r.file_path.as_ref()),
-                        pt::Cell::new(timespec_to_rfc3339(r.created_at).as_ref()),
-                        pt::Cell::new(r.dataset_id.as_ref()),
-                        pt::Cell::new(
-                            r.package_id
-                                .clone()
-                                .unwrap_or_else(|| "N/A".to_string())
-                                .as_ref(),
-                        ),
-                        pt::Cell::new(r.status.as_ref()),
-                        pt::Cell::new(if r.append { "true" } else { "false" }),
-                        pt::Cell::new(r.progress.to_string().as_ref()),
-                    ]);
-                }
-            },
+        self.table(&cli::Columns::default())
+            .map_err(|_| fmt::Error)?
+            .fmt(fmt)
+    }
+}
+
+/// Failed uploads, along with why each of them gave up.
+pub struct CliFailedUploads(UploadRecords);
+
+impl From<UploadRecords> for CliFailedUploads {
+    fn from(records: UploadRecords) -> Self {
+        CliFailedUploads(records)
+    }
+}
+
+impl CliFailedUploads {
+    const TITLES: &'static [&'static str] = &[
+        "ID",
+        "FILE",
+        "UPDATED AT",
+        "DATASET",
+        "PACKAGE",
+        "% DONE",
+        "REASON",
+    ];
+
+    /// Renders the failed uploads as a table showing only the chosen
+    /// `columns`.
+    pub fn table(&self, columns: &cli::Columns) -> cli::Result<cli::CliTable> {
+        let rows = self
+            .0
+            .records
+            .iter()
+            .map(|r| {
+                vec![
+                    r.id.map(|id| id.to_string())
+                        .unwrap_or_else(|| "N/A".to_string()),
+                    r.file_path.clone(),
+                    timespec_to_rfc3339(r.updated_at),
+                    r.dataset_id.clone(),
+                    r.package_id.clone().unwrap_or_else(|| "N/A".to_string()),
+                    r.progress.to_string(),
+                    r.failure_reason
+                        .clone()
+                        .unwrap_or_else(|| "unknown".to_string()),
+                ]
+            })
+            .collect();
+        cli::table_with_columns(Self::TITLES, rows, columns)
+    }
+}
+
+impl Display for CliFailedUploads {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.table(&cli::Columns::default())
+            .map_err(|_| fmt::Error)?
+            .fmt(fmt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn organizations() -> CliOrganizations {
+        CliOrganizations::new(
+            vec![
+                OrganizationScope::new("N:organization:1", "Lab A", "key-1"),
+                OrganizationScope::new("N:organization:2", "Lab B", "key-2"),
+                OrganizationScope::new("N:organization:3", "Lab C", "key-3"),
+            ],
+            "N:organization:2",
         )
-        .fmt(fmt)
+    }
+
+    #[test]
+    fn created_nodes_can_be_printed_as_just_their_id() {
+        let created = CliCreated::new("dataset", "N:dataset:1234", "My Samples");
+        assert_eq!(
+            created.render(OutputFormat::Rich, true).unwrap(),
+            "N:dataset:1234"
+        );
+        assert_eq!(
+            created.render(OutputFormat::Json, true).unwrap(),
+            "N:dataset:1234"
+        );
+        assert_eq!(
+            created.render(OutputFormat::Rich, false).unwrap(),
+            "Created dataset My Samples (N:dataset:1234)"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&created.render(OutputFormat::Json, false).unwrap()).unwrap();
+        assert_eq!(json["id"], "N:dataset:1234");
+        assert_eq!(json["name"], "My Samples");
+        assert_eq!(json["type"], "dataset");
+    }
+
+    #[test]
+    fn updated_nodes_list_the_fields_that_changed() {
+        let update = cli::update::DatasetUpdate::new(
+            Some("Sleep study".to_string()),
+            None,
+            Some(vec!["eeg".to_string()]),
+        )
+        .unwrap();
+        let updated = CliUpdated::new("dataset", "N:dataset:1234", "Sleep study", update);
+        assert_eq!(
+            updated.render(OutputFormat::Rich).unwrap(),
+            "Updated the name, tags of dataset Sleep study (N:dataset:1234)"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&updated.render(OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["id"], "N:dataset:1234");
+        assert_eq!(
+            json["updated"],
+            serde_json::json!({"name": "Sleep study", "tags": ["eeg"]})
+        );
+    }
+
+    #[test]
+    fn failed_uploads_show_why_they_failed() {
+        let record = crate::ps::agent::database::UploadRecord::new(
+            "/data/recording.edf",
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        let mut unauthorized = record.clone();
+        unauthorized.failure_reason = Some("unauthorized".to_string());
+
+        let table =
+            CliFailedUploads::from(UploadRecords::from(vec![unauthorized, record])).to_string();
+        assert!(table.contains("REASON"));
+        assert!(table.contains("unauthorized"));
+        // records failed before reasons were kept:
+        assert!(table.contains("unknown"));
+    }
+
+    #[test]
+    fn upload_tables_show_the_chosen_columns_in_order() {
+        let mut record = crate::ps::agent::database::UploadRecord::new(
+            "/data/recording.edf",
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.id = Some(42);
+        let uploads = CliUploadRecords::from(UploadRecords::from(vec![record]));
+
+        let table = uploads
+            .table(&cli::Columns::parse("status,id"))
+            .unwrap()
+            .to_string();
+        let status = table.find("STATUS").unwrap();
+        assert!(status < table.find("ID").unwrap());
+        assert!(table.contains("queued"));
+        assert!(table.contains("42"));
+        assert!(!table.contains("FILE"));
+        assert!(!table.contains("recording.edf"));
+
+        let table = uploads.table(&cli::Columns::default()).unwrap().to_string();
+        assert!(table.contains("% DONE"));
+        assert!(table.contains("recording.edf"));
+    }
+
+    #[test]
+    fn unknown_upload_table_columns_are_rejected() {
+        let uploads = CliUploadRecords::from(UploadRecords::from(vec![]));
+        let e = uploads
+            .table(&cli::Columns::parse("id,size"))
+            .map(|_| ())
+            .unwrap_err();
+        match e.kind() {
+            cli::ErrorKind::InvalidColumn { column, expected } => {
+                assert_eq!(column, "size");
+                assert!(expected.contains("created_at"));
+                assert!(expected.contains("done"));
+            }
+            kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn ids_are_printed_bare() {
+        assert_eq!(id_lines(vec!["N:dataset:1"]), "N:dataset:1");
+        assert_eq!(
+            id_lines(vec!["N:package:1", "N:package:2"]),
+            "N:package:1\nN:package:2"
+        );
+        assert_eq!(id_lines(Vec::<String>::new()), "");
+    }
+
+    #[test]
+    fn the_active_organization_is_flagged() {
+        let active: Vec<String> = organizations()
+            .into_iter()
+            .filter(|o| o.active)
+            .map(|o| o.id)
+            .collect();
+        assert_eq!(active, vec!["N:organization:2"]);
+
+        let listing = organizations().to_string();
+        let marked: Vec<&str> = listing
+            .lines()
+            .filter(|line| line.trim_start().starts_with('*'))
+            .collect();
+        assert_eq!(marked.len(), 1);
+        assert!(marked[0].contains("Lab B"));
+    }
+
+    #[test]
+    fn current_is_only_the_active_organization() {
+        let organizations = organizations();
+        let current = organizations.current().unwrap();
+        assert_eq!(current.id, "N:organization:2");
+        assert_eq!(current.to_string(), "Lab B (N:organization:2)");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&current.to_json().unwrap()).unwrap(),
+            serde_json::json!({"id": "N:organization:2", "name": "Lab B"})
+        );
+
+        let none = CliOrganizations::new(vec![], "N:organization:2");
+        assert!(none.current().is_none());
     }
 }
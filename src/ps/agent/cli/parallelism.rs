@@ -0,0 +1,133 @@
+//! The hidden `--parallelism` and `--allow-oversubscribe` flags, which set
+//! how many upload workers run at once, and the `--file-parallelism` and
+//! `--chunk-parallelism` flags, which set each dimension of an upload on
+//! its own.
+
+use log::*;
+
+use crate::ps::agent::cli::{Error, Result};
+use crate::ps::agent::upload::Parallelism;
+
+/// Chooses the parallelism level from the requested value, defaulting to
+/// one worker per CPU. Requests for more workers than there are CPUs are
+/// capped at the CPU count, with a warning, unless `allow_oversubscribe`
+/// is set; running more workers than CPUs can help I/O bound uploads.
+pub fn parallelism_level(
+    requested: Option<&str>,
+    cpus: usize,
+    allow_oversubscribe: bool,
+) -> Result<usize> {
+    let requested = match requested {
+        Some(value) => match value.parse::<usize>() {
+            Ok(level) if level > 0 => level,
+            _ => return Err(Error::invalid_parallelism(value)),
+        },
+        None => return Ok(cpus),
+    };
+
+    if requested > cpus && !allow_oversubscribe {
+        warn!(
+            "Parallelism level {} is more than the {} available CPUs, using {} instead; \
+             pass --allow-oversubscribe to use {}",
+            requested, cpus, cpus, requested
+        );
+        Ok(cpus)
+    } else {
+        Ok(requested)
+    }
+}
+
+/// Chooses how many files are uploaded at once, and in how many chunks at
+/// once each. `parallelism`, as given with `--parallelism`, sets both, and
+/// `files` and `chunks`, given with `--file-parallelism` and
+/// `--chunk-parallelism`, override it for their own dimension. By default
+/// one file is uploaded at a time, in one chunk per CPU at a time. Each is
+/// capped at the CPU count like `parallelism_level`.
+pub fn upload_parallelism(
+    parallelism: Option<&str>,
+    files: Option<&str>,
+    chunks: Option<&str>,
+    cpus: usize,
+    allow_oversubscribe: bool,
+) -> Result<Parallelism> {
+    let level = |requested: Option<&str>, default: usize| match requested.or(parallelism) {
+        Some(requested) => parallelism_level(Some(requested), cpus, allow_oversubscribe),
+        None => Ok(default),
+    };
+    Ok(Parallelism::new(level(files, 1)?, level(chunks, cpus)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ps::agent::cli::ErrorKind;
+
+    #[test]
+    fn default_is_one_worker_per_cpu() {
+        assert_eq!(parallelism_level(None, 4, false).unwrap(), 4);
+        assert_eq!(parallelism_level(Some("2"), 4, false).unwrap(), 2);
+    }
+
+    #[test]
+    fn zero_is_rejected() {
+        for value in &["0", "-1", "many"] {
+            match parallelism_level(Some(value), 4, true) {
+                Err(e) => assert_eq!(
+                    e.kind(),
+                    &ErrorKind::InvalidParallelism {
+                        value: value.to_string()
+                    }
+                ),
+                Ok(level) => panic!("accepted parallelism level {} for {}", level, value),
+            }
+        }
+    }
+
+    #[test]
+    fn more_workers_than_cpus_is_capped() {
+        assert_eq!(parallelism_level(Some("16"), 4, false).unwrap(), 4);
+    }
+
+    #[test]
+    fn oversubscribing_is_allowed_when_asked_for() {
+        assert_eq!(parallelism_level(Some("16"), 4, true).unwrap(), 16);
+        assert_eq!(parallelism_level(None, 4, true).unwrap(), 4);
+    }
+
+    #[test]
+    fn uploads_are_parallel_in_files_and_chunks() {
+        let parallelism =
+            |parallelism, files, chunks| upload_parallelism(parallelism, files, chunks, 4, false);
+
+        assert_eq!(
+            parallelism(None, None, None).unwrap(),
+            Parallelism::new(1, 4)
+        );
+        // `--parallelism` sets both:
+        assert_eq!(
+            parallelism(Some("2"), None, None).unwrap(),
+            Parallelism::new(2, 2)
+        );
+        // ...unless either is given on its own:
+        assert_eq!(
+            parallelism(Some("2"), Some("3"), None).unwrap(),
+            Parallelism::new(3, 2)
+        );
+        assert_eq!(
+            parallelism(None, Some("3"), Some("1")).unwrap(),
+            Parallelism::new(3, 1)
+        );
+        // Each is capped and validated like `--parallelism`:
+        assert_eq!(
+            parallelism(None, Some("16"), Some("16")).unwrap(),
+            Parallelism::new(4, 4)
+        );
+        assert_eq!(
+            upload_parallelism(None, Some("16"), None, 4, true).unwrap(),
+            Parallelism::new(16, 4)
+        );
+        assert!(parallelism(None, Some("0"), None).is_err());
+        assert!(parallelism(None, None, Some("many")).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Effective permissions, as printed by `collaborators effective`: the
+//! highest role a user has on a dataset, whether it was granted to them
+//! directly, to a team they belong to, or to their organization.
+
+use std::fmt::{self, Display};
+
+use prettytable::{cell, row};
+use serde_derive::Serialize;
+
+use crate::ps::agent::cli;
+
+/// A role on a dataset. Roles are declared from least to most permissive,
+/// so comparing two roles tells which grants more.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    None,
+    BlindReviewer,
+    Viewer,
+    Editor,
+    Manager,
+    Owner,
+}
+
+impl Role {
+    /// Parses a role as returned by the Pennsieve platform, ignoring case.
+    /// A missing or unknown role grants nothing.
+    pub fn parse(role: Option<&str>) -> Role {
+        match role.map(str::to_lowercase).as_ref().map(String::as_str) {
+            Some("blind_reviewer") => Role::BlindReviewer,
+            Some("viewer") => Role::Viewer,
+            Some("editor") => Role::Editor,
+            Some("manager") => Role::Manager,
+            Some("owner") => Role::Owner,
+            _ => Role::None,
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match *self {
+            Role::None => "none",
+            Role::BlindReviewer => "blind_reviewer",
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Manager => "manager",
+            Role::Owner => "owner",
+        };
+        write!(fmt, "{}", role)
+    }
+}
+
+/// Where a role on a dataset comes from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    User,
+    Team,
+    Organization,
+}
+
+impl Display for Source {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match *self {
+            Source::User => "user",
+            Source::Team => "team",
+            Source::Organization => "organization",
+        };
+        write!(fmt, "{}", source)
+    }
+}
+
+/// A role granted to the user, or to a team or organization they belong to.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Grant {
+    pub source: Source,
+    pub name: String,
+    pub role: Role,
+}
+
+impl Grant {
+    pub fn new<N: Into<String>>(source: Source, name: N, role: Option<&str>) -> Self {
+        Self {
+            source,
+            name: name.into(),
+            role: Role::parse(role),
+        }
+    }
+}
+
+/// The role a user effectively has on a dataset: the highest of the roles
+/// they were granted.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct EffectivePermission {
+    pub user_id: String,
+    pub role: Role,
+    pub grants: Vec<Grant>,
+}
+
+impl EffectivePermission {
+    pub fn new<U: Into<String>>(user_id: U, grants: Vec<Grant>) -> Self {
+        let role = grants
+            .iter()
+            .map(|grant| grant.role)
+            .max()
+            .unwrap_or(Role::None);
+        Self {
+            user_id: user_id.into(),
+            role,
+            grants,
+        }
+    }
+
+    /// Renders where each of the user's roles comes from.
+    pub fn table(&self) -> cli::CliTable {
+        cli::table(Some(vec!["SOURCE", "NAME", "ROLE"]), |table| {
+            for grant in &self.grants {
+                table.add_row(row![grant.source, grant.name, grant.role]);
+            }
+        })
+    }
+}
+
+impl Display for EffectivePermission {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.grants.is_empty() {
+            writeln!(fmt, "{}", self.table())?;
+        }
+        write!(fmt, "Effective role of {}: {}", self.user_id, self.role)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_are_ordered_by_what_they_grant() {
+        assert!(Role::None < Role::BlindReviewer);
+        assert!(Role::BlindReviewer < Role::Viewer);
+        assert!(Role::Viewer < Role::Editor);
+        assert!(Role::Editor < Role::Manager);
+        assert!(Role::Manager < Role::Owner);
+
+        assert_eq!(Role::parse(Some("Editor")), Role::Editor);
+        assert_eq!(Role::parse(Some("unknown")), Role::None);
+        assert_eq!(Role::parse(None), Role::None);
+    }
+
+    #[test]
+    fn a_team_role_above_the_direct_role_is_effective() {
+        let permission = EffectivePermission::new(
+            "N:user:1",
+            vec![
+                Grant::new(Source::User, "Ada Lovelace", Some("viewer")),
+                Grant::new(Source::Team, "Analysts", Some("manager")),
+                Grant::new(Source::Organization, "Lab", Some("editor")),
+            ],
+        );
+        assert_eq!(permission.role, Role::Manager);
+
+        // ...and the direct role is effective when it is the highest:
+        let permission = EffectivePermission::new(
+            "N:user:1",
+            vec![
+                Grant::new(Source::User, "Ada Lovelace", Some("owner")),
+                Grant::new(Source::Team, "Analysts", Some("manager")),
+            ],
+        );
+        assert_eq!(permission.role, Role::Owner);
+    }
+
+    #[test]
+    fn users_without_grants_have_no_role() {
+        let permission = EffectivePermission::new("N:user:1", vec![]);
+        assert_eq!(permission.role, Role::None);
+        assert_eq!(
+            serde_json::to_value(&permission).unwrap(),
+            serde_json::json!({"user_id": "N:user:1", "role": "none", "grants": []})
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! Client-side searching, sorting, filtering and pagination of listings, as
+//! used by `datasets --search`, `members --search` and `ls --type`.
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use crate::ps::agent::cli::{self, Error};
+
+/// Narrows a listing to the items matching a search term, then selects a
+/// page of the remaining items.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListQuery {
+    pub search: Option<String>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl ListQuery {
+    /// Returns true if any of `fields` contains the search term, ignoring
+    /// case. Every item matches when there is no search term.
+    pub fn matches<S: AsRef<str>>(&self, fields: &[S]) -> bool {
+        match self.search {
+            Some(ref search) => {
+                let search = search.to_lowercase();
+                fields
+                    .iter()
+                    .any(|field| field.as_ref().to_lowercase().contains(&search))
+            }
+            None => true,
+        }
+    }
+
+    /// Applies the query to `items`, preserving their order. `fields`
+    /// returns the values of an item that the search term is matched
+    /// against.
+    pub fn apply<T, F, S>(&self, items: Vec<T>, fields: F) -> Vec<T>
+    where
+        F: Fn(&T) -> Vec<S>,
+        S: AsRef<str>,
+    {
+        items
+            .into_iter()
+            .filter(|item| self.matches(&fields(item)))
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::max_value()))
+            .collect()
+    }
+}
+
+/// A field a listing can be sorted by, chosen with `datasets --sort`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Created,
+    Updated,
+}
+
+impl Default for SortKey {
+    fn default() -> Self {
+        SortKey::Name
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> cli::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "name" => Ok(SortKey::Name),
+            "created" => Ok(SortKey::Created),
+            "updated" => Ok(SortKey::Updated),
+            _ => Err(Error::invalid_sort_key(s)),
+        }
+    }
+}
+
+/// Orders a listing by one of its fields, as chosen with `--sort` and
+/// `--reverse`. Listings are sorted by name by default.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListSort {
+    pub key: SortKey,
+    pub reverse: bool,
+}
+
+impl ListSort {
+    /// Sorts `items`. `field` returns the value of the sort key for an
+    /// item, if it has one. Names are compared ignoring case, and items
+    /// without a value sort last, in either direction. Items with equal
+    /// values keep their order.
+    pub fn apply<T, F>(&self, mut items: Vec<T>, field: F) -> Vec<T>
+    where
+        F: Fn(&T, SortKey) -> Option<String>,
+    {
+        let value = |item: &T| {
+            field(item, self.key).map(|value| match self.key {
+                SortKey::Name => value.to_lowercase(),
+                _ => value,
+            })
+        };
+        items.sort_by(|a, b| match (value(a), value(b)) {
+            (Some(ref a), Some(ref b)) if self.reverse => b.cmp(a),
+            (Some(ref a), Some(ref b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        });
+        items
+    }
+}
+
+/// Narrows a listing of organization members to those matching a search
+/// term and a role.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemberFilter {
+    pub search: Option<String>,
+    pub role: Option<String>,
+}
+
+impl MemberFilter {
+    /// Applies the filter to `members`, preserving their order. `fields`
+    /// returns the values of a member that the search term is matched
+    /// against, and `role` the member's role. Roles are compared ignoring
+    /// case, and members without a role never match a role filter.
+    pub fn apply<T, F, R, S>(&self, members: Vec<T>, fields: F, role: R) -> Vec<T>
+    where
+        F: Fn(&T) -> Vec<S>,
+        R: Fn(&T) -> Option<String>,
+        S: AsRef<str>,
+    {
+        let query = ListQuery {
+            search: self.search.clone(),
+            ..Default::default()
+        };
+        members
+            .into_iter()
+            .filter(|member| query.matches(&fields(member)))
+            .filter(|member| match self.role {
+                Some(ref wanted) => role(member)
+                    .map(|role| role.eq_ignore_ascii_case(wanted))
+                    .unwrap_or(false),
+                None => true,
+            })
+            .collect()
+    }
+}
+
+/// The package types that `ls --type` accepts.
+pub const PACKAGE_TYPES: &[&str] = &[
+    "Collection",
+    "ExternalFile",
+    "HDF5",
+    "Image",
+    "MRI",
+    "MSExcel",
+    "MSWord",
+    "PDF",
+    "PowerPoint",
+    "Slide",
+    "Tabular",
+    "Text",
+    "TimeSeries",
+    "Unknown",
+    "Unsupported",
+    "Video",
+    "ZIP",
+];
+
+/// Narrows a listing of packages to those of the given types, as chosen
+/// with `ls --type`. Every package matches when no types are given.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PackageTypeFilter {
+    types: Vec<&'static str>,
+}
+
+impl PackageTypeFilter {
+    /// Creates a filter matching any of `types`, which are compared ignoring
+    /// case. Fails on the first type that is not one of `PACKAGE_TYPES`.
+    pub fn new<I, S>(types: I) -> cli::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let types = types
+            .into_iter()
+            .map(|wanted| {
+                PACKAGE_TYPES
+                    .iter()
+                    .find(|known| known.eq_ignore_ascii_case(wanted.as_ref()))
+                    .cloned()
+                    .ok_or_else(|| Error::invalid_package_type(wanted.as_ref()))
+            })
+            .collect::<cli::Result<Vec<_>>>()?;
+        Ok(Self { types })
+    }
+
+    /// Tests if a package of the given type passes the filter. Packages
+    /// without a type are taken to be "Unknown".
+    pub fn matches(&self, package_type: Option<&str>) -> bool {
+        let package_type = package_type.unwrap_or("Unknown");
+        self.types.is_empty()
+            || self
+                .types
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(package_type))
+    }
+
+    /// Applies the filter to `packages`, preserving their order.
+    /// `package_type` returns the type of a package, if it has one.
+    pub fn apply<T, F>(&self, packages: Vec<T>, package_type: F) -> Vec<T>
+    where
+        F: Fn(&T) -> Option<String>,
+    {
+        packages
+            .into_iter()
+            .filter(|package| self.matches(package_type(package).as_ref().map(String::as_str)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stand-in for the datasets returned by the Pennsieve API.
+    fn datasets() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("N:dataset:1", "EEG Recordings"),
+            ("N:dataset:2", "MRI Scans"),
+            ("N:dataset:3", "Sleep EEG"),
+            ("N:dataset:4", "Notes"),
+        ]
+    }
+
+    fn search(query: ListQuery) -> Vec<&'static str> {
+        query
+            .apply(datasets(), |(id, name)| vec![*id, *name])
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    #[test]
+    fn search_matches_names_case_insensitively() {
+        let query = ListQuery {
+            search: Some("eeg".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(search(query), vec!["EEG Recordings", "Sleep EEG"]);
+    }
+
+    #[test]
+    fn search_matches_ids() {
+        let query = ListQuery {
+            search: Some("N:DATASET:2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(search(query), vec!["MRI Scans"]);
+    }
+
+    #[test]
+    fn search_without_matches_is_empty() {
+        let query = ListQuery {
+            search: Some("microscopy".to_string()),
+            ..Default::default()
+        };
+        assert!(search(query).is_empty());
+    }
+
+    #[test]
+    fn offset_and_limit_paginate_the_matches() {
+        assert_eq!(search(ListQuery::default()).len(), 4);
+
+        let query = ListQuery {
+            search: None,
+            offset: 1,
+            limit: Some(2),
+        };
+        assert_eq!(search(query), vec!["MRI Scans", "Sleep EEG"]);
+
+        let query = ListQuery {
+            search: Some("ee".to_string()),
+            offset: 1,
+            limit: Some(10),
+        };
+        assert_eq!(search(query), vec!["Sleep EEG"]);
+
+        let query = ListQuery {
+            search: None,
+            offset: 10,
+            limit: None,
+        };
+        assert!(search(query).is_empty());
+    }
+
+    /// A stand-in for unsorted datasets returned by the Pennsieve API, as
+    /// (name, created, updated) tuples.
+    fn dated_datasets() -> Vec<(&'static str, &'static str, Option<&'static str>)> {
+        vec![
+            ("sleep EEG", "2020-03-01T00:00:00Z", None),
+            (
+                "MRI Scans",
+                "2020-01-01T00:00:00Z",
+                Some("2021-06-01T00:00:00Z"),
+            ),
+            (
+                "Notes",
+                "2020-02-01T00:00:00Z",
+                Some("2021-01-01T00:00:00Z"),
+            ),
+            ("EEG Recordings", "2020-04-01T00:00:00Z", None),
+        ]
+    }
+
+    fn sort(key: SortKey, reverse: bool) -> Vec<&'static str> {
+        ListSort { key, reverse }
+            .apply(
+                dated_datasets(),
+                |(name, created, updated), key| match key {
+                    SortKey::Name => Some(name.to_string()),
+                    SortKey::Created => Some(created.to_string()),
+                    SortKey::Updated => updated.map(String::from),
+                },
+            )
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn listings_are_sorted_by_each_key() {
+        assert_eq!(
+            sort(SortKey::Name, false),
+            vec!["EEG Recordings", "MRI Scans", "Notes", "sleep EEG"]
+        );
+        assert_eq!(
+            sort(SortKey::Created, false),
+            vec!["MRI Scans", "Notes", "sleep EEG", "EEG Recordings"]
+        );
+        // datasets that were never updated come last:
+        assert_eq!(
+            sort(SortKey::Updated, false),
+            vec!["Notes", "MRI Scans", "sleep EEG", "EEG Recordings"]
+        );
+    }
+
+    #[test]
+    fn listings_are_sorted_in_reverse() {
+        assert_eq!(
+            sort(SortKey::Name, true),
+            vec!["sleep EEG", "Notes", "MRI Scans", "EEG Recordings"]
+        );
+        assert_eq!(
+            sort(SortKey::Created, true),
+            vec!["EEG Recordings", "sleep EEG", "Notes", "MRI Scans"]
+        );
+        // ...but datasets that were never updated still come last:
+        assert_eq!(
+            sort(SortKey::Updated, true),
+            vec!["MRI Scans", "Notes", "sleep EEG", "EEG Recordings"]
+        );
+    }
+
+    #[test]
+    fn sort_keys_are_parsed() {
+        assert_eq!("created".parse::<SortKey>().unwrap(), SortKey::Created);
+        assert_eq!("Updated".parse::<SortKey>().unwrap(), SortKey::Updated);
+        assert!("size".parse::<SortKey>().is_err());
+    }
+
+    /// A stand-in for the members returned by the Pennsieve API, as
+    /// (name, email, role) tuples.
+    fn members() -> Vec<(&'static str, &'static str, Option<&'static str>)> {
+        vec![
+            ("Ada Lovelace", "ada@example.com", Some("owner")),
+            ("Alan Turing", "alan@example.com", Some("editor")),
+            ("Grace Hopper", "grace@navy.example.com", Some("Editor")),
+            ("Edsger Dijkstra", "edsger@example.com", None),
+        ]
+    }
+
+    fn filter_members(filter: MemberFilter) -> Vec<&'static str> {
+        filter
+            .apply(
+                members(),
+                |(name, email, _)| vec![*name, *email],
+                |(_, _, role)| role.map(String::from),
+            )
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn members_are_searched_by_name_and_email() {
+        let filter = MemberFilter {
+            search: Some("TURING".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(filter_members(filter), vec!["Alan Turing"]);
+
+        let filter = MemberFilter {
+            search: Some("navy".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(filter_members(filter), vec!["Grace Hopper"]);
+        assert_eq!(filter_members(MemberFilter::default()).len(), 4);
+    }
+
+    #[test]
+    fn members_are_filtered_by_role() {
+        let filter = MemberFilter {
+            role: Some("editor".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(filter_members(filter), vec!["Alan Turing", "Grace Hopper"]);
+
+        let filter = MemberFilter {
+            role: Some("viewer".to_string()),
+            ..Default::default()
+        };
+        assert!(filter_members(filter).is_empty());
+    }
+
+    #[test]
+    fn member_search_and_role_are_combined() {
+        let filter = MemberFilter {
+            search: Some("a".to_string()),
+            role: Some("editor".to_string()),
+        };
+        assert_eq!(filter_members(filter), vec!["Alan Turing", "Grace Hopper"]);
+
+        let filter = MemberFilter {
+            search: Some("ada".to_string()),
+            role: Some("editor".to_string()),
+        };
+        assert!(filter_members(filter).is_empty());
+    }
+
+    /// A stand-in for a collection listing with packages of mixed types, as
+    /// (name, type) tuples.
+    fn packages() -> Vec<(&'static str, Option<&'static str>)> {
+        vec![
+            ("EEG", Some("Collection")),
+            ("night-1.edf", Some("TimeSeries")),
+            ("scan.nii", Some("MRI")),
+            ("night-2.edf", Some("TimeSeries")),
+            ("notes", None),
+        ]
+    }
+
+    fn filter_packages(types: &[&str]) -> Vec<&'static str> {
+        PackageTypeFilter::new(types)
+            .unwrap()
+            .apply(packages(), |(_, package_type)| {
+                package_type.map(String::from)
+            })
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn packages_are_filtered_by_type() {
+        assert_eq!(
+            filter_packages(&["timeseries"]),
+            vec!["night-1.edf", "night-2.edf"]
+        );
+        assert_eq!(filter_packages(&["unknown"]), vec!["notes"]);
+        assert_eq!(filter_packages(&["Video"]), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn packages_are_filtered_by_any_of_several_types() {
+        assert_eq!(
+            filter_packages(&["Collection", "MRI"]),
+            vec!["EEG", "scan.nii"]
+        );
+        assert_eq!(filter_packages(&[]).len(), packages().len());
+    }
+
+    #[test]
+    fn unknown_package_types_are_rejected() {
+        let e = PackageTypeFilter::new(&["TimeSeries", "spreadsheet"]).unwrap_err();
+        let message = e.to_string();
+        assert!(message.contains("spreadsheet"));
+        assert!(message.contains("Collection"));
+        assert!(message.contains("TimeSeries"));
+    }
+}
@@ -0,0 +1,91 @@
+//! Checks made before renaming a dataset or package with `rename`.
+
+use crate::ps::agent::api::RenameTarget;
+use crate::ps::agent::cli::{Error, Result};
+
+/// Checks that `new_name` does not exactly match the name of one of the
+/// target's siblings, unless `force` is given.
+pub fn check_collision(target: &RenameTarget, new_name: &str, force: bool) -> Result<()> {
+    if !force && target.sibling_names.iter().any(|name| name == new_name) {
+        Err(Error::name_collision(new_name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Describes the rename of `target` to `new_name`, as printed by
+/// `rename --dry-run`.
+pub fn describe(target: &RenameTarget, new_name: &str) -> String {
+    format!(
+        "Would rename {kind} \"{name}\" ({id}) to \"{new_name}\"",
+        kind = if target.is_dataset {
+            "dataset"
+        } else {
+            "package"
+        },
+        name = target.name,
+        id = target.id,
+        new_name = new_name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ps::agent::cli::ErrorKind;
+
+    fn package() -> RenameTarget {
+        RenameTarget {
+            id: "N:package:1".to_string(),
+            name: "recording.edf".to_string(),
+            is_dataset: false,
+            sibling_names: vec!["notes.txt".to_string(), "scan.nii".to_string()],
+        }
+    }
+
+    #[test]
+    fn colliding_names_are_refused() {
+        match check_collision(&package(), "notes.txt", false) {
+            Err(e) => assert_eq!(
+                e.kind(),
+                &ErrorKind::NameCollision {
+                    name: "notes.txt".to_string()
+                }
+            ),
+            Ok(()) => panic!("renaming to a sibling's name should be refused"),
+        }
+    }
+
+    #[test]
+    fn colliding_names_are_allowed_with_force() {
+        assert!(check_collision(&package(), "notes.txt", true).is_ok());
+    }
+
+    #[test]
+    fn only_exact_matches_collide() {
+        assert!(check_collision(&package(), "Notes.txt", false).is_ok());
+        assert!(check_collision(&package(), "notes", false).is_ok());
+        // keeping the current name is not a collision:
+        assert!(check_collision(&package(), "recording.edf", false).is_ok());
+    }
+
+    #[test]
+    fn dry_run_describes_the_rename() {
+        assert_eq!(
+            describe(&package(), "session-1.edf"),
+            "Would rename package \"recording.edf\" (N:package:1) to \"session-1.edf\""
+        );
+
+        let dataset = RenameTarget {
+            id: "N:dataset:1".to_string(),
+            name: "Samples".to_string(),
+            is_dataset: true,
+            sibling_names: vec![],
+        };
+        assert_eq!(
+            describe(&dataset, "Samples 2019"),
+            "Would rename dataset \"Samples\" (N:dataset:1) to \"Samples 2019\""
+        );
+    }
+}
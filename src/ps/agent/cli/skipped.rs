@@ -0,0 +1,46 @@
+//! Tells the user about the files left out of an upload.
+
+use log::*;
+
+use crate::ps::agent::api::SkipReporter;
+use crate::ps::agent::upload::Skipped;
+
+/// Prints each file left out of an upload to stderr, along with the option
+/// that would have queued it.
+pub struct PrintSkipped;
+
+impl SkipReporter for PrintSkipped {
+    fn skipped(&self, skipped: Skipped) {
+        match skipped {
+            Skipped::SymbolicLink(link) => eprintln!(
+                "Skipping {:?}: it is a symbolic link. Use --follow-symlinks to upload what it points to.",
+                link
+            ),
+            Skipped::Empty(file_path) => eprintln!("Skipping {:?}: it is empty.", file_path),
+            Skipped::AlreadyAppended {
+                file_path,
+                package_id,
+            } => eprintln!(
+                "Skipping {:?}: it was already appended to {}. Use --force-reappend to append it again.",
+                file_path, package_id
+            ),
+            Skipped::AlreadyQueued {
+                file_path,
+                dataset_id,
+            } => eprintln!(
+                "Skipping {:?}: it is already queued for upload to {}. Use --force to queue it again.",
+                file_path, dataset_id
+            ),
+            Skipped::AlreadyExist(file_paths) => {
+                for file_path in &file_paths {
+                    info!("skipping {:?}: it already exists in the dataset", file_path);
+                }
+                eprintln!(
+                    "Skipping {} {} that already exist in the dataset.",
+                    file_paths.len(),
+                    if file_paths.len() == 1 { "file" } else { "files" }
+                );
+            }
+        }
+    }
+}
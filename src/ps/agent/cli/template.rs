@@ -0,0 +1,187 @@
+//! Dataset templates: JSON files of metadata applied to new datasets, as
+//! used by `create-dataset --from-template`.
+//!
+//! ```json
+//! {
+//!   "description": "EEG recordings from the sleep study",
+//!   "tags": ["eeg", "sleep"],
+//!   "license": "Creative Commons Attribution"
+//! }
+//! ```
+//!
+//! Every key is optional, and unknown keys are rejected.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde_derive::Deserialize;
+
+use crate::ps::agent::cli::{Error, Result};
+
+/// The metadata a new dataset is created with.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DatasetTemplate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub license: Option<String>,
+}
+
+impl DatasetTemplate {
+    /// Reads and parses the template at the given path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents)
+            .map_err(|e| Error::invalid_template(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Replaces the template's name, and its description if one is given,
+    /// with the values given on the command line.
+    pub fn with_overrides<S: Into<String>>(self, name: S, description: Option<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            description: description.or(self.description),
+            ..self
+        }
+    }
+
+    /// Tests if the template sets metadata that can only be applied once
+    /// the dataset has been created.
+    pub fn has_metadata(&self) -> bool {
+        !self.tags.is_empty() || self.license.is_some()
+    }
+}
+
+/// Reads a dataset description from the file at the given path, as given
+/// with `--description-file`. The file must be UTF-8; its contents are used
+/// as they are, line breaks included.
+pub fn read_description<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)
+        .map_err(|e| Error::invalid_description_file(format!("{}: {}", path.display(), e)))?;
+    String::from_utf8(bytes)
+        .map_err(|e| Error::invalid_description_file(format!("{}: {}", path.display(), e)))
+}
+
+impl FromStr for DatasetTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        serde_json::from_str(s).map_err(|e| Error::invalid_template(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ps::agent::cli::ErrorKind;
+    use crate::ps::util;
+
+    #[test]
+    fn valid_template() {
+        let template: DatasetTemplate = r#"{
+            "name": "Template name",
+            "description": "EEG recordings",
+            "tags": ["eeg", "sleep"],
+            "license": "MIT"
+        }"#
+        .parse()
+        .unwrap();
+        assert!(template.has_metadata());
+
+        // the positional name always wins, the description only if given:
+        let template = template.with_overrides("Sleep study", None);
+        assert_eq!(
+            template,
+            DatasetTemplate {
+                name: Some("Sleep study".to_string()),
+                description: Some("EEG recordings".to_string()),
+                tags: vec!["eeg".to_string(), "sleep".to_string()],
+                license: Some("MIT".to_string()),
+            }
+        );
+        let template = template.with_overrides("Sleep study", Some("Pilot".to_string()));
+        assert_eq!(template.description, Some("Pilot".to_string()));
+    }
+
+    #[test]
+    fn partial_template() {
+        let template: DatasetTemplate = r#"{ "description": "EEG recordings" }"#.parse().unwrap();
+        assert!(!template.has_metadata());
+        assert_eq!(
+            template.with_overrides("Sleep study", None),
+            DatasetTemplate {
+                name: Some("Sleep study".to_string()),
+                description: Some("EEG recordings".to_string()),
+                tags: vec![],
+                license: None,
+            }
+        );
+
+        let template: DatasetTemplate = "{}".parse().unwrap();
+        assert_eq!(template, DatasetTemplate::default());
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        let invalid_template = |json: &str| match json.parse::<DatasetTemplate>() {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidTemplate { message } => message.clone(),
+                kind => panic!("unexpected error: {:?}", kind),
+            },
+            Ok(template) => panic!("expected an error, got: {:?}", template),
+        };
+
+        assert!(invalid_template(r#"{ "title": "Sleep study" }"#).contains("unknown field `title`"));
+        assert!(invalid_template(r#"{ "tags": "eeg" }"#).contains("invalid type"));
+        assert!(!invalid_template(r#"{ "description": "#).is_empty());
+        assert!(!invalid_template("[]").is_empty());
+    }
+
+    #[test]
+    fn template_is_read_from_a_file() {
+        let path = util::path::temp("template", ".json").unwrap();
+        fs::write(&path, r#"{ "license": "MIT" }"#).unwrap();
+
+        let template = DatasetTemplate::from_path(&path).unwrap();
+        assert_eq!(template.license, Some("MIT".to_string()));
+
+        fs::write(&path, r#"{ "licence": "MIT" }"#).unwrap();
+        assert!(DatasetTemplate::from_path(&path).is_err());
+    }
+
+    #[test]
+    fn description_is_read_from_a_file_intact() {
+        let path = util::path::temp("description", ".txt").unwrap();
+        let description =
+            "Sleep study\n\nEEG recordings of 12 subjects,\r\nover 3 nights. \u{3b1}-waves\n";
+        fs::write(&path, description).unwrap();
+
+        let read = read_description(&path).unwrap();
+        assert_eq!(read, description);
+
+        // ...and it is what the dataset is created with:
+        let template = DatasetTemplate::default().with_overrides("Sleep study", Some(read));
+        assert_eq!(template.description, Some(description.to_string()));
+    }
+
+    #[test]
+    fn description_files_must_exist_and_be_utf8() {
+        let path = util::path::temp("description", ".txt").unwrap();
+        fs::write(&path, b"\xff\xfe not UTF-8").unwrap();
+        match read_description(&path).map_err(|e| e.kind().clone()) {
+            Err(ErrorKind::InvalidDescriptionFile { message }) => {
+                assert!(message.contains("utf-8"), "{}", message)
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        fs::remove_file(&path).unwrap();
+        assert!(read_description(&path).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Upload throughput and time remaining, as shown by the upload watcher.
+//! Both are estimated from how the progress of each import changes over
+//! time.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::fs;
+
+use time::Timespec;
+
+use crate::ps::agent::database::{UploadRecord, UploadStatus};
+
+/// How many samples of the progress of an import are kept. The throughput
+/// is averaged over them, so it follows the current rate of the upload
+/// rather than its rate since it began.
+const SAMPLE_WINDOW: usize = 10;
+
+/// The throughput of an upload and the time it has left.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Estimate {
+    /// Too little progress has been seen yet, as when the watcher starts.
+    Estimating,
+    Rate {
+        bytes_per_sec: f64,
+        remaining_secs: u64,
+    },
+}
+
+impl Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Estimate::Estimating => write!(f, "estimating…"),
+            Estimate::Rate {
+                bytes_per_sec,
+                remaining_secs,
+            } => write!(
+                f,
+                "{:.1} MB/s, ~{} min remaining",
+                bytes_per_sec / 1_000_000.0,
+                (remaining_secs + 59) / 60
+            ),
+        }
+    }
+}
+
+/// The last few samples of the progress of an upload, as the bytes
+/// uploaded by a time in seconds.
+#[derive(Clone, Debug, Default)]
+pub struct Throughput {
+    samples: VecDeque<(f64, u64)>,
+}
+
+impl Throughput {
+    /// Records that `bytes` were uploaded by `at`. A sample no later than
+    /// the last one is ignored, as the progress hasn't been updated since.
+    pub fn record(&mut self, at: Timespec, bytes: u64) {
+        let at = at.sec as f64 + f64::from(at.nsec) / 1e9;
+        if let Some(&(last, _)) = self.samples.back() {
+            if at <= last {
+                return;
+            }
+        }
+        if self.samples.len() == SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, bytes));
+    }
+
+    /// Estimates the throughput over the recorded samples, and the time
+    /// left to upload `total_bytes` at that rate.
+    pub fn estimate(&self, total_bytes: u64) -> Estimate {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(&(first_at, first_bytes)), Some(&(last_at, last_bytes)))
+                if last_at > first_at && last_bytes > first_bytes =>
+            {
+                let bytes_per_sec = (last_bytes - first_bytes) as f64 / (last_at - first_at);
+                let remaining = total_bytes.saturating_sub(last_bytes) as f64;
+                Estimate::Rate {
+                    bytes_per_sec,
+                    remaining_secs: (remaining / bytes_per_sec).ceil() as u64,
+                }
+            }
+            _ => Estimate::Estimating,
+        }
+    }
+}
+
+/// The throughput of every import the upload watcher has seen in progress,
+/// by import ID.
+#[derive(Debug, Default)]
+pub struct ImportThroughput {
+    imports: HashMap<String, (Throughput, u64)>,
+    // The sizes of the files seen so far, so they are only read once:
+    sizes: HashMap<String, u64>,
+}
+
+impl ImportThroughput {
+    /// Records the progress of every import that is in progress in
+    /// `uploads`.
+    pub fn update<'a, I>(&mut self, uploads: I)
+    where
+        I: IntoIterator<Item = &'a UploadRecord>,
+    {
+        let mut progress: HashMap<&str, (Timespec, u64, u64)> = HashMap::new();
+        for upload in uploads {
+            if upload.status != UploadStatus::InProgress {
+                continue;
+            }
+            let size = *self
+                .sizes
+                .entry(upload.file_path.clone())
+                .or_insert_with(|| fs::metadata(&upload.file_path).map_or(0, |m| m.len()));
+            let (at, bytes, total) =
+                progress
+                    .entry(upload.import_id.as_str())
+                    .or_insert((upload.updated_at, 0, 0));
+            *at = (*at).max(upload.updated_at);
+            *bytes += size * upload.progress.max(0).min(100) as u64 / 100;
+            *total += size;
+        }
+
+        for (import_id, (at, bytes, total)) in progress {
+            let (throughput, total_bytes) = self.imports.entry(import_id.to_string()).or_default();
+            throughput.record(at, bytes);
+            *total_bytes = total;
+        }
+    }
+
+    /// Estimates the throughput and time remaining of an import.
+    pub fn estimate(&self, import_id: &str) -> Estimate {
+        self.imports
+            .get(import_id)
+            .map_or(Estimate::Estimating, |(throughput, total_bytes)| {
+                throughput.estimate(*total_bytes)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use tempfile::tempdir;
+
+    fn at(sec: i64) -> Timespec {
+        Timespec::new(1_551_398_400 + sec, 0)
+    }
+
+    #[test]
+    fn throughput_needs_two_samples() {
+        let mut throughput = Throughput::default();
+        assert_eq!(throughput.estimate(1_000), Estimate::Estimating);
+        throughput.record(at(0), 100);
+        assert_eq!(throughput.estimate(1_000), Estimate::Estimating);
+        assert_eq!(throughput.estimate(1_000).to_string(), "estimating…");
+
+        // a sample that isn't newer adds nothing:
+        throughput.record(at(0), 300);
+        assert_eq!(throughput.estimate(1_000), Estimate::Estimating);
+    }
+
+    #[test]
+    fn throughput_and_time_remaining_follow_the_progress() {
+        let mut throughput = Throughput::default();
+        for (sec, bytes) in &[(0, 0), (2, 4_000_000), (4, 10_000_000)] {
+            throughput.record(at(*sec), *bytes);
+        }
+        let estimate = throughput.estimate(160_000_000);
+        assert_eq!(
+            estimate,
+            Estimate::Rate {
+                bytes_per_sec: 2_500_000.0,
+                remaining_secs: 60
+            }
+        );
+        assert_eq!(estimate.to_string(), "2.5 MB/s, ~1 min remaining");
+
+        // partial minutes are rounded up:
+        assert_eq!(
+            throughput.estimate(160_000_001).to_string(),
+            "2.5 MB/s, ~2 min remaining"
+        );
+    }
+
+    #[test]
+    fn throughput_is_averaged_over_the_latest_samples() {
+        let mut throughput = Throughput::default();
+        // slow to begin with, then 1 MB/s:
+        throughput.record(at(0), 0);
+        throughput.record(at(100), 1_000);
+        for sec in 1..=SAMPLE_WINDOW as i64 {
+            throughput.record(at(100 + sec), 1_000 + sec as u64 * 1_000_000);
+        }
+        match throughput.estimate(100_000_000) {
+            Estimate::Rate { bytes_per_sec, .. } => assert_eq!(bytes_per_sec, 1_000_000.0),
+            estimate => panic!("unexpected estimate {:?}", estimate),
+        }
+    }
+
+    #[test]
+    fn stalled_uploads_are_still_estimating() {
+        let mut throughput = Throughput::default();
+        throughput.record(at(0), 500);
+        throughput.record(at(10), 500);
+        assert_eq!(throughput.estimate(1_000), Estimate::Estimating);
+    }
+
+    #[test]
+    fn imports_are_estimated_from_the_size_and_progress_of_their_files() {
+        let dir = tempdir().unwrap();
+        let upload = |name: &str, import_id: &str, sec: i64, progress: i32| {
+            let path = dir.path().join(name);
+            fs::write(&path, vec![0; 1_000_000]).unwrap();
+            UploadRecord {
+                id: None,
+                file_path: path.display().to_string(),
+                dataset_id: "N:dataset:1".to_string(),
+                package_id: None,
+                import_id: import_id.to_string(),
+                progress,
+                status: UploadStatus::InProgress,
+                created_at: at(0),
+                updated_at: at(sec),
+                append: false,
+                upload_service: true,
+                organization_id: "N:organization:1".to_string(),
+                chunk_size: None,
+                multipart_upload_id: None,
+                priority: 0,
+                failure_reason: None,
+                tags: BTreeMap::new(),
+                last_error: None,
+                checksum: None,
+            }
+        };
+
+        let mut imports = ImportThroughput::default();
+        imports.update(&[
+            upload("a.bin", "import-1", 0, 0),
+            upload("b.bin", "import-1", 0, 0),
+        ]);
+        assert_eq!(imports.estimate("import-1"), Estimate::Estimating);
+        assert_eq!(imports.estimate("import-2"), Estimate::Estimating);
+
+        // half of 2 MB in 4 seconds:
+        imports.update(&[
+            upload("a.bin", "import-1", 4, 100),
+            upload("b.bin", "import-1", 3, 0),
+            upload("c.bin", "import-2", 4, 50),
+        ]);
+        assert_eq!(
+            imports.estimate("import-1"),
+            Estimate::Rate {
+                bytes_per_sec: 250_000.0,
+                remaining_secs: 4
+            }
+        );
+        assert_eq!(imports.estimate("import-2"), Estimate::Estimating);
+    }
+}
@@ -0,0 +1,431 @@
+//! Recursive traversal of dataset and collection hierarchies, as used by
+//! `ls --recursive`, `move` and `create-collection --path`.
+
+use std::mem;
+
+use futures::future;
+use futures::Future as _Future;
+
+use crate::ps::agent::cli::output::CliPackageTree;
+use crate::ps::agent::cli::{Error, Result};
+use crate::ps::agent::Future;
+use crate::ps::util::futures::*;
+
+/// Expands every collection beneath `root`, using `fetch` to look up the
+/// children of a collection by its ID. The children of `root` itself are
+/// expected to be populated already.
+///
+/// Nodes deeper than `max_depth` are dropped, and a collection that appears
+/// among its own ancestors is listed but not expanded again, so a cyclic
+/// hierarchy cannot cause an endless walk.
+pub fn expand<F>(root: CliPackageTree, max_depth: Option<usize>, fetch: F) -> Future<CliPackageTree>
+where
+    F: 'static + Send + Clone + Fn(String) -> Future<Vec<CliPackageTree>>,
+{
+    expand_node(root, 0, max_depth, vec![], fetch)
+}
+
+/// Splits a collection path like "A/B/C" into its components, ignoring
+/// leading, trailing and repeated slashes.
+pub fn collection_path(path: &str) -> Result<Vec<String>> {
+    let components: Vec<String> = path
+        .split('/')
+        .map(str::trim)
+        .filter(|component| !component.is_empty())
+        .map(String::from)
+        .collect();
+    if components.is_empty() {
+        Err(Error::invalid_collection_path(path))
+    } else {
+        Ok(components)
+    }
+}
+
+/// Finds or creates the nested collections named by `components` beneath
+/// `root`, whose children are expected to be populated already.
+///
+/// Each component is matched by name against the children of the one
+/// before it, which are looked up with `fetch`. Missing collections are
+/// created with `create`, given their name and the ID of the parent
+/// collection, or `None` at the root. A component that names a package
+/// other than a collection is an error.
+///
+/// Returns the leaf collection, along with how many collections were
+/// created.
+pub fn create_path<F, C>(
+    root: CliPackageTree,
+    components: Vec<String>,
+    fetch: F,
+    create: C,
+) -> Future<(CliPackageTree, usize)>
+where
+    F: 'static + Send + Clone + Fn(String) -> Future<Vec<CliPackageTree>>,
+    C: 'static + Send + Clone + Fn(String, Option<String>) -> Future<CliPackageTree>,
+{
+    if components.is_empty() {
+        return future::ok((root, 0)).into_trait();
+    }
+    create_level(root.children, None, components, 0, fetch, create)
+}
+
+fn create_level<F, C>(
+    children: Vec<CliPackageTree>,
+    parent: Option<String>,
+    mut components: Vec<String>,
+    created: usize,
+    fetch: F,
+    create: C,
+) -> Future<(CliPackageTree, usize)>
+where
+    F: 'static + Send + Clone + Fn(String) -> Future<Vec<CliPackageTree>>,
+    C: 'static + Send + Clone + Fn(String, Option<String>) -> Future<CliPackageTree>,
+{
+    let name = components.remove(0);
+    let existing = children
+        .iter()
+        .find(|child| child.name == name && child.is_collection())
+        .or_else(|| children.iter().find(|child| child.name == name))
+        .cloned();
+
+    let collection = match existing {
+        Some(package) if !package.is_collection() => {
+            return future::err(Error::not_a_collection(name, package.id).into()).into_trait();
+        }
+        Some(collection) => future::ok((collection, false)).into_trait(),
+        None => create(name, parent)
+            .map(|collection| (collection, true))
+            .into_trait(),
+    };
+
+    collection
+        .and_then(move |(collection, is_new)| {
+            let created = if is_new { created + 1 } else { created };
+            if components.is_empty() {
+                return future::ok((collection, created)).into_trait();
+            }
+            // a collection that was just created has no children to look up:
+            let children = if is_new {
+                future::ok(vec![]).into_trait()
+            } else {
+                fetch(collection.id.clone())
+            };
+            let parent = Some(collection.id);
+            children
+                .and_then(move |children| {
+                    create_level(children, parent, components, created, fetch, create)
+                })
+                .into_trait()
+        })
+        .into_trait()
+}
+
+fn expand_node<F>(
+    mut node: CliPackageTree,
+    depth: usize,
+    max_depth: Option<usize>,
+    mut ancestors: Vec<String>,
+    fetch: F,
+) -> Future<CliPackageTree>
+where
+    F: 'static + Send + Clone + Fn(String) -> Future<Vec<CliPackageTree>>,
+{
+    if max_depth.map_or(false, |max_depth| depth >= max_depth) || ancestors.contains(&node.id) {
+        node.children.clear();
+        return future::ok(node).into_trait();
+    }
+    ancestors.push(node.id.clone());
+
+    let children = if depth == 0 {
+        future::ok(mem::replace(&mut node.children, vec![])).into_trait()
+    } else if node.is_collection() {
+        fetch(node.id.clone())
+    } else {
+        return future::ok(node).into_trait();
+    };
+
+    children
+        .and_then(move |mut children| {
+            children.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+            let expanded = children
+                .into_iter()
+                .map(|child| {
+                    expand_node(
+                        child,
+                        depth + 1,
+                        max_depth,
+                        ancestors.clone(),
+                        fetch.clone(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            future::join_all(expanded).map(move |children| node.with_children(children))
+        })
+        .into_trait()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::ps::agent;
+    use crate::ps::agent::cli::{ErrorKind, PackageTypeFilter};
+
+    fn collection(name: &str, id: &str) -> CliPackageTree {
+        CliPackageTree::new(name, id, "Collection")
+    }
+
+    fn package(name: &str, id: &str, package_type: &str) -> CliPackageTree {
+        CliPackageTree::new(name, id, package_type)
+    }
+
+    /// Returns a `fetch` function that serves the children of each
+    /// collection from a fixed map, standing in for the Pennsieve API.
+    fn mock_api(
+        hierarchy: HashMap<String, Vec<CliPackageTree>>,
+    ) -> impl Fn(String) -> Future<Vec<CliPackageTree>> + Clone + Send + 'static {
+        let hierarchy = Arc::new(hierarchy);
+        move |id: String| {
+            future::ok(hierarchy.get(&id).cloned().unwrap_or_else(Vec::new)).into_trait()
+        }
+    }
+
+    /// Returns a `create` function that hands out new collection IDs and
+    /// records the (name, parent) of every collection it creates.
+    fn mock_create(
+        created: Arc<Mutex<Vec<(String, Option<String>)>>>,
+    ) -> impl Fn(String, Option<String>) -> Future<CliPackageTree> + Clone + Send + 'static {
+        move |name: String, parent: Option<String>| {
+            let mut created = created.lock().unwrap();
+            created.push((name.clone(), parent));
+            let id = format!("N:collection:new-{}", created.len());
+            future::ok(collection(&name, &id)).into_trait()
+        }
+    }
+
+    fn dataset() -> CliPackageTree {
+        CliPackageTree::new("Samples", "N:dataset:1", "Dataset").with_children(vec![
+            package("readme.txt", "N:package:1", "Text"),
+            collection("Subjects", "N:collection:1"),
+        ])
+    }
+
+    fn hierarchy() -> HashMap<String, Vec<CliPackageTree>> {
+        let mut hierarchy = HashMap::new();
+        hierarchy.insert(
+            "N:collection:1".to_string(),
+            vec![
+                collection("subject-2", "N:collection:3"),
+                collection("subject-1", "N:collection:2"),
+            ],
+        );
+        hierarchy.insert(
+            "N:collection:2".to_string(),
+            vec![package("recording.edf", "N:package:2", "TimeSeries")],
+        );
+        hierarchy.insert(
+            "N:collection:3".to_string(),
+            vec![
+                package("scan.nii", "N:package:3", "MRI"),
+                // A collection that points back at its ancestor:
+                collection("Subjects", "N:collection:1"),
+            ],
+        );
+        hierarchy
+    }
+
+    #[test]
+    fn expands_multi_level_hierarchy_in_order() {
+        let tree = expand(dataset(), None, mock_api(hierarchy()))
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            tree.to_string(),
+            vec![
+                "Samples (Dataset) N:dataset:1",
+                "  readme.txt (Text) N:package:1",
+                "  Subjects (Collection) N:collection:1",
+                "    subject-1 (Collection) N:collection:2",
+                "      recording.edf (TimeSeries) N:package:2",
+                "    subject-2 (Collection) N:collection:3",
+                "      scan.nii (MRI) N:package:3",
+                "      Subjects (Collection) N:collection:1",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn max_depth_limits_expansion() {
+        let tree = expand(dataset(), Some(2), mock_api(hierarchy()))
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            tree.to_string(),
+            vec![
+                "Samples (Dataset) N:dataset:1",
+                "  readme.txt (Text) N:package:1",
+                "  Subjects (Collection) N:collection:1",
+                "    subject-1 (Collection) N:collection:2",
+                "    subject-2 (Collection) N:collection:3",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn descendants_of_a_collection_are_counted() {
+        let subject = expand(
+            collection("subject-1", "N:collection:2")
+                .with_children(hierarchy()["N:collection:2"].clone()),
+            None,
+            mock_api(hierarchy()),
+        )
+        .wait()
+        .unwrap();
+
+        assert_eq!(subject.descendant_count(), 1);
+    }
+
+    #[test]
+    fn json_output_is_nested() {
+        let tree = expand(dataset(), Some(2), mock_api(hierarchy()))
+            .wait()
+            .unwrap();
+        let json = serde_json::to_value(&tree).unwrap();
+
+        assert_eq!(json["name"], "Samples");
+        assert_eq!(json["children"][1]["name"], "Subjects");
+        assert_eq!(json["children"][1]["children"][0]["name"], "subject-1");
+        assert_eq!(
+            json["children"][1]["children"][0]["package_type"],
+            "Collection"
+        );
+    }
+
+    #[test]
+    fn listings_are_filtered_by_package_type() {
+        let listing = collection("Samples", "N:collection:1").with_children(vec![
+            collection("Subjects", "N:collection:2").with_children(vec![package(
+                "notes.txt",
+                "N:package:3",
+                "Text",
+            )]),
+            package("night-1.edf", "N:package:1", "TimeSeries"),
+            package("scan.nii", "N:package:2", "MRI"),
+        ]);
+        let filter = PackageTypeFilter::new(&["timeseries", "collection"]).unwrap();
+        let json = serde_json::to_value(&listing.filter_types(&filter)).unwrap();
+
+        assert_eq!(json["children"].as_array().unwrap().len(), 2);
+        assert_eq!(json["children"][0]["name"], "Subjects");
+        // only the top level is filtered:
+        assert_eq!(json["children"][0]["children"][0]["name"], "notes.txt");
+        assert_eq!(json["children"][1]["name"], "night-1.edf");
+    }
+
+    #[test]
+    fn collection_paths_are_split_into_components() {
+        assert_eq!(
+            collection_path("/A//B/C/").unwrap(),
+            vec!["A".to_string(), "B".to_string(), "C".to_string()]
+        );
+        assert_eq!(collection_path("Subjects").unwrap(), vec!["Subjects"]);
+        assert_eq!(
+            collection_path(" / ").unwrap_err().kind(),
+            &ErrorKind::InvalidCollectionPath {
+                path: " / ".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn creates_a_fully_new_path() {
+        let created = Arc::new(Mutex::new(vec![]));
+        let (leaf, count) = create_path(
+            dataset(),
+            collection_path("A/B/C").unwrap(),
+            mock_api(hierarchy()),
+            mock_create(Arc::clone(&created)),
+        )
+        .wait()
+        .unwrap();
+
+        assert_eq!(count, 3);
+        assert_eq!(leaf.name, "C");
+        assert_eq!(leaf.id, "N:collection:new-3");
+        assert_eq!(
+            *created.lock().unwrap(),
+            vec![
+                ("A".to_string(), None),
+                ("B".to_string(), Some("N:collection:new-1".to_string())),
+                ("C".to_string(), Some("N:collection:new-2".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_the_existing_part_of_a_path() {
+        let created = Arc::new(Mutex::new(vec![]));
+        let (leaf, count) = create_path(
+            dataset(),
+            collection_path("Subjects/subject-1/session-1").unwrap(),
+            mock_api(hierarchy()),
+            mock_create(Arc::clone(&created)),
+        )
+        .wait()
+        .unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(leaf.id, "N:collection:new-1");
+        assert_eq!(
+            *created.lock().unwrap(),
+            vec![("session-1".to_string(), Some("N:collection:2".to_string()))]
+        );
+
+        // A path that exists entirely creates nothing:
+        let (leaf, count) = create_path(
+            dataset(),
+            collection_path("Subjects/subject-2").unwrap(),
+            mock_api(hierarchy()),
+            mock_create(Arc::clone(&created)),
+        )
+        .wait()
+        .unwrap();
+        assert_eq!(count, 0);
+        assert_eq!(leaf.id, "N:collection:3");
+        assert_eq!(created.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn a_package_in_the_path_is_refused() {
+        let created = Arc::new(Mutex::new(vec![]));
+        let result = create_path(
+            dataset(),
+            collection_path("Subjects/subject-1/recording.edf/notes").unwrap(),
+            mock_api(hierarchy()),
+            mock_create(Arc::clone(&created)),
+        )
+        .wait();
+
+        match result {
+            Err(e) => assert_eq!(
+                e.kind(),
+                &agent::ErrorKind::CliError {
+                    kind: ErrorKind::NotACollection {
+                        name: "recording.edf".to_string(),
+                        id: "N:package:2".to_string(),
+                    }
+                }
+            ),
+            Ok((leaf, _)) => panic!("expected an error, got: {:?}", leaf),
+        }
+        assert!(created.lock().unwrap().is_empty());
+    }
+}
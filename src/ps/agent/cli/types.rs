@@ -4,6 +4,8 @@ use std::fmt;
 
 use prettytable as pt;
 
+use crate::ps::agent::cli::{Error, Result};
+
 /// Creates a data table suitable for CLI display, a la
 ///
 /// ```rust,ignore
@@ -40,3 +42,143 @@ where
     build(&mut table);
     CliTable(table)
 }
+
+/// The name that chooses a table's ID column, whatever it is titled.
+const ID_COLUMN: &str = "id";
+
+/// The table columns chosen with `--columns`, in the order they are shown.
+/// Every column is shown when none were chosen.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Columns(Vec<String>);
+
+impl Columns {
+    /// Parses a comma separated list of column names, as given to
+    /// `--columns`.
+    pub fn parse(list: &str) -> Self {
+        Columns(
+            list.split(',')
+                .map(column_name)
+                .filter(|name| !name.is_empty())
+                .collect(),
+        )
+    }
+
+    /// Gets the positions of the chosen columns among `titles`, in the
+    /// order they were chosen, or of every column if none were. Fails on
+    /// the first name that isn't one of the columns.
+    ///
+    /// "id" chooses the first column of tables that title their ID column
+    /// after what it identifies, such as "DATASET".
+    pub fn select(&self, titles: &[&str]) -> Result<Vec<usize>> {
+        if self.0.is_empty() {
+            return Ok((0..titles.len()).collect());
+        }
+        let names: Vec<String> = titles.iter().map(|title| column_name(title)).collect();
+        self.0
+            .iter()
+            .map(|wanted| {
+                names
+                    .iter()
+                    .position(|name| name == wanted)
+                    .or_else(|| {
+                        if wanted == ID_COLUMN && !names.is_empty() {
+                            Some(0)
+                        } else {
+                            None
+                        }
+                    })
+                    .ok_or_else(|| Error::invalid_column(wanted.as_str(), names.join(", ")))
+            })
+            .collect()
+    }
+}
+
+/// The name a column is chosen by: its title in lower case, with words
+/// joined by underscores. "CREATED AT" is chosen as "created_at", and
+/// "% DONE" as "done".
+pub fn column_name(title: &str) -> String {
+    title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Creates a table showing the chosen `columns` of `rows`, whose cells are
+/// in the same order as `titles`.
+pub fn cli_table_with_columns(
+    titles: &[&str],
+    rows: Vec<Vec<String>>,
+    columns: &Columns,
+) -> Result<CliTable> {
+    let selected = columns.select(titles)?;
+    Ok(cli_table(
+        Some(selected.iter().map(|i| titles[*i]).collect()),
+        |t| {
+            for row in &rows {
+                t.add_row(pt::Row::new(
+                    selected
+                        .iter()
+                        .map(|i| pt::Cell::new(row[*i].as_ref()))
+                        .collect(),
+                ));
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ps::agent::cli::ErrorKind;
+
+    #[test]
+    fn column_names_are_normalized() {
+        assert_eq!(column_name("CREATED AT"), "created_at");
+        assert_eq!(column_name("% DONE"), "done");
+        assert_eq!(
+            Columns::parse(" ID, Created-At,,status"),
+            Columns(vec![
+                "id".to_string(),
+                "created_at".to_string(),
+                "status".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn columns_are_selected_in_the_order_given() {
+        let titles = ["ID", "FILE", "STATUS"];
+        assert_eq!(Columns::default().select(&titles).unwrap(), vec![0, 1, 2]);
+        assert_eq!(
+            Columns::parse("status,id").select(&titles).unwrap(),
+            vec![2, 0]
+        );
+        match Columns::parse("id,size")
+            .select(&titles)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidColumn { column, expected } => {
+                assert_eq!(column, "size");
+                assert_eq!(expected, "id, file, status");
+            }
+            kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn id_chooses_the_id_column_whatever_its_title() {
+        let titles = ["DATASET", "NAME", "STATUS"];
+        assert_eq!(
+            Columns::parse("id,status").select(&titles).unwrap(),
+            vec![0, 2]
+        );
+        assert_eq!(Columns::parse("dataset").select(&titles).unwrap(), vec![0]);
+
+        let titles = ["LAST NAME", "FIRST NAME", "EMAIL", "ID"];
+        assert_eq!(Columns::parse("id").select(&titles).unwrap(), vec![3]);
+    }
+}
@@ -0,0 +1,108 @@
+//! Changes made to a dataset with `update-dataset`.
+
+use serde_derive::Serialize;
+
+use crate::ps::agent::cli::{ErrorKind, Result};
+
+/// The fields of a dataset that `update-dataset` changes. Fields that are
+/// `None` were not given, and are left as they are.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DatasetUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+}
+
+impl DatasetUpdate {
+    /// Creates an update of the given fields, failing if none were given.
+    pub fn new(
+        name: Option<String>,
+        description: Option<String>,
+        tags: Option<Vec<String>>,
+    ) -> Result<Self> {
+        let update = Self {
+            name,
+            description,
+            tags,
+        };
+        if update == Self::default() {
+            Err(ErrorKind::NothingToUpdate.into())
+        } else {
+            Ok(update)
+        }
+    }
+
+    /// Tests if the name or description change. Both are sent in the same
+    /// request, which leaves out the description if it doesn't change.
+    pub fn changes_details(&self) -> bool {
+        self.name.is_some() || self.description.is_some()
+    }
+
+    /// Tests if the tags change. They are sent in a request of their own.
+    pub fn changes_tags(&self) -> bool {
+        self.tags.is_some()
+    }
+
+    /// The names of the fields that change, as printed in the confirmation.
+    pub fn fields(&self) -> Vec<&'static str> {
+        let mut fields = vec![];
+        if self.name.is_some() {
+            fields.push("name");
+        }
+        if self.description.is_some() {
+            fields.push("description");
+        }
+        if self.tags.is_some() {
+            fields.push("tags");
+        }
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_partial_update_only_changes_the_given_fields() {
+        let update = DatasetUpdate::new(
+            None,
+            Some("EEG recordings\nof 12 subjects".to_string()),
+            None,
+        )
+        .unwrap();
+        assert!(update.changes_details());
+        assert!(!update.changes_tags());
+        assert_eq!(update.fields(), vec!["description"]);
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            serde_json::json!({"description": "EEG recordings\nof 12 subjects"})
+        );
+
+        let update = DatasetUpdate::new(
+            None,
+            None,
+            Some(vec!["eeg".to_string(), "sleep".to_string()]),
+        )
+        .unwrap();
+        assert!(!update.changes_details());
+        assert!(update.changes_tags());
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            serde_json::json!({"tags": ["eeg", "sleep"]})
+        );
+    }
+
+    #[test]
+    fn an_update_without_fields_is_refused() {
+        assert_eq!(
+            DatasetUpdate::new(None, None, None)
+                .err()
+                .map(|e| e.kind().clone()),
+            Some(ErrorKind::NothingToUpdate)
+        );
+    }
+}
@@ -1,7 +1,11 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::result;
 use std::time::{Duration, Instant};
+use std::{cmp, iter};
 
 use actix::prelude::*;
 use futures::future;
@@ -16,13 +20,15 @@ use tokio::timer::Interval;
 use pennsieve_macros::try_future;
 
 use crate::ps::agent::config::constants::UPLOAD_PROGRESS_MAX_BARS;
-use crate::ps::agent::database::{Database, UploadRecords, UploadStatus};
+use crate::ps::agent::database::{Database, UploadRecord, UploadRecords, UploadStatus};
 use crate::ps::agent::messages::{Response, SystemShutdown, WorkerStartup};
 use crate::ps::agent::types::{OutputFormat, ServiceFuture, ServiceId, WithProps, Worker};
-use crate::ps::agent::{self, config, server, Future};
+use crate::ps::agent::{self, config, server, upload, Future};
 use crate::ps::util::actor as a;
 use crate::ps::util::futures::*;
 
+use super::throughput::ImportThroughput;
+use super::upload_manifest::write_manifest_file;
 use super::{Error, Result};
 
 lazy_static! {
@@ -32,6 +38,80 @@ lazy_static! {
     static ref ERROR_PROGRESS_BAR_STYLE: ProgressStyle = ProgressStyle::default_bar()
         .template(config::constants::UPLOAD_ERROR_PROGRESS_BAR_FORMAT)
         .progress_chars(config::constants::UPLOAD_PROGRESS_CHARACTERS);
+    static ref PLAIN_PROGRESS_BAR_STYLE: ProgressStyle = ProgressStyle::default_bar()
+        .template(config::constants::UPLOAD_PLAIN_PROGRESS_BAR_FORMAT)
+        .progress_chars(config::constants::UPLOAD_PROGRESS_CHARACTERS);
+}
+
+// Gets the style of a progress bar, drawn without color if `color` is
+// false. Failed uploads are told apart by their message in that case.
+fn progress_bar_style(color: bool, failed: bool) -> ProgressStyle {
+    match (color, failed) {
+        (false, _) => PLAIN_PROGRESS_BAR_STYLE.clone(),
+        (true, false) => PROGRESS_BAR_STYLE.clone(),
+        (true, true) => ERROR_PROGRESS_BAR_STYLE.clone(),
+    }
+}
+
+/// Draws a progress bar as plain text, laid out like the bars drawn in color.
+fn draw_plain_bar<W: Write>(
+    out: &mut W,
+    prefix: &str,
+    position: u64,
+    message: &str,
+) -> io::Result<()> {
+    let chars: Vec<char> = config::constants::UPLOAD_PROGRESS_CHARACTERS
+        .chars()
+        .collect();
+    let width = config::constants::UPLOAD_PLAIN_PROGRESS_BAR_WIDTH;
+    let filled = cmp::min(position, 100) as usize * width / 100;
+
+    let mut bar: String = iter::repeat(chars[0]).take(filled).collect();
+    if filled < width {
+        bar.push(chars[1]);
+        bar.extend(iter::repeat(chars[2]).take(width - filled - 1));
+    }
+
+    write!(out, "{:8} {} {:>4}% {}", prefix, bar, position, message)
+}
+
+/// A progress bar of the upload watcher. Bars in color are styled by
+/// indicatif, while plain bars are drawn by `draw_plain_bar`, so no style
+/// can end up in them.
+struct UploadBar {
+    bar: ProgressBar,
+    color: bool,
+    prefix: String,
+}
+
+impl UploadBar {
+    fn new(bar: ProgressBar, color: bool, prefix: String) -> Self {
+        bar.set_style(progress_bar_style(color, false));
+        Self { bar, color, prefix }
+    }
+
+    /// Draws the bar in red from now on, if it is drawn in color.
+    fn set_failed(&self) {
+        self.bar.set_style(progress_bar_style(self.color, true));
+    }
+
+    /// Updates the bar, replacing its prefix if one is given.
+    fn set(&mut self, prefix: Option<String>, position: u64, message: &str) {
+        if let Some(prefix) = prefix {
+            self.prefix = prefix;
+        }
+        self.bar.set_position(position);
+
+        if self.color {
+            self.bar.set_prefix(&self.prefix);
+            self.bar.set_message(message);
+        } else {
+            let mut line = Vec::new();
+            // writing to a `Vec` can't fail:
+            let _ = draw_plain_bar(&mut line, &self.prefix, position, message);
+            self.bar.set_message(&String::from_utf8_lossy(&line));
+        }
+    }
 }
 
 // key to identify the single bar used for displaying the progress of
@@ -45,9 +125,41 @@ thread_local! {
 
 /// The state of the progress display loop
 struct UpdateState {
-    bars: HashMap<String, ProgressBar>,
+    bars: HashMap<String, UploadBar>,
     upload_started_at: time::Timespec,
     mode: RenderMode,
+    throughput: ImportThroughput,
+}
+
+/// Requeues the failed uploads with the given IDs, returning the IDs of
+/// the uploads that were requeued followed by those that were skipped.
+/// Only failed uploads that were interrupted midway can be retried.
+pub fn requeue_failed(
+    db: &Database,
+    upload_ids: Vec<String>,
+) -> agent::Result<(Vec<String>, Vec<String>)> {
+    let mut requeued = vec![];
+    let mut skipped = vec![];
+    for id in upload_ids {
+        if db.resume_failed_upload(&id)? {
+            requeued.push(id);
+        } else {
+            skipped.push(id);
+        }
+    }
+    Ok((requeued, skipped))
+}
+
+/// Requeues every failed upload that can be retried, as `requeue_failed`
+/// does.
+pub fn requeue_all_failed(db: &Database) -> agent::Result<(Vec<String>, Vec<String>)> {
+    let upload_ids = db
+        .get_failed_uploads()?
+        .into_owned_iter()
+        .filter_map(|upload| upload.id)
+        .map(|id| id.to_string())
+        .collect();
+    requeue_failed(db, upload_ids)
 }
 
 /// An enumeration that controls watcher behavior when it first starts up.
@@ -80,6 +192,14 @@ impl StartMode {
             _ => false,
         }
     }
+
+    /// Gets the status port requested along with an empty upload queue.
+    pub fn status_port(self) -> Option<u16> {
+        match self {
+            StartMode::AllowEmptyQueue(port) => port,
+            _ => None,
+        }
+    }
 }
 
 impl Default for StartMode {
@@ -120,6 +240,32 @@ impl Default for StopMode {
     }
 }
 
+/// Gets the output format the upload watcher renders progress with. The
+/// animated progress bars of the rich format are only drawn on a terminal,
+/// and not at all with `--no-progress`; otherwise the watcher prints a line
+/// based status on every refresh.
+pub fn progress_output<F>(output: OutputFormat, no_progress: bool, is_terminal: F) -> OutputFormat
+where
+    F: FnOnce() -> bool,
+{
+    if output.is_rich() && (no_progress || !is_terminal()) {
+        OutputFormat::Simple
+    } else {
+        output
+    }
+}
+
+/// Tests if rich output should be drawn in color. Color is turned off by
+/// `--no-color`, by a non-empty `NO_COLOR` environment variable (see
+/// https://no-color.org), or when stdout is not a terminal.
+pub fn color_enabled<F>(no_color: bool, no_color_env: Option<OsString>, is_terminal: F) -> bool
+where
+    F: FnOnce() -> bool,
+{
+    let no_color_env = no_color_env.map_or(false, |value| !value.is_empty());
+    !no_color && !no_color_env && is_terminal()
+}
+
 /// An enumeration of all watcher rendering modes.
 ///
 /// # Variants
@@ -157,10 +303,14 @@ pub struct UploadWatcher;
 pub struct Props {
     pub db: Database,
     pub output: OutputFormat,
+    pub color: bool,
     pub interval_ms: u64,
-    pub parallelism: usize,
+    pub parallelism: upload::Parallelism,
     pub start_mode: StartMode,
     pub stop_mode: StopMode,
+    // Where to write a manifest of the uploaded files once uploading is
+    // complete, as given with `upload --manifest-output`:
+    pub manifest_output: Option<PathBuf>,
 }
 
 impl Actor for UploadWatcher {
@@ -203,20 +353,22 @@ impl Worker for UploadWatcher {
 }
 
 impl UploadWatcher {
-    /// Get the filepath of an upload in the given uploads list with
-    /// the status of InProgress
-    fn get_in_progress_upload(uploads: UploadRecords) -> Option<String> {
+    /// Get an upload in the given uploads list with the status of
+    /// InProgress
+    fn get_in_progress_upload(uploads: &UploadRecords) -> Option<&UploadRecord> {
         uploads
-            .into_iter()
-            .filter(|u| u.status == UploadStatus::InProgress && !u.is_file_upload_completed())
-            .map(|u| u.file_path.clone())
-            .next()
+            .iter()
+            .find(|u| u.status == UploadStatus::InProgress && !u.is_file_upload_completed())
     }
 
     /// A function that initializes the state of all progress bars
-    fn initialize_progress_bars(output: OutputFormat, uploads: UploadRecords) -> UpdateState {
+    fn initialize_progress_bars(
+        output: OutputFormat,
+        color: bool,
+        uploads: UploadRecords,
+    ) -> UpdateState {
         MULTI_PROGRESS_BAR.with(|multi| multi.replace(Some(MultiProgress::new())));
-        let mut bars: HashMap<String, ProgressBar> = HashMap::new();
+        let mut bars: HashMap<String, UploadBar> = HashMap::new();
         let total_uploads = uploads.len();
         let mode = RenderMode::get_mode(total_uploads);
 
@@ -232,12 +384,13 @@ impl UploadWatcher {
                     if let Some(ref mut mpb) = *multi.borrow_mut() {
                         for (i, u) in uploads.into_iter().enumerate() {
                             if output.is_rich() {
-                                let pb = mpb.add(ProgressBar::new(100));
-                                pb.set_style(PROGRESS_BAR_STYLE.clone());
-                                pb.set_prefix(&format!("[{}/{}]", i + 1, uploads.len()));
-                                pb.set_message(u.file_path.as_str());
-                                pb.set_position(u.progress as u64);
-                                bars.insert(u.file_path.clone(), pb);
+                                let mut bar = UploadBar::new(
+                                    mpb.add(ProgressBar::new(100)),
+                                    color,
+                                    format!("[{}/{}]", i + 1, uploads.len()),
+                                );
+                                bar.set(None, u.progress as u64, &u.file_path);
+                                bars.insert(u.file_path.clone(), bar);
                             } else {
                                 println!("- {}", u.summary());
                             }
@@ -255,11 +408,13 @@ impl UploadWatcher {
             RenderMode::ManyFiles => MULTI_PROGRESS_BAR.with(|multi| {
                 if let Some(ref mut mpb) = *multi.borrow_mut() {
                     if output.is_rich() {
-                        let pb = mpb.add(ProgressBar::new(100));
-                        pb.set_style(PROGRESS_BAR_STYLE.clone());
-                        pb.set_prefix(&format!("[{}/{}]", 0, total_uploads));
-                        pb.set_position(0 as u64);
-                        bars.insert(TOTAL_BAR_KEY.to_string(), pb);
+                        let mut bar = UploadBar::new(
+                            mpb.add(ProgressBar::new(100)),
+                            color,
+                            format!("[{}/{}]", 0, total_uploads),
+                        );
+                        bar.set(None, 0, "");
+                        bars.insert(TOTAL_BAR_KEY.to_string(), bar);
                     } else {
                         println!("[0/{} files uploaded]", total_uploads);
                         println!();
@@ -272,15 +427,19 @@ impl UploadWatcher {
             bars,
             upload_started_at,
             mode,
+            throughput: Default::default(),
         }
     }
 
-    /// A function that updates the state of all progress bars on each tick
+    /// A function that updates the state of all progress bars on each tick.
+    /// Once uploading is complete, a manifest of the uploaded files is
+    /// written to `manifest_output`, if given.
     fn update_progress_bars(
         db: &Database,
         output: OutputFormat,
         stop_mode: StopMode,
-        state: UpdateState,
+        manifest_output: Option<&Path>,
+        mut state: UpdateState,
     ) -> Result<UpdateState> {
         let uploads: UploadRecords =
             db.get_active_uploads_started_since(state.upload_started_at)?;
@@ -294,6 +453,10 @@ impl UploadWatcher {
             info!("Sending shutdown...");
             a::send_unconditionally::<server::StatusServer, _>(SystemShutdown);
 
+            if let Some(path) = manifest_output {
+                write_manifest_file(&uploads.records, path)?;
+            }
+
             let failed_uploads = uploads
                 .records
                 .into_iter()
@@ -315,23 +478,40 @@ impl UploadWatcher {
             }
         }
 
+        // The throughput of each import is estimated from how its progress
+        // changes from tick to tick:
+        state.throughput.update(uploads.iter());
+
         match state.mode {
             RenderMode::FewFiles => {
                 for u in &uploads {
+                    let uploading = u.is_in_progress() && !u.is_file_upload_completed();
                     if output.is_rich() {
-                        if let Some(progress_bar) = state.bars.get(&u.file_path) {
-                            progress_bar.set_position(u.progress as u64);
-
-                            if u.is_failed() {
-                                progress_bar.set_style(ERROR_PROGRESS_BAR_STYLE.clone());
-                                progress_bar
-                                    .set_message(&format!("{} (FAILED)", u.file_path.as_str()));
-                            }
+                        if let Some(progress_bar) = state.bars.get_mut(&u.file_path) {
+                            let message = if u.is_failed() {
+                                progress_bar.set_failed();
+                                format!("{} (FAILED)", u.file_path.as_str())
+                            } else if uploading {
+                                format!(
+                                    "{} ({})",
+                                    u.file_path,
+                                    state.throughput.estimate(&u.import_id)
+                                )
+                            } else {
+                                u.file_path.clone()
+                            };
+                            progress_bar.set(None, u.progress as u64, &message);
                         }
                     } else if u.is_failed() {
                         println!("- {} (FAILED)", u.summary());
                     } else if u.is_file_upload_completed() {
                         println!("- {} (done)", u.summary());
+                    } else if uploading {
+                        println!(
+                            "- {} ({})",
+                            u.summary(),
+                            state.throughput.estimate(&u.import_id)
+                        );
                     } else {
                         println!("- {}", u.summary());
                     }
@@ -349,6 +529,12 @@ impl UploadWatcher {
                 let failed_uploads =
                     uploads.iter().filter(|upload| upload.is_failed()).count() as u64;
                 let total_uploads = uploads.len();
+                // The throughput of the import the file being uploaded is
+                // part of:
+                let in_progress_upload = UploadWatcher::get_in_progress_upload(&uploads);
+                let estimate = in_progress_upload
+                    .map(|upload| format!(" ({})", state.throughput.estimate(&upload.import_id)))
+                    .unwrap_or_default();
 
                 if output.is_rich() {
                     let prefix = if failed_uploads == 0 {
@@ -361,17 +547,24 @@ impl UploadWatcher {
                     };
 
                     let percent_done = (completed_uploads as f64 / total_uploads as f64) * 100.0;
-                    let in_progress_upload = UploadWatcher::get_in_progress_upload(uploads);
-                    let progress_bar = &state.bars[TOTAL_BAR_KEY];
-                    progress_bar.set_position(percent_done as u64);
-                    progress_bar.set_prefix(&prefix);
-                    progress_bar.set_message(&in_progress_upload.unwrap_or_else(|| "".to_string()));
+                    if let Some(progress_bar) = state.bars.get_mut(TOTAL_BAR_KEY) {
+                        progress_bar.set(
+                            Some(prefix),
+                            percent_done as u64,
+                            &in_progress_upload
+                                .map(|upload| format!("{}{}", upload.file_path, estimate))
+                                .unwrap_or_else(|| "".to_string()),
+                        );
+                    }
                 } else if failed_uploads == 0 {
-                    println!("[{}/{} files uploaded]\n", completed_uploads, total_uploads);
+                    println!(
+                        "[{}/{} files uploaded]{}\n",
+                        completed_uploads, total_uploads, estimate
+                    );
                 } else {
                     println!(
-                        "[{}/{} files uploaded ({} failed)]\n",
-                        completed_uploads, total_uploads, failed_uploads
+                        "[{}/{} files uploaded ({} failed)]{}\n",
+                        completed_uploads, total_uploads, failed_uploads, estimate
                     );
                 }
             }
@@ -393,22 +586,30 @@ impl UploadWatcher {
 
         let db = props.db;
         let output = props.output;
+        let color = props.color;
         let interval_ms = props.interval_ms;
         let _start_mode = props.start_mode;
         let stop_mode = props.stop_mode;
+        let manifest_output = props.manifest_output;
 
         if stop_mode.never() {
             info!("Upload watcher in listening mode");
         }
 
         let uploads = try_future!(db.get_active_uploads());
-        let initial_state = Self::initialize_progress_bars(output, uploads);
+        let initial_state = Self::initialize_progress_bars(output, color, uploads);
 
         // Initiate a Future to update the state on every watch tick:
         let k = Interval::new(Instant::now(), Duration::from_millis(interval_ms))
             .map_err(Into::<Error>::into)
             .fold(initial_state, move |state, _tick| {
-                Self::update_progress_bars(&db, output, stop_mode, state)
+                Self::update_progress_bars(
+                    &db,
+                    output,
+                    stop_mode,
+                    manifest_output.as_deref(),
+                    state,
+                )
             });
 
         // Take ownership of the multiprogress bar exclusively.
@@ -442,6 +643,8 @@ impl UploadWatcher {
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+
     use crate::ps::agent::database::UploadRecord;
 
     use super::*;
@@ -463,6 +666,11 @@ mod test {
             organization_id: String::from("organization_id"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         }
     }
 
@@ -477,7 +685,8 @@ mod test {
             records: records.clone(),
         };
 
-        let initial_state = UploadWatcher::initialize_progress_bars(OutputFormat::Rich, uploads);
+        let initial_state =
+            UploadWatcher::initialize_progress_bars(OutputFormat::Rich, true, uploads);
 
         let mut expected_bars: Vec<String> = ids.map(|id| id.to_string()).collect();
         let mut actual_bars: Vec<String> = initial_state.bars.keys().map(|k| k.clone()).collect();
@@ -499,10 +708,103 @@ mod test {
             records: records.clone(),
         };
 
-        let initial_state = UploadWatcher::initialize_progress_bars(OutputFormat::Rich, uploads);
+        let initial_state =
+            UploadWatcher::initialize_progress_bars(OutputFormat::Rich, true, uploads);
 
         let actual_bars: Vec<String> = initial_state.bars.keys().map(|k| k.clone()).collect();
 
         assert_eq!(actual_bars, vec![TOTAL_BAR_KEY]);
     }
+
+    #[test]
+    fn progress_bars_are_only_drawn_on_a_terminal() {
+        assert_eq!(
+            progress_output(OutputFormat::Rich, false, || true),
+            OutputFormat::Rich
+        );
+        assert_eq!(
+            progress_output(OutputFormat::Rich, false, || false),
+            OutputFormat::Simple
+        );
+        // other formats never draw progress bars:
+        assert_eq!(
+            progress_output(OutputFormat::Json, false, || true),
+            OutputFormat::Json
+        );
+    }
+
+    #[test]
+    fn no_progress_forces_line_based_output() {
+        assert_eq!(
+            progress_output(OutputFormat::Rich, true, || true),
+            OutputFormat::Simple
+        );
+        assert_eq!(
+            progress_output(OutputFormat::Simple, true, || true),
+            OutputFormat::Simple
+        );
+    }
+
+    #[test]
+    fn color_is_off_with_no_color_or_off_a_terminal() {
+        assert!(color_enabled(false, None, || true));
+        assert!(!color_enabled(true, None, || true));
+        assert!(!color_enabled(false, None, || false));
+        assert!(!color_enabled(false, Some(OsString::from("1")), || true));
+        // an empty `NO_COLOR` is ignored:
+        assert!(color_enabled(false, Some(OsString::from("")), || true));
+    }
+
+    #[test]
+    fn plain_progress_bars_are_drawn_without_styles() {
+        for &(position, filled) in &[(0, 0), (42, 25), (100, 60)] {
+            let mut line = Vec::new();
+            draw_plain_bar(&mut line, "[1/2]", position, "a.txt (FAILED)").unwrap();
+            let line = String::from_utf8(line).unwrap();
+
+            assert!(!line.contains('\u{1b}'), "{:?}", line);
+            let mut bar = "#".repeat(filled);
+            if filled < 60 {
+                bar.push('>');
+                bar.push_str(&"-".repeat(59 - filled));
+            }
+            assert_eq!(
+                line,
+                format!("[1/2]    {} {:>4}% a.txt (FAILED)", bar, position)
+            );
+        }
+    }
+
+    #[test]
+    fn only_interrupted_failures_are_requeued() {
+        let db = crate::ps::util::database::temp().unwrap();
+        let ids: Vec<String> = [0, 40, 0, 75]
+            .iter()
+            .enumerate()
+            .map(|(i, progress)| {
+                let mut record = get_upload_record(i, UploadStatus::Failed);
+                record.progress = *progress;
+                db.insert_upload(&record).unwrap().to_string()
+            })
+            .collect();
+        let mut completed = get_upload_record(4, UploadStatus::Completed);
+        completed.progress = 100;
+        db.insert_upload(&completed).unwrap();
+
+        let (mut requeued, mut skipped) = requeue_all_failed(&db).unwrap();
+        requeued.sort();
+        skipped.sort();
+        assert_eq!(requeued, vec![ids[1].clone(), ids[3].clone()]);
+        assert_eq!(skipped, vec![ids[0].clone(), ids[2].clone()]);
+
+        let mut queued: Vec<String> = db
+            .get_queued_uploads()
+            .unwrap()
+            .into_owned_iter()
+            .map(|upload| upload.id.unwrap().to_string())
+            .collect();
+        queued.sort();
+        assert_eq!(queued, requeued);
+        assert_eq!(db.get_failed_uploads().unwrap().len(), 2);
+    }
 }
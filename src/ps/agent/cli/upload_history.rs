@@ -0,0 +1,644 @@
+//! Detailed views, summaries and exports of upload records, as printed by
+//! `upload-status --detail`, `upload-status --summary`,
+//! `upload-status --completed` and `upload-status --export`.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::str::FromStr;
+
+use pretty_bytes::converter::convert as human_bytes;
+use prettytable::row;
+use serde_derive::Serialize;
+use time::Timespec;
+
+use crate::ps::agent::cli::{self, output, Error};
+use crate::ps::agent::database::{Database, UploadRecord, UploadStatus};
+use crate::ps::agent::{self, OutputFormat};
+use crate::ps::util::temporal::timespec_to_rfc3339;
+
+/// Every field of an upload record, as written in JSON output.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UploadFields {
+    pub id: Option<i64>,
+    pub file_path: String,
+    pub dataset_id: String,
+    pub package_id: Option<String>,
+    pub import_id: String,
+    pub progress: i32,
+    pub status: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub append: bool,
+    pub upload_service: bool,
+    pub organization_id: String,
+    pub chunk_size: Option<u64>,
+    pub multipart_upload_id: Option<String>,
+    pub priority: i32,
+    pub failure_reason: Option<String>,
+    pub tags: BTreeMap<String, String>,
+    pub last_error: Option<String>,
+}
+
+impl<'a> From<&'a UploadRecord> for UploadFields {
+    fn from(record: &'a UploadRecord) -> Self {
+        Self {
+            id: record.id,
+            file_path: record.file_path.clone(),
+            dataset_id: record.dataset_id.clone(),
+            package_id: record.package_id.clone(),
+            import_id: record.import_id.clone(),
+            progress: record.progress,
+            status: record.status.as_ref().to_string(),
+            created_at: timespec_to_rfc3339(record.created_at).into(),
+            updated_at: timespec_to_rfc3339(record.updated_at).into(),
+            append: record.append,
+            upload_service: record.upload_service,
+            organization_id: record.organization_id.clone(),
+            chunk_size: record.chunk_size,
+            multipart_upload_id: record.multipart_upload_id.clone(),
+            priority: record.priority,
+            failure_reason: record.failure_reason.clone(),
+            tags: record.tags.clone(),
+            last_error: record.last_error.clone(),
+        }
+    }
+}
+
+impl UploadFields {
+    /// The tags as given to `upload --tag`: `key=value` pairs, separated
+    /// by commas.
+    fn tag_list(&self) -> String {
+        self.tags
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// The fields as (name, value) pairs, with missing values shown as
+    /// "N/A".
+    fn rows(&self) -> Vec<(&'static str, String)> {
+        fn or_na<T: ToString>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "N/A".to_string())
+        }
+
+        vec![
+            ("ID", or_na(&self.id)),
+            ("FILE", self.file_path.clone()),
+            ("DATASET", self.dataset_id.clone()),
+            ("PACKAGE", or_na(&self.package_id)),
+            ("IMPORT ID", self.import_id.clone()),
+            ("STATUS", self.status.clone()),
+            ("% DONE", self.progress.to_string()),
+            ("CREATED AT", self.created_at.clone()),
+            ("UPDATED AT", self.updated_at.clone()),
+            ("APPEND", self.append.to_string()),
+            ("UPLOAD SERVICE", self.upload_service.to_string()),
+            ("ORGANIZATION ID", self.organization_id.clone()),
+            ("CHUNK SIZE", or_na(&self.chunk_size)),
+            ("MULTIPART UPLOAD ID", or_na(&self.multipart_upload_id)),
+            ("PRIORITY", self.priority.to_string()),
+            ("FAILURE REASON", or_na(&self.failure_reason)),
+            (
+                "TAGS",
+                Some(self.tag_list())
+                    .filter(|tags| !tags.is_empty())
+                    .unwrap_or_else(|| "N/A".to_string()),
+            ),
+            ("LAST ERROR", or_na(&self.last_error)),
+        ]
+    }
+}
+
+/// The columns of an upload export in CSV format, in the order of the
+/// fields of `UploadFields`.
+const CSV_HEADER: [&str; 18] = [
+    "id",
+    "file_path",
+    "dataset_id",
+    "package_id",
+    "import_id",
+    "progress",
+    "status",
+    "created_at",
+    "updated_at",
+    "append",
+    "upload_service",
+    "organization_id",
+    "chunk_size",
+    "multipart_upload_id",
+    "priority",
+    "failure_reason",
+    "tags",
+    "last_error",
+];
+
+/// The formats the upload history can be exported in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> cli::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(Error::invalid_export_format(s)),
+        }
+    }
+}
+
+/// Quotes a CSV value if it contains a delimiter, a quote or a line break.
+pub fn csv_value(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl UploadFields {
+    /// The fields as a CSV line, with missing values left empty.
+    fn csv_row(&self) -> String {
+        fn or_empty<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(ToString::to_string).unwrap_or_default()
+        }
+
+        [
+            or_empty(&self.id),
+            self.file_path.clone(),
+            self.dataset_id.clone(),
+            or_empty(&self.package_id),
+            self.import_id.clone(),
+            self.progress.to_string(),
+            self.status.clone(),
+            self.created_at.clone(),
+            self.updated_at.clone(),
+            self.append.to_string(),
+            self.upload_service.to_string(),
+            self.organization_id.clone(),
+            or_empty(&self.chunk_size),
+            or_empty(&self.multipart_upload_id),
+            self.priority.to_string(),
+            or_empty(&self.failure_reason),
+            self.tag_list(),
+            or_empty(&self.last_error),
+        ]
+        .iter()
+        .map(|value| csv_value(value))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+/// Writes every upload record, or only those with the given status, to
+/// `out`, one record at a time. Returns the number of records written.
+pub fn export_uploads<W: Write>(
+    db: &Database,
+    status: Option<UploadStatus>,
+    format: ExportFormat,
+    mut out: W,
+) -> agent::Result<usize> {
+    match format {
+        ExportFormat::Csv => {
+            writeln!(out, "{}", CSV_HEADER.join(","))?;
+            db.get_all_uploads(status, |record| -> agent::Result<()> {
+                writeln!(out, "{}", UploadFields::from(&record).csv_row())?;
+                Ok(())
+            })
+        }
+        ExportFormat::Json => {
+            write!(out, "[")?;
+            let mut first = true;
+            let count = db.get_all_uploads(status, |record| -> agent::Result<()> {
+                if !first {
+                    write!(out, ",")?;
+                }
+                first = false;
+                write!(out, "\n  ")?;
+                serde_json::to_writer(&mut out, &UploadFields::from(&record))?;
+                Ok(())
+            })?;
+            writeln!(out, "{}]", if count > 0 { "\n" } else { "" })?;
+            Ok(count)
+        }
+    }
+}
+
+/// Notes what the last error of an upload can't tell, shown under its
+/// details.
+const LAST_ERROR_NOTE: &str = "LAST ERROR is the error of the last attempt at the whole \
+     upload that failed or stalled. Chunks are retried by the Pennsieve client itself, so \
+     errors of chunks that succeeded when retried are not recorded.";
+
+/// Renders every stored field of the upload with the given ID.
+pub fn upload_detail(
+    db: &Database,
+    upload_id: usize,
+    output: OutputFormat,
+) -> agent::Result<String> {
+    let fields = UploadFields::from(&db.get_upload_by_upload_id(upload_id)?);
+    if output.is_json() {
+        Ok(serde_json::to_string_pretty(&fields)?)
+    } else {
+        let rows = fields.rows();
+        let table = cli::table(None as Option<Vec<&str>>, |t| {
+            for (name, value) in &rows {
+                t.add_row(row![name, value]);
+            }
+        });
+        Ok(format!("{}\n{}", table, LAST_ERROR_NOTE))
+    }
+}
+
+/// Renders the completed uploads last updated between `since` and `until`,
+/// most recent first, keeping at most `num` of them. Tables show only the
+/// chosen `columns`.
+pub fn completed_uploads(
+    db: &Database,
+    num: Option<usize>,
+    since: Option<Timespec>,
+    until: Option<Timespec>,
+    output: OutputFormat,
+    columns: &cli::Columns,
+) -> agent::Result<String> {
+    let mut uploads = match (num, since, until) {
+        (Some(num), None, None) => db.get_completed_uploads(num)?,
+        _ => db.get_completed_uploads_between(since, until)?,
+    };
+    if let Some(num) = num {
+        uploads.records.truncate(num);
+    }
+
+    if output.is_json() {
+        let fields: Vec<UploadFields> = uploads.iter().map(UploadFields::from).collect();
+        Ok(serde_json::to_string_pretty(&fields)?)
+    } else if uploads.is_empty() {
+        Ok("No completed uploads".to_string())
+    } else {
+        Ok(format!(
+            "{}\n",
+            output::CliUploadRecords::from(uploads).table(columns)?
+        ))
+    }
+}
+
+/// An overview of the upload queue.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct UploadSummary {
+    pub queued: usize,
+    pub in_progress: usize,
+    pub completed: usize,
+    pub failed: usize,
+    pub pending_bytes: u64,
+    // How long the oldest queued upload has been waiting, in seconds:
+    pub oldest_queued_age_secs: Option<i64>,
+}
+
+impl UploadSummary {
+    /// Summarizes the upload records in `db`, measuring the age of the
+    /// oldest queued upload up to `now`.
+    pub fn from_db(db: &Database, now: Timespec) -> agent::Result<Self> {
+        let counts = db.count_uploads_by_status()?;
+        let count = |status| counts.get(&status).cloned().unwrap_or(0);
+        let oldest_queued = db
+            .get_queued_uploads()?
+            .iter()
+            .map(|upload| upload.created_at)
+            .min();
+
+        Ok(Self {
+            queued: count(UploadStatus::Queued),
+            in_progress: count(UploadStatus::InProgress),
+            completed: count(UploadStatus::Completed),
+            failed: count(UploadStatus::Failed),
+            pending_bytes: db.sum_pending_bytes()?,
+            oldest_queued_age_secs: oldest_queued
+                .map(|created_at| (now - created_at).num_seconds().max(0)),
+        })
+    }
+}
+
+/// Formats a number of seconds as hours, minutes and seconds, leaving out
+/// leading units that are zero.
+fn format_age(secs: i64) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+impl Display for UploadSummary {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        cli::table(Some(vec!["STATUS", "UPLOADS"]), |t| {
+            t.add_row(row!["QUEUED", self.queued]);
+            t.add_row(row!["IN PROGRESS", self.in_progress]);
+            t.add_row(row!["COMPLETED", self.completed]);
+            t.add_row(row!["FAILED", self.failed]);
+        })
+        .fmt(fmt)?;
+        writeln!(fmt, "Pending: {}", human_bytes(self.pending_bytes as f64))?;
+        match self.oldest_queued_age_secs {
+            Some(secs) => write!(fmt, "Oldest queued upload: {} ago", format_age(secs)),
+            None => write!(fmt, "Oldest queued upload: none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::Value as JSON;
+
+    use crate::ps::agent::database;
+    use crate::ps::util;
+
+    fn insert_record(db: &Database) -> usize {
+        let mut record = UploadRecord::new(
+            "/data/recording.edf",
+            "N:dataset:1",
+            Some("N:package:1"),
+            "N:organization:1",
+            "import-1",
+            false,
+            Some(5_242_880),
+            Some("multipart-1".to_string()),
+        )
+        .unwrap();
+        record.progress = 40;
+        record.status = database::UploadStatus::InProgress;
+        db.insert_upload(&record).unwrap() as usize
+    }
+
+    #[test]
+    fn summary_counts_every_status_and_ages_the_oldest_queued_upload() {
+        let db = util::database::temp().unwrap();
+        let now = time::now().to_timespec();
+        let mut record = db.get_upload_by_upload_id(insert_record(&db)).unwrap();
+        record.id = None;
+        for (status, age_secs) in &[
+            (UploadStatus::Queued, 90),
+            (UploadStatus::Queued, 3_725),
+            (UploadStatus::Completed, 10),
+            (UploadStatus::Failed, 10),
+            (UploadStatus::Failed, 20_000),
+        ] {
+            record.status = *status;
+            record.created_at = now - time::Duration::seconds(*age_secs);
+            db.insert_upload(&record).unwrap();
+        }
+
+        let summary = UploadSummary::from_db(&db, now).unwrap();
+        assert_eq!(summary.queued, 2);
+        assert_eq!(summary.in_progress, 1);
+        assert_eq!(summary.completed, 1);
+        assert_eq!(summary.failed, 2);
+        // the files don't exist:
+        assert_eq!(summary.pending_bytes, 0);
+        assert_eq!(summary.oldest_queued_age_secs, Some(3_725));
+        assert!(summary
+            .to_string()
+            .contains("Oldest queued upload: 1h 2m 5s ago"));
+
+        let json: JSON = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["queued"], 2);
+        assert_eq!(json["in_progress"], 1);
+        assert_eq!(json["oldest_queued_age_secs"], 3_725);
+    }
+
+    #[test]
+    fn summary_of_an_empty_queue_has_no_oldest_upload() {
+        let db = util::database::temp().unwrap();
+        let summary = UploadSummary::from_db(&db, time::now().to_timespec()).unwrap();
+        assert_eq!(summary, UploadSummary::default());
+        assert!(summary.to_string().contains("Oldest queued upload: none"));
+    }
+
+    #[test]
+    fn detail_includes_every_stored_field() {
+        let db = util::database::temp().unwrap();
+        let id = insert_record(&db);
+
+        let json: JSON =
+            serde_json::from_str(&upload_detail(&db, id, OutputFormat::Json).unwrap()).unwrap();
+        let fields = json.as_object().unwrap();
+        assert_eq!(fields.len(), 18);
+        assert_eq!(json["id"], id as i64);
+        assert_eq!(json["multipart_upload_id"], "multipart-1");
+        assert_eq!(json["chunk_size"], 5_242_880);
+        assert_eq!(json["upload_service"], true);
+        assert_eq!(json["organization_id"], "N:organization:1");
+        assert_eq!(json["status"], "in_progress");
+        assert_eq!(json["progress"], 40);
+        assert_eq!(json["failure_reason"], JSON::Null);
+        assert_eq!(json["tags"], serde_json::json!({}));
+        assert_eq!(json["last_error"], JSON::Null);
+
+        let table = upload_detail(&db, id, OutputFormat::Simple).unwrap();
+        assert!(table.contains("MULTIPART UPLOAD ID"));
+        assert!(table.contains("multipart-1"));
+        assert!(table.contains("5242880"));
+    }
+
+    #[test]
+    fn detail_includes_the_failure_reason() {
+        let db = util::database::temp().unwrap();
+        let id = insert_record(&db);
+        db.fail_import("import-1", "exceeded retry window", None)
+            .unwrap();
+
+        let json: JSON =
+            serde_json::from_str(&upload_detail(&db, id, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["failure_reason"], "exceeded retry window");
+
+        let table = upload_detail(&db, id, OutputFormat::Simple).unwrap();
+        assert!(table.contains("FAILURE REASON"));
+        assert!(table.contains("exceeded retry window"));
+    }
+
+    #[test]
+    fn detail_includes_the_last_error() {
+        let db = util::database::temp().unwrap();
+        let id = insert_record(&db);
+        db.record_upload_error("import-1", "connection reset by peer")
+            .unwrap();
+
+        let json: JSON =
+            serde_json::from_str(&upload_detail(&db, id, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["status"], "in_progress");
+        assert_eq!(json["failure_reason"], JSON::Null);
+        assert_eq!(json["last_error"], "connection reset by peer");
+
+        let table = upload_detail(&db, id, OutputFormat::Simple).unwrap();
+        assert!(table.contains("LAST ERROR"));
+        assert!(table.contains("connection reset by peer"));
+        assert!(table.ends_with(LAST_ERROR_NOTE));
+    }
+
+    fn export(db: &Database, status: Option<UploadStatus>, format: ExportFormat) -> String {
+        let mut out = vec![];
+        export_uploads(db, status, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_export_has_a_header_and_every_column() {
+        let db = util::database::temp().unwrap();
+        let id = insert_record(&db);
+        let mut record = db.get_upload_by_upload_id(id).unwrap();
+        record.id = None;
+        record.file_path = "/data/notes, final.txt".to_string();
+        record.multipart_upload_id = None;
+        record.tags = vec![("subject", "rat"), ("experiment", "7")]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        db.insert_upload(&record).unwrap();
+
+        let csv = export(&db, None, ExportFormat::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "id,file_path,dataset_id,package_id,import_id,progress,status,created_at,\
+             updated_at,append,upload_service,organization_id,chunk_size,\
+             multipart_upload_id,priority,failure_reason,tags,last_error"
+        );
+        assert!(lines[1].starts_with(&format!("{},/data/recording.edf,N:dataset:1,", id)));
+        assert!(lines[1].ends_with(",N:organization:1,5242880,multipart-1,0,,,"));
+        assert!(lines[2].contains(",\"/data/notes, final.txt\","));
+        assert!(lines[2].ends_with(",5242880,,0,,\"experiment=7,subject=rat\","));
+    }
+
+    #[test]
+    fn json_export_contains_every_record() {
+        let db = util::database::temp().unwrap();
+        insert_record(&db);
+        let mut record = db.get_upload_by_upload_id(insert_record(&db)).unwrap();
+        record.id = None;
+        record.status = database::UploadStatus::Failed;
+        db.insert_upload(&record).unwrap();
+
+        let json: JSON = serde_json::from_str(&export(&db, None, ExportFormat::Json)).unwrap();
+        let records = json.as_array().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].as_object().unwrap().len(), 18);
+
+        let failed = export(
+            &db,
+            Some(database::UploadStatus::Failed),
+            ExportFormat::Json,
+        );
+        let json: JSON = serde_json::from_str(&failed).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 1);
+
+        let completed = export(
+            &db,
+            Some(database::UploadStatus::Completed),
+            ExportFormat::Json,
+        );
+        let json: JSON = serde_json::from_str(&completed).unwrap();
+        assert!(json.as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn completed_uploads_are_limited_within_the_range() {
+        let db = util::database::temp().unwrap();
+        let mut record = db.get_upload_by_upload_id(insert_record(&db)).unwrap();
+        record.id = None;
+        record.status = database::UploadStatus::Completed;
+        for hour in 0..4 {
+            record.updated_at = Timespec::new(1_551_398_400 + hour * 3600, 0);
+            db.insert_upload(&record).unwrap();
+        }
+        let since = Some(Timespec::new(1_551_398_400 + 3600, 0));
+
+        let json = completed_uploads(
+            &db,
+            Some(2),
+            since,
+            None,
+            OutputFormat::Json,
+            &cli::Columns::default(),
+        )
+        .unwrap();
+        let uploads: JSON = serde_json::from_str(&json).unwrap();
+        let updated: Vec<&str> = uploads
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|upload| upload["updated_at"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            updated,
+            vec!["2019-03-01T03:00:00+00:00", "2019-03-01T02:00:00+00:00"]
+        );
+
+        let json = completed_uploads(
+            &db,
+            None,
+            since,
+            since,
+            OutputFormat::Json,
+            &cli::Columns::default(),
+        )
+        .unwrap();
+        let uploads: JSON = serde_json::from_str(&json).unwrap();
+        assert_eq!(uploads.as_array().unwrap().len(), 1);
+
+        let until = Some(Timespec::new(1_551_398_400 - 1, 0));
+        assert_eq!(
+            completed_uploads(
+                &db,
+                None,
+                None,
+                until,
+                OutputFormat::Simple,
+                &cli::Columns::default()
+            )
+            .unwrap(),
+            "No completed uploads"
+        );
+        let json = completed_uploads(
+            &db,
+            None,
+            None,
+            until,
+            OutputFormat::Json,
+            &cli::Columns::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::from_str::<JSON>(&json).unwrap(),
+            JSON::Array(vec![])
+        );
+    }
+
+    #[test]
+    fn detail_of_a_missing_upload_is_an_error() {
+        let db = util::database::temp().unwrap();
+        match upload_detail(&db, 404, OutputFormat::Json) {
+            Err(e) => assert_eq!(
+                e.kind(),
+                &agent::ErrorKind::DatabaseError {
+                    kind: database::ErrorKind::UploadNotFound { upload_id: 404 }
+                }
+            ),
+            Ok(detail) => panic!("expected an error, got: {}", detail),
+        }
+    }
+}
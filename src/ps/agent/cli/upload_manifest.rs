@@ -0,0 +1,218 @@
+//! Manifests of the files an upload sent to the Pennsieve platform, as
+//! written with `upload --manifest-output` once the upload finishes.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use log::*;
+use serde_derive::Serialize;
+
+use crate::ps::agent::cli::upload_history::csv_value;
+use crate::ps::agent::cli::{ExportFormat, Result};
+use crate::ps::agent::database::UploadRecord;
+
+/// The columns of a manifest in CSV format, in the order of the fields of
+/// `UploadedFile`.
+const CSV_HEADER: [&str; 5] = [
+    "file_path",
+    "checksum",
+    "import_id",
+    "destination_id",
+    "dataset_id",
+];
+
+/// A file sent by a completed upload, as listed in the manifest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UploadedFile {
+    pub file_path: String,
+    /// The hash of the local file when it was queued, computed the way the
+    /// Pennsieve platform computes it, so it can be compared with
+    /// `upload-verify`. Missing if the file couldn't be read then.
+    pub checksum: Option<String>,
+    pub import_id: String,
+    /// The package the file was appended to, or the collection it was
+    /// uploaded into. Missing for uploads to the top of the dataset.
+    pub destination_id: Option<String>,
+    pub dataset_id: String,
+}
+
+impl<'a> From<&'a UploadRecord> for UploadedFile {
+    fn from(record: &'a UploadRecord) -> Self {
+        Self {
+            file_path: record.file_path.clone(),
+            checksum: record.checksum.clone(),
+            import_id: record.import_id.clone(),
+            destination_id: record.package_id.clone(),
+            dataset_id: record.dataset_id.clone(),
+        }
+    }
+}
+
+impl UploadedFile {
+    /// The fields as a CSV line, with missing values left empty.
+    fn csv_row(&self) -> String {
+        [
+            self.file_path.as_str(),
+            self.checksum.as_ref().map_or("", String::as_str),
+            self.import_id.as_str(),
+            self.destination_id.as_ref().map_or("", String::as_str),
+            self.dataset_id.as_str(),
+        ]
+        .iter()
+        .map(|value| csv_value(value))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+/// The format a manifest is written in: CSV if `path` ends in `.csv`, and
+/// JSON otherwise.
+pub fn manifest_format<P: AsRef<Path>>(path: P) -> ExportFormat {
+    match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => ExportFormat::Csv,
+        _ => ExportFormat::Json,
+    }
+}
+
+/// Writes the files of the completed `uploads` to `out`. Uploads that
+/// didn't complete are left out. Returns the number of files written.
+pub fn write_manifest<W: Write>(
+    uploads: &[UploadRecord],
+    format: ExportFormat,
+    mut out: W,
+) -> io::Result<usize> {
+    let files: Vec<UploadedFile> = uploads
+        .iter()
+        .filter(|upload| upload.is_package_completed())
+        .map(UploadedFile::from)
+        .collect();
+
+    match format {
+        ExportFormat::Csv => {
+            writeln!(out, "{}", CSV_HEADER.join(","))?;
+            for file in &files {
+                writeln!(out, "{}", file.csv_row())?;
+            }
+        }
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &files)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+
+    Ok(files.len())
+}
+
+/// Writes the manifest of the completed `uploads` to the file at `path`,
+/// in the format its extension calls for.
+pub fn write_manifest_file<P: AsRef<Path>>(uploads: &[UploadRecord], path: P) -> Result<usize> {
+    let path = path.as_ref();
+    let out = BufWriter::new(File::create(path)?);
+    let count = write_manifest(uploads, manifest_format(path), out)?;
+    info!("Wrote a manifest of {} uploaded files to {:?}", count, path);
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::Value as JSON;
+    use tempfile::tempdir;
+
+    use crate::ps::agent::cli::compute_hash;
+    use crate::ps::agent::database::UploadStatus;
+
+    /// Records an upload of a new file called `name` holding `contents`,
+    /// hashed as it is queued.
+    fn upload(dir: &Path, name: &str, contents: &[u8], status: UploadStatus) -> UploadRecord {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        let checksum = compute_hash(File::open(&path).unwrap(), 5_242_880).unwrap();
+        let mut record = UploadRecord::new(
+            &path,
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            Some(5_242_880),
+            None,
+        )
+        .unwrap()
+        .with_checksum(Some(checksum));
+        record.status = status;
+        record
+    }
+
+    #[test]
+    fn the_format_follows_the_extension() {
+        assert_eq!(manifest_format("uploaded.csv"), ExportFormat::Csv);
+        assert_eq!(manifest_format("uploaded.CSV"), ExportFormat::Csv);
+        assert_eq!(manifest_format("uploaded.json"), ExportFormat::Json);
+        assert_eq!(manifest_format("uploaded"), ExportFormat::Json);
+    }
+
+    #[test]
+    fn a_manifest_lists_every_completed_file() {
+        let dir = tempdir().unwrap();
+        let mut appended = upload(dir.path(), "b.edf", b"more data", UploadStatus::Completed);
+        appended.import_id = "import-2".to_string();
+        appended.package_id = Some("N:package:1".to_string());
+        let uploads = vec![
+            upload(dir.path(), "a.txt", b"data", UploadStatus::Completed),
+            appended,
+            upload(dir.path(), "c.txt", b"failed", UploadStatus::Failed),
+        ];
+
+        let path = dir.path().join("uploaded.json");
+        assert_eq!(write_manifest_file(&uploads, &path).unwrap(), 2);
+        let json: JSON = serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+        let files = json.as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["file_path"], uploads[0].file_path.as_str());
+        assert_eq!(
+            files[0]["checksum"],
+            compute_hash(File::open(&uploads[0].file_path).unwrap(), 5_242_880).unwrap()
+        );
+        assert_eq!(files[0]["import_id"], "import-1");
+        assert_eq!(files[0]["destination_id"], JSON::Null);
+        assert_eq!(files[0]["dataset_id"], "N:dataset:1");
+        assert_eq!(files[1]["import_id"], "import-2");
+        assert_eq!(files[1]["destination_id"], "N:package:1");
+
+        let path = dir.path().join("uploaded.csv");
+        assert_eq!(write_manifest_file(&uploads, &path).unwrap(), 2);
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "file_path,checksum,import_id,destination_id,dataset_id"
+        );
+        assert!(lines[1].starts_with(&format!("{},", uploads[0].file_path)));
+        assert!(lines[1].ends_with(",import-1,,N:dataset:1"));
+        assert!(lines[2].ends_with(",import-2,N:package:1,N:dataset:1"));
+    }
+
+    #[test]
+    fn checksums_are_those_of_the_files_as_queued() {
+        let dir = tempdir().unwrap();
+        let uploads = vec![
+            upload(dir.path(), "a.txt", b"data", UploadStatus::Completed),
+            upload(dir.path(), "b.txt", b"data", UploadStatus::Completed),
+        ];
+        let queued = uploads[0].checksum.clone().unwrap();
+        // Spooled files are removed, and others may change, once uploaded:
+        std::fs::remove_file(&uploads[0].file_path).unwrap();
+        std::fs::write(&uploads[1].file_path, b"edited").unwrap();
+
+        let mut out = vec![];
+        write_manifest(&uploads, ExportFormat::Json, &mut out).unwrap();
+        let json: JSON = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json[0]["checksum"], queued.as_str());
+        assert_eq!(json[1]["checksum"], queued.as_str());
+    }
+}
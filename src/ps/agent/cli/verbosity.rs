@@ -0,0 +1,97 @@
+//! The `--quiet` and `-v` global flags, which override the log level
+//! given by the `PENNSIEVE_LOG_LEVEL` and `LOGLEVEL` environment variables.
+
+use log::LevelFilter;
+
+/// How much the agent logs, as chosen on the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Verbosity {
+    pub quiet: bool,
+    pub verbose: usize,
+}
+
+impl Verbosity {
+    /// Finds the verbosity flags in the raw command line arguments.
+    ///
+    /// Logging is set up before the full argument parser can be built, so
+    /// the flags are found by hand: `-q`, `--quiet`, `--verbose`, and `-v`
+    /// repeated on its own, as in `-vv`.
+    pub fn from_args<S: AsRef<str>>(args: &[S]) -> Self {
+        let mut verbosity = Self::default();
+        for arg in args.iter().skip(1) {
+            match arg.as_ref() {
+                "--" => break,
+                "-q" | "--quiet" => verbosity.quiet = true,
+                "--verbose" => verbosity.verbose += 1,
+                arg if arg.len() > 1
+                    && arg.starts_with('-')
+                    && arg[1..].chars().all(|c| c == 'v') =>
+                {
+                    verbosity.verbose += arg.len() - 1
+                }
+                _ => (),
+            }
+        }
+        verbosity
+    }
+
+    /// The log level chosen by the flags, if any. `--quiet` logs errors
+    /// only and wins over `-v`, which logs at the info level, and `-vv`,
+    /// which logs at the debug level.
+    pub fn level_filter(&self) -> Option<LevelFilter> {
+        if self.quiet {
+            Some(LevelFilter::Error)
+        } else {
+            match self.verbose {
+                0 => None,
+                1 => Some(LevelFilter::Info),
+                _ => Some(LevelFilter::Debug),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(args: &[&str]) -> Option<LevelFilter> {
+        let mut argv = vec!["pennsieve"];
+        argv.extend(args);
+        Verbosity::from_args(&argv).level_filter()
+    }
+
+    #[test]
+    fn no_flags_defer_to_the_environment() {
+        assert_eq!(level(&[]), None);
+        assert_eq!(level(&["datasets"]), None);
+    }
+
+    #[test]
+    fn flags_map_to_level_filters() {
+        assert_eq!(level(&["--quiet", "datasets"]), Some(LevelFilter::Error));
+        assert_eq!(level(&["-q", "datasets"]), Some(LevelFilter::Error));
+        assert_eq!(level(&["-v", "datasets"]), Some(LevelFilter::Info));
+        assert_eq!(level(&["--verbose", "datasets"]), Some(LevelFilter::Info));
+        assert_eq!(level(&["-vv", "datasets"]), Some(LevelFilter::Debug));
+        assert_eq!(level(&["datasets", "-v", "-v"]), Some(LevelFilter::Debug));
+        assert_eq!(level(&["-vvv", "datasets"]), Some(LevelFilter::Debug));
+    }
+
+    #[test]
+    fn quiet_wins_over_verbose() {
+        assert_eq!(
+            level(&["-vv", "--quiet", "datasets"]),
+            Some(LevelFilter::Error)
+        );
+        assert_eq!(level(&["-q", "-v", "datasets"]), Some(LevelFilter::Error));
+    }
+
+    #[test]
+    fn other_arguments_are_ignored() {
+        assert_eq!(level(&["-V"]), None);
+        assert_eq!(level(&["-vf", "datasets"]), None);
+        assert_eq!(level(&["upload", "--", "-v"]), None);
+        assert_eq!(level(&["-", "datasets"]), None);
+    }
+}
@@ -0,0 +1,398 @@
+//! Verifying that local files match the files uploaded to the Pennsieve
+//! platform, as done by `upload-verify`.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use futures::Future as _Future;
+use futures::*;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::ps::agent::cli::{Error, ErrorKind, Result};
+use crate::ps::agent::database::{Error as DBError, UploadRecord};
+use crate::ps::agent::{self, Future};
+use crate::ps::util::futures::*;
+
+/// The outcome of verifying a batch of uploads.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifySummary {
+    pub passed: usize,
+    // Local files that differ from the uploaded file:
+    pub mismatched: Vec<PathBuf>,
+    // Local files that could not be checked, with the reason why:
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+impl VerifySummary {
+    /// The number of uploads that did not pass verification.
+    pub fn failed(&self) -> usize {
+        self.mismatched.len() + self.errors.len()
+    }
+
+    /// Tests if every upload passed verification.
+    pub fn is_ok(&self) -> bool {
+        self.failed() == 0
+    }
+}
+
+impl Display for VerifySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.passed + self.failed();
+        write!(
+            f,
+            "Verified {} {}: {} passed, {} failed",
+            total,
+            if total == 1 { "upload" } else { "uploads" },
+            self.passed,
+            self.failed()
+        )?;
+        for path in &self.mismatched {
+            write!(f, "\n  does not match: {:?}", path)?;
+        }
+        for (path, reason) in &self.errors {
+            write!(f, "\n  could not be verified: {:?} ({})", path, reason)?;
+        }
+        Ok(())
+    }
+}
+
+fn compute_multichunk_hash(mut file: File, chunk_size: u64) -> Result<String> {
+    let mut chunk_hashes: Vec<String> = vec![];
+    let mut total_bytes_read: u64 = 0;
+    let mut buffer = vec![0; chunk_size as usize];
+
+    // Multi-chunk case:
+
+    loop {
+        let mut hasher = Sha256::new();
+
+        file.seek(SeekFrom::Start(total_bytes_read))?;
+        let bytes_read = file.read(&mut buffer)?;
+        total_bytes_read += bytes_read as u64;
+
+        if bytes_read > 0 {
+            hasher.update(&buffer[..bytes_read]);
+            chunk_hashes.push(format!("{:x}", hasher.finalize()));
+        } else {
+            break;
+        }
+    }
+
+    Ok(format!(
+        "{:x}",
+        chunk_hashes
+            .into_iter()
+            .fold(Sha256::new(), |mut acc, hash| {
+                acc.update(hash);
+                acc
+            })
+            .finalize()
+    ))
+}
+
+fn compute_simple_hash(mut file: File, file_size: u64) -> Result<String> {
+    let mut buffer = vec![0; file_size as usize];
+    let mut hasher = Sha256::new();
+
+    file.seek(SeekFrom::Start(0))?;
+    let bytes_read: usize = file.read(&mut buffer)?;
+
+    hasher.update(&buffer[..bytes_read]);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Computes the hash of a local file the way the Pennsieve platform
+/// computes the hash of an upload split into `chunk_size` parts.
+pub fn compute_hash(file: File, chunk_size: u64) -> Result<String> {
+    let file_size: u64 = file.metadata()?.len();
+    if file_size > chunk_size {
+        compute_multichunk_hash(file, chunk_size)
+    } else {
+        compute_simple_hash(file, chunk_size)
+    }
+}
+
+/// Checks a local file against the `hash` of the file `upload` sent to
+/// the Pennsieve platform, returning the path of the file that was checked.
+///
+/// If `file_path` is not given, the file that was originally uploaded is
+/// checked. If that file is gone and `search_dir` is given, a file with
+/// the same name and a matching hash is looked for beneath `search_dir`
+/// instead, as the file may have been moved since it was uploaded.
+pub fn verify_file(
+    upload: &UploadRecord,
+    hash: &str,
+    file_path: Option<PathBuf>,
+    search_dir: Option<&Path>,
+) -> agent::Result<PathBuf> {
+    let original = PathBuf::from(upload.file_path.clone());
+    let chunk_size = upload.chunk_size.ok_or_else(|| {
+        DBError::upload_without_chunk_size(upload.id.unwrap_or_default() as usize)
+    })?;
+
+    let verify_against = match (file_path, search_dir) {
+        (Some(file_path), _) => file_path,
+        (None, Some(search_dir)) if !original.exists() => {
+            return find_relocated(&original, hash, chunk_size, search_dir)
+        }
+        (None, _) => original,
+    };
+
+    let file = File::open(&verify_against)?;
+    if compute_hash(file, chunk_size)? != hash {
+        Err(Error::upload_does_not_match(verify_against).into())
+    } else {
+        Ok(verify_against)
+    }
+}
+
+/// Looks beneath `search_dir` for a file named like `original` whose hash
+/// matches `hash`. Candidates are checked in order of their paths. If
+/// files with the name were found but none of them match, the first one
+/// is reported as not matching.
+fn find_relocated(
+    original: &Path,
+    hash: &str,
+    chunk_size: u64,
+    search_dir: &Path,
+) -> agent::Result<PathBuf> {
+    let name = original
+        .file_name()
+        .ok_or_else(|| Error::upload_does_not_match(original.to_path_buf()))?;
+
+    let mut candidates = vec![];
+    for entry in WalkDir::new(search_dir) {
+        let entry = entry.map_err(|e| Error::from(io::Error::from(e)))?;
+        if entry.file_type().is_file() && entry.file_name() == name {
+            candidates.push(entry.into_path());
+        }
+    }
+    candidates.sort();
+
+    for candidate in &candidates {
+        if compute_hash(File::open(candidate)?, chunk_size)? == hash {
+            return Ok(candidate.clone());
+        }
+    }
+    match candidates.into_iter().next() {
+        Some(candidate) => Err(Error::upload_does_not_match(candidate).into()),
+        None => Err(Error::from(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{:?} is gone and was not found in {:?}",
+                original, search_dir
+            ),
+        ))
+        .into()),
+    }
+}
+
+/// Verifies each of `uploads` against its original file, running at most
+/// `parallelism` verifications at once. `fetch_hash` looks up the hash of
+/// an upload on the Pennsieve platform by its ID.
+///
+/// A verification that fails does not stop the others: every failure is
+/// collected in the returned summary.
+pub fn verify_uploads<F>(
+    uploads: Vec<UploadRecord>,
+    parallelism: usize,
+    fetch_hash: F,
+) -> Future<VerifySummary>
+where
+    F: 'static + Send + Clone + Fn(usize) -> Future<String>,
+{
+    let checks = uploads
+        .into_iter()
+        .filter_map(|upload| upload.id.map(|id| (id as usize, upload)));
+
+    stream::iter_ok::<_, agent::Error>(checks)
+        .map(move |(id, upload)| {
+            fetch_hash(id).then(move |hash| {
+                let path = PathBuf::from(upload.file_path.clone());
+                let result =
+                    hash.and_then(|hash| verify_file(&upload, &hash, None, None).map(|_| ()));
+                Ok::<_, agent::Error>((path, result))
+            })
+        })
+        .buffer_unordered(parallelism.max(1))
+        .fold(VerifySummary::default(), |mut summary, (path, result)| {
+            match result {
+                Ok(()) => summary.passed += 1,
+                Err(e) => match e.kind() {
+                    agent::ErrorKind::CliError {
+                        kind: ErrorKind::UploadDoesNotMatch { .. },
+                    } => summary.mismatched.push(path),
+                    _ => summary.errors.push((path, e.to_string())),
+                },
+            }
+            Ok::<_, agent::Error>(summary)
+        })
+        .map(|mut summary| {
+            summary.mismatched.sort();
+            summary.errors.sort();
+            summary
+        })
+        .into_trait()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn completed_upload<P: AsRef<Path>>(id: i64, file_path: P) -> UploadRecord {
+        let mut upload = UploadRecord::new(
+            file_path,
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            Some(4),
+            None,
+        )
+        .unwrap();
+        upload.id = Some(id);
+        upload
+    }
+
+    fn hash_of<P: AsRef<Path>>(file_path: P) -> String {
+        compute_hash(File::open(file_path).unwrap(), 4).unwrap()
+    }
+
+    /// Returns a `fetch_hash` function that serves hashes from a fixed
+    /// map, standing in for the Pennsieve API.
+    fn mock_api(
+        hashes: HashMap<usize, String>,
+    ) -> impl Fn(usize) -> Future<String> + Clone + Send + 'static {
+        let hashes = Arc::new(hashes);
+        move |id: usize| future::ok(hashes[&id].clone()).into_trait()
+    }
+
+    #[test]
+    fn matching_and_mismatching_uploads_are_summarized() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small.txt");
+        let large = dir.path().join("large.txt");
+        let changed = dir.path().join("changed.txt");
+        let missing = dir.path().join("missing.txt");
+        fs::write(&small, "abc").unwrap();
+        fs::write(&large, "spans several chunks").unwrap();
+        fs::write(&changed, "original").unwrap();
+
+        let mut hashes = HashMap::new();
+        hashes.insert(1, hash_of(&small));
+        hashes.insert(2, hash_of(&large));
+        hashes.insert(3, hash_of(&changed));
+        hashes.insert(4, hash_of(&small));
+        fs::write(&changed, "modified").unwrap();
+
+        let uploads = vec![
+            completed_upload(1, &small),
+            completed_upload(2, &large),
+            completed_upload(3, &changed),
+            completed_upload(4, &missing),
+        ];
+        let summary = verify_uploads(uploads, 2, mock_api(hashes)).wait().unwrap();
+
+        assert_eq!(summary.passed, 2);
+        assert_eq!(summary.failed(), 2);
+        assert_eq!(summary.mismatched, vec![changed.clone()]);
+        assert_eq!(
+            summary
+                .errors
+                .iter()
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>(),
+            vec![missing]
+        );
+        assert!(summary
+            .to_string()
+            .starts_with("Verified 4 uploads: 2 passed, 2 failed"));
+    }
+
+    #[test]
+    fn uploads_can_be_verified_against_another_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("original.txt");
+        let copy = dir.path().join("copy.txt");
+        fs::write(&original, "contents").unwrap();
+        fs::write(&copy, "contents").unwrap();
+
+        let upload = completed_upload(1, &original);
+        let hash = hash_of(&original);
+        fs::remove_file(&original).unwrap();
+
+        assert!(verify_file(&upload, &hash, Some(copy), None).is_ok());
+        assert!(verify_file(&upload, &hash, None, None).is_err());
+    }
+
+    #[test]
+    fn moved_files_are_found_in_the_search_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("original").join("recording.edf");
+        let search_dir = dir.path().join("moved");
+        fs::create_dir_all(original.parent().unwrap()).unwrap();
+        fs::create_dir_all(search_dir.join("a")).unwrap();
+        fs::create_dir_all(search_dir.join("b")).unwrap();
+        fs::write(&original, "recorded signal").unwrap();
+
+        let upload = completed_upload(1, &original);
+        let hash = hash_of(&original);
+        fs::remove_file(&original).unwrap();
+
+        // a file with the same name but other contents is passed over:
+        fs::write(search_dir.join("a").join("recording.edf"), "another signal").unwrap();
+        let moved = search_dir.join("b").join("recording.edf");
+        fs::write(&moved, "recorded signal").unwrap();
+
+        assert_eq!(
+            verify_file(&upload, &hash, None, Some(&search_dir)).unwrap(),
+            moved
+        );
+        // ...and the search is only made when asked for:
+        assert!(verify_file(&upload, &hash, None, None).is_err());
+    }
+
+    #[test]
+    fn altered_files_fail_even_with_a_search_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("recording.edf");
+        let search_dir = dir.path().join("moved");
+        fs::create_dir_all(&search_dir).unwrap();
+        fs::write(&original, "recorded signal").unwrap();
+
+        let upload = completed_upload(1, &original);
+        let hash = hash_of(&original);
+        let copy = search_dir.join("recording.edf");
+        fs::copy(&original, &copy).unwrap();
+
+        // The original is still there, so it is what is checked:
+        fs::write(&original, "altered signal").unwrap();
+        let mismatch = |result: agent::Result<PathBuf>| match result.map_err(|e| e.kind().clone()) {
+            Err(agent::ErrorKind::CliError {
+                kind: ErrorKind::UploadDoesNotMatch { path },
+            }) => path,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert_eq!(
+            mismatch(verify_file(&upload, &hash, None, Some(&search_dir))),
+            original
+        );
+
+        // A moved file that was altered doesn't match either:
+        fs::remove_file(&original).unwrap();
+        fs::write(&copy, "altered signal").unwrap();
+        assert_eq!(
+            mismatch(verify_file(&upload, &hash, None, Some(&search_dir))),
+            copy
+        );
+    }
+}
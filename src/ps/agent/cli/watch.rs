@@ -0,0 +1,121 @@
+//! `upload-status --watch`, which redraws the active uploads periodically,
+//! like `watch(1)`.
+//!
+//! Unlike the `UploadWatcher`, which follows the uploads of an agent it
+//! starts, this only polls the agent database, so it works the same
+//! whether or not an agent is running.
+
+use std::time::{Duration, Instant};
+
+use futures::{future, Future as _Future};
+use tokio::timer::Delay;
+
+use crate::ps::agent::database::Database;
+use crate::ps::agent::{self, Future};
+use crate::ps::util::futures::*;
+
+use super::output::CliUploadRecords;
+use super::Columns;
+
+// Clears the terminal and moves the cursor to its top left corner:
+const CLEAR_SCREEN: &str = "\x1B[2J\x1B[H";
+
+/// Calls `refresh` every `interval` until it returns `false`, which it does
+/// once there is nothing left to watch.
+pub fn poll<F>(interval: Duration, mut refresh: F) -> Future<()>
+where
+    F: FnMut() -> agent::Result<bool> + Send + 'static,
+{
+    future::loop_fn((), move |_| -> Future<future::Loop<(), ()>> {
+        match refresh() {
+            Ok(true) => Delay::new(Instant::now() + interval)
+                .map_err(Into::into)
+                .map(|_| future::Loop::Continue(()))
+                .into_trait(),
+            Ok(false) => future::ok(future::Loop::Break(())).into_trait(),
+            Err(e) => future::err(e).into_trait(),
+        }
+    })
+    .into_trait()
+}
+
+/// Redraws the active uploads in `db` every `interval`, until none are
+/// left.
+pub fn watch_active_uploads(db: Database, columns: Columns, interval: Duration) -> Future<()> {
+    poll(interval, move || {
+        let uploads = db.get_active_uploads()?;
+        print!("{}", CLEAR_SCREEN);
+        println!("Every {:.1}s: upload-status\n", interval_secs(interval));
+        if uploads.is_package_completed() {
+            println!("No uploads");
+            return Ok(false);
+        }
+        println!(
+            "{}\n",
+            Into::<CliUploadRecords>::into(uploads).table(&columns)?
+        );
+        Ok(true)
+    })
+}
+
+fn interval_secs(interval: Duration) -> f64 {
+    interval.as_secs() as f64 + f64::from(interval.subsec_millis()) / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::runtime::current_thread::Runtime;
+
+    use crate::ps::agent::database::{UploadRecord, UploadStatus};
+    use crate::ps::util;
+
+    #[test]
+    fn watching_stops_once_no_uploads_are_active() {
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            "/data/recording.edf",
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        db.insert_upload(&record).unwrap();
+
+        // The upload completes on the third refresh:
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let watched = {
+            let db = db.clone();
+            let refreshes = refreshes.clone();
+            poll(Duration::from_millis(1), move || {
+                if refreshes.fetch_add(1, Ordering::SeqCst) == 2 {
+                    db.update_import_status_and_progress("import_1", UploadStatus::Completed, 100)?;
+                }
+                Ok(!db.get_active_uploads()?.is_package_completed())
+            })
+        };
+
+        Runtime::new().unwrap().block_on(watched).unwrap();
+        assert_eq!(refreshes.load(Ordering::SeqCst), 3);
+
+        // With nothing to watch, the first refresh is the last:
+        refreshes.store(0, Ordering::SeqCst);
+        let watched = {
+            let refreshes = refreshes.clone();
+            poll(Duration::from_millis(1), move || {
+                refreshes.fetch_add(1, Ordering::SeqCst);
+                Ok(!db.get_active_uploads()?.is_package_completed())
+            })
+        };
+        Runtime::new().unwrap().block_on(watched).unwrap();
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,113 @@
+//! The working dataset set with `use`, as printed when `use` is run
+//! without a dataset.
+
+use log::*;
+
+use crate::ps::agent::{self, api};
+
+/// The state of the working dataset stored in the user's settings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WorkingDataset {
+    Unset,
+    Found {
+        id: String,
+        name: String,
+    },
+    /// The stored dataset has been deleted, or is no longer accessible.
+    Missing {
+        id: String,
+    },
+    /// The name of the stored dataset couldn't be looked up, as when the
+    /// agent is offline.
+    Unresolved {
+        id: String,
+    },
+}
+
+impl WorkingDataset {
+    /// Resolves the stored dataset `id` given the result of looking up its
+    /// name. Only a 404 means the stored ID is stale; after any other error,
+    /// the stored ID is used as it is.
+    pub fn resolve(id: String, name: agent::Result<String>) -> Self {
+        match name {
+            Ok(name) => WorkingDataset::Found { id, name },
+            Err(ref e) if api::is_not_found(e) => WorkingDataset::Missing { id },
+            Err(e) => {
+                warn!("ps:cli:use ~ couldn't look up dataset {}: {}", id, e);
+                WorkingDataset::Unresolved { id }
+            }
+        }
+    }
+
+    /// Describes the working dataset, as printed by `use`.
+    pub fn describe(&self) -> String {
+        match self {
+            WorkingDataset::Unset => "No dataset".to_string(),
+            WorkingDataset::Found { id, name } => {
+                format!("Using dataset \"{name}\" ({id})", name = name, id = id)
+            }
+            WorkingDataset::Missing { id } => format!(
+                "Using dataset \"{id}\", which no longer exists or is not accessible",
+                id = id
+            ),
+            WorkingDataset::Unresolved { id } => format!("Using dataset \"{}\"", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_found() -> agent::Error {
+        pennsieve_rust::ErrorKind::ApiError {
+            status_code: hyper::StatusCode::NOT_FOUND,
+            message: "not found".to_string(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn dataset_names_are_resolved() {
+        let dataset = WorkingDataset::resolve("N:dataset:1".to_string(), Ok("Samples".to_string()));
+        assert_eq!(
+            dataset,
+            WorkingDataset::Found {
+                id: "N:dataset:1".to_string(),
+                name: "Samples".to_string(),
+            }
+        );
+        assert_eq!(
+            dataset.describe(),
+            "Using dataset \"Samples\" (N:dataset:1)"
+        );
+        assert_eq!(WorkingDataset::Unset.describe(), "No dataset");
+    }
+
+    #[test]
+    fn stale_dataset_ids_are_detected() {
+        let dataset = WorkingDataset::resolve("N:dataset:1".to_string(), Err(not_found()));
+        assert_eq!(
+            dataset,
+            WorkingDataset::Missing {
+                id: "N:dataset:1".to_string()
+            }
+        );
+        assert!(dataset.describe().contains("no longer exists"));
+    }
+
+    #[test]
+    fn other_lookup_errors_fall_back_to_the_stored_dataset() {
+        let dataset = WorkingDataset::resolve(
+            "N:dataset:1".to_string(),
+            Err(agent::ErrorKind::TimeoutError.into()),
+        );
+        assert_eq!(
+            dataset,
+            WorkingDataset::Unresolved {
+                id: "N:dataset:1".to_string()
+            }
+        );
+        assert_eq!(dataset.describe(), "Using dataset \"N:dataset:1\"");
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::HashMap;
-use std::env::temp_dir;
+use std::env::{self, temp_dir};
+use std::fs;
+use std::io::{self, Write};
 use std::ops::{Deref, DerefMut};
+use std::path::Path;
 use std::str::FromStr;
 
 use crate::ps;
@@ -9,6 +12,8 @@ use crate::ps::agent::config::constants as c;
 use crate::ps::agent::config::error::{Error, Result};
 
 use pennsieve_rust::Environment as ApiEnvironment;
+use serde_derive::{Deserialize, Serialize};
+use url::Url;
 
 /// A key-value alias for a string-to-string hash map;
 type Dict = HashMap<String, String>;
@@ -280,6 +285,8 @@ pub struct ProfileConfig {
     pub token: String,
     pub secret: String,
     pub environment: ApiEnvironment,
+    pub http_proxy: Option<Url>,
+    pub https_proxy: Option<Url>,
 }
 
 impl ProfileConfig {
@@ -294,6 +301,8 @@ impl ProfileConfig {
             token: token.into(),
             secret: secret.into(),
             environment: ApiEnvironment::Production,
+            http_proxy: None,
+            https_proxy: None,
         }
     }
 
@@ -317,11 +326,27 @@ impl ProfileConfig {
             ))
         })?;
 
-        let config = Self::new(
+        let parse_proxy = |key: &str| -> Result<Option<Url>> {
+            section_properties
+                .get(key)
+                .map(|proxy| {
+                    Url::parse(proxy).map_err(|e| {
+                        Error::invalid_api_config(format!(
+                            "invalid proxy: {}:{}: {}",
+                            section_header, proxy, e
+                        ))
+                    })
+                })
+                .transpose()
+        };
+
+        let mut config = Self::new(
             section_header.clone(),
             api_token.to_string(),
             api_secret.to_string(),
         );
+        config.http_proxy = parse_proxy(c::HTTP_PROXY_KEY)?;
+        config.https_proxy = parse_proxy(c::HTTPS_PROXY_KEY)?;
 
         match section_properties.get(c::ENVIRONMENT_KEY) {
             Some(environment) => environment
@@ -339,14 +364,71 @@ impl ProfileConfig {
 
     pub fn with_environment(self, environment: ApiEnvironment) -> Self {
         Self {
-            profile: self.profile,
-            token: self.token,
-            secret: self.secret,
             environment,
+            ..self
+        }
+    }
+
+    /// Get the proxy that requests to `url` should be routed through, if
+    /// any.
+    ///
+    /// A proxy set on the profile takes precedence over the standard
+    /// `HTTP_PROXY` and `HTTPS_PROXY` environment variables. Hosts listed
+    /// in `NO_PROXY` are never proxied.
+    pub fn proxy_for(&self, url: &Url) -> Result<Option<Url>> {
+        self.proxy_for_with_env(url, |key| env::var(key).ok())
+    }
+
+    fn proxy_for_with_env<E>(&self, url: &Url, env: E) -> Result<Option<Url>>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let lookup = |keys: &[&str]| {
+            keys.iter()
+                .filter_map(|key| env(key))
+                .find(|value| !value.trim().is_empty())
+        };
+
+        if let Some(no_proxy) = lookup(&c::NO_PROXY_ENV_VARS) {
+            if url
+                .host_str()
+                .map_or(false, |host| bypasses_proxy(&no_proxy, host))
+            {
+                return Ok(None);
+            }
+        }
+
+        let (configured, env_keys) = if url.scheme() == "https" {
+            (&self.https_proxy, &c::HTTPS_PROXY_ENV_VARS)
+        } else {
+            (&self.http_proxy, &c::HTTP_PROXY_ENV_VARS)
+        };
+
+        match configured {
+            Some(proxy) => Ok(Some(proxy.clone())),
+            None => lookup(env_keys)
+                .map(|proxy| {
+                    Url::parse(proxy.trim()).map_err(|e| {
+                        Error::invalid_api_config(format!("invalid proxy: {}: {}", proxy, e))
+                    })
+                })
+                .transpose(),
         }
     }
 }
 
+/// Check whether `host` matches an entry of a `NO_PROXY` style list: a
+/// comma separated list of host names, where each entry also matches its
+/// subdomains and `*` matches every host.
+fn bypasses_proxy(no_proxy: &str, host: &str) -> bool {
+    let host = host.to_lowercase();
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.').to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
 /// This struct contains the relevant sections of a config.ini file
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Settings {
@@ -434,6 +516,43 @@ impl Settings {
         }
     }
 
+    /// Rename a profile on this instance, keeping it the default if it
+    /// was the default before.
+    pub fn rename_profile<S, T>(&mut self, old_name: S, new_name: T) -> Result<()>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let old_name: String = old_name.into();
+        let new_name: String = new_name.into();
+        if !self.contains_profile(old_name.clone()) {
+            return Err(Error::illegal_operation(format!(
+                "profile not found: {}",
+                old_name
+            )));
+        } else if new_name.is_empty() {
+            return Err(Error::illegal_operation("profile name cannot be empty"));
+        } else if c::RESERVED_PROFILE_NAMES.contains(&&new_name[..]) {
+            return Err(Error::illegal_operation(format!(
+                "profile name '{}' reserved for system",
+                new_name
+            )));
+        } else if self.profiles.contains_key(&new_name) {
+            return Err(Error::illegal_operation(format!(
+                "profile '{}' already exists",
+                new_name
+            )));
+        }
+
+        let mut profile = self.profiles.remove(&old_name).unwrap();
+        profile.profile = new_name.clone();
+        self.profiles.insert(new_name.clone(), profile);
+        if self.global_settings.get(c::DEFAULT_PROFILE_KEY) == Some(&old_name) {
+            self.set_default_profile(new_name)?;
+        }
+        Ok(())
+    }
+
     /// Set the default profile on this instance
     pub fn set_default_profile<S: Into<String>>(&mut self, profile_name: S) -> Result<()> {
         let profile_name: String = profile_name.into();
@@ -599,6 +718,212 @@ pub fn set_default_profile<S: Into<String>>(
     }
 }
 
+/// Rename the specified profile.
+///
+/// Only the profile in the settings object is renamed. The settings the
+/// agent database stores per profile are moved with
+/// `Database::rename_profile`.
+pub fn rename_profile<S, T>(settings: &mut Settings, old_name: S, new_name: T) -> Result<()>
+where
+    S: Into<String>,
+    T: Into<String>,
+{
+    let old_name: String = old_name.into();
+    let new_name: String = new_name.into();
+
+    if old_name == new_name {
+        println!("'{}' already has that name, no action taken.", old_name);
+        Ok(())
+    } else {
+        settings.rename_profile(old_name, new_name)
+    }
+}
+
+/// A profile as written by `profile export` and read by `profile import`.
+///
+/// The API host is determined by the environment. The API token and
+/// secret are only present if they were explicitly exported.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileExport {
+    pub profile: String,
+    pub environment: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https_proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_secret: Option<String>,
+}
+
+impl ProfileExport {
+    /// Read an exported profile from a JSON file.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            Error::invalid_api_config(format!("invalid profile export: {}: {}", path.display(), e))
+        })
+    }
+
+    /// Write this exported profile to a JSON file, readable by the user
+    /// alone as it may hold the API token and secret. An existing file is
+    /// only replaced if `force` is set.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P, force: bool) -> Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::invalid_api_config(e.to_string()))?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true);
+        if force {
+            options.create(true).truncate(true);
+        } else {
+            options.create_new(true);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path).map_err(|e| {
+            if e.kind() == io::ErrorKind::AlreadyExists {
+                Error::invalid_api_config(format!(
+                    "{} already exists, use --force to overwrite it",
+                    path.display()
+                ))
+            } else {
+                e.into()
+            }
+        })?;
+        // the mode only applies to new files, so an overwritten one is
+        // restricted here:
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(json.as_bytes()).map_err(Into::into)
+    }
+
+    /// Convert this export back into a profile, using `credentials` to
+    /// supply the API token and secret if they were not exported.
+    fn into_profile_config<F>(self, credentials: F) -> Result<ProfileConfig>
+    where
+        F: FnOnce() -> Result<(String, String)>,
+    {
+        let environment = self.environment.parse::<ApiEnvironment>().map_err(|_| {
+            Error::invalid_api_config(format!(
+                "invalid environment: {}:{}",
+                self.profile, self.environment
+            ))
+        })?;
+        let parse_proxy = |proxy: Option<String>| -> Result<Option<Url>> {
+            proxy
+                .map(|proxy| {
+                    Url::parse(&proxy).map_err(|e| {
+                        Error::invalid_api_config(format!(
+                            "invalid proxy: {}:{}: {}",
+                            self.profile, proxy, e
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        let http_proxy = parse_proxy(self.http_proxy.clone())?;
+        let https_proxy = parse_proxy(self.https_proxy.clone())?;
+
+        let (token, secret) = match (self.api_token, self.api_secret) {
+            (Some(token), Some(secret)) => (token, secret),
+            _ => credentials()?,
+        };
+
+        let mut profile = ProfileConfig::new(self.profile, token, secret);
+        profile.http_proxy = http_proxy;
+        profile.https_proxy = https_proxy;
+        Ok(profile.with_environment(environment))
+    }
+}
+
+/// Export the named profile. The API token and secret are only included
+/// if `include_secrets` is set.
+pub fn export_profile<S: Into<String>>(
+    settings: &Settings,
+    profile_name: S,
+    include_secrets: bool,
+) -> Result<ProfileExport> {
+    let profile_name: String = profile_name.into();
+    if !settings.contains_profile(profile_name.clone()) {
+        return Err(Error::illegal_operation(format!(
+            "profile not found: {}",
+            profile_name
+        )));
+    }
+    let profile = settings.get_profile(profile_name).unwrap();
+
+    Ok(ProfileExport {
+        environment: profile.environment.to_string(),
+        http_proxy: profile.http_proxy.map(|proxy| proxy.to_string()),
+        https_proxy: profile.https_proxy.map(|proxy| proxy.to_string()),
+        api_token: if include_secrets {
+            Some(profile.token)
+        } else {
+            None
+        },
+        api_secret: if include_secrets {
+            Some(profile.secret)
+        } else {
+            None
+        },
+        profile: profile.profile,
+    })
+}
+
+/// Add an exported profile to a settings instance, asking the user for
+/// the API token and secret if they were not exported. An existing
+/// profile of the same name is only replaced if `force` is set.
+pub fn import_profile(
+    settings: &mut Settings,
+    export: ProfileExport,
+    force: bool,
+) -> Result<ProfileConfig> {
+    import_profile_with_credentials(settings, export, force, || {
+        println!("The API token and secret were not exported:");
+        Ok((user_input("  API token:")?, user_input("  API secret:")?))
+    })
+}
+
+fn import_profile_with_credentials<F>(
+    settings: &mut Settings,
+    export: ProfileExport,
+    force: bool,
+    credentials: F,
+) -> Result<ProfileConfig>
+where
+    F: FnOnce() -> Result<(String, String)>,
+{
+    if export.profile.is_empty() {
+        return Err(Error::illegal_operation("profile name cannot be empty"));
+    } else if c::RESERVED_PROFILE_NAMES.contains(&&export.profile[..]) {
+        return Err(Error::illegal_operation(format!(
+            "profile name '{}' reserved for system",
+            export.profile
+        )));
+    } else if !force && settings.contains_profile(export.profile.clone()) {
+        return Err(Error::illegal_operation(format!(
+            "profile '{}' already exists, use --force to overwrite it",
+            export.profile
+        )));
+    }
+
+    let profile = export.into_profile_config(credentials)?;
+    settings.add_profile(profile.clone());
+
+    Ok(profile)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -670,6 +995,103 @@ mod test {
         assert_eq!(settings, expected);
     }
 
+    #[test]
+    fn parse_profile_proxies() {
+        let ini_str = r#"
+            [global]
+            default_profile = dev
+
+            [dev]
+            api_token = token
+            api_secret = secret
+            https_proxy = http://proxy.example.com:3128
+        "#;
+        let config = ini_str.parse::<Config>().unwrap();
+        let profile = config.api_settings.get_profile("dev").unwrap();
+        assert_eq!(
+            profile.https_proxy,
+            Some(Url::parse("http://proxy.example.com:3128").unwrap())
+        );
+        assert_eq!(profile.http_proxy, None);
+
+        // proxies are preserved when the config is written back out:
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert_eq!(config.api_settings.get_profile("dev").unwrap(), profile);
+
+        let ini_str = r#"
+            [global]
+            default_profile = dev
+
+            [dev]
+            api_token = token
+            api_secret = secret
+            https_proxy = not a url
+        "#;
+        assert!(ini_str.parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn proxy_is_applied_to_api_requests() {
+        let api = Url::parse("https://api.pennsieve.io").unwrap();
+        let from_env = |vars: Vec<(&'static str, &'static str)>| {
+            let vars: HashMap<_, _> = vars.into_iter().collect();
+            move |key: &str| vars.get(key).map(|value| value.to_string())
+        };
+
+        let mut profile = ProfileConfig::new("dev", "token", "secret");
+        assert_eq!(
+            profile.proxy_for_with_env(&api, from_env(vec![])).unwrap(),
+            None
+        );
+
+        // fall back to the environment:
+        assert_eq!(
+            profile
+                .proxy_for_with_env(
+                    &api,
+                    from_env(vec![
+                        ("HTTPS_PROXY", "http://env-proxy:8080"),
+                        ("HTTP_PROXY", "http://other-proxy:8080"),
+                    ])
+                )
+                .unwrap(),
+            Some(Url::parse("http://env-proxy:8080").unwrap())
+        );
+
+        // the profile takes precedence over the environment:
+        profile.https_proxy = Some(Url::parse("http://profile-proxy:3128").unwrap());
+        assert_eq!(
+            profile
+                .proxy_for_with_env(
+                    &api,
+                    from_env(vec![("https_proxy", "http://env-proxy:8080")])
+                )
+                .unwrap(),
+            Some(Url::parse("http://profile-proxy:3128").unwrap())
+        );
+
+        // hosts in NO_PROXY are never proxied:
+        assert_eq!(
+            profile
+                .proxy_for_with_env(
+                    &api,
+                    from_env(vec![("NO_PROXY", "localhost, .pennsieve.io")])
+                )
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            profile
+                .proxy_for_with_env(&api, from_env(vec![("no_proxy", "pennsieve.net")]))
+                .unwrap(),
+            Some(Url::parse("http://profile-proxy:3128").unwrap())
+        );
+
+        assert!(ProfileConfig::new("dev", "token", "secret")
+            .proxy_for_with_env(&api, from_env(vec![("HTTPS_PROXY", "not a url")]))
+            .is_err());
+    }
+
     #[test]
     fn fail_to_parse_valid_ini_no_default() {
         let ini_str = r#"
@@ -787,4 +1209,148 @@ mod test {
 
         assert!(config.api_settings.contains_profile("test"));
     }
+
+    fn profile_settings() -> Settings {
+        let ini_str = r#"
+            [global]
+            default_profile = dev
+
+            [dev]
+            api_token = token
+            api_secret = secret
+            environment = development
+            https_proxy = http://proxy.example.com:3128
+        "#;
+        ini_str.parse::<Config>().unwrap().api_settings
+    }
+
+    #[test]
+    fn export_and_import_round_trip() {
+        let settings = profile_settings();
+        let export = export_profile(&settings, "dev", true).unwrap();
+
+        let path = ps::util::path::temp("profile", ".json").unwrap();
+        export.write_to(&path, false).unwrap();
+        let export = ProfileExport::from_path(&path).unwrap();
+
+        let mut imported: Settings = Default::default();
+        import_profile_with_credentials(&mut imported, export, false, || {
+            panic!("the exported credentials should be used")
+        })
+        .unwrap();
+
+        assert_eq!(imported.profiles, settings.profiles);
+        assert_eq!(imported.default_profile(), settings.default_profile());
+    }
+
+    #[test]
+    fn export_refuses_to_overwrite_without_force() {
+        let export = export_profile(&profile_settings(), "dev", true).unwrap();
+        let path = ps::util::path::temp("profile", ".json").unwrap();
+        fs::write(&path, "notes").unwrap();
+
+        match export.write_to(&path, false) {
+            Err(e) => assert!(e.to_string().contains("already exists")),
+            Ok(()) => panic!("the existing file was overwritten"),
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "notes");
+
+        export.write_to(&path, true).unwrap();
+        assert_eq!(ProfileExport::from_path(&path).unwrap(), export);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exports_are_only_readable_by_the_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let export = export_profile(&profile_settings(), "dev", true).unwrap();
+        let new = ps::util::path::temp("profile", ".json").unwrap();
+        export.write_to(&new, false).unwrap();
+        assert_eq!(
+            fs::metadata(&new).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let existing = ps::util::path::temp("profile", ".json").unwrap();
+        fs::write(&existing, "notes").unwrap();
+        fs::set_permissions(&existing, fs::Permissions::from_mode(0o644)).unwrap();
+        export.write_to(&existing, true).unwrap();
+        assert_eq!(
+            fs::metadata(&existing).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn export_excludes_secrets_by_default() {
+        let settings = profile_settings();
+        let export = export_profile(&settings, "dev", false).unwrap();
+
+        let json = serde_json::to_string(&export).unwrap();
+        assert!(!json.contains("token"));
+        assert!(!json.contains("secret"));
+
+        let mut imported: Settings = Default::default();
+        let profile = import_profile_with_credentials(&mut imported, export, false, || {
+            Ok(("token".to_string(), "secret".to_string()))
+        })
+        .unwrap();
+        assert_eq!(profile, settings.get_profile("dev").unwrap());
+    }
+
+    #[test]
+    fn import_refuses_to_overwrite_without_force() {
+        let mut settings = profile_settings();
+        let mut export = export_profile(&settings, "dev", true).unwrap();
+        export.api_token = Some("new_token".to_string());
+
+        match import_profile_with_credentials(
+            &mut settings,
+            export.clone(),
+            false,
+            || unreachable!(),
+        ) {
+            Err(e) => assert!(e.to_string().contains("already exists")),
+            Ok(profile) => panic!("expected an error, imported: {:?}", profile),
+        }
+        assert_eq!(settings.get_profile("dev").unwrap().token, "token");
+
+        import_profile_with_credentials(&mut settings, export, true, || unreachable!()).unwrap();
+        assert_eq!(settings.get_profile("dev").unwrap().token, "new_token");
+    }
+
+    #[test]
+    fn export_of_a_missing_profile_fails() {
+        assert!(export_profile(&profile_settings(), "prod", false).is_err());
+    }
+
+    #[test]
+    fn renamed_profiles_stay_the_default() {
+        let mut settings = profile_settings();
+        rename_profile(&mut settings, "dev", "staging").unwrap();
+
+        assert!(!settings.contains_profile("dev"));
+        assert_eq!(settings.default_profile().profile, "staging");
+        assert_eq!(settings.get_profile("staging").unwrap().token, "token");
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn renaming_to_an_existing_or_reserved_profile_fails() {
+        let mut settings = profile_settings();
+        settings.add_profile(ProfileConfig::new("prod", "token2", "secret2"));
+
+        assert!(rename_profile(&mut settings, "dev", "prod").is_err());
+        assert!(rename_profile(&mut settings, "dev", "global").is_err());
+        assert!(rename_profile(&mut settings, "missing", "other").is_err());
+
+        // the non-default profile can be renamed without touching the default:
+        rename_profile(&mut settings, "prod", "production").unwrap();
+        assert_eq!(settings.default_profile().profile, "dev");
+        assert_eq!(
+            settings.get_profile("production").unwrap().profile,
+            "production"
+        );
+    }
 }
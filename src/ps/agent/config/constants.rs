@@ -1,5 +1,7 @@
 //! Program level configuration constants live here.
 
+use std::net::{IpAddr, Ipv4Addr};
+
 /// The maximum connection backlog limit:
 pub const AGENT_CONNECTION_BACKLOG_LIMIT: i32 = 128;
 
@@ -7,9 +9,13 @@ pub const AGENT_CONNECTION_BACKLOG_LIMIT: i32 = 128;
 pub const AGENT_LOG_FORMAT: &str =
     "[{d(%Y-%m-%d %H:%M:%S %Z)(utc)}][{l}][{t}][{X(tid)(main)}] {m}{n}";
 
-/// Shutdown the process after a timeout period.
+/// Shutdown the process after a timeout period, on top of the time given to
+/// draining the uploads in flight.
 pub const AGENT_MAX_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
 
+/// How often an agent started with `--idle-timeout` checks if it is idle.
+pub const AGENT_IDLE_CHECK_INTERVAL_MS: u64 = 250;
+
 /// Config defaults:
 pub const CONFIG_DEFAULT_PAGE_SIZE: u32 = 100_000; // 10k data points = 80 KB
 pub const CONFIG_DEFAULT_HARD_CACHE_SIZE: u64 = 10_000_000_000; // 10 GB
@@ -21,7 +27,15 @@ pub const CONFIG_DEFAULT_TIMESERIES_LOCAL_PORT: u16 = 9090;
 //pub const CONFIG_DEFAULT_TIMESERIES_REMOTE_HOST: &str = "wss://streaming.pennsieve.io";
 pub const CONFIG_DEFAULT_TIMESERIES_REMOTE_HOST: &str = "wss://streaming.dev.pennsieve.io";
 pub const CONFIG_DEFAULT_TIMESERIES_REMOTE_PORT: u16 = 443;
+pub const CONFIG_DEFAULT_TIMESERIES_MAX_FRAME_BYTES: usize = 0;
 pub const CONFIG_DEFAULT_STATUS_WEBSOCKET_PORT: u16 = 11235;
+pub const CONFIG_DEFAULT_BIND_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const CONFIG_DEFAULT_API_RETRIES: u32 = 3;
+pub const CONFIG_DEFAULT_API_RATE_LIMIT_PER_SEC: u32 = 10;
+pub const CONFIG_DEFAULT_API_NAME_CACHE_TTL_SECS: u64 = 300;
+pub const CONFIG_DEFAULT_CACHE_PARALLELISM: usize = 4;
+pub const CONFIG_DEFAULT_CACHE_SOFT_AGE_DAYS: u64 = 7;
+pub const CONFIG_DEFAULT_CACHE_HARD_AGE_HOURS: u64 = 12;
 
 /// If true, the only way services will be disabled is by including
 /// <service-name>=false in config.ini
@@ -30,18 +44,34 @@ pub const CONFIG_ENABLE_SERVICES_BY_DEFAULT: bool = true;
 /// The collector run interval. A collection cycle will run every N ms.
 pub const CACHE_COLLECTOR_RUN_INTERVAL_SECS: u64 = 60 * 15; // 15 minutes
 
+/// How long the collector may go without running before `/health` reports
+/// it as stalled.
+pub const CACHE_COLLECTOR_STALL_SECS: u64 = 2 * CACHE_COLLECTOR_RUN_INTERVAL_SECS;
+
+/// The number of least recently used pages fetched at a time during an
+/// LRU cache cleanup.
+pub const CACHE_COLLECTOR_LRU_BATCH_SIZE: usize = 100;
+
 /// CLI progress bar format.
 pub const UPLOAD_PROGRESS_BAR_FORMAT: &str =
     "{prefix:8.bold.dim} {spinner} {bar:60.cyan/blue} {pos:>4}% {msg}";
 pub const UPLOAD_ERROR_PROGRESS_BAR_FORMAT: &str =
     "{prefix:8.bold.dim} {spinner} {bar:60.red/red} {pos:>4}% {msg}";
 
+/// CLI progress bar format used when color is turned off. The whole bar is
+/// drawn as plain text into its message.
+pub const UPLOAD_PLAIN_PROGRESS_BAR_FORMAT: &str = "{msg}";
+pub const UPLOAD_PLAIN_PROGRESS_BAR_WIDTH: usize = 60;
+
 /// CLI progress characters.
 pub const UPLOAD_PROGRESS_CHARACTERS: &str = "#>-";
 
 /// The refresh interval used when watching the progress of uploaded files.
 pub const UPLOAD_PROGRESS_REFRESH_INTERVAL_MS: u64 = 500; // 1/2 second
 
+/// The longest interval `upload-status --watch` may redraw at.
+pub const UPLOAD_MAX_WATCH_INTERVAL_SECS: u64 = 86_400; // 1 day
+
 /// The maximum amount of progress bars that we'll show while uploading
 pub const UPLOAD_PROGRESS_MAX_BARS: u64 = 30;
 
@@ -55,6 +85,27 @@ pub const PREVIEW_DISPLAY_MAX_PACKAGES: usize = 30;
 /// This will check files for upload status changes every N seconds.
 pub const UPLOAD_WORKER_RUN_INTERVAL_SECS: u64 = 1;
 
+/// How long the upload worker may go without running a step or making
+/// upload progress before `/health` reports it as stalled.
+pub const UPLOAD_WORKER_STALL_SECS: u64 = 5 * 60; // 5 minutes
+
+/// The smallest chunk a file is split into for a multipart upload. S3
+/// rejects smaller parts, other than the last one.
+pub const UPLOAD_MIN_CHUNK_SIZE: u64 = 5 * 1024 * 1024; // 5 MiB
+
+/// The largest chunk a file is split into for a multipart upload.
+pub const UPLOAD_MAX_CHUNK_SIZE: u64 = 5 * 1024 * 1024 * 1024; // 5 GiB
+
+/// The most parts S3 accepts for a single multipart upload.
+pub const UPLOAD_MAX_CHUNK_COUNT: u64 = 10_000;
+
+/// How long a shutdown waits for the uploads in flight to finish.
+pub const UPLOAD_MAX_DRAIN_TIMEOUT_SECS: u64 = 120;
+
+/// The delay before the first retry of a failed API request. The delay
+/// doubles with each subsequent retry.
+pub const API_RETRY_BACKOFF_MS: u64 = 500;
+
 /// Used for parsing and generating the config.ini file
 pub const GLOBAL_SECTION: &str = "global";
 pub const AGENT_SECTION: &str = "agent";
@@ -62,12 +113,36 @@ pub const DEFAULT_PROFILE_KEY: &str = "default_profile";
 pub const API_TOKEN_KEY: &str = "api_token";
 pub const API_SECRET_KEY: &str = "api_secret";
 pub const ENVIRONMENT_KEY: &str = "environment";
+pub const HTTP_PROXY_KEY: &str = "http_proxy";
+pub const HTTPS_PROXY_KEY: &str = "https_proxy";
 pub const ENVIRONMENT_OVERRIDE_PROFILE: &str = "environment_override";
 pub const RESERVED_PROFILE_NAMES: [&str; 3] =
     [GLOBAL_SECTION, AGENT_SECTION, ENVIRONMENT_OVERRIDE_PROFILE];
 
-/// Frequency to check for new versions of the agent (daily
-pub const AGENT_LATEST_RELEASE_CHECK_INTERVAL_SECS: u64 = 60 * 60 * 24;
+/// Environment variables consulted for a proxy when a profile does not
+/// define one:
+pub const HTTP_PROXY_ENV_VARS: [&str; 2] = ["HTTP_PROXY", "http_proxy"];
+pub const HTTPS_PROXY_ENV_VARS: [&str; 2] = ["HTTPS_PROXY", "https_proxy"];
+pub const NO_PROXY_ENV_VARS: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+/// Frequency to check for new versions of the agent (daily), unless
+/// `version_check_interval_hours` is configured.
+pub const CONFIG_DEFAULT_VERSION_CHECK_INTERVAL_HOURS: u64 = 24;
+
+/// How long an upload may stay in progress without an update before it is
+/// retried, unless `upload_retry_after_minutes` is configured.
+pub const CONFIG_DEFAULT_UPLOAD_RETRY_AFTER_MINUTES: u64 = 60;
+
+/// How long after it was queued an upload is given up on and marked failed,
+/// unless `upload_fail_after_hours` is configured.
+pub const CONFIG_DEFAULT_UPLOAD_FAIL_AFTER_HOURS: u64 = 8;
+
+/// How many times an upload is attempted again after its session expired
+/// before it is marked failed, unless `upload_max_retries` is configured.
+pub const CONFIG_DEFAULT_UPLOAD_MAX_RETRIES: u16 = 10;
+
+/// If set, the agent never checks for new versions in the background.
+pub const DISABLE_VERSION_CHECK_ENV_VAR: &str = "PENNSIEVE_DISABLE_VERSION_CHECK";
 
 /// URL to bucket that contains public Agent binaries
 pub const VERSION_PATH: &str =
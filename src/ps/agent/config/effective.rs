@@ -0,0 +1,328 @@
+//! The fully resolved configuration, as printed by `config show --all`.
+
+use std::fmt;
+
+use serde_derive::Serialize;
+
+use crate::ps::agent::config::{Config, Service};
+use crate::ps::agent::version;
+
+/// Printed in place of secrets unless they are asked for.
+const REDACTED: &str = "********";
+
+/// Where an effective configuration value was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueSource {
+    /// The `config.ini` file, or the default if the file doesn't set it.
+    File,
+    /// Environment variables such as `PENNSIEVE_API_TOKEN`.
+    Environment,
+}
+
+impl fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ValueSource::File => write!(f, "file"),
+            ValueSource::Environment => write!(f, "environment"),
+        }
+    }
+}
+
+/// A single resolved configuration value.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EffectiveValue {
+    pub key: String,
+    pub value: String,
+    pub source: ValueSource,
+}
+
+impl EffectiveValue {
+    fn new<K: Into<String>, V: ToString>(key: K, value: V, source: ValueSource) -> Self {
+        Self {
+            key: key.into(),
+            value: value.to_string(),
+            source,
+        }
+    }
+}
+
+impl Config {
+    /// Lists every resolved configuration value for the given active
+    /// profile, in the order they appear in `config.ini`. The API token and
+    /// secret are redacted unless `show_secrets` is set.
+    pub fn effective_values(
+        &self,
+        active_profile: &str,
+        show_secrets: bool,
+    ) -> Vec<EffectiveValue> {
+        use self::ValueSource::*;
+
+        let profile_source = if self.environment_override {
+            Environment
+        } else {
+            File
+        };
+        let secret = |value: &str| {
+            if show_secrets {
+                value.to_string()
+            } else {
+                REDACTED.to_string()
+            }
+        };
+
+        let mut values = vec![EffectiveValue::new(
+            "profile",
+            active_profile,
+            profile_source,
+        )];
+        if let Some(profile) = self.api_settings.get_profile(active_profile) {
+            values.push(EffectiveValue::new(
+                "api_token",
+                secret(&profile.token),
+                profile_source,
+            ));
+            values.push(EffectiveValue::new(
+                "api_secret",
+                secret(&profile.secret),
+                profile_source,
+            ));
+            values.push(EffectiveValue::new(
+                "environment",
+                profile.environment,
+                profile_source,
+            ));
+            if let Some(proxy) = profile.http_proxy {
+                values.push(EffectiveValue::new("http_proxy", proxy, profile_source));
+            }
+            if let Some(proxy) = profile.https_proxy {
+                values.push(EffectiveValue::new("https_proxy", proxy, profile_source));
+            }
+        }
+
+        values.push(EffectiveValue::new("metrics", self.metrics, File));
+        values.push(EffectiveValue::new(
+            "cache_base_path",
+            self.cache.base_path().display(),
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "cache_page_size",
+            self.cache.page_size(),
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "cache_soft_cache_size",
+            self.cache.soft_cache_size(),
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "cache_hard_cache_size",
+            self.cache.hard_cache_size(),
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "cache_eviction_policy",
+            self.cache.eviction_policy(),
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "cache_compress",
+            self.cache.compress(),
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "cache_parallelism",
+            self.cache.parallelism(),
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "cache_soft_age_days",
+            self.cache.soft_age_days(),
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "cache_hard_age_hours",
+            self.cache.hard_age_hours(),
+            File,
+        ));
+
+        for service in self.get_services() {
+            match service {
+                Service::Proxy(proxy) => {
+                    values.push(EffectiveValue::new(
+                        "proxy_local_bind",
+                        proxy.local_bind,
+                        File,
+                    ));
+                    values.push(EffectiveValue::new(
+                        "proxy_local_port",
+                        proxy.local_port,
+                        File,
+                    ));
+                    values.push(EffectiveValue::new(
+                        "proxy_remote_host",
+                        &proxy.remote_host,
+                        File,
+                    ));
+                    values.push(EffectiveValue::new(
+                        "proxy_remote_port",
+                        proxy.remote_port,
+                        File,
+                    ));
+                }
+                Service::TimeSeries(ts) => {
+                    values.push(EffectiveValue::new(
+                        "timeseries_local_bind",
+                        ts.local_bind,
+                        File,
+                    ));
+                    values.push(EffectiveValue::new(
+                        "timeseries_local_port",
+                        ts.local_port,
+                        File,
+                    ));
+                    values.push(EffectiveValue::new(
+                        "timeseries_remote_host",
+                        &ts.remote_host,
+                        File,
+                    ));
+                    values.push(EffectiveValue::new(
+                        "timeseries_remote_port",
+                        ts.remote_port,
+                        File,
+                    ));
+                    values.push(EffectiveValue::new(
+                        "timeseries_max_frame_bytes",
+                        self.timeseries_max_frame_bytes,
+                        File,
+                    ));
+                }
+                Service::Uploader(_) => values.push(EffectiveValue::new("uploader", true, File)),
+            }
+        }
+
+        values.push(EffectiveValue::new(
+            "status_bind",
+            self.status_server_bind,
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "status_port",
+            self.status_server_port,
+            File,
+        ));
+        values.push(EffectiveValue::new("api_retries", self.api_retries, File));
+        values.push(EffectiveValue::new(
+            "api_rate_limit_per_sec",
+            self.api_rate_limit_per_sec,
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "api_name_cache_ttl_secs",
+            self.api_name_cache_ttl_secs,
+            File,
+        ));
+        if version::disabled_by_environment() {
+            values.push(EffectiveValue::new("check_updates", false, Environment));
+        } else {
+            values.push(EffectiveValue::new(
+                "check_updates",
+                self.check_updates,
+                File,
+            ));
+        }
+        values.push(EffectiveValue::new(
+            "version_check_interval_hours",
+            self.version_check_interval_hours,
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "upload_retry_after_minutes",
+            self.upload_retry_after_minutes,
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "upload_fail_after_hours",
+            self.upload_fail_after_hours,
+            File,
+        ));
+        values.push(EffectiveValue::new(
+            "upload_max_retries",
+            self.upload_max_retries,
+            File,
+        ));
+
+        values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pennsieve_rust::Environment as ApiEnvironment;
+
+    use crate::ps::agent::config::api::ProfileConfig;
+
+    const INI: &str = r#"
+        [global]
+        default_profile = dev
+
+        [dev]
+        api_token = file_token
+        api_secret = file_secret
+
+        [agent]
+        status_port = 12345
+    "#;
+
+    fn value<'a>(values: &'a [EffectiveValue], key: &str) -> &'a EffectiveValue {
+        values
+            .iter()
+            .find(|value| value.key == key)
+            .unwrap_or_else(|| panic!("missing key: {}", key))
+    }
+
+    #[test]
+    fn file_values_are_flagged_and_secrets_redacted() {
+        let config: Config = INI.parse().unwrap();
+        let values = config.effective_values("dev", false);
+
+        assert_eq!(
+            value(&values, "profile"),
+            &EffectiveValue::new("profile", "dev", ValueSource::File)
+        );
+        assert_eq!(value(&values, "api_token").value, REDACTED);
+        assert_eq!(value(&values, "api_secret").value, REDACTED);
+        assert_eq!(value(&values, "status_port").value, "12345");
+        assert_eq!(value(&values, "proxy_local_port").value, "8080");
+        assert!(values.iter().all(|value| value.source == ValueSource::File));
+
+        let values = config.effective_values("dev", true);
+        assert_eq!(value(&values, "api_token").value, "file_token");
+        assert_eq!(value(&values, "api_secret").value, "file_secret");
+    }
+
+    #[test]
+    fn environment_overrides_are_flagged() {
+        let mut config: Config = INI.parse().unwrap();
+        config
+            .add_environment_override(
+                ProfileConfig::new("environment_override", "env_token", "env_secret")
+                    .with_environment(ApiEnvironment::NonProduction),
+            )
+            .unwrap();
+        let values = config.effective_values("environment_override", true);
+
+        for key in &["profile", "api_token", "api_secret", "environment"] {
+            assert_eq!(value(&values, key).source, ValueSource::Environment);
+        }
+        assert_eq!(value(&values, "api_token").value, "env_token");
+        assert_eq!(
+            value(&values, "environment").value,
+            ApiEnvironment::NonProduction.to_string()
+        );
+        assert_eq!(value(&values, "status_port").source, ValueSource::File);
+    }
+}
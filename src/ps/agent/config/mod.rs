@@ -4,7 +4,8 @@ use std::env;
 use std::env::temp_dir;
 use std::fmt;
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
 use std::path;
 use std::str::{self, FromStr};
 
@@ -19,9 +20,11 @@ use pennsieve_rust::Environment as ApiEnvironment;
 
 pub mod api;
 pub mod constants;
+mod effective;
 mod error;
 
 pub use self::api::{AgentSettings, ConfigStore, GlobalSettings};
+pub use self::effective::{EffectiveValue, ValueSource};
 pub use self::error::{Error, ErrorKind, Result};
 
 // PS_HOME/config.ini file header:
@@ -38,9 +41,35 @@ pub struct Config {
     pub cache: CacheConfig,
     pub metrics: bool,
     services: Vec<Service>,
+    /// The largest websocket frame the timeseries server sends, in bytes.
+    /// Larger chunks are split over several frames. Zero disables the cap.
+    pub timeseries_max_frame_bytes: usize,
     pub api_settings: api::Settings,
     pub environment_override: bool,
     pub status_server_port: u16,
+    /// The local interface the status server listens on.
+    pub status_server_bind: IpAddr,
+    /// How many times read-only API requests are retried on transient
+    /// failures.
+    pub api_retries: u32,
+    /// How many requests a second are made to the Pennsieve platform by
+    /// operations that list or fetch many items. Zero disables pacing.
+    pub api_rate_limit_per_sec: u32,
+    /// How long a dataset name resolved to an ID is remembered, so it need
+    /// not be looked up again. Zero disables the cache.
+    pub api_name_cache_ttl_secs: u64,
+    /// If false, the agent never checks for new versions in the background.
+    pub check_updates: bool,
+    /// How long the agent waits between background checks for new versions.
+    pub version_check_interval_hours: u64,
+    /// How long an in progress upload goes without an update before it is
+    /// retried.
+    pub upload_retry_after_minutes: u64,
+    /// How long after it was queued an upload is marked failed.
+    pub upload_fail_after_hours: u64,
+    /// How many times an upload is attempted again before it is marked
+    /// failed.
+    pub upload_max_retries: u16,
 }
 
 impl Config {
@@ -51,16 +80,27 @@ impl Config {
     /// environment, the config file is not required. Defaults will be
     /// used for all other values.
     pub fn from_config_file_and_environment() -> Result<Self> {
-        let environment_override = Self::get_environment_override();
-
         // If no configuration file exists, fall back to the default.
-        let mut config = Self::from_config_file().unwrap_or_else(|_| Self::default());
+        Self::from_config_file()
+            .unwrap_or_else(|_| Self::default())
+            .with_environment()
+    }
 
-        if let Some(environment_override) = environment_override {
-            config.add_environment_override(environment_override)?
+    /// Like `from_config_file_and_environment`, but reads the configuration
+    /// from the file at `path` rather than the Pennsieve home directory.
+    pub fn from_path_and_environment<P: AsRef<path::Path>>(path: P) -> Result<Self> {
+        Self::from_path(path)
+            .unwrap_or_else(|_| Self::default())
+            .with_environment()
+    }
+
+    /// Apply any environment override to this object, then validate it.
+    fn with_environment(mut self) -> Result<Self> {
+        if let Some(environment_override) = Self::get_environment_override() {
+            self.add_environment_override(environment_override)?
         }
-        config.validate()?;
-        Ok(config)
+        self.validate()?;
+        Ok(self)
     }
 
     /// Get the environment override from the current process if it exists.
@@ -107,8 +147,15 @@ impl Config {
 
     /// Create a new Config object from the config file.
     fn from_config_file() -> Result<Self> {
+        ps::config_file()
+            .map_err(|e| Error::config_file_not_found(e.to_string()))
+            .and_then(Self::from_path)
+    }
+
+    /// Create a new Config object from the file at `path`.
+    fn from_path<P: AsRef<path::Path>>(path: P) -> Result<Self> {
         let mut file_contents = String::new();
-        File::open(ps::config_file().map_err(|e| Error::config_file_not_found(e.to_string()))?)
+        File::open(path.as_ref())
             .map_err(|e| Error::config_file_not_found(e.to_string()))
             .and_then(|mut file| {
                 file.read_to_string(&mut file_contents)
@@ -131,6 +178,16 @@ impl Config {
             api_settings,
             environment_override: false,
             status_server_port,
+            status_server_bind: c::CONFIG_DEFAULT_BIND_ADDRESS,
+            api_retries: c::CONFIG_DEFAULT_API_RETRIES,
+            api_rate_limit_per_sec: c::CONFIG_DEFAULT_API_RATE_LIMIT_PER_SEC,
+            api_name_cache_ttl_secs: c::CONFIG_DEFAULT_API_NAME_CACHE_TTL_SECS,
+            timeseries_max_frame_bytes: c::CONFIG_DEFAULT_TIMESERIES_MAX_FRAME_BYTES,
+            check_updates: true,
+            version_check_interval_hours: c::CONFIG_DEFAULT_VERSION_CHECK_INTERVAL_HOURS,
+            upload_retry_after_minutes: c::CONFIG_DEFAULT_UPLOAD_RETRY_AFTER_MINUTES,
+            upload_fail_after_hours: c::CONFIG_DEFAULT_UPLOAD_FAIL_AFTER_HOURS,
+            upload_max_retries: c::CONFIG_DEFAULT_UPLOAD_MAX_RETRIES,
         }
     }
 
@@ -159,6 +216,16 @@ impl Config {
         })
     }
 
+    /// Points every profile at `environment`, as when it is overridden for
+    /// a single run with `--env`. Only this copy of the configuration is
+    /// changed, not the config file.
+    pub fn with_environment(mut self, environment: ApiEnvironment) -> Self {
+        for profile in self.api_settings.profiles.values_mut() {
+            profile.environment = environment;
+        }
+        self
+    }
+
     /// Write this object to a config file. Overwrite the existing
     /// config file if it exists, preserving any untouched keys or
     /// sections.
@@ -185,6 +252,47 @@ impl Default for Config {
     }
 }
 
+/// The strategy the cache collector uses to choose which pages to evict.
+#[derive(Copy, Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+pub enum EvictionPolicy {
+    /// Only evict pages that have not been used within a fixed window
+    /// (one week for soft cleanups, 12 hours for hard cleanups).
+    TimeAged,
+    /// Evict the least recently used pages until the cache size target is
+    /// met, regardless of when they were last used.
+    Lru,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::TimeAged
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EvictionPolicy::TimeAged => write!(f, "time_aged"),
+            EvictionPolicy::Lru => write!(f, "lru"),
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "time_aged" => Ok(EvictionPolicy::TimeAged),
+            "lru" => Ok(EvictionPolicy::Lru),
+            _ => Err(Error::invalid_api_config(format!(
+                "bad value for configuration option \"cache_eviction_policy\": {}",
+                s
+            ))),
+        }
+    }
+}
+
 /// A typeful representation of the "[cache]" section of the agent's
 /// configuration file.
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
@@ -193,6 +301,11 @@ pub struct CacheConfig {
     page_size: u32,
     soft_cache_size: u64,
     hard_cache_size: u64,
+    eviction_policy: EvictionPolicy,
+    compress: bool,
+    parallelism: usize,
+    soft_age_days: u64,
+    hard_age_hours: u64,
 }
 
 impl CacheConfig {
@@ -206,6 +319,11 @@ impl CacheConfig {
             page_size,
             soft_cache_size,
             hard_cache_size,
+            eviction_policy: EvictionPolicy::default(),
+            compress: false,
+            parallelism: c::CONFIG_DEFAULT_CACHE_PARALLELISM,
+            soft_age_days: c::CONFIG_DEFAULT_CACHE_SOFT_AGE_DAYS,
+            hard_age_hours: c::CONFIG_DEFAULT_CACHE_HARD_AGE_HOURS,
         }
     }
 
@@ -216,11 +334,16 @@ impl CacheConfig {
     }
 
     /// Returns a path that represents that location of where
-    /// the template file should exist.
+    /// the template file should exist. Compressed and uncompressed
+    /// templates are kept apart.
     pub fn get_template_path(&self) -> path::PathBuf {
         let mut template_path = path::PathBuf::from(&self.base_path);
         template_path.push("templates");
-        template_path.push(self.page_size.to_string());
+        if self.compress {
+            template_path.push(format!("{}-compressed", self.page_size));
+        } else {
+            template_path.push(self.page_size.to_string());
+        }
         template_path.set_extension("bin");
         template_path
     }
@@ -237,6 +360,32 @@ impl CacheConfig {
         self.hard_cache_size
     }
 
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.eviction_policy
+    }
+
+    /// Whether cache pages are gzip compressed on disk.
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+
+    /// The number of fetched segments written to the cache at once.
+    pub fn parallelism(&self) -> usize {
+        self.parallelism
+    }
+
+    /// How many days a page must go unused before a soft recycle may
+    /// evict it.
+    pub fn soft_age_days(&self) -> u64 {
+        self.soft_age_days
+    }
+
+    /// How many hours a page must go unused before a hard recycle may
+    /// evict it.
+    pub fn hard_age_hours(&self) -> u64 {
+        self.hard_age_hours
+    }
+
     pub fn set_page_size(&mut self, size: u32) {
         self.page_size = size;
     }
@@ -246,6 +395,21 @@ impl CacheConfig {
     pub fn set_hard_cache_size(&mut self, size: u64) {
         self.hard_cache_size = size;
     }
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.eviction_policy = policy;
+    }
+    pub fn set_compress(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+    pub fn set_parallelism(&mut self, parallelism: usize) {
+        self.parallelism = parallelism;
+    }
+    pub fn set_soft_age_days(&mut self, days: u64) {
+        self.soft_age_days = days;
+    }
+    pub fn set_hard_age_hours(&mut self, hours: u64) {
+        self.hard_age_hours = hours;
+    }
 }
 
 impl Default for CacheConfig {
@@ -261,6 +425,7 @@ impl Default for CacheConfig {
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
 pub struct ProxyService {
+    pub local_bind: IpAddr,
     pub local_port: u16,
     pub remote_host: String,
     pub remote_port: u16,
@@ -268,6 +433,7 @@ pub struct ProxyService {
 impl Default for ProxyService {
     fn default() -> Self {
         Self {
+            local_bind: c::CONFIG_DEFAULT_BIND_ADDRESS,
             local_port: c::CONFIG_DEFAULT_PROXY_LOCAL_PORT,
             remote_port: c::CONFIG_DEFAULT_PROXY_REMOTE_PORT,
             remote_host: c::CONFIG_DEFAULT_PROXY_REMOTE_HOST.to_string(),
@@ -275,6 +441,9 @@ impl Default for ProxyService {
     }
 }
 impl ProxyService {
+    pub fn set_local_bind(&mut self, local_bind: IpAddr) {
+        self.local_bind = local_bind;
+    }
     pub fn set_local_port(&mut self, local_port: u16) {
         self.local_port = local_port;
     }
@@ -288,6 +457,7 @@ impl ProxyService {
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
 pub struct TimeSeriesService {
+    pub local_bind: IpAddr,
     pub local_port: u16,
     pub remote_host: String,
     pub remote_port: u16,
@@ -295,6 +465,7 @@ pub struct TimeSeriesService {
 impl Default for TimeSeriesService {
     fn default() -> Self {
         Self {
+            local_bind: c::CONFIG_DEFAULT_BIND_ADDRESS,
             local_port: c::CONFIG_DEFAULT_TIMESERIES_LOCAL_PORT,
             remote_port: c::CONFIG_DEFAULT_TIMESERIES_REMOTE_PORT,
             remote_host: c::CONFIG_DEFAULT_TIMESERIES_REMOTE_HOST.to_string(),
@@ -302,6 +473,9 @@ impl Default for TimeSeriesService {
     }
 }
 impl TimeSeriesService {
+    pub fn set_local_bind(&mut self, local_bind: IpAddr) {
+        self.local_bind = local_bind;
+    }
     pub fn set_local_port(&mut self, local_port: u16) {
         self.local_port = local_port;
     }
@@ -362,24 +536,126 @@ impl fmt::Display for Config {
                 self.cache.hard_cache_size.to_string(),
             );
 
+        if self.cache.eviction_policy != EvictionPolicy::default() {
+            agent_section(&mut ini).set(
+                "cache_eviction_policy",
+                self.cache.eviction_policy.to_string(),
+            );
+        }
+
+        if self.cache.compress {
+            agent_section(&mut ini).set("cache_compress", "true");
+        }
+
+        if self.cache.parallelism != c::CONFIG_DEFAULT_CACHE_PARALLELISM {
+            agent_section(&mut ini).set("cache_parallelism", self.cache.parallelism.to_string());
+        }
+
+        if self.cache.soft_age_days != c::CONFIG_DEFAULT_CACHE_SOFT_AGE_DAYS {
+            agent_section(&mut ini)
+                .set("cache_soft_age_days", self.cache.soft_age_days.to_string());
+        }
+
+        if self.cache.hard_age_hours != c::CONFIG_DEFAULT_CACHE_HARD_AGE_HOURS {
+            agent_section(&mut ini).set(
+                "cache_hard_age_hours",
+                self.cache.hard_age_hours.to_string(),
+            );
+        }
+
         // services
         // Note that we don't expose the ability to configure remote
         // host/port to users
         for service in &self.services {
             let mut agent_section = agent_section(&mut ini);
             match service {
-                Service::Proxy(ProxyService { local_port, .. }) => agent_section
-                    .set("proxy", "true")
-                    .set("proxy_local_port", local_port.to_string().clone()),
-                Service::TimeSeries(TimeSeriesService { local_port, .. }) => agent_section
-                    .set("timeseries", "true")
-                    .set("timeseries_local_port", local_port.to_string().clone()),
-                Service::Uploader(_) => agent_section.set("uploader", "true"),
+                Service::Proxy(ProxyService {
+                    local_bind,
+                    local_port,
+                    ..
+                }) => {
+                    agent_section
+                        .set("proxy", "true")
+                        .set("proxy_local_port", local_port.to_string().clone());
+                    if *local_bind != c::CONFIG_DEFAULT_BIND_ADDRESS {
+                        agent_section.set("proxy_local_bind", local_bind.to_string());
+                    }
+                }
+                Service::TimeSeries(TimeSeriesService {
+                    local_bind,
+                    local_port,
+                    ..
+                }) => {
+                    agent_section
+                        .set("timeseries", "true")
+                        .set("timeseries_local_port", local_port.to_string().clone());
+                    if *local_bind != c::CONFIG_DEFAULT_BIND_ADDRESS {
+                        agent_section.set("timeseries_local_bind", local_bind.to_string());
+                    }
+                }
+                Service::Uploader(_) => {
+                    agent_section.set("uploader", "true");
+                }
             };
         }
 
+        if self.timeseries_max_frame_bytes != c::CONFIG_DEFAULT_TIMESERIES_MAX_FRAME_BYTES {
+            agent_section(&mut ini).set(
+                "timeseries_max_frame_bytes",
+                self.timeseries_max_frame_bytes.to_string(),
+            );
+        }
+
         // status server:
         agent_section(&mut ini).set("status_port", self.status_server_port.to_string());
+        if self.status_server_bind != c::CONFIG_DEFAULT_BIND_ADDRESS {
+            agent_section(&mut ini).set("status_bind", self.status_server_bind.to_string());
+        }
+
+        // api settings:
+        if self.api_retries != c::CONFIG_DEFAULT_API_RETRIES {
+            agent_section(&mut ini).set("api_retries", self.api_retries.to_string());
+        }
+        if self.api_rate_limit_per_sec != c::CONFIG_DEFAULT_API_RATE_LIMIT_PER_SEC {
+            agent_section(&mut ini).set(
+                "api_rate_limit_per_sec",
+                self.api_rate_limit_per_sec.to_string(),
+            );
+        }
+        if self.api_name_cache_ttl_secs != c::CONFIG_DEFAULT_API_NAME_CACHE_TTL_SECS {
+            agent_section(&mut ini).set(
+                "api_name_cache_ttl_secs",
+                self.api_name_cache_ttl_secs.to_string(),
+            );
+        }
+
+        // version checks:
+        if !self.check_updates {
+            agent_section(&mut ini).set("check_updates", "false");
+        }
+        if self.version_check_interval_hours != c::CONFIG_DEFAULT_VERSION_CHECK_INTERVAL_HOURS {
+            agent_section(&mut ini).set(
+                "version_check_interval_hours",
+                self.version_check_interval_hours.to_string(),
+            );
+        }
+
+        // upload lifecycle:
+        if self.upload_retry_after_minutes != c::CONFIG_DEFAULT_UPLOAD_RETRY_AFTER_MINUTES {
+            agent_section(&mut ini).set(
+                "upload_retry_after_minutes",
+                self.upload_retry_after_minutes.to_string(),
+            );
+        }
+        if self.upload_fail_after_hours != c::CONFIG_DEFAULT_UPLOAD_FAIL_AFTER_HOURS {
+            agent_section(&mut ini).set(
+                "upload_fail_after_hours",
+                self.upload_fail_after_hours.to_string(),
+            );
+        }
+        if self.upload_max_retries != c::CONFIG_DEFAULT_UPLOAD_MAX_RETRIES {
+            agent_section(&mut ini).set("upload_max_retries", self.upload_max_retries.to_string());
+        }
 
         // profiles
         for (profile_name, profile) in &self.api_settings.profiles {
@@ -391,6 +667,16 @@ impl fmt::Display for Config {
                 ini.with_section(Some(profile_name.clone()))
                     .set(c::ENVIRONMENT_KEY, profile.environment.to_string());
             }
+
+            if let Some(ref proxy) = profile.http_proxy {
+                ini.with_section(Some(profile_name.clone()))
+                    .set(c::HTTP_PROXY_KEY, proxy.to_string());
+            }
+
+            if let Some(ref proxy) = profile.https_proxy {
+                ini.with_section(Some(profile_name.clone()))
+                    .set(c::HTTPS_PROXY_KEY, proxy.to_string());
+            }
         }
 
         let mut bytes: Vec<u8> = vec![];
@@ -444,16 +730,81 @@ impl FromStr for Config {
             c::CONFIG_DEFAULT_HARD_CACHE_SIZE,
         )?;
 
-        let cache_config = CacheConfig::new(
+        let cache_eviction_policy = match agent_settings.store().get("cache_eviction_policy") {
+            Some(policy) => policy.parse::<EvictionPolicy>()?,
+            None => EvictionPolicy::default(),
+        };
+        let cache_compress =
+            agent_settings.get_as_and_update::<_, bool>("cache_compress", false)?;
+        let cache_parallelism = agent_settings.get_as_and_update::<_, usize>(
+            "cache_parallelism",
+            c::CONFIG_DEFAULT_CACHE_PARALLELISM,
+        )?;
+        if cache_parallelism == 0 {
+            return Err(Error::invalid_api_config(
+                "bad value for configuration option \"cache_parallelism\": it must be at least 1",
+            ));
+        }
+
+        let cache_soft_age_days = agent_settings.get_as_and_update::<_, u64>(
+            "cache_soft_age_days",
+            c::CONFIG_DEFAULT_CACHE_SOFT_AGE_DAYS,
+        )?;
+        let cache_hard_age_hours = agent_settings.get_as_and_update::<_, u64>(
+            "cache_hard_age_hours",
+            c::CONFIG_DEFAULT_CACHE_HARD_AGE_HOURS,
+        )?;
+
+        let mut cache_config = CacheConfig::new(
             cache_base_path,
             cache_page_size,
             cache_soft_cache_size,
             cache_hard_cache_size,
         );
+        cache_config.set_eviction_policy(cache_eviction_policy);
+        cache_config.set_compress(cache_compress);
+        cache_config.set_parallelism(cache_parallelism);
+        cache_config.set_soft_age_days(cache_soft_age_days);
+        cache_config.set_hard_age_hours(cache_hard_age_hours);
 
         // status server port:
         let status_server_port = agent_settings
             .get_as_and_update::<_, u16>("status_port", c::CONFIG_DEFAULT_STATUS_WEBSOCKET_PORT)?;
+        let status_server_bind = agent_settings
+            .get_as_and_update::<_, IpAddr>("status_bind", c::CONFIG_DEFAULT_BIND_ADDRESS)?;
+
+        // api request retries:
+        let api_retries = agent_settings
+            .get_as_and_update::<_, u32>("api_retries", c::CONFIG_DEFAULT_API_RETRIES)?;
+        let api_rate_limit_per_sec = agent_settings.get_as_and_update::<_, u32>(
+            "api_rate_limit_per_sec",
+            c::CONFIG_DEFAULT_API_RATE_LIMIT_PER_SEC,
+        )?;
+        let api_name_cache_ttl_secs = agent_settings.get_as_and_update::<_, u64>(
+            "api_name_cache_ttl_secs",
+            c::CONFIG_DEFAULT_API_NAME_CACHE_TTL_SECS,
+        )?;
+
+        // version checks:
+        let check_updates = agent_settings.get_as_and_update::<_, bool>("check_updates", true)?;
+        let version_check_interval_hours = agent_settings.get_as_and_update::<_, u64>(
+            "version_check_interval_hours",
+            c::CONFIG_DEFAULT_VERSION_CHECK_INTERVAL_HOURS,
+        )?;
+
+        // upload lifecycle:
+        let upload_retry_after_minutes = agent_settings.get_as_and_update::<_, u64>(
+            "upload_retry_after_minutes",
+            c::CONFIG_DEFAULT_UPLOAD_RETRY_AFTER_MINUTES,
+        )?;
+        let upload_fail_after_hours = agent_settings.get_as_and_update::<_, u64>(
+            "upload_fail_after_hours",
+            c::CONFIG_DEFAULT_UPLOAD_FAIL_AFTER_HOURS,
+        )?;
+        let upload_max_retries = agent_settings.get_as_and_update::<_, u16>(
+            "upload_max_retries",
+            c::CONFIG_DEFAULT_UPLOAD_MAX_RETRIES,
+        )?;
 
         // services
         let mut services: Vec<Service> = vec![];
@@ -463,6 +814,10 @@ impl FromStr for Config {
         {
             let proxy_enabled = agent_settings
                 .get_as_and_update::<_, bool>("proxy", c::CONFIG_ENABLE_SERVICES_BY_DEFAULT)?;
+            let proxy_local_bind = agent_settings.get_as_and_update::<_, IpAddr>(
+                "proxy_local_bind",
+                c::CONFIG_DEFAULT_BIND_ADDRESS,
+            )?;
             let proxy_local_port = agent_settings.get_as_and_update::<_, u16>(
                 "proxy_local_port",
                 c::CONFIG_DEFAULT_PROXY_LOCAL_PORT,
@@ -478,6 +833,7 @@ impl FromStr for Config {
 
             if proxy_enabled {
                 let mut service = ProxyService::default();
+                service.set_local_bind(proxy_local_bind);
                 service.set_local_port(proxy_local_port);
                 service.set_remote_port(proxy_remote_port);
                 service.set_remote_host(proxy_remote_host.clone());
@@ -487,9 +843,17 @@ impl FromStr for Config {
 
         // timeseries service -- only disable if timeseries=false is explicitly
         // provided in config.ini
+        let timeseries_max_frame_bytes = agent_settings.get_as_and_update::<_, usize>(
+            "timeseries_max_frame_bytes",
+            c::CONFIG_DEFAULT_TIMESERIES_MAX_FRAME_BYTES,
+        )?;
         {
             let timeseries_enabled = agent_settings
                 .get_as_and_update::<_, bool>("timeseries", c::CONFIG_ENABLE_SERVICES_BY_DEFAULT)?;
+            let timeseries_local_bind = agent_settings.get_as_and_update::<_, IpAddr>(
+                "timeseries_local_bind",
+                c::CONFIG_DEFAULT_BIND_ADDRESS,
+            )?;
             let timeseries_local_port = agent_settings.get_as_and_update::<_, u16>(
                 "timeseries_local_port",
                 c::CONFIG_DEFAULT_TIMESERIES_LOCAL_PORT,
@@ -505,6 +869,7 @@ impl FromStr for Config {
 
             if timeseries_enabled {
                 let mut service = TimeSeriesService::default();
+                service.set_local_bind(timeseries_local_bind);
                 service.set_local_port(timeseries_local_port);
                 service.set_remote_port(timeseries_remote_port);
                 service.set_remote_host(timeseries_remote_host.clone());
@@ -544,13 +909,25 @@ impl FromStr for Config {
 
         let api_settings = api::Settings::new(profiles, global_settings, agent_settings)?;
 
-        Ok(Config::new(
+        let mut config = Config::new(
             cache_config,
             metrics,
             services,
             api_settings,
             status_server_port,
-        ))
+        );
+        config.status_server_bind = status_server_bind;
+        config.api_retries = api_retries;
+        config.api_rate_limit_per_sec = api_rate_limit_per_sec;
+        config.api_name_cache_ttl_secs = api_name_cache_ttl_secs;
+        config.timeseries_max_frame_bytes = timeseries_max_frame_bytes;
+        config.check_updates = check_updates;
+        config.version_check_interval_hours = version_check_interval_hours;
+        config.upload_retry_after_minutes = upload_retry_after_minutes;
+        config.upload_fail_after_hours = upload_fail_after_hours;
+        config.upload_max_retries = upload_max_retries;
+
+        Ok(config)
     }
 }
 
@@ -594,24 +971,50 @@ fn overwrite_configuration_file<S: Into<String>>(new_contents: S) -> Result<()>
             return Ok(());
         }
 
-        // remove the old config file
-        fs::remove_file(path.clone())?;
-
         let existing_config = Ini::load_from_str(&old_contents)?;
         merge_ini(&existing_config, &mut new_config);
     }
 
-    // recreate the config file
-    let mut file = File::create(path)?;
-
     // convert the new_config to a string
     let mut bytes: Vec<u8> = vec![];
     new_config.write_to(&mut bytes).unwrap();
     let managed_config = str::from_utf8(&bytes).unwrap();
 
-    // write the managed config string to the config file, prepended
+    // replace the config file with the managed config string, prepended
     // with PS_HEADER
-    write!(file, "{}\n{}", PS_HEADER, managed_config)?;
+    write_atomically(&path, |file| {
+        write!(file, "{}\n{}", PS_HEADER, managed_config)
+    })
+}
+
+/// Replaces the file at `path` with what `write` writes to it, without
+/// ever leaving a partially written file behind: the contents are written
+/// to a temporary file in the same directory, which is only renamed over
+/// `path` once `write` succeeded. The file being replaced is kept next to
+/// it, with a `.bak` extension.
+fn write_atomically<F>(path: &path::Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    let dir = match path.parent() {
+        Some(dir) if dir != path::Path::new("") => dir,
+        _ => path::Path::new("."),
+    };
+
+    // the temporary file is removed if anything below fails:
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    write(temp.as_file_mut())?;
+    temp.as_file().sync_all()?;
+
+    if path.exists() {
+        fs::set_permissions(temp.path(), fs::metadata(path)?.permissions())?;
+
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        fs::copy(path, backup)?;
+    }
+
+    temp.persist(path).map_err(|e| e.error)?;
     Ok(())
 }
 
@@ -649,6 +1052,7 @@ Would you like to overwrite your existing configuration?",
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ps::util;
 
     #[test]
     fn fail_to_parse_invalid_ini_string() {
@@ -705,6 +1109,71 @@ mod tests {
         )
     }
 
+    #[test]
+    fn config_files_are_replaced_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.ini");
+        let backup = dir.path().join("config.ini.bak");
+        fs::write(&path, "[global]\ndefault_profile=default\n").unwrap();
+
+        // A write that fails partway through leaves the file untouched:
+        let result = write_atomically(&path, |file| {
+            write!(file, "[global]\ndefault_")?;
+            Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[global]\ndefault_profile=default\n"
+        );
+        assert!(!backup.exists());
+
+        // ...while a complete one replaces it, keeping the old file:
+        write_atomically(&path, |file| {
+            write!(file, "[global]\ndefault_profile=lab\n")
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[global]\ndefault_profile=lab\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&backup).unwrap(),
+            "[global]\ndefault_profile=default\n"
+        );
+
+        // ...and no temporary files are left behind:
+        let mut files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["config.ini", "config.ini.bak"]);
+    }
+
+    #[test]
+    fn config_file_override_takes_precedence() {
+        let path = util::path::temp("config", ".ini").unwrap();
+        fs::write(
+            &path,
+            test_ini_with_agent_settings(
+                r#"
+            status_port = 12345
+        "#,
+            ),
+        )
+        .unwrap();
+
+        let config = Config::from_path_and_environment(&path).unwrap();
+        assert_eq!(config.status_server_port, 12345);
+
+        let _config_file = ps::ConfigFileGuard::set(path.clone());
+        assert_eq!(ps::config_file().unwrap().to_path_buf(), path);
+
+        let config = Config::from_config_file_and_environment().unwrap();
+        assert_eq!(config.status_server_port, 12345);
+    }
+
     #[test]
     fn valid_proxy_raw_config() {
         let ini_str = test_ini_with_agent_settings(
@@ -733,11 +1202,13 @@ mod tests {
         cache_cfg.set_hard_cache_size(50000);
 
         let proxy = Service::Proxy(ProxyService {
+            local_bind: c::CONFIG_DEFAULT_BIND_ADDRESS,
             local_port: 8000,
             remote_host: "https://www.google.com".to_string(),
             remote_port: 443,
         });
         let websocket = Service::TimeSeries(TimeSeriesService {
+            local_bind: c::CONFIG_DEFAULT_BIND_ADDRESS,
             local_port: 8001,
             remote_host: "wss://echo.websocket.org".to_string(),
             remote_port: 443,
@@ -814,6 +1285,269 @@ mod tests {
         assert!(config.services.len() > 0);
     }
 
+    #[test]
+    fn valid_cache_eviction_policy() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            cache_eviction_policy = lru
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.cache.eviction_policy(), EvictionPolicy::Lru);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.cache.eviction_policy(), EvictionPolicy::TimeAged);
+
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            cache_eviction_policy = newest
+        "#,
+        );
+        assert!((&ini_str).parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn valid_cache_compress() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            cache_compress = true
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert!(config.cache.compress());
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert!(config.cache.compress());
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert!(!config.cache.compress());
+    }
+
+    #[test]
+    fn valid_cache_parallelism() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            cache_parallelism = 8
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.cache.parallelism(), 8);
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert_eq!(config.cache.parallelism(), 8);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(
+            config.cache.parallelism(),
+            c::CONFIG_DEFAULT_CACHE_PARALLELISM
+        );
+
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            cache_parallelism = 0
+        "#,
+        );
+        assert!((&ini_str).parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn valid_cache_age_thresholds() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            cache_soft_age_days = 30
+            cache_hard_age_hours = 2
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.cache.soft_age_days(), 30);
+        assert_eq!(config.cache.hard_age_hours(), 2);
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert_eq!(config.cache.soft_age_days(), 30);
+        assert_eq!(config.cache.hard_age_hours(), 2);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(
+            config.cache.soft_age_days(),
+            c::CONFIG_DEFAULT_CACHE_SOFT_AGE_DAYS
+        );
+        assert_eq!(
+            config.cache.hard_age_hours(),
+            c::CONFIG_DEFAULT_CACHE_HARD_AGE_HOURS
+        );
+    }
+
+    #[test]
+    fn valid_api_retries() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            api_retries = 5
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.api_retries, 5);
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert_eq!(config.api_retries, 5);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.api_retries, c::CONFIG_DEFAULT_API_RETRIES);
+    }
+
+    #[test]
+    fn valid_api_rate_limit() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            api_rate_limit_per_sec = 2
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.api_rate_limit_per_sec, 2);
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert_eq!(config.api_rate_limit_per_sec, 2);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(
+            config.api_rate_limit_per_sec,
+            c::CONFIG_DEFAULT_API_RATE_LIMIT_PER_SEC
+        );
+    }
+
+    #[test]
+    fn valid_api_name_cache_ttl() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            api_name_cache_ttl_secs = 0
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.api_name_cache_ttl_secs, 0);
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert_eq!(config.api_name_cache_ttl_secs, 0);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(
+            config.api_name_cache_ttl_secs,
+            c::CONFIG_DEFAULT_API_NAME_CACHE_TTL_SECS
+        );
+    }
+
+    #[test]
+    fn valid_timeseries_max_frame_bytes() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            timeseries_max_frame_bytes = 65536
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.timeseries_max_frame_bytes, 65536);
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert_eq!(config.timeseries_max_frame_bytes, 65536);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.timeseries_max_frame_bytes, 0);
+    }
+
+    #[test]
+    fn valid_version_check_settings() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            check_updates = false
+            version_check_interval_hours = 168
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert!(!config.check_updates);
+        assert_eq!(config.version_check_interval_hours, 168);
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert!(!config.check_updates);
+        assert_eq!(config.version_check_interval_hours, 168);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert!(config.check_updates);
+        assert_eq!(
+            config.version_check_interval_hours,
+            c::CONFIG_DEFAULT_VERSION_CHECK_INTERVAL_HOURS
+        );
+    }
+
+    #[test]
+    fn valid_upload_lifecycle_settings() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            upload_retry_after_minutes = 15
+            upload_fail_after_hours = 48
+            upload_max_retries = 3
+        "#,
+        );
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.upload_retry_after_minutes, 15);
+        assert_eq!(config.upload_fail_after_hours, 48);
+        assert_eq!(config.upload_max_retries, 3);
+        let config = config.to_string().parse::<Config>().unwrap();
+        assert_eq!(config.upload_retry_after_minutes, 15);
+        assert_eq!(config.upload_fail_after_hours, 48);
+        assert_eq!(config.upload_max_retries, 3);
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(
+            config.upload_retry_after_minutes,
+            c::CONFIG_DEFAULT_UPLOAD_RETRY_AFTER_MINUTES
+        );
+        assert_eq!(
+            config.upload_fail_after_hours,
+            c::CONFIG_DEFAULT_UPLOAD_FAIL_AFTER_HOURS
+        );
+        assert_eq!(
+            config.upload_max_retries,
+            c::CONFIG_DEFAULT_UPLOAD_MAX_RETRIES
+        );
+    }
+
+    #[test]
+    fn valid_bind_addresses() {
+        let ini_str = test_ini_with_agent_settings(
+            r#"
+            status_bind = 0.0.0.0
+            proxy_local_bind = 192.168.1.10
+            timeseries_local_bind = ::1
+        "#,
+        );
+        let check = |config: &Config| {
+            assert_eq!(
+                config.status_server_bind,
+                "0.0.0.0".parse::<IpAddr>().unwrap()
+            );
+            for service in config.get_services() {
+                match service {
+                    Service::Proxy(proxy) => {
+                        assert_eq!(proxy.local_bind, "192.168.1.10".parse::<IpAddr>().unwrap())
+                    }
+                    Service::TimeSeries(ts) => {
+                        assert_eq!(ts.local_bind, "::1".parse::<IpAddr>().unwrap())
+                    }
+                    Service::Uploader(_) => (),
+                }
+            }
+        };
+        let config = (&ini_str).parse::<Config>().unwrap();
+        check(&config);
+        check(&config.to_string().parse::<Config>().unwrap());
+
+        let ini_str = test_ini_with_agent_settings("");
+        let config = (&ini_str).parse::<Config>().unwrap();
+        assert_eq!(config.status_server_bind, c::CONFIG_DEFAULT_BIND_ADDRESS);
+
+        let ini_str = test_ini_with_agent_settings("status_bind = localhost");
+        assert!((&ini_str).parse::<Config>().is_err());
+    }
+
     #[test]
     fn invalid_proxy_config() {
         let ini_str = r#"
@@ -43,6 +43,22 @@ impl Error {
         }
         .into()
     }
+
+    pub fn irreversible_migration<S: Into<String>>(version: usize, name: S) -> Error {
+        ErrorKind::IrreversibleMigration {
+            version,
+            name: name.into(),
+        }
+        .into()
+    }
+
+    pub fn invalid_rollback(current_version: usize, version: usize) -> Error {
+        ErrorKind::InvalidRollback {
+            current_version,
+            version,
+        }
+        .into()
+    }
 }
 
 impl Fail for Error {
@@ -82,6 +98,21 @@ pub enum ErrorKind {
         sql: String,
     },
 
+    #[fail(
+        display = "Migration {} can't be rolled back, it has no down-script: {}",
+        version, name
+    )]
+    IrreversibleMigration { version: usize, name: String },
+
+    #[fail(
+        display = "Can't roll back from schema version {} to version {}",
+        current_version, version
+    )]
+    InvalidRollback {
+        current_version: usize,
+        version: usize,
+    },
+
     #[fail(display = "r2d2 error: {}", error)]
     R2d2Error { error: String },
 
@@ -100,6 +131,9 @@ pub enum ErrorKind {
     #[fail(display = "I/O error: {}", error)]
     IoError { error: String },
 
+    #[fail(display = "JSON error: {}", error)]
+    JsonError { error: String },
+
     #[fail(display = "Config error: {}", kind)]
     ConfigError { kind: config::ErrorKind },
 }
@@ -113,6 +147,15 @@ impl From<io::Error> for Error {
     }
 }
 
+/// map from JSON errors
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::from(Context::new(ErrorKind::JsonError {
+            error: error.to_string(),
+        }))
+    }
+}
+
 /// map from r2d2 errors
 impl From<r2d2::Error> for Error {
     fn from(error: r2d2::Error) -> Error {
@@ -1,22 +1,25 @@
 //! The database layer that uses SQLite for persistence.
 
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::slice;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::vec::IntoIter;
 use std::{fmt, result};
 
 use log::*;
-use r2d2::{Pool, PooledConnection};
+use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{OptionalExtension, Row, NO_PARAMS};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Row, NO_PARAMS};
 use serde_derive::Serialize;
 use time;
 
 mod error;
 
-use pennsieve_migrations::Migrations;
+use pennsieve_migrations::{Migration, Migrations};
 use pennsieve_rust::Environment as ApiEnvironment;
 
 // Re-export:
@@ -27,17 +30,25 @@ use crate::ps::agent::config;
 /// This is used to support only one login at a time.
 const USER_INNER_ID: i32 = 1;
 
+/// The checksum recorded for NaN filled pages, which have no file on disk
+/// to checksum. Real checksums are CRC32s, so are never negative.
+pub const NAN_PAGE_CHECKSUM: i64 = -1;
+
+/// The last error recorded for uploads that were in progress when the agent
+/// stopped, and are queued again when it starts.
+pub const STALLED_UPLOAD_ERROR: &str = "the agent stopped while the upload was in progress";
+
+/// Used to give every in-memory database in the process a unique name.
+static MEMORY_DATABASE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 /// Source used to configure which backing scheme to use for SQLite.
 #[derive(Debug)]
 pub enum Source {
     // A file backed database.
     File(PathBuf),
-    // In memory database.
-    // NOTE: this type should not be used. There seems to be a bug
-    // where calling `.clone()`, on the resource pool, creates a new
-    // in memory database, as opposed to allowing multiple connections
-    // to the same one.
-    //Memory,
+    // An in memory database, discarded when the `Database` and all of
+    // its clones are dropped.
+    Memory,
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -176,6 +187,13 @@ impl UserRecord {
 
         expires.gt(&time::now().to_timespec())
     }
+
+    /// Returns a boolean based on whether a new login is needed to obtain
+    /// a session token for this user, either because the current token is
+    /// no longer valid, or because a refresh is being forced.
+    pub fn requires_login(&self, force: bool) -> bool {
+        force || !self.is_token_valid()
+    }
 }
 
 /// Changeable user-specific settings, like persistent dataset, etc.
@@ -271,6 +289,94 @@ impl fmt::Display for UploadStatus {
     }
 }
 
+/// How long uploads are given before the upload worker steps in, as set by
+/// `upload_retry_after_minutes` and `upload_fail_after_hours` in the agent
+/// config, and how often they are attempted again, as set by
+/// `upload_max_retries`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadThresholds {
+    /// How long an in progress upload may go without an update before it
+    /// is retried.
+    pub retry_after: time::Duration,
+    /// How long after it was queued an upload is marked failed.
+    pub fail_after: time::Duration,
+    /// How many times an upload whose session expired is attempted again
+    /// before it is marked failed.
+    pub max_retries: u16,
+}
+
+impl UploadThresholds {
+    pub fn new(retry_after_minutes: u64, fail_after_hours: u64) -> Self {
+        Self {
+            retry_after: time::Duration::minutes(retry_after_minutes as i64),
+            fail_after: time::Duration::hours(fail_after_hours as i64),
+            max_retries: config::constants::CONFIG_DEFAULT_UPLOAD_MAX_RETRIES,
+        }
+    }
+
+    /// Attempts an upload again at most `max_retries` times.
+    pub fn with_max_retries(self, max_retries: u16) -> Self {
+        Self {
+            max_retries,
+            ..self
+        }
+    }
+}
+
+impl Default for UploadThresholds {
+    fn default() -> Self {
+        Self::new(
+            config::constants::CONFIG_DEFAULT_UPLOAD_RETRY_AFTER_MINUTES,
+            config::constants::CONFIG_DEFAULT_UPLOAD_FAIL_AFTER_HOURS,
+        )
+    }
+}
+
+impl<'a> From<&'a config::Config> for UploadThresholds {
+    fn from(config: &'a config::Config) -> Self {
+        Self::new(
+            config.upload_retry_after_minutes,
+            config.upload_fail_after_hours,
+        )
+        .with_max_retries(config.upload_max_retries)
+    }
+}
+
+/// How long cache pages must go unused before the collector may evict
+/// them, as set by `cache_soft_age_days` and `cache_hard_age_hours` in the
+/// agent config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageAgeThresholds {
+    /// The age past which pages are evicted by a soft recycle.
+    pub soft_age: time::Duration,
+    /// The age past which pages are evicted by a hard recycle.
+    pub hard_age: time::Duration,
+}
+
+impl PageAgeThresholds {
+    pub fn new(soft_age_days: u64, hard_age_hours: u64) -> Self {
+        Self {
+            soft_age: time::Duration::days(soft_age_days as i64),
+            hard_age: time::Duration::hours(hard_age_hours as i64),
+        }
+    }
+}
+
+impl Default for PageAgeThresholds {
+    fn default() -> Self {
+        Self::new(
+            config::constants::CONFIG_DEFAULT_CACHE_SOFT_AGE_DAYS,
+            config::constants::CONFIG_DEFAULT_CACHE_HARD_AGE_HOURS,
+        )
+    }
+}
+
+impl<'a> From<&'a config::CacheConfig> for PageAgeThresholds {
+    fn from(config: &'a config::CacheConfig) -> Self {
+        Self::new(config.soft_age_days(), config.hard_age_hours())
+    }
+}
+
 /// An upload record represents a single file, defined on the local filesystem,
 /// that will be uploaded to the Pennsieve platform. The cli places records into
 /// this table and the upload worker reads them and attempts to upload.
@@ -290,6 +396,20 @@ pub struct UploadRecord {
     pub organization_id: String,
     pub chunk_size: Option<u64>,
     pub multipart_upload_id: Option<String>,
+    /// Queued uploads with a higher priority are uploaded first.
+    pub priority: i32,
+    /// Why the upload gave up, if it failed.
+    pub failure_reason: Option<String>,
+    /// Properties set on the uploaded package once the upload completes,
+    /// as given with `upload --tag key=value`.
+    pub tags: BTreeMap<String, String>,
+    /// The error the last failed attempt at the upload ran into, even if
+    /// the upload is still being retried. Cleared once it completes.
+    pub last_error: Option<String>,
+    /// The hash of the file when it was queued, computed the way the
+    /// Pennsieve platform computes it. Only recorded for chunked uploads
+    /// queued with `upload --manifest-output`.
+    pub checksum: Option<String>,
 }
 
 impl UploadRecord {
@@ -329,6 +449,11 @@ impl UploadRecord {
                 organization_id: organization_id.into(),
                 chunk_size,
                 multipart_upload_id,
+                priority: 0,
+                failure_reason: None,
+                tags: BTreeMap::new(),
+                last_error: None,
+                checksum: None,
             })
         } else {
             Err(Error::path(file_path.as_ref().to_path_buf()))
@@ -340,8 +465,16 @@ impl UploadRecord {
         let status: String = row.get(6);
         let status: UploadStatus = status.parse()?;
 
-        let chunk_size: Option<u32> = row.get(12);
-        let chunk_size: Option<u64> = chunk_size.map(u64::from);
+        // Chunks can be larger than 4 GiB, so they don't fit in a `u32`:
+        let chunk_size: Option<i64> = row.get(12);
+        let chunk_size: Option<u64> = chunk_size.map(|size| size as u64);
+
+        // Tags are stored as a JSON object, or NULL if there are none:
+        let tags: Option<String> = row.get(16);
+        let tags: BTreeMap<String, String> = match tags {
+            Some(tags) => serde_json::from_str(&tags)?,
+            None => BTreeMap::new(),
+        };
 
         Ok(Self {
             id: Some(row.get(0)),
@@ -358,26 +491,52 @@ impl UploadRecord {
             organization_id: row.get(11),
             chunk_size,
             multipart_upload_id: row.get(13),
+            priority: row.get(14),
+            failure_reason: row.get(15),
+            tags,
+            last_error: row.get(17),
+            checksum: row.get(18),
         })
     }
 
+    /// Sets the priority of this upload. Queued uploads with a higher
+    /// priority are uploaded before those with a lower one, regardless of
+    /// when they were queued.
+    pub fn with_priority(self, priority: i32) -> Self {
+        Self { priority, ..self }
+    }
+
+    /// Sets the tags of this upload, which are set as properties on the
+    /// uploaded package once the upload completes.
+    pub fn with_tags(self, tags: BTreeMap<String, String>) -> Self {
+        Self { tags, ..self }
+    }
+
+    /// Sets the checksum of the file this upload sends, as computed when it
+    /// was queued.
+    pub fn with_checksum(self, checksum: Option<String>) -> Self {
+        Self { checksum, ..self }
+    }
+
     /// Returns a boolean specifying whether this upload should be retried.
-    /// There's a 1 hour threshold for when records can be retried. This
-    /// threshold is based on the records `updated_at` time.
-    pub fn should_retry(&self) -> bool {
-        // retry if the upload has been "in_progress" for 1 hour
-        let threshold = self.updated_at + time::Duration::hours(1);
+    /// Records can be retried once `thresholds.retry_after` (1 hour by
+    /// default) has passed. This threshold is based on the records
+    /// `updated_at` time.
+    pub fn should_retry(&self, thresholds: UploadThresholds) -> bool {
+        // retry if the upload has been "in_progress" for too long
+        let threshold = self.updated_at + thresholds.retry_after;
 
         time::now().to_timespec().gt(&threshold)
     }
 
     /// Returns a boolean specifying whether this upload failed.
-    /// There's a window of 8 hours that a record can be retried in,
-    /// outside of this window it is considered failed.
+    /// There's a window of `thresholds.fail_after` (8 hours by default)
+    /// that a record can be retried in, outside of this window it is
+    /// considered failed.
     /// The threshold is based on the records `created_at` time.
-    pub fn should_fail(&self) -> bool {
-        // uploads fail if it was created more than 8 hours
-        let threshold = self.created_at + time::Duration::hours(8);
+    pub fn should_fail(&self, thresholds: UploadThresholds) -> bool {
+        // uploads fail if they were created too long ago
+        let threshold = self.created_at + thresholds.fail_after;
 
         time::now().to_timespec().gt(&threshold)
     }
@@ -556,10 +715,27 @@ impl fmt::Debug for Database {
 impl Database {
     /// Creates a new database based on the provided source.
     pub fn new(source: &Source) -> Result<Database> {
-        let manager = match *source {
-            Source::File(ref path) => SqliteConnectionManager::file(path),
+        let pool = match *source {
+            Source::File(ref path) => Pool::new(SqliteConnectionManager::file(path))?,
+            Source::Memory => {
+                // A plain `:memory:` connection gets a private database, so
+                // each connection in the pool would see a different one.
+                // Instead, every connection opens the same named, shared
+                // cache database. It lives as long as one connection to it
+                // is open, so the pool must never close idle connections.
+                let uri = format!(
+                    "file:ps-memory-{}-{}?mode=memory&cache=shared",
+                    std::process::id(),
+                    MEMORY_DATABASE_COUNT.fetch_add(1, Ordering::SeqCst)
+                );
+                let manager = SqliteConnectionManager::file(uri)
+                    .with_flags(OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI);
+                Pool::builder()
+                    .idle_timeout(None)
+                    .max_lifetime(None)
+                    .build(manager)?
+            }
         };
-        let pool = Pool::new(manager)?;
         let database = Database { pool };
 
         database.setup()?;
@@ -629,6 +805,16 @@ impl Database {
             )",
             NO_PARAMS,
         )?;
+        count += conn.execute(
+            "CREATE TABLE IF NOT EXISTS name_resolution (
+                organization_id VARCHAR(255) NOT NULL,
+                name TEXT NOT NULL,
+                node_id VARCHAR(255) NOT NULL,
+                resolved_at VARCHAR(255) NOT NULL,
+                PRIMARY KEY (organization_id, name)
+            )",
+            NO_PARAMS,
+        )?;
 
         // Sometimes if a user's agent.db file is messed up, we need to forego
         // running migrations so a manual repair can take place.
@@ -660,9 +846,7 @@ impl Database {
 
     /// Get the version of the schema using SQLite's "PRAGMA user_version"
     /// feature.
-    fn internal_get_schema_version(
-        conn: &PooledConnection<SqliteConnectionManager>,
-    ) -> Result<usize> {
+    fn internal_get_schema_version(conn: &Connection) -> Result<usize> {
         conn.query_row("PRAGMA user_version", NO_PARAMS, |row| {
             let version: u32 = row.get(0);
             version as usize
@@ -676,10 +860,7 @@ impl Database {
     }
 
     /// Increment the schema version, returning the new version.
-    fn internal_set_schema_version(
-        conn: &PooledConnection<SqliteConnectionManager>,
-        version: usize,
-    ) -> Result<usize> {
+    fn internal_set_schema_version(conn: &Connection, version: usize) -> Result<usize> {
         conn.execute_named(format!("PRAGMA user_version = {}", version).as_str(), &[])
             .map_err(Into::into)
     }
@@ -690,7 +871,25 @@ impl Database {
     }
 
     /// Run the migrations in the `<PROJECT_ROOT>/migrations/sql` directory.
-    fn run_migrations(conn: &PooledConnection<SqliteConnectionManager>) -> Result<usize> {
+    fn run_migrations(conn: &Connection) -> Result<usize> {
+        Self::apply_migrations(conn, &Migrations::get_all())
+    }
+
+    /// Runs every pending migration, even if `DISABLE_MIGRATIONS` is set,
+    /// returning the resulting schema version. This is how migrations are
+    /// resumed after a manual repair.
+    pub fn migrate(&self) -> Result<usize> {
+        Self::force_migrations(&self.pool.get()?, &Migrations::get_all())
+    }
+
+    fn force_migrations(conn: &Connection, migrations: &[Migration]) -> Result<usize> {
+        Self::apply_migrations(conn, migrations)?;
+        Self::internal_get_schema_version(conn)
+    }
+
+    /// Apply every migration in `migrations` that is newer than the current
+    /// schema version.
+    fn apply_migrations(conn: &Connection, migrations: &[Migration]) -> Result<usize> {
         let mut latest_version: usize = 0;
 
         // NOTE: `i` starts from 0; by default SQLite's `PRAGMA user_version` is
@@ -711,7 +910,8 @@ impl Database {
         // [DEBUG][pennsieve_rust::ps::agent::database][main] MIGRATION: CURRENT VERSION = 2
         // [DEBUG][pennsieve_rust::ps::agent::database][main] MIGRATION: Running 000003_upload_records_add_org_id.sql@2
         // [DEBUG][pennsieve_rust::ps::agent::database][main] MIGRATION: LATEST VERSION = 3
-        for (i, (filename, contents)) in Migrations::get_all().enumerate() {
+        for (i, migration) in migrations.iter().enumerate() {
+            let filename = &migration.name;
             debug!(
                 "MIGRATION: {filename}@{version}",
                 filename = filename,
@@ -729,8 +929,9 @@ impl Database {
                     filename = filename,
                     version = i
                 );
-                conn.execute_batch(contents.as_ref())
-                    .map_err(|e| Error::migration(current_version, e.to_string(), contents))?;
+                conn.execute_batch(migration.up.as_ref()).map_err(|e| {
+                    Error::migration(current_version, e.to_string(), migration.up.as_ref())
+                })?;
                 latest_version = i + 1;
                 Self::internal_set_schema_version(conn, latest_version)?;
                 debug!(
@@ -749,16 +950,76 @@ impl Database {
         Ok(latest_version)
     }
 
+    /// Roll the schema back to `version` by running the down-scripts of
+    /// every newer migration, most recent first. Fails without changing
+    /// anything if one of those migrations has no down-script.
+    pub fn rollback_to(&self, version: usize) -> Result<usize> {
+        Self::rollback_migrations(&self.pool.get()?, &Migrations::get_all(), version)
+    }
+
+    fn rollback_migrations(
+        conn: &Connection,
+        migrations: &[Migration],
+        version: usize,
+    ) -> Result<usize> {
+        let current_version = Self::internal_get_schema_version(conn)?;
+        if version > current_version || current_version > migrations.len() {
+            return Err(Error::invalid_rollback(current_version, version));
+        }
+
+        let to_roll_back = &migrations[version..current_version];
+        if let Some((i, migration)) = to_roll_back
+            .iter()
+            .enumerate()
+            .find(|(_, migration)| migration.down.is_none())
+        {
+            return Err(Error::irreversible_migration(
+                version + i + 1,
+                migration.name.clone(),
+            ));
+        }
+
+        for (i, migration) in to_roll_back.iter().enumerate().rev() {
+            // Rolling back migration `i` returns the schema to the version
+            // it was at before the migration was applied:
+            let previous_version = version + i;
+            debug!(
+                "MIGRATION: Rolling back {filename}@{version}",
+                filename = migration.name,
+                version = previous_version
+            );
+            let down = migration.down.as_ref().unwrap();
+            conn.execute_batch(down.as_ref()).map_err(|e| {
+                Error::migration(previous_version + 1, e.to_string(), down.as_ref())
+            })?;
+            Self::internal_set_schema_version(conn, previous_version)?;
+        }
+
+        Ok(version)
+    }
+
     // ----------
     // start of page_record table functions
     // ----------
 
     /// Insert a page into the database. Ignores records that already exist.
     pub fn upsert_page(&self, record: &PageRecord) -> Result<usize> {
+        self.upsert_page_with_checksum(record, None)
+    }
+
+    /// Like `upsert_page`, but also records the checksum of the page file,
+    /// which is verified before the page is read back. Without a checksum,
+    /// the one already recorded for the page, if any, is kept.
+    pub fn upsert_page_with_checksum(
+        &self,
+        record: &PageRecord,
+        checksum: Option<u32>,
+    ) -> Result<usize> {
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare(
-            "INSERT OR REPLACE INTO page_record (id, nan_filled, complete, size, last_used)
-             VALUES (:id, :nan_filled, :complete, :size, :last_used)",
+            "INSERT OR REPLACE INTO page_record (id, nan_filled, complete, size, last_used, checksum)
+             VALUES (:id, :nan_filled, :complete, :size, :last_used,
+                     COALESCE(:checksum, (SELECT checksum FROM page_record WHERE id = :id)))",
         )?;
 
         stmt.execute_named(&[
@@ -767,6 +1028,7 @@ impl Database {
             (":complete", &record.complete),
             (":size", &record.size),
             (":last_used", &record.last_used),
+            (":checksum", &checksum.map(i64::from)),
         ])
         .map(|count| count as usize)
         .map_err(Into::into)
@@ -778,8 +1040,8 @@ impl Database {
     pub fn write_nan_filled(&self, id: &str, complete: bool) -> Result<usize> {
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare(
-            "INSERT OR REPLACE INTO page_record (id, nan_filled, complete, size, last_used)
-             VALUES (:id, :nan_filled, :complete, :size, :last_used)",
+            "INSERT OR REPLACE INTO page_record (id, nan_filled, complete, size, last_used, checksum)
+             VALUES (:id, :nan_filled, :complete, :size, :last_used, :checksum)",
         )?;
 
         stmt.execute_named(&[
@@ -788,6 +1050,7 @@ impl Database {
             (":complete", &complete),
             (":size", &0),
             (":last_used", &time::now().to_timespec()),
+            (":checksum", &NAN_PAGE_CHECKSUM),
         ])
         .map(|count| count as usize)
         .map_err(Into::into)
@@ -842,6 +1105,19 @@ impl Database {
         }
     }
 
+    /// Returns the number of pages cached on the local filesystem.
+    pub fn get_page_count(&self) -> Result<i64> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT COUNT(*) FROM page_record")?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+
+        if let Some(res) = rows.next() {
+            res.map(|r| r.get(0)).map_err(Into::into)
+        } else {
+            Ok(0)
+        }
+    }
+
     /// Deletes the provided page record from the database.
     pub fn delete_page(&self, record: &PageRecord) -> Result<usize> {
         let conn = self.pool.get()?;
@@ -873,22 +1149,103 @@ impl Database {
         Ok(records.into_iter())
     }
 
-    /// Gets cached pages that have a `last_used` time of greater than a week
-    /// ago.
-    pub fn get_soft_aged_pages(&self) -> Result<IntoIter<PageRecord>> {
-        let threshold = time::now().to_timespec() - time::Duration::weeks(1);
+    /// Gets cached pages that have a `last_used` time of longer ago than
+    /// the soft age threshold (a week, by default).
+    pub fn get_soft_aged_pages(
+        &self,
+        thresholds: PageAgeThresholds,
+    ) -> Result<IntoIter<PageRecord>> {
+        let threshold = time::now().to_timespec() - thresholds.soft_age;
 
         self.get_aged_pages_helper(&threshold)
     }
 
-    /// Gets cached pages that have a `last_used` time of greater than 12
-    /// hours ago.
-    pub fn get_hard_aged_pages(&self) -> Result<IntoIter<PageRecord>> {
-        let threshold = time::now().to_timespec() - time::Duration::hours(12);
+    /// Gets cached pages that have a `last_used` time of longer ago than
+    /// the hard age threshold (12 hours, by default).
+    pub fn get_hard_aged_pages(
+        &self,
+        thresholds: PageAgeThresholds,
+    ) -> Result<IntoIter<PageRecord>> {
+        let threshold = time::now().to_timespec() - thresholds.hard_age;
 
         self.get_aged_pages_helper(&threshold)
     }
 
+    /// Gets every cached page, ordered by key.
+    pub fn get_pages(&self) -> Result<IntoIter<PageRecord>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, nan_filled, complete, size, last_used
+             FROM page_record
+             ORDER BY id",
+        )?;
+        let records = stmt
+            .query_and_then(NO_PARAMS, PageRecord::from_row)?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(records.into_iter())
+    }
+
+    /// Gets up to `limit` cached pages, ordered from least to most recently
+    /// used. NaN filled pages are excluded, since they take up no space on
+    /// the local filesystem.
+    pub fn get_pages_by_lru(&self, limit: usize) -> Result<IntoIter<PageRecord>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, nan_filled, complete, size, last_used
+             FROM page_record
+             WHERE nan_filled = :false
+             ORDER BY last_used ASC
+             LIMIT :limit",
+        )?;
+        let records = stmt
+            .query_and_then_named(
+                &[(":false", &false), (":limit", &(limit as i64))],
+                PageRecord::from_row,
+            )?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(records.into_iter())
+    }
+
+    fn get_pages_by_page_size_helper(
+        &self,
+        page_size: u32,
+        matching: bool,
+    ) -> Result<IntoIter<PageRecord>> {
+        let conn = self.pool.get()?;
+        // Page keys have the form "<package>.<channel>.<page size>.<index>",
+        // and package and channel IDs never contain a ".":
+        let mut stmt = conn.prepare(
+            "SELECT id, nan_filled, complete, size, last_used
+             FROM page_record
+             WHERE id LIKE '%.%.%.%' AND (id LIKE :pattern) = :matching
+             ORDER BY id",
+        )?;
+        let pattern = format!("%.{}.%", page_size);
+        let records = stmt
+            .query_and_then_named(
+                &[(":pattern", &pattern), (":matching", &matching)],
+                PageRecord::from_row,
+            )?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(records.into_iter())
+    }
+
+    /// Gets the cached pages whose key was composed with the given page
+    /// size.
+    pub fn get_pages_with_page_size(&self, page_size: u32) -> Result<IntoIter<PageRecord>> {
+        self.get_pages_by_page_size_helper(page_size, true)
+    }
+
+    /// Gets the cached pages whose key was composed with any page size other
+    /// than the given one, such as pages cached before the configured page
+    /// size was changed.
+    pub fn get_pages_with_other_page_size(&self, page_size: u32) -> Result<IntoIter<PageRecord>> {
+        self.get_pages_by_page_size_helper(page_size, false)
+    }
+
     /// Returns a boolean based on if the provided `id` is associated with
     /// a NaN filled page.
     pub fn is_page_nan(&self, id: &str) -> Result<bool> {
@@ -903,6 +1260,21 @@ impl Database {
         }
     }
 
+    /// Returns the checksum recorded for the page with the provided `id`.
+    /// `None` is returned for pages recorded before checksums were kept,
+    /// as well as for pages that are not in the database.
+    pub fn get_page_checksum(&self, id: &str) -> Result<Option<i64>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT checksum FROM page_record WHERE id = :id")?;
+        let mut rows = stmt.query_named(&[(":id", &id)])?;
+
+        if let Some(res) = rows.next() {
+            res.map(|r| r.get(0)).map_err(Into::into)
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Returns a boolean based on if the provided `id` is associated with
     /// a record that is present and has a complete field of `true`. Having
     /// a complete field of `false` means that that page is on the local
@@ -1056,18 +1428,43 @@ impl Database {
         .map_err(Into::into)
     }
 
+    /// Moves everything stored for the profile `old_name` to `new_name`:
+    /// the settings of each user and, if it was logged in with the old
+    /// profile, the current user. Settings already stored for `new_name`
+    /// are replaced. On success, returns the number of updated records.
+    pub fn rename_profile(&self, old_name: &str, new_name: &str) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let settings = tx.execute_named(
+            "UPDATE OR REPLACE user_settings
+             SET profile = :new_name
+             WHERE profile = :old_name",
+            &[(":old_name", &old_name), (":new_name", &new_name)],
+        )?;
+        let users = tx.execute_named(
+            "UPDATE user_record
+             SET profile = :new_name
+             WHERE profile = :old_name",
+            &[(":old_name", &old_name), (":new_name", &new_name)],
+        )?;
+        tx.commit()?;
+        Ok((settings + users) as usize)
+    }
+
     // ----------
     // start of upload_record table functions
     // ----------
 
     /// Update upload records with the provided `status`, for all records
-    /// associated with the provided `import_id`. On success, returns the
-    /// number of updated records.
+    /// associated with the provided `import_id`. Completing the records
+    /// clears their last error. On success, returns the number of updated
+    /// records.
     pub fn update_import_status(&self, import_id: &str, status: UploadStatus) -> Result<usize> {
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare(
             "UPDATE upload_record
-             SET status = :status, updated_at = :updated_at
+             SET status = :status, updated_at = :updated_at,
+                 last_error = CASE WHEN :status = 'completed' THEN NULL ELSE last_error END
              WHERE import_id = :import_id",
         )?;
 
@@ -1082,6 +1479,7 @@ impl Database {
 
     /// Update upload records with the provided `status` and `progress`,
     /// for all records associated with the provided `import_id`.
+    /// Completing the records clears their last error.
     /// On success, returns the number of updated records.
     pub fn update_import_status_and_progress(
         &self,
@@ -1092,7 +1490,8 @@ impl Database {
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare(
             "UPDATE upload_record
-             SET status = :status, updated_at = :updated_at, progress = :progress
+             SET status = :status, updated_at = :updated_at, progress = :progress,
+                 last_error = CASE WHEN :status = 'completed' THEN NULL ELSE last_error END
              WHERE import_id = :import_id",
         )?;
 
@@ -1106,6 +1505,58 @@ impl Database {
         .map_err(Into::into)
     }
 
+    /// Records `message` as the last error of every upload record
+    /// associated with the provided `import_id`, replacing the error of
+    /// any earlier attempt. Unlike `fail_import`, the status of the
+    /// records is left as it is, as the upload may still be retried.
+    /// On success, returns the number of updated records.
+    pub fn record_upload_error(&self, import_id: &str, message: &str) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "UPDATE upload_record
+             SET last_error = :last_error, updated_at = :updated_at
+             WHERE import_id = :import_id",
+        )?;
+
+        stmt.execute_named(&[
+            (":import_id", &import_id),
+            (":last_error", &message),
+            (":updated_at", &time::now().to_timespec()),
+        ])
+        .map(|count| count as usize)
+        .map_err(Into::into)
+    }
+
+    /// Marks every upload record associated with the provided `import_id`
+    /// as failed, recording `reason` as the reason why. With `progress`,
+    /// the progress of the records is reset as well.
+    /// On success, returns the number of updated records.
+    pub fn fail_import(
+        &self,
+        import_id: &str,
+        reason: &str,
+        progress: Option<i32>,
+    ) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "UPDATE upload_record
+             SET status = 'failed',
+                 failure_reason = :failure_reason,
+                 progress = COALESCE(:progress, progress),
+                 updated_at = :updated_at
+             WHERE import_id = :import_id",
+        )?;
+
+        stmt.execute_named(&[
+            (":import_id", &import_id),
+            (":failure_reason", &reason),
+            (":progress", &progress),
+            (":updated_at", &time::now().to_timespec()),
+        ])
+        .map(|count| count as usize)
+        .map_err(Into::into)
+    }
+
     /// Updates the upload record associated with a particular file
     /// with the provided `progress` value, only if the provided value
     /// is greater than the existing value in the database (progress
@@ -1145,10 +1596,15 @@ impl Database {
     /// identifier of the inserted record.
     pub fn insert_upload(&self, record: &UploadRecord) -> Result<i64> {
         let conn = self.pool.get()?;
+        let tags: Option<String> = if record.tags.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&record.tags)?)
+        };
 
         let mut stmt = conn.prepare(
-            "INSERT INTO upload_record (file_path, dataset_id, package_id, import_id, progress, status, created_at, updated_at, append, upload_service, organization_id, chunk_size, multipart_upload_id)
-             VALUES (:file_path, :dataset_id, :package_id, :import_id, :progress, :status, :created_at, :updated_at, :append, :upload_service, :organization_id, :chunk_size, :multipart_upload_id)"
+            "INSERT INTO upload_record (file_path, dataset_id, package_id, import_id, progress, status, created_at, updated_at, append, upload_service, organization_id, chunk_size, multipart_upload_id, priority, failure_reason, tags, last_error, checksum)
+             VALUES (:file_path, :dataset_id, :package_id, :import_id, :progress, :status, :created_at, :updated_at, :append, :upload_service, :organization_id, :chunk_size, :multipart_upload_id, :priority, :failure_reason, :tags, :last_error, :checksum)"
         )?;
 
         stmt.execute_named(&[
@@ -1165,6 +1621,11 @@ impl Database {
             (":organization_id", &record.organization_id),
             (":chunk_size", &record.chunk_size.map(|c| c.to_string())),
             (":multipart_upload_id", &record.multipart_upload_id),
+            (":priority", &record.priority),
+            (":failure_reason", &record.failure_reason),
+            (":tags", &tags),
+            (":last_error", &record.last_error),
+            (":checksum", &record.checksum),
         ])
         .map_err(Into::into)
         .and_then(|_| Ok(conn.last_insert_rowid()))
@@ -1175,12 +1636,13 @@ impl Database {
     /// is stopped mid-upload.
     ///
     /// If the upload is not meant for the upload_service, reset the
-    /// progress back to 0 as well.
+    /// progress back to 0 as well. `STALLED_UPLOAD_ERROR` is recorded as
+    /// the last error of the uploads reset.
     pub fn reset_stalled_uploads(&self) -> Result<usize> {
         let conn = self.pool.get()?;
         let mut global_stmt = conn.prepare(
             "UPDATE upload_record
-             SET status = 'queued'
+             SET status = 'queued', last_error = :last_error
              WHERE status = 'in_progress'",
         )?;
         let mut non_upload_service_stmt = conn.prepare(
@@ -1190,7 +1652,7 @@ impl Database {
         )?;
 
         global_stmt
-            .execute(NO_PARAMS)
+            .execute_named(&[(":last_error", &STALLED_UPLOAD_ERROR)])
             .map(|count| count as usize)
             .map_err(Into::into)
             .and_then(|global_count| {
@@ -1218,7 +1680,12 @@ impl Database {
                     upload_service,
                     organization_id,
                     chunk_size,
-                    multipart_upload_id
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
              FROM upload_record
              WHERE import_id = :import_id",
         )?;
@@ -1246,7 +1713,12 @@ impl Database {
                     upload_service,
                     organization_id,
                     chunk_size,
-                    multipart_upload_id
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
              FROM upload_record
              WHERE id = :upload_id",
         )?;
@@ -1282,7 +1754,12 @@ impl Database {
                     upload_service,
                     organization_id,
                     chunk_size,
-                    multipart_upload_id
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
              FROM upload_record
              WHERE status = 'in_progress'
              ORDER by created_at",
@@ -1294,6 +1771,63 @@ impl Database {
         Ok(UploadRecords { records })
     }
 
+    /// Counts the upload records with each status. Statuses without any
+    /// upload are left out.
+    pub fn count_uploads_by_status(&self) -> Result<HashMap<UploadStatus, usize>> {
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare("SELECT status, COUNT(*) FROM upload_record GROUP BY status")?;
+        let rows = stmt.query_and_then(NO_PARAMS, |row| -> Result<(UploadStatus, usize)> {
+            let status: String = row.get(0);
+            let count: i64 = row.get(1);
+            Ok((status.parse()?, count as usize))
+        })?;
+
+        rows.collect()
+    }
+
+    /// Counts the upload records with each status that were last updated
+    /// after `after` and no later than `until`. Statuses without any such
+    /// upload are left out.
+    pub fn count_uploads_updated_between(
+        &self,
+        after: time::Timespec,
+        until: time::Timespec,
+    ) -> Result<HashMap<UploadStatus, usize>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT status, COUNT(*) FROM upload_record
+             WHERE updated_at > :after AND updated_at <= :until
+             GROUP BY status",
+        )?;
+        let rows = stmt.query_and_then_named(
+            &[(":after", &after), (":until", &until)],
+            |row| -> Result<(UploadStatus, usize)> {
+                let status: String = row.get(0);
+                let count: i64 = row.get(1);
+                Ok((status.parse()?, count as usize))
+            },
+        )?;
+
+        rows.collect()
+    }
+
+    /// Returns the number of bytes left to upload for the queued and
+    /// in-progress uploads, estimated from the size of each file and the
+    /// progress made on it so far. Files that can no longer be read count
+    /// for nothing.
+    pub fn sum_pending_bytes(&self) -> Result<u64> {
+        Ok(self
+            .get_active_uploads()?
+            .iter()
+            .map(|upload| {
+                let size = fs::metadata(&upload.file_path).map_or(0, |m| m.len());
+                let remaining = 100 - upload.progress.max(0).min(100) as u64;
+                size * remaining / 100
+            })
+            .sum())
+    }
+
     /// Returns all `UploadStatus::Queued` upload records.
     pub fn get_queued_uploads(&self) -> Result<UploadRecords> {
         let conn = self.pool.get()?;
@@ -1311,10 +1845,15 @@ impl Database {
                     upload_service,
                     organization_id,
                     chunk_size,
-                    multipart_upload_id
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
              FROM upload_record
              WHERE status = 'queued'
-             ORDER by created_at",
+             ORDER BY priority DESC, created_at ASC",
         )?;
         let records = stmt
             .query_and_then_named(&[], UploadRecord::from_row)?
@@ -1341,7 +1880,12 @@ impl Database {
                     upload_service,
                     organization_id,
                     chunk_size,
-                    multipart_upload_id
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
              FROM upload_record
              WHERE status IN ('in_progress', 'queued')
              ORDER by status, created_at",
@@ -1370,7 +1914,12 @@ impl Database {
                     upload_service,
                     organization_id,
                     chunk_size,
-                    multipart_upload_id
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
              FROM upload_record
              WHERE status = 'failed'
              ORDER by created_at",
@@ -1382,6 +1931,59 @@ impl Database {
         Ok(UploadRecords { records })
     }
 
+    /// Visits every upload record, or only those with the given status, in
+    /// the order they were created. Records are read one at a time, so a
+    /// large upload history is never held in memory at once. Returns the
+    /// number of records visited.
+    pub fn get_all_uploads<F, E>(
+        &self,
+        status: Option<UploadStatus>,
+        mut visit: F,
+    ) -> result::Result<usize, E>
+    where
+        F: FnMut(UploadRecord) -> result::Result<(), E>,
+        E: From<Error>,
+    {
+        let conn = self.pool.get().map_err(Into::<Error>::into)?;
+        let mut stmt = conn
+            .prepare(
+                "SELECT id,
+                        file_path,
+                        dataset_id,
+                        package_id,
+                        import_id,
+                        progress,
+                        status,
+                        created_at,
+                        updated_at,
+                        append,
+                        upload_service,
+                        organization_id,
+                        chunk_size,
+                        multipart_upload_id,
+                        priority,
+                        failure_reason,
+                        tags,
+                        last_error,
+                        checksum
+                 FROM upload_record
+                 WHERE :status IS NULL OR status = :status
+                 ORDER BY created_at, id",
+            )
+            .map_err(Into::<Error>::into)?;
+        let status: Option<String> = status.map(Into::into);
+        let records = stmt
+            .query_and_then_named(&[(":status", &status)], UploadRecord::from_row)
+            .map_err(Into::<Error>::into)?;
+
+        let mut visited = 0;
+        for record in records {
+            visit(record?)?;
+            visited += 1;
+        }
+        Ok(visited)
+    }
+
     /// Returns NUM most recently completed uploads.
     pub fn get_completed_uploads(&self, num: usize) -> Result<UploadRecords> {
         let conn = self.pool.get()?;
@@ -1399,7 +2001,12 @@ impl Database {
                     upload_service,
                     organization_id,
                     chunk_size,
-                    multipart_upload_id
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
              FROM upload_record
              WHERE status = 'completed'
              ORDER BY updated_at DESC
@@ -1412,47 +2019,14 @@ impl Database {
         Ok(UploadRecords { records })
     }
 
-    /// Resumes the specified upload. Note: Only failed uploads that have a progress > 0 can be retried.
-    pub fn resume_failed_upload(&self, id: &str) -> Result<bool> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn
-            .prepare(
-                "UPDATE upload_record SET status = 'queued' WHERE ID = :id AND status = 'failed' AND progress > 0",
-            )?;
-        stmt.execute_named(&[(":id", &id)])
-            .map(|count| count >= 1)
-            .map_err(Into::into)
-    }
-
-    /// Cancels the specified upload. Note: only queued or in-progress
-    /// uploads can be cancelled.
-    pub fn cancel_upload(&self, id: &str) -> Result<bool> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare(
-            "DELETE FROM upload_record WHERE ID = :id AND status IN ('queued', 'in_progress')",
-        )?;
-        stmt.execute_named(&[(":id", &id)])
-            .map(|count| count >= 1)
-            .map_err(Into::into)
-    }
-
-    /// Cancels all queued uploads, leaving in-progress uploads to finish.
-    pub fn cancel_queued_uploads(&self) -> Result<usize> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare("DELETE FROM upload_record WHERE status = 'queued'")?;
-        stmt.execute_named(&[]).map_err(Into::into)
-    }
-
-    /// Cancels all uploads, regardless of status.
-    pub fn cancel_all_uploads(&self) -> Result<usize> {
-        let conn = self.pool.get()?;
-        let mut stmt =
-            conn.prepare("DELETE FROM upload_record WHERE status IN ('queued', 'in_progress')")?;
-        stmt.execute_named(&[]).map_err(Into::into)
-    }
-
-    /// Gets all active uploads that began since a given date.
-    pub fn get_active_uploads_started_since(&self, since: time::Timespec) -> Result<UploadRecords> {
+    /// Returns the completed upload records last updated between `start`
+    /// and `end`, both inclusive, most recent first. A missing bound leaves
+    /// that end of the range open.
+    pub fn get_completed_uploads_between(
+        &self,
+        start: Option<time::Timespec>,
+        end: Option<time::Timespec>,
+    ) -> Result<UploadRecords> {
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare(
             "SELECT id,
@@ -1468,11 +2042,179 @@ impl Database {
                     upload_service,
                     organization_id,
                     chunk_size,
-                    multipart_upload_id
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
              FROM upload_record
-             WHERE status IN ('in_progress', 'queued')
-                    OR created_at >= :since
-             ORDER by status, created_at",
+             WHERE status = 'completed'
+                    AND (:start IS NULL OR updated_at >= :start)
+                    AND (:end IS NULL OR updated_at <= :end)
+             ORDER BY updated_at DESC",
+        )?;
+        let records = stmt
+            .query_and_then_named(
+                &[(":start", &start), (":end", &end)],
+                UploadRecord::from_row,
+            )?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(UploadRecords { records })
+    }
+
+    /// Returns all completed append upload records for the provided
+    /// `package_id`.
+    pub fn get_completed_appends(&self, package_id: &str) -> Result<UploadRecords> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id,
+                    file_path,
+                    dataset_id,
+                    package_id,
+                    import_id,
+                    progress,
+                    status,
+                    created_at,
+                    updated_at,
+                    append,
+                    upload_service,
+                    organization_id,
+                    chunk_size,
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
+             FROM upload_record
+             WHERE status = 'completed' AND append = :true AND package_id = :package_id
+             ORDER BY updated_at DESC",
+        )?;
+        let records = stmt
+            .query_and_then_named(
+                &[(":true", &true), (":package_id", &package_id)],
+                UploadRecord::from_row,
+            )?
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(UploadRecords { records })
+    }
+
+    /// Tests if the file at `file_path` is already queued or being uploaded
+    /// to the dataset `dataset_id`.
+    pub fn has_active_upload_for(&self, dataset_id: &str, file_path: &str) -> Result<bool> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT EXISTS (
+                SELECT 1 FROM upload_record
+                WHERE dataset_id = :dataset_id
+                AND file_path = :file_path
+                AND status IN ('queued', 'in_progress')
+             )",
+        )?;
+
+        stmt.query_row_named(
+            &[(":dataset_id", &dataset_id), (":file_path", &file_path)],
+            |row| row.get(0),
+        )
+        .map_err(Into::into)
+    }
+
+    /// Resumes the specified upload. Note: Only failed uploads that have a progress > 0 can be retried.
+    pub fn resume_failed_upload(&self, id: &str) -> Result<bool> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn
+            .prepare(
+                "UPDATE upload_record SET status = 'queued', failure_reason = NULL WHERE ID = :id AND status = 'failed' AND progress > 0",
+            )?;
+        stmt.execute_named(&[(":id", &id)])
+            .map(|count| count >= 1)
+            .map_err(Into::into)
+    }
+
+    /// Cancels the specified upload. Note: only queued or in-progress
+    /// uploads can be cancelled.
+    pub fn cancel_upload(&self, id: &str) -> Result<bool> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "DELETE FROM upload_record WHERE ID = :id AND status IN ('queued', 'in_progress')",
+        )?;
+        stmt.execute_named(&[(":id", &id)])
+            .map(|count| count >= 1)
+            .map_err(Into::into)
+    }
+
+    /// Cancels all queued uploads, leaving in-progress uploads to finish.
+    pub fn cancel_queued_uploads(&self) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("DELETE FROM upload_record WHERE status = 'queued'")?;
+        stmt.execute_named(&[]).map_err(Into::into)
+    }
+
+    /// Cancels the queued and in-progress uploads to one dataset, leaving
+    /// uploads to other datasets alone.
+    pub fn cancel_uploads_for_dataset(&self, dataset_id: &str) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "DELETE FROM upload_record
+             WHERE dataset_id = :dataset_id
+                   AND status IN ('queued', 'in_progress')",
+        )?;
+        stmt.execute_named(&[(":dataset_id", &dataset_id)])
+            .map_err(Into::into)
+    }
+
+    /// Cancels the queued and in-progress uploads of every file queued
+    /// under one import ID, so a package of many files is cancelled at
+    /// once. Returns the number of uploads cancelled.
+    pub fn cancel_uploads_by_import_id(&self, import_id: &str) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "DELETE FROM upload_record
+             WHERE import_id = :import_id
+                   AND status IN ('queued', 'in_progress')",
+        )?;
+        stmt.execute_named(&[(":import_id", &import_id)])
+            .map_err(Into::into)
+    }
+
+    /// Cancels all uploads, regardless of status.
+    pub fn cancel_all_uploads(&self) -> Result<usize> {
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare("DELETE FROM upload_record WHERE status IN ('queued', 'in_progress')")?;
+        stmt.execute_named(&[]).map_err(Into::into)
+    }
+
+    /// Gets all active uploads that began since a given date.
+    pub fn get_active_uploads_started_since(&self, since: time::Timespec) -> Result<UploadRecords> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT id,
+                    file_path,
+                    dataset_id,
+                    package_id,
+                    import_id,
+                    progress,
+                    status,
+                    created_at,
+                    updated_at,
+                    append,
+                    upload_service,
+                    organization_id,
+                    chunk_size,
+                    multipart_upload_id,
+                    priority,
+                    failure_reason,
+                    tags,
+                    last_error,
+                    checksum
+             FROM upload_record
+             WHERE status IN ('in_progress', 'queued')
+                    OR created_at >= :since
+             ORDER by status, created_at",
         )?;
         let records = stmt
             .query_and_then_named(&[(":since", &since)], UploadRecord::from_row)?
@@ -1506,6 +2248,71 @@ impl Database {
             .map_err(Into::into)
             .and_then(|_| Ok(now))
     }
+
+    /// Get the node ID `name` was last resolved to in the organization, if
+    /// it was resolved since `since`.
+    pub fn get_resolved_name(
+        &self,
+        organization_id: &str,
+        name: &str,
+        since: time::Timespec,
+    ) -> Result<Option<String>> {
+        let conn = self.pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT node_id FROM name_resolution
+             WHERE organization_id = :organization_id
+             AND name = :name
+             AND resolved_at >= :since",
+        )?;
+
+        stmt.query_row_named(
+            &[
+                (":organization_id", &organization_id),
+                (":name", &name),
+                (":since", &since),
+            ],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(Into::into)
+    }
+
+    /// Record that `name` was just resolved to `node_id` in the
+    /// organization, replacing any earlier resolution.
+    pub fn upsert_resolved_name(
+        &self,
+        organization_id: &str,
+        name: &str,
+        node_id: &str,
+    ) -> Result<usize> {
+        let conn = self.pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "INSERT OR REPLACE INTO name_resolution (organization_id, name, node_id, resolved_at)
+             VALUES (:organization_id, :name, :node_id, :resolved_at)",
+        )?;
+
+        stmt.execute_named(&[
+            (":organization_id", &organization_id),
+            (":name", &name),
+            (":node_id", &node_id),
+            (":resolved_at", &time::now().to_timespec()),
+        ])
+        .map_err(Into::into)
+    }
+
+    /// Forget what `name` was resolved to in the organization, such as when
+    /// the node it was resolved to no longer exists.
+    pub fn delete_resolved_name(&self, organization_id: &str, name: &str) -> Result<usize> {
+        let conn = self.pool.get()?;
+
+        conn.execute_named(
+            "DELETE FROM name_resolution WHERE organization_id = :organization_id AND name = :name",
+            &[(":organization_id", &organization_id), (":name", &name)],
+        )
+        .map_err(Into::into)
+    }
 }
 
 #[cfg(test)]
@@ -1516,6 +2323,207 @@ mod test {
     use super::*;
     use crate::ps::util;
 
+    fn test_migrations() -> Vec<Migration> {
+        vec![
+            Migration {
+                name: "000001_create_samples".to_string(),
+                up: "CREATE TABLE samples (id INTEGER PRIMARY KEY);".into(),
+                down: Some("DROP TABLE samples;".into()),
+            },
+            Migration {
+                name: "000002_samples_add_index".to_string(),
+                up: "CREATE INDEX samples_i1 ON samples (id);".into(),
+                down: Some("DROP INDEX samples_i1;".into()),
+            },
+        ]
+    }
+
+    fn schema_objects(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master ORDER BY name")
+            .unwrap();
+        let names = stmt
+            .query_map(NO_PARAMS, |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .unwrap();
+        names
+    }
+
+    #[test]
+    fn migrations_can_be_rolled_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        let migrations = test_migrations();
+
+        assert_eq!(Database::apply_migrations(&conn, &migrations).unwrap(), 2);
+        assert_eq!(schema_objects(&conn), vec!["samples", "samples_i1"]);
+
+        assert_eq!(
+            Database::rollback_migrations(&conn, &migrations, 1).unwrap(),
+            1
+        );
+        assert_eq!(Database::internal_get_schema_version(&conn).unwrap(), 1);
+        assert_eq!(schema_objects(&conn), vec!["samples"]);
+
+        assert_eq!(
+            Database::rollback_migrations(&conn, &migrations, 0).unwrap(),
+            0
+        );
+        assert_eq!(Database::internal_get_schema_version(&conn).unwrap(), 0);
+        assert!(schema_objects(&conn).is_empty());
+
+        // Rolled back migrations are applied again:
+        assert_eq!(Database::apply_migrations(&conn, &migrations).unwrap(), 2);
+        assert_eq!(schema_objects(&conn), vec!["samples", "samples_i1"]);
+    }
+
+    #[test]
+    fn migrations_without_down_scripts_are_not_rolled_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut migrations = test_migrations();
+        migrations[0].down = None;
+
+        Database::apply_migrations(&conn, &migrations).unwrap();
+        match Database::rollback_migrations(&conn, &migrations, 0) {
+            Err(e) => assert_eq!(
+                e.kind(),
+                &ErrorKind::IrreversibleMigration {
+                    version: 1,
+                    name: "000001_create_samples".to_string()
+                }
+            ),
+            Ok(version) => panic!("rolled back to version {}", version),
+        }
+
+        // Nothing was rolled back, not even the reversible migration:
+        assert_eq!(Database::internal_get_schema_version(&conn).unwrap(), 2);
+        assert_eq!(schema_objects(&conn), vec!["samples", "samples_i1"]);
+    }
+
+    #[test]
+    fn pinned_schemas_are_migrated_to_the_latest_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        let migrations = test_migrations();
+
+        // A schema left at the first version, as after a manual repair:
+        Database::apply_migrations(&conn, &migrations[..1]).unwrap();
+        assert_eq!(Database::internal_get_schema_version(&conn).unwrap(), 1);
+        assert_eq!(schema_objects(&conn), vec!["samples"]);
+
+        assert_eq!(Database::force_migrations(&conn, &migrations).unwrap(), 2);
+        assert_eq!(schema_objects(&conn), vec!["samples", "samples_i1"]);
+
+        // Nothing is left to run, so the version stays put:
+        assert_eq!(Database::force_migrations(&conn, &migrations).unwrap(), 2);
+    }
+
+    #[test]
+    fn migrating_an_up_to_date_database_keeps_its_version() {
+        let db = Database::new(&Source::Memory).unwrap();
+        assert_eq!(db.migrate().unwrap(), Migrations::get_all().len());
+        assert_eq!(
+            db.get_schema_version().unwrap(),
+            Migrations::get_all().len()
+        );
+    }
+
+    #[test]
+    fn the_agent_migrations_can_be_rolled_back() {
+        let db = Database::new(&Source::Memory).unwrap();
+        let mut record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.priority = 5;
+        db.insert_upload(&record).unwrap();
+        db.upsert_page_with_checksum(&PageRecord::new("c1.100.1", false, true, 800), Some(42))
+            .unwrap();
+
+        let columns = |table: &str| {
+            let conn = db.pool.get().unwrap();
+            let mut stmt = conn
+                .prepare(&format!("PRAGMA table_info({})", table))
+                .unwrap();
+            let names = stmt
+                .query_map(NO_PARAMS, |row| row.get(1))
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<String>>>()
+                .unwrap();
+            names
+        };
+
+        // Back to before page checksums were recorded:
+        assert_eq!(db.rollback_to(5).unwrap(), 5);
+        assert!(!columns("page_record").contains(&"checksum".to_string()));
+        assert_eq!(
+            columns("upload_record").last().map(String::as_str),
+            Some("multipart_upload_id")
+        );
+        assert_eq!(db.get_total_size().unwrap(), 800);
+
+        // The rows survive the way back, with the rolled back columns reset:
+        assert_eq!(db.migrate().unwrap(), Migrations::get_all().len());
+        let uploads = db.get_uploads_by_import_id("import_1").unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].priority, 0);
+        assert_eq!(db.get_page("c1.100.1").unwrap().size, 800);
+    }
+
+    #[test]
+    fn rollback_target_must_not_be_newer() {
+        let db = Database::new(&Source::Memory).unwrap();
+        let version = db.get_schema_version().unwrap();
+
+        match db.rollback_to(version + 1) {
+            Err(e) => assert_eq!(
+                e.kind(),
+                &ErrorKind::InvalidRollback {
+                    current_version: version,
+                    version: version + 1
+                }
+            ),
+            Ok(version) => panic!("rolled back to version {}", version),
+        }
+        // Rolling back to the current version does nothing:
+        assert_eq!(db.rollback_to(version).unwrap(), version);
+    }
+
+    #[test]
+    fn memory_database_is_shared_between_connections() {
+        let mut user = UserRecord::new(
+            "N:user:foo".to_string(),               // id
+            "Joe Schmoe".to_string(),               // name
+            "token".to_string(),                    // token
+            "default".to_string(),                  // profile
+            ApiEnvironment::NonProduction,          // environment
+            "N:organization:pennsieve".to_string(), // org id
+            "Pennsieve".to_string(),                // org name,
+            "encryption_key".to_string(),           // encryption_key
+        );
+        let db = Database::new(&Source::Memory).unwrap();
+
+        // Holding a connection forces the insert onto a different one:
+        let conn = db.pool.get().unwrap();
+        db.upsert_user(&mut user).unwrap();
+
+        let name: String = conn
+            .query_row("SELECT name FROM user_record", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(name, "Joe Schmoe");
+        assert_eq!(db.clone().get_user().unwrap().unwrap().id, "N:user:foo");
+
+        // Separate in-memory databases are not shared:
+        let other = Database::new(&Source::Memory).unwrap();
+        assert!(other.get_user().unwrap().is_none());
+    }
+
     #[test]
     fn creating_users_with_settings_succeeds() {
         let mut user = UserRecord::new(
@@ -1561,6 +2569,72 @@ mod test {
         );
     }
 
+    #[test]
+    fn renaming_a_profile_moves_its_settings_and_user() {
+        let mut user = UserRecord::new(
+            "N:user:foo".to_string(),               // id
+            "Joe Schmoe".to_string(),               // name
+            "token".to_string(),                    // token
+            "default".to_string(),                  // profile
+            ApiEnvironment::NonProduction,          // environment
+            "N:organization:pennsieve".to_string(), // org id
+            "Pennsieve".to_string(),                // org name,
+            "encryption_key".to_string(),           // encryption_key
+        );
+        let db = util::database::temp().unwrap();
+        db.upsert_user(&mut user).unwrap();
+
+        let settings = UserSettings::new(Some("foo"));
+        db.upsert_user_settings(&user.id, "default", &settings)
+            .unwrap();
+        let other = UserSettings::new(Some("bar"));
+        db.upsert_user_settings(&user.id, "other", &other).unwrap();
+
+        assert_eq!(db.rename_profile("default", "renamed").unwrap(), 2);
+
+        assert_eq!(db.get_user().unwrap().unwrap().profile, "renamed");
+        assert_eq!(
+            db.get_user_settings(&user.id, "renamed").unwrap(),
+            Some(settings)
+        );
+        assert_eq!(db.get_user_settings(&user.id, "default").unwrap(), None);
+        assert_eq!(
+            db.get_user_settings(&user.id, "other").unwrap(),
+            Some(other)
+        );
+
+        // Renaming a profile the user is not logged in with leaves the user
+        // alone:
+        assert_eq!(db.rename_profile("other", "another").unwrap(), 1);
+        assert_eq!(db.get_user().unwrap().unwrap().profile, "renamed");
+    }
+
+    #[test]
+    fn stale_user_token_requires_login_and_is_replaced() {
+        let mut user = UserRecord::new(
+            "N:user:foo".to_string(),               // id
+            "Joe Schmoe".to_string(),               // name
+            "stale-token".to_string(),              // token
+            "default".to_string(),                  // profile
+            ApiEnvironment::NonProduction,          // environment
+            "N:organization:pennsieve".to_string(), // org id
+            "Pennsieve".to_string(),                // org name,
+            "encryption_key".to_string(),           // encryption_key
+        );
+        user.updated_at = time::now().to_timespec() - time::Duration::hours(2);
+        assert!(user.requires_login(false));
+
+        // Logging in again stores the fresh token:
+        let db = util::database::temp().unwrap();
+        user.session_token = "fresh-token".to_string();
+        db.upsert_user(&mut user).unwrap();
+
+        let found_user = db.get_user().unwrap().unwrap();
+        assert_eq!(found_user.session_token, "fresh-token");
+        assert!(!found_user.requires_login(false));
+        assert!(found_user.requires_login(true));
+    }
+
     #[test]
     fn is_cached_row_exists_complete_false() {
         let db = util::database::temp().unwrap();
@@ -1587,6 +2661,32 @@ mod test {
         assert!(db.is_page_cached(&key).unwrap());
     }
 
+    #[test]
+    fn page_checksums_are_recorded() {
+        let db = util::database::temp().unwrap();
+        let record = PageRecord::new(String::from("c1.100.4"), false, true, 0);
+        db.upsert_page_with_checksum(&record, Some(u32::max_value()))
+            .unwrap();
+        assert_eq!(
+            db.get_page_checksum(&record.id).unwrap(),
+            Some(i64::from(u32::max_value()))
+        );
+
+        // the checksum is kept when the page is updated without one:
+        db.upsert_page(&record).unwrap();
+        assert_eq!(
+            db.get_page_checksum(&record.id).unwrap(),
+            Some(i64::from(u32::max_value()))
+        );
+
+        db.write_nan_filled(&record.id, true).unwrap();
+        assert_eq!(
+            db.get_page_checksum(&record.id).unwrap(),
+            Some(NAN_PAGE_CHECKSUM)
+        );
+        assert_eq!(db.get_page_checksum("c1.100.5").unwrap(), None);
+    }
+
     #[test]
     fn is_row_cached_row_doesnt_exist() {
         let db = util::database::temp().unwrap();
@@ -1634,6 +2734,52 @@ mod test {
         assert_eq!(db.get_total_size().unwrap(), 601);
     }
 
+    #[test]
+    fn get_page_count() {
+        let db = util::database::temp().unwrap();
+        assert_eq!(db.get_page_count().unwrap(), 0);
+        let record = PageRecord::new(String::from("c1.100.1"), false, false, 0);
+        db.upsert_page(&record).unwrap();
+        let record = PageRecord::new(String::from("c1.100.2"), false, false, 100);
+        db.upsert_page(&record).unwrap();
+        assert_eq!(db.get_page_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn pages_are_found_by_page_size() {
+        let db = util::database::temp().unwrap();
+        for id in &[
+            "N:package:1.N:channel:1.100.1",
+            "N:package:1.N:channel:1.100.2",
+            "N:package:1.N:channel:1.1000.1",
+            "N:package:1.N:channel:2.10.100",
+            "c1.100.1",
+        ] {
+            db.upsert_page(&PageRecord::new(id.to_string(), false, false, 10))
+                .unwrap();
+        }
+
+        let ids = |pages: IntoIter<PageRecord>| pages.map(|page| page.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(db.get_pages_with_page_size(100).unwrap()),
+            vec![
+                "N:package:1.N:channel:1.100.1",
+                "N:package:1.N:channel:1.100.2"
+            ]
+        );
+        assert_eq!(
+            ids(db.get_pages_with_other_page_size(100).unwrap()),
+            vec![
+                "N:package:1.N:channel:1.1000.1",
+                "N:package:1.N:channel:2.10.100"
+            ]
+        );
+        assert_eq!(
+            ids(db.get_pages_with_page_size(1000).unwrap()),
+            vec!["N:package:1.N:channel:1.1000.1"]
+        );
+    }
+
     #[test]
     fn delete_record() {
         let db = util::database::temp().unwrap();
@@ -1681,7 +2827,7 @@ mod test {
         };
         db.upsert_page(&record4).unwrap();
         assert_eq!(
-            db.get_soft_aged_pages()
+            db.get_soft_aged_pages(PageAgeThresholds::default())
                 .unwrap()
                 .collect::<Vec<PageRecord>>(),
             vec![record2, record1, record3]
@@ -1725,13 +2871,81 @@ mod test {
         };
         db.upsert_page(&record4).unwrap();
         assert_eq!(
-            db.get_hard_aged_pages()
+            db.get_hard_aged_pages(PageAgeThresholds::default())
                 .unwrap()
                 .collect::<Vec<PageRecord>>(),
             vec![record2, record1, record3]
         );
     }
 
+    #[test]
+    fn aged_pages_respect_the_configured_thresholds() {
+        let db = util::database::temp().unwrap();
+        let now = time::now().to_timespec();
+        let page = |id: &str, last_used: time::Timespec| {
+            let record = PageRecord {
+                id: id.to_string(),
+                nan_filled: false,
+                complete: false,
+                size: 0,
+                last_used,
+            };
+            db.upsert_page(&record).unwrap();
+            record
+        };
+        let month_old = page("c1.100.1", now - time::Duration::days(30));
+        let week_old = page("c1.100.2", now - time::Duration::days(8));
+        let day_old = page("c1.100.3", now - time::Duration::hours(26));
+        let hour_old = page("c1.100.4", now - time::Duration::hours(1));
+
+        let aged = |pages: Result<IntoIter<PageRecord>>| pages.unwrap().collect::<Vec<_>>();
+
+        // Keeping pages longer than the default:
+        let patient = PageAgeThresholds::new(14, 48);
+        assert_eq!(
+            aged(db.get_soft_aged_pages(patient)),
+            vec![month_old.clone()]
+        );
+        assert_eq!(
+            aged(db.get_hard_aged_pages(patient)),
+            vec![month_old.clone(), week_old.clone()]
+        );
+
+        // Evicting pages sooner than the default:
+        let aggressive = PageAgeThresholds::new(1, 0);
+        assert_eq!(
+            aged(db.get_soft_aged_pages(aggressive)),
+            vec![month_old.clone(), week_old.clone(), day_old.clone()]
+        );
+        assert_eq!(
+            aged(db.get_hard_aged_pages(aggressive)),
+            vec![month_old, week_old, day_old, hour_old]
+        );
+    }
+
+    #[test]
+    fn page_age_thresholds_come_from_the_config() {
+        let mut config = config::CacheConfig::default();
+        assert_eq!(
+            PageAgeThresholds::from(&config),
+            PageAgeThresholds::default()
+        );
+        assert_eq!(
+            PageAgeThresholds::default().soft_age,
+            time::Duration::weeks(1)
+        );
+        assert_eq!(
+            PageAgeThresholds::default().hard_age,
+            time::Duration::hours(12)
+        );
+
+        config.set_soft_age_days(30);
+        config.set_hard_age_hours(2);
+        let thresholds = PageAgeThresholds::from(&config);
+        assert_eq!(thresholds.soft_age, time::Duration::days(30));
+        assert_eq!(thresholds.hard_age, time::Duration::hours(2));
+    }
+
     #[test]
     fn test_get_user() {
         let db = util::database::temp().unwrap();
@@ -1849,6 +3063,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record).unwrap();
         let mut record2 = UploadRecord {
@@ -1866,6 +3085,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record2).unwrap();
         let mut record3 = UploadRecord::new(
@@ -1885,6 +3109,98 @@ mod test {
         assert_eq!(coll.iter().collect::<Vec<_>>(), vec![&record2, &record]);
     }
 
+    #[test]
+    fn queued_uploads_are_ordered_by_priority_then_age() {
+        let db = util::database::temp().unwrap();
+        let now = time::now().to_timespec();
+        let queue = |file_path: &str, priority: i32, age_in_weeks: i64| {
+            let mut record = UploadRecord::new(
+                file_path,
+                "ds_1",
+                None as Option<String>,
+                "organization_1",
+                "import_1",
+                false,
+                Some(100),
+                None,
+            )
+            .unwrap()
+            .with_priority(priority);
+            record.created_at = now - time::Duration::weeks(age_in_weeks);
+            db.insert_upload(&record).unwrap();
+        };
+        queue("file/path/old", 0, 3);
+        queue("file/path/urgent", 10, 1);
+        queue("file/path/new", 0, 1);
+        queue("file/path/older_urgent", 10, 2);
+        queue("file/path/background", -5, 4);
+
+        let queued: Vec<(String, i32)> = db
+            .get_queued_uploads()
+            .unwrap()
+            .into_owned_iter()
+            .map(|record| (record.file_path, record.priority))
+            .collect();
+        assert_eq!(
+            queued,
+            vec![
+                ("file/path/older_urgent".to_string(), 10),
+                ("file/path/urgent".to_string(), 10),
+                ("file/path/old".to_string(), 0),
+                ("file/path/new".to_string(), 0),
+                ("file/path/background".to_string(), -5),
+            ]
+        );
+    }
+
+    #[test]
+    fn all_uploads_are_visited_in_order() {
+        let db = util::database::temp().unwrap();
+        let now = time::now().to_timespec();
+        let insert = |file_path: &str, status: UploadStatus, age_in_weeks: i64| {
+            let mut record = UploadRecord::new(
+                file_path,
+                "ds_1",
+                None as Option<String>,
+                "organization_1",
+                "import_1",
+                false,
+                Some(100),
+                None,
+            )
+            .unwrap();
+            record.status = status;
+            record.created_at = now - time::Duration::weeks(age_in_weeks);
+            db.insert_upload(&record).unwrap();
+        };
+        insert("file/path/new", UploadStatus::Failed, 1);
+        insert("file/path/old", UploadStatus::Completed, 3);
+        insert("file/path/middle", UploadStatus::Failed, 2);
+
+        let mut visited = vec![];
+        let count = db
+            .get_all_uploads(None, |record| -> Result<()> {
+                visited.push(record.file_path);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            visited,
+            vec!["file/path/old", "file/path/middle", "file/path/new"]
+        );
+
+        let mut failed = vec![];
+        let count = db
+            .get_all_uploads(Some(UploadStatus::Failed), |record| -> Result<()> {
+                failed.push(record.file_path);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(failed, vec!["file/path/middle", "file/path/new"]);
+    }
+
     #[test]
     fn test_cancel_queued_uploads() {
         let db = util::database::temp().unwrap();
@@ -1904,6 +3220,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record).unwrap();
         let mut record2 = UploadRecord {
@@ -1921,6 +3242,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record2).unwrap();
         let mut record3 = UploadRecord {
@@ -1938,6 +3264,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record3).unwrap();
         let records = db.get_queued_uploads().unwrap();
@@ -1948,6 +3279,87 @@ mod test {
         assert_eq!(records.len(), 1);
     }
 
+    #[test]
+    fn uploads_are_cancelled_for_one_dataset_only() {
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        for (dataset_id, status) in [
+            ("ds_1", UploadStatus::Queued),
+            ("ds_1", UploadStatus::InProgress),
+            ("ds_1", UploadStatus::Completed),
+            ("ds_2", UploadStatus::Queued),
+            ("ds_2", UploadStatus::InProgress),
+        ]
+        .iter()
+        {
+            let mut record = record.clone();
+            record.dataset_id = dataset_id.to_string();
+            record.status = *status;
+            db.insert_upload(&record).unwrap();
+        }
+
+        assert_eq!(db.cancel_uploads_for_dataset("ds_1").unwrap(), 2);
+
+        let active = db.get_active_uploads().unwrap();
+        assert_eq!(active.len(), 2);
+        assert!(active.iter().all(|upload| upload.dataset_id == "ds_2"));
+        // Finished uploads are kept:
+        assert_eq!(db.get_completed_uploads(10).unwrap().len(), 1);
+        assert_eq!(db.cancel_uploads_for_dataset("ds_1").unwrap(), 0);
+    }
+
+    #[test]
+    fn uploads_are_cancelled_for_one_import_id_only() {
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        for (file_path, import_id, status) in [
+            ("/data/recording.edf", "import_1", UploadStatus::Queued),
+            ("/data/recording.bdf", "import_1", UploadStatus::Queued),
+            ("/data/recording.hdr", "import_1", UploadStatus::InProgress),
+            ("/data/old.edf", "import_1", UploadStatus::Completed),
+            ("/data/notes.txt", "import_2", UploadStatus::Queued),
+            ("/data/scan.nii", "import_3", UploadStatus::InProgress),
+        ]
+        .iter()
+        {
+            let mut record = record.clone();
+            record.file_path = file_path.to_string();
+            record.import_id = import_id.to_string();
+            record.status = *status;
+            db.insert_upload(&record).unwrap();
+        }
+
+        assert_eq!(db.cancel_uploads_by_import_id("import_1").unwrap(), 3);
+
+        let active = db.get_active_uploads().unwrap();
+        assert_eq!(active.len(), 2);
+        assert!(active.iter().all(|upload| upload.import_id != "import_1"));
+        // Finished uploads are kept:
+        assert_eq!(db.get_completed_uploads(10).unwrap().len(), 1);
+        assert_eq!(db.cancel_uploads_by_import_id("import_1").unwrap(), 0);
+        assert_eq!(db.cancel_uploads_by_import_id("import_9").unwrap(), 0);
+    }
+
     #[test]
     fn test_get_in_progress_uploads() {
         let db = util::database::temp().unwrap();
@@ -1967,6 +3379,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record).unwrap();
         let mut record2 = UploadRecord {
@@ -1984,6 +3401,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record2).unwrap();
         let mut record3 = UploadRecord::new(
@@ -2014,6 +3436,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record4).unwrap();
         let coll = db.get_in_progress_uploads().unwrap();
@@ -2039,6 +3466,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record).unwrap();
         let mut record2 = UploadRecord {
@@ -2056,6 +3488,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record2).unwrap();
         let mut record3 = UploadRecord::new(
@@ -2086,6 +3523,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record4).unwrap();
         let coll = db.get_active_uploads().unwrap();
@@ -2114,6 +3556,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record1).unwrap();
         let mut record2 = UploadRecord {
@@ -2131,6 +3578,11 @@ mod test {
             organization_id: String::from("organization_2"),
             chunk_size: Some(200),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record2).unwrap();
         let mut record3 = UploadRecord {
@@ -2148,6 +3600,11 @@ mod test {
             organization_id: String::from("organization_3"),
             chunk_size: Some(300),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record3).unwrap();
         let mut record4 = UploadRecord {
@@ -2165,6 +3622,11 @@ mod test {
             organization_id: String::from("organization_4"),
             chunk_size: Some(400),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record4).unwrap();
         let coll = db.get_completed_uploads(10).unwrap();
@@ -2193,6 +3655,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record).unwrap();
         let mut record2 = UploadRecord {
@@ -2210,6 +3677,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record2).unwrap();
         let mut record3 = UploadRecord::new(
@@ -2240,6 +3712,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record4).unwrap();
         assert_eq!(
@@ -2251,6 +3728,27 @@ mod test {
         assert_eq!(db.get_in_progress_uploads().unwrap().records.len(), 2);
     }
 
+    #[test]
+    fn large_chunk_sizes_are_stored() {
+        let db = util::database::temp().unwrap();
+        let chunk_size = 5 * 1024 * 1024 * 1024;
+        let record = UploadRecord::new(
+            "file/path/1",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            Some(chunk_size),
+            None,
+        )
+        .unwrap();
+        let id = db.insert_upload(&record).unwrap();
+
+        let stored = db.get_upload_by_upload_id(id as usize).unwrap();
+        assert_eq!(stored.chunk_size, Some(chunk_size));
+    }
+
     #[test]
     fn test_upload_should_retry() {
         let now = time::now().to_timespec();
@@ -2269,12 +3767,18 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
-        assert!(!record.should_retry());
+        let thresholds = UploadThresholds::default();
+        assert!(!record.should_retry(thresholds));
         record.updated_at = now - time::Duration::minutes(30);
-        assert!(!record.should_retry());
+        assert!(!record.should_retry(thresholds));
         record.updated_at = now - time::Duration::minutes(90);
-        assert!(record.should_retry());
+        assert!(record.should_retry(thresholds));
     }
 
     #[test]
@@ -2295,12 +3799,68 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
-        assert!(!record.should_fail());
+        let thresholds = UploadThresholds::default();
+        assert!(!record.should_fail(thresholds));
+        record.created_at = now - time::Duration::hours(5);
+        assert!(!record.should_fail(thresholds));
+        record.created_at = now - time::Duration::hours(10);
+        assert!(record.should_fail(thresholds));
+    }
+
+    #[test]
+    fn upload_thresholds_shift_retry_and_fail_decisions() {
+        let now = time::now().to_timespec();
+        let mut record = UploadRecord::new(
+            "file/path/1",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.updated_at = now - time::Duration::minutes(30);
         record.created_at = now - time::Duration::hours(5);
-        assert!(!record.should_fail());
+
+        // the defaults give the upload more time:
+        assert!(!record.should_retry(UploadThresholds::default()));
+        assert!(!record.should_fail(UploadThresholds::default()));
+
+        // a tighter window retries and fails it sooner:
+        let impatient = UploadThresholds::new(15, 4);
+        assert!(record.should_retry(impatient));
+        assert!(record.should_fail(impatient));
+
+        // a looser window keeps waiting longer:
+        record.updated_at = now - time::Duration::minutes(90);
         record.created_at = now - time::Duration::hours(10);
-        assert!(record.should_fail());
+        assert!(record.should_retry(UploadThresholds::default()));
+        assert!(record.should_fail(UploadThresholds::default()));
+        let patient = UploadThresholds::new(120, 24);
+        assert!(!record.should_retry(patient));
+        assert!(!record.should_fail(patient));
+    }
+
+    #[test]
+    fn upload_thresholds_come_from_the_config() {
+        let mut config = config::Config::default();
+        assert_eq!(UploadThresholds::from(&config), UploadThresholds::default());
+
+        config.upload_retry_after_minutes = 5;
+        config.upload_fail_after_hours = 72;
+        config.upload_max_retries = 2;
+        let thresholds = UploadThresholds::from(&config);
+        assert_eq!(thresholds.retry_after, time::Duration::minutes(5));
+        assert_eq!(thresholds.fail_after, time::Duration::hours(72));
+        assert_eq!(thresholds.max_retries, 2);
     }
 
     #[test]
@@ -2354,6 +3914,11 @@ mod test {
             organization_id: String::from("organization_1"),
             chunk_size: Some(100),
             multipart_upload_id: Some(String::from("multipart_upload_id")),
+            priority: 0,
+            failure_reason: None,
+            tags: BTreeMap::new(),
+            last_error: None,
+            checksum: None,
         };
         db.insert_upload(&mut record).unwrap();
 
@@ -2443,6 +4008,345 @@ mod test {
         assert_eq!(coll.iter().collect::<Vec<_>>().len(), 12);
     }
 
+    #[test]
+    fn uploads_are_counted_by_status() {
+        let db = util::database::temp().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.bin");
+        fs::write(&file_path, vec![0; 1000]).unwrap();
+
+        let record = UploadRecord::new(
+            &file_path,
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            Some(100),
+            None,
+        )
+        .unwrap();
+        let statuses = [
+            (UploadStatus::Queued, 0),
+            (UploadStatus::Queued, 0),
+            (UploadStatus::InProgress, 40),
+            (UploadStatus::Completed, 100),
+            (UploadStatus::Completed, 100),
+            (UploadStatus::Completed, 100),
+            (UploadStatus::Failed, 10),
+        ];
+        for (status, progress) in statuses.iter() {
+            let mut record = record.clone();
+            record.status = *status;
+            record.progress = *progress;
+            db.insert_upload(&record).unwrap();
+        }
+        // a queued file that has since been removed:
+        let mut missing = record.clone();
+        missing.file_path = dir.path().join("missing.bin").display().to_string();
+        db.insert_upload(&missing).unwrap();
+
+        let counts = db.count_uploads_by_status().unwrap();
+        assert_eq!(counts[&UploadStatus::Queued], 3);
+        assert_eq!(counts[&UploadStatus::InProgress], 1);
+        assert_eq!(counts[&UploadStatus::Completed], 3);
+        assert_eq!(counts[&UploadStatus::Failed], 1);
+
+        // two untouched files, and 60% of a third:
+        assert_eq!(db.sum_pending_bytes().unwrap(), 2600);
+    }
+
+    #[test]
+    fn uploads_are_counted_by_when_they_were_last_updated() {
+        let db = util::database::temp().unwrap();
+        let now = time::now().to_timespec();
+        let record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        for (status, minutes_ago) in &[
+            (UploadStatus::Completed, 90),
+            (UploadStatus::Completed, 30),
+            (UploadStatus::Failed, 30),
+            (UploadStatus::Completed, 0),
+        ] {
+            let mut record = record.clone();
+            record.status = *status;
+            record.updated_at = now - time::Duration::minutes(*minutes_ago);
+            db.insert_upload(&record).unwrap();
+        }
+
+        let counts = db
+            .count_uploads_updated_between(now - time::Duration::hours(1), now)
+            .unwrap();
+        assert_eq!(counts[&UploadStatus::Completed], 2);
+        assert_eq!(counts[&UploadStatus::Failed], 1);
+
+        // the start of the range is exclusive:
+        let counts = db
+            .count_uploads_updated_between(now - time::Duration::minutes(30), now)
+            .unwrap();
+        assert_eq!(counts[&UploadStatus::Completed], 1);
+        assert!(!counts.contains_key(&UploadStatus::Failed));
+    }
+
+    #[test]
+    fn failure_reasons_are_persisted_until_the_upload_is_retried() {
+        let db = util::database::temp().unwrap();
+        let mut record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.status = UploadStatus::InProgress;
+        record.progress = 40;
+        let id = db.insert_upload(&record).unwrap() as usize;
+        assert_eq!(db.get_upload_by_upload_id(id).unwrap().failure_reason, None);
+
+        assert_eq!(
+            db.fail_import("import_1", "checksum mismatch", None)
+                .unwrap(),
+            1
+        );
+        let failed = db.get_upload_by_upload_id(id).unwrap();
+        assert_eq!(failed.status, UploadStatus::Failed);
+        assert_eq!(failed.progress, 40);
+        assert_eq!(failed.failure_reason, Some("checksum mismatch".to_string()));
+        assert_eq!(
+            db.get_failed_uploads().unwrap().records[0].failure_reason,
+            Some("checksum mismatch".to_string())
+        );
+
+        db.fail_import("import_1", "exceeded retry window", Some(0))
+            .unwrap();
+        let failed = db.get_upload_by_upload_id(id).unwrap();
+        assert_eq!(failed.progress, 0);
+        assert_eq!(
+            failed.failure_reason,
+            Some("exceeded retry window".to_string())
+        );
+
+        // Retrying an upload clears the reason it failed:
+        db.update_import_status_and_progress("import_1", UploadStatus::Failed, 50)
+            .unwrap();
+        assert!(db.resume_failed_upload(&id.to_string()).unwrap());
+        let resumed = db.get_upload_by_upload_id(id).unwrap();
+        assert_eq!(resumed.status, UploadStatus::Queued);
+        assert_eq!(resumed.failure_reason, None);
+    }
+
+    #[test]
+    fn the_last_error_of_an_upload_is_kept_until_it_completes() {
+        let db = util::database::temp().unwrap();
+        let mut record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.status = UploadStatus::InProgress;
+        let id = db.insert_upload(&record).unwrap() as usize;
+        assert_eq!(db.get_upload_by_upload_id(id).unwrap().last_error, None);
+
+        assert_eq!(
+            db.record_upload_error("import_1", "connection reset by peer")
+                .unwrap(),
+            1
+        );
+        let retried = db.get_upload_by_upload_id(id).unwrap();
+        assert_eq!(retried.status, UploadStatus::InProgress);
+        assert_eq!(retried.failure_reason, None);
+        assert_eq!(
+            retried.last_error,
+            Some("connection reset by peer".to_string())
+        );
+
+        // a later attempt overwrites the error of the earlier one:
+        db.record_upload_error("import_1", "request timed out")
+            .unwrap();
+        db.update_import_status_and_progress("import_1", UploadStatus::InProgress, 60)
+            .unwrap();
+        assert_eq!(
+            db.get_upload_by_upload_id(id).unwrap().last_error,
+            Some("request timed out".to_string())
+        );
+
+        db.update_import_status_and_progress("import_1", UploadStatus::Completed, 100)
+            .unwrap();
+        assert_eq!(db.get_upload_by_upload_id(id).unwrap().last_error, None);
+
+        db.record_upload_error("import_1", "request timed out")
+            .unwrap();
+        db.update_import_status("import_1", UploadStatus::Completed)
+            .unwrap();
+        assert_eq!(db.get_upload_by_upload_id(id).unwrap().last_error, None);
+    }
+
+    #[test]
+    fn stalled_uploads_record_why_they_were_reset() {
+        let db = Database::new(&Source::Memory).unwrap();
+        let mut record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.status = UploadStatus::InProgress;
+        let id = db.insert_upload(&record).unwrap() as usize;
+
+        assert_eq!(db.reset_stalled_uploads().unwrap(), 1);
+        let reset = db.get_upload_by_upload_id(id).unwrap();
+        assert_eq!(reset.status, UploadStatus::Queued);
+        assert_eq!(reset.last_error, Some(STALLED_UPLOAD_ERROR.to_string()));
+    }
+
+    #[test]
+    fn checksums_are_persisted_with_the_upload() {
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            Some(4),
+            None,
+        )
+        .unwrap()
+        .with_checksum(Some("2c26b46b".to_string()));
+        let id = db.insert_upload(&record).unwrap() as usize;
+
+        assert_eq!(
+            db.get_upload_by_upload_id(id).unwrap().checksum,
+            Some("2c26b46b".to_string())
+        );
+        assert_eq!(
+            db.get_queued_uploads().unwrap().records[0].checksum,
+            Some("2c26b46b".to_string())
+        );
+    }
+
+    #[test]
+    fn tags_are_persisted_with_the_upload() {
+        let db = util::database::temp().unwrap();
+        let record = |file_path: &str| {
+            UploadRecord::new(
+                file_path,
+                "ds_1",
+                None as Option<String>,
+                "organization_1",
+                "import_1",
+                false,
+                None,
+                None,
+            )
+            .unwrap()
+        };
+        let mut tags = BTreeMap::new();
+        tags.insert("subject".to_string(), "mouse-12".to_string());
+        tags.insert("session".to_string(), "a = b".to_string());
+
+        let tagged = db
+            .insert_upload(&record("/data/tagged.edf").with_tags(tags.clone()))
+            .unwrap() as usize;
+        let untagged = db.insert_upload(&record("/data/untagged.edf")).unwrap() as usize;
+
+        assert_eq!(db.get_upload_by_upload_id(tagged).unwrap().tags, tags);
+        assert!(db
+            .get_upload_by_upload_id(untagged)
+            .unwrap()
+            .tags
+            .is_empty());
+        assert_eq!(db.get_queued_uploads().unwrap().records[0].tags, tags);
+    }
+
+    #[test]
+    fn completed_uploads_are_filtered_by_when_they_were_updated() {
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            "/data/recording.edf",
+            "ds_1",
+            None as Option<String>,
+            "organization_1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        let day = |n: i64| time::Timespec::new(1_551_398_400 + n * 86_400, 0);
+        for (status, updated_at) in [
+            (UploadStatus::Completed, day(0)),
+            (UploadStatus::Completed, day(1)),
+            (UploadStatus::Completed, day(2)),
+            (UploadStatus::Failed, day(1)),
+        ]
+        .iter()
+        {
+            let mut record = record.clone();
+            record.status = *status;
+            record.updated_at = *updated_at;
+            db.insert_upload(&record).unwrap();
+        }
+        let updated = |uploads: UploadRecords| -> Vec<time::Timespec> {
+            uploads.iter().map(|upload| upload.updated_at).collect()
+        };
+
+        assert_eq!(
+            updated(db.get_completed_uploads_between(None, None).unwrap()),
+            vec![day(2), day(1), day(0)]
+        );
+        assert_eq!(
+            updated(
+                db.get_completed_uploads_between(Some(day(1)), None)
+                    .unwrap()
+            ),
+            vec![day(2), day(1)]
+        );
+        assert_eq!(
+            updated(
+                db.get_completed_uploads_between(None, Some(day(1)))
+                    .unwrap()
+            ),
+            vec![day(1), day(0)]
+        );
+        assert_eq!(
+            updated(
+                db.get_completed_uploads_between(Some(day(1)), Some(day(1)))
+                    .unwrap()
+            ),
+            vec![day(1)]
+        );
+        assert!(db
+            .get_completed_uploads_between(Some(day(3)), None)
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn test_version_checks() {
         let db = util::database::temp().unwrap();
@@ -2456,4 +4360,54 @@ mod test {
         let second = db.add_version_check().unwrap();
         assert_eq!(db.get_last_version_check().unwrap(), Some(second));
     }
+
+    #[test]
+    fn test_resolved_names() {
+        let db = util::database::temp().unwrap();
+        let before = time::now().to_timespec();
+        assert_eq!(
+            db.get_resolved_name("N:organization:1", "recordings", before)
+                .unwrap(),
+            None
+        );
+
+        db.upsert_resolved_name("N:organization:1", "recordings", "N:dataset:1")
+            .unwrap();
+        assert_eq!(
+            db.get_resolved_name("N:organization:1", "recordings", before)
+                .unwrap(),
+            Some("N:dataset:1".to_string())
+        );
+        // ...only in the organization it was resolved in:
+        assert_eq!(
+            db.get_resolved_name("N:organization:2", "recordings", before)
+                .unwrap(),
+            None
+        );
+
+        // Resolutions older than `since` are ignored:
+        thread::sleep(Duration::from_millis(1));
+        let after = time::now().to_timespec();
+        assert_eq!(
+            db.get_resolved_name("N:organization:1", "recordings", after)
+                .unwrap(),
+            None
+        );
+
+        db.upsert_resolved_name("N:organization:1", "recordings", "N:dataset:2")
+            .unwrap();
+        assert_eq!(
+            db.get_resolved_name("N:organization:1", "recordings", after)
+                .unwrap(),
+            Some("N:dataset:2".to_string())
+        );
+
+        db.delete_resolved_name("N:organization:1", "recordings")
+            .unwrap();
+        assert_eq!(
+            db.get_resolved_name("N:organization:1", "recordings", before)
+                .unwrap(),
+            None
+        );
+    }
 }
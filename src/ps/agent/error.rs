@@ -19,14 +19,9 @@ pub struct Error {
 impl Error {
     fn _render<T: string::ToString>(&self, context: Option<T>) -> i32 {
         let kind = self.kind();
-        let error_code = match kind {
-            // user cancellations should be ignored and treated as
-            // successful exits
-            ErrorKind::UserCancelledError => 0,
-            _ => 1,
-        };
+        let exit_code = kind.exit_code();
 
-        if error_code > 0 {
+        if exit_code != ExitCode::Success {
             // Display the message to the user
             match context {
                 Some(ctx) => eprintln!("{context}:{kind}", context = ctx.to_string(), kind = kind),
@@ -43,7 +38,7 @@ impl Error {
             );
         }
 
-        error_code
+        exit_code.into()
     }
 
     /// This function will display an error to the user and return the
@@ -122,6 +117,41 @@ impl fmt::Display for Error {
     }
 }
 
+/// The code the agent exits with for each class of error. These values
+/// are stable so that scripts wrapping the agent can branch on them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitCode {
+    /// The command completed, or the user explicitly cancelled it.
+    Success = 0,
+
+    /// Any error that does not fall into one of the categories below.
+    GeneralError = 1,
+
+    /// The user is not logged in, their profile is missing or invalid, or
+    /// the Pennsieve API rejected their credentials.
+    AuthError = 2,
+
+    /// An upload could not be queued, performed or verified.
+    UploadError = 3,
+
+    /// The configuration file or one of its values is missing or invalid.
+    ConfigError = 4,
+
+    /// The Pennsieve API or another remote host could not be reached, or
+    /// responded with an error.
+    NetworkError = 5,
+
+    /// The API key and secret of the profile were rejected, likely because
+    /// they were rotated or revoked. The user has to log in again.
+    Unauthorized = 6,
+}
+
+impl From<ExitCode> for i32 {
+    fn from(code: ExitCode) -> i32 {
+        code as i32
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Fail)]
 pub enum ErrorKind {
     #[fail(display = "malformed hostname {:?}", hostname)]
@@ -213,6 +243,75 @@ pub enum ErrorKind {
     VersionError { kind: version::ErrorKind },
 }
 
+impl ErrorKind {
+    /// Return the code with which the program should exit when it
+    /// encounters this kind of error.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            // user cancellations should be ignored and treated as
+            // successful exits
+            ErrorKind::UserCancelledError => ExitCode::Success,
+
+            ErrorKind::ApiError { kind } => match kind {
+                api::ErrorKind::NoUserError
+                | api::ErrorKind::NoUserProfileError
+                | api::ErrorKind::InvalidUserProfile { .. } => ExitCode::AuthError,
+                api::ErrorKind::Unauthorized { .. } => ExitCode::Unauthorized,
+                api::ErrorKind::InvalidUploadResponse { .. }
+                | api::ErrorKind::InvalidUpload { .. } => ExitCode::UploadError,
+                api::ErrorKind::Pennsieve { .. } | api::ErrorKind::Timeout { .. } => {
+                    ExitCode::NetworkError
+                }
+                _ => ExitCode::GeneralError,
+            },
+            ErrorKind::CliError { kind } => match kind {
+                cli::ErrorKind::InvalidLogin { .. } => ExitCode::AuthError,
+                cli::ErrorKind::UploadDoesNotMatch { .. } | cli::ErrorKind::UploadError { .. } => {
+                    ExitCode::UploadError
+                }
+                cli::ErrorKind::ConfigError { .. } => ExitCode::ConfigError,
+                _ => ExitCode::GeneralError,
+            },
+            ErrorKind::Pennsieve { kind } => match kind {
+                pennsieve_rust::ErrorKind::ApiError { status_code, .. }
+                    if *status_code == hyper::StatusCode::UNAUTHORIZED
+                        || *status_code == hyper::StatusCode::FORBIDDEN =>
+                {
+                    ExitCode::AuthError
+                }
+                _ => ExitCode::NetworkError,
+            },
+
+            ErrorKind::NoUploads | ErrorKind::UploadError { .. } => ExitCode::UploadError,
+
+            ErrorKind::MalformedHostName { .. }
+            | ErrorKind::UnsupportedScheme { .. }
+            | ErrorKind::MissingAssetDir
+            | ErrorKind::ConfigError { .. } => ExitCode::ConfigError,
+
+            ErrorKind::TimeoutError
+            | ErrorKind::UrlParseError { .. }
+            | ErrorKind::HyperError { .. }
+            | ErrorKind::VersionError { .. } => ExitCode::NetworkError,
+
+            ErrorKind::OutputFormat { .. }
+            | ErrorKind::Startup { .. }
+            | ErrorKind::ServiceTermination { .. }
+            | ErrorKind::TokioTimerError { .. }
+            | ErrorKind::ProtobufError { .. }
+            | ErrorKind::ParseIntError { .. }
+            | ErrorKind::IoError { .. }
+            | ErrorKind::SetLoggerError { .. }
+            | ErrorKind::FromUtf8Error { .. }
+            | ErrorKind::JsonError { .. }
+            | ErrorKind::SemVerError { .. }
+            | ErrorKind::DatabaseError { .. }
+            | ErrorKind::CacheError { .. }
+            | ErrorKind::ServerError { .. } => ExitCode::GeneralError,
+        }
+    }
+}
+
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Error {
         Error::from(Context::new(kind))
@@ -454,3 +553,231 @@ impl From<futures::Canceled> for Error {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_error_kind_renders_its_exit_code() {
+        let cases = vec![
+            (
+                ErrorKind::MalformedHostName {
+                    hostname: "host".to_string(),
+                },
+                ExitCode::ConfigError,
+            ),
+            (
+                ErrorKind::UnsupportedScheme {
+                    hostname: "host".to_string(),
+                    scheme: "ftp".to_string(),
+                },
+                ExitCode::ConfigError,
+            ),
+            (ErrorKind::MissingAssetDir, ExitCode::ConfigError),
+            (ErrorKind::NoUploads, ExitCode::UploadError),
+            (
+                ErrorKind::OutputFormat {
+                    bad_format: "xml".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::Startup {
+                    cause: "startup".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::ServiceTermination {
+                    error: "terminated".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::TokioTimerError {
+                    error: "timer".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (ErrorKind::TimeoutError, ExitCode::NetworkError),
+            (
+                ErrorKind::UrlParseError {
+                    error: "url".to_string(),
+                },
+                ExitCode::NetworkError,
+            ),
+            (
+                ErrorKind::HyperError {
+                    error: "hyper".to_string(),
+                },
+                ExitCode::NetworkError,
+            ),
+            (
+                ErrorKind::ProtobufError {
+                    error: "protobuf".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (ErrorKind::UserCancelledError, ExitCode::Success),
+            (
+                ErrorKind::Pennsieve {
+                    kind: pennsieve_rust::ErrorKind::ApiError {
+                        status_code: hyper::StatusCode::UNAUTHORIZED,
+                        message: "unauthorized".to_string(),
+                    },
+                },
+                ExitCode::AuthError,
+            ),
+            (
+                ErrorKind::Pennsieve {
+                    kind: pennsieve_rust::ErrorKind::ApiError {
+                        status_code: hyper::StatusCode::BAD_GATEWAY,
+                        message: "bad gateway".to_string(),
+                    },
+                },
+                ExitCode::NetworkError,
+            ),
+            (
+                ErrorKind::ParseIntError {
+                    error: "parse".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::IoError {
+                    error: "io".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::SetLoggerError {
+                    error: "logger".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::FromUtf8Error {
+                    error: "utf8".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::JsonError {
+                    error: "json".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::SemVerError {
+                    error: "semver".to_string(),
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::ApiError {
+                    kind: api::ErrorKind::NoUserError,
+                },
+                ExitCode::AuthError,
+            ),
+            (
+                ErrorKind::ApiError {
+                    kind: api::ErrorKind::Unauthorized {
+                        profile: "default".to_string(),
+                    },
+                },
+                ExitCode::Unauthorized,
+            ),
+            (
+                ErrorKind::ApiError {
+                    kind: api::ErrorKind::InvalidUpload {
+                        message: "upload".to_string(),
+                    },
+                },
+                ExitCode::UploadError,
+            ),
+            (
+                ErrorKind::ApiError {
+                    kind: api::ErrorKind::MissingDatasetPackage,
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::ApiError {
+                    kind: api::ErrorKind::Timeout { seconds: 30 },
+                },
+                ExitCode::NetworkError,
+            ),
+            (
+                ErrorKind::DatabaseError {
+                    kind: database::ErrorKind::QueryReturnedNoRows,
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::ConfigError {
+                    kind: config::ErrorKind::MissingProfile,
+                },
+                ExitCode::ConfigError,
+            ),
+            (
+                ErrorKind::UploadError {
+                    kind: upload::ErrorKind::NoFilesToUpload,
+                },
+                ExitCode::UploadError,
+            ),
+            (
+                ErrorKind::CacheError {
+                    kind: cache::ErrorKind::CollectorCancelled,
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::ServerError {
+                    kind: server::ErrorKind::Cancelled,
+                },
+                ExitCode::GeneralError,
+            ),
+            (
+                ErrorKind::CliError {
+                    kind: cli::ErrorKind::InvalidLogin {
+                        message: "login".to_string(),
+                    },
+                },
+                ExitCode::AuthError,
+            ),
+            (
+                ErrorKind::CliError {
+                    kind: cli::ErrorKind::ConfigError {
+                        kind: config::ErrorKind::MissingProfile,
+                    },
+                },
+                ExitCode::ConfigError,
+            ),
+            (
+                ErrorKind::VersionError {
+                    kind: version::ErrorKind::HyperError {
+                        error: "hyper".to_string(),
+                    },
+                },
+                ExitCode::NetworkError,
+            ),
+        ];
+
+        for (kind, expected) in cases {
+            let rendered = Error::from(kind.clone()).render();
+            assert_eq!(rendered, i32::from(expected), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn exit_codes_are_stable() {
+        assert_eq!(i32::from(ExitCode::Success), 0);
+        assert_eq!(i32::from(ExitCode::GeneralError), 1);
+        assert_eq!(i32::from(ExitCode::AuthError), 2);
+        assert_eq!(i32::from(ExitCode::UploadError), 3);
+        assert_eq!(i32::from(ExitCode::ConfigError), 4);
+        assert_eq!(i32::from(ExitCode::NetworkError), 5);
+        assert_eq!(i32::from(ExitCode::Unauthorized), 6);
+    }
+}
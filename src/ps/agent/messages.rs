@@ -1,6 +1,6 @@
 //! Message types that can be sent between services.
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::string::ToString;
 
@@ -8,21 +8,25 @@ use actix::prelude::*;
 use actix_net::server as s;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::ps::agent::database::Database;
 use crate::ps::agent::server;
 
 /// Signal that the system is shutting down.
 #[derive(Clone, Debug, Message)]
 pub struct SystemShutdown;
 
-/// Signal that the agent's status server should start.
+/// Signal that the agent's status server should start. If a database is
+/// given, the server reports on it at `/health`.
 #[derive(Clone, Debug)]
 pub struct StartStatusServer {
+    pub bind: IpAddr,
     pub port: u16,
+    pub db: Option<Database>,
 }
 
 impl StartStatusServer {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    pub fn new(bind: IpAddr, port: u16, db: Option<Database>) -> Self {
+        Self { bind, port, db }
     }
 }
 
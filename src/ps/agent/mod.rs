@@ -14,18 +14,20 @@ pub mod upload;
 pub mod version;
 
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
 use std::thread;
 
 use actix::dev::*;
 use log::*;
 use log_mdc;
 
+use self::database::Database;
 use self::messages::{ServerStartup, WorkerStartup};
 use self::types::ServiceHandle;
 pub use self::types::{
-    Error, ErrorKind, Future, HostName, OutputFormat, Result, Server, Service, ServiceFuture,
-    ServiceId, WithProps, Worker,
+    Error, ErrorKind, ExitCode, Future, HostName, OutputFormat, Result, Server, Service,
+    ServiceFuture, ServiceId, WithProps, Worker,
 };
 
 // A simple macro that sets up logging for background services.
@@ -44,19 +46,29 @@ macro_rules! setup_logging {
 pub struct AgentHandle {
     handles: Vec<ServiceHandle>,
     status_addr: Option<Addr<server::StatusServer>>,
+    status_bind: IpAddr,
     #[allow(dead_code)]
     status_port: u16,
+    db: Option<Database>,
     #[allow(dead_code)]
     quiet: bool,
 }
 
 impl AgentHandle {
     /// Create a handle to the running agent.
-    fn new(handles: Vec<ServiceHandle>, status_port: u16, quiet: bool) -> Self {
+    fn new(
+        handles: Vec<ServiceHandle>,
+        status_bind: IpAddr,
+        status_port: u16,
+        db: Option<Database>,
+        quiet: bool,
+    ) -> Self {
         Self {
             handles,
             status_addr: None,
+            status_bind,
             status_port,
+            db,
             quiet,
         }
     }
@@ -105,14 +117,19 @@ impl AgentHandle {
         {
             if !self.quiet {
                 println!(
-                    "Status server listening on port {port}",
+                    "Status server listening on {bind}:{port}",
+                    bind = self.status_bind,
                     port = self.status_port
                 );
             }
         }
 
         // Tell the status server to start up the websocket frontend:
-        status_addr.do_send(messages::StartStatusServer::new(self.status_port));
+        status_addr.do_send(messages::StartStatusServer::new(
+            self.status_bind,
+            self.status_port,
+            self.db.clone(),
+        ));
         self.status_addr = Some(status_addr);
 
         Ok(())
@@ -123,15 +140,20 @@ impl AgentHandle {
 /// A server context contains the context necessary in order to start (and
 /// restart) a service actor as needed.
 struct ServerContext<S: Server> {
+    local_bind: IpAddr,
     local_port: u16,
     inner: S,
 }
 
 impl<S: Server> ServerContext<S> {
     /// Define a new server with associated props.
-    pub fn define(local_port: u16, props: S::Props, inner: S) -> Self {
+    pub fn define(local_bind: IpAddr, local_port: u16, props: S::Props, inner: S) -> Self {
         S::with_props(props);
-        Self { local_port, inner }
+        Self {
+            local_bind,
+            local_port,
+            inner,
+        }
     }
 
     /// Take the server implementation contained in this context.
@@ -149,10 +171,9 @@ impl<S: Server> Service for ServerContext<S> {
     /// `Server` instance
     fn run(self: Box<Self>) -> Result<ServiceHandle> {
         let id = self.id();
-        let local_port = self.local_port;
+        let address = SocketAddr::new(self.local_bind, self.local_port);
         setup_logging!();
         let inner = self.into_inner();
-        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), local_port);
         let addr = inner.start();
         let addr_clone = addr.clone();
         Ok(ServiceHandle::new(
@@ -206,8 +227,11 @@ pub struct Agent {
     ports_in_use: HashMap<u16, ServiceId>,
     // Definitions for the servers and workers that will be run by the agent:
     services: Vec<Box<dyn Service>>,
-    // Status server port
+    // Status server bind address and port
+    status_bind: IpAddr,
     status_port: u16,
+    // Database reported on by the status server
+    db: Option<Database>,
     // Supress output?
     quiet: bool,
 }
@@ -218,7 +242,9 @@ impl Agent {
             ports_in_use: HashMap::new(),
             services: vec![],
             quiet: false,
+            status_bind: config::constants::CONFIG_DEFAULT_BIND_ADDRESS,
             status_port: config::constants::CONFIG_DEFAULT_STATUS_WEBSOCKET_PORT,
+            db: None,
         }
     }
 
@@ -246,27 +272,77 @@ impl Agent {
         self.quiet = true;
     }
 
+    /// Sets the local interface the status server will listen on.
+    pub fn set_status_bind(&mut self, bind: IpAddr) {
+        self.status_bind = bind;
+    }
+
     #[allow(dead_code)]
     /// Sets the port the status server will listen on.
     pub fn set_status_port(&mut self, port: u16) {
         self.status_port = port;
     }
 
-    /// Defines a new server for the agent to run.
+    /// Checks that the status server will be able to listen on its port,
+    /// returning `PortInUse` if something, typically another agent, is
+    /// already listening there.
+    pub fn check_status_port(&self) -> Result<()> {
+        match TcpListener::bind(SocketAddr::new(self.status_bind, self.status_port)) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+                Err(server::Error::port_in_use(self.status_bind, self.status_port).into())
+            }
+            Err(e) => Err(server::Error::from(e).into()),
+        }
+    }
+
+    /// Sets the database the status server reports on at `/health`.
+    pub fn set_database(&mut self, db: Database) {
+        self.db = Some(db);
+    }
+
+    /// Defines a new server for the agent to run, listening on the default
+    /// local interface.
     pub fn define_server<S>(
         &mut self,
         local_port: u16,
         props: S::Props,
         server: S,
     ) -> Result<&mut Self>
+    where
+        S: 'static + Server,
+    {
+        self.define_server_with_bind(
+            config::constants::CONFIG_DEFAULT_BIND_ADDRESS,
+            local_port,
+            props,
+            server,
+        )
+    }
+
+    /// Defines a new server for the agent to run, listening on the given
+    /// local interface.
+    pub fn define_server_with_bind<S>(
+        &mut self,
+        local_bind: IpAddr,
+        local_port: u16,
+        props: S::Props,
+        server: S,
+    ) -> Result<&mut Self>
     where
         S: 'static + Server,
     {
         match self.check_and_register_port(local_port, server.id()) {
             Ok(_) => {
-                info!("Defined server: port {} => {:?}", local_port, server.id());
-                self.services
-                    .push(Box::new(ServerContext::define(local_port, props, server)));
+                info!(
+                    "Defined server: {}:{} => {:?}",
+                    local_bind,
+                    local_port,
+                    server.id()
+                );
+                self.services.push(Box::new(ServerContext::define(
+                    local_bind, local_port, props, server,
+                )));
                 Ok(self)
             }
             Err(e) => Err(e),
@@ -308,7 +384,13 @@ impl Agent {
             })
             .collect::<Result<Vec<ServiceHandle>>>()?;
 
-        Ok(AgentHandle::new(handles, self.status_port, self.quiet))
+        Ok(AgentHandle::new(
+            handles,
+            self.status_bind,
+            self.status_port,
+            self.db,
+            self.quiet,
+        ))
     }
 }
 
@@ -317,6 +399,7 @@ mod test {
     use crate::ps;
     use crate::ps::agent::{server, Agent};
     use actix::prelude::*;
+    use std::net::TcpListener;
 
     const REMOTE_HOST: &str = "https://httpbin.org";
 
@@ -415,6 +498,27 @@ mod test {
         assert!(agent.setup().is_ok());
     }
 
+    #[test]
+    fn a_second_agent_on_the_status_port_fails() {
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = first.local_addr().unwrap().port();
+
+        let mut agent = Agent::new();
+        agent.set_status_bind("127.0.0.1".parse().unwrap());
+        agent.set_status_port(port);
+
+        let e = agent.check_status_port().unwrap_err();
+        match e.kind() {
+            ps::ErrorKind::ServerError {
+                kind: server::ErrorKind::PortInUse { port: p, .. },
+            } => assert_eq!(*p, port),
+            kind => panic!("unexpected error: {}", kind),
+        }
+
+        drop(first);
+        assert!(agent.check_status_port().is_ok());
+    }
+
     #[test]
     fn agent_can_get_addr_for_worker() {
         let mut agent = Agent::new();
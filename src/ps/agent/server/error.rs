@@ -1,12 +1,14 @@
 //! Server related errors
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::{fmt, io, result, sync};
 
 use failure::{Backtrace, Context, Fail};
 
 use futures::sync::mpsc;
 
-use crate::ps::agent::cache;
 use crate::ps::agent::types::ServiceId;
+use crate::ps::agent::{cache, database};
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -31,6 +33,14 @@ impl Error {
         ErrorKind::PortAlreadyInUseError { port, service_id }.into()
     }
 
+    pub fn port_in_use(bind: IpAddr, port: u16) -> Error {
+        ErrorKind::PortInUse { bind, port }.into()
+    }
+
+    pub fn already_running(pid: u32, path: PathBuf) -> Error {
+        ErrorKind::AlreadyRunning { pid, path }.into()
+    }
+
     pub fn startup<S: Into<String>>(message: S) -> Error {
         ErrorKind::StartupError {
             message: message.into(),
@@ -79,9 +89,23 @@ pub enum ErrorKind {
     #[fail(display = "Port already in use: {}", port)]
     PortAlreadyInUseError { port: u16, service_id: ServiceId },
 
+    #[fail(
+        display = "The status port {}:{} is already in use, most likely by another Pennsieve agent.\n\
+                   Stop the running agent (check `server status --pid-file <path>`, or find it with `lsof -i :{}`),\n\
+                   or set a different `status_port` in config.ini",
+        bind, port, port
+    )]
+    PortInUse { bind: IpAddr, port: u16 },
+
     #[fail(display = "Invalid message type: {}", message_type)]
     InvalidMessageType { message_type: String },
 
+    #[fail(
+        display = "The agent is already running (PID {}, from {:?})",
+        pid, path
+    )]
+    AlreadyRunning { pid: u32, path: PathBuf },
+
     #[fail(display = "Startup error: {}", message)]
     StartupError { message: String },
 
@@ -114,6 +138,9 @@ pub enum ErrorKind {
 
     #[fail(display = "Cache error: {}", kind)]
     CacheError { kind: cache::ErrorKind },
+
+    #[fail(display = "Database error: {}", kind)]
+    DatabaseError { kind: database::ErrorKind },
 }
 
 impl From<ErrorKind> for Error {
@@ -141,6 +168,14 @@ impl From<cache::Error> for Error {
 }
 
 /// map from io errors
+impl From<database::Error> for Error {
+    fn from(error: database::Error) -> Error {
+        Error::from(Context::new(ErrorKind::DatabaseError {
+            kind: error.kind().clone(),
+        }))
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Error {
         Error::from(Context::new(ErrorKind::IoError {
@@ -0,0 +1,116 @@
+//! Heartbeats of the background workers, so that `/health` can tell a
+//! worker that has wedged from one that is merely waiting for work.
+//!
+//! Each worker beats on every iteration of its loop, and is reported as
+//! stalled once it hasn't beaten for longer than its own threshold.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use serde_derive::Serialize;
+
+use crate::ps::agent::types::ServiceId;
+
+lazy_static! {
+    // The heartbeats of the workers running in this process:
+    static ref HEARTBEATS: Mutex<Heartbeats> = Mutex::new(Heartbeats::default());
+}
+
+/// Records that `worker` is alive. It is reported as stalled if it doesn't
+/// beat again within `stall_after`.
+pub fn beat(worker: ServiceId, stall_after: Duration) {
+    if let Ok(mut heartbeats) = HEARTBEATS.lock() {
+        heartbeats.beat(worker, Instant::now(), stall_after);
+    }
+}
+
+/// Reports the heartbeat of every worker that has beaten at least once.
+pub fn report() -> Vec<WorkerHeartbeat> {
+    HEARTBEATS
+        .lock()
+        .map(|heartbeats| heartbeats.report(Instant::now()))
+        .unwrap_or_else(|_| vec![])
+}
+
+/// The heartbeat of a worker, as reported by `/health`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct WorkerHeartbeat {
+    pub worker: String,
+    pub last_heartbeat_seconds: u64,
+    pub stalled: bool,
+}
+
+#[derive(Debug)]
+struct Heartbeat {
+    at: Instant,
+    stall_after: Duration,
+}
+
+/// The last heartbeat of each worker.
+#[derive(Debug, Default)]
+struct Heartbeats(HashMap<&'static str, Heartbeat>);
+
+impl Heartbeats {
+    fn beat(&mut self, worker: ServiceId, at: Instant, stall_after: Duration) {
+        self.0.insert(worker.into(), Heartbeat { at, stall_after });
+    }
+
+    /// Reports the age of each heartbeat as of `now`, ordered by worker.
+    fn report(&self, now: Instant) -> Vec<WorkerHeartbeat> {
+        let mut report: Vec<WorkerHeartbeat> = self
+            .0
+            .iter()
+            .map(|(worker, heartbeat)| {
+                let age = now.duration_since(heartbeat.at);
+                WorkerHeartbeat {
+                    worker: worker.to_string(),
+                    last_heartbeat_seconds: age.as_secs(),
+                    stalled: age > heartbeat.stall_after,
+                }
+            })
+            .collect();
+        report.sort_by(|a, b| a.worker.cmp(&b.worker));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workers_that_stop_beating_are_stalled() {
+        let start = Instant::now();
+        let mut heartbeats = Heartbeats::default();
+        heartbeats.beat(ServiceId("Uploader"), start, Duration::from_secs(60));
+        heartbeats.beat(ServiceId("CacheCollector"), start, Duration::from_secs(600));
+
+        let report = heartbeats.report(start + Duration::from_secs(61));
+        assert_eq!(
+            report,
+            vec![
+                WorkerHeartbeat {
+                    worker: "CacheCollector".to_string(),
+                    last_heartbeat_seconds: 61,
+                    stalled: false,
+                },
+                WorkerHeartbeat {
+                    worker: "Uploader".to_string(),
+                    last_heartbeat_seconds: 61,
+                    stalled: true,
+                },
+            ]
+        );
+
+        // Beating again revives the worker:
+        heartbeats.beat(
+            ServiceId("Uploader"),
+            start + Duration::from_secs(61),
+            Duration::from_secs(60),
+        );
+        let report = heartbeats.report(start + Duration::from_secs(62));
+        assert!(report.iter().all(|heartbeat| !heartbeat.stalled));
+    }
+}
@@ -0,0 +1,110 @@
+//! Shutting down an agent that has been idle for a while, as requested
+//! with `--idle-timeout`.
+//!
+//! The agent is idle while its upload queue is empty and no websocket
+//! messages arrive. Monitoring requests to `/health` and `/metrics` don't
+//! count, so that a scraper or liveness probe doesn't keep it running.
+
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use lazy_static::lazy_static;
+use log::*;
+use timer::Timer;
+
+use crate::ps::agent::config::constants::AGENT_IDLE_CHECK_INTERVAL_MS;
+use crate::ps::agent::database::Database;
+use crate::ps::agent::messages::SystemShutdown;
+use crate::ps::agent::server::StatusServer;
+use crate::ps::agent::upload::Uploader;
+
+lazy_static! {
+    // When the agent was last seen doing something:
+    static ref LAST_ACTIVITY: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+/// Records that the agent is doing something, restarting the idle period.
+pub fn record_activity() {
+    if let Ok(mut last_activity) = LAST_ACTIVITY.lock() {
+        *last_activity = Instant::now();
+    }
+}
+
+fn idle_for(now: Instant) -> Duration {
+    LAST_ACTIVITY
+        .lock()
+        .map(|last_activity| now.duration_since(*last_activity))
+        .unwrap_or_else(|_| Duration::from_secs(0))
+}
+
+/// Tests if the agent has been idle for at least `timeout`, counting any
+/// active upload as activity.
+fn is_idle(db: &Database, timeout: Duration) -> bool {
+    let has_active_uploads = db
+        .get_active_uploads()
+        .map(|uploads| !uploads.is_empty())
+        .unwrap_or_else(|e| {
+            error!("idle: couldn't read the upload queue = {}", e);
+            true
+        });
+    if has_active_uploads || !Uploader::is_idle() {
+        record_activity();
+    }
+    idle_for(Instant::now()) >= timeout
+}
+
+/// Shuts down `system` once the agent has been idle for `timeout`.
+pub fn install_idle_shutdown(system: System, db: Database, timeout: Duration) {
+    info!("idle: shutting down after {:?} of inactivity", timeout);
+    record_activity();
+
+    let timer = Timer::new();
+    let mut shutting_down = false;
+    timer
+        .schedule_repeating(
+            chrono::Duration::milliseconds(AGENT_IDLE_CHECK_INTERVAL_MS as i64),
+            move || {
+                if !shutting_down && is_idle(&db, timeout) {
+                    info!("idle: timeout exceeded");
+                    shutting_down = true;
+                    system
+                        .registry()
+                        .get::<StatusServer>()
+                        .do_send(SystemShutdown);
+                }
+            },
+        )
+        .ignore();
+    mem::forget(timer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::thread;
+
+    use crate::ps::util;
+
+    #[test]
+    fn idle_agent_shuts_itself_down() {
+        let db = util::database::temp().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let started_at = Instant::now();
+            System::run(move || {
+                install_idle_shutdown(System::current(), db, Duration::from_millis(500));
+            });
+            sender.send(started_at.elapsed()).unwrap();
+        });
+
+        let elapsed = receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the idle agent didn't shut down");
+        assert!(elapsed >= Duration::from_millis(500));
+    }
+}
@@ -1,9 +1,13 @@
 mod error;
+pub mod heartbeat;
+pub mod idle;
+mod pid_file;
 pub mod rp;
 mod status;
 pub mod ts;
 
 pub use self::error::{Error, ErrorKind, Result};
+pub use self::pid_file::{PidFile, PidStatus};
 pub use self::rp::ReverseProxyServer;
 pub use self::status::{StatusServer, WebSocketServer};
 pub use self::ts::TimeSeriesServer;
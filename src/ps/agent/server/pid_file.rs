@@ -0,0 +1,224 @@
+//! The PID file written by `server --pid-file`, so that service managers
+//! (and `server status`) can find the running agent.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::*;
+
+use crate::ps::agent::server::{Error, Result};
+
+lazy_static! {
+    // The PID file of the running agent, once it is held:
+    static ref HELD: Mutex<Option<PidFile>> = Mutex::new(None);
+}
+
+/// A PID file held by the running agent. The file is removed when this
+/// value is dropped, as happens when the agent shuts down cleanly.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    /// Writes the PID of this process to `path`.
+    ///
+    /// If the file already names a live process, the agent is already
+    /// running and an error is returned. A file left behind by an agent
+    /// that has since exited is taken over.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        if let PidStatus::Running(pid) = PidStatus::read(&path)? {
+            return Err(Error::already_running(pid, path));
+        }
+
+        let pid = process::id();
+        fs::write(&path, format!("{}\n", pid))?;
+        info!("pid-file: wrote {} to {:?}", pid, path);
+
+        Ok(Self { path, pid })
+    }
+
+    /// The path of the PID file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Holds on to the PID file until `release` is called, so that it is
+    /// removed even if the agent is forced to exit.
+    pub fn hold(self) {
+        if let Ok(mut held) = HELD.lock() {
+            *held = Some(self);
+        }
+    }
+
+    /// Removes the PID file being held, if any.
+    pub fn release() {
+        if let Ok(mut held) = HELD.lock() {
+            held.take();
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave the file alone if another agent has since taken it over:
+        if read_pid(&self.path).ok() == Some(Some(self.pid)) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("pid-file: couldn't remove {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// The state of the agent according to a PID file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PidStatus {
+    /// There is no PID file.
+    Missing,
+    /// The PID file names a process that is still alive.
+    Running(u32),
+    /// The PID file was left behind by a process that is no longer alive,
+    /// or does not contain a PID at all.
+    Stale(Option<u32>),
+}
+
+impl PidStatus {
+    /// Reads the PID file at `path` and checks if the process it names is
+    /// alive.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(PidStatus::Missing);
+        }
+        Ok(match read_pid(path)? {
+            Some(pid) if is_alive(pid) => PidStatus::Running(pid),
+            pid => PidStatus::Stale(pid),
+        })
+    }
+
+    /// Tests if the agent is running.
+    pub fn is_running(self) -> bool {
+        match self {
+            PidStatus::Running(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PidStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PidStatus::Missing => write!(f, "Agent is not running (no PID file)"),
+            PidStatus::Running(pid) => write!(f, "Agent is running (PID {})", pid),
+            PidStatus::Stale(Some(pid)) => {
+                write!(f, "Agent is not running (stale PID file for PID {})", pid)
+            }
+            PidStatus::Stale(None) => write!(f, "Agent is not running (invalid PID file)"),
+        }
+    }
+}
+
+// Reads the PID in the file at `path`, if it holds one.
+fn read_pid(path: &Path) -> Result<Option<u32>> {
+    Ok(fs::read_to_string(path)?.trim().parse::<u32>().ok())
+}
+
+// Tests if a process with the given PID exists.
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    Command::new("kill")
+        .args(&["-0", &pid.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+// Tests if a process with the given PID exists.
+#[cfg(windows)]
+fn is_alive(pid: u32) -> bool {
+    Command::new("tasklist")
+        .args(&["/FI", &format!("PID eq {}", pid), "/NH"])
+        .stderr(Stdio::null())
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ps::agent::server::ErrorKind;
+
+    // Larger than any PID a system hands out:
+    const DEAD_PID: u32 = 999_999_999;
+
+    #[test]
+    fn pid_files_are_written_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.pid");
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(process::id()));
+        assert_eq!(
+            PidStatus::read(&path).unwrap(),
+            PidStatus::Running(process::id())
+        );
+
+        drop(pid_file);
+        assert!(!path.exists());
+        assert_eq!(PidStatus::read(&path).unwrap(), PidStatus::Missing);
+    }
+
+    #[test]
+    fn a_live_pid_file_prevents_a_second_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.pid");
+
+        let _pid_file = PidFile::create(&path).unwrap();
+        let e = PidFile::create(&path).unwrap_err();
+        match e.kind() {
+            ErrorKind::AlreadyRunning { pid, .. } => assert_eq!(*pid, process::id()),
+            kind => panic!("unexpected error: {}", kind),
+        }
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn stale_pid_files_are_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.pid");
+        fs::write(&path, format!("{}\n", DEAD_PID)).unwrap();
+        assert_eq!(
+            PidStatus::read(&path).unwrap(),
+            PidStatus::Stale(Some(DEAD_PID))
+        );
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(process::id()));
+
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn held_pid_files_are_removed_on_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.pid");
+
+        PidFile::create(&path).unwrap().hold();
+        assert!(path.exists());
+
+        PidFile::release();
+        assert!(!path.exists());
+    }
+}
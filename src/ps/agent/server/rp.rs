@@ -1,11 +1,13 @@
 //! A reverse proxy server
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 use std::{io, net};
 
 use actix::prelude::*;
 use futures::future;
 use futures::*;
-use hyper::{self, client, Body, Method, Uri};
+use hyper::{self, client, Body, Method, StatusCode, Uri};
 use hyper_tls;
 use log::*;
 use tokio;
@@ -30,6 +32,88 @@ const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const X_PS_API_LOCATION: &str = "X-Ps-Api-Location";
 
+// Counts of the requests proxied since the agent started:
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// What the reverse proxies have done since the agent started, as reported
+/// by the status server's `/metrics`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProxyStats {
+    /// Requests received, not counting health checks.
+    pub requests: usize,
+    /// Requests that couldn't be proxied, or were answered with a server
+    /// error.
+    pub errors: usize,
+    /// Body bytes proxied, in either direction.
+    pub bytes: usize,
+}
+
+/// Gets what the reverse proxies have done since the agent started.
+pub fn stats() -> ProxyStats {
+    ProxyStats {
+        requests: REQUESTS.load(Ordering::SeqCst),
+        errors: ERRORS.load(Ordering::SeqCst),
+        bytes: BYTES.load(Ordering::SeqCst),
+    }
+}
+
+/// Counts the bytes of `body` as they are proxied.
+fn counted(body: Body) -> Body {
+    Body::wrap_stream(body.inspect(|chunk| {
+        BYTES.fetch_add(chunk.len(), Ordering::SeqCst);
+    }))
+}
+
+/// A request being proxied, which is counted and logged once it is
+/// answered.
+struct ProxiedRequest {
+    method: Method,
+    path: String,
+    started: Instant,
+}
+
+impl ProxiedRequest {
+    fn new<B>(req: &hyper::Request<B>) -> Self {
+        REQUESTS.fetch_add(1, Ordering::SeqCst);
+        Self {
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Records the status the request was answered with, or `None` if the
+    /// remote host couldn't be reached. Requests are logged at the debug
+    /// level, so they are left out of the logs by default.
+    fn answered(&self, status: Option<StatusCode>) {
+        let latency = self.started.elapsed().as_millis();
+        if status.map_or(true, |status| status.is_server_error()) {
+            ERRORS.fetch_add(1, Ordering::SeqCst);
+        }
+        match status {
+            Some(status) => debug!(
+                "proxy: {} {} {} {}ms",
+                self.method,
+                self.path,
+                status.as_u16(),
+                latency
+            ),
+            None => debug!("proxy: {} {} failed {}ms", self.method, self.path, latency),
+        }
+    }
+
+    /// Answers the request with a server error, without proxying it.
+    fn fail(
+        &self,
+        message: String,
+    ) -> Box<dyn Future<Item = hyper::Response<Body>, Error = hyper::Error> + Send> {
+        self.answered(Some(StatusCode::INTERNAL_SERVER_ERROR));
+        future::ok(h::fail_with_message(message)).into_trait()
+    }
+}
+
 /// An agent server that acts as a reverse proxy
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct ReverseProxyServer;
@@ -172,26 +256,22 @@ impl hyper::service::Service for ReverseProxyService {
                 #[cfg(debug_assertions)]
                 inspect_request(&req);
 
+                let proxied = ProxiedRequest::new(&req);
+
                 let remote_host: String = match req.headers().get(X_PS_API_LOCATION) {
                     Some(api_location) => {
                         let redirect_api_loc = match api_location.to_str() {
                             Ok(location) => location,
                             Err(_) => {
-                                return future::ok(h::fail_with_message(format!(
-                                    "Bad API location: {:?}",
-                                    api_location
-                                )))
-                                .into_trait();
+                                return proxied
+                                    .fail(format!("Bad API location: {:?}", api_location));
                             }
                         };
                         match HostName::parse(redirect_api_loc) {
                             Ok(uri) => uri.into(),
                             Err(_) => {
-                                return future::ok(h::fail_with_message(format!(
-                                    "Bad API location: {:?}",
-                                    redirect_api_loc
-                                )))
-                                .into_trait();
+                                return proxied
+                                    .fail(format!("Bad API location: {:?}", redirect_api_loc));
                             }
                         }
                     }
@@ -205,11 +285,7 @@ impl hyper::service::Service for ReverseProxyService {
                 let remote_uri: Uri = match remote_uri_str.parse() {
                     Ok(uri) => uri,
                     Err(_) => {
-                        return future::ok(h::fail_with_message(format!(
-                            "Bad URI: {}",
-                            remote_uri_str
-                        )))
-                        .into_trait();
+                        return proxied.fail(format!("Bad URI: {}", remote_uri_str));
                     }
                 };
 
@@ -245,12 +321,24 @@ impl hyper::service::Service for ReverseProxyService {
 
                 *outgoing_req.method_mut() = req.method().clone();
                 *outgoing_req.uri_mut() = remote_uri;
-                *outgoing_req.body_mut() = req.into_body();
+                *outgoing_req.body_mut() = counted(req.into_body());
 
                 #[cfg(debug_assertions)]
                 inspect_request(&outgoing_req);
 
-                to_future_trait(self.client.request(outgoing_req))
+                self.client
+                    .request(outgoing_req)
+                    .then(move |result| match result {
+                        Ok(response) => {
+                            proxied.answered(Some(response.status()));
+                            Ok(response.map(counted))
+                        }
+                        Err(e) => {
+                            proxied.answered(None);
+                            Err(e)
+                        }
+                    })
+                    .into_trait()
             }
         }
     }
@@ -1,15 +1,20 @@
 //! Status reporting endpoint
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::time::Duration;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use ::actix::prelude::*;
 use actix_net::server as s;
 use actix_web::server::HttpServer;
 use actix_web::*;
 use log::*;
-use serde_json::{self, Value as JSON};
+use serde_json::{self, json, Value as JSON};
+use time::Timespec;
 
+use crate::ps::agent::database::{Database, UploadStatus};
 use crate::ps::agent::messages::{self, *};
 use crate::ps::agent::{server, upload};
 use crate::ps::util::actor as a;
@@ -47,6 +52,136 @@ thread_local! {
     static CLIENTS: RefCell<HashSet<Addr<WebSocketServer>>> = RefCell::new(HashSet::new());
 }
 
+////////////////////////////////////////////////////////////////////////////////
+// Metrics
+////////////////////////////////////////////////////////////////////////////////
+
+/// The number of uploads seen to fail or complete while the agent has been
+/// running. Each scrape adds the uploads that failed or completed since the
+/// one before, so the `/metrics` counters never decrease when a failed
+/// upload is resumed or an upload record is removed.
+#[derive(Debug)]
+struct UploadCounters {
+    /// When the uploads were last counted, or when the agent started.
+    since: Timespec,
+    failed: usize,
+    completed: usize,
+}
+
+impl UploadCounters {
+    fn new(since: Timespec) -> Self {
+        Self {
+            since,
+            failed: 0,
+            completed: 0,
+        }
+    }
+}
+
+/// Appends a metric in the Prometheus text format to `out`.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: i64) {
+    // Writing to a `String` cannot fail:
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Builds the body of a `/metrics` response, updating the upload counters
+/// with the uploads that have failed or completed since the last scrape.
+fn metrics(db: &Database, counters: &Mutex<UploadCounters>) -> server::Result<String> {
+    let active = db.get_active_uploads()?.len() as i64;
+    let (failed, completed) = {
+        let mut counters = counters.lock()?;
+        let now = time::now().to_timespec();
+        let counts = db.count_uploads_updated_between(counters.since, now)?;
+        counters.failed += counts.get(&UploadStatus::Failed).cloned().unwrap_or(0);
+        counters.completed += counts.get(&UploadStatus::Completed).cloned().unwrap_or(0);
+        counters.since = now;
+        (counters.failed as i64, counters.completed as i64)
+    };
+
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "pennsieve_uploads_active",
+        "gauge",
+        "Uploads that are queued or in progress.",
+        active,
+    );
+    write_metric(
+        &mut out,
+        "pennsieve_uploads_failed_total",
+        "counter",
+        "Uploads seen to fail since the agent started.",
+        failed,
+    );
+    write_metric(
+        &mut out,
+        "pennsieve_uploads_completed_total",
+        "counter",
+        "Uploads seen to complete since the agent started.",
+        completed,
+    );
+    write_metric(
+        &mut out,
+        "pennsieve_cache_bytes",
+        "gauge",
+        "Size of the timeseries page cache in bytes.",
+        db.get_total_size()?,
+    );
+    write_metric(
+        &mut out,
+        "pennsieve_cache_pages",
+        "gauge",
+        "Number of pages in the timeseries page cache.",
+        db.get_page_count()?,
+    );
+
+    let proxy = server::rp::stats();
+    write_metric(
+        &mut out,
+        "pennsieve_proxy_requests_total",
+        "counter",
+        "Requests received by the reverse proxies since the agent started.",
+        proxy.requests as i64,
+    );
+    write_metric(
+        &mut out,
+        "pennsieve_proxy_errors_total",
+        "counter",
+        "Proxied requests that failed or were answered with a server error.",
+        proxy.errors as i64,
+    );
+    write_metric(
+        &mut out,
+        "pennsieve_proxy_bytes_total",
+        "counter",
+        "Body bytes proxied in either direction.",
+        proxy.bytes as i64,
+    );
+    Ok(out)
+}
+
+/// Handles `GET /metrics`, reporting the agent's metrics in the Prometheus
+/// text format. Scrapes don't count as activity for `--idle-timeout`, so a
+/// scraper doesn't keep an idle agent running.
+fn metrics_scrape(req: &HttpRequest<WebsocketSharedState>) -> HttpResponse {
+    let state = req.state();
+    let db = match state.db {
+        Some(ref db) => db,
+        None => return HttpResponse::ServiceUnavailable().body("no database available"),
+    };
+    match metrics(db, &state.counters) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => {
+            error!("status server: metrics scrape failed = {}", e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // Websocket shared state
 ////////////////////////////////////////////////////////////////////////////////
@@ -54,12 +189,28 @@ thread_local! {
 pub struct WebsocketSharedState {
     /// The actix-web state shared amongst all web socket server instances.
     status_addr: Addr<StatusServer>,
+    /// The database reported on by `/health`, if any.
+    db: Option<Database>,
+    /// When the status server was started.
+    started_at: Instant,
+    /// The uploads counted by `/metrics`, shared by all server threads.
+    counters: Arc<Mutex<UploadCounters>>,
 }
 
 impl WebsocketSharedState {
     /// Create a new shared websocket state.
-    fn new(status_addr: Addr<StatusServer>) -> Self {
-        Self { status_addr }
+    fn new(
+        status_addr: Addr<StatusServer>,
+        db: Option<Database>,
+        started_at: Instant,
+        counters: Arc<Mutex<UploadCounters>>,
+    ) -> Self {
+        Self {
+            status_addr,
+            db,
+            started_at,
+            counters,
+        }
     }
 
     /// Get the address of the status server.
@@ -68,6 +219,60 @@ impl WebsocketSharedState {
     }
 }
 
+////////////////////////////////////////////////////////////////////////////////
+// Health check
+////////////////////////////////////////////////////////////////////////////////
+
+/// Builds the body of a `/health` response. The cache size is the number of
+/// bytes the cached pages take up on disk, as recorded when each page is
+/// written. The upload and cache figures are `null` if the status server
+/// was started without a database. The status is "stalled" if any of the
+/// `workers` has stopped beating.
+fn health(
+    db: Option<&Database>,
+    uptime: Duration,
+    workers: Vec<server::heartbeat::WorkerHeartbeat>,
+) -> server::Result<JSON> {
+    let (active_uploads, cache_size) = match db {
+        Some(db) => (
+            Some(db.get_active_uploads()?.len()),
+            Some(db.get_total_size()?),
+        ),
+        None => (None, None),
+    };
+
+    let stalled = workers.iter().any(|worker| worker.stalled);
+
+    Ok(json!({
+        "status": if stalled { "stalled" } else { "ok" },
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_seconds": uptime.as_secs(),
+        "active_uploads": active_uploads,
+        "cache_size_bytes": cache_size,
+        "workers": workers,
+    }))
+}
+
+/// Handles `GET /health`, a liveness probe that requires no authentication.
+/// Like `/metrics`, it doesn't count as activity for `--idle-timeout`.
+fn health_check(req: &HttpRequest<WebsocketSharedState>) -> HttpResponse {
+    let state = req.state();
+    match health(
+        state.db.as_ref(),
+        state.started_at.elapsed(),
+        server::heartbeat::report(),
+    ) {
+        Ok(body) => HttpResponse::Ok().json(body),
+        Err(e) => {
+            error!("status server: health check failed = {}", e);
+            HttpResponse::ServiceUnavailable().json(json!({
+                "status": "error",
+                "error": e.to_string(),
+            }))
+        }
+    }
+}
+
 // Like `Props` instances for the various servers and workers, the thread-local
 // state for this module contains the current, active web socket server
 // instances. This is needed due to the restriction of `Default` being
@@ -154,18 +359,29 @@ impl Handler<StartStatusServer> for StatusServer {
     type Result = server::Result<Addr<s::Server>>;
 
     fn handle(&mut self, msg: StartStatusServer, ctx: &mut Self::Context) -> Self::Result {
-        let port = msg.port;
+        let addr = SocketAddr::new(msg.bind, msg.port);
+        let db = msg.db;
+        let started_at = Instant::now();
+        let counters = Arc::new(Mutex::new(UploadCounters::new(time::now().to_timespec())));
         let self_addr: Addr<StatusServer> = ctx.address();
 
-        info!("Server status websocket running on 0.0.0.0:{}", port);
+        info!("Server status websocket running on {}", addr);
 
         let http_server_addr: Addr<_> = HttpServer::new(move || {
             let self_addr = self_addr.clone();
-            App::with_state(WebsocketSharedState::new(self_addr)).resource("/", move |r| {
+            App::with_state(WebsocketSharedState::new(
+                self_addr,
+                db.clone(),
+                started_at,
+                counters.clone(),
+            ))
+            .resource("/health", |r| r.get().f(health_check))
+            .resource("/metrics", |r| r.get().f(metrics_scrape))
+            .resource("/", move |r| {
                 r.route().f(move |req| ws::start(req, WebSocketServer))
             })
         })
-        .bind(format!("0.0.0.0:{}", port))?
+        .bind(addr)?
         .start();
 
         Ok(http_server_addr)
@@ -214,6 +430,7 @@ impl Handler<SendPayload> for WebSocketServer {
 
 impl StreamHandler<ws::Message, ws::ProtocolError> for WebSocketServer {
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        server::idle::record_activity();
         match msg {
             ws::Message::Text(text) => {
                 // Attempt to decode the text as a `Request` instance:
@@ -241,3 +458,177 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WebSocketServer {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use actix_web::test::TestRequest;
+
+    use crate::ps::agent::database::{PageRecord, UploadRecord};
+    use crate::ps::util;
+
+    fn metric(body: &str, name: &str) -> i64 {
+        body.lines()
+            .find(|line| line.starts_with(&format!("{} ", name)))
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| panic!("metric not found: {}", name))
+    }
+
+    #[test]
+    fn counters_do_not_decrease() {
+        let db = util::database::temp().unwrap();
+        let counters = Mutex::new(UploadCounters::new(
+            time::now().to_timespec() - time::Duration::seconds(1),
+        ));
+
+        let mut record = UploadRecord::new(
+            "test.txt",
+            "N:dataset:1",
+            None::<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.status = UploadStatus::Failed;
+        record.progress = 50;
+        let id = db.insert_upload(&record).unwrap();
+
+        let body = metrics(&db, &counters).unwrap();
+        assert_eq!(metric(&body, "pennsieve_uploads_failed_total"), 1);
+        assert_eq!(metric(&body, "pennsieve_uploads_active"), 0);
+
+        // Resuming the upload makes it active, but it has still failed once:
+        assert!(db.resume_failed_upload(&id.to_string()).unwrap());
+        let body = metrics(&db, &counters).unwrap();
+        assert_eq!(metric(&body, "pennsieve_uploads_failed_total"), 1);
+        assert_eq!(metric(&body, "pennsieve_uploads_active"), 1);
+    }
+
+    #[test]
+    fn uploads_finished_before_the_agent_started_are_not_counted() {
+        let db = util::database::temp().unwrap();
+        let started_at = time::now().to_timespec();
+        let counters = Mutex::new(UploadCounters::new(started_at));
+
+        let mut record = UploadRecord::new(
+            "test.txt",
+            "N:dataset:1",
+            None::<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.status = UploadStatus::Completed;
+        record.updated_at = started_at - time::Duration::hours(1);
+        db.insert_upload(&record).unwrap();
+
+        let body = metrics(&db, &counters).unwrap();
+        assert_eq!(metric(&body, "pennsieve_uploads_completed_total"), 0);
+
+        record.updated_at = time::now().to_timespec();
+        db.insert_upload(&record).unwrap();
+        let body = metrics(&db, &counters).unwrap();
+        assert_eq!(metric(&body, "pennsieve_uploads_completed_total"), 1);
+
+        // a later scrape doesn't count it again:
+        let body = metrics(&db, &counters).unwrap();
+        assert_eq!(metric(&body, "pennsieve_uploads_completed_total"), 1);
+    }
+
+    #[test]
+    fn stalled_workers_are_reported_by_the_health_check() {
+        let workers = vec![
+            server::heartbeat::WorkerHeartbeat {
+                worker: "CacheCollector".to_string(),
+                last_heartbeat_seconds: 10,
+                stalled: false,
+            },
+            server::heartbeat::WorkerHeartbeat {
+                worker: "Uploader".to_string(),
+                last_heartbeat_seconds: 900,
+                stalled: true,
+            },
+        ];
+
+        let body = health(None, Duration::from_secs(1000), workers).unwrap();
+        assert_eq!(body["status"], "stalled");
+        assert_eq!(body["workers"][1]["worker"], "Uploader");
+        assert_eq!(body["workers"][1]["last_heartbeat_seconds"], 900);
+        assert_eq!(body["workers"][1]["stalled"], true);
+
+        let body = health(None, Duration::from_secs(1000), vec![]).unwrap();
+        assert_eq!(body["status"], "ok");
+    }
+
+    #[test]
+    fn the_health_check_reports_the_cache_size_in_bytes() {
+        let db = util::database::temp().unwrap();
+        db.upsert_page(&PageRecord::new("c1.100.1", false, true, 800))
+            .unwrap();
+        db.upsert_page(&PageRecord::new("c1.100.2", false, true, 312))
+            .unwrap();
+        db.write_nan_filled("c1.100.3", true).unwrap();
+
+        let body = health(Some(&db), Duration::from_secs(1), vec![]).unwrap();
+        assert_eq!(body["cache_size_bytes"], 1112);
+        assert_eq!(body["active_uploads"], 0);
+    }
+
+    #[test]
+    fn monitoring_requests_do_not_keep_an_idle_agent_running() {
+        let db = util::database::temp().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        thread::spawn(move || {
+            let started_at = Instant::now();
+            let polling = Arc::clone(&stopped);
+            System::run(move || {
+                server::idle::install_idle_shutdown(
+                    System::current(),
+                    db.clone(),
+                    Duration::from_millis(500),
+                );
+
+                let status_addr = System::current().registry().get::<StatusServer>();
+                let state = move || {
+                    WebsocketSharedState::new(
+                        status_addr.clone(),
+                        Some(db.clone()),
+                        Instant::now(),
+                        Arc::new(Mutex::new(UploadCounters::new(time::now().to_timespec()))),
+                    )
+                };
+                // A liveness probe and a scraper, both polling more often
+                // than the idle timeout:
+                thread::spawn(move || {
+                    while !polling.load(Ordering::SeqCst) {
+                        let response = TestRequest::with_state(state()).run(&health_check);
+                        assert_eq!(response.unwrap().status(), http::StatusCode::OK);
+                        let response = TestRequest::with_state(state()).run(&metrics_scrape);
+                        assert_eq!(response.unwrap().status(), http::StatusCode::OK);
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                });
+            });
+            stopped.store(true, Ordering::SeqCst);
+            sender.send(started_at.elapsed()).unwrap();
+        });
+
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("monitoring requests kept the idle agent running");
+    }
+}
@@ -9,6 +9,7 @@ use futures::prelude::*;
 use futures::sync::oneshot;
 use futures::{future as f, stream as st, Future as _Future};
 use log::*;
+use protobuf::repeated::RepeatedField;
 use protobuf::{self, Message};
 use serde_derive::{Deserialize, Serialize};
 use serde_json;
@@ -27,7 +28,10 @@ use crate::ps::agent::messages::{Response, ServerStartup};
 use crate::ps::agent::server;
 use crate::ps::agent::types::{HostName, Server, ServiceId, TxStop, WithProps};
 use crate::ps::agent::{self, cache, Future};
-use crate::ps::proto::timeseries::{AgentTimeSeriesResponse, StateMessage, TimeSeriesMessage};
+use crate::ps::proto;
+use crate::ps::proto::timeseries::{
+    AgentTimeSeriesResponse, ChannelChunk, ChunkResponse, Segment, StateMessage, TimeSeriesMessage,
+};
 use crate::ps::util::actor as a;
 use crate::ps::util::futures::*;
 
@@ -133,6 +137,83 @@ fn status_done() -> WsMessage {
     WsMessage::Binary(state_message_bytes(DONE, None as Option<&str>))
 }
 
+/// Encodes `chunk` as a message to send to the client.
+fn chunk_bytes(chunk: ChunkResponse) -> Result<Vec<u8>> {
+    let mut response = AgentTimeSeriesResponse::new();
+    response.set_chunk(chunk);
+    response.write_to_bytes().map_err(Into::into)
+}
+
+/// Splits `chunk` in two, the first part holding its first `n` samples.
+fn split_chunk(mut chunk: ChunkResponse, n: usize) -> (ChunkResponse, ChunkResponse) {
+    let (mut first, mut second): (Vec<ChannelChunk>, Vec<ChannelChunk>) = (vec![], vec![]);
+    let mut remaining = n;
+
+    for mut channel in chunk.take_channels().into_vec() {
+        if remaining >= channel.data.len() {
+            remaining -= channel.data.len();
+            first.push(channel);
+        } else if remaining == 0 {
+            second.push(channel);
+        } else {
+            let mut data = channel.take_data().into_vec();
+            let rest = data.split_off(remaining);
+            remaining = 0;
+            first.push(proto::create_channel_chunk(channel.id.clone(), data));
+            second.push(proto::create_channel_chunk(channel.id, rest));
+        }
+    }
+
+    let chunk_of = |channels: Vec<ChannelChunk>| {
+        let mut chunk = ChunkResponse::new();
+        chunk.set_channels(RepeatedField::from_vec(channels));
+        chunk
+    };
+    (chunk_of(first), chunk_of(second))
+}
+
+/// Encodes `chunk` as frames of at most `max_bytes` bytes each, halving it
+/// until every part fits. A part holding a single sample is never split,
+/// so it may still exceed `max_bytes`.
+fn frame_chunk(chunk: ChunkResponse, max_bytes: usize, frames: &mut Vec<Vec<u8>>) -> Result<()> {
+    let samples: usize = chunk
+        .channels
+        .iter()
+        .map(|channel| channel.data.len())
+        .sum();
+    if samples <= 1 {
+        frames.push(chunk_bytes(chunk)?);
+        return Ok(());
+    }
+
+    let bytes = chunk_bytes(chunk.clone())?;
+    if bytes.len() <= max_bytes {
+        frames.push(bytes);
+        return Ok(());
+    }
+
+    let (first, second) = split_chunk(chunk, samples / 2);
+    frame_chunk(first, max_bytes, frames)?;
+    frame_chunk(second, max_bytes, frames)
+}
+
+/// Splits an encoded chunk into the frames sent to the client, so a single
+/// frame never exceeds `max_bytes`, whatever chunk size the client asked
+/// for. Each frame is a chunk of its own, holding the next samples of
+/// every channel in order, so appending the channel data of the frames
+/// reassembles the original chunk. Without a cap, the chunk is sent as is.
+fn into_frames(bytes: Vec<u8>, max_bytes: Option<usize>) -> Result<Vec<Vec<u8>>> {
+    match max_bytes {
+        Some(max_bytes) if bytes.len() > max_bytes => {
+            let mut response: AgentTimeSeriesResponse = protobuf::parse_from_bytes(&bytes)?;
+            let mut frames = Vec::new();
+            frame_chunk(response.take_chunk(), max_bytes, &mut frames)?;
+            Ok(frames)
+        }
+        _ => Ok(vec![bytes]),
+    }
+}
+
 // All messages with a matching (source, start-time, end-time) tuple will
 // hash to the same bucket and be considered part of the same message group:
 type MessageGroupKey = (String, u64, u64);
@@ -426,6 +507,86 @@ impl From<AgentRequest> for cache::Request {
         )
     }
 }
+
+/// Fetches pages of a package directly from the Pennsieve streaming
+/// timeseries server, returning every segment it sends back. This is used
+/// to fill the cache without a websocket client, as `cache prefetch` does.
+pub fn fetch_segments(
+    hostname: HostName,
+    port: u16,
+    session: String,
+    package_id: String,
+    pages: Vec<cache::PageRequest>,
+) -> Future<Vec<Segment>> {
+    if pages.is_empty() {
+        return f::ok(vec![]).into_trait();
+    }
+
+    let path = format!("ts/retrieve?session={}&package={}", session, package_id);
+    let url = try_future!(remote_url(hostname, port, &path));
+    let requests = try_future!(pages
+        .iter()
+        .map(|page| {
+            serde_json::to_string(&into_api_request(&session, &package_id, page))
+                .map(WsMessage::Text)
+        })
+        .collect::<serde_json::Result<Vec<_>>>());
+
+    // Collects the messages received for every chunk of requests:
+    let (tx_messages, rx_messages) = futures::sync::mpsc::unbounded::<WsMessage>();
+
+    connect_async(url)
+        .map_err(Into::<Error>::into)
+        .and_then(move |(ts_ws_stream, _headers)| {
+            let (ts_sink, ts_stream) = ts_ws_stream.split();
+            let ts_stream = TimeSeriesStream::new(to_stream_trait(ts_stream), 0);
+            let stream_state = ts_stream.state();
+
+            st::iter_ok::<_, Error>(requests)
+                .chunks(CONCURRENT_REQUEST_CHUNK_LIMIT)
+                .fold(
+                    (ts_sink, ts_stream, tx_messages),
+                    move |(ts_sink, ts_stream, tx_messages), reqs| {
+                        stream_state.lock().unwrap().reset(reqs.len());
+                        ts_sink
+                            .send_all(st::iter_ok::<_, tungstenite::Error>(reqs))
+                            .map_err(Into::<Error>::into)
+                            .and_then(move |(ts_sink, _)| {
+                                tx_messages
+                                    .clone()
+                                    .sink_map_err(Into::<Error>::into)
+                                    .send_all(ts_stream)
+                                    .map(move |(_, ts_stream)| (ts_sink, ts_stream, tx_messages))
+                            })
+                    },
+                )
+        })
+        .and_then(move |(_, _, tx_messages)| {
+            // Close the channel so the collected messages can be read:
+            drop(tx_messages);
+            rx_messages
+                .map_err(|_| Error::io_error("error collecting timeseries messages"))
+                .collect()
+        })
+        .and_then(|messages| {
+            messages
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    WsMessage::Binary(data) => Some(into_timeseries(&data)),
+                    _ => None,
+                })
+                .map(|ts| {
+                    ts.and_then(|ts| {
+                        ts.segment
+                            .into_option()
+                            .ok_or_else(|| ErrorKind::EmptyMessage.into())
+                    })
+                })
+                .collect::<Result<Vec<Segment>>>()
+        })
+        .map_err(Into::<agent::Error>::into)
+        .into_trait()
+}
 // ============================================================================
 
 /// Commands sent to the the time series server from a client (Python, R, etc.)
@@ -589,6 +750,8 @@ pub struct Props {
     pub port: u16,
     pub config: cache::Config,
     pub db: Database,
+    /// The largest frame sent to clients, in bytes, if capped.
+    pub max_frame_bytes: Option<usize>,
 }
 
 impl Actor for TimeSeriesServer {
@@ -643,6 +806,7 @@ impl TimeSeriesServer {
         let port: u16 = props.port;
         let config = props.config;
         let db = props.db;
+        let max_frame_bytes = props.max_frame_bytes;
         let page_creator = cache::PageCreator::new();
 
         listener
@@ -746,33 +910,36 @@ impl TimeSeriesServer {
                                         let database = Arc::new(state.get_db().clone());
 
                                         // For each message received through `rx_streaming_server`,
-                                        // cache the time series message data to disk,
-                                        // while running the code in a separate event loop task:
+                                        // cache the time series message data to disk as it arrives,
+                                        // writing several segments at once on a thread pool:
+                                        let cached_response = Arc::clone(&response);
+                                        let parallelism = state.get_config().parallelism();
+                                        let writer = response.lock().unwrap().segment_writer(&page_creator, parallelism);
                                         let cache_messages = rx_streaming_server
                                             .map_err(|_| Error::io_error("error streaming timeseries message"))
-                                            .fold(Arc::clone(&response), move |res, msg: WsMessage| -> Result<Arc<Mutex<cache::Response>>>  {
+                                            .and_then(|msg: WsMessage| -> Result<Segment> {
                                                 if let WsMessage::Binary(data) = msg {
-                                                    match into_timeseries(&data) {
-                                                        Ok(ts) => {
-                                                            if let Some(segment) = ts.segment.into_option() {
-                                                                if let Err(e) = res.lock().unwrap().cache_response(&page_creator, &segment) {
-                                                                    Err(e.into())
-                                                                } else {
-                                                                    Ok(Arc::clone(&res))
-                                                                }
-                                                            } else {
-                                                                Err(ErrorKind::EmptyMessage.into())
-                                                            }
-                                                       },
-                                                        Err(e) => Err(e)
-                                                    }
+                                                    into_timeseries(&data).and_then(|ts| {
+                                                        ts.segment
+                                                            .into_option()
+                                                            .ok_or_else(|| ErrorKind::EmptyMessage.into())
+                                                    })
                                                 } else {
                                                     Err(Error::invalid_message_type("non-binary"))
                                                 }
                                             })
-                                            .and_then(|_| {
+                                            .map(move |segment| writer.write(segment).map_err(Into::<Error>::into))
+                                            .buffer_unordered(parallelism)
+                                            .fold(cache::CacheProgress::default(), |progress, written| -> Result<cache::CacheProgress> {
+                                                Ok(progress.merge(written))
+                                            })
+                                            .and_then(move |progress| -> Result<()> {
                                                 info!("all messages received");
-                                                Ok(())
+                                                cached_response
+                                                    .lock()
+                                                    .unwrap()
+                                                    .record_progress(progress)
+                                                    .map_err(Into::into)
                                             });
 
                                         let streaming_api_url_copy = streaming_api_url.clone();
@@ -912,9 +1079,14 @@ impl TimeSeriesServer {
                                         let (mut state2, maybe_chunk_iter) = state.split();
                                         if let Some(mut it) = maybe_chunk_iter {
                                             if let Some(chunk_bytes) = it.next() {
-                                                if let Ok(bytes) = chunk_bytes {
-                                                    debug!("sending message <NEXT::OK>");
-                                                    state2.send_message(WsMessage::Binary(bytes));
+                                                let frames = chunk_bytes
+                                                    .map_err(Into::<Error>::into)
+                                                    .and_then(|bytes| into_frames(bytes, max_frame_bytes));
+                                                if let Ok(frames) = frames {
+                                                    debug!("sending message <NEXT::OK> in {} frame(s)", frames.len());
+                                                    for frame in frames {
+                                                        state2.send_message(WsMessage::Binary(frame));
+                                                    }
                                                 } else {
                                                     error!("sending message <NEXT::ERR>");
                                                     // TODO change malformed here to something more informative
@@ -981,3 +1153,60 @@ impl TimeSeriesServer {
             .into_trait()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The samples of every channel in `frames`, appended in order.
+    fn reassemble(frames: &[Vec<u8>]) -> Vec<(String, Vec<(u64, f64)>)> {
+        let mut channels: Vec<(String, Vec<(u64, f64)>)> = vec![];
+        for frame in frames {
+            let mut response: AgentTimeSeriesResponse = protobuf::parse_from_bytes(frame).unwrap();
+            for channel in response.take_chunk().take_channels().into_vec() {
+                let samples = channel.data.iter().map(|datum| (datum.time, datum.value));
+                match channels.iter_mut().find(|(id, _)| *id == channel.id) {
+                    Some((_, data)) => data.extend(samples),
+                    None => channels.push((channel.id.clone(), samples.collect())),
+                }
+            }
+        }
+        channels
+    }
+
+    fn large_chunk() -> Vec<u8> {
+        let mut chunk = ChunkResponse::new();
+        for id in &["c1", "c2"] {
+            chunk.channels.push(proto::create_channel_chunk(
+                id.to_string(),
+                (0..1000)
+                    .map(|t| proto::create_datum(1_000_000 + t, t as f64 / 2.0))
+                    .collect(),
+            ));
+        }
+        chunk_bytes(chunk).unwrap()
+    }
+
+    #[test]
+    fn large_chunks_are_split_into_frames_that_reassemble_the_chunk() {
+        let bytes = large_chunk();
+        let frames = into_frames(bytes.clone(), Some(1024)).unwrap();
+
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|frame| frame.len() <= 1024));
+        assert_eq!(reassemble(&frames), reassemble(&[bytes]));
+    }
+
+    #[test]
+    fn chunks_within_the_cap_are_sent_as_is() {
+        let bytes = large_chunk();
+        assert_eq!(
+            into_frames(bytes.clone(), None).unwrap(),
+            vec![bytes.clone()]
+        );
+        assert_eq!(
+            into_frames(bytes.clone(), Some(bytes.len())).unwrap(),
+            vec![bytes]
+        );
+    }
+}
@@ -16,7 +16,7 @@ use futures::future::Future as _Future;
 use futures::sync::oneshot;
 use futures::{self, future};
 
-pub use crate::ps::agent::error::{Error, ErrorKind, Result};
+pub use crate::ps::agent::error::{Error, ErrorKind, ExitCode, Result};
 use crate::ps::agent::messages::{ServerStartup, WorkerStartup};
 
 pub type Future<T> = Box<dyn _Future<Item = T, Error = Error> + Send>;
@@ -129,7 +129,7 @@ impl ToString for ServiceId {
 pub enum OutputFormat {
     Simple, // Simple, uncolorized newline separated text
     Rich,   // The default (colorized, terminal library supported IO)
-            //Json, // JSON formatted
+    Json,   // JSON formatted, by the commands listed in the help of --output
 }
 
 impl OutputFormat {
@@ -144,6 +144,11 @@ impl OutputFormat {
     pub fn is_rich(self) -> bool {
         self == OutputFormat::Rich
     }
+
+    /// Tests if the output format is "json".
+    pub fn is_json(self) -> bool {
+        self == OutputFormat::Json
+    }
 }
 
 impl Default for OutputFormat {
@@ -164,8 +169,9 @@ impl FromStr for OutputFormat {
 
     fn from_str(format: &str) -> result::Result<Self, Self::Err> {
         match format.to_lowercase().as_ref() {
-            "rich" => Ok(OutputFormat::Rich),
+            "rich" | "table" => Ok(OutputFormat::Rich),
             "simple" => Ok(OutputFormat::Simple),
+            "json" => Ok(OutputFormat::Json),
             _ => Err(Error::output_format(format)),
         }
     }
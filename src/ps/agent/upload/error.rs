@@ -38,10 +38,30 @@ impl Error {
         .into()
     }
 
+    pub fn invalid_tag<S: Into<String>>(tag: S) -> Error {
+        ErrorKind::InvalidTag { tag: tag.into() }.into()
+    }
+
     pub fn no_parent<S: Into<String>>(path: S) -> Error {
         ErrorKind::NoParent { path: path.into() }.into()
     }
 
+    pub fn chunk_size_too_small(chunk_size: u64, file_size: u64, min_chunk_size: u64) -> Error {
+        ErrorKind::ChunkSizeTooSmall {
+            chunk_size,
+            file_size,
+            min_chunk_size,
+        }
+        .into()
+    }
+
+    pub fn drained<S: Into<String>>(import_id: S) -> Error {
+        ErrorKind::Drained {
+            import_id: import_id.into(),
+        }
+        .into()
+    }
+
     pub fn upload_failed(cause: pennsieve_rust::Error) -> Error {
         ErrorKind::UploadFailed {
             message: cause.to_string(),
@@ -83,6 +103,21 @@ pub enum ErrorKind {
     #[fail(display = "No file(s) given to upload")]
     NoFilesToUpload,
 
+    #[fail(
+        display = "All files have already been appended to this package. Use --force-reappend to append them again"
+    )]
+    AlreadyAppended,
+
+    #[fail(
+        display = "All files already exist in the dataset. Leave out --skip-existing to upload them again"
+    )]
+    AlreadyUploaded,
+
+    #[fail(
+        display = "All files are already queued for upload to this dataset. Use --force to queue them again"
+    )]
+    AlreadyQueued,
+
     #[fail(
         display = "When using multiple paths, all paths must be files. A directory was provided: {:?}",
         directory
@@ -92,6 +127,9 @@ pub enum ErrorKind {
     #[fail(display = "Invalid path: {}", message)]
     InvalidPath { message: String },
 
+    #[fail(display = "Invalid tag, expected key=value: {:?}", tag)]
+    InvalidTag { tag: String },
+
     #[fail(display = "Cancelled")]
     UserCancelledError,
 
@@ -101,6 +139,22 @@ pub enum ErrorKind {
     #[fail(display = "Upload failed: {}", message)]
     UploadFailed { message: String },
 
+    #[fail(
+        display = "A chunk size of {} bytes splits a file of {} bytes into too many parts; use a --chunk-size of at least {} bytes",
+        chunk_size, file_size, min_chunk_size
+    )]
+    ChunkSizeTooSmall {
+        chunk_size: u64,
+        file_size: u64,
+        min_chunk_size: u64,
+    },
+
+    #[fail(
+        display = "Upload of {} stopped by a shutdown; it resumes when the agent next runs",
+        import_id
+    )]
+    Drained { import_id: String },
+
     #[fail(display = "{}", kind)]
     Pennsieve { kind: pennsieve_rust::ErrorKind },
 
@@ -0,0 +1,158 @@
+//! Upload manifests: files listing the paths to queue for upload.
+//!
+//! A manifest contains one path per line, optionally followed by a comma
+//! and the ID or name of the folder the file should be uploaded to:
+//!
+//! ```text
+//! # recordings from the first session
+//! /data/session-1/recording.edf
+//! /data/session-1/notes.txt,Session 1
+//! ```
+//!
+//! Lines beginning with `#` are comments, and blank lines are skipped.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ps::agent::upload::{Error, Result};
+
+/// A single file listed in a manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub folder: Option<String>,
+}
+
+/// The contents of a manifest, split into the files that exist and can be
+/// queued, and those that are missing and will be skipped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+    missing: Vec<PathBuf>,
+}
+
+impl Manifest {
+    /// Reads and parses the manifest at the given path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// Parses the contents of a manifest, checking that each listed file
+    /// exists.
+    pub fn parse(contents: &str) -> Self {
+        let mut manifest = Self::default();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut columns = line.splitn(2, ',').map(str::trim);
+            let path = PathBuf::from(columns.next().unwrap_or(""));
+            let folder = columns
+                .next()
+                .filter(|folder| !folder.is_empty())
+                .map(String::from);
+
+            if path.exists() {
+                manifest.entries.push(ManifestEntry { path, folder });
+            } else {
+                manifest.missing.push(path);
+            }
+        }
+
+        manifest
+    }
+
+    /// The files listed in the manifest that exist.
+    pub fn entries(&self) -> &Vec<ManifestEntry> {
+        &self.entries
+    }
+
+    /// The files listed in the manifest that could not be found.
+    pub fn missing(&self) -> &Vec<PathBuf> {
+        &self.missing
+    }
+
+    /// Groups the existing files by their target folder, so each group can
+    /// be queued in a single request. Files without a folder are grouped
+    /// under `None`.
+    pub fn group_by_folder(&self) -> Result<BTreeMap<Option<String>, Vec<String>>> {
+        let mut groups: BTreeMap<Option<String>, Vec<String>> = BTreeMap::new();
+        for entry in &self.entries {
+            let path = entry
+                .path
+                .to_str()
+                .ok_or_else(|| Error::invalid_path(format!("{:?}", entry.path)))?;
+            groups
+                .entry(entry.folder.clone())
+                .or_insert_with(Vec::new)
+                .push(path.to_string());
+        }
+        Ok(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pennsieve_macros::{path, test_resources_path};
+
+    use crate::ps::util;
+
+    fn resource(name: &str) -> String {
+        test_resources_path!(name)
+            .canonicalize()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn manifest_skips_comments_blank_lines_and_missing_files() {
+        let first = resource("upload_test/recursive/8.txt");
+        let second = resource("upload_test/1.txt");
+        let manifest = Manifest::parse(&format!(
+            "# a comment\n\n{}\n  # an indented comment\n/does/not/exist.txt\n{}, My Folder\n",
+            first, second
+        ));
+
+        assert_eq!(
+            manifest.entries(),
+            &vec![
+                ManifestEntry {
+                    path: PathBuf::from(&first),
+                    folder: None,
+                },
+                ManifestEntry {
+                    path: PathBuf::from(&second),
+                    folder: Some("My Folder".to_string()),
+                },
+            ]
+        );
+        assert_eq!(
+            manifest.missing(),
+            &vec![PathBuf::from("/does/not/exist.txt")]
+        );
+
+        let groups = manifest.group_by_folder().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&None], vec![first]);
+        assert_eq!(groups[&Some("My Folder".to_string())], vec![second]);
+    }
+
+    #[test]
+    fn manifest_is_read_from_a_file() {
+        let listed = resource("upload_test/recursive/8.txt");
+        let path = util::path::temp("manifest", ".txt").unwrap();
+        fs::write(&path, format!("{}\n# {}\n", listed, listed)).unwrap();
+
+        let manifest = Manifest::from_path(&path).unwrap();
+        assert_eq!(manifest.entries().len(), 1);
+        assert!(manifest.missing().is_empty());
+    }
+}
@@ -1,11 +1,14 @@
 //! This module contains functionality specific to file uploading.
 
 mod error;
+pub mod manifest;
+mod session;
+pub mod stdin;
+mod upload_log;
 pub mod worker;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
-#[cfg(windows)]
 use std::fs;
 #[cfg(windows)]
 use std::os::windows::prelude::*;
@@ -22,11 +25,16 @@ use pennsieve_rust::model::{PackagePreview, UploadId};
 
 use crate::ps::agent::cli::input::confirm;
 use crate::ps::agent::config::constants::{
-    PREVIEW_DISPLAY_MAX_FILES, PREVIEW_DISPLAY_MAX_PACKAGES,
+    PREVIEW_DISPLAY_MAX_FILES, PREVIEW_DISPLAY_MAX_PACKAGES, UPLOAD_MAX_CHUNK_COUNT,
+    UPLOAD_MAX_CHUNK_SIZE, UPLOAD_MIN_CHUNK_SIZE,
 };
+use crate::ps::agent::database::Database;
 
 pub use self::error::{Error, ErrorKind, Result};
-pub use self::worker::{Props, Uploader};
+pub use self::manifest::{Manifest, ManifestEntry};
+pub use self::session::Session;
+pub use self::upload_log::UploadLog;
+pub use self::worker::{NoopProgressSink, Parallelism, ProgressSink, Props, Uploader};
 
 /// A wrapper around `response::UploadPreview`.
 pub struct UploadPreview(response::UploadPreview);
@@ -143,6 +151,8 @@ pub struct PreviewFiles {
     // respective upload ids
     #[allow(dead_code)]
     file_paths: Vec<(UploadId, PathBuf)>,
+    // Symbolic links that were found but not followed
+    skipped_links: Vec<PathBuf>,
 }
 
 /// An entry returned by the file preview iterator.
@@ -216,7 +226,18 @@ impl PreviewFiles {
                 }
             }
         }
-        Ok(Self { path, file_paths })
+        Ok(Self {
+            path,
+            file_paths,
+            skipped_links: vec![],
+        })
+    }
+
+    fn with_skipped_links(self, skipped_links: Vec<PathBuf>) -> Self {
+        Self {
+            skipped_links,
+            ..self
+        }
     }
 
     // https://blog.guillaume-gomez.fr/articles/2017-03-09+Little+tour+of+multiple+iterators+implementation+in+Rust
@@ -237,6 +258,352 @@ impl PreviewFiles {
     pub fn file_paths(&self) -> &Vec<(UploadId, PathBuf)> {
         &self.file_paths
     }
+
+    /// Returns the symbolic links that were skipped while generating the
+    /// preview.
+    pub fn skipped_links(&self) -> &Vec<PathBuf> {
+        &self.skipped_links
+    }
+
+    /// Removes the empty files from the preview, as requested with
+    /// `--skip-empty`, returning the remaining preview along with the
+    /// paths of the files that were removed.
+    pub fn without_empty_files(self) -> Result<(Self, Vec<PathBuf>)> {
+        let (skipped, remaining): (Vec<_>, Vec<_>) = self
+            .file_paths
+            .into_iter()
+            .partition(|(_, file_path)| is_empty_file(file_path));
+
+        if remaining.is_empty() {
+            return Err(ErrorKind::NoFilesToUpload.into());
+        }
+
+        let skipped = skipped
+            .into_iter()
+            .map(|(_, file_path)| file_path)
+            .collect();
+        let skipped_links = self.skipped_links;
+        PreviewFiles::new(self.path, remaining)
+            .map(|preview| (preview.with_skipped_links(skipped_links), skipped))
+    }
+}
+
+/// Tests if `file` is an existing file with no content.
+pub fn is_empty_file<P: AsRef<Path>>(file: P) -> bool {
+    fs::metadata(file)
+        .map(|metadata| metadata.is_file() && metadata.len() == 0)
+        .unwrap_or(false)
+}
+
+/// Picks the chunk size for a multipart upload of a file of `file_size`
+/// bytes. Files start out with the smallest chunk S3 accepts, which is
+/// doubled until the file fits in the number of parts S3 allows, up to
+/// `UPLOAD_MAX_CHUNK_SIZE`.
+pub fn auto_chunk_size(file_size: u64) -> u64 {
+    let mut chunk_size = UPLOAD_MIN_CHUNK_SIZE;
+    while chunk_size < UPLOAD_MAX_CHUNK_SIZE
+        && (file_size + chunk_size - 1) / chunk_size > UPLOAD_MAX_CHUNK_COUNT
+    {
+        chunk_size *= 2;
+    }
+    chunk_size.min(UPLOAD_MAX_CHUNK_SIZE)
+}
+
+/// Gets the chunk size to upload a file of `file_size` bytes with: the
+/// `requested` size given with `--chunk-size`, if any, or else one picked
+/// by `auto_chunk_size`. A requested size that would split the file into
+/// more parts than S3 allows is refused.
+pub fn choose_chunk_size(file_size: u64, requested: Option<u64>) -> Result<u64> {
+    match requested {
+        Some(chunk_size) => {
+            // The smallest chunk that fits the file in the parts S3 allows:
+            let min_chunk_size = file_size / UPLOAD_MAX_CHUNK_COUNT
+                + if file_size % UPLOAD_MAX_CHUNK_COUNT == 0 {
+                    0
+                } else {
+                    1
+                };
+            if chunk_size < min_chunk_size {
+                Err(Error::chunk_size_too_small(
+                    chunk_size,
+                    file_size,
+                    min_chunk_size,
+                ))
+            } else {
+                Ok(chunk_size)
+            }
+        }
+        None => Ok(auto_chunk_size(file_size)),
+    }
+}
+
+/// Parses a tag given with `upload --tag` into its key and value. Tags
+/// are written `key=value`; the key can't be empty, and only the first
+/// `=` separates the two, so values may contain `=` themselves.
+pub fn parse_tag(tag: &str) -> Result<(String, String)> {
+    let mut parts = tag.splitn(2, '=');
+    match (parts.next().map(str::trim), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => {
+            Ok((key.to_string(), value.trim().to_string()))
+        }
+        _ => Err(Error::invalid_tag(tag)),
+    }
+}
+
+/// Parses every tag given with `upload --tag`. When a key is given more
+/// than once, the last value given wins.
+pub fn parse_tags<I, S>(tags: I) -> Result<BTreeMap<String, String>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    tags.into_iter()
+        .map(|tag| parse_tag(tag.as_ref()))
+        .collect()
+}
+
+/// The files an upload would queue, as reported by `upload --dry-run`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct UploadSummary {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl fmt::Display for UploadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{files} {thing}, {size} in total",
+            files = self.files,
+            thing = if self.files == 1 { "file" } else { "files" },
+            size = human_bytes(self.bytes as f64)
+        )
+    }
+}
+
+impl PreviewFiles {
+    /// Counts the files in the preview and adds up their sizes.
+    pub fn summary(&self) -> Result<UploadSummary> {
+        self.file_paths
+            .iter()
+            .map(|(_, path)| fs::metadata(path).map(|metadata| metadata.len()))
+            .collect::<std::io::Result<Vec<u64>>>()
+            .map(|sizes| UploadSummary {
+                files: sizes.len(),
+                bytes: sizes.iter().sum(),
+            })
+            .map_err(Into::into)
+    }
+}
+
+/// Resolves the files an upload of `files` would queue, as with
+/// `generate_file_preview`, and summarizes them without queueing anything.
+pub fn summarize_upload<P>(
+    files: Vec<P>,
+    recursive: bool,
+    follow_symlinks: bool,
+    skip_empty: bool,
+) -> Result<UploadSummary>
+where
+    P: AsRef<Path>,
+{
+    let preview = generate_file_preview(files, recursive, follow_symlinks)?;
+    if skip_empty {
+        preview.without_empty_files()?.0.summary()
+    } else {
+        preview.summary()
+    }
+}
+
+/// Splits `root` up into the datasets of an `upload --dataset-from-path`:
+/// each directory directly beneath `root` is uploaded to the dataset it is
+/// named after. Returns the name of each dataset along with its directory,
+/// ordered by name, and the files directly beneath `root`, which belong to
+/// no dataset and are skipped.
+pub fn datasets_from_path<P>(root: P) -> Result<(Vec<(String, PathBuf)>, Vec<PathBuf>)>
+where
+    P: AsRef<Path>,
+{
+    let root = root.as_ref();
+    if !root.is_dir() {
+        return Err(Error::invalid_path(format!(
+            "--dataset-from-path needs a directory of datasets: {:?}",
+            root
+        )));
+    }
+
+    let mut datasets = vec![];
+    let mut skipped = vec![];
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if is_hidden_file(&path) {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(String::from);
+        match name {
+            Some(name) if path.is_dir() => datasets.push((name, path)),
+            _ => skipped.push(path),
+        }
+    }
+    if datasets.is_empty() {
+        return Err(Error::invalid_path(format!(
+            "no dataset directories found in {:?}",
+            root
+        )));
+    }
+    datasets.sort();
+    skipped.sort();
+
+    Ok((datasets, skipped))
+}
+
+/// A file, or files, left out of an upload while its files are queued.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Skipped {
+    /// A symbolic link found in a directory, which is not followed.
+    SymbolicLink(PathBuf),
+    /// An empty file, left out with `--skip-empty`.
+    Empty(PathBuf),
+    /// A file already appended to the package.
+    AlreadyAppended {
+        file_path: PathBuf,
+        package_id: String,
+    },
+    /// A file already queued or being uploaded to the dataset.
+    AlreadyQueued {
+        file_path: PathBuf,
+        dataset_id: String,
+    },
+    /// The files that already exist at the upload target, left out with
+    /// `--skip-existing`.
+    AlreadyExist(Vec<PathBuf>),
+}
+
+/// Removes files from `preview` that were already fully appended to the
+/// package `package_id`, according to the completed append records in the
+/// agent database. Returns the remaining files along with the paths of the
+/// files that were skipped.
+pub fn skip_appended_files(
+    db: &Database,
+    preview: PreviewFiles,
+    package_id: &str,
+) -> Result<(PreviewFiles, Vec<PathBuf>)> {
+    let appended = db.get_completed_appends(package_id)?;
+
+    let (skipped, remaining): (Vec<_>, Vec<_>) =
+        preview.file_paths.into_iter().partition(|(_, file_path)| {
+            file_path
+                .to_str()
+                .map(|path| appended.contains_file(path))
+                .unwrap_or(false)
+        });
+
+    if remaining.is_empty() && !skipped.is_empty() {
+        return Err(ErrorKind::AlreadyAppended.into());
+    }
+
+    let skipped = skipped
+        .into_iter()
+        .map(|(_, file_path)| file_path)
+        .collect();
+    PreviewFiles::new(preview.path, remaining).map(|preview| (preview, skipped))
+}
+
+/// Removes files from `preview` that are already queued or being uploaded
+/// to the dataset `dataset_id`, so running the same upload twice does not
+/// upload the files twice. Returns the remaining files along with the paths
+/// of the files that were skipped.
+pub fn skip_queued_files(
+    db: &Database,
+    preview: PreviewFiles,
+    dataset_id: &str,
+) -> Result<(PreviewFiles, Vec<PathBuf>)> {
+    let mut skipped = vec![];
+    let mut remaining = vec![];
+    for (upload_id, file_path) in preview.file_paths {
+        let queued = match file_path.to_str() {
+            Some(path) => db.has_active_upload_for(dataset_id, path)?,
+            None => false,
+        };
+        if queued {
+            skipped.push(file_path);
+        } else {
+            remaining.push((upload_id, file_path));
+        }
+    }
+
+    if remaining.is_empty() && !skipped.is_empty() {
+        return Err(ErrorKind::AlreadyQueued.into());
+    }
+
+    PreviewFiles::new(preview.path, remaining).map(|preview| (preview, skipped))
+}
+
+/// A file found at an upload target on the Pennsieve platform. For files
+/// this agent uploaded there before, the checksum the platform computed is
+/// known, along with the chunk size it was computed over.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExistingFile {
+    pub checksum: Option<(String, u64)>,
+}
+
+/// The files at an upload target, keyed by their path relative to it.
+pub type ExistingFiles = HashMap<String, ExistingFile>;
+
+/// The path `file_path` is given relative to the upload target: its path
+/// beneath the directory being uploaded, or just its name if files were
+/// given one by one. Components are separated by "/".
+pub fn target_path(base: Option<&Path>, file_path: &Path) -> String {
+    let relative = base
+        .and_then(|base| file_path.strip_prefix(base).ok())
+        .or_else(|| file_path.file_name().map(Path::new))
+        .unwrap_or(file_path);
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Removes the files that already exist at the upload target from the
+/// preview, as requested with `--skip-existing`, returning the remaining
+/// preview along with the paths of the files that were removed.
+///
+/// Files are matched by their path relative to the target. When the
+/// checksum of an existing file is known, the local file is only skipped
+/// if `hash`, given its path and the chunk size, computes the same one.
+pub fn skip_existing_files<H>(
+    preview: PreviewFiles,
+    existing: &ExistingFiles,
+    hash: H,
+) -> Result<(PreviewFiles, Vec<PathBuf>)>
+where
+    H: Fn(&Path, u64) -> Option<String>,
+{
+    let base = preview.path.clone();
+    let (skipped, remaining): (Vec<_>, Vec<_>) =
+        preview.file_paths.into_iter().partition(|(_, file_path)| {
+            match existing.get(&target_path(base.as_ref().map(AsRef::as_ref), file_path)) {
+                Some(ExistingFile {
+                    checksum: Some((checksum, chunk_size)),
+                }) => hash(file_path, *chunk_size).as_ref() == Some(checksum),
+                Some(_) => true,
+                None => false,
+            }
+        });
+
+    if remaining.is_empty() && !skipped.is_empty() {
+        return Err(ErrorKind::AlreadyUploaded.into());
+    }
+
+    let skipped = skipped
+        .into_iter()
+        .map(|(_, file_path)| file_path)
+        .collect();
+    PreviewFiles::new(base, remaining).map(|preview| (preview, skipped))
 }
 
 fn is_hidden_dot_file<P>(file: P) -> bool
@@ -282,8 +649,27 @@ where
     // ignore dotfiles as well
 }
 
+/// Whether a directory given to upload stands for its contents rather than
+/// the directory itself: when it ends with a path separator, as in `data/`,
+/// or is `.` or `..`.
+fn uploads_contents_of(path: &Path) -> bool {
+    let raw = path.as_os_str().to_string_lossy();
+    raw.ends_with('/') || raw.ends_with(std::path::MAIN_SEPARATOR) || path.file_name().is_none()
+}
+
 /// Generates a list of files to be uploaded to the Pennsieve platform.
-pub fn generate_file_preview<P>(files: Vec<P>, recursive: bool) -> Result<PreviewFiles>
+///
+/// A single directory is uploaded the way `rsync` copies one: `data/`
+/// uploads the contents of `data` into the target, while `data` creates a
+/// folder named `data` in the target and uploads the contents beneath it.
+///
+/// Symbolic links found in a directory are skipped, and listed by
+/// `PreviewFiles::skipped_links`, unless `follow_symlinks` is set.
+pub fn generate_file_preview<P>(
+    files: Vec<P>,
+    recursive: bool,
+    follow_symlinks: bool,
+) -> Result<PreviewFiles>
 where
     P: AsRef<Path>,
 {
@@ -312,31 +698,53 @@ where
 
         let buf = &path_bufs[0];
 
-        let walk_dir = WalkDir::new(buf).min_depth(1);
+        // Files are placed relative to the base path, so basing them on the
+        // parent of the directory uploads them beneath a folder named after
+        // it:
+        let base = if uploads_contents_of(files[0].as_ref()) {
+            buf.clone()
+        } else {
+            buf.parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| buf.clone())
+        };
+
+        let walk_dir = WalkDir::new(buf).min_depth(1).follow_links(follow_symlinks);
         let walk_dir = if recursive {
             walk_dir
         } else {
             walk_dir.max_depth(1)
         };
 
-        // WalkDir returns an iterator over results.
-        let file_paths: Vec<PathBuf> = walk_dir
-            .into_iter()
-            .map(|dir_entry_result| {
-                dir_entry_result
-                    .map_err(Into::<Error>::into)
-                    .and_then(|dir_entry| dir_entry.path().canonicalize().map_err(Into::into))
-            })
-            .filter(|path_result| {
-                path_result
-                    .as_ref()
-                    .map(|path| path.is_file())
-                    .unwrap_or(true)
-            })
-            .collect::<Result<Vec<PathBuf>>>()?
-            .into_iter()
-            .filter(|file| !is_hidden_file(file))
-            .collect();
+        // Symbolic links are skipped unless they are followed. Followed
+        // links that lead back to a directory that was already walked are
+        // skipped too, so circular links can't cause an endless walk:
+        let mut visited: HashSet<PathBuf> = HashSet::new();
+        visited.insert(buf.clone());
+        let mut skipped_links: Vec<PathBuf> = vec![];
+        let mut file_paths: Vec<PathBuf> = vec![];
+
+        let mut entries = walk_dir.into_iter();
+        while let Some(entry) = entries.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(ref e) if e.loop_ancestor().is_some() => {
+                    skipped_links.extend(e.path().map(Path::to_path_buf));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if entry.path_is_symlink() && !follow_symlinks {
+                skipped_links.push(entry.into_path());
+            } else if entry.file_type().is_dir() {
+                if !visited.insert(entry.path().canonicalize()?) {
+                    skipped_links.push(entry.into_path());
+                    entries.skip_current_dir();
+                }
+            } else if entry.file_type().is_file() && !is_hidden_file(entry.path()) {
+                file_paths.push(entry.into_path());
+            }
+        }
 
         // If we didn't match anything, it should probably be reported as an error:
         if file_paths.is_empty() {
@@ -348,7 +756,8 @@ where
             .enumerate()
             .map(|(id, path)| (UploadId::from(id as u64), path))
             .collect();
-        PreviewFiles::new(Some(buf.clone().into_boxed_path()), enumerated_file_paths)
+        PreviewFiles::new(Some(base.into_boxed_path()), enumerated_file_paths)
+            .map(|preview| preview.with_skipped_links(skipped_links))
     } else {
         for buf in &path_bufs {
             if buf.is_dir() {
@@ -369,29 +778,153 @@ where
 mod test {
     use super::*;
 
+    use std::fs;
+
     use pennsieve_macros::{path, src_path, test_resources_path};
+    use tempfile::{tempdir, TempDir};
+
+    use crate::ps::agent::database::{UploadRecord, UploadStatus};
+    use crate::ps::util;
 
     #[test]
-    fn bad_path_fails() {
-        assert!(
-            generate_file_preview(vec![src_path!("ps", "agent", "not-real", "upload")], false)
-                .is_err()
+    fn tags_are_parsed_into_keys_and_values() {
+        let tags = parse_tags(vec![
+            "subject=mouse-12",
+            " session = 3 ",
+            "query=a=b",
+            "empty=",
+            "subject=mouse-13",
+        ])
+        .unwrap();
+        let expected: Vec<(&str, &str)> = vec![
+            ("empty", ""),
+            ("query", "a=b"),
+            ("session", "3"),
+            ("subject", "mouse-13"),
+        ];
+        assert_eq!(
+            tags.iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        for malformed in &["subject", "=mouse-12", " =x", ""] {
+            match parse_tag(malformed) {
+                Err(ref e) => match e.kind() {
+                    ErrorKind::InvalidTag { tag } => assert_eq!(tag, malformed),
+                    other => panic!("unexpected error: {:?}", other),
+                },
+                Ok(tag) => panic!("{:?} parsed as {:?}", malformed, tag),
+            }
+        }
+        assert!(parse_tags(vec!["a=1", "b"]).is_err());
+    }
+
+    #[test]
+    fn chunk_sizes_grow_with_the_file_size() {
+        const MIB: u64 = 1024 * 1024;
+        const GIB: u64 = 1024 * MIB;
+
+        // Small files are sent in a single minimal chunk:
+        assert_eq!(auto_chunk_size(0), UPLOAD_MIN_CHUNK_SIZE);
+        assert_eq!(auto_chunk_size(1), UPLOAD_MIN_CHUNK_SIZE);
+        assert_eq!(auto_chunk_size(4 * MIB), UPLOAD_MIN_CHUNK_SIZE);
+        // Up to 10,000 parts of the smallest chunk size:
+        assert_eq!(auto_chunk_size(10 * GIB), UPLOAD_MIN_CHUNK_SIZE);
+        assert_eq!(
+            auto_chunk_size(UPLOAD_MAX_CHUNK_COUNT * UPLOAD_MIN_CHUNK_SIZE),
+            UPLOAD_MIN_CHUNK_SIZE
+        );
+        assert_eq!(
+            auto_chunk_size(UPLOAD_MAX_CHUNK_COUNT * UPLOAD_MIN_CHUNK_SIZE + 1),
+            2 * UPLOAD_MIN_CHUNK_SIZE
         );
+        assert_eq!(auto_chunk_size(100 * GIB), 20 * MIB);
+        assert_eq!(auto_chunk_size(1024 * GIB), 160 * MIB);
+        // Files too large to fit are capped:
+        assert_eq!(auto_chunk_size(u64::max_value() / 2), UPLOAD_MAX_CHUNK_SIZE);
+
+        for size in &[0, MIB, GIB, 100 * GIB, 1024 * GIB, 40 * 1024 * GIB] {
+            let chunk_size = auto_chunk_size(*size);
+            assert!(chunk_size >= UPLOAD_MIN_CHUNK_SIZE);
+            assert!(chunk_size <= UPLOAD_MAX_CHUNK_SIZE);
+            assert!((size + chunk_size - 1) / chunk_size <= UPLOAD_MAX_CHUNK_COUNT);
+        }
     }
 
     #[test]
-    fn nonrecursive_include_wildcard_works() {
-        let preview =
-            generate_file_preview(vec![src_path!("ps", "agent", "upload")], false).unwrap();
-        let expected_files = vec![
-            src_path!("ps", "agent", "upload", "error.rs"),
-            src_path!("ps", "agent", "upload", "mod.rs"),
-            src_path!("ps", "agent", "upload", "worker.rs"),
-        ];
-        let expected_files: Vec<PathBuf> = expected_files
+    fn a_requested_chunk_size_overrides_the_automatic_one() {
+        assert_eq!(
+            choose_chunk_size(1024, None).unwrap(),
+            UPLOAD_MIN_CHUNK_SIZE
+        );
+        assert_eq!(
+            choose_chunk_size(1024, Some(64 * 1024 * 1024)).unwrap(),
+            64 * 1024 * 1024
+        );
+        assert_eq!(
+            choose_chunk_size(
+                UPLOAD_MAX_CHUNK_COUNT * UPLOAD_MIN_CHUNK_SIZE,
+                Some(UPLOAD_MIN_CHUNK_SIZE)
+            )
+            .unwrap(),
+            UPLOAD_MIN_CHUNK_SIZE
+        );
+    }
+
+    #[test]
+    fn a_requested_chunk_size_must_fit_the_file_in_the_allowed_parts() {
+        let file_size = UPLOAD_MAX_CHUNK_COUNT * UPLOAD_MIN_CHUNK_SIZE + 1;
+        match choose_chunk_size(file_size, Some(UPLOAD_MIN_CHUNK_SIZE)) {
+            Err(e) => assert_eq!(
+                *e.kind(),
+                ErrorKind::ChunkSizeTooSmall {
+                    chunk_size: UPLOAD_MIN_CHUNK_SIZE,
+                    file_size,
+                    min_chunk_size: UPLOAD_MIN_CHUNK_SIZE + 1,
+                }
+            ),
+            Ok(chunk_size) => panic!("expected an error, got {}", chunk_size),
+        }
+        assert_eq!(
+            choose_chunk_size(file_size, Some(UPLOAD_MIN_CHUNK_SIZE + 1)).unwrap(),
+            UPLOAD_MIN_CHUNK_SIZE + 1
+        );
+    }
+
+    #[test]
+    fn bad_path_fails() {
+        assert!(generate_file_preview(
+            vec![src_path!("ps", "agent", "not-real", "upload")],
+            false,
+            false
+        )
+        .is_err());
+    }
+
+    /// A directory of three files, and a subdirectory holding a fourth
+    /// that is only previewed recursively. Returns the three files.
+    fn nonrecursive_fixture() -> (TempDir, Vec<PathBuf>) {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("nested").join("4.txt"), "4").unwrap();
+
+        let files = ["1.txt", "2.txt", "3.txt"]
             .iter()
-            .map(|f| f.canonicalize().unwrap())
+            .map(|name| {
+                let path = dir.path().join(name);
+                fs::write(&path, name).unwrap();
+                path.canonicalize().unwrap()
+            })
             .collect();
+        (dir, files)
+    }
+
+    #[test]
+    fn nonrecursive_include_wildcard_works() {
+        let (dir, expected_files) = nonrecursive_fixture();
+        let preview = generate_file_preview(vec![dir.path()], false, false).unwrap();
         let mut actual_files: Vec<PathBuf> = preview
             .file_paths()
             .into_iter()
@@ -403,17 +936,8 @@ mod test {
 
     #[test]
     fn nonrecursive_include_works() {
-        let preview =
-            generate_file_preview(vec![src_path!("ps", "agent", "upload")], false).unwrap();
-        let expected_files = vec![
-            src_path!("ps", "agent", "upload", "error.rs"),
-            src_path!("ps", "agent", "upload", "mod.rs"),
-            src_path!("ps", "agent", "upload", "worker.rs"),
-        ];
-        let expected_files: Vec<PathBuf> = expected_files
-            .iter()
-            .map(|f| f.canonicalize().unwrap())
-            .collect();
+        let (dir, expected_files) = nonrecursive_fixture();
+        let preview = generate_file_preview(vec![dir.path()], false, false).unwrap();
         let mut actual_files: Vec<PathBuf> = preview
             .file_paths()
             .into_iter()
@@ -426,7 +950,7 @@ mod test {
     #[test]
     fn recursive_include_works() {
         let preview =
-            generate_file_preview(vec![test_resources_path!("upload_test")], true).unwrap();
+            generate_file_preview(vec![test_resources_path!("upload_test")], true, false).unwrap();
 
         let expected_files = vec![
             test_resources_path!("upload_test/1.txt"),
@@ -455,9 +979,12 @@ mod test {
 
     #[test]
     fn recursive_include_works_for_deeply_nested_directories() {
-        let preview =
-            generate_file_preview(vec![test_resources_path!("upload_test/recursive")], true)
-                .unwrap();
+        let preview = generate_file_preview(
+            vec![test_resources_path!("upload_test/recursive").join("")],
+            true,
+            false,
+        )
+        .unwrap();
 
         let expected_files = vec![
             test_resources_path!("upload_test/recursive/8.txt"),
@@ -494,7 +1021,7 @@ mod test {
     #[test]
     fn recursive_include_creates_expected_file_names() {
         let base_path = test_resources_path!("upload_test/recursive");
-        let preview = generate_file_preview(vec![base_path], true).unwrap();
+        let preview = generate_file_preview(vec![base_path], true, false).unwrap();
 
         let mut expected_files: Vec<PathBuf> = vec![
             test_resources_path!("upload_test/recursive/layer/layer/9.txt"),
@@ -517,4 +1044,456 @@ mod test {
 
         assert_eq!(&actual_files, &expected_files);
     }
+
+    fn insert_append_record(
+        db: &Database,
+        file_path: &PathBuf,
+        package_id: &str,
+        status: UploadStatus,
+    ) {
+        let mut record = UploadRecord::new(
+            file_path,
+            "N:dataset:1",
+            Some(package_id),
+            "N:organization:1",
+            "import-1",
+            true,
+            None,
+            None,
+        )
+        .unwrap();
+        record.status = status;
+        db.insert_upload(&record).unwrap();
+    }
+
+    #[test]
+    fn skip_appended_files_removes_completed_appends() {
+        let db = util::database::temp().unwrap();
+        let appended = test_resources_path!("upload_test/recursive/8.txt")
+            .canonicalize()
+            .unwrap();
+        let not_appended = test_resources_path!("upload_test/recursive/layer/layer/9.txt")
+            .canonicalize()
+            .unwrap();
+
+        insert_append_record(&db, &appended, "N:package:ts", UploadStatus::Completed);
+        // Failed appends and appends to other packages should not be skipped:
+        insert_append_record(&db, &not_appended, "N:package:ts", UploadStatus::Failed);
+        insert_append_record(
+            &db,
+            &not_appended,
+            "N:package:other",
+            UploadStatus::Completed,
+        );
+
+        let preview = generate_file_preview(
+            vec![test_resources_path!("upload_test/recursive")],
+            true,
+            false,
+        )
+        .unwrap();
+        let (preview, skipped) = skip_appended_files(&db, preview, "N:package:ts").unwrap();
+
+        assert_eq!(skipped, vec![appended]);
+        let remaining: Vec<PathBuf> = preview
+            .file_paths()
+            .iter()
+            .map(|(_id, path)| path.clone())
+            .collect();
+        assert_eq!(remaining, vec![not_appended]);
+    }
+
+    #[test]
+    fn skip_appended_files_fails_if_everything_was_appended() {
+        let db = util::database::temp().unwrap();
+        let appended = test_resources_path!("upload_test/recursive/8.txt")
+            .canonicalize()
+            .unwrap();
+        insert_append_record(&db, &appended, "N:package:ts", UploadStatus::Completed);
+
+        let preview = generate_file_preview(vec![appended], false, false).unwrap();
+
+        assert_eq!(
+            skip_appended_files(&db, preview, "N:package:ts")
+                .err()
+                .map(|e| e.kind().clone()),
+            Some(ErrorKind::AlreadyAppended)
+        );
+    }
+
+    fn insert_upload_record(
+        db: &Database,
+        file_path: &Path,
+        dataset_id: &str,
+        status: UploadStatus,
+    ) {
+        let mut record = UploadRecord::new(
+            file_path,
+            dataset_id,
+            None as Option<String>,
+            "N:organization:1",
+            "import_1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        record.status = status;
+        db.insert_upload(&record).unwrap();
+    }
+
+    #[test]
+    fn skip_queued_files_suppresses_a_second_queue_attempt() {
+        let db = util::database::temp().unwrap();
+        let queued = test_resources_path!("upload_test/recursive/8.txt")
+            .canonicalize()
+            .unwrap();
+        let not_queued = test_resources_path!("upload_test/recursive/layer/layer/9.txt")
+            .canonicalize()
+            .unwrap();
+
+        insert_upload_record(&db, &queued, "N:dataset:1", UploadStatus::Queued);
+        // Finished uploads and uploads to other datasets should not be skipped:
+        insert_upload_record(&db, &not_queued, "N:dataset:1", UploadStatus::Completed);
+        insert_upload_record(&db, &not_queued, "N:dataset:2", UploadStatus::InProgress);
+
+        let preview = generate_file_preview(
+            vec![test_resources_path!("upload_test/recursive")],
+            true,
+            false,
+        )
+        .unwrap();
+        let (preview, skipped) = skip_queued_files(&db, preview, "N:dataset:1").unwrap();
+
+        assert_eq!(skipped, vec![queued.clone()]);
+        let remaining: Vec<PathBuf> = preview
+            .file_paths()
+            .iter()
+            .map(|(_id, path)| path.clone())
+            .collect();
+        assert_eq!(remaining, vec![not_queued]);
+
+        // Queuing only files that are already queued fails:
+        let preview = generate_file_preview(vec![queued], false, false).unwrap();
+        assert_eq!(
+            skip_queued_files(&db, preview, "N:dataset:1")
+                .err()
+                .map(|e| e.kind().clone()),
+            Some(ErrorKind::AlreadyQueued)
+        );
+    }
+
+    // A directory to upload, along with a listing of the dataset it is
+    // uploaded to that already holds a.txt and nested/c.txt:
+    fn existing_upload() -> (tempfile::TempDir, ExistingFiles) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("nested").join("c.txt"), "c").unwrap();
+        fs::write(dir.path().join("nested").join("d.txt"), "d").unwrap();
+
+        let mut existing = ExistingFiles::new();
+        for path in &["a.txt", "nested/c.txt", "unrelated.txt"] {
+            existing.insert(path.to_string(), ExistingFile::default());
+        }
+        (dir, existing)
+    }
+
+    fn target_paths(preview: &PreviewFiles) -> Vec<String> {
+        let mut paths: Vec<String> = preview
+            .file_paths()
+            .iter()
+            .map(|(_, path)| target_path(preview.path(), path))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn target_paths_are_relative_to_the_uploaded_directory() {
+        let base = Path::new("/data/upload");
+        assert_eq!(
+            target_path(Some(base), &base.join("nested").join("c.txt")),
+            "nested/c.txt"
+        );
+        assert_eq!(target_path(None, &base.join("a.txt")), "a.txt");
+    }
+
+    #[test]
+    fn a_trailing_slash_uploads_the_contents_of_a_directory() {
+        let (dir, _) = existing_upload();
+        let root = dir.path().canonicalize().unwrap();
+        let name = root.file_name().unwrap().to_string_lossy().into_owned();
+
+        let contents = generate_file_preview(vec![root.join("")], true, false).unwrap();
+        assert_eq!(contents.path(), Some(root.as_path()));
+        assert_eq!(
+            target_paths(&contents),
+            vec!["a.txt", "b.txt", "nested/c.txt", "nested/d.txt"]
+        );
+
+        // Without the slash, the directory itself becomes a folder:
+        let folder = generate_file_preview(vec![&root], true, false).unwrap();
+        assert_eq!(folder.path(), root.parent());
+        assert_eq!(
+            target_paths(&folder),
+            vec![
+                format!("{}/a.txt", name),
+                format!("{}/b.txt", name),
+                format!("{}/nested/c.txt", name),
+                format!("{}/nested/d.txt", name),
+            ]
+        );
+        assert_eq!(folder.file_paths(), contents.file_paths());
+    }
+
+    #[test]
+    fn skip_existing_files_queues_only_new_files() {
+        let (dir, existing) = existing_upload();
+        let root = dir.path().canonicalize().unwrap();
+        let preview = generate_file_preview(vec![root.join("")], true, false).unwrap();
+
+        let (preview, mut skipped) = skip_existing_files(preview, &existing, |_, _| None).unwrap();
+        skipped.sort();
+
+        assert_eq!(
+            skipped,
+            vec![root.join("a.txt"), root.join("nested").join("c.txt")]
+        );
+        assert_eq!(target_paths(&preview), vec!["b.txt", "nested/d.txt"]);
+    }
+
+    #[test]
+    fn skip_existing_files_compares_known_checksums() {
+        let (dir, mut existing) = existing_upload();
+        let root = dir.path().canonicalize().unwrap();
+        existing.insert(
+            "a.txt".to_string(),
+            ExistingFile {
+                checksum: Some(("stale".to_string(), 1024)),
+            },
+        );
+        existing.insert(
+            "nested/c.txt".to_string(),
+            ExistingFile {
+                checksum: Some(("current".to_string(), 1024)),
+            },
+        );
+        let preview = generate_file_preview(vec![root.join("")], true, false).unwrap();
+
+        // a.txt changed since it was uploaded, so it is uploaded again:
+        let (preview, skipped) =
+            skip_existing_files(preview, &existing, |_, _| Some("current".to_string())).unwrap();
+        assert_eq!(skipped, vec![root.join("nested").join("c.txt")]);
+        assert_eq!(
+            target_paths(&preview),
+            vec!["a.txt", "b.txt", "nested/d.txt"]
+        );
+    }
+
+    #[test]
+    fn skip_existing_files_fails_if_everything_exists() {
+        let (dir, existing) = existing_upload();
+        let preview = generate_file_preview(vec![dir.path().join("a.txt")], false, false).unwrap();
+
+        assert_eq!(
+            skip_existing_files(preview, &existing, |_, _| None)
+                .err()
+                .map(|e| e.kind().clone()),
+            Some(ErrorKind::AlreadyUploaded)
+        );
+    }
+
+    #[test]
+    fn dry_run_summarizes_without_queueing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), vec![0u8; 100]).unwrap();
+        fs::write(dir.path().join("b.txt"), vec![0u8; 2_000]).unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("nested").join("c.txt"), vec![0u8; 30]).unwrap();
+        // hidden files are never uploaded:
+        fs::write(dir.path().join(".hidden"), vec![0u8; 5]).unwrap();
+        let db = util::database::temp().unwrap();
+
+        assert_eq!(
+            summarize_upload(vec![dir.path()], true, false, false).unwrap(),
+            UploadSummary {
+                files: 3,
+                bytes: 2_130
+            }
+        );
+        assert_eq!(
+            summarize_upload(vec![dir.path()], false, false, false).unwrap(),
+            UploadSummary {
+                files: 2,
+                bytes: 2_100
+            }
+        );
+        assert_eq!(
+            summarize_upload(vec![dir.path().join("a.txt")], false, false, false)
+                .unwrap()
+                .to_string(),
+            "1 file, 100 B in total"
+        );
+        assert!(db.get_active_uploads().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    fn file_names(preview: &PreviewFiles) -> Vec<String> {
+        let mut names: Vec<String> = preview
+            .file_paths()
+            .iter()
+            .map(|(_, path)| {
+                path.strip_prefix(preview.path().unwrap())
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn circular_symlinks_are_skipped() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("nested")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("nested").join("b.txt"), "b").unwrap();
+        symlink(&root, root.join("nested").join("loop")).unwrap();
+        symlink(root.join("a.txt"), root.join("link.txt")).unwrap();
+
+        let preview = generate_file_preview(vec![root.join("")], true, false).unwrap();
+        assert_eq!(file_names(&preview), vec!["a.txt", "nested/b.txt"]);
+        let mut skipped = preview.skipped_links().clone();
+        skipped.sort();
+        let root = root.canonicalize().unwrap();
+        assert_eq!(
+            skipped,
+            vec![root.join("link.txt"), root.join("nested").join("loop")]
+        );
+
+        // Following the links still terminates:
+        let preview = generate_file_preview(vec![root.join("")], true, true).unwrap();
+        assert_eq!(
+            file_names(&preview),
+            vec!["a.txt", "link.txt", "nested/b.txt"]
+        );
+        assert_eq!(
+            preview.skipped_links(),
+            &vec![root.join("nested").join("loop")]
+        );
+    }
+
+    #[test]
+    fn files_are_routed_to_the_dataset_named_by_their_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("Mouse Study").join("session-1")).unwrap();
+        fs::create_dir_all(root.join("Rat Study")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join("Mouse Study").join("a.edf"), "a").unwrap();
+        fs::write(
+            root.join("Mouse Study").join("session-1").join("b.edf"),
+            "b",
+        )
+        .unwrap();
+        fs::write(root.join("Rat Study").join("c.edf"), "c").unwrap();
+        fs::write(root.join("README.txt"), "loose").unwrap();
+
+        let (datasets, skipped) = datasets_from_path(root).unwrap();
+        assert_eq!(skipped, vec![root.join("README.txt")]);
+
+        let routed: Vec<(String, Vec<String>)> = datasets
+            .into_iter()
+            .map(|(dataset, path)| {
+                let preview = generate_file_preview(vec![path.join("")], true, false).unwrap();
+                (dataset, file_names(&preview))
+            })
+            .collect();
+        assert_eq!(
+            routed,
+            vec![
+                (
+                    "Mouse Study".to_string(),
+                    vec!["a.edf".to_string(), "session-1/b.edf".to_string()]
+                ),
+                ("Rat Study".to_string(), vec!["c.edf".to_string()]),
+            ]
+        );
+
+        // The root must be a directory holding at least one dataset:
+        assert!(datasets_from_path(root.join("README.txt")).is_err());
+        assert!(datasets_from_path(root.join("Rat Study")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn followed_symlinks_reach_sibling_directories() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let sibling = dir.path().join("sibling");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&sibling).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(sibling.join("c.txt"), "c").unwrap();
+        symlink(&sibling, root.join("linked")).unwrap();
+
+        let preview = generate_file_preview(vec![root.join("")], true, true).unwrap();
+        assert_eq!(file_names(&preview), vec!["a.txt", "linked/c.txt"]);
+        assert!(preview.skipped_links().is_empty());
+
+        let preview = generate_file_preview(vec![root.join("")], true, false).unwrap();
+        assert_eq!(file_names(&preview), vec!["a.txt"]);
+        assert_eq!(
+            preview.skipped_links(),
+            &vec![root.canonicalize().unwrap().join("linked")]
+        );
+    }
+
+    #[test]
+    fn empty_files_are_skipped_on_request() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("data.txt"), "data").unwrap();
+        fs::write(dir.path().join("empty.txt"), "").unwrap();
+
+        let preview = generate_file_preview(vec![dir.path()], false, false).unwrap();
+        assert_eq!(preview.file_paths().len(), 2);
+
+        let (preview, skipped) = preview.without_empty_files().unwrap();
+        let remaining: Vec<PathBuf> = preview
+            .file_paths()
+            .iter()
+            .map(|(_, path)| path.clone())
+            .collect();
+        let dir = dir.path().canonicalize().unwrap();
+        assert_eq!(remaining, vec![dir.join("data.txt")]);
+        assert_eq!(skipped, vec![dir.join("empty.txt")]);
+
+        assert_eq!(
+            summarize_upload(vec![&dir], false, false, true).unwrap(),
+            UploadSummary { files: 1, bytes: 4 }
+        );
+        assert_eq!(
+            summarize_upload(vec![&dir], false, false, false).unwrap(),
+            UploadSummary { files: 2, bytes: 4 }
+        );
+
+        // a preview of nothing but empty files leaves nothing to upload:
+        let preview = generate_file_preview(vec![dir.join("empty.txt")], false, false).unwrap();
+        assert_eq!(
+            preview
+                .without_empty_files()
+                .err()
+                .map(|e| e.kind().clone()),
+            Some(ErrorKind::NoFilesToUpload)
+        );
+    }
 }
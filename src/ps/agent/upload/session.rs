@@ -0,0 +1,361 @@
+//! Uploading files from another program, without the command line
+//! interface.
+//!
+//! A `Session` is the supported entry point for embedding the uploader. It
+//! queues the given files, then uploads them in the current process, the
+//! way `upload` does when no agent is running in server mode:
+//!
+//! ```rust,ignore
+//! let uploaded = upload::Session::new(&db, &api, "My Samples", vec!["data/"])
+//!     .recursive(true)
+//!     .file_parallelism(4)
+//!     .progress_sink(Arc::new(MyProgress))
+//!     .start()
+//!     .wait()?;
+//! ```
+//!
+//! A session only uploads the files it queued, leaving any other uploads
+//! in `db` as they are. It still shouldn't be run against the database of
+//! an agent running in server mode, whose own upload worker would pick up
+//! the same files.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future as _Future};
+use tokio::timer::Delay;
+
+use crate::ps::agent::api::Api;
+use crate::ps::agent::config::constants::UPLOAD_WORKER_RUN_INTERVAL_SECS;
+use crate::ps::agent::database::{
+    Database, UploadRecord, UploadRecords, UploadStatus, UploadThresholds,
+};
+use crate::ps::agent::upload::worker::{self, Parallelism, ProgressSink};
+use crate::ps::agent::{self, Future};
+use crate::ps::util::futures::*;
+
+/// Uploads a set of files to a dataset. See the module documentation.
+pub struct Session {
+    db: Database,
+    api: Api,
+    dataset: String,
+    package: Option<String>,
+    paths: Vec<String>,
+    recursive: bool,
+    parallelism: Parallelism,
+    progress_sink: Option<Arc<dyn ProgressSink>>,
+    thresholds: UploadThresholds,
+}
+
+impl Session {
+    /// Creates a session uploading `paths` to the dataset with the given ID
+    /// or name. Files are uploaded one at a time, and directories are not
+    /// descended into, unless asked.
+    pub fn new<D, P>(db: &Database, api: &Api, dataset: D, paths: Vec<P>) -> Self
+    where
+        D: Into<String>,
+        P: Into<String>,
+    {
+        Self {
+            db: db.clone(),
+            api: api.clone(),
+            dataset: dataset.into(),
+            package: None,
+            paths: paths.into_iter().map(Into::into).collect(),
+            recursive: false,
+            parallelism: Parallelism::default(),
+            progress_sink: None,
+            thresholds: UploadThresholds::default(),
+        }
+    }
+
+    /// Uploads the files into the collection with the given ID or name,
+    /// rather than the top level of the dataset.
+    pub fn package<P: Into<String>>(mut self, package: P) -> Self {
+        self.package = Some(package.into());
+        self
+    }
+
+    /// Uploads the files in any directories given, and their
+    /// subdirectories.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Uploads up to `parallelism` files at a time, each in up to
+    /// `parallelism` chunks at a time.
+    pub fn parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = Parallelism::level(parallelism);
+        self
+    }
+
+    /// Uploads up to `files` files at a time.
+    pub fn file_parallelism(mut self, files: usize) -> Self {
+        self.parallelism = Parallelism::new(files, self.parallelism.chunks);
+        self
+    }
+
+    /// Uploads each file in up to `chunks` chunks at a time.
+    pub fn chunk_parallelism(mut self, chunks: usize) -> Self {
+        self.parallelism = Parallelism::new(self.parallelism.files, chunks);
+        self
+    }
+
+    /// Reports the progress of each file to `sink`.
+    pub fn progress_sink(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.progress_sink = Some(sink);
+        self
+    }
+
+    /// Sets when stalled uploads are retried, and when they are given up on.
+    pub fn thresholds(mut self, thresholds: UploadThresholds) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Queues the files and uploads them. The future resolves to the final
+    /// state of each file's upload record once every file has completed
+    /// or failed.
+    pub fn start(self) -> Future<UploadRecords> {
+        let Session {
+            db,
+            api,
+            dataset,
+            package,
+            paths,
+            recursive,
+            parallelism,
+            progress_sink,
+            thresholds,
+        } = self;
+
+        let queued = api.queue_uploads_simple(dataset, package, paths, false, recursive);
+        run(db, queued, move |db, import_ids| {
+            worker::step(
+                db,
+                &api,
+                parallelism,
+                progress_sink.clone(),
+                None,
+                thresholds,
+                Some(import_ids),
+            )
+        })
+    }
+}
+
+// Tests if an upload is done with, one way or the other.
+fn is_finished(record: &UploadRecord) -> bool {
+    record.status == UploadStatus::Completed || record.status == UploadStatus::Failed
+}
+
+/// Runs upload steps on the imports of `queued` until every upload in it
+/// has finished, waiting between steps the way the upload worker does.
+fn run<S>(db: Database, queued: Future<UploadRecords>, step: S) -> Future<UploadRecords>
+where
+    S: Fn(Database, &HashSet<String>) -> Future<()> + Send + 'static,
+{
+    queued
+        .and_then(move |queued| {
+            let import_ids: HashSet<String> = queued
+                .iter()
+                .map(|record| record.import_id.clone())
+                .collect();
+
+            future::loop_fn((), move |_| {
+                let db = db.clone();
+                let import_ids = import_ids.clone();
+                step(db.clone(), &import_ids)
+                    .and_then(move |_| -> agent::Result<Option<UploadRecords>> {
+                        let mut records = vec![];
+                        for import_id in &import_ids {
+                            records.extend(db.get_uploads_by_import_id(import_id)?.records);
+                        }
+                        Ok(if records.iter().all(is_finished) {
+                            Some(records.into())
+                        } else {
+                            None
+                        })
+                    })
+                    .and_then(|finished| -> Future<future::Loop<UploadRecords, ()>> {
+                        match finished {
+                            Some(records) => future::ok(future::Loop::Break(records)).into_trait(),
+                            None => Delay::new(
+                                Instant::now()
+                                    + Duration::from_secs(UPLOAD_WORKER_RUN_INTERVAL_SECS),
+                            )
+                            .map_err(Into::into)
+                            .map(|_| future::Loop::Continue(()))
+                            .into_trait(),
+                        }
+                    })
+            })
+        })
+        .into_trait()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use tokio::runtime::current_thread::Runtime;
+
+    use crate::ps::util;
+
+    #[derive(Default)]
+    struct RecordingProgressSink {
+        progress: Mutex<Vec<(String, i32)>>,
+    }
+
+    impl ProgressSink for RecordingProgressSink {
+        fn on_progress(&self, record: &UploadRecord) {
+            self.progress
+                .lock()
+                .unwrap()
+                .push((record.file_path.clone(), record.progress));
+        }
+    }
+
+    fn queue(db: &Database, files: &[(&str, &str)]) -> Future<UploadRecords> {
+        let mut records = vec![];
+        for (file_path, import_id) in files {
+            let mut record = UploadRecord::new(
+                *file_path,
+                "N:dataset:1",
+                None as Option<String>,
+                "N:organization:1",
+                *import_id,
+                false,
+                None,
+                None,
+            )
+            .unwrap();
+            record.id = Some(db.insert_upload(&record).unwrap());
+            records.push(record);
+        }
+        future::ok(records.into()).into_trait()
+    }
+
+    #[test]
+    fn sessions_run_until_every_upload_has_finished() {
+        let db = util::database::temp().unwrap();
+        let queued = queue(
+            &db,
+            &[
+                ("/data/a.edf", "import-1"),
+                ("/data/b.edf", "import-1"),
+                ("/data/c.edf", "import-2"),
+            ],
+        );
+        // Someone else's upload, which the session leaves alone:
+        queue(&db, &[("/data/other.edf", "import-3")])
+            .wait()
+            .unwrap();
+
+        // Stands in for the upload worker: the first step makes progress
+        // on every file, and the second finishes them, failing one import.
+        let sink = Arc::new(RecordingProgressSink::default());
+        let steps = Arc::new(AtomicUsize::new(0));
+        let step = {
+            let sink = sink.clone();
+            let steps = steps.clone();
+            move |db: Database, _: &HashSet<String>| -> Future<()> {
+                let step = steps.fetch_add(1, Ordering::SeqCst);
+                let result = (|| -> agent::Result<()> {
+                    for import_id in &["import-1", "import-2"] {
+                        if step == 0 {
+                            for record in db.get_uploads_by_import_id(import_id)?.iter() {
+                                db.update_file_progress(import_id, &record.file_path, 50)?;
+                            }
+                        } else if *import_id == "import-2" {
+                            db.fail_import(import_id, "checksum mismatch", None)?;
+                        } else {
+                            db.update_import_status_and_progress(
+                                import_id,
+                                UploadStatus::Completed,
+                                100,
+                            )?;
+                        }
+                        for record in db.get_uploads_by_import_id(import_id)?.iter() {
+                            sink.on_progress(record);
+                        }
+                    }
+                    Ok(())
+                })();
+                future::result(result).into_trait()
+            }
+        };
+
+        let mut rt = Runtime::new().unwrap();
+        let uploaded = rt.block_on(run(db.clone(), queued, step)).unwrap();
+
+        assert_eq!(steps.load(Ordering::SeqCst), 2);
+        let mut statuses: Vec<(String, UploadStatus)> = uploaded
+            .iter()
+            .map(|record| (record.file_path.clone(), record.status))
+            .collect();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            statuses,
+            vec![
+                ("/data/a.edf".to_string(), UploadStatus::Completed),
+                ("/data/b.edf".to_string(), UploadStatus::Completed),
+                ("/data/c.edf".to_string(), UploadStatus::Failed),
+            ]
+        );
+        let progress = sink.progress.lock().unwrap().clone();
+        assert_eq!(progress.len(), 6);
+        assert!(progress.contains(&("/data/b.edf".to_string(), 50)));
+        assert!(progress.contains(&("/data/b.edf".to_string(), 100)));
+        assert_eq!(db.get_queued_uploads().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sessions_only_step_through_their_own_imports() {
+        let db = util::database::temp().unwrap();
+        let queued = queue(&db, &[("/data/a.edf", "import-1")]);
+        queue(&db, &[("/data/other.edf", "import-2")])
+            .wait()
+            .unwrap();
+
+        // Selects uploads the way the upload worker's step does, then
+        // completes whatever it was given:
+        let selected = Arc::new(Mutex::new(vec![]));
+        let step = {
+            let selected = selected.clone();
+            move |db: Database, import_ids: &HashSet<String>| -> Future<()> {
+                let result = (|| -> agent::Result<()> {
+                    let pending = worker::pending_uploads(
+                        &db,
+                        UploadThresholds::default(),
+                        Some(import_ids),
+                    )?;
+                    for import_id in pending.keys() {
+                        selected.lock().unwrap().push(import_id.clone());
+                        db.update_import_status_and_progress(
+                            import_id,
+                            UploadStatus::Completed,
+                            100,
+                        )?;
+                    }
+                    Ok(())
+                })();
+                future::result(result).into_trait()
+            }
+        };
+
+        let mut rt = Runtime::new().unwrap();
+        let uploaded = rt.block_on(run(db.clone(), queued, step)).unwrap();
+
+        assert_eq!(*selected.lock().unwrap(), vec!["import-1".to_string()]);
+        assert_eq!(uploaded.len(), 1);
+        let queued = db.get_queued_uploads().unwrap();
+        assert_eq!(queued.len(), 1);
+        assert!(queued.iter().all(|record| record.import_id == "import-2"));
+    }
+}
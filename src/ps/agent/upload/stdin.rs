@@ -0,0 +1,276 @@
+//! Uploading data read from stdin, as done by `upload --stdin`.
+//!
+//! Uploads are read from disk by the upload worker, possibly long after
+//! they were queued and in another process, so the data is first spooled
+//! to a file of its own under the spool directory. The file is removed
+//! once its upload completes or is cancelled. Files of failed uploads are
+//! kept so they can be retried, until the upload worker sweeps them away
+//! once their retry window has passed.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+use crate::ps;
+use crate::ps::agent::database::{Database, UploadRecords};
+use crate::ps::agent::upload::{Error, Result};
+use crate::ps::util::strings;
+
+/// Gets the directory data read from stdin is spooled to before it is
+/// uploaded.
+pub fn spool_dir() -> ps::Result<PathBuf> {
+    ps::home_dir().map(|dir| dir.join("stdin"))
+}
+
+/// Copies everything read from `reader` to a new file called `name` in
+/// `dir`, returning the path of the file. The data is copied a buffer at a
+/// time, so its length needn't be known up front.
+pub fn spool<R: Read>(mut reader: R, dir: &Path, name: &str) -> Result<PathBuf> {
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." || name.contains(|c| c == '/' || c == '\\') {
+        return Err(Error::invalid_path(format!(
+            "invalid name for data read from stdin: {:?}",
+            name
+        )));
+    }
+
+    // Each upload gets a directory of its own, so that it keeps its name
+    // and never collides with another upload of the same name:
+    let dir = dir.join(strings::random_suffix("upload"));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(name);
+
+    let copied = {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let copied = io::copy(&mut reader, &mut writer)?;
+        writer.flush()?;
+        copied
+    };
+    if copied == 0 {
+        remove_spooled(&path);
+        return Err(Error::invalid_path("no data was read from stdin"));
+    }
+    info!("upload: spooled {} bytes from stdin to {:?}", copied, path);
+
+    Ok(path)
+}
+
+/// Tests if the file at `path` was spooled from stdin.
+pub fn is_spooled<P: AsRef<Path>>(path: P) -> bool {
+    spool_dir()
+        .map(|dir| path.as_ref().starts_with(dir))
+        .unwrap_or(false)
+}
+
+/// Removes a file spooled from stdin, along with the directory it was
+/// spooled to.
+pub fn remove_spooled<P: AsRef<Path>>(path: P) {
+    let path = path.as_ref();
+    let dir = path.parent().unwrap_or(path);
+    if let Err(e) = fs::remove_dir_all(dir) {
+        warn!("upload: couldn't remove spooled stdin {:?}: {}", dir, e);
+    }
+}
+
+/// Gets the files of `records` that were spooled to `dir`.
+pub fn spooled_in(records: &UploadRecords, dir: &Path) -> Vec<PathBuf> {
+    records
+        .iter()
+        .map(|record| PathBuf::from(&record.file_path))
+        .filter(|path| path.starts_with(dir))
+        .collect()
+}
+
+/// Gets the files uploads in `db` may still read: those of queued and in
+/// progress uploads, and of uploads that failed since `failed_since`, which
+/// can still be retried.
+fn needed(db: &Database, failed_since: time::Timespec) -> Result<HashSet<PathBuf>> {
+    let mut needed: HashSet<PathBuf> = db
+        .get_active_uploads()?
+        .iter()
+        .map(|record| PathBuf::from(&record.file_path))
+        .collect();
+    needed.extend(
+        db.get_failed_uploads()?
+            .iter()
+            .filter(|record| record.updated_at > failed_since)
+            .map(|record| PathBuf::from(&record.file_path)),
+    );
+    Ok(needed)
+}
+
+/// Removes the spooled files among `paths` that no upload in `db` needs any
+/// longer, such as those of uploads that were just cancelled.
+pub fn remove_unneeded(db: &Database, paths: Vec<PathBuf>) -> Result<()> {
+    let needed = needed(db, time::Timespec::new(0, 0))?;
+    for path in paths {
+        if !needed.contains(&path) {
+            remove_spooled(path);
+        }
+    }
+    Ok(())
+}
+
+/// Removes the directories in `dir` that data was spooled to more than
+/// `grace` ago and that no upload in `db` needs any longer, such as those
+/// left behind by an agent that stopped before it could clean up. Files of
+/// uploads that failed more than `grace` ago are removed too. Returns the
+/// number of directories removed.
+pub fn sweep(db: &Database, dir: &Path, grace: time::Duration) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let needed = needed(db, time::now().to_timespec() - grace)?;
+    let grace = grace.to_std().unwrap_or_default();
+
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // A file may have been spooled, but not queued yet:
+        let recent = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(true, |age| age < grace);
+        if !path.is_dir() || recent || needed.iter().any(|file| file.starts_with(&path)) {
+            continue;
+        }
+        match fs::remove_dir_all(&path) {
+            Ok(_) => removed += 1,
+            Err(e) => warn!("upload: couldn't remove spooled stdin {:?}: {}", path, e),
+        }
+    }
+    if removed > 0 {
+        info!(
+            "upload: removed {} orphaned stdin spool directories",
+            removed
+        );
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    use crate::ps::agent::database::UploadRecord;
+    use crate::ps::util;
+
+    /// Hands out its data a few bytes at a time, like a pipe whose writer
+    /// is slower than its reader.
+    struct Pipe {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let remaining = &self.data[self.position..];
+            let n = remaining.len().min(buf.len()).min(7);
+            buf[..n].copy_from_slice(&remaining[..n]);
+            self.position += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn piped_data_is_spooled_to_a_file_with_the_given_name() {
+        let dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let pipe = Pipe {
+            data: data.clone(),
+            position: 0,
+        };
+
+        let path = spool(pipe, dir.path(), "recording.csv").unwrap();
+        assert_eq!(path.file_name().unwrap(), "recording.csv");
+        assert!(path.starts_with(dir.path()));
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        // Uploads of the same name don't collide:
+        let other = spool(&b"more"[..], dir.path(), "recording.csv").unwrap();
+        assert_ne!(other, path);
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        remove_spooled(&path);
+        assert!(!path.exists());
+        assert!(!path.parent().unwrap().exists());
+        assert!(other.exists());
+    }
+
+    #[test]
+    fn empty_input_and_bad_names_are_rejected() {
+        let dir = tempdir().unwrap();
+        assert!(spool(&b""[..], dir.path(), "empty.csv").is_err());
+        assert!(spool(&b"data"[..], dir.path(), "").is_err());
+        assert!(spool(&b"data"[..], dir.path(), "../escape.csv").is_err());
+        // nothing is left behind:
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    fn queue(db: &Database, path: &Path, import_id: &str) {
+        let record = UploadRecord::new(
+            path.to_string_lossy(),
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            import_id,
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        db.insert_upload(&record).unwrap();
+    }
+
+    #[test]
+    fn cancelled_uploads_lose_their_spooled_files() {
+        let dir = tempdir().unwrap();
+        let db = util::database::temp().unwrap();
+        let cancelled = spool(&b"cancelled"[..], dir.path(), "a.csv").unwrap();
+        let kept = spool(&b"kept"[..], dir.path(), "b.csv").unwrap();
+        queue(&db, &cancelled, "import-1");
+        queue(&db, &kept, "import-2");
+
+        let spooled = spooled_in(&db.get_active_uploads().unwrap(), dir.path());
+        assert_eq!(spooled.len(), 2);
+        db.cancel_uploads_by_import_id("import-1").unwrap();
+        remove_unneeded(&db, spooled).unwrap();
+
+        assert!(!cancelled.parent().unwrap().exists());
+        assert!(kept.exists());
+    }
+
+    #[test]
+    fn orphaned_spool_directories_are_swept() {
+        let dir = tempdir().unwrap();
+        let db = util::database::temp().unwrap();
+        let orphaned = spool(&b"orphaned"[..], dir.path(), "a.csv").unwrap();
+        let queued = spool(&b"queued"[..], dir.path(), "b.csv").unwrap();
+        let failed = spool(&b"failed"[..], dir.path(), "c.csv").unwrap();
+        queue(&db, &queued, "import-1");
+        queue(&db, &failed, "import-2");
+        db.fail_import("import-2", "unauthorized", None).unwrap();
+
+        // Nothing is old enough to be swept yet:
+        assert_eq!(sweep(&db, dir.path(), time::Duration::hours(1)).unwrap(), 0);
+        assert!(orphaned.exists());
+
+        // Once the grace period has passed, only the files of queued and
+        // in progress uploads are kept:
+        assert_eq!(sweep(&db, dir.path(), time::Duration::zero()).unwrap(), 2);
+        assert!(!orphaned.parent().unwrap().exists());
+        assert!(!failed.parent().unwrap().exists());
+        assert!(queued.exists());
+
+        assert_eq!(
+            sweep(&db, &dir.path().join("missing"), time::Duration::zero()).unwrap(),
+            0
+        );
+    }
+}
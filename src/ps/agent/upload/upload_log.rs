@@ -0,0 +1,107 @@
+//! Per-upload log files, written to the directory given with
+//! `--upload-log`.
+//!
+//! Each import group gets its own file, named by its import ID, so the
+//! diagnostics of a single upload can be read without turning on debug
+//! logging for the whole agent.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::*;
+use log4rs::append::file::FileAppender;
+use log4rs::append::Append;
+
+use crate::ps::util::logging::LogFormat;
+
+/// The log of a single upload, named by its import ID. Records are
+/// written straight to the upload's own file, whatever the level of the
+/// agent's logger.
+///
+/// A log opened without a directory discards everything written to it.
+#[derive(Clone, Debug, Default)]
+pub struct UploadLog {
+    import_id: String,
+    appender: Option<Arc<FileAppender>>,
+}
+
+impl UploadLog {
+    /// The file the log of `import_id` is written to in `dir`.
+    pub fn file_path<P: AsRef<Path>>(dir: P, import_id: &str) -> PathBuf {
+        dir.as_ref().join(format!("{}.log", import_id))
+    }
+
+    /// Opens the log of `import_id` in `dir`, appending to it if a previous
+    /// attempt at the upload already wrote one. If the file cannot be
+    /// opened, a warning is logged and the upload goes on without it.
+    pub fn open<P: AsRef<Path>>(dir: Option<P>, import_id: &str) -> Self {
+        let appender = dir.and_then(|dir| {
+            let file_path = Self::file_path(&dir, import_id);
+            fs::create_dir_all(dir)
+                .and_then(|_| {
+                    FileAppender::builder()
+                        .encoder(LogFormat::from_env().encoder())
+                        .append(true)
+                        .build(&file_path)
+                })
+                .map_err(|e| warn!("Could not open the upload log {:?}: {}", file_path, e))
+                .ok()
+        });
+
+        Self {
+            import_id: import_id.to_string(),
+            appender: appender.map(Arc::new),
+        }
+    }
+
+    /// Tests if records written to this log are kept.
+    pub fn is_enabled(&self) -> bool {
+        self.appender.is_some()
+    }
+
+    /// Writes a record to the log, with the import ID as its target.
+    pub fn log(&self, level: Level, args: fmt::Arguments<'_>) {
+        if let Some(ref appender) = self.appender {
+            let record = Record::builder()
+                .args(args)
+                .level(level)
+                .target(&self.import_id)
+                .build();
+            if let Err(e) = appender.append(&record) {
+                warn!("Could not write to the upload log: {}", e);
+            }
+            appender.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_are_named_by_import_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = UploadLog::open(Some(dir.path()), "import-1");
+        assert!(log.is_enabled());
+
+        log.log(Level::Info, format_args!("part {} sent", 1));
+        UploadLog::open(Some(dir.path()), "import-1")
+            .log(Level::Info, format_args!("part {} sent", 2));
+
+        let contents = fs::read_to_string(UploadLog::file_path(dir.path(), "import-1")).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert!(contents.contains("import-1"));
+        assert!(contents.contains("part 1 sent"));
+        assert!(contents.contains("part 2 sent"));
+    }
+
+    #[test]
+    fn logs_without_a_directory_are_discarded() {
+        let log = UploadLog::open(None as Option<&Path>, "import-1");
+        assert!(!log.is_enabled());
+        log.log(Level::Error, format_args!("nowhere to go"));
+    }
+}
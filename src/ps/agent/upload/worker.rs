@@ -2,13 +2,17 @@
 //! persisting packages to the Pennsieve platform.
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use futures::{future, stream, Future as _Future, IntoFuture, Stream};
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use log::*;
 use tokio::timer::{Delay, Interval};
 
@@ -17,10 +21,10 @@ use pennsieve_rust::api::{ProgressCallback, ProgressUpdate};
 use pennsieve_rust::model;
 
 use crate::ps::agent::api::Api;
-use crate::ps::agent::database::{Database, UploadRecord, UploadStatus};
+use crate::ps::agent::database::{Database, UploadRecord, UploadStatus, UploadThresholds};
 use crate::ps::agent::messages::{QueueUpload, Response, WorkerStartup};
 use crate::ps::agent::types::{ServiceId, WithProps, Worker};
-use crate::ps::agent::upload::{Error, Result};
+use crate::ps::agent::upload::{self, is_empty_file, stdin, Error, Result, UploadLog};
 use crate::ps::agent::{self, config, server, Future};
 
 use crate::ps::util::futures::*;
@@ -28,14 +32,96 @@ use crate::ps::util::{actor as a, futures as f};
 
 type ImportGroup = (String, Vec<UploadRecord>);
 
+lazy_static! {
+    // Set once the agent starts shutting down, after which no new uploads
+    // are started:
+    static ref DRAINING: AtomicBool = AtomicBool::new(false);
+    // The number of import groups currently being uploaded, and a
+    // condition signalled when it drops to zero:
+    static ref UPLOADS_IN_FLIGHT: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+}
+
+/// Counts an import group as in flight for as long as it is alive.
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        let (count, _) = &*UPLOADS_IN_FLIGHT;
+        *count.lock().unwrap() += 1;
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let (count, idle) = &*UPLOADS_IN_FLIGHT;
+        let mut count = count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            idle.notify_all();
+        }
+    }
+}
+
+/// A receiver for upload progress, allowing code that embeds the agent to
+/// observe uploads without parsing the output of the CLI.
+pub trait ProgressSink: Send + Sync {
+    /// Called with the updated record each time the progress of a file is
+    /// persisted to the database.
+    fn on_progress(&self, _record: &UploadRecord) {}
+}
+
+/// A `ProgressSink` that ignores all progress updates.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopProgressSink;
+
+impl ProgressSink for NoopProgressSink {}
+
 #[derive(Clone)]
 pub struct DatabaseUpdater {
     db: Database,
+    sink: Option<Arc<dyn ProgressSink>>,
+    upload_log: UploadLog,
 }
 
 impl DatabaseUpdater {
-    pub fn new(db: &Database) -> Self {
-        Self { db: db.clone() }
+    pub fn new(db: &Database, sink: Option<Arc<dyn ProgressSink>>) -> Self {
+        Self {
+            db: db.clone(),
+            sink,
+            upload_log: Default::default(),
+        }
+    }
+
+    /// Records the progress of each part in the log of the upload.
+    pub fn with_upload_log(self, upload_log: UploadLog) -> Self {
+        Self { upload_log, ..self }
+    }
+
+    /// Persists the progress of a file, then passes the updated upload
+    /// record to the progress sink, if one was provided. Updates that do
+    /// not advance the progress of the file are not forwarded.
+    fn update_file_progress<P: AsRef<Path>>(
+        &self,
+        import_id: &str,
+        file_path: P,
+        percent_done: i32,
+    ) -> Result<()> {
+        let file_path = file_path.as_ref();
+        let updated = self
+            .db
+            .update_file_progress(import_id, file_path, percent_done)?;
+
+        if let (Some(sink), true) = (&self.sink, updated > 0) {
+            let file_path = file_path.to_string_lossy();
+            self.db
+                .get_uploads_by_import_id(import_id)?
+                .iter()
+                .filter(|record| record.file_path == file_path)
+                .for_each(|record| sink.on_progress(record));
+        }
+
+        Ok(())
     }
 }
 
@@ -49,7 +135,8 @@ impl ProgressCallback for DatabaseUpdater {
         let part_number = update.part_number();
         let size = update.size();
         let is_done = update.is_done();
-        let percent_done = update.percent_done() as i32;
+        let percent_done = file_percent_done(size, is_done, update.percent_done() as i32);
+        heartbeat();
 
         debug!(
             "DatabaseUpdater::on_update({}) : {:?} => {}",
@@ -67,15 +154,40 @@ impl ProgressCallback for DatabaseUpdater {
             is_done,
         ));
 
-        if let Err(e) = self
-            .db
-            .update_file_progress(&import_id, &file_path, percent_done)
-        {
+        self.upload_log.log(
+            Level::Info,
+            format_args!(
+                "{:?}: part {} sent, {}/{} bytes ({}%){}",
+                file_path,
+                part_number,
+                bytes_sent,
+                size,
+                percent_done,
+                if is_done { ", done" } else { "" }
+            ),
+        );
+
+        if let Err(e) = self.update_file_progress(&import_id, &file_path, percent_done) {
             error!("upload-worker/database-updater :: {:?}", e);
+            self.upload_log.log(
+                Level::Error,
+                format_args!("Could not record progress: {}", e),
+            );
         }
     }
 }
 
+/// Returns the progress of a file as a percentage. The reported progress
+/// of an empty file is meaningless, as nothing is ever sent: it is done
+/// once the upload says so.
+fn file_percent_done(size: u64, is_done: bool, reported: i32) -> i32 {
+    match (size, is_done) {
+        (0, true) => 100,
+        (0, false) => 0,
+        _ => reported.max(0).min(100),
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
 trait IntoS3File {
@@ -87,14 +199,45 @@ impl IntoS3File for UploadRecord {
     fn into_s3_file(&self) -> Result<model::S3File> {
         let s3_file = model::S3File::from_file_path(self.file_path.clone(), None, None)?;
 
+        // Empty files have nothing to split into parts, so they are always
+        // sent in a single request:
+        if is_empty_file(&self.file_path) {
+            return Ok(s3_file.with_chunk_size(None).with_multipart_upload_id(None));
+        }
+
         Ok(s3_file
             .with_chunk_size(self.chunk_size)
             .with_multipart_upload_id(self.multipart_upload_id.clone().map(Into::into)))
     }
 }
 
+/// The failure reason of uploads that were still not done once their retry
+/// window passed.
+const RETRY_WINDOW_EXCEEDED: &str = "exceeded retry window";
+
+/// The failure reason of uploads whose session expired more often than the
+/// configured `upload_max_retries` allows.
+const RETRY_LIMIT_EXCEEDED: &str = "exceeded retry limit";
+
+/// Describes why an upload the Pennsieve platform rejected failed, to be
+/// recorded with its upload records.
+fn failure_reason(e: &pennsieve_rust::Error) -> String {
+    match e.kind() {
+        pennsieve_rust::ErrorKind::ApiError {
+            status_code: hyper::StatusCode::UNAUTHORIZED,
+            ..
+        }
+        | pennsieve_rust::ErrorKind::ApiError {
+            status_code: hyper::StatusCode::FORBIDDEN,
+            ..
+        } => "unauthorized".to_string(),
+        _ => e.to_string(),
+    }
+}
+
 /// Updates the upload status for a collection of `import_id`s.
-/// Each `import_id` can map to one or many records.
+/// Each `import_id` can map to one or many records. Uploads that are
+/// failed are given `RETRY_WINDOW_EXCEEDED` as the reason why.
 fn update_upload_statuses(
     db: &Database,
     records: &HashMap<String, Vec<UploadRecord>>,
@@ -110,7 +253,7 @@ fn update_upload_statuses(
             );
         }
         for key in keys {
-            db.update_import_status_and_progress(key, status, 0)?;
+            db.fail_import(key, RETRY_WINDOW_EXCEEDED, Some(0))?;
         }
     } else if !records.is_empty() {
         debug!(
@@ -146,15 +289,41 @@ fn update_import_status(
     }
 }
 
-/// Update an upload as failed, returning the original error in a future
+/// Records `e` as the last error of the upload with the given
+/// `import_id`, whether or not the attempt it failed is retried. A
+/// failure to record it is only logged, so it doesn't replace `e`.
+fn record_upload_error(db: &Database, import_id: &model::ImportId, e: &pennsieve_rust::Error) {
+    if let Err(other) = db.record_upload_error(import_id.borrow(), &e.to_string()) {
+        warn!(
+            "Couldn't record the error of import_id {:?}: {}",
+            import_id, other
+        );
+    }
+}
+
+/// Update an upload as failed for the given `reason`, returning the
+/// original error in a future
 fn fail_upload_with_error<T: 'static + Send>(
     db: &Database,
     import_id: &model::ImportId,
+    upload_log: &UploadLog,
+    reason: &str,
     e: Error,
 ) -> Result<T> {
-    match update_import_status(db, import_id, UploadStatus::Failed, None) {
-        Ok(_) => Err(e),          // return the previous error
-        Err(other) => Err(other), // otherwise, the new error
+    upload_log.log(Level::Error, format_args!("{}", e));
+    upload_log.log(
+        Level::Info,
+        format_args!("Status: {} ({})", UploadStatus::Failed, reason),
+    );
+    debug!(
+        "Transitioning import_id {:?} to {}: {}",
+        import_id,
+        UploadStatus::Failed,
+        reason
+    );
+    match db.fail_import(import_id.borrow(), reason, None) {
+        Ok(_) => Err(e),                 // return the previous error
+        Err(other) => Err(other.into()), // otherwise, the new error
     }
 }
 
@@ -193,14 +362,15 @@ fn upload_recursive(
     base_path: PathBuf,
     append: bool,
     retry_number: u16,
-    parallelism: usize,
+    max_retries: u16,
+    parallelism: Parallelism,
+    sink: Option<Arc<dyn ProgressSink>>,
+    upload_log: UploadLog,
 ) -> Future<()> {
-    // the maximum amount of times we will refresh the user's token
-    // during a single upload. a single upload cannot run
-    // uninterrupted for more than 90 * MAX_RETRIES minutes.
-    const MAX_RETRIES: u16 = 10;
-
-    let updater = DatabaseUpdater::new(&db);
+    // `max_retries` is the maximum amount of times we will refresh the
+    // user's token during a single upload. a single upload cannot run
+    // uninterrupted for more than 90 * max_retries minutes.
+    let updater = DatabaseUpdater::new(&db, sink.clone()).with_upload_log(upload_log.clone());
 
     // clone all arguments in case we need to retry this function
     let api_retry = api.clone();
@@ -210,92 +380,193 @@ fn upload_recursive(
     let dataset_id_retry = dataset_id.clone();
     let package_id_retry = package_id.clone();
     let s3_files_retry = s3_files.clone();
-
-    api.client()
-        .upload_file_chunks_with_retries(
-            &organization_id.clone(),
-            &import_id.clone(),
-            &base_path,
-            s3_files,
-            updater,
-            parallelism,
-        )
-        .for_each(|import_id| {
-            debug!("Done uploading {:?}", import_id);
-            Ok(())
-        })
-        // If one file that is part of a collection of files
-        // associated with an import Id fails, the whole batch
-        // has to fail.
-        .or_else(move |e| {
-            debug!("Upload error => {:?}", e);
-            match e.kind() {
-                pennsieve_rust::ErrorKind::ApiError {
-                    status_code: hyper::StatusCode::UNAUTHORIZED,
-                    ..
-                } if retry_number < MAX_RETRIES => {
-                    debug!(
+    let upload_log_retry = upload_log.clone();
+    let upload_log_complete = upload_log.clone();
+
+    // Set if a drain leaves some of the files unsent, in which case the
+    // upload isn't completed:
+    let drained = Arc::new(AtomicBool::new(false));
+    let inner_drained = drained.clone();
+    let drained_import_id = import_id.clone();
+
+    let ps = api.client().clone();
+    let upload_organization_id = organization_id.clone();
+    let upload_import_id = import_id.clone();
+    let upload_base_path = base_path.clone();
+    upload_files(
+        s3_files,
+        parallelism,
+        move || {
+            let draining = Uploader::is_draining();
+            if draining {
+                inner_drained.store(true, Ordering::SeqCst);
+            }
+            draining
+        },
+        move |s3_file, chunks| {
+            ps.upload_file_chunks_with_retries(
+                &upload_organization_id,
+                &upload_import_id,
+                &upload_base_path,
+                vec![s3_file],
+                updater.clone(),
+                chunks,
+            )
+        },
+    )
+    .for_each(|import_id| {
+        debug!("Done uploading {:?}", import_id);
+        Ok(())
+    })
+    // If one file that is part of a collection of files
+    // associated with an import Id fails, the whole batch
+    // has to fail.
+    .or_else(move |e| {
+        debug!("Upload error => {:?}", e);
+        record_upload_error(&db_retry, &import_id_retry, &e);
+        match e.kind() {
+            pennsieve_rust::ErrorKind::ApiError {
+                status_code: hyper::StatusCode::UNAUTHORIZED,
+                ..
+            } if retry_number < max_retries => {
+                debug!(
+                    "Token expired, refreshing [{}/{}]...",
+                    retry_number + 1,
+                    max_retries
+                );
+                upload_log_retry.log(
+                    Level::Warn,
+                    format_args!(
                         "Token expired, refreshing [{}/{}]...",
                         retry_number + 1,
-                        MAX_RETRIES
-                    );
-                    let api_retry_clone = api_retry.clone();
-                    Delay::new(Instant::now() + Duration::from_secs(10))
-                        .map_err(Into::into)
-                        .and_then(move |_| api_retry_clone.get_user_and_refresh())
-                        .and_then(move |_| {
-                            upload_recursive(
-                                db_retry,
-                                api_retry,
-                                s3_files_retry,
-                                import_id_retry,
-                                organization_id_retry,
-                                dataset_id_retry,
-                                package_id_retry,
-                                base_path,
-                                append,
-                                retry_number + 1,
-                                parallelism,
-                            )
-                        })
-                        .into_trait()
-                }
-                _ => fail_upload_with_error(&db_retry, &import_id_retry, Error::upload_failed(e))
+                        max_retries
+                    ),
+                );
+                let api_retry_clone = api_retry.clone();
+                Delay::new(Instant::now() + Duration::from_secs(10))
                     .map_err(Into::into)
-                    .into_future()
-                    .into_trait(),
+                    .and_then(move |_| api_retry_clone.get_user_and_refresh())
+                    .and_then(move |_| {
+                        upload_recursive(
+                            db_retry,
+                            api_retry,
+                            s3_files_retry,
+                            import_id_retry,
+                            organization_id_retry,
+                            dataset_id_retry,
+                            package_id_retry,
+                            base_path,
+                            append,
+                            retry_number + 1,
+                            max_retries,
+                            parallelism,
+                            sink,
+                            upload_log_retry,
+                        )
+                    })
+                    .into_trait()
             }
-        })
-        .map(move |_| {
-            (
-                api.client().clone(),
-                db.clone(),
-                import_id.clone(),
-                dataset_id.clone(),
-                organization_id,
+            pennsieve_rust::ErrorKind::ApiError {
+                status_code: hyper::StatusCode::UNAUTHORIZED,
+                ..
+            } => fail_upload_with_error(
+                &db_retry,
+                &import_id_retry,
+                &upload_log_retry,
+                RETRY_LIMIT_EXCEEDED,
+                Error::upload_failed(e),
             )
-        })
-        .and_then(move |(ps, db, import_id, dataset_id, organization_id)| {
-            debug!("Completing (platform): {:?}", import_id);
-            let import_id_copy = import_id.clone();
-            let db_copy = db.clone();
-            ps.complete_upload(
-                &organization_id,
-                &import_id,
-                &dataset_id,
-                package_id.as_ref(),
-                append,
+            .map_err(Into::into)
+            .into_future()
+            .into_trait(),
+            _ => fail_upload_with_error(
+                &db_retry,
+                &import_id_retry,
+                &upload_log_retry,
+                &failure_reason(&e),
+                Error::upload_failed(e),
             )
-            .or_else(move |e| fail_upload_with_error(&db, &import_id, Error::upload_failed(e)))
             .map_err(Into::into)
-            .map(|_| (db_copy, import_id_copy))
-        })
-        .and_then(move |(db, import_id)| {
-            debug!("Completing (db): {:?}", import_id);
-            update_import_status(&db, &import_id, UploadStatus::Completed, Some(100))
-                .map_err(Into::into)
+            .into_future()
+            .into_trait(),
+        }
+    })
+    .and_then(move |_| -> agent::Result<()> {
+        if drained.load(Ordering::SeqCst) {
+            let import_id: &str = drained_import_id.borrow();
+            return Err(Error::drained(import_id).into());
+        }
+        Ok(())
+    })
+    .map(move |_| {
+        (
+            api.client().clone(),
+            db.clone(),
+            import_id.clone(),
+            dataset_id.clone(),
+            organization_id,
+        )
+    })
+    .and_then(move |(ps, db, import_id, dataset_id, organization_id)| {
+        debug!("Completing (platform): {:?}", import_id);
+        let import_id_copy = import_id.clone();
+        let db_copy = db.clone();
+        ps.complete_upload(
+            &organization_id,
+            &import_id,
+            &dataset_id,
+            package_id.as_ref(),
+            append,
+        )
+        .or_else(move |e| {
+            record_upload_error(&db, &import_id, &e);
+            let reason = failure_reason(&e);
+            fail_upload_with_error(
+                &db,
+                &import_id,
+                &upload_log,
+                &reason,
+                Error::upload_failed(e),
+            )
         })
-        .into_trait()
+        .map_err(Into::into)
+        .map(|_| (db_copy, import_id_copy))
+    })
+    .and_then(move |(db, import_id)| {
+        debug!("Completing (db): {:?}", import_id);
+        upload_log_complete.log(
+            Level::Info,
+            format_args!("Status: {}", UploadStatus::Completed),
+        );
+        update_import_status(&db, &import_id, UploadStatus::Completed, Some(100))
+            .map_err(Into::into)
+    })
+    .into_trait()
+}
+
+/// Sends `files` with `transport`, which sends one file in up to the given
+/// number of chunks at a time. Files are sent `parallelism.files` at a
+/// time, each in up to `parallelism.chunks` chunks at a time, yielding
+/// what `transport` yields for each. Should sending any file fail, the
+/// stream fails. Once `draining` returns true, no more files are started,
+/// and the stream ends when the files already started have been sent.
+fn upload_files<F, D, T, S>(
+    files: Vec<F>,
+    parallelism: Parallelism,
+    draining: D,
+    transport: T,
+) -> impl Stream<Item = S::Item, Error = S::Error>
+where
+    D: Fn() -> bool,
+    T: Fn(F, usize) -> S,
+    S: Stream,
+{
+    stream::iter_ok::<_, S::Error>(files)
+        .take_while(move |_| Ok(!draining()))
+        .map(move |file| transport(file, parallelism.chunks).collect())
+        .buffer_unordered(parallelism.files)
+        .map(stream::iter_ok)
+        .flatten()
 }
 
 /// Performs the actual file uploading operation for a given import group.
@@ -305,9 +576,13 @@ fn upload(
     db: Database,
     api: Api,
     group: ImportGroup,
-    parallelism: usize,
+    parallelism: Parallelism,
+    max_retries: u16,
+    sink: Option<Arc<dyn ProgressSink>>,
+    upload_log_dir: Option<PathBuf>,
 ) -> Future<model::ImportId> {
     let (import_id, uploads) = group;
+    let upload_log = UploadLog::open(upload_log_dir, &import_id);
 
     // Only append if all `append` properties in the import group are also
     // set to `append=true`:
@@ -351,6 +626,18 @@ fn upload(
         uploads.len(),
         append
     );
+    upload_log.log(
+        Level::Info,
+        format_args!(
+            "Uploading import_id: {:?} with {} files; append = {}",
+            import_id,
+            uploads.len(),
+            append
+        ),
+    );
+    for upload in &uploads {
+        upload_log.log(Level::Info, format_args!("File: {:?}", upload.file_path));
+    }
 
     try_future!(update_import_status(
         &db,
@@ -360,6 +647,24 @@ fn upload(
     ));
 
     let completed_import_id = import_id.clone();
+    // Tags given with `upload --tag` are set on the packages the upload
+    // creates once it completes:
+    let tags = uploads
+        .first()
+        .map(|upload| upload.tags.clone())
+        .unwrap_or_default();
+    let tag_api = api.clone();
+    let tagged_import_id = import_id.clone();
+    let tag_dataset_id = dataset_id.clone();
+    let tag_package_id = package_id.clone();
+    let names = uploaded_names(&base_path, &uploads);
+    // Data read from stdin was spooled to a file of its own, which is no
+    // longer needed once it has been uploaded:
+    let spooled: Vec<String> = uploads
+        .iter()
+        .filter(|upload| stdin::is_spooled(&upload.file_path))
+        .map(|upload| upload.file_path.clone())
+        .collect();
 
     upload_recursive(
         db.clone(),
@@ -372,115 +677,235 @@ fn upload(
         base_path,
         append,
         0,
+        max_retries,
         parallelism,
+        sink,
+        upload_log,
     )
-    .and_then(|_| Ok(completed_import_id))
+    .and_then(move |_| -> Future<()> {
+        if tags.is_empty() {
+            return future::ok(()).into_trait();
+        }
+        // Appended files don't create packages of their own, so the package
+        // they were appended to is tagged instead:
+        let tagged = match tag_package_id {
+            Some(package_id) if append => tag_api.set_package_properties(package_id, tags),
+            package_id => tag_api.tag_uploaded_packages(tag_dataset_id, package_id, names, tags),
+        };
+        // The files were uploaded regardless, so failing to tag them only
+        // warrants a warning:
+        tagged
+            .or_else(move |e| {
+                warn!("Couldn't tag the uploads of {:?}: {}", tagged_import_id, e);
+                Ok::<_, agent::Error>(())
+            })
+            .into_trait()
+    })
+    .and_then(move |_| {
+        for path in spooled {
+            stdin::remove_spooled(path);
+        }
+        Ok(completed_import_id)
+    })
     .into_trait()
 }
 
+/// The names of the packages and collections an import group creates at
+/// the top of its target: the first component of the path of each file
+/// relative to `base_path`.
+fn uploaded_names(base_path: &Path, uploads: &[UploadRecord]) -> HashSet<String> {
+    uploads
+        .iter()
+        .filter_map(|upload| {
+            let path = upload::target_path(Some(base_path), Path::new(&upload.file_path));
+            path.split('/').next().map(String::from)
+        })
+        .collect()
+}
+
+/// Uploads each import group in turn. Once a drain has begun, the files
+/// of the group in flight that were already started are allowed to
+/// finish, but its remaining files and the remaining groups are left as
+/// they are for the next time the agent runs.
+fn upload_groups<I, F>(groups: I, upload: F) -> Future<()>
+where
+    I: IntoIterator<Item = ImportGroup>,
+    I::IntoIter: 'static + Send,
+    F: 'static + Send + Fn(ImportGroup) -> Future<()>,
+{
+    let f = stream::iter_ok::<_, agent::Error>(groups).for_each(move |group| {
+        if Uploader::is_draining() {
+            debug!("Draining: not starting import_id {}", group.0);
+            return future::ok(()).into_trait();
+        }
+        let in_flight = InFlight::start();
+        upload(group)
+            .then(move |result| {
+                drop(in_flight);
+                match result {
+                    Err(ref e) if is_drained(e) => Ok(()),
+                    result => result,
+                }
+            })
+            .into_trait()
+    });
+
+    f::to_future_trait(f)
+}
+
+/// Tests if `e` is an upload left unfinished by a drain.
+fn is_drained(e: &agent::Error) -> bool {
+    match e.kind() {
+        agent::ErrorKind::UploadError {
+            kind: upload::ErrorKind::Drained { .. },
+        } => true,
+        _ => false,
+    }
+}
+
+/// Orders import groups for uploading: groups holding a higher priority
+/// upload go first, then older groups before newer ones.
+fn by_priority(groups: HashMap<String, Vec<UploadRecord>>) -> Vec<ImportGroup> {
+    let mut groups: Vec<ImportGroup> = groups.into_iter().collect();
+    groups.sort_by_key(|(_, records)| {
+        let priority = records.iter().map(|r| r.priority).max();
+        let created_at = records.iter().map(|r| r.created_at).min();
+        (Reverse(priority), created_at)
+    });
+    groups
+}
+
 // Note: The implemention of the `step` function was moved into a private,
 // top-level function due to the restrictions placed on `Future`s by the
 // new version of Tokio (>0.17). `Future`s must have a 'static lifetime and
 // be `Send`able. The use of `self` in a closure of the returned `Future`
 // made returning a `Future` with a 'static lifetime was not possible.
 
-/// Runs one upload step. One step consists of the following:
-/// - Get queued and in_progress upload records.
-/// - Merge and group by import_id.
-/// - Get grant access to s3.
-/// - Perform upload to s3.
-/// - Call api /complete endpoint.
-fn step(db: Database, api: &Api, parallelism: usize) -> Future<()> {
-    // Get all uploads that are of `UploadStatus::Queued` status.
-    let queued: Result<HashMap<String, Vec<UploadRecord>>> = db
-        .get_queued_uploads()
-        .map(|uploads| {
-            uploads
-                .into_owned_iter()
-                .map(|upload| (upload.import_id.clone(), upload))
-                .into_group_map()
-        })
-        .map_err(Into::<Error>::into);
+/// Gets the uploads the next step works on, grouped by `import_id`: those
+/// that are queued, and those in progress that are due a retry. In progress
+/// uploads past their retry window are marked failed instead.
+///
+/// Only the uploads of `import_ids` are looked at, if given.
+pub(crate) fn pending_uploads(
+    db: &Database,
+    thresholds: UploadThresholds,
+    import_ids: Option<&HashSet<String>>,
+) -> Result<HashMap<String, Vec<UploadRecord>>> {
+    let wanted =
+        |upload: &UploadRecord| import_ids.map_or(true, |ids| ids.contains(&upload.import_id));
 
-    let queued: HashMap<String, Vec<UploadRecord>> = match queued {
-        Ok(queued) => queued,
-        Err(e) => return future::err(e.into()).into_trait(),
-    };
+    // Get all uploads that are of `UploadStatus::Queued` status.
+    let mut pending: HashMap<String, Vec<UploadRecord>> = db
+        .get_queued_uploads()?
+        .into_owned_iter()
+        .filter(|upload| wanted(upload))
+        .map(|upload| (upload.import_id.clone(), upload))
+        .into_group_map();
 
     // Get all uploads that are of `UploadStatus::InProgress` status
     // and filter the results to only include records that will attempt
     // a retry. Records that will not be retried will be transitioned
     // to a `UploadStatus::Failed` status.
-    let in_progress: Result<HashMap<String, Vec<UploadRecord>>> = db
-        .get_in_progress_uploads()
-        .map(|uploads| {
-            uploads
-                .into_owned_iter()
-                .map(|upload| (upload.import_id.clone(), upload))
-                .into_group_map()
-                .into_iter()
+    let (failed, retry): (HashMap<_, _>, HashMap<_, _>) = db
+        .get_in_progress_uploads()?
+        .into_owned_iter()
+        .filter(|upload| wanted(upload))
+        .map(|upload| (upload.import_id.clone(), upload))
+        .into_group_map()
+        .into_iter()
+        .filter(|&(_, ref records)| {
+            records
+                .first()
+                .map_or(false, |record| record.should_retry(thresholds))
         })
-        .map(|iter| {
-            iter.filter(|&(_, ref records)| {
-                records
-                    .first()
-                    .map_or(false, |record| record.should_retry())
-            })
-            .partition(|&(_, ref records)| {
-                records.first().map_or(true, |record| record.should_fail())
-            })
-        })
-        .map_err(Into::<Error>::into)
-        .and_then(|(failed, retry)| {
-            update_upload_statuses(&db, &failed, UploadStatus::Failed).map(|_| retry)
+        .partition(|&(_, ref records)| {
+            records
+                .first()
+                .map_or(true, |record| record.should_fail(thresholds))
         });
+    update_upload_statuses(db, &failed, UploadStatus::Failed)?;
+
+    // The attempt that stalled left no error behind, so why the upload is
+    // retried is recorded instead:
+    for import_id in retry.keys() {
+        let message = format!(
+            "no progress for {} minutes; retrying",
+            thresholds.retry_after.num_minutes()
+        );
+        if let Err(e) = db.record_upload_error(import_id, &message) {
+            warn!(
+                "Couldn't record the error of import_id {:?}: {}",
+                import_id, e
+            );
+        }
+    }
 
-    let in_progress: HashMap<String, Vec<UploadRecord>> = match in_progress {
-        Ok(in_progress) => in_progress,
-        Err(e) => return future::err(e.into()).into_trait(),
-    };
+    pending.extend(retry);
+    Ok(pending)
+}
+
+/// Runs one upload step. One step consists of the following:
+/// - Get queued and in_progress upload records, only those of
+///   `import_ids` if given.
+/// - Merge and group by import_id.
+/// - Get grant access to s3.
+/// - Perform upload to s3.
+/// - Call api /complete endpoint.
+pub(crate) fn step(
+    db: Database,
+    api: &Api,
+    parallelism: Parallelism,
+    sink: Option<Arc<dyn ProgressSink>>,
+    upload_log_dir: Option<PathBuf>,
+    thresholds: UploadThresholds,
+    import_ids: Option<&HashSet<String>>,
+) -> Future<()> {
+    // Don't start any new work once a drain has begun:
+    if Uploader::is_draining() {
+        return Ok(()).into_future().into_trait();
+    }
+
+    let pending = try_future!(pending_uploads(&db, thresholds, import_ids));
 
     // If there are no queued or in-progress uploads, bail out early:
-    if queued.is_empty() && in_progress.is_empty() {
+    if pending.is_empty() {
         return Ok(()).into_future().into_trait();
     }
 
-    // Get an active Pennsieve API user and combine the `queued` and
-    // `in_progress` records into one stream. The records are combined
-    // by grouping by their `import_id`. Each grouping gets uploaded.
+    // Get an active Pennsieve API user and upload the pending records,
+    // which are grouped by their `import_id`. Each grouping gets uploaded.
     let inner_api = api.clone();
     api.get_user_and_refresh()
         .and_then(move |_| {
-            let mut pending = queued;
-            pending.extend(in_progress);
-            Ok(stream::iter_ok::<_, Error>(pending))
-        })
-        .and_then(move |stream| {
-            stream
-                .map_err(Into::<agent::Error>::into)
-                .for_each(move |import_group| {
-                    upload(
-                        db.clone(),
-                        inner_api.clone(),
-                        import_group.clone(),
-                        parallelism,
-                    )
-                    .map_err(move |e| {
+            upload_groups(by_priority(pending), move |import_group| {
+                upload(
+                    db.clone(),
+                    inner_api.clone(),
+                    import_group.clone(),
+                    parallelism,
+                    thresholds.max_retries,
+                    sink.clone(),
+                    upload_log_dir.clone(),
+                )
+                .map_err(move |e| {
+                    // An upload stopped by a drain hasn't failed:
+                    if !is_drained(&e) {
                         let (import_id, _) = import_group;
                         a::send_unconditionally::<server::StatusServer, _>(Response::upload_error(
                             e.clone(),
                             import_id,
                         ));
-                        e
-                    })
-                    .and_then(|import_id| {
-                        a::send_unconditionally::<server::StatusServer, _>(
-                            Response::upload_complete(import_id),
-                        );
-                        Ok(())
-                    })
+                    }
+                    e
                 })
-                .map(|_| ())
+                .and_then(|import_id| {
+                    a::send_unconditionally::<server::StatusServer, _>(Response::upload_complete(
+                        import_id,
+                    ));
+                    Ok(())
+                })
+                .into_trait()
+            })
         })
         .into_trait()
 }
@@ -491,11 +916,53 @@ fn step(db: Database, api: &Api, parallelism: usize) -> Future<()> {
 #[derive(Default)]
 pub struct Uploader;
 
+/// How much of an upload is sent at once: up to `files` files of an
+/// import group, each in up to `chunks` chunks at a time. At most
+/// `files * chunks` chunks are in flight in all.
+///
+/// A few huge files upload fastest with many chunks at once, and many
+/// small files with many files at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Parallelism {
+    pub files: usize,
+    pub chunks: usize,
+}
+
+impl Parallelism {
+    /// Sends up to `files` files at once, each in up to `chunks` chunks at
+    /// a time. Neither can be less than one.
+    pub fn new(files: usize, chunks: usize) -> Self {
+        Self {
+            files: files.max(1),
+            chunks: chunks.max(1),
+        }
+    }
+
+    /// Sends up to `level` files at once, each in up to `level` chunks at
+    /// a time, as `--parallelism` does.
+    pub fn level(level: usize) -> Self {
+        Self::new(level, level)
+    }
+}
+
+impl Default for Parallelism {
+    /// One file at a time, in one chunk at a time.
+    fn default() -> Self {
+        Self::level(1)
+    }
+}
+
 #[derive(Clone)]
 pub struct Props {
     pub api: Api,
     pub db: Database,
-    pub parallelism: usize,
+    pub file_parallelism: usize,
+    pub chunk_parallelism: usize,
+    pub progress_sink: Option<Arc<dyn ProgressSink>>,
+    // The directory to write a log file for each upload to, if any:
+    pub upload_log_dir: Option<PathBuf>,
+    // When stalled uploads are retried, and when they are given up on:
+    pub thresholds: UploadThresholds,
 }
 
 impl Actor for Uploader {
@@ -568,6 +1035,15 @@ impl Handler<QueueUpload> for Uploader {
     }
 }
 
+// Records that the upload worker is alive. Steps wait for their uploads to
+// finish, so upload progress counts as a heartbeat too.
+fn heartbeat() {
+    server::heartbeat::beat(
+        Uploader.id(),
+        Duration::from_secs(config::constants::UPLOAD_WORKER_STALL_SECS),
+    );
+}
+
 impl Worker for Uploader {
     fn id(&self) -> ServiceId {
         ServiceId("Uploader")
@@ -575,6 +1051,40 @@ impl Worker for Uploader {
 }
 
 impl Uploader {
+    /// Signals every upload worker to stop starting new uploads, so that
+    /// the agent can shut down once the uploads in flight have finished
+    /// and their records have been updated.
+    pub fn begin_drain() {
+        info!("Draining uploads");
+        DRAINING.store(true, Ordering::SeqCst);
+    }
+
+    /// Tests if a drain has begun.
+    pub fn is_draining() -> bool {
+        DRAINING.load(Ordering::SeqCst)
+    }
+
+    /// Tests if no uploads are in flight.
+    pub fn is_idle() -> bool {
+        *UPLOADS_IN_FLIGHT.0.lock().unwrap() == 0
+    }
+
+    /// Waits for the uploads in flight to finish, for at most `timeout`.
+    /// Returns whether they did.
+    pub fn wait_until_idle(timeout: Duration) -> bool {
+        let (count, idle) = &*UPLOADS_IN_FLIGHT;
+        let deadline = Instant::now() + timeout;
+        let mut count = count.lock().unwrap();
+        while *count > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            count = idle.wait_timeout(count, deadline - now).unwrap().0;
+        }
+        true
+    }
+
     /// Runs one upload step. One step consists of the following:
     /// - Get queued and in_progress upload records.
     /// - Merge and group by import_id.
@@ -588,7 +1098,15 @@ impl Uploader {
         self.borrow_props(|props: Option<&Props>| {
             let props: &Props = props.unwrap_or_else(|| panic!("{:?}: missing props", id));
             debug!("Running upload step");
-            step(props.db.clone(), &props.api, props.parallelism)
+            step(
+                props.db.clone(),
+                &props.api,
+                Parallelism::new(props.file_parallelism, props.chunk_parallelism),
+                props.progress_sink.clone(),
+                props.upload_log_dir.clone(),
+                props.thresholds,
+                None,
+            )
         })
     }
 
@@ -599,7 +1117,10 @@ impl Uploader {
             .unwrap_or_else(|| panic!("{:?}: missing props", id));
         let api = props.api;
         let db = props.db;
-        let parallelism = props.parallelism;
+        let parallelism = Parallelism::new(props.file_parallelism, props.chunk_parallelism);
+        let sink = props.progress_sink;
+        let upload_log_dir = props.upload_log_dir;
+        let thresholds = props.thresholds;
 
         // run one upload step every N seconds:
         let timer = Interval::new(
@@ -619,13 +1140,32 @@ impl Uploader {
             Err(e) => return future::err(e.into()).into_trait(),
         };
 
+        // Data spooled from stdin that no upload needs any longer, such as
+        // that of uploads that failed long ago, or that was left behind by
+        // an agent that stopped before it could clean up:
+        if let Err(e) = stdin::spool_dir()
+            .and_then(|dir| stdin::sweep(&db, &dir, thresholds.fail_after).map_err(Into::into))
+        {
+            warn!("Couldn't sweep the stdin spool directory: {}", e);
+        }
+
         // Create a future based stream that will perform one upload
         // step based on the timer. This future will always return the
         // `Ok(())`, this is because `stream::for_each` terminates the stream
         // on `Err` conditions.
         let f = timer
             .for_each(move |_| {
-                step(db.clone(), &api, parallelism).then(|res| match res {
+                heartbeat();
+                step(
+                    db.clone(),
+                    &api,
+                    parallelism,
+                    sink.clone(),
+                    upload_log_dir.clone(),
+                    thresholds,
+                    None,
+                )
+                .then(|res| match res {
                     Ok(_) => Ok(()),
                     Err(e) => {
                         warn!("Uploader step failed: {:?}", e);
@@ -638,3 +1178,432 @@ impl Uploader {
         f::to_future_trait(f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use tokio::runtime::current_thread::Runtime;
+
+    use crate::ps::agent::upload::ErrorKind;
+    use crate::ps::util;
+
+    /// Ends a drain begun by a test when dropped, even if the test fails.
+    struct ResetDrain;
+
+    impl Drop for ResetDrain {
+        fn drop(&mut self) {
+            DRAINING.store(false, Ordering::SeqCst);
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingProgressSink {
+        progress: Mutex<Vec<i32>>,
+    }
+
+    impl ProgressSink for RecordingProgressSink {
+        fn on_progress(&self, record: &UploadRecord) {
+            self.progress.lock().unwrap().push(record.progress);
+        }
+    }
+
+    /// Counts how many of something are in flight, and the most that ever
+    /// were at once.
+    #[derive(Default)]
+    struct Gauge {
+        current: Mutex<(usize, usize)>,
+    }
+
+    impl Gauge {
+        fn start(&self) {
+            let mut current = self.current.lock().unwrap();
+            current.0 += 1;
+            current.1 = current.1.max(current.0);
+        }
+
+        fn stop(&self) {
+            self.current.lock().unwrap().0 -= 1;
+        }
+
+        fn max(&self) -> usize {
+            self.current.lock().unwrap().1
+        }
+    }
+
+    #[test]
+    fn files_and_chunks_are_sent_as_parallel_as_asked() {
+        const CHUNKS_PER_FILE: usize = 6;
+
+        for &(files, chunks) in &[(1, 4), (3, 1), (2, 3)] {
+            let files_in_flight = Arc::new(Gauge::default());
+            let chunks_in_flight = Arc::new(Gauge::default());
+
+            // Sends each chunk of a file in turn, after a short wait,
+            // keeping track of what is in flight:
+            let transport = {
+                let files_in_flight = files_in_flight.clone();
+                let chunks_in_flight = chunks_in_flight.clone();
+                move |file: usize, parallelism: usize| {
+                    let files_in_flight = files_in_flight.clone();
+                    let chunks_in_flight = chunks_in_flight.clone();
+                    let file_done = files_in_flight.clone();
+                    future::lazy(move || {
+                        files_in_flight.start();
+                        stream::iter_ok::<_, ()>(0..CHUNKS_PER_FILE)
+                            .map(move |_| {
+                                let chunk_done = chunks_in_flight.clone();
+                                chunks_in_flight.start();
+                                Delay::new(Instant::now() + Duration::from_millis(5))
+                                    .map_err(|_| ())
+                                    .map(move |_| chunk_done.stop())
+                            })
+                            .buffer_unordered(parallelism)
+                            .collect()
+                    })
+                    .map(move |_| {
+                        file_done.stop();
+                        file
+                    })
+                    .into_stream()
+                }
+            };
+
+            let mut sent = Runtime::new()
+                .unwrap()
+                .block_on(
+                    upload_files(
+                        (0..6).collect(),
+                        Parallelism::new(files, chunks),
+                        || false,
+                        transport,
+                    )
+                    .collect(),
+                )
+                .unwrap();
+            sent.sort();
+
+            assert_eq!(sent, (0..6).collect::<Vec<_>>());
+            assert_eq!(files_in_flight.max(), files);
+            assert_eq!(chunks_in_flight.max(), files * chunks);
+        }
+    }
+
+    #[test]
+    fn uploaded_names_are_the_top_of_each_path() {
+        let record = |file_path: &str| {
+            UploadRecord::new(
+                file_path,
+                "N:dataset:1",
+                None as Option<String>,
+                "N:organization:1",
+                "import_1",
+                false,
+                None,
+                None,
+            )
+            .unwrap()
+        };
+        let uploads = vec![
+            record("/data/a.edf"),
+            record("/data/b.edf"),
+            record("/data/sessions/1/c.edf"),
+            record("/data/sessions/2/d.edf"),
+        ];
+
+        let mut names: Vec<String> = uploaded_names(Path::new("/data"), &uploads)
+            .into_iter()
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a.edf", "b.edf", "sessions"]);
+    }
+
+    #[test]
+    fn groups_are_uploaded_by_priority_then_age() {
+        let now = time::now().to_timespec();
+        let record = |import_id: &str, priority: i32, age_in_minutes: i64| {
+            let mut record = UploadRecord::new(
+                "/tmp/priority.txt",
+                "N:dataset:1",
+                None as Option<String>,
+                "N:organization:1",
+                import_id,
+                false,
+                None,
+                None,
+            )
+            .unwrap()
+            .with_priority(priority);
+            record.created_at = now - time::Duration::minutes(age_in_minutes);
+            record
+        };
+
+        let mut groups = HashMap::new();
+        groups.insert("old".to_string(), vec![record("old", 0, 60)]);
+        groups.insert("new".to_string(), vec![record("new", 0, 1)]);
+        groups.insert(
+            "urgent".to_string(),
+            vec![record("urgent", 0, 2), record("urgent", 5, 2)],
+        );
+        groups.insert("backlog".to_string(), vec![record("backlog", -1, 120)]);
+
+        let order: Vec<String> = by_priority(groups)
+            .into_iter()
+            .map(|(import_id, _)| import_id)
+            .collect();
+        assert_eq!(order, vec!["urgent", "old", "new", "backlog"]);
+    }
+
+    #[test]
+    fn progress_sink_receives_persisted_progress() {
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            "/tmp/progress.txt",
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        db.insert_upload(&record).unwrap();
+
+        let sink = Arc::new(RecordingProgressSink::default());
+        let updater = DatabaseUpdater::new(&db, Some(sink.clone()));
+
+        for percent_done in &[10, 50, 30, 100] {
+            updater
+                .update_file_progress("import-1", "/tmp/progress.txt", *percent_done)
+                .unwrap();
+        }
+
+        // progress that goes backwards is not persisted, and so is not
+        // reported to the sink:
+        assert_eq!(*sink.progress.lock().unwrap(), vec![10, 50, 100]);
+    }
+
+    #[test]
+    fn empty_files_complete_once_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("empty.txt");
+        std::fs::write(&file_path, "").unwrap();
+
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            &file_path,
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            Some(5 * 1024 * 1024),
+            Some("multipart-1".to_string()),
+        )
+        .unwrap();
+        db.insert_upload(&record).unwrap();
+
+        // nothing is chunked for an empty file:
+        let s3_file = record.into_s3_file().unwrap();
+        assert!(s3_file.chunked_upload().is_none());
+        assert!(s3_file.multipart_upload_id().is_none());
+
+        assert_eq!(file_percent_done(0, false, 0), 0);
+        let updater = DatabaseUpdater::new(&db, None);
+        updater
+            .update_file_progress("import-1", &file_path, file_percent_done(0, true, 0))
+            .unwrap();
+
+        let uploads = db.get_uploads_by_import_id("import-1").unwrap();
+        assert!(uploads.iter().all(UploadRecord::is_file_upload_completed));
+    }
+
+    #[test]
+    fn failed_uploads_are_recorded_in_their_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            "/tmp/failing.txt",
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        db.insert_upload(&record).unwrap();
+
+        let upload_log = UploadLog::open(Some(dir.path()), "import-1");
+        let result: Result<()> = fail_upload_with_error(
+            &db,
+            &model::ImportId::new("import-1"),
+            &upload_log,
+            RETRY_LIMIT_EXCEEDED,
+            ErrorKind::UploadFailed {
+                message: "connection reset by peer".to_string(),
+            }
+            .into(),
+        );
+        assert!(result.is_err());
+
+        let uploads = db.get_uploads_by_import_id("import-1").unwrap();
+        assert!(uploads.iter().all(|r| r.status == UploadStatus::Failed));
+        assert!(uploads
+            .iter()
+            .all(|r| r.failure_reason == Some(RETRY_LIMIT_EXCEEDED.to_string())));
+
+        let contents =
+            std::fs::read_to_string(UploadLog::file_path(dir.path(), "import-1")).unwrap();
+        assert!(contents.contains("import-1"));
+        assert!(contents.contains("Upload failed: connection reset by peer"));
+        assert!(contents.contains("Status: FAILED (exceeded retry limit)"));
+    }
+
+    #[test]
+    fn noop_progress_sink_does_not_affect_updates() {
+        let db = util::database::temp().unwrap();
+        let record = UploadRecord::new(
+            "/tmp/progress.txt",
+            "N:dataset:1",
+            None as Option<String>,
+            "N:organization:1",
+            "import-1",
+            false,
+            None,
+            None,
+        )
+        .unwrap();
+        db.insert_upload(&record).unwrap();
+
+        let updater = DatabaseUpdater::new(&db, Some(Arc::new(NoopProgressSink)));
+        updater
+            .update_file_progress("import-1", "/tmp/progress.txt", 42)
+            .unwrap();
+
+        let uploads = db.get_uploads_by_import_id("import-1").unwrap();
+        assert_eq!(
+            uploads.iter().map(|r| r.progress).collect::<Vec<_>>(),
+            vec![42]
+        );
+    }
+
+    #[test]
+    fn drain_finishes_the_upload_in_flight() {
+        let db = util::database::temp().unwrap();
+        let group = |import_id: &str| {
+            let record = UploadRecord::new(
+                format!("/tmp/{}.txt", import_id),
+                "N:dataset:1",
+                None as Option<String>,
+                "N:organization:1",
+                import_id,
+                false,
+                None,
+                None,
+            )
+            .unwrap();
+            db.insert_upload(&record).unwrap();
+            (import_id.to_string(), vec![record])
+        };
+        let groups = vec![group("import-1"), group("import-2")];
+        let _drain = ResetDrain;
+
+        let inner_db = db.clone();
+        let started = Arc::new(Mutex::new(vec![]));
+        let inner_started = started.clone();
+        let uploads = upload_groups(groups, move |(import_id, _)| {
+            inner_started.lock().unwrap().push(import_id.clone());
+            update_import_status(
+                &inner_db,
+                &model::ImportId::new(import_id.clone()),
+                UploadStatus::InProgress,
+                Some(50),
+            )
+            .unwrap();
+
+            // SIGINT arrives while the first upload is in flight:
+            Uploader::begin_drain();
+            assert!(!Uploader::is_idle());
+
+            let db = inner_db.clone();
+            Delay::new(Instant::now() + Duration::from_millis(50))
+                .map_err(Into::into)
+                .and_then(move |_| {
+                    update_import_status(
+                        &db,
+                        &model::ImportId::new(import_id),
+                        UploadStatus::Completed,
+                        Some(100),
+                    )
+                    .map_err(Into::into)
+                })
+                .into_trait()
+        });
+        Runtime::new().unwrap().block_on(uploads).unwrap();
+
+        assert!(Uploader::wait_until_idle(Duration::from_secs(10)));
+        assert_eq!(*started.lock().unwrap(), vec!["import-1".to_string()]);
+
+        // the upload in flight was finalized, the next was never started:
+        let statuses = |import_id| {
+            db.get_uploads_by_import_id(import_id)
+                .unwrap()
+                .iter()
+                .map(|r| (r.status, r.progress))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(statuses("import-1"), vec![(UploadStatus::Completed, 100)]);
+        assert_eq!(statuses("import-2"), vec![(UploadStatus::Queued, 0)]);
+    }
+
+    #[test]
+    fn a_drain_stops_starting_files() {
+        let started = Arc::new(Mutex::new(vec![]));
+        let inner_started = started.clone();
+        let draining = Arc::new(AtomicBool::new(false));
+        let inner_draining = draining.clone();
+        let transport = move |file: usize, _| {
+            inner_started.lock().unwrap().push(file);
+            // the drain begins once the second file has started:
+            if file == 1 {
+                inner_draining.store(true, Ordering::SeqCst);
+            }
+            stream::once::<_, ()>(Ok(file))
+        };
+
+        let sent = Runtime::new()
+            .unwrap()
+            .block_on(
+                upload_files(
+                    (0..6).collect(),
+                    Parallelism::new(1, 1),
+                    move || draining.load(Ordering::SeqCst),
+                    transport,
+                )
+                .collect(),
+            )
+            .unwrap();
+
+        // the files in flight finish, but no more are started:
+        assert_eq!(sent, vec![0, 1]);
+        assert_eq!(*started.lock().unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn waiting_for_uploads_in_flight_ends_once_they_finish() {
+        let in_flight = InFlight::start();
+        assert!(!Uploader::wait_until_idle(Duration::from_millis(10)));
+
+        let finished = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(in_flight);
+        });
+        assert!(Uploader::wait_until_idle(Duration::from_secs(10)));
+        finished.join().unwrap();
+    }
+}
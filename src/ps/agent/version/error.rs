@@ -19,6 +19,13 @@ impl Error {
     pub fn http_error(status: hyper::StatusCode, uri: hyper::Uri) -> Error {
         ErrorKind::HttpError { status, uri }.into()
     }
+
+    pub fn invalid_format<S: Into<String>>(format: S) -> Error {
+        ErrorKind::InvalidFormat {
+            format: format.into(),
+        }
+        .into()
+    }
 }
 
 impl Fail for Error {
@@ -62,6 +69,12 @@ pub enum ErrorKind {
 
     #[fail(display = "invalid URI: {}", error)]
     InvalidUri { error: String },
+
+    #[fail(
+        display = "invalid version format: {} (expected plain, json or long)",
+        format
+    )]
+    InvalidFormat { format: String },
 }
 
 impl From<ErrorKind> for Error {
@@ -1,4 +1,7 @@
+use std::env;
+use std::fmt;
 use std::result;
+use std::str::FromStr;
 
 use chrono::Duration;
 use futures::Future as _Future;
@@ -8,9 +11,12 @@ use hyper::{Body, Client, Uri};
 use hyper_tls::HttpsConnector;
 use reqwest::ClientBuilder;
 use semver::Version;
-use serde_json::Value;
+use serde_derive::Serialize;
+use serde_json::{json, Value};
 
-use crate::ps::agent::config;
+use pennsieve_macros::try_future;
+
+use crate::ps::agent::config::{self, Config};
 use crate::ps::agent::database::Database;
 use crate::ps::agent::Future;
 use crate::ps::util::futures::*;
@@ -18,15 +24,72 @@ use crate::ps::util::futures::*;
 mod error;
 pub use self::error::{Error, ErrorKind, Result};
 
+/// When the agent checks for new versions in the background.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UpdateCheck {
+    pub enabled: bool,
+    pub interval: Duration,
+}
+
+impl UpdateCheck {
+    /// The update check configured by `check_updates` and
+    /// `version_check_interval_hours`. Setting
+    /// `PENNSIEVE_DISABLE_VERSION_CHECK` disables it regardless.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            enabled: config.check_updates && !disabled_by_environment(),
+            interval: Duration::hours(config.version_check_interval_hours as i64),
+        }
+    }
+}
+
+/// Tests if `PENNSIEVE_DISABLE_VERSION_CHECK` disables the update check,
+/// which it does if set to `true`, `1` or `yes`, in any case. Any other
+/// value, such as `false` or `0`, leaves the check enabled.
+pub fn disabled_by_environment() -> bool {
+    env::var(config::constants::DISABLE_VERSION_CHECK_ENV_VAR)
+        .map_or(false, |value| is_true(&value))
+}
+
+fn is_true(value: &str) -> bool {
+    match value.trim().to_lowercase().as_ref() {
+        "true" | "1" | "yes" => true,
+        _ => false,
+    }
+}
+
 /// Check whether the user is using the latest version of the agent
-pub fn check_for_new_version(db: Database) -> Future<()> {
-    let db = db.clone();
+pub fn check_for_new_version(db: Database, update_check: UpdateCheck) -> Future<()> {
+    check_for_new_version_with(db, update_check, get_latest_version)
+}
+
+fn check_for_new_version_with<F>(
+    db: Database,
+    update_check: UpdateCheck,
+    latest_version: F,
+) -> Future<()>
+where
+    F: 'static + Send + FnOnce() -> Future<Version>,
+{
+    if !update_check.enabled {
+        return future::ok(()).into_trait();
+    }
     db.get_last_version_check()
         .map_err(|e| e.into())
         .into_future()
         .and_then(move |last_check| {
-            if should_check_for_new_version(last_check) {
-                validate_version_is_current()
+            if should_check_for_new_version(last_check, update_check.interval) {
+                check_version_with(latest_version)
+                    .map(|check| {
+                        if check.is_update_available() {
+                            // Print to stderr so that consumers don't see this message in
+                            // the output of `version` or `config show`.
+                            eprintln!(
+                                "\n\u{01F680} A new version ({}) of the Pennsieve Agent is available.\nVisit https://developer.pennsieve.io/agent to upgrade\n",
+                                check.latest
+                            );
+                        }
+                    })
                     // Always update that we checked the version, even in the case
                     // failures. The agent should not constantly  check for updates
                     // if e.g. something goes wrong with the S3 bucket
@@ -44,33 +107,129 @@ pub fn check_for_new_version(db: Database) -> Future<()> {
         .into_trait()
 }
 
-/// The agent checks for updates at a predefined interval
-pub fn should_check_for_new_version(last_check: Option<time::Timespec>) -> bool {
+/// The agent checks for updates once `interval` has passed since the
+/// last check.
+pub fn should_check_for_new_version(
+    last_check: Option<time::Timespec>,
+    interval: Duration,
+) -> bool {
     match last_check {
-        Some(last_check) => {
-            (last_check
-                + Duration::seconds(
-                    config::constants::AGENT_LATEST_RELEASE_CHECK_INTERVAL_SECS as i64,
-                ))
-                < time::now().to_timespec()
-        }
+        Some(last_check) => (last_check + interval) < time::now().to_timespec(),
         None => true,
     }
 }
 
-pub fn validate_version_is_current() -> Future<()> {
-    get_latest_version()
-        .and_then(move |latest_version| {
-            if latest_version > Version::parse(env!("CARGO_PKG_VERSION")).map_err(Into::<Error>::into)? {
-                // Print to stderr so that consumers don't see this message in
-                // the output of `version` or `config show`.
-                eprintln!(
-                    "\n\u{01F680} A new version ({}) of the Pennsieve Agent is available.\nVisit https://developer.pennsieve.io/agent to upgrade\n",
-                    latest_version
-                );
-            }
-            Ok(())
-        })
+/// The result of comparing the running agent's version to the most
+/// recently released version, as printed by `version --check`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionCheck {
+    pub current: Version,
+    pub latest: Version,
+}
+
+impl VersionCheck {
+    /// Tests if a newer version of the agent has been released.
+    pub fn is_update_available(&self) -> bool {
+        self.latest > self.current
+    }
+}
+
+impl fmt::Display for VersionCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Current version: {}", self.current)?;
+        writeln!(f, "Latest version:  {}", self.latest)?;
+        if self.is_update_available() {
+            write!(
+                f,
+                "An update is available. Visit https://developer.pennsieve.io/agent to upgrade"
+            )
+        } else {
+            write!(f, "The Pennsieve Agent is up to date")
+        }
+    }
+}
+
+/// How `version` prints the running agent's version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionFormat {
+    /// Just the version number, as `version` has always printed.
+    Plain,
+    /// A JSON object with `version`, `commit` and `built` keys.
+    Json,
+    /// The version number and build metadata, for humans.
+    Long,
+}
+
+impl Default for VersionFormat {
+    fn default() -> Self {
+        VersionFormat::Plain
+    }
+}
+
+impl FromStr for VersionFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_lowercase().as_ref() {
+            "plain" => Ok(VersionFormat::Plain),
+            "json" => Ok(VersionFormat::Json),
+            "long" => Ok(VersionFormat::Long),
+            _ => Err(Error::invalid_format(format)),
+        }
+    }
+}
+
+/// The version of the running agent, and the build it came from. The build
+/// metadata is embedded by the build script.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub built: &'static str,
+}
+
+impl BuildInfo {
+    /// Describes the running agent.
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("PENNSIEVE_BUILD_COMMIT"),
+            built: env!("PENNSIEVE_BUILD_TIMESTAMP"),
+        }
+    }
+
+    /// Renders the build as printed by `version --format`.
+    pub fn render(&self, format: VersionFormat) -> String {
+        match format {
+            VersionFormat::Plain => self.version.to_string(),
+            VersionFormat::Json => format!("{:#}", json!(self)),
+            VersionFormat::Long => format!(
+                "Pennsieve Agent {}\ncommit: {}\nbuilt:  {}",
+                self.version, self.commit, self.built
+            ),
+        }
+    }
+}
+
+/// Checks for a new version of the agent now, regardless of when the agent
+/// last checked, and records the check. Unlike `check_for_new_version`,
+/// any error is returned.
+pub fn check_version_now(db: Database) -> Future<VersionCheck> {
+    check_version_with(get_latest_version)
+        .and_then(move |check| db.add_version_check().map(|_| check).map_err(Into::into))
+        .into_trait()
+}
+
+/// Compares the running agent's version to the version returned by
+/// `latest_version`.
+pub fn check_version_with<F>(latest_version: F) -> Future<VersionCheck>
+where
+    F: FnOnce() -> Future<Version>,
+{
+    let current =
+        try_future!(Version::parse(env!("CARGO_PKG_VERSION")).map_err(Into::<Error>::into));
+    latest_version()
+        .map(move |latest| VersionCheck { current, latest })
         .into_trait()
 }
 
@@ -132,35 +291,140 @@ pub fn get_latest_version() -> Future<Version> {
         .into_trait()
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use std::thread;
-
-//     use super::*;
-
-//     #[test]
-//     fn test_get_latest_version() {
-//         thread::sleep(std::time::Duration::from_secs(1));
-//         let mut rt = tokio::runtime::Runtime::new().unwrap();
-//         rt.block_on(get_latest_version()).unwrap();
-//     }
-
-//     #[test]
-//     fn test_should_check_for_new_version() {
-//         thread::sleep(std::time::Duration::from_secs(1));
-//         let last_check = None;
-//         assert!(should_check_for_new_version(last_check));
-//         let last_check = Some(time::now().to_timespec());
-//         assert!(!should_check_for_new_version(last_check));
-//         let last_check = Some((time::now() - Duration::hours(4)).to_timespec());
-//         assert!(!should_check_for_new_version(last_check));
-//         let last_check = Some(
-//             (time::now()
-//                 - Duration::seconds(
-//                     1 + config::constants::AGENT_LATEST_RELEASE_CHECK_INTERVAL_SECS as i64,
-//                 ))
-//             .to_timespec(),
-//         );
-//         assert!(should_check_for_new_version(last_check));
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::runtime::Runtime;
+
+    use crate::ps::agent;
+    use crate::ps::util;
+
+    fn check(latest: &'static str) -> agent::Result<VersionCheck> {
+        Runtime::new()
+            .unwrap()
+            .block_on(check_version_with(move || {
+                future::result(Version::parse(latest).map_err(Into::into)).into_trait()
+            }))
+    }
+
+    #[test]
+    fn up_to_date() {
+        let check = check(env!("CARGO_PKG_VERSION")).unwrap();
+        assert!(!check.is_update_available());
+        assert_eq!(check.current, check.latest);
+        assert!(check.to_string().ends_with("up to date"));
+    }
+
+    #[test]
+    fn update_available() {
+        let check = check("999.0.0").unwrap();
+        assert!(check.is_update_available());
+        assert_eq!(check.latest, Version::new(999, 0, 0));
+        let message = check.to_string();
+        assert!(message.contains("Latest version:  999.0.0"));
+        assert!(message.contains("An update is available"));
+    }
+
+    #[test]
+    fn json_versions_include_the_build() {
+        let build = BuildInfo::current();
+        assert_eq!(
+            build.render(VersionFormat::default()),
+            env!("CARGO_PKG_VERSION")
+        );
+
+        let json: Value = serde_json::from_str(&build.render(VersionFormat::Json)).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["commit"].as_str().map_or(false, |c| !c.is_empty()));
+        assert!(json["built"].as_str().map_or(false, |b| !b.is_empty()));
+
+        assert!(build
+            .render(VersionFormat::Long)
+            .starts_with(&format!("Pennsieve Agent {}", env!("CARGO_PKG_VERSION"))));
+        assert_eq!(
+            "JSON".parse::<VersionFormat>().unwrap(),
+            VersionFormat::Json
+        );
+        assert!("yaml".parse::<VersionFormat>().is_err());
+    }
+
+    #[test]
+    fn errors_are_not_suppressed() {
+        assert!(check("not a version").is_err());
+    }
+
+    /// Runs a background check against a mocked version source, returning
+    /// how many times the source was queried.
+    fn background_check(db: &Database, update_check: UpdateCheck) -> usize {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        Runtime::new()
+            .unwrap()
+            .block_on(check_for_new_version_with(
+                db.clone(),
+                update_check,
+                move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    future::ok(Version::new(0, 0, 1)).into_trait()
+                },
+            ))
+            .unwrap();
+        queries.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn disabled_check_skips_the_version_source() {
+        let db = util::database::temp().unwrap();
+        let update_check = UpdateCheck {
+            enabled: false,
+            interval: Duration::hours(24),
+        };
+        assert_eq!(background_check(&db, update_check), 0);
+        assert_eq!(db.get_last_version_check().unwrap(), None);
+    }
+
+    #[test]
+    fn interval_suppresses_early_checks() {
+        let db = util::database::temp().unwrap();
+        let daily = UpdateCheck {
+            enabled: true,
+            interval: Duration::hours(24),
+        };
+        assert_eq!(background_check(&db, daily), 1);
+        assert!(db.get_last_version_check().unwrap().is_some());
+        assert_eq!(background_check(&db, daily), 0);
+
+        let last_check = Some((time::now() - Duration::hours(4)).to_timespec());
+        assert!(!should_check_for_new_version(
+            last_check,
+            Duration::hours(24)
+        ));
+        assert!(should_check_for_new_version(last_check, Duration::hours(1)));
+        assert!(should_check_for_new_version(None, Duration::hours(24)));
+    }
+
+    #[test]
+    fn update_check_is_configured() {
+        let mut config = Config::default();
+        config.version_check_interval_hours = 168;
+        let update_check = UpdateCheck::from_config(&config);
+        assert_eq!(update_check.interval, Duration::hours(168));
+
+        config.check_updates = false;
+        assert!(!UpdateCheck::from_config(&config).enabled);
+    }
+
+    #[test]
+    fn only_true_values_disable_the_update_check() {
+        for value in &["true", "1", "yes", "TRUE", " Yes "] {
+            assert!(is_true(value), "{:?}", value);
+        }
+        for value in &["false", "0", "no", ""] {
+            assert!(!is_true(value), "{:?}", value);
+        }
+    }
+}
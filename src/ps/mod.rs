@@ -1,7 +1,10 @@
 //! Pennsieve top-level definitions go here:
 
 use dirs;
+use lazy_static::lazy_static;
+use std::env;
 use std::path;
+use std::sync::RwLock;
 
 pub mod agent;
 pub mod proto;
@@ -9,7 +12,7 @@ pub mod util;
 
 pub use self::agent::messages;
 pub use self::agent::{
-    Error, ErrorKind, Future, HostName, OutputFormat, Result, Server, Service, ServiceId,
+    Error, ErrorKind, ExitCode, Future, HostName, OutputFormat, Result, Server, Service, ServiceId,
     WithProps, Worker,
 };
 
@@ -18,9 +21,67 @@ pub use self::agent::{
 #[allow(dead_code)]
 pub static PENNSIEVE_HOME: &str = ".pennsieve";
 
+/// The environment variable that relocates the home directory for
+/// Pennsieve assets, in place of "~/.pennsieve".
+pub static PENNSIEVE_HOME_ENV_VAR: &str = "PENNSIEVE_HOME";
+
+lazy_static! {
+    /// An alternate configuration file location, set by `--config`.
+    static ref CONFIG_FILE_OVERRIDE: RwLock<Option<path::PathBuf>> = RwLock::new(None);
+    /// An alternate database file location, set by `--db`.
+    static ref DATABASE_FILE_OVERRIDE: RwLock<Option<path::PathBuf>> = RwLock::new(None);
+}
+
+/// Overrides the location returned by `config_file()` for the remainder of
+/// the process.
+pub fn set_config_file<P: Into<path::PathBuf>>(path: P) {
+    *CONFIG_FILE_OVERRIDE
+        .write()
+        .expect("ps:set_config_file ~ lock poisoned") = Some(path.into());
+}
+
+/// Restores the location returned by `config_file()` when dropped. As the
+/// override is global, tests use this to keep it from leaking into others.
+#[cfg(test)]
+pub struct ConfigFileGuard(Option<path::PathBuf>);
+
+#[cfg(test)]
+impl ConfigFileGuard {
+    /// Overrides the location returned by `config_file()` until the guard
+    /// is dropped.
+    pub fn set<P: Into<path::PathBuf>>(path: P) -> Self {
+        let mut config_file = CONFIG_FILE_OVERRIDE
+            .write()
+            .expect("ps:ConfigFileGuard ~ lock poisoned");
+        ConfigFileGuard(std::mem::replace(&mut *config_file, Some(path.into())))
+    }
+}
+
+#[cfg(test)]
+impl Drop for ConfigFileGuard {
+    fn drop(&mut self) {
+        *CONFIG_FILE_OVERRIDE
+            .write()
+            .expect("ps:ConfigFileGuard ~ lock poisoned") = self.0.take();
+    }
+}
+
+/// Overrides the location returned by `database_file()` for the remainder
+/// of the process.
+pub fn set_database_file<P: Into<path::PathBuf>>(path: P) {
+    *DATABASE_FILE_OVERRIDE
+        .write()
+        .expect("ps:set_database_file ~ lock poisoned") = Some(path.into());
+}
+
 /// Gets the base directory used by the agent for Pennsieve-related assets
 /// such configuration files, cached data, etc.
+/// By default, this directory is located at "~/.pennsieve", unless
+/// relocated with the `PENNSIEVE_HOME` environment variable.
 pub fn home_dir() -> Result<Box<path::Path>> {
+    if let Some(path) = env::var_os(PENNSIEVE_HOME_ENV_VAR).filter(|path| !path.is_empty()) {
+        return Ok(path::PathBuf::from(path).into());
+    }
     match dirs::home_dir() {
         Some(path) => {
             let mut ps_assets = path.clone();
@@ -32,8 +93,15 @@ pub fn home_dir() -> Result<Box<path::Path>> {
 }
 
 /// Gets the location of the Pennsieve agent configuration file.
-/// By default, this file is located at "${home_dir()}/config.ini".
+/// By default, this file is located at "${home_dir()}/config.ini", unless
+/// overridden with `set_config_file()`.
 pub fn config_file() -> Result<Box<path::Path>> {
+    if let Some(ref path) = *CONFIG_FILE_OVERRIDE
+        .read()
+        .expect("ps:config_file ~ lock poisoned")
+    {
+        return Ok(path.clone().into());
+    }
     home_dir().and_then(|dir| {
         let mut config_file = dir.to_path_buf();
         config_file.push("config");
@@ -43,8 +111,15 @@ pub fn config_file() -> Result<Box<path::Path>> {
 }
 
 /// Gets the location of the Pennsieve agent database file.
-/// By default, this file is located at "${home_dir()}/agent.db".
+/// By default, this file is located at "${home_dir()}/agent.db", unless
+/// overridden with `set_database_file()`.
 pub fn database_file() -> Result<Box<path::Path>> {
+    if let Some(ref path) = *DATABASE_FILE_OVERRIDE
+        .read()
+        .expect("ps:database_file ~ lock poisoned")
+    {
+        return Ok(path.clone().into());
+    }
     home_dir().and_then(|dir| {
         let mut db_file = dir.to_path_buf();
         db_file.push("agent");
@@ -0,0 +1,186 @@
+//! Log record encoders, chosen with `PENNSIEVE_LOG_FORMAT`, and switching
+//! to debug logging at runtime.
+
+use std::env;
+use std::error::Error;
+use std::io::Write;
+use std::str::FromStr;
+
+use log::{LevelFilter, Record};
+use log4rs::config::Config;
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::{self, Encode};
+use serde_json::json;
+
+use crate::ps::agent::config::constants::AGENT_LOG_FORMAT;
+
+/// The format log records are written in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The human-readable `AGENT_LOG_FORMAT` pattern.
+    Pattern,
+    /// One JSON object per line.
+    Json,
+}
+
+impl LogFormat {
+    /// Reads the format from `PENNSIEVE_LOG_FORMAT`, falling back to the
+    /// human-readable pattern if it is unset or invalid.
+    pub fn from_env() -> Self {
+        match env::var("PENNSIEVE_LOG_FORMAT") {
+            Ok(format) => format.parse().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                LogFormat::Pattern
+            }),
+            Err(_) => LogFormat::Pattern,
+        }
+    }
+
+    /// Creates an encoder for this format.
+    pub fn encoder(self) -> Box<dyn Encode> {
+        match self {
+            LogFormat::Pattern => Box::new(PatternEncoder::new(AGENT_LOG_FORMAT)),
+            LogFormat::Json => Box::new(JsonLineEncoder),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pattern" | "text" => Ok(LogFormat::Pattern),
+            "json" => Ok(LogFormat::Json),
+            format => Err(format!("not a valid logging format: {}", format)),
+        }
+    }
+}
+
+/// Encodes each log record as a JSON object on its own line, with
+/// `timestamp`, `level`, `target` and `message` keys.
+#[derive(Debug, Default)]
+pub struct JsonLineEncoder;
+
+impl Encode for JsonLineEncoder {
+    fn encode(
+        &self,
+        w: &mut dyn encode::Write,
+        record: &Record<'_>,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let line = json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": record.level().to_string(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        writeln!(w, "{}", line)?;
+        Ok(())
+    }
+}
+
+/// Switches logging between its configured levels and the debug level,
+/// so a running agent can be made verbose without restarting it.
+pub struct LevelToggle {
+    build: Box<dyn Fn(Option<LevelFilter>) -> Config + Send>,
+    debug: bool,
+}
+
+impl LevelToggle {
+    /// `build` creates the logging configuration. Given a level, every
+    /// logger it configures must log at that level instead of its own.
+    pub fn new<F>(build: F) -> Self
+    where
+        F: Fn(Option<LevelFilter>) -> Config + Send + 'static,
+    {
+        Self {
+            build: Box::new(build),
+            debug: false,
+        }
+    }
+
+    /// Tests if logging is switched to the debug level.
+    pub fn is_debug(&self) -> bool {
+        self.debug
+    }
+
+    /// The logging configuration to use now.
+    pub fn config(&self) -> Config {
+        (self.build)(if self.debug {
+            Some(LevelFilter::Debug)
+        } else {
+            None
+        })
+    }
+
+    /// Switches to the debug level, or back to the configured levels,
+    /// returning the configuration to use from now on.
+    pub fn toggle(&mut self) -> Config {
+        self.debug = !self.debug;
+        self.config()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::Level;
+    use log4rs::config::Root;
+    use log4rs::encode::writer::simple::SimpleWriter;
+    use serde_json::Value as JSON;
+
+    #[test]
+    fn formats_are_parsed() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert_eq!("pattern".parse(), Ok(LogFormat::Pattern));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn records_are_encoded_as_json_lines() {
+        let mut writer = SimpleWriter(Vec::new());
+        LogFormat::Json
+            .encoder()
+            .encode(
+                &mut writer,
+                &Record::builder()
+                    .args(format_args!("uploaded \"{}\"", "test.txt"))
+                    .level(Level::Warn)
+                    .target("pennsieve::ps::agent::upload")
+                    .build(),
+            )
+            .unwrap();
+
+        let output = String::from_utf8(writer.0).unwrap();
+        assert!(output.ends_with('\n'));
+        assert_eq!(output.lines().count(), 1);
+
+        let line: JSON = serde_json::from_str(&output).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "pennsieve::ps::agent::upload");
+        assert_eq!(line["message"], "uploaded \"test.txt\"");
+        let timestamp = line["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+        assert_eq!(line.as_object().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn toggling_switches_between_debug_and_the_configured_level() {
+        let mut toggle = LevelToggle::new(|level: Option<LevelFilter>| {
+            Config::builder()
+                .build(Root::builder().build(level.unwrap_or(LevelFilter::Warn)))
+                .unwrap()
+        });
+        assert!(!toggle.is_debug());
+        assert_eq!(toggle.config().root().level(), LevelFilter::Warn);
+
+        assert_eq!(toggle.toggle().root().level(), LevelFilter::Debug);
+        assert!(toggle.is_debug());
+        assert_eq!(toggle.config().root().level(), LevelFilter::Debug);
+
+        assert_eq!(toggle.toggle().root().level(), LevelFilter::Warn);
+        assert!(!toggle.is_debug());
+    }
+}
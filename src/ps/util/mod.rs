@@ -4,6 +4,7 @@ pub mod actor;
 pub mod database;
 pub mod futures;
 pub mod http;
+pub mod logging;
 pub mod path;
 pub mod strings;
 pub mod temporal;
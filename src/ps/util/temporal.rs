@@ -28,3 +28,84 @@ pub fn timespec_to_rfc3339(ts: Timespec) -> RFC3339 {
     let t = chrono::NaiveDateTime::from_timestamp(ts.sec as i64, ts.nsec as u32);
     RFC3339(chrono::DateTime::<chrono::Utc>::from_utc(t, chrono::Utc).to_rfc3339())
 }
+
+/// Which end of a date range a date given on the command line bounds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bound {
+    Start,
+    End,
+}
+
+/// Parses an ISO 8601 date (`2019-03-01`) or date and time
+/// (`2019-03-01T12:30:00`, with an optional UTC offset) as a bound of an
+/// inclusive range. Times without an offset are taken to be UTC.
+///
+/// A date on its own starts at midnight, or, as the end of a range, covers
+/// the whole of that day.
+pub fn parse_date_bound(s: &str, bound: Bound) -> Option<Timespec> {
+    let s = s.trim();
+    let datetime = if let Ok(datetime) = chrono::DateTime::parse_from_rfc3339(s) {
+        datetime.naive_utc()
+    } else if let Some(datetime) = ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .filter_map(|format| chrono::NaiveDateTime::parse_from_str(s, format).ok())
+        .next()
+    {
+        datetime
+    } else {
+        let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+        match bound {
+            Bound::Start => date.and_hms(0, 0, 0),
+            Bound::End => date.and_hms_nano(23, 59, 59, 999_999_999),
+        }
+    };
+    Some(Timespec::new(
+        datetime.timestamp(),
+        datetime.timestamp_subsec_nanos() as i32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_bound_whole_days() {
+        let start = parse_date_bound("2019-03-01", Bound::Start).unwrap();
+        let end = parse_date_bound("2019-03-01", Bound::End).unwrap();
+        assert_eq!(start, Timespec::new(1_551_398_400, 0));
+        assert_eq!(end, Timespec::new(1_551_484_799, 999_999_999));
+    }
+
+    #[test]
+    fn datetimes_are_exact() {
+        let expected = Some(Timespec::new(1_551_443_400, 0));
+        assert_eq!(
+            parse_date_bound("2019-03-01T12:30:00", Bound::End),
+            expected
+        );
+        assert_eq!(
+            parse_date_bound("2019-03-01 12:30:00", Bound::Start),
+            expected
+        );
+        assert_eq!(
+            parse_date_bound("2019-03-01T12:30:00Z", Bound::End),
+            expected
+        );
+        assert_eq!(
+            parse_date_bound("2019-03-01T14:30:00+02:00", Bound::Start),
+            expected
+        );
+        assert_eq!(
+            parse_date_bound("2019-03-01T12:30:00.250", Bound::Start),
+            Some(Timespec::new(1_551_443_400, 250_000_000))
+        );
+    }
+
+    #[test]
+    fn other_values_are_rejected() {
+        assert_eq!(parse_date_bound("yesterday", Bound::Start), None);
+        assert_eq!(parse_date_bound("2019-13-01", Bound::Start), None);
+        assert_eq!(parse_date_bound("03/01/2019", Bound::End), None);
+    }
+}
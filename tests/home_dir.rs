@@ -0,0 +1,43 @@
+use std::env;
+
+use tempfile::tempdir;
+
+use pennsieve::cache::{create_page_template, Config};
+use pennsieve::upload::stdin;
+
+// Kept to a single test, as the environment is shared by every test in
+// this binary:
+#[test]
+fn every_asset_path_resolves_under_pennsieve_home() {
+    let home = tempdir().unwrap();
+    env::set_var("PENNSIEVE_HOME", home.path());
+
+    assert_eq!(pennsieve::home_dir().unwrap().to_path_buf(), home.path());
+    assert_eq!(
+        pennsieve::config_file().unwrap().to_path_buf(),
+        home.path().join("config.ini")
+    );
+    assert_eq!(
+        pennsieve::database_file().unwrap().to_path_buf(),
+        home.path().join("agent.db")
+    );
+    assert_eq!(
+        pennsieve::cache_dir().unwrap().to_path_buf(),
+        home.path().join("cache")
+    );
+    assert_eq!(stdin::spool_dir().unwrap(), home.path().join("stdin"));
+
+    let config = Config::default();
+    assert_eq!(config.base_path().to_path_buf(), home.path().join("cache"));
+    create_page_template(&config).unwrap();
+    assert!(config
+        .get_template_path()
+        .starts_with(home.path().join("cache")));
+    assert!(config.get_template_path().exists());
+
+    // an empty variable falls back to the default:
+    env::set_var("PENNSIEVE_HOME", "");
+    assert!(pennsieve::home_dir().unwrap().ends_with(".pennsieve"));
+
+    env::remove_var("PENNSIEVE_HOME");
+}
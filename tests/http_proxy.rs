@@ -3,16 +3,90 @@ extern crate pennsieve_macros;
 
 mod helpers;
 
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Mutex;
 use std::{thread, time};
 
 use actix::prelude::*;
+use lazy_static::lazy_static;
 
 use serde_json::Value;
 
-use pennsieve::{server, Agent, HostName};
+use pennsieve::{server, util, Agent, HostName};
 
 const POST_JSON_DATA: &str = "{ \"foo\": \"bar\" }";
 
+/// A logger that keeps every message, so tests can check what was logged.
+struct CapturedLog(Mutex<Vec<String>>);
+
+impl log::Log for CapturedLog {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+lazy_static! {
+    static ref LOG: CapturedLog = CapturedLog(Mutex::new(Vec::new()));
+}
+
+/// Logs debug messages to `LOG` while it is held. The logger can only be
+/// installed once, so the previous level is restored on drop to keep other
+/// tests in this binary from being logged.
+struct DebugLogGuard(log::LevelFilter);
+
+impl DebugLogGuard {
+    fn new() -> Self {
+        let previous = log::max_level();
+        let _ = log::set_logger(&*LOG);
+        log::set_max_level(log::LevelFilter::Debug);
+        DebugLogGuard(previous)
+    }
+}
+
+impl Drop for DebugLogGuard {
+    fn drop(&mut self) {
+        log::set_max_level(self.0);
+    }
+}
+
+/// Starts a backend that answers a single request with "hello", returning
+/// the port it listens on.
+fn stub_backend() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("stub: bind");
+    let port = listener.local_addr().expect("stub: address").port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("stub: accept");
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).expect("stub: read");
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello")
+            .expect("stub: write");
+    });
+    port
+}
+
+fn metric(body: &str, name: &str) -> usize {
+    body.lines()
+        .find(|line| line.starts_with(&format!("{} ", name)))
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| panic!("metric not found: {}", name))
+}
+
 /// Tests in this file do not run on Windows because running/stopping
 /// multiple systems in multiple threads seems to interfere with
 /// actix's windows-specific signal handling streams.
@@ -168,3 +242,65 @@ fn test_proxied_multiple_servers() {
 
     system.run();
 }
+
+#[test]
+#[cfg(unix)]
+fn test_proxied_requests_are_counted_and_logged() {
+    // Proxied requests are only logged at the debug level:
+    let _log = DebugLogGuard::new();
+
+    let system = System::new("ps");
+    let props = server::rp::Props {
+        hostname: "http://127.0.0.1"
+            .parse::<HostName>()
+            .expect("counted: parse"),
+        remote_port: stub_backend(),
+    };
+    let local_port = 8095;
+    let status_port = 11243;
+
+    let mut agent = Agent::new();
+    agent.quiet();
+    agent.set_status_port(status_port);
+    agent.set_database(util::database::temp().expect("counted: database"));
+    agent
+        .define_server(local_port, props, server::ReverseProxyServer)
+        .expect("counted: server");
+
+    // Other tests proxy requests concurrently, so only increases are
+    // checked:
+    let before = server::rp::stats();
+    let current = System::current();
+
+    thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(200));
+
+        let mut resp = reqwest::get(&format!("http://127.0.0.1:{}/stub", local_port))
+            .expect("counted: response");
+        assert!(resp.status().is_success());
+        assert_eq!(resp.text().expect("counted: body"), "hello");
+
+        let stats = server::rp::stats();
+        assert!(stats.requests > before.requests);
+        assert!(stats.bytes >= before.bytes + 5);
+
+        let body = reqwest::get(&format!("http://127.0.0.1:{}/metrics", status_port))
+            .expect("counted: metrics response")
+            .text()
+            .expect("counted: metrics body");
+        assert!(metric(&body, "pennsieve_proxy_requests_total") >= stats.requests);
+        assert!(metric(&body, "pennsieve_proxy_bytes_total") >= stats.bytes);
+
+        assert!(LOG
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line.starts_with("proxy: GET /stub 200 ")));
+
+        current.stop();
+    });
+
+    agent.setup().expect("setup").run().expect("run");
+    system.run();
+}
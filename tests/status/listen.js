@@ -4,7 +4,7 @@
 const WebSocket = require('ws');
 
 const statusPort = process.argv[2] || 11235;
-const ws = new WebSocket(`ws://0.0.0.0:${statusPort}`);
+const ws = new WebSocket(`ws://127.0.0.1:${statusPort}`);
 
 ws.onopen = function open(evt) {
   console.log("listen:connected");
@@ -11,7 +11,7 @@ const targetDir = fs.realpathSync(process.argv[3]);
 console.log(`Uploading ${targetDir}`);
 
 const statusPort = process.argv[4] || 11235;
-const ws = new WebSocket(`ws://0.0.0.0:${statusPort}`);
+const ws = new WebSocket(`ws://127.0.0.1:${statusPort}`);
 
 ws.onopen = function open(evt) {
   console.log("upload:connected");
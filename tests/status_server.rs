@@ -0,0 +1,139 @@
+use std::{thread, time};
+
+use actix::prelude::*;
+
+use serde_json::Value;
+
+use pennsieve::{util, Agent};
+
+/// Like the reverse proxy tests, this test does not run on Windows, as
+/// running multiple actor systems in multiple threads interferes with
+/// actix's windows-specific signal handling.
+
+#[test]
+#[cfg(unix)]
+fn test_health_check() {
+    let system = System::new("ps");
+    let status_port = 11240;
+
+    let mut agent = Agent::new();
+    agent.quiet();
+    agent.set_status_port(status_port);
+    agent.set_database(util::database::temp().expect("health: database"));
+
+    let current = System::current();
+
+    thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(200));
+
+        let uri = format!("http://127.0.0.1:{}/health", status_port);
+        let mut resp = reqwest::get(&uri).expect("health: response");
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+        let json: Value = resp.json().expect("health: json");
+        assert_eq!(json["status"], "ok");
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["uptime_seconds"].is_u64());
+        assert_eq!(json["active_uploads"], 0);
+        assert_eq!(json["cache_size_bytes"], 0);
+
+        // No credentials are needed, and other methods are not allowed:
+        let resp = reqwest::Client::new()
+            .post(&uri)
+            .send()
+            .expect("health: post response");
+        assert!(resp.status().is_client_error());
+
+        current.stop();
+    });
+
+    agent.setup().expect("setup").run().expect("run");
+    system.run();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_metrics() {
+    let system = System::new("ps");
+    let status_port = 11241;
+
+    let mut agent = Agent::new();
+    agent.quiet();
+    agent.set_status_port(status_port);
+    agent.set_database(util::database::temp().expect("metrics: database"));
+
+    let current = System::current();
+
+    thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(200));
+
+        let uri = format!("http://127.0.0.1:{}/metrics", status_port);
+        let mut resp = reqwest::get(&uri).expect("metrics: response");
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let body = resp.text().expect("metrics: body");
+
+        let mut names = vec![];
+        for line in body.lines() {
+            if line.starts_with('#') {
+                let mut parts = line.splitn(4, ' ');
+                assert_eq!(parts.next(), Some("#"));
+                assert!(["HELP", "TYPE"].contains(&parts.next().unwrap()));
+                continue;
+            }
+            let parts: Vec<&str> = line.split(' ').collect();
+            assert_eq!(parts.len(), 2, "invalid metric line: {}", line);
+            assert!(parts[1].parse::<f64>().is_ok(), "invalid value: {}", line);
+            names.push(parts[0]);
+        }
+        assert_eq!(
+            names,
+            vec![
+                "pennsieve_uploads_active",
+                "pennsieve_uploads_failed_total",
+                "pennsieve_uploads_completed_total",
+                "pennsieve_cache_bytes",
+                "pennsieve_cache_pages",
+                "pennsieve_proxy_requests_total",
+                "pennsieve_proxy_errors_total",
+                "pennsieve_proxy_bytes_total",
+            ]
+        );
+
+        current.stop();
+    });
+
+    agent.setup().expect("setup").run().expect("run");
+    system.run();
+}
+
+/// Other loopback addresses than 127.0.0.1 are only available on Linux
+/// without further configuration.
+#[test]
+#[cfg(target_os = "linux")]
+fn test_bind_address() {
+    let system = System::new("ps");
+    let status_port = 11242;
+
+    let mut agent = Agent::new();
+    agent.quiet();
+    agent.set_status_bind("127.0.0.2".parse().expect("bind: address"));
+    agent.set_status_port(status_port);
+
+    let current = System::current();
+
+    thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(200));
+
+        let resp = reqwest::get(&format!("http://127.0.0.2:{}/health", status_port))
+            .expect("bind: response");
+        assert!(resp.status().is_success());
+
+        // Nothing is listening on the default interface:
+        assert!(reqwest::get(&format!("http://127.0.0.1:{}/health", status_port)).is_err());
+
+        current.stop();
+    });
+
+    agent.setup().expect("setup").run().expect("run");
+    system.run();
+}
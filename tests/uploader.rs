@@ -8,7 +8,7 @@ use actix::prelude::*;
 
 use futures::*;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use pennsieve_rust::model::upload::MultipartUploadId;
 use pennsieve_rust::model::{DatasetNodeId, ImportId, OrganizationId, UploadId};
@@ -51,6 +51,11 @@ fn in_progress_retry_record(
         organization_id: (*FIXTURE_ORGANIZATION_NODE_ID).clone(),
         chunk_size: Some(100),
         multipart_upload_id: Some(multipart_upload_id.0),
+        priority: 0,
+        failure_reason: None,
+        tags: BTreeMap::new(),
+        last_error: None,
+        checksum: None,
     }
 }
 
@@ -75,6 +80,11 @@ fn in_progress_aged_record(
         organization_id: (*FIXTURE_ORGANIZATION_NODE_ID).clone(),
         chunk_size: Some(100),
         multipart_upload_id: Some(multipart_upload_id.0),
+        priority: 0,
+        failure_reason: None,
+        tags: BTreeMap::new(),
+        last_error: None,
+        checksum: None,
     }
 }
 
@@ -99,6 +109,11 @@ fn queued_record(
         organization_id: (*FIXTURE_ORGANIZATION_NODE_ID).clone(),
         chunk_size: Some(100),
         multipart_upload_id: Some(multipart_upload_id.0),
+        priority: 0,
+        failure_reason: None,
+        tags: BTreeMap::new(),
+        last_error: None,
+        checksum: None,
     }
 }
 
@@ -151,7 +166,8 @@ pub fn test_complex_file_uploads() {
     let props = upload::Props {
         api: api.clone(),
         db: db.clone(),
-        parallelism: num_cpus::get(),
+        file_parallelism: 1,
+        chunk_parallelism: num_cpus::get(),
     };
     upload::Uploader::with_props(props);
 